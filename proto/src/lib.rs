@@ -0,0 +1,292 @@
+//! layered-proto
+//!
+//! `user.proto` のメッセージとgRPCのサーバー・クライアント。
+//!
+//! tonicはasync/awaitを使うので、edition 2015の本体からは直接書けない。
+//! 非同期の部分はこのcrateに閉じ込めて、本体には同期的な `UserService` traitだけを見せる。
+//! ビルドにprotocが要らないように、tonic-buildが生成するものと同じ形のコードを手で書いている。
+//! `user.proto` を変えた時はここも合わせて変える。
+
+// tonicのAPIが `Result<_, Status>` を返すので、Statusの大きさはこちらでは変えられない
+#![allow(clippy::result_large_err)]
+
+/// サービスの名前(`パッケージ名.サービス名`)
+pub const SERVICE_NAME: &str = "layered.UserService";
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct User {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "3")]
+    pub email: String,
+    #[prost(string, tag = "4")]
+    pub role: String,
+    #[prost(string, tag = "5")]
+    pub status: String,
+    /// RFC 3339
+    #[prost(string, tag = "6")]
+    pub create_time: String,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CreateUserRequest {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub email: String,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetUserRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListUsersRequest {
+    /// 1始まり。0なら1ページ目
+    #[prost(uint32, tag = "1")]
+    pub page: u32,
+    /// 0なら設定の件数
+    #[prost(uint32, tag = "2")]
+    pub per_page: u32,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListUsersResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub users: Vec<User>,
+    #[prost(uint32, tag = "2")]
+    pub page: u32,
+    #[prost(uint32, tag = "3")]
+    pub per_page: u32,
+    #[prost(uint32, tag = "4")]
+    pub total: u32,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteUserRequest {
+    #[prost(string, tag = "1")]
+    pub id: String,
+    #[prost(string, tag = "2")]
+    pub confirmation_token: String,
+}
+
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct DeleteUserResponse {
+    /// 確認用のトークンを発行した時だけ入る
+    #[prost(string, tag = "1")]
+    pub confirmation_token: String,
+    /// 退会が確定した時だけ入る
+    #[prost(message, optional, tag = "2")]
+    pub user: Option<User>,
+}
+
+pub mod user_service_server {
+    use tonic::codegen::*;
+    use tonic::{Request, Response, Status};
+
+    /// サービスの実装。各メソッドはリクエストを処理するスレッドの上で同期的に呼ばれる。
+    pub trait UserService: Send + Sync + 'static {
+        fn create_user(&self, request: Request<super::CreateUserRequest>) -> Result<Response<super::User>, Status>;
+        fn get_user(&self, request: Request<super::GetUserRequest>) -> Result<Response<super::User>, Status>;
+        fn list_users(
+            &self,
+            request: Request<super::ListUsersRequest>,
+        ) -> Result<Response<super::ListUsersResponse>, Status>;
+        fn delete_user(
+            &self,
+            request: Request<super::DeleteUserRequest>,
+        ) -> Result<Response<super::DeleteUserResponse>, Status>;
+    }
+
+    /// `UserService` をgRPCのサービスとして公開する
+    #[derive(Debug)]
+    pub struct UserServiceServer<T> {
+        inner: Arc<T>,
+    }
+
+    impl<T> UserServiceServer<T> {
+        pub fn new(inner: T) -> Self {
+            Self::from_arc(Arc::new(inner))
+        }
+
+        pub fn from_arc(inner: Arc<T>) -> Self {
+            Self { inner }
+        }
+    }
+
+    impl<T> Clone for UserServiceServer<T> {
+        fn clone(&self) -> Self {
+            Self {
+                inner: self.inner.clone(),
+            }
+        }
+    }
+
+    /// 1つのメソッドを `UnaryService` にする
+    struct Unary<T, F> {
+        inner: Arc<T>,
+        method: F,
+    }
+
+    impl<T, F, Req, Res> tonic::server::UnaryService<Req> for Unary<T, F>
+    where
+        T: UserService,
+        F: Fn(&T, Request<Req>) -> Result<Response<Res>, Status> + Copy + Send + 'static,
+        Req: Send + 'static,
+        Res: Send + 'static,
+    {
+        type Response = Res;
+        type Future = BoxFuture<Response<Res>, Status>;
+
+        fn call(&mut self, request: Request<Req>) -> Self::Future {
+            let (inner, method) = (self.inner.clone(), self.method);
+            Box::pin(async move { method(&inner, request) })
+        }
+    }
+
+    fn unary<T, F, Req, Res, B>(
+        inner: Arc<T>,
+        method: F,
+        req: http::Request<B>,
+    ) -> BoxFuture<http::Response<tonic::body::BoxBody>, std::convert::Infallible>
+    where
+        T: UserService,
+        F: Fn(&T, Request<Req>) -> Result<Response<Res>, Status> + Copy + Send + 'static,
+        Req: prost::Message + Default + Send + 'static,
+        Res: prost::Message + Send + 'static,
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        Box::pin(async move {
+            let mut grpc = tonic::server::Grpc::new(tonic::codec::ProstCodec::default());
+            Ok(grpc.unary(Unary { inner, method }, req).await)
+        })
+    }
+
+    impl<T, B> Service<http::Request<B>> for UserServiceServer<T>
+    where
+        T: UserService,
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/layered.UserService/CreateUser" => unary(inner, T::create_user, req),
+                "/layered.UserService/GetUser" => unary(inner, T::get_user, req),
+                "/layered.UserService/ListUsers" => unary(inner, T::list_users, req),
+                "/layered.UserService/DeleteUser" => unary(inner, T::delete_user, req),
+                _ => Box::pin(async move {
+                    Ok(Status::unimplemented(format!("unknown method: {}", req.uri().path())).into_http())
+                }),
+            }
+        }
+    }
+
+    impl<T> tonic::server::NamedService for UserServiceServer<T> {
+        const NAME: &'static str = super::SERVICE_NAME;
+    }
+}
+
+pub mod user_service_client {
+    use tonic::codegen::*;
+    use tonic::{IntoRequest, Response, Status};
+
+    #[derive(Debug, Clone)]
+    pub struct UserServiceClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+
+    impl UserServiceClient<tonic::transport::Channel> {
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let channel = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(channel))
+        }
+    }
+
+    impl<T> UserServiceClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::Error: Into<StdError>,
+        T::ResponseBody: Body<Data = Bytes> + Send + 'static,
+        <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+    {
+        pub fn new(inner: T) -> Self {
+            Self {
+                inner: tonic::client::Grpc::new(inner),
+            }
+        }
+
+        async fn unary<Req, Res>(
+            &mut self,
+            request: tonic::Request<Req>,
+            method: &'static str,
+            path: &'static str,
+        ) -> Result<Response<Res>, Status>
+        where
+            Req: prost::Message + Send + Sync + 'static,
+            Res: prost::Message + Default + Send + Sync + 'static,
+        {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| Status::unknown(format!("service was not ready: {}", e.into())))?;
+            let mut request = request;
+            request
+                .extensions_mut()
+                .insert(GrpcMethod::new(super::SERVICE_NAME, method));
+            let path = http::uri::PathAndQuery::from_static(path);
+            self.inner
+                .unary(request, path, tonic::codec::ProstCodec::default())
+                .await
+        }
+
+        pub async fn create_user(
+            &mut self,
+            request: impl IntoRequest<super::CreateUserRequest>,
+        ) -> Result<Response<super::User>, Status> {
+            self.unary(request.into_request(), "CreateUser", "/layered.UserService/CreateUser")
+                .await
+        }
+
+        pub async fn get_user(
+            &mut self,
+            request: impl IntoRequest<super::GetUserRequest>,
+        ) -> Result<Response<super::User>, Status> {
+            self.unary(request.into_request(), "GetUser", "/layered.UserService/GetUser")
+                .await
+        }
+
+        pub async fn list_users(
+            &mut self,
+            request: impl IntoRequest<super::ListUsersRequest>,
+        ) -> Result<Response<super::ListUsersResponse>, Status> {
+            self.unary(request.into_request(), "ListUsers", "/layered.UserService/ListUsers")
+                .await
+        }
+
+        pub async fn delete_user(
+            &mut self,
+            request: impl IntoRequest<super::DeleteUserRequest>,
+        ) -> Result<Response<super::DeleteUserResponse>, Status> {
+            self.unary(request.into_request(), "DeleteUser", "/layered.UserService/DeleteUser")
+                .await
+        }
+    }
+}
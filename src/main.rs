@@ -21,19 +21,75 @@
 //! * `use`: モジュール内のアイテムの読み込み。
 //! * Rustは関数の最後の式にセミコロンを付けない場合、その式の戻り値を関数の戻り値として返します。
 
+extern crate aes_gcm;
+extern crate argon2;
+extern crate async_graphql;
+extern crate axum;
+extern crate base64;
 extern crate chrono;
+extern crate clap;
+#[macro_use]
 extern crate failure;
+extern crate futures;
+extern crate handlebars;
+extern crate hkdf;
+extern crate hmac;
+#[cfg(feature = "kafka")]
+extern crate kafka;
+extern crate layered_proto;
+extern crate lettre;
+extern crate maxminddb;
+extern crate rand;
+extern crate ratatui;
+extern crate redis;
+extern crate reqwest;
+extern crate rustyline;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+extern crate sha2;
+extern crate sqlx;
+extern crate tantivy;
+extern crate thiserror;
+extern crate tokio;
+extern crate toml;
+extern crate tonic;
+extern crate tracing;
+extern crate utoipa;
+extern crate uuid;
+
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
+#[cfg(test)]
+extern crate tokio_tungstenite;
+#[cfg(test)]
+extern crate tower;
 
 mod component {
     //! ストレージアクセス、DBアクセス、現在時刻取得、ネットワークアクセス等の(多くの場合IOを伴う副作用を持つ)処理をcomponentとしてまとめる。
     //! Clean Architecture の円形の図で言うと最も外側に当たるレイヤ。
 
     pub mod time {
+        //! 時刻は実行環境のタイムゾーンに左右されないように、内部では常にUTCで扱う。
+        //! 利用者に見せる時だけ、presenterが `now_in` / `to_timezone` で見る人のタイムゾーンに変換する。
+
         use chrono::prelude::*;
+        use chrono::Duration;
+        use std::thread;
+        use std::time;
 
         /// 現在時間取得処理を行うレイヤ
         pub trait TimeComponent {
-            fn now(&self) -> DateTime<Local>;
+            /// Entityに保存する時刻はこれを使う
+            fn now(&self) -> DateTime<Utc>;
+
+            /// 表示用に、指定したタイムゾーンでの現在時刻を返す
+            fn now_in<Tz: TimeZone>(&self, tz: &Tz) -> DateTime<Tz> {
+                to_timezone(&self.now(), tz)
+            }
         }
 
         /// これを実装(impl)している型はTimeComponentを返せる。抽象化されたGetter.
@@ -46,314 +102,22744 @@ mod component {
         pub struct Chrono;
 
         impl TimeComponent for Chrono {
-            fn now(&self) -> DateTime<Local> {
-                Local::now()
+            fn now(&self) -> DateTime<Utc> {
+                Utc::now()
             }
         }
-    }
 
-    pub mod storage {
-        use entity::user::{Name, User};
-        use failure::Error;
-        use std::collections::BTreeMap;
+        /// MonotonicTimeComponentが返す時点。時計毎の起点からの経過時間なので、違う時計の値とは比べられない。
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+        pub struct Instant(Duration);
 
-        /// ユーザー情報をストレージに出し入れするレイヤ
-        pub trait UserStorageComponent {
-            fn read(&self, Name) -> Result<User, Error>;
-            fn save(&mut self, Name, User) -> Result<(), Error>;
-            fn read_all(&self) -> Result<Vec<User>, Error>;
-            fn save_all(&mut self, users: &[(Name, User)]) -> Result<(), Error>;
+        impl Instant {
+            pub fn from_origin(elapsed: Duration) -> Instant {
+                Instant(elapsed)
+            }
         }
 
-        /// これを実装(impl)している型はUserStorageComponentを返せる。抽象化されたGetter.
-        /// 引数の型が&selfの方は参照only。mutはmutableの略で、これが付いてると値の変更が可能。
-        pub trait HaveUserStorageComponent {
-            type UserStorageComponent: UserStorageComponent;
-            fn user_storage_component(&self) -> &Self::UserStorageComponent;
-            fn user_storage_component_mut(&mut self) -> &mut Self::UserStorageComponent;
+        /// 経過時間を測る為の、戻らない時計と待機のレイヤ。
+        /// 壁時計(TimeComponent)は合わせ直されて戻る事があるので、待ち時間やタイムアウトにはこちらを使う。
+        pub trait MonotonicTimeComponent {
+            fn instant(&self) -> Instant;
+
+            fn elapsed(&self, since: Instant) -> Duration {
+                self.instant().0 - since.0
+            }
+
+            /// 負の値なら待たない
+            fn sleep(&self, duration: Duration);
         }
 
-        /// メモリ上に値を保持するストレージ抽象型
-        pub struct MemoryStorage {
-            list: BTreeMap<Name, User>,
+        /// これを実装(impl)している型はMonotonicTimeComponentを返せる。抽象化されたGetter.
+        pub trait HaveMonotonicTimeComponent {
+            type MonotonicTimeComponent: MonotonicTimeComponent;
+            fn monotonic_time_component(&self) -> &Self::MonotonicTimeComponent;
         }
 
-        /// MemoryStorage型のメソッドを定義
-        impl MemoryStorage {
-            pub fn new() -> MemoryStorage {
-                MemoryStorage {
-                    list: BTreeMap::new(),
-                }
-            }
+        /// MonotonicTimeComponentを標準ライブラリの時計とスレッドの待機で実装(impl)する型。
+        /// 作った時点を起点にする。
+        pub struct StdClock {
+            origin: time::Instant,
         }
 
-        /// MemoryStorage型用のUserStorageComponentの実装(impl)
-        impl UserStorageComponent for MemoryStorage {
-            fn read(&self, name: Name) -> Result<User, Error> {
-                Ok(self.list.get(&name).unwrap().clone())
+        impl StdClock {
+            pub fn new() -> StdClock {
+                StdClock {
+                    origin: time::Instant::now(),
+                }
             }
+        }
 
-            fn save(&mut self, name: Name, user: User) -> Result<(), Error> {
-                self.list.insert(name, user).map(|_| ());
-                Ok(())
+        impl Default for StdClock {
+            fn default() -> Self {
+                StdClock::new()
             }
+        }
 
-            fn read_all(&self) -> Result<Vec<User>, Error> {
-                Ok(self.list.values().map(|v| v.clone()).collect())
+        impl MonotonicTimeComponent for StdClock {
+            fn instant(&self) -> Instant {
+                Instant::from_origin(Duration::from_std(self.origin.elapsed()).unwrap_or(Duration::MAX))
             }
 
-            fn save_all(&mut self, users: &[(Name, User)]) -> Result<(), Error> {
-                for (name, user) in users {
-                    self.list.insert(name.clone(), user.clone());
+            fn sleep(&self, duration: Duration) {
+                if let Ok(duration) = duration.to_std() {
+                    thread::sleep(duration);
                 }
-                Ok(())
             }
         }
+
+        /// 表示用に、指定したタイムゾーンの時刻へ変換する
+        pub fn to_timezone<Tz: TimeZone>(time: &DateTime<Utc>, tz: &Tz) -> DateTime<Tz> {
+            time.with_timezone(tz)
+        }
     }
-}
 
-mod repository {
-    pub mod users {
-        //! Cacheとかしたい場合はCacheComponentとHaveCacheComponentを定義して、UserRepositoryの制約に加える。
-        //! 実際のプロダクトではこの辺のレイヤはもっと泥臭い感じになると思う
+    pub mod id {
+        use uuid::Uuid;
 
-        use component::storage::{UserStorageComponent, HaveUserStorageComponent};
-        use component::time::{TimeComponent, HaveTimeComponent};
-        use entity::user::{Email, Name, User};
-        use failure::Error;
+        /// 新しいEntityの識別子を払い出すレイヤ
+        pub trait IdGeneratorComponent {
+            fn generate(&self) -> Uuid;
+        }
+
+        /// これを実装(impl)している型はIdGeneratorComponentを返せる。抽象化されたGetter.
+        pub trait HaveIdGeneratorComponent {
+            type IdGeneratorComponent: IdGeneratorComponent;
+            fn id_generator_component(&self) -> &Self::IdGeneratorComponent;
+        }
+
+        /// IdGeneratorComponentをランダムなUUID(v4)で実装(impl)する型
+        pub struct UuidGen;
 
-        /// `HaveUserStorageComponent + HaveTimeComponent` は、+の左右のtraitを実装(impl)している型だけが、
-        /// UserRepositoryを実装できる事を意味している。
-        pub trait UserRepository: HaveUserStorageComponent + HaveTimeComponent {
-            fn get(&self, name: Name) -> Result<User, Error> {
-                Ok(self.user_storage_component().read(name)?)
+        impl IdGeneratorComponent for UuidGen {
+            fn generate(&self) -> Uuid {
+                Uuid::new_v4()
             }
+        }
+    }
 
-            fn insert(&mut self, name: Name, email: Email) -> Result<(), Error> {
-                let now = self.time_component().now();
-                let user = User {
-                    name: name.clone(),
-                    email,
-                    create_time: now,
-                    update_time: now,
-                };
-                self.user_storage_component_mut().save(name, user)?;
-                Ok(())
+    pub mod random {
+        use rand::RngCore;
+        use rand::rngs::OsRng;
+
+        /// トークンに使う文字。URLにそのまま入れられるように英数字だけにする。
+        const TOKEN_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+        /// 乱数を取り出すレイヤ
+        pub trait RandomComponent {
+            fn fill_bytes(&self, buf: &mut [u8]);
+
+            /// 英数字 `len` 文字のランダムな文字列
+            fn token(&self, len: usize) -> String {
+                // 62の倍数未満の値だけを使い、文字ごとの出やすさが偏らないようにする
+                let limit = (256 / TOKEN_CHARS.len() * TOKEN_CHARS.len()) as u8;
+                let mut token = String::with_capacity(len);
+                let mut byte = [0u8];
+                while token.len() < len {
+                    self.fill_bytes(&mut byte);
+                    if byte[0] < limit {
+                        token.push(TOKEN_CHARS[byte[0] as usize % TOKEN_CHARS.len()] as char);
+                    }
+                }
+                token
             }
         }
 
-        pub trait HaveUserRepository {
-            type UserRepository: UserRepository;
-            fn user_repository(&self) -> &Self::UserRepository;
-            fn user_repository_mut(&mut self) -> &mut Self::UserRepository;
+        /// これを実装(impl)している型はRandomComponentを返せる。抽象化されたGetter.
+        pub trait HaveRandomComponent {
+            type RandomComponent: RandomComponent;
+            fn random_component(&self) -> &Self::RandomComponent;
         }
 
-        /// traitの実装(impl)は具象型だけでなくジェネリクスのパラメータのみで実装する事も出来る。
-        /// これにより特定の条件を満たしている型全ての実装(impl)を用意する事が簡単に行える。
-        impl<T: HaveUserStorageComponent + HaveTimeComponent> UserRepository for T {}
+        /// RandomComponentをOSの乱数生成器で実装(impl)する型
+        #[derive(Debug, Default, Clone, Copy)]
+        pub struct OsRandom;
+
+        impl RandomComponent for OsRandom {
+            fn fill_bytes(&self, buf: &mut [u8]) {
+                OsRng.fill_bytes(buf);
+            }
+        }
     }
-}
 
-mod entity {
-    //! 一意性を持つデータを抽象化するレイヤ。
-    //! 一意性を持たない場合は値として扱い、entityにはしない（数値の1とか文字列とかと同じ扱いにする）
+    pub mod password {
+        use argon2::password_hash::rand_core::OsRng;
+        use argon2::password_hash::{self, PasswordHasher, PasswordVerifier, SaltString};
+        use argon2::Argon2;
+        use entity::credentials::PasswordHash;
+        use failure::Error;
 
-    pub mod user {
-        use chrono::prelude::*;
+        /// パスワードのハッシュ化と照合を行うレイヤ
+        pub trait PasswordHasherComponent {
+            fn hash(&self, password: &str) -> Result<PasswordHash, Error>;
+            fn verify(&self, password: &str, hash: &PasswordHash) -> Result<bool, Error>;
+        }
 
-        /// アカウント1つを表す型
-        #[derive(Debug, Clone)]
-        pub struct User {
-            pub name: Name,
-            pub email: Email,
-            pub create_time: DateTime<Local>,
-            pub update_time: DateTime<Local>,
+        /// これを実装(impl)している型はPasswordHasherComponentを返せる。抽象化されたGetter.
+        pub trait HavePasswordHasherComponent {
+            type PasswordHasherComponent: PasswordHasherComponent;
+            fn password_hasher_component(&self) -> &Self::PasswordHasherComponent;
         }
 
-        #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
-        pub struct Name {
-            pub name: String,
+        /// PasswordHasherComponentをArgon2idで実装(impl)する型。
+        /// ハッシュはソルトやパラメータを含むPHC文字列(`$argon2id$v=19$...`)で保存する。
+        #[derive(Default)]
+        pub struct Argon2Hasher {
+            argon2: Argon2<'static>,
         }
 
-        #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
-        pub struct Email {
-            pub email: String,
+        impl PasswordHasherComponent for Argon2Hasher {
+            fn hash(&self, password: &str) -> Result<PasswordHash, Error> {
+                let salt = SaltString::generate(&mut OsRng);
+                let hash = self
+                    .argon2
+                    .hash_password(password.as_bytes(), &salt)
+                    .map_err(|e| format_err!("failed to hash password: {}", e))?;
+                Ok(PasswordHash::new(hash.to_string()))
+            }
+
+            /// パスワードが違う時はOk(false)、保存されている値がPHC文字列として読めない時はエラー
+            fn verify(&self, password: &str, hash: &PasswordHash) -> Result<bool, Error> {
+                let parsed = password_hash::PasswordHash::new(hash.as_str())
+                    .map_err(|e| format_err!("invalid password hash: {}", e))?;
+                match self.argon2.verify_password(password.as_bytes(), &parsed) {
+                    Ok(()) => Ok(true),
+                    Err(password_hash::Error::Password) => Ok(false),
+                    Err(e) => bail!("failed to verify password: {}", e),
+                }
+            }
         }
     }
-}
 
-mod env {
-    use component::time::{HaveTimeComponent, Chrono};
-    use component::storage::{HaveUserStorageComponent, MemoryStorage};
-    use repository::users::{HaveUserRepository};
+    pub mod crypto {
+        //! 暗号化と署名。保存する値の一部のフィールドの暗号化や、トークンの署名に使う。
+        //! 鍵は秘密の値から作り、鍵そのものはこのレイヤの外に出さない。
 
-    /// Cake Pattern での環境型
-    /// この構造体に各レイヤーを担当するオブジェクトを格納する。
-    pub struct RealWorld {
-        time_component: Chrono,
-        storage_component: MemoryStorage,
-    }
+        use aes_gcm::aead::{Aead, KeyInit};
+        use aes_gcm::{Aes256Gcm, Key, Nonce};
+        use component::random::{OsRandom, RandomComponent};
+        use component::secrets::Secret;
+        use failure::Error;
+        use hkdf::Hkdf;
+        use hmac::{Hmac, Mac};
+        use sha2::Sha256;
 
-    impl RealWorld {
-        pub fn new() -> RealWorld {
-            RealWorld {
-                time_component: Chrono,
-                storage_component: MemoryStorage::new(),
+        /// AES-GCMのnonceの長さ
+        const NONCE_LEN: usize = 12;
+
+        /// 暗号化・署名を行うレイヤ
+        pub trait CryptoComponent {
+            /// 暗号文には復号と改竄の検出に必要な情報(nonce等)も含める
+            fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error>;
+            /// 鍵が違ったり改竄されていたりする暗号文はエラーにする
+            fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Error>;
+            fn sign(&self, message: &[u8]) -> Vec<u8>;
+            fn verify(&self, message: &[u8], signature: &[u8]) -> bool;
+        }
+
+        /// これを実装(impl)している型はCryptoComponentを返せる。抽象化されたGetter.
+        pub trait HaveCryptoComponent {
+            type CryptoComponent: CryptoComponent;
+            fn crypto_component(&self) -> &Self::CryptoComponent;
+        }
+
+        /// AES-256-GCMで暗号化し、HMAC-SHA256で署名するCryptoComponent実装。
+        /// 暗号化の鍵と署名の鍵は、同じ秘密の値からHKDFで用途毎に別々に導出する。
+        /// nonceは `R` から取る。鍵の展開済みの表が大きいので、cipherはヒープに置く
+        #[derive(Clone)]
+        pub struct AesGcmCrypto<R = OsRandom> {
+            cipher: Box<Aes256Gcm>,
+            signing_key: [u8; 32],
+            random: R,
+        }
+
+        impl AesGcmCrypto {
+            pub fn new(secret: &Secret) -> AesGcmCrypto {
+                AesGcmCrypto::with_random(secret, OsRandom)
+            }
+
+            /// プロセス毎に作った乱数の鍵で作る。再起動すると以前の暗号文や署名は使えなくなる。
+            pub fn ephemeral() -> AesGcmCrypto {
+                AesGcmCrypto::new(&Secret::new(OsRandom.token(64)))
             }
         }
-    }
 
-    impl HaveTimeComponent for RealWorld {
-        type TimeComponent = Chrono;
-        fn time_component(&self) -> &Chrono {
-            &self.time_component
+        impl<R: RandomComponent> AesGcmCrypto<R> {
+            pub fn with_random(secret: &Secret, random: R) -> AesGcmCrypto<R> {
+                let key = derive_key(secret.expose(), "encrypt");
+                AesGcmCrypto {
+                    cipher: Box::new(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))),
+                    signing_key: derive_key(secret.expose(), "sign"),
+                    random,
+                }
+            }
         }
-    }
 
-    impl HaveUserStorageComponent for RealWorld {
-        type UserStorageComponent = MemoryStorage;
-        fn user_storage_component(&self) -> &MemoryStorage {
-            &self.storage_component
+        /// 用途をHKDFのinfoに入れるので、同じ秘密の値からでも用途が違えば関係の無い鍵になる
+        fn derive_key(secret: &str, purpose: &str) -> [u8; 32] {
+            let mut key = [0; 32];
+            Hkdf::<Sha256>::new(None, secret.as_bytes())
+                .expand(purpose.as_bytes(), &mut key)
+                .expect("HKDF-SHA256 can expand 32 bytes");
+            key
+        }
+
+        impl<R: RandomComponent> CryptoComponent for AesGcmCrypto<R> {
+            fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+                let mut nonce = [0; NONCE_LEN];
+                self.random.fill_bytes(&mut nonce);
+                let ciphertext = self
+                    .cipher
+                    .encrypt(Nonce::from_slice(&nonce), plaintext)
+                    .map_err(|_| format_err!("encryption failed"))?;
+                Ok(nonce.iter().chain(ciphertext.iter()).cloned().collect())
+            }
+
+            fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+                if ciphertext.len() < NONCE_LEN {
+                    bail!("ciphertext is too short");
+                }
+                let (nonce, ciphertext) = ciphertext.split_at(NONCE_LEN);
+                self.cipher
+                    .decrypt(Nonce::from_slice(nonce), ciphertext)
+                    .map_err(|_| format_err!("decryption failed"))
+            }
+
+            fn sign(&self, message: &[u8]) -> Vec<u8> {
+                self.mac(message).finalize().into_bytes().to_vec()
+            }
+
+            /// 比較にかかる時間からは署名の中身が分からない
+            fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+                self.mac(message).verify_slice(signature).is_ok()
+            }
         }
 
-        fn user_storage_component_mut(&mut self) -> &mut MemoryStorage {
-            &mut self.storage_component
+        impl<R> AesGcmCrypto<R> {
+            fn mac(&self, message: &[u8]) -> Hmac<Sha256> {
+                let mut mac =
+                    <Hmac<Sha256> as Mac>::new_from_slice(&self.signing_key).expect("HMAC accepts any key length");
+                mac.update(message);
+                mac
+            }
         }
     }
 
-    impl HaveUserRepository for RealWorld {
-        type UserRepository = Self;
-        fn user_repository(&self) -> &Self {
-            self
+    pub mod environment {
+        //! プロセスの環境(環境変数、ホスト名、プロセスID)。
+        //! グローバルな状態なので、直接触るのはここだけにして、他はこのcomponentを通して読む。
+
+        use std::env;
+        use std::fs;
+        use std::process;
+
+        /// プロセスの環境を読むレイヤ
+        pub trait EnvironmentComponent {
+            /// 設定されていないか、UTF-8でなければNone
+            fn var(&self, key: &str) -> Option<String>;
+            fn hostname(&self) -> String;
+            fn pid(&self) -> u32;
+
+            /// ログやロックの持ち主の表示に使う、このプロセスを表す名前
+            fn instance_name(&self) -> String {
+                format!("{}:{}", self.hostname(), self.pid())
+            }
         }
 
-        fn user_repository_mut(&mut self) -> &mut Self {
-            self
+        /// 実際のプロセスの環境を読むEnvironmentComponent実装
+        #[derive(Debug, Default, Clone, Copy)]
+        pub struct ProcessEnvironment;
+
+        impl EnvironmentComponent for ProcessEnvironment {
+            fn var(&self, key: &str) -> Option<String> {
+                env::var(key).ok()
+            }
+
+            /// `HOSTNAME` が無ければLinuxのカーネルから読む。どちらも無ければ `localhost`
+            fn hostname(&self) -> String {
+                self.var("HOSTNAME")
+                    .or_else(|| fs::read_to_string("/proc/sys/kernel/hostname").ok())
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or_else(|| "localhost".to_string())
+            }
+
+            fn pid(&self) -> u32 {
+                process::id()
+            }
         }
     }
-}
 
-fn main() {
-    use repository::users::{UserRepository, HaveUserRepository};
-    use entity::user::{Email, Name};
-    use env::RealWorld;
+    pub mod config {
+        //! 実行時の設定。TOMLファイルを読んだ後に環境変数で上書きする。
+        //!
+        //! * `LAYERED_CONFIG`: 設定ファイルのパス。無ければファイルは読まない
+        //! * `LAYERED_STORAGE_PATH`: ユーザーを保存するファイルのパス。無ければメモリ上に保存する
+        //! * `LAYERED_SNAPSHOT_PATH`: メモリ上に保存する時、終了する前にユーザーを書き出し、起動した時に読み込むファイルのパス
+        //! * `LAYERED_MEMORY_SHARDS`: メモリ上に保存する時、ユーザーを分けて持つシャードの数。
+        //!   無ければ1つのロックで持つ。スナップショットとは一緒に使えない
+        //! * `LAYERED_CACHE_POLICY`: ユーザーのストレージの前に置くキャッシュの方針(`read-through`, `write-through`, `write-back`)
+        //! * `LAYERED_JOBS_PATH`: 後から行うジョブを保存するファイルのパス。無ければメモリ上に積むので、終了すると消える
+        //! * `LAYERED_PAGE_SIZE`: 一覧取得の1ページの件数
+        //! * `LAYERED_FEATURES`: 有効にする機能名のカンマ区切り
+        //! * `LAYERED_ROLLOUTS`: 一部のユーザーにだけ有効にする機能の `機能名=割合(%)` のカンマ区切り
+        //! * `LAYERED_SMTP_HOST`, `LAYERED_SMTP_PORT`: メールを送るSMTPサーバー
+        //! * `LAYERED_SMTP_USER`: SMTP AUTHのユーザー名。パスワードは秘密の値 `smtp_password` から読む
+        //! * `LAYERED_MAIL_FROM`: 送信元のメールアドレス
+        //! * `LAYERED_NOTIFIER`: 通知の送り先(`console`, `email`, `webhook`)
+        //! * `LAYERED_WEBHOOK_URL`: `webhook` で通知する時の送り先URL
+        //! * `LAYERED_EVENT_WEBHOOK_URLS`: ユーザーのイベントをWebhookで送るURLのカンマ区切り。
+        //!   本文は秘密の値 `webhook_signing_key` で署名する
+        //! * `LAYERED_EVENT_WEBHOOK_MAX_ATTEMPTS`: Webhookを1回の配信で試す回数
+        //! * `LAYERED_QUEUE_BROKERS`: ドメインイベントを流すKafkaのブローカーのカンマ区切り。無ければメモリ上のキューを使う
+        //! * `LAYERED_SECRETS_PATH`: 秘密の値を書いたTOMLファイルのパス。無ければ環境変数から読む
+        //! * `LAYERED_LOCK_URL`: 複数のインスタンスで共有するロックのRedisのURL。無ければプロセス内のロックを使う
+        //! * `LAYERED_CACHE_URL`: 複数のインスタンスで共有するユーザーのキャッシュのRedisのURL。無ければプロセス内に持つ
+        //! * `LAYERED_REDIS_POOL_SIZE`: Redisへ同時に張る接続の上限
+        //! * `LAYERED_REMOTE_STORAGE_URL`: ユーザーをREST APIで持つ別のサービスのURL。あればファイルより優先する
+        //! * `LAYERED_REMOTE_TIMEOUT_MS`: 別のサービス・DB・SMTPサーバーの1回の呼び出しを待つ時間の上限(ミリ秒)
+        //! * `LAYERED_ALLOWED_EMAIL_DOMAINS`: 登録できるメールアドレスのドメインのカンマ区切り。無ければ制限しない
+        //! * `LAYERED_GEOIP_DATABASE`: IPアドレスの場所を引くMaxMindのデータベースのパス。無ければ場所は引かない
+        //! * `LAYERED_TRUST_ACTOR_HEADER`: `x-user-id` (HTTPのヘッダ、gRPCのメタデータ)をそのまま信じるか(`1`/`0`)。
+        //!   既定では信じずにセッションかAPIトークンだけで認証する。前段のゲートウェイで認証している時だけ `1` にする
+        //! * `LAYERED_PUBLIC_URL`: 利用者から見たこのサービスのURL。メールに載せるリンクやCookieの `Secure` の判断に使う
 
-    let mut app = RealWorld::new();
+        use component::cache::CachePolicy;
+        use component::environment::EnvironmentComponent;
+        use component::filesystem::FileSystemComponent;
+        use failure::Error;
+        use std::collections::{BTreeMap, BTreeSet};
+        use std::path::{Path, PathBuf};
+        use std::str::FromStr;
+        use std::time::Duration;
+        use toml;
 
-    let name = Name {
-        name: "user_a".to_string(),
-    };
+        /// 設定値を型付きで返すレイヤ
+        pub trait ConfigComponent {
+            fn storage_path(&self) -> Option<&Path>;
+            fn snapshot_path(&self) -> Option<&Path>;
+            /// Noneなら1つのロックで持つMemoryStorage、Someならその数のシャードに分けるConcurrentMemoryStorage
+            fn memory_shards(&self) -> Option<usize>;
+            fn cache_policy(&self) -> CachePolicy;
+            fn jobs_path(&self) -> Option<&Path>;
+            fn page_size(&self) -> usize;
+            /// 一部のユーザーにだけ有効にする機能と、その割合(0〜100)
+            fn rollouts(&self) -> &BTreeMap<String, u8>;
+            fn smtp_host(&self) -> &str;
+            fn smtp_port(&self) -> u16;
+            fn smtp_user(&self) -> Option<&str>;
+            fn mail_from(&self) -> &str;
+            fn notifier(&self) -> NotifierKind;
+            fn webhook_url(&self) -> Option<&str>;
+            fn event_webhook_urls(&self) -> &[String];
+            fn event_webhook_max_attempts(&self) -> u32;
+            fn queue_brokers(&self) -> &[String];
+            fn secrets_path(&self) -> Option<&Path>;
+            fn lock_url(&self) -> Option<&str>;
+            fn cache_url(&self) -> Option<&str>;
+            fn redis_pool_size(&self) -> usize;
+            /// ユーザーをPostgreSQLに保存する時の接続先
+            fn database_url(&self) -> Option<&str>;
+            fn remote_storage_url(&self) -> Option<&str>;
+            fn remote_timeout(&self) -> Duration;
+            fn allowed_email_domains(&self) -> &[String];
+            fn geoip_database(&self) -> Option<&Path>;
+            fn trust_actor_header(&self) -> bool;
+            /// メールに載せる画面のURLの先頭。末尾の `/` は付けない
+            fn public_url(&self) -> &str;
+        }
 
-    app.user_repository_mut().insert(
-        name.clone(),
-        Email {
-            email: "user_a@example.com".to_string(),
-        },
-    ).unwrap();
-    println!("{:?}", app.get(name));
-}
+        /// アカウントの変更をどこへ通知するか
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+        #[serde(rename_all = "lowercase")]
+        pub enum NotifierKind {
+            #[default]
+            Console,
+            Email,
+            Webhook,
+        }
 
-#[cfg(test)]
-mod tests {
-    mod mock {
-        pub mod time {
-            use chrono::prelude::*;
-            use component::time::TimeComponent;
-            use std::str::FromStr;
+        impl FromStr for NotifierKind {
+            type Err = Error;
+            fn from_str(s: &str) -> Result<NotifierKind, Error> {
+                match s {
+                    "console" => Ok(NotifierKind::Console),
+                    "email" => Ok(NotifierKind::Email),
+                    "webhook" => Ok(NotifierKind::Webhook),
+                    _ => bail!("unknown notifier: {}", s),
+                }
+            }
+        }
 
-            /// テスト用のTimeComponent実装。
-            /// now()が特定の日時しか返さない。
-            pub struct MockTime;
+        /// これを実装(impl)している型はConfigComponentを返せる。抽象化されたGetter.
+        pub trait HaveConfigComponent {
+            type ConfigComponent: ConfigComponent;
+            fn config_component(&self) -> &Self::ConfigComponent;
+        }
 
-            impl TimeComponent for MockTime {
-                fn now(&self) -> DateTime<Local> {
-                    DateTime::from_str("2018-08-20T10:00:00 +0900").unwrap()
+        /// ConfigComponentを設定ファイルと環境変数から作る値で実装(impl)する型
+        #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+        #[serde(default, deny_unknown_fields)]
+        pub struct Config {
+            pub storage_path: Option<PathBuf>,
+            pub snapshot_path: Option<PathBuf>,
+            pub memory_shards: Option<usize>,
+            pub cache_policy: CachePolicy,
+            pub jobs_path: Option<PathBuf>,
+            pub page_size: usize,
+            pub features: BTreeSet<String>,
+            pub rollouts: BTreeMap<String, u8>,
+            pub smtp_host: String,
+            pub smtp_port: u16,
+            pub smtp_user: Option<String>,
+            pub mail_from: String,
+            pub notifier: NotifierKind,
+            pub webhook_url: Option<String>,
+            pub event_webhook_urls: Vec<String>,
+            pub event_webhook_max_attempts: u32,
+            pub queue_brokers: Vec<String>,
+            pub secrets_path: Option<PathBuf>,
+            pub lock_url: Option<String>,
+            pub cache_url: Option<String>,
+            pub redis_pool_size: usize,
+            pub database_url: Option<String>,
+            pub remote_storage_url: Option<String>,
+            pub remote_timeout_ms: u64,
+            pub allowed_email_domains: Vec<String>,
+            pub geoip_database: Option<PathBuf>,
+            pub trust_actor_header: bool,
+            pub public_url: String,
+        }
+
+        impl Default for Config {
+            fn default() -> Config {
+                Config {
+                    storage_path: None,
+                    snapshot_path: None,
+                    memory_shards: None,
+                    cache_policy: CachePolicy::default(),
+                    jobs_path: None,
+                    page_size: 20,
+                    features: BTreeSet::new(),
+                    rollouts: BTreeMap::new(),
+                    smtp_host: "localhost".to_string(),
+                    smtp_port: 25,
+                    smtp_user: None,
+                    mail_from: "noreply@localhost".to_string(),
+                    notifier: NotifierKind::default(),
+                    webhook_url: None,
+                    event_webhook_urls: Vec::new(),
+                    event_webhook_max_attempts: 3,
+                    queue_brokers: Vec::new(),
+                    secrets_path: None,
+                    lock_url: None,
+                    cache_url: None,
+                    redis_pool_size: 8,
+                    database_url: None,
+                    remote_storage_url: None,
+                    remote_timeout_ms: 5000,
+                    allowed_email_domains: Vec::new(),
+                    geoip_database: None,
+                    trust_actor_header: false,
+                    public_url: "http://127.0.0.1:8080".to_string(),
                 }
             }
         }
 
-        pub mod env {
-            use super::time::MockTime;
-            use component::time::HaveTimeComponent;
-            use component::storage::{HaveUserStorageComponent, MemoryStorage};
-            use repository::users::{HaveUserRepository};
+        impl Config {
+            /// 環境変数から設定を読み込む。`LAYERED_CONFIG` のファイルは `fs` から読む。
+            pub fn load<E: EnvironmentComponent, F: FileSystemComponent>(env: &E, fs: &F) -> Result<Config, Error> {
+                let config = match env.var("LAYERED_CONFIG") {
+                    Some(path) => match fs.read(Path::new(&path))? {
+                        Some(source) => Config::from_toml(&source)?,
+                        None => bail!("config file not found: {}", path),
+                    },
+                    None => Config::default(),
+                };
+                config.override_with(|key| env.var(key))
+            }
 
-            /// テスト用の Cake Pattern での環境型
-            /// この構造体に各レイヤーを担当するオブジェクトを格納する。
-            pub struct TestWorld {
-                time_component: MockTime,
-                storage_component: MemoryStorage,
+            /// 書かれていない項目はデフォルト値になる
+            pub fn from_toml(source: &str) -> Result<Config, Error> {
+                Ok(toml::from_str(source)?)
             }
 
-            impl TestWorld {
-                pub fn new() -> TestWorld {
-                    TestWorld {
-                        time_component: MockTime,
-                        storage_component: MemoryStorage::new(),
-                    }
+            /// `var` が値を返した項目だけを上書きする
+            pub fn override_with<F: Fn(&str) -> Option<String>>(mut self, var: F) -> Result<Config, Error> {
+                if let Some(path) = var("LAYERED_STORAGE_PATH") {
+                    self.storage_path = Some(PathBuf::from(path));
+                }
+                if let Some(path) = var("LAYERED_SNAPSHOT_PATH") {
+                    self.snapshot_path = Some(PathBuf::from(path));
+                }
+                if let Some(shards) = var("LAYERED_MEMORY_SHARDS") {
+                    let shards = shards
+                        .parse()
+                        .map_err(|_| format_err!("invalid LAYERED_MEMORY_SHARDS: {}", shards))?;
+                    self.memory_shards = Some(shards);
+                }
+                if let Some(policy) = var("LAYERED_CACHE_POLICY") {
+                    self.cache_policy = policy.parse()?;
+                }
+                if let Some(path) = var("LAYERED_JOBS_PATH") {
+                    self.jobs_path = Some(PathBuf::from(path));
+                }
+                if let Some(size) = var("LAYERED_PAGE_SIZE") {
+                    self.page_size = size
+                        .parse()
+                        .map_err(|_| format_err!("invalid LAYERED_PAGE_SIZE: {}", size))?;
+                }
+                if let Some(features) = var("LAYERED_FEATURES") {
+                    self.features = split_list(&features).into_iter().collect();
+                }
+                if let Some(rollouts) = var("LAYERED_ROLLOUTS") {
+                    self.rollouts = split_list(&rollouts)
+                        .into_iter()
+                        .map(|rollout| {
+                            let mut parts = rollout.splitn(2, '=');
+                            match (parts.next(), parts.next().and_then(|p| p.trim().parse().ok())) {
+                                (Some(feature), Some(percentage)) => Ok((feature.trim().to_string(), percentage)),
+                                _ => Err(format_err!("invalid LAYERED_ROLLOUTS: {}", rollout)),
+                            }
+                        })
+                        .collect::<Result<_, Error>>()?;
+                }
+                if let Some(host) = var("LAYERED_SMTP_HOST") {
+                    self.smtp_host = host;
+                }
+                if let Some(port) = var("LAYERED_SMTP_PORT") {
+                    self.smtp_port = port
+                        .parse()
+                        .map_err(|_| format_err!("invalid LAYERED_SMTP_PORT: {}", port))?;
+                }
+                if let Some(user) = var("LAYERED_SMTP_USER") {
+                    self.smtp_user = Some(user);
+                }
+                if let Some(from) = var("LAYERED_MAIL_FROM") {
+                    self.mail_from = from;
+                }
+                if let Some(notifier) = var("LAYERED_NOTIFIER") {
+                    self.notifier = notifier.parse()?;
+                }
+                if let Some(url) = var("LAYERED_WEBHOOK_URL") {
+                    self.webhook_url = Some(url);
+                }
+                if let Some(urls) = var("LAYERED_EVENT_WEBHOOK_URLS") {
+                    self.event_webhook_urls = split_list(&urls);
+                }
+                if let Some(attempts) = var("LAYERED_EVENT_WEBHOOK_MAX_ATTEMPTS") {
+                    self.event_webhook_max_attempts = attempts
+                        .parse()
+                        .map_err(|_| format_err!("invalid LAYERED_EVENT_WEBHOOK_MAX_ATTEMPTS: {}", attempts))?;
+                }
+                if let Some(brokers) = var("LAYERED_QUEUE_BROKERS") {
+                    self.queue_brokers = split_list(&brokers);
+                }
+                if let Some(path) = var("LAYERED_SECRETS_PATH") {
+                    self.secrets_path = Some(PathBuf::from(path));
+                }
+                if let Some(url) = var("LAYERED_LOCK_URL") {
+                    self.lock_url = Some(url);
+                }
+                if let Some(url) = var("LAYERED_CACHE_URL") {
+                    self.cache_url = Some(url);
+                }
+                if let Some(size) = var("LAYERED_REDIS_POOL_SIZE") {
+                    self.redis_pool_size = size
+                        .parse()
+                        .map_err(|_| format_err!("invalid LAYERED_REDIS_POOL_SIZE: {}", size))?;
+                }
+                if let Some(url) = var("LAYERED_DATABASE_URL") {
+                    self.database_url = Some(url);
+                }
+                if let Some(url) = var("LAYERED_REMOTE_STORAGE_URL") {
+                    self.remote_storage_url = Some(url);
+                }
+                if let Some(timeout) = var("LAYERED_REMOTE_TIMEOUT_MS") {
+                    self.remote_timeout_ms = timeout
+                        .parse()
+                        .map_err(|_| format_err!("invalid LAYERED_REMOTE_TIMEOUT_MS: {}", timeout))?;
+                }
+                if let Some(domains) = var("LAYERED_ALLOWED_EMAIL_DOMAINS") {
+                    self.allowed_email_domains = split_list(&domains);
+                }
+                if let Some(path) = var("LAYERED_GEOIP_DATABASE") {
+                    self.geoip_database = Some(PathBuf::from(path));
+                }
+                if let Some(trust) = var("LAYERED_TRUST_ACTOR_HEADER") {
+                    self.trust_actor_header = match trust.as_str() {
+                        "1" | "true" => true,
+                        "0" | "false" => false,
+                        _ => bail!("invalid LAYERED_TRUST_ACTOR_HEADER: {}", trust),
+                    };
                 }
+                if let Some(url) = var("LAYERED_PUBLIC_URL") {
+                    self.public_url = url;
+                }
+                if self.page_size == 0 {
+                    bail!("page_size must be greater than 0");
+                }
+                if self.memory_shards == Some(0) {
+                    bail!("memory_shards must be greater than 0");
+                }
+                if self.memory_shards.is_some() && self.snapshot_path.is_some() {
+                    bail!("memory_shards can not be used with snapshot_path");
+                }
+                if self.event_webhook_max_attempts == 0 {
+                    bail!("event_webhook_max_attempts must be greater than 0");
+                }
+                if self.redis_pool_size == 0 {
+                    bail!("redis_pool_size must be greater than 0");
+                }
+                if self.remote_timeout_ms == 0 {
+                    bail!("remote_timeout_ms must be greater than 0");
+                }
+                if let Some((feature, _)) = self.rollouts.iter().find(|&(_, &percentage)| percentage > 100) {
+                    bail!("rollout of {} must be at most 100", feature);
+                }
+                Ok(self)
             }
+        }
 
-            impl HaveTimeComponent for TestWorld {
-                type TimeComponent = MockTime;
-                fn time_component(&self) -> &MockTime {
-                    &self.time_component
-                }
+        /// カンマ区切りの値を、前後の空白と空の要素を除いて分ける
+        fn split_list(list: &str) -> Vec<String> {
+            list.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        }
+
+        impl ConfigComponent for Config {
+            fn storage_path(&self) -> Option<&Path> {
+                self.storage_path.as_deref()
             }
 
-            impl HaveUserStorageComponent for TestWorld {
-                type UserStorageComponent = MemoryStorage;
-                fn user_storage_component(&self) -> &MemoryStorage {
-                    &self.storage_component
-                }
+            fn snapshot_path(&self) -> Option<&Path> {
+                self.snapshot_path.as_deref()
+            }
 
-                fn user_storage_component_mut(&mut self) -> &mut MemoryStorage {
-                    &mut self.storage_component
-                }
+            fn memory_shards(&self) -> Option<usize> {
+                self.memory_shards
             }
 
-            impl HaveUserRepository for TestWorld {
-                type UserRepository = Self;
-                fn user_repository(&self) -> &Self {
-                    self
-                }
+            fn cache_policy(&self) -> CachePolicy {
+                self.cache_policy
+            }
 
-                fn user_repository_mut(&mut self) -> &mut Self {
-                    self
-                }
+            fn jobs_path(&self) -> Option<&Path> {
+                self.jobs_path.as_deref()
             }
-        }
-    }
 
-    use self::mock::env::TestWorld;
-    use chrono::prelude::*;
-    use entity::user::{Email, Name};
-    use repository::users::{UserRepository, HaveUserRepository};
-    use std::str::FromStr;
+            fn page_size(&self) -> usize {
+                self.page_size
+            }
 
-    #[test]
-    fn add_user() {
-        let mut app = TestWorld::new();
+            fn rollouts(&self) -> &BTreeMap<String, u8> {
+                &self.rollouts
+            }
 
-        let name = Name {
-            name: "user1".to_string(),
-        };
-        let email = Email {
-            email: "user1@example.com".to_string(),
-        };
+            fn smtp_host(&self) -> &str {
+                &self.smtp_host
+            }
 
-        app.user_repository_mut().insert(name.clone(), email.clone()).unwrap();
+            fn smtp_port(&self) -> u16 {
+                self.smtp_port
+            }
 
-        let user = app.user_repository().get(name.clone()).unwrap();
-        assert_eq!(user.name, name);
-        assert_eq!(user.email, email);
-        assert_eq!(
-            user.create_time,
-            DateTime::<Local>::from_str("2018-08-20T10:00:00 +0900").unwrap()
-        );
-        assert_eq!(
-            user.update_time,
-            DateTime::<Local>::from_str("2018-08-20T10:00:00 +0900").unwrap()
-        );
+            fn smtp_user(&self) -> Option<&str> {
+                self.smtp_user.as_deref()
+            }
+
+            fn mail_from(&self) -> &str {
+                &self.mail_from
+            }
+
+            fn notifier(&self) -> NotifierKind {
+                self.notifier
+            }
+
+            fn webhook_url(&self) -> Option<&str> {
+                self.webhook_url.as_deref()
+            }
+
+            fn event_webhook_urls(&self) -> &[String] {
+                &self.event_webhook_urls
+            }
+
+            fn event_webhook_max_attempts(&self) -> u32 {
+                self.event_webhook_max_attempts
+            }
+
+            fn queue_brokers(&self) -> &[String] {
+                &self.queue_brokers
+            }
+
+            fn secrets_path(&self) -> Option<&Path> {
+                self.secrets_path.as_deref()
+            }
+
+            fn lock_url(&self) -> Option<&str> {
+                self.lock_url.as_deref()
+            }
+
+            fn cache_url(&self) -> Option<&str> {
+                self.cache_url.as_deref()
+            }
+
+            fn redis_pool_size(&self) -> usize {
+                self.redis_pool_size
+            }
+
+            fn database_url(&self) -> Option<&str> {
+                self.database_url.as_deref()
+            }
+
+            fn remote_storage_url(&self) -> Option<&str> {
+                self.remote_storage_url.as_deref()
+            }
+
+            fn remote_timeout(&self) -> Duration {
+                Duration::from_millis(self.remote_timeout_ms)
+            }
+
+            fn allowed_email_domains(&self) -> &[String] {
+                &self.allowed_email_domains
+            }
+
+            fn geoip_database(&self) -> Option<&Path> {
+                self.geoip_database.as_deref()
+            }
+
+            fn trust_actor_header(&self) -> bool {
+                self.trust_actor_header
+            }
+
+            fn public_url(&self) -> &str {
+                self.public_url.trim_end_matches('/')
+            }
+        }
+    }
+
+    pub mod secrets {
+        //! DBのパスワードや署名鍵などの秘密の値。設定ファイルには書かず、名前で引く。
+
+        use component::environment::{EnvironmentComponent, ProcessEnvironment};
+        use component::filesystem::{FileSystemComponent, StdFileSystem};
+        use failure::Error;
+        use std::collections::BTreeMap;
+        use std::fmt;
+        use std::path::Path;
+        use toml;
+
+        /// 秘密の値。ログ等にうっかり出ないように、Debugでは中身を表示しない。
+        #[derive(Clone, PartialEq, Eq)]
+        pub struct Secret {
+            value: String,
+        }
+
+        impl Secret {
+            pub fn new<S: Into<String>>(value: S) -> Secret {
+                Secret { value: value.into() }
+            }
+
+            /// 中身が必要な所でだけ呼ぶ
+            pub fn expose(&self) -> &str {
+                &self.value
+            }
+        }
+
+        impl fmt::Debug for Secret {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("Secret(***)")
+            }
+        }
+
+        /// 名前から秘密の値を引くレイヤ
+        pub trait SecretsComponent {
+            /// 無ければNone
+            fn secret(&self, name: &str) -> Option<Secret>;
+
+            /// 無ければエラー
+            fn require(&self, name: &str) -> Result<Secret, Error> {
+                match self.secret(name) {
+                    Some(secret) => Ok(secret),
+                    None => bail!("secret not found: {}", name),
+                }
+            }
+        }
+
+        /// 環境変数から読むSecretsComponent実装。
+        /// `smtp_password` は `LAYERED_SECRET_SMTP_PASSWORD` から読む。
+        pub struct EnvSecrets<E = ProcessEnvironment> {
+            environment: E,
+        }
+
+        impl<E: EnvironmentComponent> EnvSecrets<E> {
+            pub fn with_environment(environment: E) -> EnvSecrets<E> {
+                EnvSecrets { environment }
+            }
+        }
+
+        impl<E: EnvironmentComponent> SecretsComponent for EnvSecrets<E> {
+            fn secret(&self, name: &str) -> Option<Secret> {
+                self.environment
+                    .var(&format!("LAYERED_SECRET_{}", name.to_uppercase()))
+                    .map(Secret::new)
+            }
+        }
+
+        /// `名前 = "値"` の形で書いたTOMLファイルから読むSecretsComponent実装。
+        /// ファイルは開いた時に一度だけ読む。
+        pub struct FileVault {
+            secrets: BTreeMap<String, String>,
+        }
+
+        impl FileVault {
+            pub fn open<P: AsRef<Path>>(path: P) -> Result<FileVault, Error> {
+                FileVault::open_with(path, StdFileSystem)
+            }
+
+            /// `fs` の上のファイルを開く
+            pub fn open_with<P: AsRef<Path>, F: FileSystemComponent>(path: P, fs: F) -> Result<FileVault, Error> {
+                let path = path.as_ref();
+                match fs.read(path)? {
+                    Some(source) => Ok(FileVault {
+                        secrets: toml::from_str(&source)?,
+                    }),
+                    None => bail!("secrets file not found: {}", path.display()),
+                }
+            }
+        }
+
+        impl SecretsComponent for FileVault {
+            fn secret(&self, name: &str) -> Option<Secret> {
+                self.secrets.get(name).map(|value| Secret::new(value.as_str()))
+            }
+        }
+    }
+
+    pub mod feature_flag {
+        //! 機能を段階的に公開するためのフラグ。
+        //! 同じユーザーには常に同じ結果を返すので、リクエスト毎に挙動が変わる事はない。
+
+        use entity::user::{User, UserId};
+        use std::collections::BTreeMap;
+
+        /// ユーザー毎に機能が有効かどうかを判定するレイヤ
+        pub trait FeatureFlagComponent {
+            fn is_enabled(&self, flag: &str, user: &User) -> bool;
+        }
+
+        /// これを実装(impl)している型はFeatureFlagComponentを返せる。抽象化されたGetter.
+        pub trait HaveFeatureFlagComponent {
+            type FeatureFlagComponent: FeatureFlagComponent;
+            fn feature_flag_component(&self) -> &Self::FeatureFlagComponent;
+        }
+
+        /// 機能毎に決めた割合のユーザーにだけ有効にするFeatureFlagComponent実装。
+        /// ユーザーIDと機能名からユーザーを0〜99のバケットに振り分けるので、割合を増やしても一度有効になったユーザーは有効のまま。
+        #[derive(Debug, Clone, Default)]
+        pub struct PercentageRollout {
+            percentages: BTreeMap<String, u8>,
+        }
+
+        impl PercentageRollout {
+            /// 100を超える割合は100として扱う
+            pub fn new<I: IntoIterator<Item = (String, u8)>>(percentages: I) -> PercentageRollout {
+                PercentageRollout {
+                    percentages: percentages.into_iter().map(|(flag, p)| (flag, p.min(100))).collect(),
+                }
+            }
+        }
+
+        impl FeatureFlagComponent for PercentageRollout {
+            fn is_enabled(&self, flag: &str, user: &User) -> bool {
+                match self.percentages.get(flag) {
+                    Some(&percentage) => bucket(flag, &user.id) < percentage,
+                    None => false,
+                }
+            }
+        }
+
+        /// FNV-1aでハッシュする。プロセスやRustのバージョンが変わっても同じ値になるように、標準のHasherは使わない。
+        fn bucket(flag: &str, id: &UserId) -> u8 {
+            let hash = flag
+                .as_bytes()
+                .iter()
+                .chain(id.as_uuid().as_bytes())
+                .fold(0xcbf2_9ce4_8422_2325u64, |hash, &b| {
+                    (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+                });
+            (hash % 100) as u8
+        }
+    }
+
+    pub mod rate_limit {
+        //! 同じ相手(ユーザーやトークン)からの試行回数を制限する。
+        //! 時刻はTimeComponentから取るので、テストではMockTimeで制限の回復を確かめられる。
+
+        use chrono::prelude::*;
+        use chrono::Duration;
+        use component::time::{Chrono, TimeComponent};
+        use std::collections::BTreeMap;
+        use std::sync::{Mutex, PoisonError};
+
+        /// 試行してよいかどうか
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum RateLimit {
+            Allowed,
+            /// `retry_after` 後には1回試行できるようになる
+            Limited { retry_after: Duration },
+        }
+
+        /// キー毎に試行回数を数えるレイヤ
+        pub trait RateLimiterComponent {
+            /// 試行してよければ1回分を消費してAllowedを返す
+            fn check_and_consume(&self, key: &str) -> RateLimit;
+        }
+
+        /// これを実装(impl)している型はRateLimiterComponentを返せる。抽象化されたGetter.
+        pub trait HaveRateLimiterComponent {
+            type RateLimiterComponent: RateLimiterComponent;
+            fn rate_limiter_component(&self) -> &Self::RateLimiterComponent;
+        }
+
+        /// キー毎のバケツの中身
+        struct Bucket {
+            tokens: u32,
+            refilled_at: DateTime<Utc>,
+        }
+
+        /// トークンバケットでRateLimiterComponentを実装(impl)する型。
+        /// キー毎に `capacity` 回まで続けて試行でき、`refill_every` 経つ毎に1回分ずつ回復する。
+        pub struct TokenBucket<T = Chrono> {
+            capacity: u32,
+            refill_every: Duration,
+            clock: T,
+            buckets: Mutex<BTreeMap<String, Bucket>>,
+        }
+
+        impl TokenBucket {
+            pub fn new(capacity: u32, refill_every: Duration) -> TokenBucket {
+                TokenBucket::with_clock(capacity, refill_every, Chrono)
+            }
+        }
+
+        impl<T: TimeComponent> TokenBucket<T> {
+            pub fn with_clock(capacity: u32, refill_every: Duration, clock: T) -> TokenBucket<T> {
+                assert!(refill_every > Duration::zero(), "refill_every must be positive");
+                TokenBucket {
+                    capacity,
+                    refill_every,
+                    clock,
+                    buckets: Mutex::new(BTreeMap::new()),
+                }
+            }
+        }
+
+        impl<T: TimeComponent> RateLimiterComponent for TokenBucket<T> {
+            fn check_and_consume(&self, key: &str) -> RateLimit {
+                let now = self.clock.now();
+                // 途中でpanicしても残りの回数が狂うだけなので、そのまま使い続ける
+                let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+                let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+                    tokens: self.capacity,
+                    refilled_at: now,
+                });
+
+                // 経過時間分だけ回復させる。端数は次の回復に持ち越す。
+                let elapsed = now.signed_duration_since(bucket.refilled_at);
+                let periods = elapsed.num_milliseconds() / self.refill_every.num_milliseconds().max(1);
+                if periods > 0 {
+                    let refill = periods.min(i64::from(self.capacity)) as u32;
+                    bucket.tokens = (bucket.tokens + refill).min(self.capacity);
+                    bucket.refilled_at = if bucket.tokens == self.capacity {
+                        now
+                    } else {
+                        bucket.refilled_at + self.refill_every * periods as i32
+                    };
+                }
+
+                if bucket.tokens == 0 {
+                    return RateLimit::Limited {
+                        retry_after: bucket.refilled_at + self.refill_every - now,
+                    };
+                }
+                bucket.tokens -= 1;
+                RateLimit::Allowed
+            }
+        }
+    }
+
+    pub mod lock {
+        //! 名前付きのロック。複数の手順からなる処理を、他のスレッドや他のインスタンスと同時に行わないようにする。
+
+        use chrono::Duration;
+        use component::pool::{ConnectionPool, ConnectionPoolComponent};
+        use component::time::{MonotonicTimeComponent, StdClock};
+        use failure::Error;
+        use redis::{self, Client};
+        use std::collections::BTreeMap;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::{Condvar, Mutex};
+        use std::time::Instant;
+        use uuid::Uuid;
+
+        /// 取得したロック。解放する時に、自分が取ったロックである事の確認に使う。
+        #[derive(Debug, PartialEq, Eq)]
+        pub struct LockToken {
+            name: String,
+            owner: String,
+        }
+
+        /// ロックの取得と解放を行うレイヤ
+        pub trait LockComponent {
+            /// `timeout` 待っても取れなければエラー
+            fn acquire(&self, name: &str, timeout: Duration) -> Result<LockToken, Error>;
+            /// 既に他に取られている(期限切れで奪われた等)場合はエラー
+            fn release(&self, token: LockToken) -> Result<(), Error>;
+        }
+
+        /// これを実装(impl)している型はLockComponentを返せる。抽象化されたGetter.
+        pub trait HaveLockComponent {
+            type LockComponent: LockComponent;
+            fn lock_component(&self) -> &Self::LockComponent;
+        }
+
+        /// 同じプロセス内のスレッドの間でだけ効くLockComponent実装
+        #[derive(Default)]
+        pub struct InProcessLocks {
+            held: Mutex<BTreeMap<String, String>>,
+            released: Condvar,
+            next_owner: AtomicUsize,
+        }
+
+        impl InProcessLocks {
+            pub fn new() -> InProcessLocks {
+                InProcessLocks::default()
+            }
+        }
+
+        impl LockComponent for InProcessLocks {
+            fn acquire(&self, name: &str, timeout: Duration) -> Result<LockToken, Error> {
+                let deadline = Instant::now() + timeout.to_std()?;
+                let mut held = self.held.lock().map_err(|_| format_err!("lock table is poisoned"))?;
+                while held.contains_key(name) {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        bail!("timed out waiting for lock: {}", name);
+                    }
+                    held = self
+                        .released
+                        .wait_timeout(held, deadline - now)
+                        .map_err(|_| format_err!("lock table is poisoned"))?
+                        .0;
+                }
+                let owner = self.next_owner.fetch_add(1, Ordering::SeqCst).to_string();
+                held.insert(name.to_string(), owner.clone());
+                Ok(LockToken {
+                    name: name.to_string(),
+                    owner,
+                })
+            }
+
+            fn release(&self, token: LockToken) -> Result<(), Error> {
+                let mut held = self.held.lock().map_err(|_| format_err!("lock table is poisoned"))?;
+                if held.get(&token.name) != Some(&token.owner) {
+                    bail!("lock is not held: {}", token.name);
+                }
+                held.remove(&token.name);
+                self.released.notify_all();
+                Ok(())
+            }
+        }
+
+        /// 自分が取ったロックの時だけ消す
+        const RELEASE_SCRIPT: &str =
+            "if redis.call('get', KEYS[1]) == ARGV[1] then return redis.call('del', KEYS[1]) else return 0 end";
+
+        /// Redisを使って、複数のインスタンスの間で効くLockComponent実装。
+        /// ロックを取ったまま落ちたインスタンスがあっても、`lease` 経てば他が取れるようになる。
+        /// 取れるまで待つ間の時間の計測と待機は `M` で行う。Redisへの接続は `pool` から借りる。
+        pub struct RedisLocks<M = StdClock> {
+            pool: ConnectionPool<Client>,
+            lease: Duration,
+            instance: String,
+            clock: M,
+        }
+
+        impl RedisLocks {
+            /// `instance` はロックの値に入れて、どのプロセスが持っているかをRedis上で見られるようにする。
+            pub fn new(pool: ConnectionPool<Client>, lease: Duration, instance: &str) -> RedisLocks {
+                RedisLocks::with_clock(pool, lease, instance, StdClock::new())
+            }
+        }
+
+        impl<M: MonotonicTimeComponent> RedisLocks<M> {
+            pub fn with_clock(
+                pool: ConnectionPool<Client>,
+                lease: Duration,
+                instance: &str,
+                clock: M,
+            ) -> RedisLocks<M> {
+                RedisLocks {
+                    pool,
+                    lease,
+                    instance: instance.to_string(),
+                    clock,
+                }
+            }
+        }
+
+        impl<M: MonotonicTimeComponent> LockComponent for RedisLocks<M> {
+            /// 取れるまで少し待っては `SET NX` を繰り返す
+            fn acquire(&self, name: &str, timeout: Duration) -> Result<LockToken, Error> {
+                let started = self.clock.instant();
+                let owner = format!("{}:{}", self.instance, Uuid::new_v4().simple());
+                loop {
+                    let acquired: Option<String> = self.pool.with_connection(|connection| {
+                        Ok(redis::cmd("SET")
+                            .arg(format!("lock:{}", name))
+                            .arg(&owner)
+                            .arg("NX")
+                            .arg("PX")
+                            .arg(self.lease.num_milliseconds())
+                            .query(connection)?)
+                    })?;
+                    if acquired.is_some() {
+                        return Ok(LockToken {
+                            name: name.to_string(),
+                            owner,
+                        });
+                    }
+                    if self.clock.elapsed(started) >= timeout {
+                        bail!("timed out waiting for lock: {}", name);
+                    }
+                    self.clock.sleep(Duration::milliseconds(50));
+                }
+            }
+
+            fn release(&self, token: LockToken) -> Result<(), Error> {
+                let deleted: i64 = self.pool.with_connection(|connection| {
+                    Ok(redis::cmd("EVAL")
+                        .arg(RELEASE_SCRIPT)
+                        .arg(1)
+                        .arg(format!("lock:{}", token.name))
+                        .arg(&token.owner)
+                        .query(connection)?)
+                })?;
+                if deleted == 0 {
+                    bail!("lock is not held: {}", token.name);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub mod pool {
+        //! ネットワークの向こうのバックエンドへの接続を使い回す。
+        //! RedisのロックやキャッシュはここからConnectionを借り、使い終わったら返す。
+
+        use chrono::Duration;
+        use component::time::{Instant, MonotonicTimeComponent, StdClock};
+        use failure::Error;
+        use redis::{self, Client, Connection};
+        use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+        use std::thread;
+
+        /// 接続を作り、まだ使えるかを確かめる
+        pub trait Connector {
+            type Connection;
+            fn connect(&self) -> Result<Self::Connection, Error>;
+            /// しばらく使われていなかった接続を貸す前に呼ぶ。falseなら捨てて作り直す
+            fn is_healthy(&self, connection: &mut Self::Connection) -> bool;
+        }
+
+        /// `redis://127.0.0.1/` の形のURLから作ったClientで、Redisへ接続する
+        impl Connector for Client {
+            type Connection = Connection;
+            fn connect(&self) -> Result<Connection, Error> {
+                Ok(self.get_connection()?)
+            }
+
+            fn is_healthy(&self, connection: &mut Connection) -> bool {
+                redis::cmd("PING").query::<String>(connection).is_ok()
+            }
+        }
+
+        /// 接続を借りて返すレイヤ
+        pub trait ConnectionPoolComponent {
+            type Connection;
+            /// 上限まで貸していれば、返されるまで待つ。待っても空かなければエラー
+            fn acquire(&self) -> Result<Self::Connection, Error>;
+            /// 借りた接続を返す。壊れていても返してよく、次に貸す前に確かめて捨てる
+            fn release(&self, connection: Self::Connection);
+            /// 借りた接続を返さずに捨てた時に、その分の枠を空ける
+            fn abandon(&self);
+
+            /// 借りた接続を `f` に渡し、`f` が失敗しても返す。
+            /// `f` がpanicした時は接続がやり取りの途中かもしれないので、返さずに捨てて枠だけ空ける
+            fn with_connection<T, F>(&self, f: F) -> Result<T, Error>
+            where
+                F: FnOnce(&mut Self::Connection) -> Result<T, Error>,
+            {
+                let mut connection = self.acquire()?;
+                let lending = Lending(self);
+                let result = f(&mut connection);
+                drop(lending);
+                self.release(connection);
+                result
+            }
+        }
+
+        /// `with_connection` で貸している間にpanicしたら、Dropで枠を空ける
+        struct Lending<'a, P: ConnectionPoolComponent + ?Sized>(&'a P);
+
+        impl<'a, P: ConnectionPoolComponent + ?Sized> Drop for Lending<'a, P> {
+            fn drop(&mut self) {
+                if thread::panicking() {
+                    self.0.abandon();
+                }
+            }
+        }
+
+        /// `max_size` 個まで接続を作り、返された接続を使い回すConnectionPoolComponent実装。
+        /// 接続は最初から張っておかず、借りられた時に足りなければ作る。
+        /// 待ち時間と、接続が使われずに置かれていた時間は `M` の時計で測る。
+        pub struct ConnectionPool<C: Connector, M = StdClock> {
+            connector: C,
+            max_size: usize,
+            timeout: Duration,
+            idle_check: Duration,
+            clock: M,
+            state: Mutex<PoolState<C::Connection>>,
+            released: Condvar,
+        }
+
+        struct PoolState<T> {
+            /// 返された接続と、返された時点
+            idle: Vec<(T, Instant)>,
+            in_use: usize,
+        }
+
+        impl<C: Connector> ConnectionPool<C> {
+            pub fn new(connector: C, max_size: usize) -> ConnectionPool<C> {
+                ConnectionPool::with_clock(connector, max_size, StdClock::new())
+            }
+        }
+
+        impl<C: Connector, M: MonotonicTimeComponent> ConnectionPool<C, M> {
+            pub fn with_clock(connector: C, max_size: usize, clock: M) -> ConnectionPool<C, M> {
+                ConnectionPool {
+                    connector,
+                    max_size: max_size.max(1),
+                    timeout: Duration::seconds(5),
+                    idle_check: Duration::seconds(30),
+                    clock,
+                    state: Mutex::new(PoolState {
+                        idle: Vec::new(),
+                        in_use: 0,
+                    }),
+                    released: Condvar::new(),
+                }
+            }
+
+            /// 空きを待つ時間。デフォルトは5秒
+            pub fn with_timeout(mut self, timeout: Duration) -> ConnectionPool<C, M> {
+                self.timeout = timeout;
+                self
+            }
+
+            /// 数と空いている接続しか持っていないので、途中でpanicしてもそのまま使い続ける
+            fn state(&self) -> MutexGuard<'_, PoolState<C::Connection>> {
+                self.state.lock().unwrap_or_else(PoisonError::into_inner)
+            }
+
+            /// 貸す分の枠は取ってあるので、作れなかった時はその枠を空ける
+            fn connect(&self) -> Result<C::Connection, Error> {
+                self.connector.connect().inspect_err(|_| {
+                    self.state().in_use -= 1;
+                    self.released.notify_one();
+                })
+            }
+        }
+
+        impl<C: Connector, M: MonotonicTimeComponent> ConnectionPoolComponent for ConnectionPool<C, M> {
+            type Connection = C::Connection;
+
+            /// 確かめたり作ったりしている間はロックを離すので、他のスレッドはその間も接続を返せる
+            fn acquire(&self) -> Result<C::Connection, Error> {
+                let started = self.clock.instant();
+                let mut state = self.state();
+                loop {
+                    if let Some((mut connection, released)) = state.idle.pop() {
+                        state.in_use += 1;
+                        drop(state);
+                        let fresh = self.clock.elapsed(released) < self.idle_check;
+                        if fresh || self.connector.is_healthy(&mut connection) {
+                            return Ok(connection);
+                        }
+                        // 壊れた接続は捨て、その分の枠で作り直す
+                        return self.connect();
+                    }
+                    if state.in_use < self.max_size {
+                        state.in_use += 1;
+                        drop(state);
+                        return self.connect();
+                    }
+                    // 残りが無ければ待たずに諦める
+                    let remaining = (self.timeout - self.clock.elapsed(started)).to_std().unwrap_or_default();
+                    let (next, waited) = self
+                        .released
+                        .wait_timeout(state, remaining)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    state = next;
+                    if waited.timed_out() {
+                        bail!("timed out waiting for a connection: {} in use", state.in_use);
+                    }
+                }
+            }
+
+            fn release(&self, connection: C::Connection) {
+                let released = self.clock.instant();
+                let mut state = self.state();
+                state.in_use = state.in_use.saturating_sub(1);
+                state.idle.push((connection, released));
+                self.released.notify_one();
+            }
+
+            fn abandon(&self) {
+                let mut state = self.state();
+                state.in_use = state.in_use.saturating_sub(1);
+                self.released.notify_one();
+            }
+        }
+    }
+
+    pub mod scheduler {
+        //! 定期的に実行するジョブの管理。
+        //! ここではいつ実行するかだけを決めて、ジョブの中身はusecaseが名前を見て実行する。
+
+        use chrono::prelude::*;
+        use component::time::{Chrono, TimeComponent};
+        use failure::Error;
+        use std::str::FromStr;
+        use std::sync::mpsc::{self, Receiver};
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::Duration;
+
+        /// cronと同じ `分 時 日 月 曜日` の5項目で書く実行時刻。
+        /// 各項目には `*`, `n`, `n-m`, `*/n`, `n-m/k` とそれらのカンマ区切りが使える。曜日は0が日曜日。
+        /// 日と曜日の両方を指定した時は、cronと違って両方に合う日だけ実行する。
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct Schedule {
+            minutes: u64,
+            hours: u64,
+            days: u64,
+            months: u64,
+            weekdays: u64,
+        }
+
+        impl Schedule {
+            /// `time` の分が実行時刻に当たるか
+            pub fn matches(&self, time: &DateTime<Utc>) -> bool {
+                let has = |mask: u64, value: u32| mask & (1 << value) != 0;
+                has(self.minutes, time.minute())
+                    && has(self.hours, time.hour())
+                    && has(self.days, time.day())
+                    && has(self.months, time.month())
+                    && has(self.weekdays, time.weekday().num_days_from_sunday())
+            }
+        }
+
+        impl FromStr for Schedule {
+            type Err = Error;
+            fn from_str(s: &str) -> Result<Schedule, Error> {
+                let fields: Vec<&str> = s.split_whitespace().collect();
+                if fields.len() != 5 {
+                    bail!("schedule must have 5 fields: {}", s);
+                }
+                Ok(Schedule {
+                    minutes: parse_field(fields[0], 0, 59)?,
+                    hours: parse_field(fields[1], 0, 23)?,
+                    days: parse_field(fields[2], 1, 31)?,
+                    months: parse_field(fields[3], 1, 12)?,
+                    weekdays: parse_field(fields[4], 0, 6)?,
+                })
+            }
+        }
+
+        /// 1項目を、当てはまる値のビットを立てた値にする
+        fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, Error> {
+            let invalid = || format_err!("invalid schedule field: {}", field);
+            let number = |s: &str| -> Result<u32, Error> {
+                match s.parse() {
+                    Ok(n) if min <= n && n <= max => Ok(n),
+                    _ => Err(invalid()),
+                }
+            };
+            let mut mask = 0;
+            for part in field.split(',') {
+                let mut parts = part.splitn(2, '/');
+                let range = parts.next().unwrap_or("");
+                let step = match parts.next() {
+                    Some(step) => step.parse().ok().filter(|&s: &u32| s > 0).ok_or_else(invalid)?,
+                    None => 1,
+                };
+                let (from, to) = match range {
+                    "*" => (min, max),
+                    _ => match range.find('-') {
+                        Some(i) => (number(&range[..i])?, number(&range[i + 1..])?),
+                        None => (number(range)?, number(range)?),
+                    },
+                };
+                if from > to {
+                    return Err(invalid());
+                }
+                for value in (from..=to).step_by(step as usize) {
+                    mask |= 1 << value;
+                }
+            }
+            Ok(mask)
+        }
+
+        /// 定期実行するジョブを登録し、実行時刻が来たジョブを返すレイヤ
+        pub trait SchedulerComponent {
+            /// 同じ名前で登録し直すと実行時刻を置き換える
+            fn register(&self, job: &str, schedule: Schedule);
+            /// 前回呼んでから今までに実行時刻が来たジョブの名前
+            fn due_jobs(&self) -> Vec<String>;
+        }
+
+        /// これを実装(impl)している型はSchedulerComponentを返せる。抽象化されたGetter.
+        pub trait HaveSchedulerComponent {
+            type SchedulerComponent: SchedulerComponent;
+            fn scheduler_component(&self) -> &Self::SchedulerComponent;
+        }
+
+        type Jobs = Arc<Mutex<Vec<(String, Schedule)>>>;
+
+        /// 別スレッドで毎分の0秒に実行時刻を確かめるSchedulerComponent実装。
+        /// 実行時刻が来たジョブは溜めておき、`due_jobs` で取り出す。
+        pub struct ThreadScheduler {
+            jobs: Jobs,
+            due: Mutex<Receiver<String>>,
+        }
+
+        impl ThreadScheduler {
+            /// スレッドはThreadSchedulerを捨てると止まる
+            pub fn start() -> ThreadScheduler {
+                let jobs: Jobs = Arc::new(Mutex::new(Vec::new()));
+                let (sender, due) = mpsc::channel();
+                let shared = Arc::downgrade(&jobs);
+                thread::spawn(move || loop {
+                    let now = Chrono.now();
+                    thread::sleep(Duration::from_secs(u64::from(60 - now.second())));
+                    let now = Chrono.now();
+                    let jobs = match shared.upgrade() {
+                        Some(jobs) => jobs,
+                        None => return,
+                    };
+                    let jobs = match jobs.lock() {
+                        Ok(jobs) => jobs.clone(),
+                        Err(_) => return,
+                    };
+                    for (job, schedule) in jobs {
+                        if schedule.matches(&now) && sender.send(job).is_err() {
+                            return;
+                        }
+                    }
+                });
+                ThreadScheduler {
+                    jobs,
+                    due: Mutex::new(due),
+                }
+            }
+        }
+
+        impl SchedulerComponent for ThreadScheduler {
+            fn register(&self, job: &str, schedule: Schedule) {
+                if let Ok(mut jobs) = self.jobs.lock() {
+                    jobs.retain(|(name, _)| name != job);
+                    jobs.push((job.to_string(), schedule));
+                }
+            }
+
+            fn due_jobs(&self) -> Vec<String> {
+                match self.due.lock() {
+                    Ok(due) => due.try_iter().collect(),
+                    Err(_) => Vec::new(),
+                }
+            }
+        }
+    }
+
+    pub mod metrics {
+        //! 運用のための数値の記録。名前は `users.created` のようにドット区切りにする。
+
+        /// カウンタとヒストグラムを記録するレイヤ
+        pub trait MetricsComponent {
+            /// カウンタを `value` だけ増やす
+            fn increment(&self, name: &str, value: u64);
+            /// ヒストグラムに値を1つ記録する
+            fn observe(&self, name: &str, value: f64);
+        }
+
+        /// これを実装(impl)している型はMetricsComponentを返せる。抽象化されたGetter.
+        pub trait HaveMetricsComponent {
+            type MetricsComponent: MetricsComponent;
+            fn metrics_component(&self) -> &Self::MetricsComponent;
+        }
+
+        /// 何も記録しないMetricsComponent実装
+        pub struct NoopMetrics;
+
+        impl MetricsComponent for NoopMetrics {
+            fn increment(&self, _name: &str, _value: u64) {}
+            fn observe(&self, _name: &str, _value: f64) {}
+        }
+    }
+
+    pub mod search {
+        //! 全文検索。検索対象は `id` と本文だけを持つ文書として登録し、検索結果はidで返す。
+        //! Entityそのものは持たないので、検索した後はRepositoryから取り直す。
+
+        use failure::Error;
+        use std::sync::Mutex;
+        use tantivy::collector::TopDocs;
+        use tantivy::query::{BooleanQuery, FuzzyTermQuery, Occur, Query};
+        use tantivy::schema::{Field, Schema, Value, STORED, STRING, TEXT};
+        use tantivy::{Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term};
+
+        /// 文書の登録と検索を行うレイヤ
+        pub trait SearchComponent {
+            /// 同じidの文書があれば置き換える
+            fn index(&self, id: &str, text: &str) -> Result<(), Error>;
+            /// 無いidを指定してもエラーにはしない
+            fn remove(&self, id: &str) -> Result<(), Error>;
+            /// よく合う順に最大 `limit` 件のidを返す
+            fn query(&self, query: &str, limit: usize) -> Result<Vec<String>, Error>;
+        }
+
+        /// これを実装(impl)している型はSearchComponentを返せる。抽象化されたGetter.
+        pub trait HaveSearchComponent {
+            type SearchComponent: SearchComponent;
+            fn search_component(&self) -> &Self::SearchComponent;
+        }
+
+        /// SearchComponentをtantivyで実装(impl)する型。
+        /// 検索語は単語毎に編集距離1までの違いを許すので、多少の綴り間違いでも見つかる。
+        /// 登録・削除の度にコミットするので、すぐに検索結果に反映される。
+        pub struct TantivySearch {
+            index: Index,
+            writer: Mutex<IndexWriter>,
+            reader: IndexReader,
+            id: Field,
+            text: Field,
+        }
+
+        impl TantivySearch {
+            /// 索引をメモリ上に作る
+            pub fn in_memory() -> Result<TantivySearch, Error> {
+                let mut schema = Schema::builder();
+                let id = schema.add_text_field("id", STRING | STORED);
+                let text = schema.add_text_field("text", TEXT);
+                let index = Index::create_in_ram(schema.build());
+                let writer = index.writer_with_num_threads(1, 15_000_000)?;
+                let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?;
+                Ok(TantivySearch {
+                    index,
+                    writer: Mutex::new(writer),
+                    reader,
+                    id,
+                    text,
+                })
+            }
+
+            fn commit(&self, writer: &mut IndexWriter) -> Result<(), Error> {
+                writer.commit()?;
+                self.reader.reload()?;
+                Ok(())
+            }
+        }
+
+        impl SearchComponent for TantivySearch {
+            fn index(&self, id: &str, text: &str) -> Result<(), Error> {
+                let mut writer = self.writer.lock().map_err(|_| format_err!("search index writer is poisoned"))?;
+                writer.delete_term(Term::from_field_text(self.id, id));
+                let mut document = TantivyDocument::default();
+                document.add_text(self.id, id);
+                document.add_text(self.text, text);
+                writer.add_document(document)?;
+                self.commit(&mut writer)
+            }
+
+            fn remove(&self, id: &str) -> Result<(), Error> {
+                let mut writer = self.writer.lock().map_err(|_| format_err!("search index writer is poisoned"))?;
+                writer.delete_term(Term::from_field_text(self.id, id));
+                self.commit(&mut writer)
+            }
+
+            fn query(&self, query: &str, limit: usize) -> Result<Vec<String>, Error> {
+                // 登録時と同じ分け方・小文字化をしてから単語毎にあいまい検索する
+                let mut tokenizer = self.index.tokenizer_for_field(self.text)?;
+                let mut stream = tokenizer.token_stream(query);
+                let mut terms: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+                while stream.advance() {
+                    let term = Term::from_field_text(self.text, &stream.token().text);
+                    terms.push((Occur::Should, Box::new(FuzzyTermQuery::new(term, 1, true))));
+                }
+                if terms.is_empty() || limit == 0 {
+                    return Ok(Vec::new());
+                }
+                let searcher = self.reader.searcher();
+                let mut ids = Vec::new();
+                for (_, address) in searcher.search(&BooleanQuery::new(terms), &TopDocs::with_limit(limit))? {
+                    let document: TantivyDocument = searcher.doc(address)?;
+                    if let Some(id) = document.get_first(self.id).and_then(|v| v.as_str()) {
+                        ids.push(id.to_string());
+                    }
+                }
+                Ok(ids)
+            }
+        }
+    }
+
+    pub mod trace {
+        //! 処理の区間(span)を記録して、レイヤを跨いだ処理の流れを追えるようにする。
+        //! spanは開始した時に返る値を捨てた時点でも終わるので、`?` で途中で抜けても終わらせ忘れない。
+
+        use tracing::info_span;
+        use tracing::span::EnteredSpan;
+
+        /// spanの開始と終了を記録するレイヤ
+        pub trait TracingComponent {
+            /// 開始したspan。捨てると終わる。
+            type Span;
+            /// `attributes` はspanに付けておくキーと値の組
+            fn start_span(&self, name: &'static str, attributes: &[(&str, &str)]) -> Self::Span;
+        }
+
+        /// これを実装(impl)している型はTracingComponentを返せる。抽象化されたGetter.
+        pub trait HaveTracingComponent {
+            type TracingComponent: TracingComponent;
+            fn tracing_component(&self) -> &Self::TracingComponent;
+        }
+
+        /// TracingComponentをtracing crateで実装(impl)する型。
+        /// tracingのspanの名前は静的に決まっている必要があるので、spanの名前は `name` フィールドに入れる。
+        /// 記録したspanをどこに出すかは、プロセスに設定したtracingのsubscriberが決める。
+        pub struct TracingSpans;
+
+        impl TracingComponent for TracingSpans {
+            type Span = EnteredSpan;
+
+            fn start_span(&self, name: &'static str, attributes: &[(&str, &str)]) -> EnteredSpan {
+                let attributes: Vec<String> =
+                    attributes.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+                info_span!("layered", name, attributes = %attributes.join(" ")).entered()
+            }
+        }
+    }
+
+    pub mod health {
+        //! 稼働状態の確認。環境型が使っているComponentを1つずつ確かめて、結果をまとめて返す。
+        //! 結果はそのままJSONにできるので、HTTPの `/health` 等でそのまま返せる。
+
+        use chrono::{TimeZone, Utc};
+        use component::queue::MessageQueueComponent;
+        use component::storage::StorageComponent;
+        use component::time::TimeComponent;
+        use failure::Error;
+
+        /// 1つの確認の結果
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+        pub struct Check {
+            pub name: &'static str,
+            pub healthy: bool,
+            /// 失敗した時の理由
+            pub error: Option<String>,
+        }
+
+        /// 全ての確認の結果。1つでも失敗していれば `healthy` はfalseになる。
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+        pub struct HealthReport {
+            pub healthy: bool,
+            pub checks: Vec<Check>,
+        }
+
+        impl HealthReport {
+            pub fn new() -> HealthReport {
+                HealthReport {
+                    healthy: true,
+                    checks: Vec::new(),
+                }
+            }
+
+            pub fn check(mut self, name: &'static str, result: Result<(), Error>) -> HealthReport {
+                let error = result.err().map(|e| e.to_string());
+                self.healthy &= error.is_none();
+                self.checks.push(Check {
+                    name,
+                    healthy: error.is_none(),
+                    error,
+                });
+                self
+            }
+        }
+
+        impl Default for HealthReport {
+            fn default() -> Self {
+                HealthReport::new()
+            }
+        }
+
+        /// 稼働状態を確かめるレイヤ。
+        /// 何を確かめるかは使っているComponentで決まるので、このtraitは環境型自身が実装(impl)する。
+        pub trait HealthCheckComponent {
+            fn health(&self) -> HealthReport;
+        }
+
+        /// ストレージから読めるか
+        pub fn ping_storage<K, V, S: StorageComponent<K, V>>(storage: &S) -> Result<(), Error> {
+            storage.read_all().map(|_| ())
+        }
+
+        /// 時計が合わされていて、戻っていないか
+        pub fn check_clock<T: TimeComponent>(time: &T) -> Result<(), Error> {
+            let (first, second) = (time.now(), time.now());
+            if first < Utc.with_ymd_and_hms(2018, 1, 1, 0, 0, 0).unwrap() {
+                bail!("clock is not set: {}", first);
+            }
+            if second < first {
+                bail!("clock went backwards: {} -> {}", first, second);
+            }
+            Ok(())
+        }
+
+        /// キューのブローカーに繋がるか
+        pub fn ping_queue<Q: MessageQueueComponent>(queue: &Q) -> Result<(), Error> {
+            queue.ping()
+        }
+    }
+
+    pub mod validation {
+        //! デプロイ先毎に変えたい検証のルール。ルールは環境型を作る時に登録する。
+        //! どこでも守るべき形は値オブジェクトを作る時に検証し、ここでは保存してよいかだけを確かめる。
+
+        use entity::ValidationError;
+        use entity::credentials::PlainPassword;
+        use entity::user::User;
+
+        /// ルールを守っていればtrueを返す
+        pub type Rule<E> = Box<dyn Fn(&E) -> bool + Send + Sync>;
+
+        /// Entity `E` を保存してよいかを確かめるレイヤ
+        pub trait ValidationComponent<E> {
+            /// 最初に破ったルールの名前をエラーにして返す
+            fn validate(&self, entity: &E) -> Result<(), ValidationError>;
+        }
+
+        /// これを実装(impl)している型はUser用のValidationComponentを返せる。抽象化されたGetter.
+        pub trait HaveValidationComponent {
+            type ValidationComponent: ValidationComponent<User>;
+            fn validation_component(&self) -> &Self::ValidationComponent;
+        }
+
+        /// これを実装(impl)している型は新しいパスワードの強度を確かめるValidationComponentを返せる
+        pub trait HavePasswordPolicyComponent {
+            type PasswordPolicyComponent: ValidationComponent<PlainPassword>;
+            fn password_policy_component(&self) -> &Self::PasswordPolicyComponent;
+        }
+
+        /// 名前を付けたルールを登録した順に確かめるValidationComponent実装。ルールが無ければ何でも通す。
+        pub struct Rules<E> {
+            rules: Vec<(String, Rule<E>)>,
+        }
+
+        impl<E> Rules<E> {
+            pub fn new() -> Rules<E> {
+                Rules { rules: Vec::new() }
+            }
+
+            pub fn with(mut self, name: &str, rule: Rule<E>) -> Rules<E> {
+                self.rules.push((name.to_string(), rule));
+                self
+            }
+        }
+
+        impl<E> Default for Rules<E> {
+            fn default() -> Self {
+                Rules::new()
+            }
+        }
+
+        impl<E> ValidationComponent<E> for Rules<E> {
+            fn validate(&self, entity: &E) -> Result<(), ValidationError> {
+                match self.rules.iter().find(|(_, rule)| !rule(entity)) {
+                    Some((name, _)) => Err(ValidationError::RuleViolated(name.clone())),
+                    None => Ok(()),
+                }
+            }
+        }
+
+        /// パスワードの強度の決まり。`min_length` 文字以上で、英字と数字の両方を含む事。
+        pub fn password_policy(min_length: usize) -> Rules<PlainPassword> {
+            Rules::new()
+                .with(
+                    "password_min_length",
+                    Box::new(move |password: &PlainPassword| password.expose().chars().count() >= min_length),
+                )
+                .with(
+                    "password_letters_and_digits",
+                    Box::new(|password: &PlainPassword| {
+                        let password = password.expose();
+                        password.chars().any(char::is_alphabetic) && password.chars().any(|c| c.is_ascii_digit())
+                    }),
+                )
+        }
+
+        /// メールアドレスのドメインが `domains` のどれかである事。社内のアドレスだけを許す時等に使う。
+        pub fn email_domain_in<I: IntoIterator<Item = String>>(domains: I) -> Rule<User> {
+            let domains: Vec<String> = domains.into_iter().map(|domain| domain.to_lowercase()).collect();
+            Box::new(move |user: &User| domains.iter().any(|domain| user.email.domain() == domain))
+        }
+    }
+
+    pub mod locale {
+        //! 利用者に見せる文言の翻訳。文言はキーで引き、ロケール毎のカタログから選ぶ。
+        //! カタログはFluentに倣って `キー = 文言` を1行ずつ書き、文言の中の `{ $引数 }` は渡された値で置き換える。
+        //! HTTPのレイヤはエラーの文言を自分で組み立てず、ここで利用者のロケールの文言にしてから返す。
+
+        use entity::ValidationError;
+        use entity::user::StatusError;
+        use failure::Error;
+        use std::collections::BTreeMap;
+
+        /// 組み込みの日本語のカタログ
+        pub const JA: &str = "
+            # 値の検証
+            validation-empty = { $field } を入力してください(空白だけにはできません)
+            validation-invalid-email = メールアドレスの形式が正しくありません: { $email }
+            validation-missing-field = { $field } を入力してください
+            validation-invalid-address = 住所の { $field } が正しくありません
+            validation-invalid-phone-number = 電話番号の形式が正しくありません: { $number }
+            validation-too-long = { $field } は { $max } 文字以内で入力してください
+            validation-rule-violated = 登録できない内容です ({ $rule })
+            # ユーザーの状態
+            user-not-active = 有効でないユーザー({ $status })には { $action } を行えません
+            user-already = ユーザーは既に { $status } です
+            user-not-deactivated = 退会していないユーザー({ $status })には { $action } を行えません
+        ";
+
+        /// 組み込みの英語のカタログ
+        pub const EN: &str = "
+            # validation
+            validation-empty = Please enter { $field } (it cannot be blank)
+            validation-invalid-email = Invalid email address: { $email }
+            validation-missing-field = Please enter { $field }
+            validation-invalid-address = Invalid { $field } in the address
+            validation-invalid-phone-number = Invalid phone number: { $number }
+            validation-too-long = { $field } must be at most { $max } characters
+            validation-rule-violated = This cannot be registered ({ $rule })
+            # user status
+            user-not-active = Cannot { $action } a user who is { $status }
+            user-already = The user is already { $status }
+            user-not-deactivated = Cannot { $action } a user who is { $status }; deactivate the user first
+        ";
+
+        /// 翻訳できるエラー。キーと引数だけを決め、文言はカタログに任せる。
+        pub trait Localize {
+            fn message_key(&self) -> &'static str;
+            fn message_args(&self) -> Vec<(&'static str, String)> {
+                Vec::new()
+            }
+        }
+
+        impl Localize for ValidationError {
+            fn message_key(&self) -> &'static str {
+                match *self {
+                    ValidationError::Empty(_) => "validation-empty",
+                    ValidationError::InvalidEmail(_) => "validation-invalid-email",
+                    ValidationError::MissingField(_) => "validation-missing-field",
+                    ValidationError::InvalidAddress(_) => "validation-invalid-address",
+                    ValidationError::InvalidPhoneNumber(_) => "validation-invalid-phone-number",
+                    ValidationError::TooLong { .. } => "validation-too-long",
+                    ValidationError::RuleViolated(_) => "validation-rule-violated",
+                }
+            }
+
+            fn message_args(&self) -> Vec<(&'static str, String)> {
+                match *self {
+                    ValidationError::Empty(field) => vec![("field", field.to_string())],
+                    ValidationError::InvalidEmail(ref email) => vec![("email", email.clone())],
+                    ValidationError::MissingField(field) => vec![("field", field.to_string())],
+                    ValidationError::InvalidAddress(field) => vec![("field", field.to_string())],
+                    ValidationError::InvalidPhoneNumber(ref number) => vec![("number", number.clone())],
+                    ValidationError::TooLong { field, max } => {
+                        vec![("field", field.to_string()), ("max", max.to_string())]
+                    }
+                    ValidationError::RuleViolated(ref rule) => vec![("rule", rule.clone())],
+                }
+            }
+        }
+
+        impl Localize for StatusError {
+            fn message_key(&self) -> &'static str {
+                match *self {
+                    StatusError::NotActive { .. } => "user-not-active",
+                    StatusError::Already { .. } => "user-already",
+                    StatusError::NotDeactivated { .. } => "user-not-deactivated",
+                }
+            }
+
+            fn message_args(&self) -> Vec<(&'static str, String)> {
+                let status = |status| format!("{:?}", status).to_lowercase();
+                match *self {
+                    StatusError::NotActive { action, status: s, .. }
+                    | StatusError::NotDeactivated { action, status: s, .. } => {
+                        vec![("action", action.to_string()), ("status", status(s))]
+                    }
+                    StatusError::Already { status: s, .. } => vec![("status", status(s))],
+                }
+            }
+        }
+
+        /// 翻訳できるエラーから取り出したキーと引数。元のエラーの型を知らなくても後から翻訳できる
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct MessageKey {
+            pub key: &'static str,
+            pub args: Vec<(&'static str, String)>,
+        }
+
+        impl MessageKey {
+            pub fn of<L: Localize>(item: &L) -> MessageKey {
+                MessageKey {
+                    key: item.message_key(),
+                    args: item.message_args(),
+                }
+            }
+        }
+
+        impl Localize for MessageKey {
+            fn message_key(&self) -> &'static str {
+                self.key
+            }
+
+            fn message_args(&self) -> Vec<(&'static str, String)> {
+                self.args.clone()
+            }
+        }
+
+        /// キーから利用者のロケールの文言を引くレイヤ
+        pub trait LocaleComponent {
+            /// `locale` のカタログに無いキーは既定のロケールで探し、そこにも無ければキーをそのまま返す
+            fn message(&self, locale: &str, key: &str, args: &[(&str, String)]) -> String;
+
+            fn localize<L: Localize>(&self, locale: &str, item: &L) -> String {
+                self.message(locale, item.message_key(), &item.message_args())
+            }
+        }
+
+        /// これを実装(impl)している型はLocaleComponentを返せる。抽象化されたGetter.
+        pub trait HaveLocaleComponent {
+            type LocaleComponent: LocaleComponent;
+            fn locale_component(&self) -> &Self::LocaleComponent;
+        }
+
+        /// ロケール毎のカタログを持つLocaleComponent実装。
+        /// `ja-JP` のカタログが無ければ `ja` のカタログを探す。
+        pub struct Catalogs {
+            default_locale: String,
+            catalogs: BTreeMap<String, BTreeMap<String, String>>,
+        }
+
+        impl Catalogs {
+            pub fn new(default_locale: &str) -> Catalogs {
+                Catalogs {
+                    default_locale: default_locale.to_string(),
+                    catalogs: BTreeMap::new(),
+                }
+            }
+
+            /// 組み込みの日本語・英語のカタログを読み込んだもの。既定は日本語。
+            pub fn builtin() -> Catalogs {
+                Catalogs::new("ja")
+                    .with("ja", JA)
+                    .and_then(|catalogs| catalogs.with("en", EN))
+                    .expect("builtin catalogs are well-formed")
+            }
+
+            /// `locale` のカタログを読み込む。既にあるキーは上書きする。
+            pub fn with(mut self, locale: &str, source: &str) -> Result<Catalogs, Error> {
+                let catalog = self.catalogs.entry(locale.to_string()).or_default();
+                for (number, line) in source.lines().enumerate() {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    match line.split_once('=') {
+                        Some((key, text)) if !key.trim().is_empty() => {
+                            catalog.insert(key.trim().to_string(), text.trim().to_string());
+                        }
+                        _ => bail!("invalid catalog line {} for {}: {:?}", number + 1, locale, line),
+                    }
+                }
+                Ok(self)
+            }
+
+            fn lookup(&self, locale: &str, key: &str) -> Option<&String> {
+                let language = locale.split(['-', '_']).next().unwrap_or(locale);
+                [locale, language]
+                    .iter()
+                    .filter_map(|locale| self.catalogs.get(*locale))
+                    .find_map(|catalog| catalog.get(key))
+            }
+        }
+
+        impl Default for Catalogs {
+            fn default() -> Self {
+                Catalogs::builtin()
+            }
+        }
+
+        impl LocaleComponent for Catalogs {
+            fn message(&self, locale: &str, key: &str, args: &[(&str, String)]) -> String {
+                let template = match self.lookup(locale, key).or_else(|| self.lookup(&self.default_locale, key)) {
+                    Some(template) => template,
+                    None => return key.to_string(),
+                };
+                args.iter().fold(template.clone(), |text, (name, value)| {
+                    text.replace(&format!("{{ ${} }}", name), value)
+                })
+            }
+        }
+    }
+
+    pub mod geoip {
+        //! IPアドレスからおおよその場所を引く。場所は統計や表示に使うだけなので、引けなくても処理は続けられる。
+
+        use failure::Error;
+        use maxminddb::{self, geoip2, MaxMindDBError};
+        use std::net::IpAddr;
+        use std::path::Path;
+
+        /// IPアドレスから引いた場所
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct Location {
+            /// ISO 3166-1の2文字の国コード
+            pub country: Option<String>,
+            /// 英語の都市名
+            pub city: Option<String>,
+        }
+
+        /// IPアドレスの場所を引くレイヤ
+        pub trait GeoIpComponent {
+            /// データベースに無いアドレス(プライベートアドレス等)はNoneを返す
+            fn lookup(&self, ip: IpAddr) -> Result<Option<Location>, Error>;
+        }
+
+        /// これを実装(impl)している型はGeoIpComponentを返せる。抽象化されたGetter.
+        pub trait HaveGeoIpComponent {
+            type GeoIpComponent: GeoIpComponent;
+            fn geo_ip_component(&self) -> &Self::GeoIpComponent;
+        }
+
+        /// MaxMindのGeoIP2/GeoLite2 Cityのデータベースファイルで引くGeoIpComponent実装
+        pub struct MaxMindGeoIp {
+            reader: maxminddb::Reader<Vec<u8>>,
+        }
+
+        impl MaxMindGeoIp {
+            pub fn open(path: &Path) -> Result<MaxMindGeoIp, Error> {
+                let reader = maxminddb::Reader::open_readfile(path)
+                    .map_err(|e| format_err!("cannot open geoip database {}: {}", path.display(), e))?;
+                Ok(MaxMindGeoIp { reader })
+            }
+        }
+
+        impl GeoIpComponent for MaxMindGeoIp {
+            fn lookup(&self, ip: IpAddr) -> Result<Option<Location>, Error> {
+                let city: geoip2::City = match self.reader.lookup(ip) {
+                    Ok(city) => city,
+                    Err(MaxMindDBError::AddressNotFoundError(_)) => return Ok(None),
+                    Err(e) => bail!("geoip lookup failed for {}: {}", ip, e),
+                };
+                Ok(Some(Location {
+                    country: city.country.and_then(|country| country.iso_code).map(str::to_string),
+                    city: city
+                        .city
+                        .and_then(|city| city.names)
+                        .and_then(|names| names.get("en").map(|name| name.to_string())),
+                }))
+            }
+        }
+
+        /// データベースが無い時に使う、何も引けないGeoIpComponent実装
+        #[derive(Debug, Clone, Copy, Default)]
+        pub struct NoGeoIp;
+
+        impl GeoIpComponent for NoGeoIp {
+            fn lookup(&self, _ip: IpAddr) -> Result<Option<Location>, Error> {
+                Ok(None)
+            }
+        }
+    }
+
+    pub mod event_bus {
+        //! ドメインイベントを購読者に配る。
+        //! 購読者は環境型を受け取る関数として登録するので、購読者が使うComponentを
+        //! イベントを発行する側(Repository)の制約に入れなくて済む。
+
+        use entity::user::{User, UserEvent};
+        use failure::Error;
+        use std::sync::{Arc, PoisonError, RwLock};
+
+        /// 購読者。`W` は環境型で、購読者は必要なComponentをそこから取り出す。
+        pub type Handler<W> = Box<dyn Fn(&W, &User, UserEvent) -> Result<(), Error> + Send + Sync>;
+
+        /// イベントの購読と配信を行うレイヤ
+        pub trait EventBusComponent<W> {
+            /// `name` は購読者が失敗した時にどれが失敗したかを示すのに使う
+            fn subscribe(&self, name: &str, handler: Handler<W>);
+            /// 登録された順に全ての購読者に配り、失敗した購読者の名前とエラーを返す。
+            /// 1つが失敗しても残りの購読者には配る。
+            fn dispatch(&self, world: &W, user: &User, event: UserEvent) -> Vec<(String, Error)>;
+        }
+
+        /// これを実装(impl)している型はEventBusComponentを返せる。抽象化されたGetter.
+        /// 購読者には環境型自身を渡すので、EventBusComponentの型引数は `Self` になる。
+        pub trait HaveEventBusComponent: Sized {
+            type EventBusComponent: EventBusComponent<Self>;
+            fn event_bus_component(&self) -> &Self::EventBusComponent;
+        }
+
+        /// 発行したスレッドでその場で全ての購読者を呼ぶEventBusComponent実装。
+        /// 購読者の中でイベントを発行する事も、購読者を登録する事も出来る。
+        /// 配っている途中に登録された購読者には、次のイベントから届く。
+        pub struct SyncEventBus<W> {
+            /// 登録する度に作り直すので、配る側は複製したArcを持ってロックを離せる
+            handlers: RwLock<Arc<[Subscriber<W>]>>,
+        }
+
+        /// 登録した名前と購読者。作り直す時は中身を複製せずに使い回す
+        type Subscriber<W> = Arc<(String, Handler<W>)>;
+
+        impl<W> SyncEventBus<W> {
+            pub fn new() -> SyncEventBus<W> {
+                SyncEventBus {
+                    handlers: RwLock::new(Arc::new([])),
+                }
+            }
+        }
+
+        impl<W> Default for SyncEventBus<W> {
+            fn default() -> Self {
+                SyncEventBus::new()
+            }
+        }
+
+        impl<W> EventBusComponent<W> for SyncEventBus<W> {
+            fn subscribe(&self, name: &str, handler: Handler<W>) {
+                let mut handlers = self.handlers.write().unwrap_or_else(PoisonError::into_inner);
+                let added = Arc::new((name.to_string(), handler));
+                *handlers = handlers.iter().cloned().chain(Some(added)).collect();
+            }
+
+            fn dispatch(&self, world: &W, user: &User, event: UserEvent) -> Vec<(String, Error)> {
+                // 購読者を呼ぶ間はロックを持たないので、購読者の中で発行も登録も出来る
+                let handlers = Arc::clone(&self.handlers.read().unwrap_or_else(PoisonError::into_inner));
+                handlers
+                    .iter()
+                    .filter_map(|entry| {
+                        let (ref name, ref handler) = **entry;
+                        handler(world, user, event).err().map(|e| (name.clone(), e))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    pub mod template {
+        //! 利用者に送る文面。文面はテンプレートに書いておき、埋め込む値だけを呼び出し側が渡す。
+
+        use failure::Error;
+        use handlebars::{self, Handlebars};
+        use serde_json::Value;
+
+        /// アカウント作成時のメール。`name` を埋め込む。
+        pub const WELCOME: &str = "welcome";
+        /// パスワード再設定のメール。`name` と `reset_url` を埋め込む。
+        pub const PASSWORD_RESET: &str = "password_reset";
+        /// 招待のメール。招待した人の `inviter` と `accept_url` を埋め込む。
+        pub const INVITATION: &str = "invitation";
+
+        /// 組み込みのテンプレート。メールは件名を `<名前>.subject`、本文を `<名前>.body` に書く。
+        const BUILTIN_TEMPLATES: &[(&str, &str)] = &[
+            ("welcome.subject", "Welcome, {{name}}"),
+            ("welcome.body", "Hello {{name}},\n\nYour account has been created.\n"),
+            ("password_reset.subject", "Reset your password"),
+            (
+                "password_reset.body",
+                "Hello {{name}},\n\nOpen the link below to reset your password:\n{{reset_url}}\n",
+            ),
+            ("invitation.subject", "{{inviter}} invited you"),
+            (
+                "invitation.body",
+                "Hello,\n\n{{inviter}} invited you. Open the link below to create your account:\n{{accept_url}}\n",
+            ),
+        ];
+
+        /// 名前で選んだテンプレートに値を埋め込むレイヤ
+        pub trait TemplateComponent {
+            fn render(&self, template: &str, context: &Value) -> Result<String, Error>;
+        }
+
+        /// これを実装(impl)している型はTemplateComponentを返せる。抽象化されたGetter.
+        pub trait HaveTemplateComponent {
+            type TemplateComponent: TemplateComponent;
+            fn template_component(&self) -> &Self::TemplateComponent;
+        }
+
+        /// TemplateComponentをHandlebarsで実装(impl)する型。
+        /// 文面はプレーンテキストなのでHTMLのエスケープはせず、埋め込む値が足りない時はエラーにする。
+        pub struct HandlebarsTemplates {
+            registry: Handlebars<'static>,
+        }
+
+        impl HandlebarsTemplates {
+            /// 組み込みのテンプレートを登録した状態で作る
+            pub fn new() -> Result<HandlebarsTemplates, Error> {
+                let mut registry = Handlebars::new();
+                registry.set_strict_mode(true);
+                registry.register_escape_fn(handlebars::no_escape);
+                let mut templates = HandlebarsTemplates { registry };
+                for &(name, source) in BUILTIN_TEMPLATES {
+                    templates.register(name, source)?;
+                }
+                Ok(templates)
+            }
+
+            /// 同じ名前のテンプレートは置き換える
+            pub fn register(&mut self, name: &str, source: &str) -> Result<(), Error> {
+                self.registry
+                    .register_template_string(name, source)
+                    .map_err(|e| format_err!("invalid template {}: {}", name, e))
+            }
+        }
+
+        impl TemplateComponent for HandlebarsTemplates {
+            fn render(&self, template: &str, context: &Value) -> Result<String, Error> {
+                self.registry
+                    .render(template, context)
+                    .map_err(|e| format_err!("failed to render {}: {}", template, e))
+            }
+        }
+    }
+
+    pub mod mail {
+        use component::secrets::Secret;
+        use component::template::TemplateComponent;
+        use entity::user::Email;
+        use failure::Error;
+        use lettre::message::Mailbox;
+        use lettre::transport::smtp::authentication::Credentials;
+        use lettre::{Message, SmtpTransport, Transport};
+        use serde_json::Value;
+
+        /// 送信するメール1通
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct Mail {
+            pub to: Email,
+            pub subject: String,
+            pub body: String,
+        }
+
+        impl Mail {
+            /// 件名を `<template>.subject`、本文を `<template>.body` のテンプレートから作る
+            pub fn render<T: TemplateComponent>(
+                templates: &T,
+                template: &str,
+                to: Email,
+                context: &Value,
+            ) -> Result<Mail, Error> {
+                Ok(Mail {
+                    to,
+                    subject: templates.render(&format!("{}.subject", template), context)?,
+                    body: templates.render(&format!("{}.body", template), context)?,
+                })
+            }
+        }
+
+        /// メールを送信するレイヤ
+        pub trait EmailSenderComponent {
+            fn send(&self, mail: &Mail) -> Result<(), Error>;
+        }
+
+        /// これを実装(impl)している型はEmailSenderComponentを返せる。抽象化されたGetter.
+        pub trait HaveEmailSenderComponent {
+            type EmailSenderComponent: EmailSenderComponent;
+            fn email_sender_component(&self) -> &Self::EmailSenderComponent;
+        }
+
+        /// 他のcomponentに送信を任せる時に、所有権を渡さず参照のままで使えるようにする
+        impl<S: EmailSenderComponent> EmailSenderComponent for &S {
+            fn send(&self, mail: &Mail) -> Result<(), Error> {
+                (**self).send(mail)
+            }
+        }
+
+        /// EmailSenderComponentをSMTP(lettre)で実装(impl)する型。
+        /// TLSは使わないので、同じホストやネットワーク内のリレーサーバーに渡す想定。
+        pub struct SmtpSender {
+            from: Mailbox,
+            transport: SmtpTransport,
+        }
+
+        impl SmtpSender {
+            pub fn new(host: &str, port: u16, from: &str) -> Result<SmtpSender, Error> {
+                SmtpSender::build(host, port, from, None)
+            }
+
+            /// SMTP AUTHで認証してから送る
+            pub fn with_credentials(
+                host: &str,
+                port: u16,
+                from: &str,
+                user: &str,
+                password: &Secret,
+            ) -> Result<SmtpSender, Error> {
+                let credentials = Credentials::new(user.to_string(), password.expose().to_string());
+                SmtpSender::build(host, port, from, Some(credentials))
+            }
+
+            fn build(host: &str, port: u16, from: &str, credentials: Option<Credentials>) -> Result<SmtpSender, Error> {
+                let mut transport = SmtpTransport::builder_dangerous(host).port(port);
+                if let Some(credentials) = credentials {
+                    transport = transport.credentials(credentials);
+                }
+                Ok(SmtpSender {
+                    from: from.parse().map_err(|e| format_err!("invalid sender address {}: {}", from, e))?,
+                    transport: transport.build(),
+                })
+            }
+        }
+
+        impl EmailSenderComponent for SmtpSender {
+            fn send(&self, mail: &Mail) -> Result<(), Error> {
+                let to: Mailbox = mail.to.as_str().parse().map_err(|e| format_err!("invalid address: {}", e))?;
+                let message = Message::builder()
+                    .from(self.from.clone())
+                    .to(to)
+                    .subject(mail.subject.as_str())
+                    .body(mail.body.clone())
+                    .map_err(|e| format_err!("failed to build mail: {}", e))?;
+                self.transport
+                    .send(&message)
+                    .map_err(|e| format_err!("failed to send mail: {}", e))?;
+                Ok(())
+            }
+        }
+    }
+
+    pub mod http {
+        //! 外部サービスへのHTTP呼び出し。
+        //! 実装はJSONの値を送受信するだけにして、型付きの読み書きはtraitのデフォルト実装で行う。
+
+        use failure::Error;
+        use reqwest::StatusCode;
+        use reqwest::blocking::{Client, Response};
+        use serde::Serialize;
+        use serde::de::DeserializeOwned;
+        use serde_json::{self, Value};
+        use std::time::Duration;
+
+        /// HTTPでJSONをやり取りするレイヤ
+        pub trait HttpClientComponent {
+            fn get_json(&self, url: &str) -> Result<Value, Error>;
+            /// 404の時はエラーにせずNoneを返す
+            fn find_json(&self, url: &str) -> Result<Option<Value>, Error>;
+            fn post_json(&self, url: &str, body: &Value) -> Result<Value, Error>;
+            /// 署名等のヘッダを付けてPOSTする
+            fn post_json_with_headers(&self, url: &str, body: &Value, headers: &[(&str, &str)]) -> Result<Value, Error>;
+            fn put_json(&self, url: &str, body: &Value) -> Result<Value, Error>;
+            /// 消したらtrue、404だったらfalse
+            fn delete(&self, url: &str) -> Result<bool, Error>;
+
+            fn get<T: DeserializeOwned>(&self, url: &str) -> Result<T, Error> {
+                Ok(serde_json::from_value(self.get_json(url)?)?)
+            }
+
+            fn post<B: Serialize, T: DeserializeOwned>(&self, url: &str, body: &B) -> Result<T, Error> {
+                Ok(serde_json::from_value(self.post_json(url, &serde_json::to_value(body)?)?)?)
+            }
+        }
+
+        /// 他のcomponentに呼び出しを任せる時に、所有権を渡さず参照のままで使えるようにする
+        impl<H: HttpClientComponent> HttpClientComponent for &H {
+            fn get_json(&self, url: &str) -> Result<Value, Error> {
+                (**self).get_json(url)
+            }
+
+            fn find_json(&self, url: &str) -> Result<Option<Value>, Error> {
+                (**self).find_json(url)
+            }
+
+            fn post_json(&self, url: &str, body: &Value) -> Result<Value, Error> {
+                (**self).post_json(url, body)
+            }
+
+            fn post_json_with_headers(
+                &self,
+                url: &str,
+                body: &Value,
+                headers: &[(&str, &str)],
+            ) -> Result<Value, Error> {
+                (**self).post_json_with_headers(url, body, headers)
+            }
+
+            fn put_json(&self, url: &str, body: &Value) -> Result<Value, Error> {
+                (**self).put_json(url, body)
+            }
+
+            fn delete(&self, url: &str) -> Result<bool, Error> {
+                (**self).delete(url)
+            }
+        }
+
+        /// HttpClientComponentをreqwestで実装(impl)する型
+        #[derive(Default)]
+        pub struct ReqwestClient {
+            client: Client,
+        }
+
+        impl ReqwestClient {
+            /// `timeout` を過ぎても応答が終わらないリクエストはエラーにする。
+            /// `default()` で作った時は待ち続ける。
+            pub fn new(timeout: Duration) -> Result<ReqwestClient, Error> {
+                Ok(ReqwestClient {
+                    client: Client::builder().timeout(timeout).build()?,
+                })
+            }
+
+            /// 2xx以外はエラー。本文が空の時はnullとして扱う。
+            fn read(response: Response) -> Result<Value, Error> {
+                let text = response.error_for_status()?.text()?;
+                if text.trim().is_empty() {
+                    return Ok(Value::Null);
+                }
+                Ok(serde_json::from_str(&text)?)
+            }
+        }
+
+        impl HttpClientComponent for ReqwestClient {
+            fn get_json(&self, url: &str) -> Result<Value, Error> {
+                ReqwestClient::read(self.client.get(url).send()?)
+            }
+
+            fn find_json(&self, url: &str) -> Result<Option<Value>, Error> {
+                let response = self.client.get(url).send()?;
+                if response.status() == StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                ReqwestClient::read(response).map(Some)
+            }
+
+            fn post_json(&self, url: &str, body: &Value) -> Result<Value, Error> {
+                ReqwestClient::read(self.client.post(url).json(body).send()?)
+            }
+
+            fn post_json_with_headers(
+                &self,
+                url: &str,
+                body: &Value,
+                headers: &[(&str, &str)],
+            ) -> Result<Value, Error> {
+                let request = headers
+                    .iter()
+                    .fold(self.client.post(url).json(body), |request, &(name, value)| request.header(name, value));
+                ReqwestClient::read(request.send()?)
+            }
+
+            fn put_json(&self, url: &str, body: &Value) -> Result<Value, Error> {
+                ReqwestClient::read(self.client.put(url).json(body).send()?)
+            }
+
+            fn delete(&self, url: &str) -> Result<bool, Error> {
+                let response = self.client.delete(url).send()?;
+                if response.status() == StatusCode::NOT_FOUND {
+                    return Ok(false);
+                }
+                ReqwestClient::read(response).map(|_| true)
+            }
+        }
+    }
+
+    pub mod rest {
+        //! 別のサービスがREST APIで持っている値を、HttpClientComponent越しに読み書きするストレージ。
+        //! `{base}/{key}` を値1つ、`{base}` を全件として、GET・PUT・DELETEと、まとめて保存するPOSTを使う。
+        //! バージョンの確認は向こうのサービスが行い、ぶつかった時は409等のエラーが返る。
+
+        use component::http::HttpClientComponent;
+        use component::storage::{save_in_chunks, StorageComponent, StorageError};
+        use failure::Error;
+        use serde::Serialize;
+        use serde::de::DeserializeOwned;
+        use serde_json::{self, Value};
+        use std::fmt::Debug;
+        use std::marker::PhantomData;
+
+        /// `base_url` の下に値を置くStorageComponent実装。待つ時間の上限は `H` の設定に従う
+        pub struct RestStorage<H, K, V> {
+            client: H,
+            base_url: String,
+            values: PhantomData<fn(K) -> V>,
+        }
+
+        impl<H: HttpClientComponent, K: Serialize + Debug, V> RestStorage<H, K, V> {
+            pub fn new<S: Into<String>>(client: H, base_url: S) -> RestStorage<H, K, V> {
+                RestStorage {
+                    client,
+                    base_url: base_url.into().trim_end_matches('/').to_string(),
+                    values: PhantomData,
+                }
+            }
+
+            /// 文字列に直列化されるキーは引用符を付けずにパスへ入れる
+            fn url(&self, key: &K) -> Result<String, Error> {
+                Ok(match serde_json::to_value(key)? {
+                    Value::String(key) => format!("{}/{}", self.base_url, key),
+                    key => format!("{}/{}", self.base_url, key),
+                })
+            }
+        }
+
+        impl<H, K, V> StorageComponent<K, V> for RestStorage<H, K, V>
+        where
+            H: HttpClientComponent + Sync,
+            K: Serialize + Debug + Ord + Clone + Send + Sync,
+            V: Serialize + DeserializeOwned + Clone + Send + Sync,
+        {
+            fn read(&self, key: K) -> Result<V, Error> {
+                match self.client.find_json(&self.url(&key)?)? {
+                    Some(value) => Ok(serde_json::from_value(value)?),
+                    None => Err(StorageError::not_found(&key).into()),
+                }
+            }
+
+            fn save(&self, key: K, value: V) -> Result<(), Error> {
+                self.client.put_json(&self.url(&key)?, &serde_json::to_value(&value)?)?;
+                Ok(())
+            }
+
+            fn delete(&self, key: K) -> Result<(), Error> {
+                if !self.client.delete(&self.url(&key)?)? {
+                    return Err(StorageError::not_found(&key).into());
+                }
+                Ok(())
+            }
+
+            fn read_all(&self) -> Result<Vec<V>, Error> {
+                self.client.get(&self.base_url)
+            }
+
+            /// 全て保存するか何も保存しないかは、向こうのサービスが1回のPOSTの中で守る
+            fn save_all(&self, values: &[(K, V)]) -> Result<(), Error> {
+                let values: Vec<&V> = values.iter().map(|(_, value)| value).collect();
+                self.client.post_json(&self.base_url, &serde_json::to_value(&values)?)?;
+                Ok(())
+            }
+
+            /// 塊ごとに別のスレッドからPOSTするので、応答を待つ間に他の塊を送れる
+            fn save_all_concurrent(&self, values: &[(K, V)], parallelism: usize) -> Result<(), Error> {
+                save_in_chunks(values, parallelism, |chunk| self.save_all(chunk))
+            }
+        }
+    }
+
+    pub mod timeout {
+        //! 終わらないcomponentの呼び出しを、決めた時間で諦めるデコレータ。
+        //! 呼び出しはデコレータごとに決まった数だけ立てるスレッドで行い、呼び出した側はMonotonicTimeComponentの時計で期限まで待つ。
+        //! 期限を過ぎたら `TimeoutError` を返す。同期の呼び出しは途中で止められないので、裏で終わるまで走らせて結果は捨てる。
+        //! 諦めた呼び出しでスレッドが埋まっても増やさず、待ちきれない分の呼び出しはすぐにエラーにする。
+
+        use chrono::Duration;
+        use component::http::HttpClientComponent;
+        use component::mail::{EmailSenderComponent, Mail};
+        use component::storage::{StorageComponent, UserStorageComponent};
+        use component::time::{MonotonicTimeComponent, StdClock};
+        use entity::user::{Email, Name, User};
+        use failure::Error;
+        use serde_json::Value;
+        use std::cmp;
+        use std::error;
+        use std::fmt;
+        use std::panic::{self, AssertUnwindSafe};
+        use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+        use std::sync::{Arc, Mutex, PoisonError};
+        use std::thread;
+
+        /// 結果が届いたかを確かめる間隔
+        const TICK_MILLIS: i64 = 10;
+
+        /// 1つのデコレータが呼び出しに使うスレッドの数
+        pub const WORKERS: usize = 4;
+
+        /// スレッドが空くのを待てる呼び出しの数
+        pub const BACKLOG: usize = 16;
+
+        type Task = Box<dyn FnOnce() + Send>;
+
+        /// 呼び出しを実行するスレッド。最初に呼ばれた時から `WORKERS` 個まで立て、デコレータを捨てると終わる
+        struct Workers {
+            sender: SyncSender<Task>,
+            receiver: Arc<Mutex<Receiver<Task>>>,
+            spawned: Mutex<usize>,
+        }
+
+        impl Workers {
+            fn new() -> Workers {
+                let (sender, receiver) = mpsc::sync_channel(BACKLOG);
+                Workers {
+                    sender,
+                    receiver: Arc::new(Mutex::new(receiver)),
+                    spawned: Mutex::new(0),
+                }
+            }
+
+            fn run(&self, operation: &str, task: Task) -> Result<(), Error> {
+                {
+                    let mut spawned = self.spawned.lock().unwrap_or_else(PoisonError::into_inner);
+                    if *spawned < WORKERS {
+                        let receiver = self.receiver.clone();
+                        thread::Builder::new().name(format!("timeout-{}", *spawned)).spawn(move || loop {
+                            let task = receiver.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                            match task {
+                                // パニックは結果を送らずに終わった事として呼び出した側に伝わるので、スレッドは使い続ける
+                                Ok(task) => drop(panic::catch_unwind(AssertUnwindSafe(task))),
+                                Err(_) => return,
+                            }
+                        })?;
+                        *spawned += 1;
+                    }
+                }
+                match self.sender.try_send(task) {
+                    Ok(()) => Ok(()),
+                    Err(TrySendError::Full(_)) => bail!("{} is busy: too many calls have not finished", operation),
+                    Err(TrySendError::Disconnected(_)) => bail!("{} has no worker", operation),
+                }
+            }
+        }
+
+        /// `limit` を過ぎても `operation` が終わらなかった
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct TimeoutError {
+            pub operation: String,
+            pub limit: Duration,
+        }
+
+        impl fmt::Display for TimeoutError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{} did not finish within {}ms", self.operation, self.limit.num_milliseconds())
+            }
+        }
+
+        impl error::Error for TimeoutError {}
+
+        /// componentに `.with_timeout(limit)` で重ねる
+        pub trait WithTimeout: Send + Sync + Sized + 'static {
+            fn with_timeout(self, limit: Duration) -> Timeout<Self> {
+                Timeout::with_clock(self, limit, StdClock::new())
+            }
+        }
+
+        impl<C: Send + Sync + 'static> WithTimeout for C {}
+
+        /// 包んだcomponentの呼び出しを `limit` までしか待たないデコレータ。期限は `T` の時計で測る
+        pub struct Timeout<C, T = StdClock> {
+            inner: Arc<C>,
+            limit: Duration,
+            clock: T,
+            workers: Workers,
+        }
+
+        impl<C: Send + Sync + 'static, T: MonotonicTimeComponent> Timeout<C, T> {
+            pub fn with_clock(inner: C, limit: Duration, clock: T) -> Timeout<C, T> {
+                Timeout {
+                    inner: Arc::new(inner),
+                    limit,
+                    clock,
+                    workers: Workers::new(),
+                }
+            }
+
+            pub fn inner(&self) -> &C {
+                &self.inner
+            }
+
+            /// `f` を呼び出し用のスレッドで呼び、結果が届くか期限を過ぎるまで待つ。
+            /// 本物の時計は待った分だけ進むが、テスト用の時計はここで残りを `sleep` して進める
+            fn call<R, F>(&self, operation: &str, f: F) -> Result<R, Error>
+            where
+                R: Send + 'static,
+                F: FnOnce(&C) -> Result<R, Error> + Send + 'static,
+            {
+                let (sender, receiver) = mpsc::channel();
+                let inner = self.inner.clone();
+                self.workers.run(
+                    operation,
+                    Box::new(move || {
+                        // 諦めた後に届いた結果は、受け取る側が居ないので捨てる
+                        let _ = sender.send(f(&inner));
+                    }),
+                )?;
+                let started = self.clock.instant();
+                loop {
+                    let remaining = self.limit - self.clock.elapsed(started);
+                    if remaining <= Duration::zero() {
+                        return Err(TimeoutError {
+                            operation: operation.to_string(),
+                            limit: self.limit,
+                        }
+                        .into());
+                    }
+                    let tick = cmp::min(remaining, Duration::milliseconds(TICK_MILLIS));
+                    let round = self.clock.instant();
+                    match receiver.recv_timeout(tick.to_std().unwrap_or_default()) {
+                        Ok(result) => return result,
+                        Err(RecvTimeoutError::Disconnected) => bail!("{} panicked", operation),
+                        Err(RecvTimeoutError::Timeout) => self.clock.sleep(tick - self.clock.elapsed(round)),
+                    }
+                }
+            }
+        }
+
+        impl<K, V, C, T> StorageComponent<K, V> for Timeout<C, T>
+        where
+            K: Clone + Send + 'static,
+            V: Clone + Send + 'static,
+            C: StorageComponent<K, V> + Send + Sync + 'static,
+            T: MonotonicTimeComponent,
+        {
+            fn read(&self, key: K) -> Result<V, Error> {
+                self.call("storage.read", move |storage| storage.read(key))
+            }
+
+            fn save(&self, key: K, value: V) -> Result<(), Error> {
+                self.call("storage.save", move |storage| storage.save(key, value))
+            }
+
+            fn delete(&self, key: K) -> Result<(), Error> {
+                self.call("storage.delete", move |storage| storage.delete(key))
+            }
+
+            fn read_all(&self) -> Result<Vec<V>, Error> {
+                self.call("storage.read_all", |storage| storage.read_all())
+            }
+
+            /// 期限を過ぎても、包んだストレージが全てか無しかを守ったまま裏で書き終える
+            fn save_all(&self, values: &[(K, V)]) -> Result<(), Error> {
+                let values = values.to_vec();
+                self.call("storage.save_all", move |storage| storage.save_all(&values))
+            }
+
+            fn read_many(&self, keys: &[K]) -> Result<Vec<V>, Error> {
+                let keys = keys.to_vec();
+                self.call("storage.read_many", move |storage| storage.read_many(&keys))
+            }
+
+            fn flush(&self) -> Result<(), Error> {
+                self.call("storage.flush", |storage| storage.flush())
+            }
+        }
+
+        impl<C, T> UserStorageComponent for Timeout<C, T>
+        where
+            C: UserStorageComponent + Send + Sync + 'static,
+            T: MonotonicTimeComponent,
+        {
+            fn read_by_name(&self, name: &Name) -> Result<User, Error> {
+                let name = name.clone();
+                self.call("storage.read_by_name", move |storage| storage.read_by_name(&name))
+            }
+
+            fn read_by_email(&self, email: &Email) -> Result<User, Error> {
+                let email = email.clone();
+                self.call("storage.read_by_email", move |storage| storage.read_by_email(&email))
+            }
+        }
+
+        impl<C, T> HttpClientComponent for Timeout<C, T>
+        where
+            C: HttpClientComponent + Send + Sync + 'static,
+            T: MonotonicTimeComponent,
+        {
+            fn get_json(&self, url: &str) -> Result<Value, Error> {
+                let url = url.to_string();
+                self.call("http.get", move |client| client.get_json(&url))
+            }
+
+            fn find_json(&self, url: &str) -> Result<Option<Value>, Error> {
+                let url = url.to_string();
+                self.call("http.get", move |client| client.find_json(&url))
+            }
+
+            fn post_json(&self, url: &str, body: &Value) -> Result<Value, Error> {
+                let (url, body) = (url.to_string(), body.clone());
+                self.call("http.post", move |client| client.post_json(&url, &body))
+            }
+
+            fn post_json_with_headers(
+                &self,
+                url: &str,
+                body: &Value,
+                headers: &[(&str, &str)],
+            ) -> Result<Value, Error> {
+                let (url, body) = (url.to_string(), body.clone());
+                let headers: Vec<(String, String)> =
+                    headers.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect();
+                self.call("http.post", move |client| {
+                    let headers: Vec<(&str, &str)> = headers.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect();
+                    client.post_json_with_headers(&url, &body, &headers)
+                })
+            }
+
+            fn put_json(&self, url: &str, body: &Value) -> Result<Value, Error> {
+                let (url, body) = (url.to_string(), body.clone());
+                self.call("http.put", move |client| client.put_json(&url, &body))
+            }
+
+            fn delete(&self, url: &str) -> Result<bool, Error> {
+                let url = url.to_string();
+                self.call("http.delete", move |client| client.delete(&url))
+            }
+        }
+
+        /// 期限を過ぎた後に送り終わる事があるので、Timeoutになったメールは届いているかもしれない
+        impl<C, T> EmailSenderComponent for Timeout<C, T>
+        where
+            C: EmailSenderComponent + Send + Sync + 'static,
+            T: MonotonicTimeComponent,
+        {
+            fn send(&self, mail: &Mail) -> Result<(), Error> {
+                let mail = mail.clone();
+                self.call("mail.send", move |sender| sender.send(&mail))
+            }
+        }
+    }
+
+    pub mod webhook {
+        //! ユーザーのイベントを外部のURLへ送るWebhook。
+        //! 本文にはHMAC-SHA256の署名をヘッダで付けるので、受け取る側は同じ鍵で本文を署名し直して確かめられる。
+
+        use chrono::Duration;
+        use component::http::HttpClientComponent;
+        use component::random::{OsRandom, RandomComponent};
+        use component::secrets::Secret;
+        use component::time::{MonotonicTimeComponent, StdClock};
+        use failure::Error;
+        use hmac::{Hmac, Mac};
+        use serde_json::{self, Value};
+        use sha2::Sha256;
+        use std::mem;
+        use std::sync::{Mutex, MutexGuard, PoisonError};
+
+        /// 署名のヘッダ。値は `sha256=<本文のHMAC-SHA256の16進>`
+        pub const SIGNATURE_HEADER: &str = "X-Layered-Signature";
+
+        /// 送れなかったWebhook。後でもう一度送るまで取っておく
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct DeadLetter {
+            pub url: String,
+            pub payload: Value,
+            /// これまでに試した回数
+            pub attempts: u32,
+            /// 最後に失敗した理由
+            pub error: String,
+        }
+
+        /// イベントをWebhookで送るレイヤ
+        pub trait WebhookComponent {
+            /// 設定された全てのURLへ送る。送れなかったものはデッドレターに入れてエラーにする
+            fn deliver(&self, payload: &Value) -> Result<(), Error>;
+            fn dead_letters(&self) -> Vec<DeadLetter>;
+            /// デッドレターをもう一度送り、送れた数を返す。また送れなかったものはデッドレターに残す
+            fn redeliver(&self) -> usize;
+        }
+
+        /// これを実装(impl)している型はWebhookComponentを返せる。抽象化されたGetter.
+        pub trait HaveWebhookComponent {
+            type WebhookComponent: WebhookComponent;
+            fn webhook_component(&self) -> &Self::WebhookComponent;
+        }
+
+        /// `body` を `key` で署名した、SIGNATURE_HEADERの値
+        pub fn signature(key: &Secret, body: &str) -> String {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key.expose().as_bytes())
+                .expect("HMAC accepts any key length");
+            mac.update(body.as_bytes());
+            let hex: String = mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+            format!("sha256={}", hex)
+        }
+
+        /// 署名した本文をHttpClientComponentでPOSTするWebhookComponent実装。
+        /// 1つのURLに `max_attempts` 回まで試し、それでも送れなければデッドレターに入れる。
+        /// 試す間は `M` で待ち、待つ時間は失敗する度に倍にして `R` で揺らすので、落ちている送り先を続けて叩かない。
+        /// デッドレターはメモリ上にしか無いので、再起動すると消える。
+        pub struct WebhookDispatcher<H, M = StdClock, R = OsRandom> {
+            urls: Vec<String>,
+            max_attempts: u32,
+            key: Secret,
+            client: H,
+            clock: M,
+            random: R,
+            dead_letters: Mutex<Vec<DeadLetter>>,
+        }
+
+        impl<H: HttpClientComponent> WebhookDispatcher<H> {
+            pub fn new(urls: Vec<String>, max_attempts: u32, key: Secret, client: H) -> WebhookDispatcher<H> {
+                WebhookDispatcher::with_clock(urls, max_attempts, key, client, StdClock::new(), OsRandom)
+            }
+        }
+
+        impl<H: HttpClientComponent, M: MonotonicTimeComponent, R: RandomComponent> WebhookDispatcher<H, M, R> {
+            pub fn with_clock(
+                urls: Vec<String>,
+                max_attempts: u32,
+                key: Secret,
+                client: H,
+                clock: M,
+                random: R,
+            ) -> WebhookDispatcher<H, M, R> {
+                WebhookDispatcher {
+                    urls,
+                    max_attempts: max_attempts.max(1),
+                    key,
+                    client,
+                    clock,
+                    random,
+                    dead_letters: Mutex::new(Vec::new()),
+                }
+            }
+
+            /// `attempts` 回続けて失敗した後に待つ時間。1秒から倍々にした時間の半分から全部までの間で揺らし、
+            /// 同じ時に失敗した配信が揃ってやり直さないようにする
+            fn delay(&self, attempts: u32) -> Duration {
+                let full = Duration::seconds(1 << attempts.saturating_sub(1).min(10));
+                let half = full.num_milliseconds() / 2;
+                let mut bytes = [0u8; 4];
+                self.random.fill_bytes(&mut bytes);
+                let jitter = i64::from(u32::from_le_bytes(bytes)) % (full.num_milliseconds() - half + 1);
+                Duration::milliseconds(half + jitter)
+            }
+
+            /// 積んでいる途中でpanicしても、積めた分はそのまま使う
+            fn dead_letter_queue(&self) -> MutexGuard<'_, Vec<DeadLetter>> {
+                self.dead_letters.lock().unwrap_or_else(PoisonError::into_inner)
+            }
+
+            /// 送れれば試した回数を、送れなければ試した回数と最後のエラーを返す
+            fn post(&self, url: &str, payload: &Value) -> Result<u32, (u32, Error)> {
+                let body = serde_json::to_string(payload).map_err(|e| (0, e.into()))?;
+                let signature = signature(&self.key, &body);
+                let mut attempts = 0;
+                loop {
+                    attempts += 1;
+                    match self
+                        .client
+                        .post_json_with_headers(url, payload, &[(SIGNATURE_HEADER, &signature)])
+                    {
+                        Ok(_) => return Ok(attempts),
+                        Err(e) if attempts >= self.max_attempts => return Err((attempts, e)),
+                        Err(_) => self.clock.sleep(self.delay(attempts)),
+                    }
+                }
+            }
+        }
+
+        impl<H, M, R> WebhookComponent for WebhookDispatcher<H, M, R>
+        where
+            H: HttpClientComponent,
+            M: MonotonicTimeComponent,
+            R: RandomComponent,
+        {
+            fn deliver(&self, payload: &Value) -> Result<(), Error> {
+                let mut failed = Vec::new();
+                for url in &self.urls {
+                    if let Err((attempts, e)) = self.post(url, payload) {
+                        self.dead_letter_queue().push(DeadLetter {
+                            url: url.clone(),
+                            payload: payload.clone(),
+                            attempts,
+                            error: e.to_string(),
+                        });
+                        failed.push(url.as_str());
+                    }
+                }
+                if !failed.is_empty() {
+                    bail!("failed to deliver webhook to {}", failed.join(", "));
+                }
+                Ok(())
+            }
+
+            fn dead_letters(&self) -> Vec<DeadLetter> {
+                self.dead_letter_queue().clone()
+            }
+
+            fn redeliver(&self) -> usize {
+                let letters = mem::take(&mut *self.dead_letter_queue());
+                let mut delivered = 0;
+                for mut letter in letters {
+                    match self.post(&letter.url, &letter.payload) {
+                        Ok(_) => delivered += 1,
+                        Err((attempts, e)) => {
+                            letter.attempts += attempts;
+                            letter.error = e.to_string();
+                            self.dead_letter_queue().push(letter);
+                        }
+                    }
+                }
+                delivered
+            }
+        }
+    }
+
+    pub mod queue {
+        //! 他のシステムへメッセージを流すキュー。
+        //! メッセージの中身はバイト列のまま扱い、どう読み書きするかは使う側が決める。
+
+        use failure::Error;
+        use std::collections::BTreeMap;
+        use std::sync::{Mutex, MutexGuard};
+        use std::sync::mpsc::{channel, Receiver, Sender};
+
+        /// トピックへメッセージを送り、受け取るレイヤ
+        pub trait MessageQueueComponent {
+            fn publish(&self, topic: &str, payload: &[u8]) -> Result<(), Error>;
+            /// 今受け取れるメッセージを全て取り出す。無ければ空。
+            fn consume(&self, topic: &str) -> Result<Vec<Vec<u8>>, Error>;
+            /// ブローカーに繋がるかを確かめる。プロセス内のキューは常に成功する。
+            fn ping(&self) -> Result<(), Error> {
+                Ok(())
+            }
+        }
+
+        /// これを実装(impl)している型はMessageQueueComponentを返せる。抽象化されたGetter.
+        pub trait HaveMessageQueueComponent {
+            type MessageQueueComponent: MessageQueueComponent;
+            fn message_queue_component(&self) -> &Self::MessageQueueComponent;
+        }
+
+        /// 1つのトピックの送信側と受信側
+        type Channel = (Sender<Vec<u8>>, Receiver<Vec<u8>>);
+
+        /// トピックごとにチャネルを持つ、プロセス内だけのMessageQueueComponent実装
+        #[derive(Default)]
+        pub struct InMemoryQueue {
+            topics: Mutex<BTreeMap<String, Channel>>,
+        }
+
+        impl InMemoryQueue {
+            pub fn new() -> InMemoryQueue {
+                InMemoryQueue::default()
+            }
+
+            fn topics(&self) -> Result<MutexGuard<'_, BTreeMap<String, Channel>>, Error> {
+                self.topics.lock().map_err(|_| format_err!("queue topics are poisoned"))
+            }
+        }
+
+        impl MessageQueueComponent for InMemoryQueue {
+            fn publish(&self, topic: &str, payload: &[u8]) -> Result<(), Error> {
+                let mut topics = self.topics()?;
+                let (sender, _) = topics.entry(topic.to_string()).or_insert_with(channel);
+                // 受信側も自分で持っているので、送信に失敗する事はない
+                sender.send(payload.to_vec()).unwrap();
+                Ok(())
+            }
+
+            fn consume(&self, topic: &str) -> Result<Vec<Vec<u8>>, Error> {
+                Ok(match self.topics()?.get(topic) {
+                    Some((_, receiver)) => receiver.try_iter().collect(),
+                    None => Vec::new(),
+                })
+            }
+        }
+
+        #[cfg(feature = "kafka")]
+        pub use self::kafka_queue::KafkaQueue;
+
+        /// `kafka` featureを有効にした時だけ使えるKafka実装
+        #[cfg(feature = "kafka")]
+        mod kafka_queue {
+            use failure::Error;
+            use kafka::client::KafkaClient;
+            use kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
+            use kafka::producer::{Producer, Record, RequiredAcks};
+            use std::collections::BTreeMap;
+            use std::sync::Mutex;
+            use std::time::Duration;
+            use super::MessageQueueComponent;
+
+            /// MessageQueueComponentをKafkaで実装(impl)する型。
+            /// 受信はトピックごとにconsumerを作り、`group` のオフセットとしてKafkaに記録する。
+            pub struct KafkaQueue {
+                brokers: Vec<String>,
+                group: String,
+                producer: Mutex<Producer>,
+                consumers: Mutex<BTreeMap<String, Consumer>>,
+            }
+
+            impl KafkaQueue {
+                pub fn new(brokers: Vec<String>, group: &str) -> Result<KafkaQueue, Error> {
+                    let producer = Producer::from_hosts(brokers.clone())
+                        .with_ack_timeout(Duration::from_secs(1))
+                        .with_required_acks(RequiredAcks::One)
+                        .create()
+                        .map_err(|e| format_err!("failed to connect to kafka: {}", e))?;
+                    Ok(KafkaQueue {
+                        brokers,
+                        group: group.to_string(),
+                        producer: Mutex::new(producer),
+                        consumers: Mutex::new(BTreeMap::new()),
+                    })
+                }
+            }
+
+            impl MessageQueueComponent for KafkaQueue {
+                fn publish(&self, topic: &str, payload: &[u8]) -> Result<(), Error> {
+                    self.producer
+                        .lock()
+                        .map_err(|_| format_err!("kafka producer is poisoned"))?
+                        .send(&Record::from_value(topic, payload))
+                        .map_err(|e| format_err!("failed to publish to {}: {}", topic, e))
+                }
+
+                fn consume(&self, topic: &str) -> Result<Vec<Vec<u8>>, Error> {
+                    let mut consumers = self.consumers.lock().map_err(|_| format_err!("kafka consumers are poisoned"))?;
+                    if !consumers.contains_key(topic) {
+                        let consumer = Consumer::from_hosts(self.brokers.clone())
+                            .with_topic(topic.to_string())
+                            .with_group(self.group.clone())
+                            .with_fallback_offset(FetchOffset::Earliest)
+                            .with_offset_storage(Some(GroupOffsetStorage::Kafka))
+                            .create()
+                            .map_err(|e| format_err!("failed to subscribe to {}: {}", topic, e))?;
+                        consumers.insert(topic.to_string(), consumer);
+                    }
+                    let consumer = consumers.get_mut(topic).unwrap();
+                    let mut messages = Vec::new();
+                    for set in consumer.poll().map_err(|e| format_err!("failed to poll {}: {}", topic, e))?.iter() {
+                        messages.extend(set.messages().iter().map(|m| m.value.to_vec()));
+                        consumer
+                            .consume_messageset(set)
+                            .map_err(|e| format_err!("failed to consume {}: {}", topic, e))?;
+                    }
+                    consumer
+                        .commit_consumed()
+                        .map_err(|e| format_err!("failed to commit {}: {}", topic, e))?;
+                    Ok(messages)
+                }
+
+                fn ping(&self) -> Result<(), Error> {
+                    KafkaClient::new(self.brokers.clone())
+                        .load_metadata_all()
+                        .map_err(|e| format_err!("failed to connect to kafka: {}", e))
+                }
+            }
+        }
+    }
+
+    pub mod jobs {
+        //! リクエストの外で後から行うジョブのキュー。
+        //! ジョブはStorageComponentに保存するので、ファイルに保存すればプロセスを再起動しても消えない。
+
+        use chrono::prelude::*;
+        use component::storage::StorageComponent;
+        use entity::job::{Job, JobId, JobStatus};
+        use failure::Error;
+        use std::collections::BTreeSet;
+        use std::sync::{Mutex, PoisonError};
+
+        /// ジョブを積み、実行できるものを1つずつ取り出すレイヤ
+        pub trait JobQueueComponent {
+            fn enqueue(&self, job: Job) -> Result<(), Error>;
+            /// `now` までに実行するジョブを古い順に1つ取り出して、実行中にする。無ければNone。
+            fn claim(&self, now: DateTime<Utc>) -> Result<Option<Job>, Error>;
+            /// 終わったジョブをキューから消す
+            fn complete(&self, id: &JobId) -> Result<(), Error>;
+            /// 失敗したジョブを `run_at` にもう一度実行する
+            fn retry(&self, id: &JobId, error: &str, run_at: DateTime<Utc>) -> Result<(), Error>;
+            /// もう実行しないジョブにする。原因を調べられるように、キューから外してデッドレターに残す。
+            fn poison(&self, id: &JobId, error: &str) -> Result<(), Error>;
+            /// 積まれている全てのジョブ
+            fn jobs(&self) -> Result<Vec<Job>, Error>;
+            /// 諦めたジョブ
+            fn dead_letters(&self) -> Result<Vec<Job>, Error>;
+        }
+
+        /// これを実装(impl)している型はJobQueueComponentを返せる。抽象化されたGetter.
+        pub trait HaveJobQueueComponent {
+            type JobQueueComponent: JobQueueComponent;
+            fn job_queue_component(&self) -> &Self::JobQueueComponent;
+        }
+
+        /// 待っているジョブを実行する順に並べる鍵
+        type Due = (DateTime<Utc>, DateTime<Utc>, JobId);
+
+        fn due(job: &Job) -> Due {
+            (job.run_at, job.create_time, job.id.clone())
+        }
+
+        /// JobQueueComponentをStorageComponentの上に実装(impl)する型
+        pub struct StoredJobQueue<S> {
+            storage: S,
+            /// 諦めたジョブの保存先
+            dead_letters: S,
+            /// 待っているジョブの索引。取り出す度に全てのジョブを読まなくて済むようにする。
+            /// 2つのワーカーが同じジョブを取り出さないように、取り出す間はロックしておく。
+            pending: Mutex<BTreeSet<Due>>,
+        }
+
+        impl<S: StorageComponent<JobId, Job>> StoredJobQueue<S> {
+            /// 実行中のまま残っているジョブは前のプロセスが終える前に止まったものなので、もう一度実行する
+            pub fn new(storage: S, dead_letters: S) -> Result<StoredJobQueue<S>, Error> {
+                let mut pending = BTreeSet::new();
+                for mut job in storage.read_all()? {
+                    if job.status == JobStatus::Running {
+                        job.status = JobStatus::Pending;
+                        storage.save(job.id.clone(), job.clone())?;
+                    }
+                    pending.insert(due(&job));
+                }
+                Ok(StoredJobQueue {
+                    storage,
+                    dead_letters,
+                    pending: Mutex::new(pending),
+                })
+            }
+
+            fn fail(&self, id: &JobId, error: &str, status: JobStatus) -> Result<Job, Error> {
+                let mut job = self.storage.read(id.clone())?;
+                job.status = status;
+                job.attempts += 1;
+                job.last_error = Some(error.to_string());
+                Ok(job)
+            }
+        }
+
+        impl<S: StorageComponent<JobId, Job>> JobQueueComponent for StoredJobQueue<S> {
+            fn enqueue(&self, job: Job) -> Result<(), Error> {
+                let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+                let key = due(&job);
+                self.storage.save(job.id.clone(), job)?;
+                pending.insert(key);
+                Ok(())
+            }
+
+            fn claim(&self, now: DateTime<Utc>) -> Result<Option<Job>, Error> {
+                let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+                let key = match pending.iter().next() {
+                    Some(key) if key.0 <= now => key.clone(),
+                    _ => return Ok(None),
+                };
+                let mut job = self.storage.read(key.2.clone())?;
+                job.status = JobStatus::Running;
+                self.storage.save(job.id.clone(), job.clone())?;
+                pending.remove(&key);
+                Ok(Some(job))
+            }
+
+            fn complete(&self, id: &JobId) -> Result<(), Error> {
+                self.storage.delete(id.clone())
+            }
+
+            fn retry(&self, id: &JobId, error: &str, run_at: DateTime<Utc>) -> Result<(), Error> {
+                let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+                let mut job = self.fail(id, error, JobStatus::Pending)?;
+                job.run_at = run_at;
+                let key = due(&job);
+                self.storage.save(id.clone(), job)?;
+                pending.insert(key);
+                Ok(())
+            }
+
+            fn poison(&self, id: &JobId, error: &str) -> Result<(), Error> {
+                let job = self.fail(id, error, JobStatus::Poisoned)?;
+                self.dead_letters.save(id.clone(), job)?;
+                self.storage.delete(id.clone())
+            }
+
+            fn jobs(&self) -> Result<Vec<Job>, Error> {
+                self.storage.read_all()
+            }
+
+            fn dead_letters(&self) -> Result<Vec<Job>, Error> {
+                self.dead_letters.read_all()
+            }
+        }
+    }
+
+    pub mod notification {
+        //! アカウントに起きた変更を本人や運用者に知らせる。
+        //! どの経路で知らせるかはenvが選ぶので、Repositoryは送り先を知らない。
+
+        use component::http::HttpClientComponent;
+        use component::mail::{EmailSenderComponent, Mail};
+        use entity::user::{User, UserId};
+        use failure::Error;
+        use serde_json::Value;
+
+        /// 通知を送るレイヤ
+        pub trait NotificationComponent {
+            fn notify(&self, user: &User, message: &str) -> Result<(), Error>;
+        }
+
+        /// これを実装(impl)している型はNotificationComponentを返せる。抽象化されたGetter.
+        pub trait HaveNotificationComponent {
+            type NotificationComponent: NotificationComponent;
+            fn notification_component(&self) -> &Self::NotificationComponent;
+        }
+
+        /// 標準出力に書き出すNotificationComponent実装
+        pub struct ConsoleNotifier;
+
+        impl NotificationComponent for ConsoleNotifier {
+            fn notify(&self, user: &User, message: &str) -> Result<(), Error> {
+                println!("[notification] {}: {}", user.name, message);
+                Ok(())
+            }
+        }
+
+        /// 本人のメールアドレスに送るNotificationComponent実装
+        pub struct EmailNotifier<S> {
+            sender: S,
+        }
+
+        impl<S: EmailSenderComponent> EmailNotifier<S> {
+            pub fn new(sender: S) -> EmailNotifier<S> {
+                EmailNotifier { sender }
+            }
+        }
+
+        impl<S: EmailSenderComponent> NotificationComponent for EmailNotifier<S> {
+            fn notify(&self, user: &User, message: &str) -> Result<(), Error> {
+                self.sender.send(&Mail {
+                    to: user.email.clone(),
+                    subject: "Your account was updated".to_string(),
+                    body: message.to_string(),
+                })
+            }
+        }
+
+        #[derive(Serialize)]
+        struct WebhookPayload<'a> {
+            user_id: &'a UserId,
+            name: &'a str,
+            message: &'a str,
+        }
+
+        /// 指定したURLにJSONをPOSTするNotificationComponent実装。応答の本文は読み捨てる。
+        pub struct WebhookNotifier<H> {
+            url: String,
+            client: H,
+        }
+
+        impl<H: HttpClientComponent> WebhookNotifier<H> {
+            pub fn new(url: &str, client: H) -> WebhookNotifier<H> {
+                WebhookNotifier {
+                    url: url.to_string(),
+                    client,
+                }
+            }
+        }
+
+        impl<H: HttpClientComponent> NotificationComponent for WebhookNotifier<H> {
+            fn notify(&self, user: &User, message: &str) -> Result<(), Error> {
+                let payload = WebhookPayload {
+                    user_id: &user.id,
+                    name: user.name.as_str(),
+                    message,
+                };
+                self.client.post::<_, Value>(&self.url, &payload)?;
+                Ok(())
+            }
+        }
+    }
+
+    pub mod log {
+        //! ログ出力もcomponentとして差し込む。
+        //! 各レイヤから直接 `println!` せずにLoggingComponent経由で書けば、出力先の切り替えやテストでの確認が出来る。
+
+        use std::fmt;
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub enum Level {
+            Info,
+            Warn,
+            Error,
+        }
+
+        impl fmt::Display for Level {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(match *self {
+                    Level::Info => "INFO",
+                    Level::Warn => "WARN",
+                    Level::Error => "ERROR",
+                })
+            }
+        }
+
+        /// ログを書き出すレイヤ
+        pub trait LoggingComponent {
+            fn log(&self, level: Level, message: &str);
+
+            fn info(&self, message: &str) {
+                self.log(Level::Info, message);
+            }
+
+            fn warn(&self, message: &str) {
+                self.log(Level::Warn, message);
+            }
+        }
+
+        /// これを実装(impl)している型はLoggingComponentを返せる。抽象化されたGetter.
+        pub trait HaveLoggingComponent {
+            type LoggingComponent: LoggingComponent;
+            fn logging_component(&self) -> &Self::LoggingComponent;
+        }
+
+        /// 標準エラー出力に書き出すLoggingComponent実装
+        pub struct ConsoleLogger;
+
+        impl LoggingComponent for ConsoleLogger {
+            fn log(&self, level: Level, message: &str) {
+                eprintln!("[{}] {}", level, message);
+            }
+        }
+    }
+
+    pub mod storage {
+        use component::file::RecordCodec;
+        use component::filesystem::FileSystemComponent;
+        use entity::Entity;
+        use entity::api_token::{ApiToken, ApiTokenId};
+        use entity::credentials::Credentials;
+        use entity::group::{Group, GroupName};
+        use entity::invitation::{Invitation, InvitationId};
+        use entity::password_reset::{PasswordResetId, PasswordResetToken};
+        use entity::profile::Profile;
+        use entity::session::{Session, SessionId};
+        use entity::user::{Email, Name, User, UserId};
+        use failure::Error;
+        use std::collections::hash_map::DefaultHasher;
+        use std::collections::{BTreeMap, BTreeSet};
+        use std::error;
+        use std::fmt::{self, Debug};
+        use std::hash::{Hash, Hasher};
+        use std::iter;
+        use std::path::Path;
+        use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+        use std::thread;
+
+        /// ストレージ操作が失敗した理由のうち、呼び出し側が区別したいもの
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum StorageError {
+            /// 保存しようとした値が、既に保存されている値より新しくなかった(他の誰かが先に更新した)
+            Conflict { stored: u64, given: u64 },
+            /// 指定されたキーの値が保存されていない
+            NotFound { key: String },
+            /// 他と被ってはいけない値(名前・メールアドレスなど)を、既に別の値が使っている
+            Taken { field: &'static str, value: String },
+        }
+
+        impl StorageError {
+            pub fn not_found<K: Debug>(key: &K) -> StorageError {
+                StorageError::NotFound {
+                    key: format!("{:?}", key),
+                }
+            }
+
+            pub fn taken<T: Debug>(field: &'static str, value: &T) -> StorageError {
+                StorageError::Taken {
+                    field,
+                    value: format!("{:?}", value),
+                }
+            }
+        }
+
+        impl fmt::Display for StorageError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match *self {
+                    StorageError::Conflict { stored, given } => {
+                        write!(f, "version conflict: stored {}, given {}", stored, given)
+                    }
+                    StorageError::NotFound { ref key } => write!(f, "not found: {}", key),
+                    StorageError::Taken { field, ref value } => write!(f, "{} already taken: {}", field, value),
+                }
+            }
+        }
+
+        impl error::Error for StorageError {}
+
+        /// 楽観的排他制御のチェック。
+        /// バージョンを持つEntityは、既に保存されている値より新しいバージョンでなければ保存できない。
+        pub fn check_version<V: Entity>(stored: Option<&V>, given: &V) -> Result<(), StorageError> {
+            match (stored.and_then(Entity::version), given.version()) {
+                (Some(stored), Some(given)) if given <= stored => Err(StorageError::Conflict { stored, given }),
+                _ => Ok(()),
+            }
+        }
+
+        /// `values` をキーで `parallelism` 個までの塊に分け、塊ごとに別のスレッドで `save` を呼ぶ。
+        /// 同じキーの値は全て同じ塊に、並べた順のまま入る。
+        /// 全ての塊が終わるのを待ってから、失敗した塊があれば先頭の塊に近いもののエラーを返す。
+        pub fn save_in_chunks<K, V, F>(values: &[(K, V)], parallelism: usize, save: F) -> Result<(), Error>
+        where
+            K: Ord + Clone + Send + Sync,
+            V: Clone + Send + Sync,
+            F: Fn(&[(K, V)]) -> Result<(), Error> + Sync,
+        {
+            if values.is_empty() {
+                return Ok(());
+            }
+            let mut chunks = vec![Vec::new(); parallelism.clamp(1, values.len())];
+            let mut assigned = BTreeMap::new();
+            for (key, value) in values {
+                let next = assigned.len() % chunks.len();
+                let chunk = *assigned.entry(key).or_insert(next);
+                chunks[chunk].push((key.clone(), value.clone()));
+            }
+            if chunks.len() == 1 {
+                return save(&chunks[0]);
+            }
+            let save = &save;
+            let results: Vec<Result<(), Error>> = thread::scope(|scope| {
+                let handles: Vec<_> = chunks.iter().map(|chunk| scope.spawn(move || save(chunk))).collect();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap_or_else(|_| Err(format_err!("saving a chunk panicked"))))
+                    .collect()
+            });
+            results.into_iter().collect()
+        }
+
+        /// キーと値の組をストレージに出し入れするレイヤ。
+        /// Entityの種類ごとにストレージのtraitを書かなくて済むように、キーと値の型をパラメータにしている。
+        pub trait StorageComponent<K, V> {
+            fn read(&self, key: K) -> Result<V, Error>;
+            fn save(&self, key: K, value: V) -> Result<(), Error>;
+            fn delete(&self, key: K) -> Result<(), Error>;
+            fn read_all(&self) -> Result<Vec<V>, Error>;
+            fn save_all(&self, values: &[(K, V)]) -> Result<(), Error>;
+
+            /// まとめて読む。保存されていないキーは飛ばし、見つかった値をキーと同じ順番で返す。
+            /// ここでは1件ずつ読むので、まとめて問い合わせられるストレージはこれを上書きする。
+            fn read_many(&self, keys: &[K]) -> Result<Vec<V>, Error>
+            where
+                K: Clone,
+            {
+                let mut values = Vec::with_capacity(keys.len());
+                for key in keys {
+                    match self.read(key.clone()) {
+                        Ok(value) => values.push(value),
+                        Err(e) => match e.downcast_ref::<StorageError>() {
+                            Some(StorageError::NotFound { .. }) => {}
+                            _ => return Err(e),
+                        },
+                    }
+                }
+                Ok(values)
+            }
+
+            /// 保存されている値を1件ずつ順に読む。
+            /// ここではread_allで全部読んでから返すので、少しずつ読み出せるストレージはこれを上書きする。
+            fn iter_all<'a>(&'a self) -> Box<dyn Iterator<Item = Result<V, Error>> + 'a>
+            where
+                V: 'a,
+            {
+                match self.read_all() {
+                    Ok(values) => Box::new(values.into_iter().map(Ok)),
+                    Err(e) => Box::new(iter::once(Err(e))),
+                }
+            }
+
+            /// まだ書き出していない変更を書き出す。プロセスを終了する前に呼ぶ。
+            /// ここではすぐに書き出す前提で何もしないので、変更を溜めておくストレージはこれを上書きする。
+            fn flush(&self) -> Result<(), Error> {
+                Ok(())
+            }
+
+            /// 大量の値を `parallelism` 個までの塊に分けて、塊ごとに並行して保存する。
+            /// 同じキーの値は同じ塊に並べた順のまま入るので、`save_all` と同じく最後の値が残る。
+            /// 全て保存するか1件も保存しないかは塊の中でだけ守られ、どれかの塊が失敗しても他の塊は保存されたまま残る。
+            /// 全てか無しかが要る時は `save_all` を使う。
+            /// ここでは並行に書けないので `save_all` で1回で書く。塊ごとに別々に書けるストレージは `save_in_chunks` で上書きする。
+            fn save_all_concurrent(&self, values: &[(K, V)], _parallelism: usize) -> Result<(), Error> {
+                self.save_all(values)
+            }
+        }
+
+        /// これを実装(impl)している型はEntity `E` 用のStorageComponentを返せる。
+        /// 汎用のRepositoryはこのtraitだけを見て実装(impl)される。
+        pub trait HaveStorageComponent<E: Entity> {
+            type StorageComponent: StorageComponent<E::Id, E>;
+            fn storage_component(&self) -> &Self::StorageComponent;
+        }
+
+        /// ユーザー情報をストレージに出し入れするレイヤ。
+        /// ユーザーはUserIdで保存し、名前・メールアドレスからも引けるようにしておく。
+        pub trait UserStorageComponent: StorageComponent<UserId, User> {
+            fn read_by_name(&self, name: &Name) -> Result<User, Error>;
+            fn read_by_email(&self, email: &Email) -> Result<User, Error>;
+        }
+
+        /// これを実装(impl)している型はUserStorageComponentを返せる。抽象化されたGetter.
+        /// 書き込みもStorageComponentが中でロックを取って `&self` で行うので、Getterは `&self` の1つだけでよい。
+        pub trait HaveUserStorageComponent {
+            type UserStorageComponent: UserStorageComponent;
+            fn user_storage_component(&self) -> &Self::UserStorageComponent;
+        }
+
+        /// ストレージ `S` に名前・メールアドレスからUserIdへの索引を付けるUserStorageComponent。
+        /// 同じ名前・同じメールアドレスのユーザーが2人できないようにするのもここで行う。
+        /// Emailは生成時に正規化されているので、大文字小文字違いのアドレスも同じキーになる。
+        /// 重複の確認から保存までの間は索引のロックを持ったままにするので、同時に保存しても索引とずれない。
+        pub struct IndexedUserStorage<S> {
+            storage: S,
+            index: Mutex<UserIndex>,
+        }
+
+        #[derive(Default)]
+        struct UserIndex {
+            names: BTreeMap<Name, UserId>,
+            emails: BTreeMap<Email, UserId>,
+        }
+
+        impl UserIndex {
+            fn build(users: Vec<User>) -> UserIndex {
+                let mut index = UserIndex::default();
+                for user in users {
+                    index.names.insert(user.name, user.id.clone());
+                    index.emails.insert(user.email, user.id);
+                }
+                index
+            }
+        }
+
+        impl<S: StorageComponent<UserId, User>> IndexedUserStorage<S> {
+            /// 既にストレージに入っているユーザーから索引を作る
+            pub fn new(storage: S) -> Result<IndexedUserStorage<S>, Error> {
+                let index = UserIndex::build(storage.read_all()?);
+                Ok(IndexedUserStorage { storage, index: Mutex::new(index) })
+            }
+
+            pub fn storage(&self) -> &S {
+                &self.storage
+            }
+
+            fn index(&self) -> Result<MutexGuard<'_, UserIndex>, Error> {
+                self.index.lock().map_err(|_| format_err!("user index is poisoned"))
+            }
+
+            /// 全件の名前・メールアドレスを確かめてから `save` で保存し、索引を更新する。
+            /// 保存に失敗した時は、どこまで保存されたかが分からないので索引をストレージから作り直す。
+            fn save_batch<F>(&self, users: &[(UserId, User)], save: F) -> Result<(), Error>
+            where
+                F: FnOnce(&[(UserId, User)]) -> Result<(), Error>,
+            {
+                let mut index = self.index()?;
+                let mut names = BTreeMap::new();
+                let mut emails = BTreeMap::new();
+                for (id, user) in users {
+                    let taken = |owner: Option<&UserId>| owner.map(|owner| owner != id).unwrap_or(false);
+                    if taken(index.names.get(&user.name)) || taken(names.insert(&user.name, id)) {
+                        return Err(StorageError::taken("name", &user.name).into());
+                    }
+                    if taken(index.emails.get(&user.email)) || taken(emails.insert(&user.email, id)) {
+                        return Err(StorageError::taken("email", &user.email).into());
+                    }
+                }
+                let ids: Vec<UserId> = users.iter().map(|(id, _)| id.clone()).collect();
+                let olds = self.storage.read_many(&ids)?;
+                if let Err(e) = save(users) {
+                    *index = UserIndex::build(self.storage.read_all()?);
+                    return Err(e);
+                }
+                for old in olds {
+                    index.names.remove(&old.name);
+                    index.emails.remove(&old.email);
+                }
+                for (id, user) in users {
+                    index.names.insert(user.name.clone(), id.clone());
+                    index.emails.insert(user.email.clone(), id.clone());
+                }
+                Ok(())
+            }
+        }
+
+        impl<S: StorageComponent<UserId, User>> StorageComponent<UserId, User> for IndexedUserStorage<S> {
+            fn read(&self, id: UserId) -> Result<User, Error> {
+                self.storage.read(id)
+            }
+
+            fn read_many(&self, ids: &[UserId]) -> Result<Vec<User>, Error> {
+                self.storage.read_many(ids)
+            }
+
+            fn save(&self, id: UserId, user: User) -> Result<(), Error> {
+                let mut index = self.index()?;
+                if index.names.get(&user.name).map(|owner| *owner != id).unwrap_or(false) {
+                    return Err(StorageError::taken("name", &user.name).into());
+                }
+                if index.emails.get(&user.email).map(|owner| *owner != id).unwrap_or(false) {
+                    return Err(StorageError::taken("email", &user.email).into());
+                }
+                let old = self.storage.read(id.clone()).ok();
+                let (name, email) = (user.name.clone(), user.email.clone());
+                self.storage.save(id.clone(), user)?;
+                if let Some(old) = old {
+                    index.names.remove(&old.name);
+                    index.emails.remove(&old.email);
+                }
+                index.names.insert(name, id.clone());
+                index.emails.insert(email, id);
+                Ok(())
+            }
+
+            fn delete(&self, id: UserId) -> Result<(), Error> {
+                let mut index = self.index()?;
+                let user = self.storage.read(id.clone())?;
+                self.storage.delete(id)?;
+                index.names.remove(&user.name);
+                index.emails.remove(&user.email);
+                Ok(())
+            }
+
+            fn read_all(&self) -> Result<Vec<User>, Error> {
+                self.storage.read_all()
+            }
+
+            fn flush(&self) -> Result<(), Error> {
+                self.storage.flush()
+            }
+
+            fn iter_all<'a>(&'a self) -> Box<dyn Iterator<Item = Result<User, Error>> + 'a>
+            where
+                User: 'a,
+            {
+                self.storage.iter_all()
+            }
+
+            /// 全件の名前・メールアドレスを確かめてから、ストレージへは1回で保存する
+            fn save_all(&self, users: &[(UserId, User)]) -> Result<(), Error> {
+                self.save_batch(users, |users| self.storage.save_all(users))
+            }
+
+            /// 名前・メールアドレスは全件まとめて確かめるので、塊をまたいで重複していても1件も保存しない
+            fn save_all_concurrent(&self, users: &[(UserId, User)], parallelism: usize) -> Result<(), Error> {
+                self.save_batch(users, |users| self.storage.save_all_concurrent(users, parallelism))
+            }
+        }
+
+        impl<S: StorageComponent<UserId, User>> UserStorageComponent for IndexedUserStorage<S> {
+            fn read_by_name(&self, name: &Name) -> Result<User, Error> {
+                match self.index()?.names.get(name).cloned() {
+                    Some(id) => self.storage.read(id),
+                    None => Err(StorageError::not_found(name).into()),
+                }
+            }
+
+            fn read_by_email(&self, email: &Email) -> Result<User, Error> {
+                match self.index()?.emails.get(email).cloned() {
+                    Some(id) => self.storage.read(id),
+                    None => Err(StorageError::not_found(email).into()),
+                }
+            }
+        }
+
+        impl<T: HaveUserStorageComponent> HaveStorageComponent<User> for T {
+            type StorageComponent = T::UserStorageComponent;
+            fn storage_component(&self) -> &T::UserStorageComponent {
+                self.user_storage_component()
+            }
+        }
+
+        /// 認証情報をストレージに出し入れするレイヤ
+        pub trait CredentialStorageComponent: StorageComponent<UserId, Credentials> {}
+
+        impl<T: StorageComponent<UserId, Credentials>> CredentialStorageComponent for T {}
+
+        /// これを実装(impl)している型はCredentialStorageComponentを返せる。抽象化されたGetter.
+        pub trait HaveCredentialStorageComponent {
+            type CredentialStorageComponent: CredentialStorageComponent;
+            fn credential_storage_component(&self) -> &Self::CredentialStorageComponent;
+        }
+
+        impl<T: HaveCredentialStorageComponent> HaveStorageComponent<Credentials> for T {
+            type StorageComponent = T::CredentialStorageComponent;
+            fn storage_component(&self) -> &T::CredentialStorageComponent {
+                self.credential_storage_component()
+            }
+        }
+
+        /// グループをストレージに出し入れするレイヤ
+        pub trait GroupStorageComponent: StorageComponent<GroupName, Group> {}
+
+        impl<T: StorageComponent<GroupName, Group>> GroupStorageComponent for T {}
+
+        /// これを実装(impl)している型はGroupStorageComponentを返せる。抽象化されたGetter.
+        pub trait HaveGroupStorageComponent {
+            type GroupStorageComponent: GroupStorageComponent;
+            fn group_storage_component(&self) -> &Self::GroupStorageComponent;
+        }
+
+        impl<T: HaveGroupStorageComponent> HaveStorageComponent<Group> for T {
+            type StorageComponent = T::GroupStorageComponent;
+            fn storage_component(&self) -> &T::GroupStorageComponent {
+                self.group_storage_component()
+            }
+        }
+
+        /// プロフィールをストレージに出し入れするレイヤ
+        pub trait ProfileStorageComponent: StorageComponent<UserId, Profile> {}
+
+        impl<T: StorageComponent<UserId, Profile>> ProfileStorageComponent for T {}
+
+        /// これを実装(impl)している型はProfileStorageComponentを返せる。抽象化されたGetter.
+        pub trait HaveProfileStorageComponent {
+            type ProfileStorageComponent: ProfileStorageComponent;
+            fn profile_storage_component(&self) -> &Self::ProfileStorageComponent;
+        }
+
+        impl<T: HaveProfileStorageComponent> HaveStorageComponent<Profile> for T {
+            type StorageComponent = T::ProfileStorageComponent;
+            fn storage_component(&self) -> &T::ProfileStorageComponent {
+                self.profile_storage_component()
+            }
+        }
+
+        /// ログインセッションをストレージに出し入れするレイヤ
+        pub trait SessionStorageComponent: StorageComponent<SessionId, Session> {}
+
+        impl<T: StorageComponent<SessionId, Session>> SessionStorageComponent for T {}
+
+        /// これを実装(impl)している型はSessionStorageComponentを返せる。抽象化されたGetter.
+        pub trait HaveSessionStorageComponent {
+            type SessionStorageComponent: SessionStorageComponent;
+            fn session_storage_component(&self) -> &Self::SessionStorageComponent;
+        }
+
+        impl<T: HaveSessionStorageComponent> HaveStorageComponent<Session> for T {
+            type StorageComponent = T::SessionStorageComponent;
+            fn storage_component(&self) -> &T::SessionStorageComponent {
+                self.session_storage_component()
+            }
+        }
+
+        /// APIトークンをストレージに出し入れするレイヤ
+        pub trait ApiTokenStorageComponent: StorageComponent<ApiTokenId, ApiToken> {}
+
+        impl<T: StorageComponent<ApiTokenId, ApiToken>> ApiTokenStorageComponent for T {}
+
+        /// これを実装(impl)している型はApiTokenStorageComponentを返せる。抽象化されたGetter.
+        pub trait HaveApiTokenStorageComponent {
+            type ApiTokenStorageComponent: ApiTokenStorageComponent;
+            fn api_token_storage_component(&self) -> &Self::ApiTokenStorageComponent;
+        }
+
+        impl<T: HaveApiTokenStorageComponent> HaveStorageComponent<ApiToken> for T {
+            type StorageComponent = T::ApiTokenStorageComponent;
+            fn storage_component(&self) -> &T::ApiTokenStorageComponent {
+                self.api_token_storage_component()
+            }
+        }
+
+        /// パスワード再設定用のトークンをストレージに出し入れするレイヤ
+        pub trait PasswordResetStorageComponent: StorageComponent<PasswordResetId, PasswordResetToken> {}
+
+        impl<T: StorageComponent<PasswordResetId, PasswordResetToken>> PasswordResetStorageComponent for T {}
+
+        /// これを実装(impl)している型はPasswordResetStorageComponentを返せる。抽象化されたGetter.
+        pub trait HavePasswordResetStorageComponent {
+            type PasswordResetStorageComponent: PasswordResetStorageComponent;
+            fn password_reset_storage_component(&self) -> &Self::PasswordResetStorageComponent;
+        }
+
+        impl<T: HavePasswordResetStorageComponent> HaveStorageComponent<PasswordResetToken> for T {
+            type StorageComponent = T::PasswordResetStorageComponent;
+            fn storage_component(&self) -> &T::PasswordResetStorageComponent {
+                self.password_reset_storage_component()
+            }
+        }
+
+        /// 招待をストレージに出し入れするレイヤ
+        pub trait InvitationStorageComponent: StorageComponent<InvitationId, Invitation> {}
+
+        impl<T: StorageComponent<InvitationId, Invitation>> InvitationStorageComponent for T {}
+
+        /// これを実装(impl)している型はInvitationStorageComponentを返せる。抽象化されたGetter.
+        pub trait HaveInvitationStorageComponent {
+            type InvitationStorageComponent: InvitationStorageComponent;
+            fn invitation_storage_component(&self) -> &Self::InvitationStorageComponent;
+        }
+
+        impl<T: HaveInvitationStorageComponent> HaveStorageComponent<Invitation> for T {
+            type StorageComponent = T::InvitationStorageComponent;
+            fn storage_component(&self) -> &T::InvitationStorageComponent {
+                self.invitation_storage_component()
+            }
+        }
+
+        /// メモリ上に値を保持するストレージ抽象型。
+        /// `&self` のまま書き込めるように、値はRwLockの中に持つ。
+        pub struct MemoryStorage<K, V> {
+            list: RwLock<BTreeMap<K, V>>,
+        }
+
+        /// MemoryStorage型のメソッドを定義
+        impl<K: Ord, V> MemoryStorage<K, V> {
+            pub fn new() -> MemoryStorage<K, V> {
+                MemoryStorage {
+                    list: RwLock::new(BTreeMap::new()),
+                }
+            }
+
+            /// 書き込みの途中でパニックしても値は壊れていないので、毒されたロックもそのまま使う
+            fn entries(&self) -> RwLockReadGuard<'_, BTreeMap<K, V>> {
+                self.list.read().unwrap_or_else(PoisonError::into_inner)
+            }
+
+            fn entries_mut(&self) -> RwLockWriteGuard<'_, BTreeMap<K, V>> {
+                self.list.write().unwrap_or_else(PoisonError::into_inner)
+            }
+        }
+
+        impl<K: Ord, V> Default for MemoryStorage<K, V> {
+            fn default() -> MemoryStorage<K, V> {
+                MemoryStorage::new()
+            }
+        }
+
+        /// メモリ上の値はプロセスと一緒に消えるので、終了する前にファイルへ書き出し、次に起動した時に読み込む。
+        /// ファイルの形は `FileStorage` と同じ1行1レコードなので、`FileStorage` でもそのまま開ける。
+        impl<K: Ord, V: Entity<Id = K>> MemoryStorage<K, V> {
+            /// `snapshot` で書き出したファイルから作る。ファイルが無ければ空
+            pub fn restore<C, F>(path: &Path, codec: &C, fs: &F) -> Result<MemoryStorage<K, V>, Error>
+            where
+                C: RecordCodec<V> + ?Sized,
+                F: FileSystemComponent,
+            {
+                let mut list = BTreeMap::new();
+                if let Some(contents) = fs.read(path)? {
+                    for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+                        let value = codec.decode(line)?;
+                        list.insert(value.id(), value);
+                    }
+                }
+                Ok(MemoryStorage { list: RwLock::new(list) })
+            }
+
+            /// 全件を `path` に書き出し、書き出した件数を返す
+            pub fn snapshot<C, F>(&self, path: &Path, codec: &C, fs: &F) -> Result<usize, Error>
+            where
+                C: RecordCodec<V> + ?Sized,
+                F: FileSystemComponent,
+            {
+                let entries = self.entries();
+                let mut contents = String::new();
+                for value in entries.values() {
+                    contents.push_str(&codec.encode(value)?);
+                    contents.push('\n');
+                }
+                fs.write(path, &contents)?;
+                Ok(entries.len())
+            }
+        }
+
+        /// MemoryStorage型用のStorageComponentの実装(impl)
+        impl<K: Ord + Clone + Debug, V: Entity + Clone> StorageComponent<K, V> for MemoryStorage<K, V> {
+            fn read(&self, key: K) -> Result<V, Error> {
+                self.entries()
+                    .get(&key)
+                    .cloned()
+                    .ok_or_else(|| StorageError::not_found(&key).into())
+            }
+
+            /// バージョンの確認から書き込みまで書き込みロックを持つので、同時に更新しても片方はConflictになる
+            fn save(&self, key: K, value: V) -> Result<(), Error> {
+                let mut entries = self.entries_mut();
+                check_version(entries.get(&key), &value)?;
+                entries.insert(key, value);
+                Ok(())
+            }
+
+            fn delete(&self, key: K) -> Result<(), Error> {
+                self.entries_mut()
+                    .remove(&key)
+                    .map(|_| ())
+                    .ok_or_else(|| StorageError::not_found(&key).into())
+            }
+
+            fn read_all(&self) -> Result<Vec<V>, Error> {
+                Ok(self.entries().values().cloned().collect())
+            }
+
+            fn read_many(&self, keys: &[K]) -> Result<Vec<V>, Error> {
+                let entries = self.entries();
+                Ok(keys.iter().filter_map(|key| entries.get(key).cloned()).collect())
+            }
+
+            /// 全件のバージョンを確かめてから書き込む。どれかがConflictなら1件も保存しない
+            fn save_all(&self, values: &[(K, V)]) -> Result<(), Error> {
+                let mut entries = self.entries_mut();
+                for (key, value) in values {
+                    check_version(entries.get(key), value)?;
+                }
+                for (key, value) in values {
+                    entries.insert(key.clone(), value.clone());
+                }
+                Ok(())
+            }
+        }
+
+        /// 同時に読み書きするスレッドが多い時のMemoryStorage。
+        /// キーのハッシュで値を `shards` 個のRwLockに分けて持つので、別のシャードのキーへの書き込みは互いに待たない。
+        /// DashMapと同じ作りだが、`save_all` で複数のシャードをまとめてロックするために自前で持っている。
+        pub struct ConcurrentMemoryStorage<K, V> {
+            shards: Vec<RwLock<BTreeMap<K, V>>>,
+        }
+
+        impl<K: Ord + Hash, V> ConcurrentMemoryStorage<K, V> {
+            /// 論理CPU数の4倍のシャードに分ける
+            pub fn new() -> ConcurrentMemoryStorage<K, V> {
+                let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+                ConcurrentMemoryStorage::with_shards(cpus * 4)
+            }
+
+            pub fn with_shards(shards: usize) -> ConcurrentMemoryStorage<K, V> {
+                ConcurrentMemoryStorage {
+                    shards: (0..shards.max(1)).map(|_| RwLock::new(BTreeMap::new())).collect(),
+                }
+            }
+
+            fn shard_of(&self, key: &K) -> usize {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                (hasher.finish() % self.shards.len() as u64) as usize
+            }
+
+            /// MemoryStorageと同じく、毒されたロックもそのまま使う
+            fn shard(&self, key: &K) -> RwLockReadGuard<'_, BTreeMap<K, V>> {
+                self.shards[self.shard_of(key)].read().unwrap_or_else(PoisonError::into_inner)
+            }
+
+            fn shard_mut(&self, key: &K) -> RwLockWriteGuard<'_, BTreeMap<K, V>> {
+                self.shards[self.shard_of(key)].write().unwrap_or_else(PoisonError::into_inner)
+            }
+        }
+
+        impl<K: Ord + Hash, V> Default for ConcurrentMemoryStorage<K, V> {
+            fn default() -> ConcurrentMemoryStorage<K, V> {
+                ConcurrentMemoryStorage::new()
+            }
+        }
+
+        impl<K, V> StorageComponent<K, V> for ConcurrentMemoryStorage<K, V>
+        where
+            K: Ord + Hash + Clone + Debug + Send + Sync,
+            V: Entity + Clone + Send + Sync,
+        {
+            fn read(&self, key: K) -> Result<V, Error> {
+                self.shard(&key)
+                    .get(&key)
+                    .cloned()
+                    .ok_or_else(|| StorageError::not_found(&key).into())
+            }
+
+            /// バージョンの確認から書き込みまで、そのキーのシャードの書き込みロックだけを持つ
+            fn save(&self, key: K, value: V) -> Result<(), Error> {
+                let mut shard = self.shard_mut(&key);
+                check_version(shard.get(&key), &value)?;
+                shard.insert(key, value);
+                Ok(())
+            }
+
+            fn delete(&self, key: K) -> Result<(), Error> {
+                self.shard_mut(&key)
+                    .remove(&key)
+                    .map(|_| ())
+                    .ok_or_else(|| StorageError::not_found(&key).into())
+            }
+
+            /// シャードを1つずつ読むので、読んでいる途中の書き込みは一部だけ見える事がある。
+            /// 並びはMemoryStorageと同じくキーの順にする
+            fn read_all(&self) -> Result<Vec<V>, Error> {
+                let mut entries = Vec::new();
+                for shard in &self.shards {
+                    let shard = shard.read().unwrap_or_else(PoisonError::into_inner);
+                    entries.extend(shard.iter().map(|(key, value)| (key.clone(), value.clone())));
+                }
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                Ok(entries.into_iter().map(|(_, value)| value).collect())
+            }
+
+            fn read_many(&self, keys: &[K]) -> Result<Vec<V>, Error> {
+                Ok(keys.iter().filter_map(|key| self.shard(key).get(key).cloned()).collect())
+            }
+
+            /// 関わるシャードの書き込みロックを番号の順に全て取ってから確かめるので、
+            /// 互いに待ち合って止まる事は無く、どれかがConflictなら1件も保存しない
+            fn save_all(&self, values: &[(K, V)]) -> Result<(), Error> {
+                let indices: BTreeSet<usize> = values.iter().map(|(key, _)| self.shard_of(key)).collect();
+                let mut shards: BTreeMap<usize, RwLockWriteGuard<'_, BTreeMap<K, V>>> = indices
+                    .into_iter()
+                    .map(|i| (i, self.shards[i].write().unwrap_or_else(PoisonError::into_inner)))
+                    .collect();
+                for (key, value) in values {
+                    check_version(shards[&self.shard_of(key)].get(key), value)?;
+                }
+                for (key, value) in values {
+                    if let Some(shard) = shards.get_mut(&self.shard_of(key)) {
+                        shard.insert(key.clone(), value.clone());
+                    }
+                }
+                Ok(())
+            }
+
+            /// 塊ごとに `save_all` するので、別のシャードに落ちた塊は同時に書き込める
+            fn save_all_concurrent(&self, values: &[(K, V)], parallelism: usize) -> Result<(), Error> {
+                save_in_chunks(values, parallelism, |chunk| self.save_all(chunk))
+            }
+        }
+    }
+
+    pub mod nonblocking {
+        //! 待つ間スレッドを塞がない、ユーザーのストレージのtrait。
+        //! ネットワークやDBの向こうのバックエンドはこちらを直接実装(impl)する。
+
+        use component::storage::{StorageComponent, UserStorageComponent};
+        use entity::user::{Email, Name, User, UserId};
+        use failure::Error;
+        use futures::future::Future;
+        use tokio::runtime::{self, Runtime};
+
+        /// StorageComponentの非同期版
+        pub trait AsyncStorageComponent<K, V> {
+            fn read(&self, key: K) -> impl Future<Output = Result<V, Error>> + Send;
+            fn save(&self, key: K, value: V) -> impl Future<Output = Result<(), Error>> + Send;
+            fn delete(&self, key: K) -> impl Future<Output = Result<(), Error>> + Send;
+            fn read_all(&self) -> impl Future<Output = Result<Vec<V>, Error>> + Send;
+            /// 全て保存するか何も保存しないか。同じキーが2回入っていれば後の方が残る
+            fn save_all(&self, values: Vec<(K, V)>) -> impl Future<Output = Result<(), Error>> + Send;
+        }
+
+        /// UserStorageComponentの非同期版。複数のタスクから同時に使うのでSyncにする。
+        /// 返すFutureが引数を借りたままにならないように、名前・メールアドレスは値で受け取る。
+        pub trait AsyncUserStorageComponent: AsyncStorageComponent<UserId, User> + Sync {
+            fn read_by_name(&self, name: Name) -> impl Future<Output = Result<User, Error>> + Send;
+            fn read_by_email(&self, email: Email) -> impl Future<Output = Result<User, Error>> + Send;
+        }
+
+        /// 非同期のストレージを、同期のStorageComponentとして使うための包み。
+        /// 問い合わせは自前のランタイムで動かし、呼んだスレッドは答えが来るまで待つ。
+        /// tokioのランタイムのスレッドからは待てないので、Timeoutのワーカーなどランタイムの外のスレッドから呼ぶ。
+        pub struct BlockOn<S> {
+            storage: S,
+            runtime: Option<Runtime>,
+        }
+
+        impl<S> BlockOn<S> {
+            /// `open` は自前のランタイムの中で呼ぶので、接続のプールのようにランタイムが要るものも作れる
+            pub fn new<F: FnOnce() -> Result<S, Error>>(open: F) -> Result<BlockOn<S>, Error> {
+                let runtime = runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build()?;
+                let storage = {
+                    let _entered = runtime.enter();
+                    open()?
+                };
+                Ok(BlockOn {
+                    storage,
+                    runtime: Some(runtime),
+                })
+            }
+
+            pub fn storage(&self) -> &S {
+                &self.storage
+            }
+
+            pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+                match self.runtime {
+                    Some(ref runtime) => runtime.block_on(future),
+                    None => unreachable!("the runtime is only taken on drop"),
+                }
+            }
+        }
+
+        /// ランタイムの中で捨てられても止められるように、実行中のタスクの終わりは待たない
+        impl<S> Drop for BlockOn<S> {
+            fn drop(&mut self) {
+                if let Some(runtime) = self.runtime.take() {
+                    runtime.shutdown_background();
+                }
+            }
+        }
+
+        impl<K: Clone, V: Clone, S: AsyncStorageComponent<K, V>> StorageComponent<K, V> for BlockOn<S> {
+            fn read(&self, key: K) -> Result<V, Error> {
+                self.block_on(self.storage.read(key))
+            }
+
+            fn save(&self, key: K, value: V) -> Result<(), Error> {
+                self.block_on(self.storage.save(key, value))
+            }
+
+            fn delete(&self, key: K) -> Result<(), Error> {
+                self.block_on(self.storage.delete(key))
+            }
+
+            fn read_all(&self) -> Result<Vec<V>, Error> {
+                self.block_on(self.storage.read_all())
+            }
+
+            fn save_all(&self, values: &[(K, V)]) -> Result<(), Error> {
+                self.block_on(self.storage.save_all(values.to_vec()))
+            }
+        }
+
+        impl<S: AsyncUserStorageComponent> UserStorageComponent for BlockOn<S> {
+            fn read_by_name(&self, name: &Name) -> Result<User, Error> {
+                self.block_on(self.storage.read_by_name(name.clone()))
+            }
+
+            fn read_by_email(&self, email: &Email) -> Result<User, Error> {
+                self.block_on(self.storage.read_by_email(email.clone()))
+            }
+        }
+    }
+
+    pub mod postgres {
+        //! ユーザーをPostgreSQLの `users` テーブルに保存する、待つ間スレッドを塞がないストレージ。
+        //! 値はJSONBで持ち、名前・メールアドレス・バージョンだけを別の列にも入れる。
+        //! 同じ名前・メールアドレスのユーザーはUNIQUE制約で弾き、バージョンの確認は保存するSQLの中で行う。
+
+        use component::nonblocking::{AsyncStorageComponent, AsyncUserStorageComponent};
+        use component::storage::StorageError;
+        use entity::user::{Email, Name, User, UserId};
+        use failure::Error;
+        use futures::future::{self, Future, FutureExt};
+        use sqlx;
+        use sqlx::postgres::{PgDatabaseError, PgPool, PgPoolOptions};
+        use sqlx::types::Json;
+        use std::collections::BTreeMap;
+        use std::time::Duration;
+        use tokio::time;
+        use uuid::Uuid;
+
+        /// `migrate` で作るテーブル
+        pub const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS users (
+            id UUID PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            email TEXT NOT NULL UNIQUE,
+            version BIGINT NOT NULL,
+            body JSONB NOT NULL
+        )";
+
+        /// 保存されているものより新しいバージョンの時だけ書き込み、書き込む前のバージョンと書き込めたかを返す
+        const SAVE: &str = "WITH stored AS (SELECT version FROM users WHERE id = $1),
+        saved AS (
+            INSERT INTO users (id, name, email, version, body) VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (id) DO UPDATE
+            SET name = EXCLUDED.name, email = EXCLUDED.email, version = EXCLUDED.version, body = EXCLUDED.body
+            WHERE users.version < EXCLUDED.version
+            RETURNING 1
+        )
+        SELECT (SELECT version FROM stored), EXISTS (SELECT 1 FROM saved)";
+
+        /// まとめて保存する。保存されている行をロックしてからバージョンを確かめ、1件でも古ければ1件も書き込まない。
+        /// 古かった1件の、保存されていたバージョンと渡されたバージョンを返す
+        const SAVE_ALL: &str = "WITH input AS (
+            SELECT * FROM UNNEST($1::uuid[], $2::text[], $3::text[], $4::bigint[], $5::jsonb[])
+            AS input (id, name, email, version, body)
+        ),
+        stored AS (SELECT users.id, users.version FROM users JOIN input ON users.id = input.id FOR UPDATE OF users),
+        conflict AS (
+            SELECT stored.version AS stored, input.version AS given FROM stored JOIN input ON stored.id = input.id
+            WHERE stored.version >= input.version LIMIT 1
+        ),
+        saved AS (
+            INSERT INTO users (id, name, email, version, body)
+            SELECT * FROM input WHERE NOT EXISTS (SELECT 1 FROM conflict)
+            ON CONFLICT (id) DO UPDATE
+            SET name = EXCLUDED.name, email = EXCLUDED.email, version = EXCLUDED.version, body = EXCLUDED.body
+            RETURNING 1
+        )
+        SELECT (SELECT stored FROM conflict), (SELECT given FROM conflict)";
+
+        /// 接続を共有するプールと、1回の問い合わせを待つ時間の上限
+        #[derive(Clone)]
+        pub struct PostgresUserStorage {
+            pool: PgPool,
+            timeout: Duration,
+        }
+
+        impl PostgresUserStorage {
+            pub fn new(pool: PgPool, timeout: Duration) -> PostgresUserStorage {
+                PostgresUserStorage { pool, timeout }
+            }
+
+            /// 接続は最初の問い合わせの時に張る。接続を待つ時間の上限も `timeout` にする。
+            /// プールが後始末のタスクを立てるので、tokioのランタイムの中で呼ぶ。
+            pub fn connect_lazy(url: &str, timeout: Duration) -> Result<PostgresUserStorage, Error> {
+                let pool = PgPoolOptions::new().acquire_timeout(timeout).connect_lazy(url)?;
+                Ok(PostgresUserStorage::new(pool, timeout))
+            }
+
+            /// テーブルが無ければ作る
+            pub fn migrate<'a>(&'a self) -> impl Future<Output = Result<(), Error>> + Send + 'a {
+                timed(self.timeout, sqlx::query(SCHEMA).execute(&self.pool)).map(|done| done.map(|_| ()))
+            }
+
+            fn find<'a>(
+                &'a self,
+                sql: &'static str,
+                value: &str,
+            ) -> impl Future<Output = Result<User, Error>> + Send + 'a {
+                let value = value.to_string();
+                let query = sqlx::query_scalar::<_, Json<User>>(sql).bind(value.clone()).fetch_optional(&self.pool);
+                timed(self.timeout, query).map(move |user| match user? {
+                    Some(Json(user)) => Ok(user),
+                    None => Err(StorageError::NotFound { key: value }.into()),
+                })
+            }
+        }
+
+        /// `timeout` を過ぎても終わらない問い合わせはエラーにする。待つのをやめた問い合わせはそこで取り消される。
+        /// 時間は待ち始めた時から計るので、ランタイムの外で作ったFutureでもよい
+        fn timed<T, F>(timeout: Duration, query: F) -> impl Future<Output = Result<T, Error>> + Send
+        where
+            F: Future<Output = Result<T, sqlx::Error>> + Send,
+        {
+            let timed = future::lazy(move |_| time::timeout(timeout, query)).flatten();
+            timed.map(move |done| match done {
+                Ok(done) => done.map_err(Error::from),
+                Err(_) => Err(format_err!("postgres did not answer within {:?}", timeout)),
+            })
+        }
+
+        /// UNIQUE制約に引っかかった時は、IndexedUserStorageと同じエラーにする。
+        /// まとめて保存した時は、どの値が引っかかったかを `Key (name)=(alice) already exists.` の形のdetailから探す
+        fn taken(e: Error, users: &[(Name, Email)]) -> Error {
+            let error = e.downcast_ref::<sqlx::Error>().and_then(|e| e.as_database_error());
+            let constraint = error.and_then(|e| e.constraint()).map(str::to_string);
+            let detail = error.and_then(|e| e.try_downcast_ref::<PgDatabaseError>()).and_then(|e| e.detail());
+            let detail = detail.unwrap_or_default().to_string();
+            let is_taken = |value: &str| users.len() == 1 || detail.contains(&format!("=({})", value));
+            let taken = match constraint.as_deref() {
+                Some("users_name_key") => users
+                    .iter()
+                    .find(|(name, _)| is_taken(name.as_str()))
+                    .map(|(name, _)| StorageError::taken("name", name)),
+                Some("users_email_key") => users
+                    .iter()
+                    .find(|(_, email)| is_taken(email.as_str()))
+                    .map(|(_, email)| StorageError::taken("email", email)),
+                _ => None,
+            };
+            taken.map_or(e, Error::from)
+        }
+
+        impl AsyncStorageComponent<UserId, User> for PostgresUserStorage {
+            fn read(&self, key: UserId) -> impl Future<Output = Result<User, Error>> + Send {
+                let query = sqlx::query_scalar::<_, Json<User>>("SELECT body FROM users WHERE id = $1")
+                    .bind(*key.as_uuid())
+                    .fetch_optional(&self.pool);
+                timed(self.timeout, query).map(move |user| match user? {
+                    Some(Json(user)) => Ok(user),
+                    None => Err(StorageError::not_found(&key).into()),
+                })
+            }
+
+            fn save(&self, key: UserId, value: User) -> impl Future<Output = Result<(), Error>> + Send {
+                let (name, email, given) = (value.name.clone(), value.email.clone(), value.version);
+                let query = sqlx::query_as::<_, (Option<i64>, bool)>(SAVE)
+                    .bind(*key.as_uuid())
+                    .bind(name.as_str().to_string())
+                    .bind(email.as_str().to_string())
+                    .bind(given as i64)
+                    .bind(Json(value))
+                    .fetch_one(&self.pool);
+                timed(self.timeout, query).map(move |saved| match saved {
+                    Ok((_, true)) => Ok(()),
+                    Ok((stored, false)) => Err(StorageError::Conflict {
+                        stored: stored.unwrap_or_default() as u64,
+                        given,
+                    }
+                    .into()),
+                    Err(e) => Err(taken(e, &[(name, email)])),
+                })
+            }
+
+            fn delete(&self, key: UserId) -> impl Future<Output = Result<(), Error>> + Send {
+                let query = sqlx::query("DELETE FROM users WHERE id = $1")
+                    .bind(*key.as_uuid())
+                    .execute(&self.pool);
+                timed(self.timeout, query).map(move |done| match done?.rows_affected() {
+                    0 => Err(StorageError::not_found(&key).into()),
+                    _ => Ok(()),
+                })
+            }
+
+            fn read_all(&self) -> impl Future<Output = Result<Vec<User>, Error>> + Send {
+                let query = sqlx::query_scalar::<_, Json<User>>("SELECT body FROM users ORDER BY id");
+                let query = query.fetch_all(&self.pool);
+                timed(self.timeout, query).map(|users| Ok(users?.into_iter().map(|Json(user)| user).collect()))
+            }
+
+            fn save_all(&self, values: Vec<(UserId, User)>) -> impl Future<Output = Result<(), Error>> + Send {
+                // 1つの文で同じ行を2回は書けないので、同じIDは後の方だけを残す
+                let values: BTreeMap<UserId, User> = values.into_iter().collect();
+                let users: Vec<(Name, Email)> =
+                    values.values().map(|user| (user.name.clone(), user.email.clone())).collect();
+                let ids: Vec<Uuid> = values.keys().map(|id| *id.as_uuid()).collect();
+                let names: Vec<String> = users.iter().map(|(name, _)| name.as_str().to_string()).collect();
+                let emails: Vec<String> = users.iter().map(|(_, email)| email.as_str().to_string()).collect();
+                let versions: Vec<i64> = values.values().map(|user| user.version as i64).collect();
+                let bodies: Vec<Json<User>> = values.into_values().map(Json).collect();
+                let query = sqlx::query_as::<_, (Option<i64>, Option<i64>)>(SAVE_ALL)
+                    .bind(ids)
+                    .bind(names)
+                    .bind(emails)
+                    .bind(versions)
+                    .bind(bodies)
+                    .fetch_one(&self.pool);
+                timed(self.timeout, query).map(move |saved| match saved {
+                    Ok((Some(stored), Some(given))) => Err(StorageError::Conflict {
+                        stored: stored as u64,
+                        given: given as u64,
+                    }
+                    .into()),
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(taken(e, &users)),
+                })
+            }
+        }
+
+        impl AsyncUserStorageComponent for PostgresUserStorage {
+            fn read_by_name(&self, name: Name) -> impl Future<Output = Result<User, Error>> + Send {
+                self.find("SELECT body FROM users WHERE name = $1", name.as_str())
+            }
+
+            fn read_by_email(&self, email: Email) -> impl Future<Output = Result<User, Error>> + Send {
+                self.find("SELECT body FROM users WHERE email = $1", email.as_str())
+            }
+        }
+    }
+
+    pub mod transaction {
+        //! 複数のストレージへの変更を、全て反映するか全て戻すかのどちらかにする。
+        //! ストレージ自体にトランザクションが無くても、変更前の値を覚えておいて戻す。
+
+        use component::storage::{StorageComponent, UserStorageComponent};
+        use entity::Entity;
+        use entity::user::{Email, Name, User};
+        use failure::Error;
+        use std::panic::{self, AssertUnwindSafe};
+        use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+        use std::thread::{self, ThreadId};
+
+        /// トランザクションに参加するストレージ。
+        /// トランザクションは始めたスレッドのもので、他のスレッドの書き込みは変更前の値として覚えない。
+        pub trait Participant {
+            /// このスレッドがトランザクション中か
+            fn in_transaction(&self) -> bool;
+            /// 他のスレッドがトランザクション中なら、終わるまで待ってから始める
+            fn begin(&self);
+            /// 変更前の値を捨てる。変更はストレージに反映済みなので失敗しない。
+            fn commit(&self);
+            /// 変更前の値に戻す
+            fn rollback(&self) -> Result<(), Error>;
+        }
+
+        /// トランザクションの開始・確定・取り消しを行うレイヤ。
+        /// ストレージは環境型のフィールドなので、このtraitは環境型自身が実装(impl)し、
+        /// 参加するストレージを `participants` で列挙する。
+        /// EventBusComponentの購読者が行った通知等は取り消せないので、取り消しても届いてしまう。
+        /// トランザクションは1度に1つだけで、他のスレッドは確定するか取り消すまで `begin` で待つ。
+        /// トランザクション中の変更は、確定する前から他のスレッドの読み込みにも見える。
+        pub trait TransactionComponent {
+            fn participants(&self) -> Vec<&dyn Participant>;
+
+            fn in_transaction(&self) -> bool {
+                self.participants().iter().any(|participant| participant.in_transaction())
+            }
+
+            /// トランザクションの入れ子は出来ない。
+            /// 参加するストレージは毎回同じ順に始めるので、2つのスレッドが互いを待ち続ける事は無い。
+            fn begin(&self) -> Result<(), Error> {
+                if self.in_transaction() {
+                    bail!("transaction already started");
+                }
+                for participant in self.participants() {
+                    participant.begin();
+                }
+                Ok(())
+            }
+
+            fn commit(&self) {
+                for participant in self.participants() {
+                    participant.commit();
+                }
+            }
+
+            /// 1つが戻せなくても残りは戻し、最初のエラーを返す
+            fn rollback(&self) -> Result<(), Error> {
+                let mut first_error = None;
+                for participant in self.participants() {
+                    if let Err(e) = participant.rollback() {
+                        first_error.get_or_insert(e);
+                    }
+                }
+                match first_error {
+                    Some(e) => Err(e),
+                    None => Ok(()),
+                }
+            }
+
+            /// `f` が成功したら確定し、失敗したら変更を戻して `f` のエラーを返す。
+            /// 既にトランザクション中なら `f` はそのトランザクションに加わり、確定するかどうかは外側が決める。
+            /// `f` がpanicした時も変更を戻してから、panicをそのまま伝える。
+            fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
+            where
+                Self: Sized,
+                E: From<Error>,
+                F: FnOnce(&Self) -> Result<T, E>,
+            {
+                if self.in_transaction() {
+                    return f(self);
+                }
+                self.begin()?;
+                match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+                    Ok(Ok(value)) => {
+                        self.commit();
+                        Ok(value)
+                    }
+                    Ok(Err(e)) => {
+                        // 戻す処理自体の失敗より、元のエラーを優先して返す
+                        let _ = self.rollback();
+                        Err(e)
+                    }
+                    Err(panicked) => {
+                        let _ = self.rollback();
+                        panic::resume_unwind(panicked)
+                    }
+                }
+            }
+        }
+
+        /// ストレージ `S` をトランザクションに参加させる。
+        /// トランザクション中は、キー毎に最初に変更した時の変更前の値を覚えておく。
+        /// 書き込みは `&self` で行うので、変更前の値はMutexの中に覚える。
+        pub struct Journaled<S, V: Entity> {
+            storage: S,
+            journal: Mutex<Option<Journal<V>>>,
+            /// トランザクションが終わった事を、`begin` で待っている他のスレッドへ知らせる
+            finished: Condvar,
+        }
+
+        /// トランザクション中のスレッドと、キーとそのキーを最初に変更する前の値。無かった場合はNone
+        struct Journal<V: Entity> {
+            owner: ThreadId,
+            before: Vec<(V::Id, Option<V>)>,
+        }
+
+        impl<S: StorageComponent<V::Id, V>, V: Entity> Journaled<S, V> {
+            pub fn new(storage: S) -> Journaled<S, V> {
+                Journaled {
+                    storage,
+                    journal: Mutex::new(None),
+                    finished: Condvar::new(),
+                }
+            }
+
+            pub fn storage(&self) -> &S {
+                &self.storage
+            }
+
+            fn journal(&self) -> MutexGuard<'_, Option<Journal<V>>> {
+                self.journal.lock().unwrap_or_else(PoisonError::into_inner)
+            }
+
+            fn record(&self, key: &V::Id) {
+                let mut journal = self.journal();
+                if let Some(ref mut journal) = *journal {
+                    let recorded = journal.before.iter().any(|(recorded, _)| recorded == key);
+                    if journal.owner == thread::current().id() && !recorded {
+                        journal.before.push((key.clone(), self.storage.read(key.clone()).ok()));
+                    }
+                }
+            }
+
+            /// このスレッドのトランザクションを終え、覚えていた変更前の値を返す
+            fn finish(&self) -> Vec<(V::Id, Option<V>)> {
+                let mut journal = self.journal();
+                // 他のスレッドのトランザクションには手を出さない
+                if !journal.as_ref().map(|journal| journal.owner == thread::current().id()).unwrap_or(false) {
+                    return Vec::new();
+                }
+                let before = journal.take().map(|journal| journal.before).unwrap_or_default();
+                self.finished.notify_all();
+                before
+            }
+        }
+
+        impl<S: StorageComponent<V::Id, V>, V: Entity> Participant for Journaled<S, V> {
+            fn in_transaction(&self) -> bool {
+                self.journal()
+                    .as_ref()
+                    .map(|journal| journal.owner == thread::current().id())
+                    .unwrap_or(false)
+            }
+
+            fn begin(&self) {
+                let mut journal = self.journal();
+                while journal.is_some() {
+                    journal = self.finished.wait(journal).unwrap_or_else(PoisonError::into_inner);
+                }
+                *journal = Some(Journal {
+                    owner: thread::current().id(),
+                    before: Vec::new(),
+                });
+            }
+
+            fn commit(&self) {
+                self.finish();
+            }
+
+            fn rollback(&self) -> Result<(), Error> {
+                let mut first_error = None;
+                for (key, before) in self.finish().into_iter().rev() {
+                    let current = self.storage.read(key.clone()).ok();
+                    let result = match (before, current) {
+                        (Some(mut before), current) => {
+                            // 変更前の値のバージョンは古いので、今保存されている値より新しくしてから戻す
+                            if let Some(version) = current.as_ref().and_then(Entity::version) {
+                                before.set_version(version + 1);
+                            }
+                            self.storage.save(key, before)
+                        }
+                        (None, Some(_)) => self.storage.delete(key),
+                        (None, None) => Ok(()),
+                    };
+                    if let Err(e) = result {
+                        first_error.get_or_insert(e);
+                    }
+                }
+                match first_error {
+                    Some(e) => Err(e),
+                    None => Ok(()),
+                }
+            }
+        }
+
+        impl<S: StorageComponent<V::Id, V>, V: Entity> StorageComponent<V::Id, V> for Journaled<S, V> {
+            fn read(&self, key: V::Id) -> Result<V, Error> {
+                self.storage.read(key)
+            }
+
+            fn read_many(&self, keys: &[V::Id]) -> Result<Vec<V>, Error>
+            where
+                V::Id: Clone,
+            {
+                self.storage.read_many(keys)
+            }
+
+            fn save(&self, key: V::Id, value: V) -> Result<(), Error> {
+                self.record(&key);
+                self.storage.save(key, value)
+            }
+
+            fn delete(&self, key: V::Id) -> Result<(), Error> {
+                self.record(&key);
+                self.storage.delete(key)
+            }
+
+            fn read_all(&self) -> Result<Vec<V>, Error> {
+                self.storage.read_all()
+            }
+
+            fn iter_all<'a>(&'a self) -> Box<dyn Iterator<Item = Result<V, Error>> + 'a>
+            where
+                V: 'a,
+            {
+                self.storage.iter_all()
+            }
+
+            fn save_all(&self, values: &[(V::Id, V)]) -> Result<(), Error> {
+                for (key, _) in values {
+                    self.record(key);
+                }
+                self.storage.save_all(values)
+            }
+
+            /// 一部の塊だけが保存されても、取り消せば全てのキーが元に戻る
+            fn save_all_concurrent(&self, values: &[(V::Id, V)], parallelism: usize) -> Result<(), Error> {
+                for (key, _) in values {
+                    self.record(key);
+                }
+                self.storage.save_all_concurrent(values, parallelism)
+            }
+
+            /// 書き出しても値は変わらないので、変更前の値は覚えない
+            fn flush(&self) -> Result<(), Error> {
+                self.storage.flush()
+            }
+        }
+
+        impl<S: UserStorageComponent> UserStorageComponent for Journaled<S, User> {
+            fn read_by_name(&self, name: &Name) -> Result<User, Error> {
+                self.storage.read_by_name(name)
+            }
+
+            fn read_by_email(&self, email: &Email) -> Result<User, Error> {
+                self.storage.read_by_email(email)
+            }
+        }
+    }
+
+    pub mod cache {
+        //! ストレージの手前に置くキャッシュ。
+        //! Repositoryは `HaveStorageComponent` に対して汎用に実装(impl)されているので、
+        //! キャッシュはストレージを包むデコレータとして差し込む。
+
+        use chrono::prelude::*;
+        use chrono::Duration;
+        use component::storage::{check_version, StorageComponent};
+        use component::time::{Chrono, TimeComponent};
+        use entity::Entity;
+        use failure::Error;
+        use component::pool::{ConnectionPool, ConnectionPoolComponent};
+        use redis::{Client, Commands, Connection, RedisResult};
+        use serde::Serialize;
+        use serde::de::DeserializeOwned;
+        use serde_json;
+        use std::collections::BTreeMap;
+        use std::fmt::Debug;
+        use std::str::FromStr;
+        use std::sync::{Mutex, MutexGuard, PoisonError};
+
+        /// キャッシュの書き込み方針。どれを使うかはenvが設定から決める。
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+        #[serde(rename_all = "kebab-case")]
+        pub enum CachePolicy {
+            /// 読み込み時にキャッシュが無ければストレージから読んでキャッシュする。書き込みはストレージのみで、キャッシュは破棄する。
+            ReadThrough,
+            /// 書き込み時にストレージとキャッシュの両方を更新する。
+            #[default]
+            WriteThrough,
+            /// 書き込みはキャッシュのみで、`flush()` した時にまとめてストレージへ書き出す。
+            WriteBack,
+        }
+
+        impl FromStr for CachePolicy {
+            type Err = Error;
+            fn from_str(s: &str) -> Result<CachePolicy, Error> {
+                match s {
+                    "read-through" => Ok(CachePolicy::ReadThrough),
+                    "write-through" => Ok(CachePolicy::WriteThrough),
+                    "write-back" => Ok(CachePolicy::WriteBack),
+                    _ => bail!("unknown cache policy: {}", s),
+                }
+            }
+        }
+
+        /// キーと値の組を一時的に保持するレイヤ。
+        /// 読み込み時にもキャッシュを埋められるように、全メソッド `&self` で呼べるようにしている。
+        /// キャッシュは無くても正しく動く前提なので、失敗はエラーにせず「無かった」事にする。
+        pub trait CacheComponent<K, V> {
+            fn get(&self, key: &K) -> Option<V>;
+            /// 期限なしで保持する
+            fn set(&self, key: K, value: V);
+            /// `ttl` が経ったら無かった事になる
+            fn set_with_ttl(&self, key: K, value: V, ttl: Duration);
+            fn invalidate(&self, key: &K);
+        }
+
+        /// 値と、その有効期限
+        type Entry<V> = (V, Option<DateTime<Utc>>);
+
+        /// メモリ上に値を保持するキャッシュ。期限切れの判定には `T` の現在時刻を使う。
+        pub struct MemoryCache<K, V, T = Chrono> {
+            map: Mutex<BTreeMap<K, Entry<V>>>,
+            clock: T,
+        }
+
+        impl<K: Ord, V> MemoryCache<K, V> {
+            pub fn new() -> MemoryCache<K, V> {
+                MemoryCache::with_clock(Chrono)
+            }
+        }
+
+        impl<K: Ord, V, T: TimeComponent> MemoryCache<K, V, T> {
+            pub fn with_clock(clock: T) -> MemoryCache<K, V, T> {
+                MemoryCache {
+                    map: Mutex::new(BTreeMap::new()),
+                    clock,
+                }
+            }
+
+            /// 期限切れでまだ取り除かれていない値も数える
+            #[cfg(test)]
+            pub fn len(&self) -> usize {
+                self.map().len()
+            }
+
+            #[cfg(test)]
+            pub fn is_empty(&self) -> bool {
+                self.map().is_empty()
+            }
+
+            /// キャッシュは捨てても困らないので、途中でpanicしていてもそのまま使う
+            fn map(&self) -> MutexGuard<'_, BTreeMap<K, Entry<V>>> {
+                self.map.lock().unwrap_or_else(PoisonError::into_inner)
+            }
+        }
+
+        impl<K: Ord, V> Default for MemoryCache<K, V> {
+            fn default() -> MemoryCache<K, V> {
+                MemoryCache::new()
+            }
+        }
+
+        impl<K: Ord, V: Clone, T: TimeComponent> CacheComponent<K, V> for MemoryCache<K, V, T> {
+            /// 期限切れの値はここで取り除く
+            fn get(&self, key: &K) -> Option<V> {
+                let mut map = self.map();
+                let expired = match map.get(key) {
+                    Some(&(ref value, expires_at)) => match expires_at {
+                        Some(expires_at) if expires_at <= self.clock.now() => true,
+                        _ => return Some(value.clone()),
+                    },
+                    None => return None,
+                };
+                if expired {
+                    map.remove(key);
+                }
+                None
+            }
+
+            fn set(&self, key: K, value: V) {
+                self.map().insert(key, (value, None));
+            }
+
+            fn set_with_ttl(&self, key: K, value: V, ttl: Duration) {
+                let expires_at = self.clock.now() + ttl;
+                self.map().insert(key, (value, Some(expires_at)));
+            }
+
+            fn invalidate(&self, key: &K) {
+                self.map().remove(key);
+            }
+        }
+
+        /// Redisに値を保持するキャッシュ。
+        /// キーは `<prefix>:<キーのJSON>`、値はJSONで保存するので、複数のプロセスで同じキャッシュを共有できる。
+        pub struct RedisCache {
+            prefix: String,
+            pool: ConnectionPool<Client>,
+        }
+
+        impl RedisCache {
+            pub fn new(pool: ConnectionPool<Client>, prefix: &str) -> RedisCache {
+                RedisCache {
+                    prefix: prefix.to_string(),
+                    pool,
+                }
+            }
+
+            /// 繋がらない時や接続が空かない時は、キャッシュに無いものとして扱えるようにOptionで返す
+            fn query<T, F: FnOnce(&mut Connection) -> RedisResult<T>>(&self, f: F) -> Option<T> {
+                self.pool.with_connection(|connection| Ok(f(connection)?)).ok()
+            }
+
+            fn key<K: Serialize>(&self, key: &K) -> Option<String> {
+                serde_json::to_string(key).ok().map(|key| format!("{}:{}", self.prefix, key))
+            }
+        }
+
+        impl<K: Serialize, V: Serialize + DeserializeOwned> CacheComponent<K, V> for RedisCache {
+            fn get(&self, key: &K) -> Option<V> {
+                let key = self.key(key)?;
+                let value: Option<String> = self.query(|connection| connection.get(key))?;
+                serde_json::from_str(&value?).ok()
+            }
+
+            fn set(&self, key: K, value: V) {
+                if let (Some(key), Ok(value)) = (self.key(&key), serde_json::to_string(&value)) {
+                    self.query(|connection| connection.set::<_, _, ()>(key, value));
+                }
+            }
+
+            /// Redisの期限は秒単位なので、1秒未満は1秒に切り上げる
+            fn set_with_ttl(&self, key: K, value: V, ttl: Duration) {
+                if let (Some(key), Ok(value)) = (self.key(&key), serde_json::to_string(&value)) {
+                    let seconds = ttl.num_seconds().max(1) as u64;
+                    self.query(|connection| connection.set_ex::<_, _, ()>(key, value, seconds));
+                }
+            }
+
+            fn invalidate(&self, key: &K) {
+                if let Some(key) = self.key(key) {
+                    self.query(|connection| connection.del::<_, ()>(key));
+                }
+            }
+        }
+
+        /// ストレージ `S` の手前にキャッシュ `C` を置くStorageComponent。
+        /// これ自身もStorageComponentなので、envはこれを返すだけでキャッシュ付きのRepositoryになる。
+        pub struct CachingStorage<S, C, K, V> {
+            storage: S,
+            cache: C,
+            policy: CachePolicy,
+            /// WriteBackでまだストレージに書き出していない値。
+            /// キャッシュから追い出されても消えないように、キャッシュとは別に持っておく。
+            /// ストレージとキャッシュの両方を書き換える間はこのロックを持ち、古い値がキャッシュに残らないようにする。
+            pending: Mutex<BTreeMap<K, V>>,
+            /// キャッシュに入れた値の有効期間。Noneなら期限なし。
+            ttl: Option<Duration>,
+        }
+
+        impl<S, C, K: Ord, V> CachingStorage<S, C, K, V> {
+            pub fn new(storage: S, cache: C, policy: CachePolicy) -> CachingStorage<S, C, K, V> {
+                CachingStorage {
+                    storage,
+                    cache,
+                    policy,
+                    pending: Mutex::new(BTreeMap::new()),
+                    ttl: None,
+                }
+            }
+
+            pub fn with_ttl(mut self, ttl: Duration) -> CachingStorage<S, C, K, V> {
+                self.ttl = Some(ttl);
+                self
+            }
+
+            pub fn storage(&self) -> &S {
+                &self.storage
+            }
+
+            #[cfg(test)]
+            pub fn cache(&self) -> &C {
+                &self.cache
+            }
+
+            fn pending(&self) -> MutexGuard<'_, BTreeMap<K, V>> {
+                self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+            }
+        }
+
+        impl<S, C: CacheComponent<K, V>, K, V> CachingStorage<S, C, K, V> {
+            fn fill(&self, key: K, value: V) {
+                match self.ttl {
+                    Some(ttl) => self.cache.set_with_ttl(key, value, ttl),
+                    None => self.cache.set(key, value),
+                }
+            }
+        }
+
+        impl<S, C, K, V> CachingStorage<S, C, K, V>
+        where
+            S: StorageComponent<K, V>,
+            C: CacheComponent<K, V>,
+            K: Ord + Clone,
+            V: Clone,
+        {
+            /// 今見えている値。溜めている値、キャッシュ、ストレージの順に探す
+            fn current(&self, pending: &BTreeMap<K, V>, key: &K) -> Option<V> {
+                match pending.get(key) {
+                    Some(value) => Some(value.clone()),
+                    None => self.cache.get(key).or_else(|| self.storage.read(key.clone()).ok()),
+                }
+            }
+        }
+
+        impl<S, C, K, V> StorageComponent<K, V> for CachingStorage<S, C, K, V>
+        where
+            S: StorageComponent<K, V>,
+            C: CacheComponent<K, V>,
+            K: Ord + Clone + Debug,
+            V: Entity<Id = K> + Clone,
+        {
+            fn read(&self, key: K) -> Result<V, Error> {
+                let pending = self.pending().get(&key).cloned();
+                if let Some(value) = pending {
+                    return Ok(value);
+                }
+                if let Some(value) = self.cache.get(&key) {
+                    return Ok(value);
+                }
+                let _filling = self.pending();
+                let value = self.storage.read(key.clone())?;
+                self.fill(key, value.clone());
+                Ok(value)
+            }
+
+            /// キャッシュに無かったものだけをまとめてストレージから読む
+            fn read_many(&self, keys: &[K]) -> Result<Vec<V>, Error> {
+                let mut found = BTreeMap::new();
+                let mut missing = Vec::new();
+                let pending = self.pending().clone();
+                for key in keys {
+                    match pending.get(key).cloned().or_else(|| self.cache.get(key)) {
+                        Some(value) => {
+                            found.insert(key.clone(), value);
+                        }
+                        None => missing.push(key.clone()),
+                    }
+                }
+                let _filling = self.pending();
+                for value in self.storage.read_many(&missing)? {
+                    self.fill(value.id(), value.clone());
+                    found.insert(value.id(), value);
+                }
+                Ok(keys.iter().filter_map(|key| found.get(key).cloned()).collect())
+            }
+
+            fn save(&self, key: K, value: V) -> Result<(), Error> {
+                match self.policy {
+                    CachePolicy::ReadThrough => {
+                        // 読み込みがキャッシュを埋めるのと同じロックを持ち、書き込み前の値で埋め直されないようにする
+                        let _writing = self.pending();
+                        self.storage.save(key.clone(), value)?;
+                        self.cache.invalidate(&key);
+                    }
+                    CachePolicy::WriteThrough => {
+                        let _writing = self.pending();
+                        self.storage.save(key.clone(), value.clone())?;
+                        self.fill(key, value);
+                    }
+                    CachePolicy::WriteBack => {
+                        // ストレージへ書き出すのは後なので、バージョンはここで見えている値と比べる。
+                        // 比べてから溜めるまで溜めている値のロックを持つので、同時に保存しても片方はConflictになる
+                        let mut pending = self.pending();
+                        check_version(self.current(&pending, &key).as_ref(), &value)?;
+                        self.fill(key.clone(), value.clone());
+                        pending.insert(key, value);
+                    }
+                }
+                Ok(())
+            }
+
+            fn delete(&self, key: K) -> Result<(), Error> {
+                let mut pending = self.pending();
+                self.cache.invalidate(&key);
+                let was_pending = pending.remove(&key).is_some();
+                if was_pending && self.storage.read(key.clone()).is_err() {
+                    // まだストレージに書き出していない値だったので、ここで消すだけで良い
+                    return Ok(());
+                }
+                self.storage.delete(key)
+            }
+
+            /// WriteBackで溜まっている値をストレージへ書き出してから、ストレージにも書き出させる。
+            /// 途中で失敗した場合、書き出せなかった値は残る。
+            fn flush(&self) -> Result<(), Error> {
+                let mut pending = self.pending();
+                while let Some((key, value)) = pending.iter().next().map(|(k, v)| (k.clone(), v.clone())) {
+                    self.storage.save(key.clone(), value)?;
+                    pending.remove(&key);
+                }
+                drop(pending);
+                self.storage.flush()
+            }
+
+            fn read_all(&self) -> Result<Vec<V>, Error> {
+                let pending = self.pending().clone();
+                let mut values: Vec<V> = self
+                    .storage
+                    .read_all()?
+                    .into_iter()
+                    .filter(|v| !pending.contains_key(&v.id()))
+                    .collect();
+                values.extend(pending.into_values());
+                values.sort_by_key(|v| v.id());
+                Ok(values)
+            }
+
+            /// WriteBack以外はストレージへ1回で保存する。WriteBackも全て溜めるか1件も溜めないか
+            fn save_all(&self, values: &[(K, V)]) -> Result<(), Error> {
+                match self.policy {
+                    CachePolicy::ReadThrough => {
+                        let _writing = self.pending();
+                        self.storage.save_all(values)?;
+                        for (key, _) in values {
+                            self.cache.invalidate(key);
+                        }
+                    }
+                    CachePolicy::WriteThrough => {
+                        let _writing = self.pending();
+                        self.storage.save_all(values)?;
+                        for (key, value) in values {
+                            self.fill(key.clone(), value.clone());
+                        }
+                    }
+                    CachePolicy::WriteBack => {
+                        // 1つのロックの中で全てのバージョンを確かめてから溜めるので、どれかがConflictなら1件も溜めない。
+                        // 同じキーが2回あれば、後の値は前の値と比べる
+                        let mut pending = self.pending();
+                        let mut staged = BTreeMap::new();
+                        for (key, value) in values {
+                            let current = staged.get(key).cloned().or_else(|| self.current(&pending, key));
+                            check_version(current.as_ref(), value)?;
+                            staged.insert(key.clone(), value.clone());
+                        }
+                        for (key, value) in staged {
+                            self.fill(key.clone(), value.clone());
+                            pending.insert(key, value);
+                        }
+                    }
+                }
+                Ok(())
+            }
+
+            /// WriteBackは溜めるだけなので `save_all` と同じ。それ以外はストレージへ並行に保存し、
+            /// 失敗した時はどこまで保存されたかが分からないので、書こうとしたキーを全てキャッシュから消す。
+            fn save_all_concurrent(&self, values: &[(K, V)], parallelism: usize) -> Result<(), Error> {
+                if self.policy == CachePolicy::WriteBack {
+                    return self.save_all(values);
+                }
+                let _writing = self.pending();
+                let saved = self.storage.save_all_concurrent(values, parallelism);
+                for (key, value) in values {
+                    match (self.policy, &saved) {
+                        (CachePolicy::WriteThrough, Ok(())) => self.fill(key.clone(), value.clone()),
+                        _ => self.cache.invalidate(key),
+                    }
+                }
+                saved
+            }
+        }
+    }
+
+    pub mod filesystem {
+        //! ファイルの読み書き。ファイルに保存するストレージやエクスポートはこれを通してファイルを触る。
+
+        use failure::Error;
+        use std::fs::{self, OpenOptions};
+        use std::io::{ErrorKind, Write};
+        use std::path::Path;
+
+        /// パスを指定してファイルを読み書きするレイヤ
+        pub trait FileSystemComponent {
+            /// ファイルが無ければNone
+            fn read(&self, path: &Path) -> Result<Option<String>, Error>;
+            /// ファイル全体を `contents` で置き換える。途中で落ちても元のファイルは壊れない。
+            fn write(&self, path: &Path, contents: &str) -> Result<(), Error>;
+            /// ファイルの末尾に `contents` を足す。ファイルが無ければ作る。
+            fn append(&self, path: &Path, contents: &str) -> Result<(), Error>;
+        }
+
+        /// これを実装(impl)している型はFileSystemComponentを返せる。抽象化されたGetter.
+        pub trait HaveFileSystemComponent {
+            type FileSystemComponent: FileSystemComponent;
+            fn file_system_component(&self) -> &Self::FileSystemComponent;
+        }
+
+        /// 他のcomponentに読み書きを任せる時に、所有権を渡さず参照のままで使えるようにする
+        impl<F: FileSystemComponent> FileSystemComponent for &F {
+            fn read(&self, path: &Path) -> Result<Option<String>, Error> {
+                (**self).read(path)
+            }
+
+            fn write(&self, path: &Path, contents: &str) -> Result<(), Error> {
+                (**self).write(path, contents)
+            }
+
+            fn append(&self, path: &Path, contents: &str) -> Result<(), Error> {
+                (**self).append(path, contents)
+            }
+        }
+
+        /// FileSystemComponentをstd::fsで実装(impl)する型
+        #[derive(Debug, Default, Clone, Copy)]
+        pub struct StdFileSystem;
+
+        impl FileSystemComponent for StdFileSystem {
+            fn read(&self, path: &Path) -> Result<Option<String>, Error> {
+                match fs::read_to_string(path) {
+                    Ok(contents) => Ok(Some(contents)),
+                    Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(format_err!("failed to read {}: {}", path.display(), e)),
+                }
+            }
+
+            /// 一時ファイルに書いてからrenameする
+            fn write(&self, path: &Path, contents: &str) -> Result<(), Error> {
+                let tmp = path.with_extension("tmp");
+                fs::write(&tmp, contents)?;
+                fs::rename(&tmp, path)?;
+                Ok(())
+            }
+
+            fn append(&self, path: &Path, contents: &str) -> Result<(), Error> {
+                let mut file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .map_err(|e| format_err!("failed to open {}: {}", path.display(), e))?;
+                file.write_all(contents.as_bytes())?;
+                Ok(())
+            }
+        }
+    }
+
+    pub mod file {
+        //! Entityを1行1レコードでファイルに保存するストレージ。
+        //! レコードの形はRecordCodecに任せるので、Entityにフィールドが増えても
+        //! Codecが古い形を読めれば既存のファイルをそのまま読み込める。
+
+        use base64::engine::general_purpose::STANDARD as BASE64;
+        use base64::Engine;
+        use chrono::prelude::*;
+        use component::crypto::CryptoComponent;
+        use component::filesystem::{FileSystemComponent, StdFileSystem};
+        use component::storage::{check_version, StorageComponent, StorageError};
+        use entity::Entity;
+        use entity::user::{Email, Name, Role, User, UserId};
+        use failure::Error;
+        use serde::Serialize;
+        use serde::de::DeserializeOwned;
+        use serde_json::{self, Value};
+        use std::collections::BTreeMap;
+        use std::fmt::Debug;
+        use std::path::{Path, PathBuf};
+        use std::sync::{Mutex, MutexGuard, PoisonError};
+
+        /// 値とファイル上の1行との相互変換
+        pub trait RecordCodec<V> {
+            fn encode(&self, value: &V) -> Result<String, Error>;
+            fn decode(&self, record: &str) -> Result<V, Error>;
+        }
+
+        /// 値をそのまま1行のJSONで読み書きする。版を上げて読み替える必要の無い値に使う。
+        #[derive(Debug, Default)]
+        pub struct JsonCodec;
+
+        impl<V: Serialize + DeserializeOwned> RecordCodec<V> for JsonCodec {
+            fn encode(&self, value: &V) -> Result<String, Error> {
+                Ok(serde_json::to_string(value)?)
+            }
+
+            fn decode(&self, record: &str) -> Result<V, Error> {
+                Ok(serde_json::from_str(record)?)
+            }
+        }
+
+        /// 暗号化したフィールドの値の頭に付ける印
+        const ENCRYPTED_PREFIX: &str = "enc:";
+
+        /// 別のRecordCodecで作ったレコードのうち、指定したフィールドだけを暗号化するRecordCodec。
+        /// フィールドは `/user/email` のようなJSON Pointerで指定し、暗号文はBase64にして `enc:` を付けて書く。
+        /// 暗号化されていない値もそのまま読めるので、既存のファイルは次に書き出した時に暗号化される。
+        pub struct EncryptedFields<C, K> {
+            codec: C,
+            crypto: K,
+            fields: Vec<String>,
+        }
+
+        impl<C, K> EncryptedFields<C, K> {
+            pub fn new(codec: C, crypto: K, fields: &[&str]) -> EncryptedFields<C, K> {
+                EncryptedFields {
+                    codec,
+                    crypto,
+                    fields: fields.iter().map(|field| field.to_string()).collect(),
+                }
+            }
+        }
+
+        impl<V, C: RecordCodec<V>, K: CryptoComponent> RecordCodec<V> for EncryptedFields<C, K> {
+            fn encode(&self, value: &V) -> Result<String, Error> {
+                let mut record: Value = serde_json::from_str(&self.codec.encode(value)?)?;
+                for field in &self.fields {
+                    // 値の無いフィールドは暗号化しない
+                    if let Some(value) = record.pointer_mut(field).filter(|value| !value.is_null()) {
+                        let ciphertext = self.crypto.encrypt(&serde_json::to_vec(value)?)?;
+                        *value = Value::String(format!("{}{}", ENCRYPTED_PREFIX, BASE64.encode(ciphertext)));
+                    }
+                }
+                Ok(serde_json::to_string(&record)?)
+            }
+
+            fn decode(&self, record: &str) -> Result<V, Error> {
+                let mut record: Value = serde_json::from_str(record)?;
+                for field in &self.fields {
+                    if let Some(value) = record.pointer_mut(field) {
+                        let ciphertext = match value.as_str().and_then(|s| s.strip_prefix(ENCRYPTED_PREFIX)) {
+                            Some(encoded) => BASE64.decode(encoded)?,
+                            None => continue,
+                        };
+                        *value = serde_json::from_slice(&self.crypto.decrypt(&ciphertext)?)?;
+                    }
+                }
+                self.codec.decode(&record.to_string())
+            }
+        }
+
+        /// 今書き出しているUserレコードの版
+        pub const USER_RECORD_VERSION: u64 = 2;
+
+        /// Userを `{"v": 2, "user": {...}}` の形で読み書きする。
+        /// 版の無い素のUserは、状態・住所・電話番号・バージョンが無かった頃の1版として読み込む。
+        #[derive(Debug, Default)]
+        pub struct UserRecordCodec;
+
+        #[derive(Serialize)]
+        struct UserRecord<'a> {
+            v: u64,
+            user: &'a User,
+        }
+
+        /// 1版のUserレコード
+        #[derive(Deserialize)]
+        struct UserV1 {
+            id: UserId,
+            name: Name,
+            email: Email,
+            role: Role,
+            create_time: DateTime<Utc>,
+            update_time: DateTime<Utc>,
+        }
+
+        impl UserV1 {
+            /// 1版に無かったフィールドは新規作成時と同じ値で埋める
+            fn upgrade(self) -> User {
+                User {
+                    id: self.id,
+                    name: self.name,
+                    email: self.email,
+                    role: self.role,
+                    status: Default::default(),
+                    address: None,
+                    phone_number: None,
+                    create_time: self.create_time,
+                    update_time: self.update_time,
+                    version: 1,
+                }
+            }
+        }
+
+        impl RecordCodec<User> for UserRecordCodec {
+            fn encode(&self, user: &User) -> Result<String, Error> {
+                Ok(serde_json::to_string(&UserRecord {
+                    v: USER_RECORD_VERSION,
+                    user,
+                })?)
+            }
+
+            fn decode(&self, record: &str) -> Result<User, Error> {
+                let mut value: Value = serde_json::from_str(record)?;
+                let version = match value.get("v") {
+                    Some(v) => v.as_u64().ok_or_else(|| format_err!("invalid record version: {}", v))?,
+                    None => 1,
+                };
+                match version {
+                    1 => Ok(serde_json::from_value::<UserV1>(value)?.upgrade()),
+                    USER_RECORD_VERSION => Ok(serde_json::from_value(value["user"].take())?),
+                    _ => bail!("unsupported user record version: {}", version),
+                }
+            }
+        }
+
+        /// 全件をメモリに持ち、書き込みの度にファイル全体を書き直す。
+        /// ファイルの読み書きは `F` に任せる。書き直している間は値のロックを持つので、書き込みは1つずつ行われる。
+        /// 変更は写しに当てて書き出し、書き出せた時だけメモリ上の値と入れ替える。
+        pub struct FileStorage<K, V, C, F = StdFileSystem> {
+            path: PathBuf,
+            codec: C,
+            fs: F,
+            list: Mutex<BTreeMap<K, V>>,
+        }
+
+        impl<K: Ord + Clone, V: Entity<Id = K> + Clone, C: RecordCodec<V>> FileStorage<K, V, C> {
+            /// ファイルが無ければ空のストレージとして開く
+            pub fn open<P: AsRef<Path>>(path: P, codec: C) -> Result<FileStorage<K, V, C>, Error> {
+                FileStorage::open_with(path, codec, StdFileSystem)
+            }
+        }
+
+        impl<K: Ord + Clone, V: Entity<Id = K> + Clone, C: RecordCodec<V>, F: FileSystemComponent>
+            FileStorage<K, V, C, F>
+        {
+            /// `fs` の上のファイルを開く
+            pub fn open_with<P: AsRef<Path>>(path: P, codec: C, fs: F) -> Result<FileStorage<K, V, C, F>, Error> {
+                let path = path.as_ref().to_path_buf();
+                let mut list = BTreeMap::new();
+                if let Some(contents) = fs.read(&path)? {
+                    for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+                        let value = codec.decode(line)?;
+                        list.insert(value.id(), value);
+                    }
+                }
+                Ok(FileStorage {
+                    path,
+                    codec,
+                    fs,
+                    list: Mutex::new(list),
+                })
+            }
+
+            fn list(&self) -> MutexGuard<'_, BTreeMap<K, V>> {
+                self.list.lock().unwrap_or_else(PoisonError::into_inner)
+            }
+
+            fn write(&self, list: &BTreeMap<K, V>) -> Result<(), Error> {
+                let mut contents = String::new();
+                for value in list.values() {
+                    contents.push_str(&self.codec.encode(value)?);
+                    contents.push('\n');
+                }
+                self.fs.write(&self.path, &contents)
+            }
+        }
+
+        impl<K, V, C, F> StorageComponent<K, V> for FileStorage<K, V, C, F>
+        where
+            K: Ord + Clone + Debug,
+            V: Entity<Id = K> + Clone,
+            C: RecordCodec<V>,
+            F: FileSystemComponent,
+        {
+            fn read(&self, key: K) -> Result<V, Error> {
+                match self.list().get(&key) {
+                    Some(value) => Ok(value.clone()),
+                    None => Err(StorageError::not_found(&key).into()),
+                }
+            }
+
+            fn save(&self, key: K, value: V) -> Result<(), Error> {
+                let mut list = self.list();
+                check_version(list.get(&key), &value)?;
+                let mut next = list.clone();
+                next.insert(key, value);
+                self.write(&next)?;
+                *list = next;
+                Ok(())
+            }
+
+            fn delete(&self, key: K) -> Result<(), Error> {
+                let mut list = self.list();
+                let mut next = list.clone();
+                if next.remove(&key).is_none() {
+                    return Err(StorageError::not_found(&key).into());
+                }
+                self.write(&next)?;
+                *list = next;
+                Ok(())
+            }
+
+            fn read_all(&self) -> Result<Vec<V>, Error> {
+                Ok(self.list().values().cloned().collect())
+            }
+
+            fn read_many(&self, keys: &[K]) -> Result<Vec<V>, Error> {
+                let list = self.list();
+                Ok(keys.iter().filter_map(|key| list.get(key).cloned()).collect())
+            }
+
+            /// 全件の検証が通ってから1回だけ書き出す
+            fn save_all(&self, values: &[(K, V)]) -> Result<(), Error> {
+                let mut list = self.list();
+                for (key, value) in values {
+                    check_version(list.get(key), value)?;
+                }
+                let mut next = list.clone();
+                for (key, value) in values {
+                    next.insert(key.clone(), value.clone());
+                }
+                self.write(&next)?;
+                *list = next;
+                Ok(())
+            }
+        }
+    }
+}
+
+mod repository {
+    //! Entityの取得・保存を抽象化するレイヤ。
+
+    use component::storage::{HaveStorageComponent, StorageComponent, StorageError};
+    use component::trace::{HaveTracingComponent, TracingComponent};
+    use entity::user::StatusError;
+    use entity::{Entity, ValidationError};
+    use failure::Error;
+    use std::any;
+    use std::collections::BTreeSet;
+    use std::error;
+    use std::fmt::Debug;
+
+    /// RepositoryとUserCommands、ユースケースが返すエラー。呼び出し側は文言を見ずに、種類でmatchして扱いを分けられる。
+    /// `?` でそのままfailure::Errorにも変換できる。
+    #[derive(Debug, ::thiserror::Error)]
+    pub enum DomainError {
+        /// 指定されたIDなどのEntityが無い
+        #[error("not found: {key}")]
+        NotFound { key: String },
+        /// 同じIDのEntityや、他と被ってはいけない値を使っているEntityが既にある
+        #[error("{field} already taken: {value}")]
+        AlreadyExists { field: &'static str, value: String },
+        /// 読んでから保存するまでの間に、他の誰かが先に更新した。読み直せばやり直せる
+        #[error("version conflict: stored {stored}, given {given}")]
+        Conflict { stored: u64, given: u64 },
+        #[error(transparent)]
+        Validation(ValidationError),
+        /// 今のユーザーの状態ではできない操作
+        #[error(transparent)]
+        Status(StatusError),
+        /// ユースケースの決まりで断った(権限が無い・認証できない・入力の形が違う等)。理由は利用者に見せてよい
+        #[error(transparent)]
+        Rejected(Box<dyn error::Error + Send + Sync>),
+        /// ストレージやその先が失敗した。理由は利用者に見せない
+        #[error(transparent)]
+        Storage(Box<dyn error::Error + Send + Sync>),
+    }
+
+    impl DomainError {
+        fn already_exists<Id: Debug>(id: &Id) -> DomainError {
+            DomainError::AlreadyExists {
+                field: "id",
+                value: format!("{:?}", id),
+            }
+        }
+
+        /// `reason` で断る。文言だけの時は `&str` や `String` も渡せる
+        pub fn rejected<E: Into<Box<dyn error::Error + Send + Sync>>>(reason: E) -> DomainError {
+            DomainError::Rejected(reason.into())
+        }
+
+        /// 断った理由が `E` ならそれを返す
+        pub fn reason<E: error::Error + 'static>(&self) -> Option<&E> {
+            match *self {
+                DomainError::Rejected(ref reason) => reason.downcast_ref(),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<ValidationError> for DomainError {
+        fn from(e: ValidationError) -> DomainError {
+            DomainError::Validation(e)
+        }
+    }
+
+    impl From<StatusError> for DomainError {
+        fn from(e: StatusError) -> DomainError {
+            DomainError::Status(e)
+        }
+    }
+
+    impl From<StorageError> for DomainError {
+        fn from(e: StorageError) -> DomainError {
+            match e {
+                StorageError::NotFound { key } => DomainError::NotFound { key },
+                StorageError::Taken { field, value } => DomainError::AlreadyExists { field, value },
+                StorageError::Conflict { stored, given } => DomainError::Conflict { stored, given },
+            }
+        }
+    }
+
+    /// componentが返すfailure::Errorを、中身の型を見て振り分ける
+    impl From<Error> for DomainError {
+        fn from(e: Error) -> DomainError {
+            let e = match e.downcast::<DomainError>() {
+                Ok(e) => return e,
+                Err(e) => e,
+            };
+            let e = match e.downcast::<StorageError>() {
+                Ok(e) => return e.into(),
+                Err(e) => e,
+            };
+            let e = match e.downcast::<StatusError>() {
+                Ok(e) => return e.into(),
+                Err(e) => e,
+            };
+            match e.downcast::<ValidationError>() {
+                Ok(e) => DomainError::Validation(e),
+                Err(e) => DomainError::Storage(Box::new(e.compat())),
+            }
+        }
+    }
+
+    /// Entityの種類によらない汎用のRepository。
+    /// `HaveStorageComponent<E>` を実装(impl)している型なら何でもこれを実装(impl)できるので、
+    /// Entityを増やす時にtraitを丸ごとコピペしなくて済む。
+    pub trait Repository<E, Id> {
+        fn get(&self, id: Id) -> Result<E, DomainError>;
+        fn insert(&self, entity: E) -> Result<(), DomainError>;
+        fn update(&self, entity: E) -> Result<(), DomainError>;
+        fn delete(&self, id: Id) -> Result<(), DomainError>;
+        fn list(&self) -> Result<Vec<E>, DomainError>;
+        fn insert_many(&self, entities: Vec<E>) -> Result<(), DomainError>;
+        fn save_all_concurrent(&self, entities: Vec<E>, parallelism: usize) -> Result<(), DomainError>;
+    }
+
+    /// spanに付けるEntityの型名。モジュールのパスは除く。
+    fn entity_name<E>() -> &'static str {
+        any::type_name::<E>().rsplit("::").next().unwrap_or_default()
+    }
+
+    impl<E: Entity, T: HaveStorageComponent<E> + HaveTracingComponent> Repository<E, E::Id> for T {
+        fn get(&self, id: E::Id) -> Result<E, DomainError> {
+            let _span = self
+                .tracing_component()
+                .start_span("repository.get", &[("entity", entity_name::<E>()), ("id", &format!("{:?}", id))]);
+            Ok(self.storage_component().read(id)?)
+        }
+
+        /// 既に同じIDのEntityが存在する場合はエラー
+        fn insert(&self, entity: E) -> Result<(), DomainError> {
+            let id = entity.id();
+            let _span = self
+                .tracing_component()
+                .start_span("repository.insert", &[("entity", entity_name::<E>()), ("id", &format!("{:?}", id))]);
+            if self.storage_component().read(id.clone()).is_ok() {
+                return Err(DomainError::already_exists(&id));
+            }
+            Ok(self.storage_component().save(id, entity)?)
+        }
+
+        /// 同じIDのEntityが存在しない場合はエラー。
+        /// バージョンを持つEntityはバージョンを1つ上げて保存するので、
+        /// 読んでから保存するまでに他で更新されているとストレージがConflictを返す。
+        fn update(&self, mut entity: E) -> Result<(), DomainError> {
+            let id = entity.id();
+            let _span = self
+                .tracing_component()
+                .start_span("repository.update", &[("entity", entity_name::<E>()), ("id", &format!("{:?}", id))]);
+            self.storage_component().read(id.clone())?;
+            if let Some(version) = entity.version() {
+                entity.set_version(version + 1);
+            }
+            Ok(self.storage_component().save(id, entity)?)
+        }
+
+        fn delete(&self, id: E::Id) -> Result<(), DomainError> {
+            let _span = self
+                .tracing_component()
+                .start_span("repository.delete", &[("entity", entity_name::<E>()), ("id", &format!("{:?}", id))]);
+            Ok(self.storage_component().delete(id)?)
+        }
+
+        fn list(&self) -> Result<Vec<E>, DomainError> {
+            let _span = self.tracing_component().start_span("repository.list", &[("entity", entity_name::<E>())]);
+            Ok(self.storage_component().read_all()?)
+        }
+
+        /// まとめて保存する。既にあるIDや、同じIDが2回入っている場合は1件も保存しない。
+        fn insert_many(&self, entities: Vec<E>) -> Result<(), DomainError> {
+            let _span = self.tracing_component().start_span(
+                "repository.insert_many",
+                &[("entity", entity_name::<E>()), ("count", &entities.len().to_string())],
+            );
+            let mut ids = BTreeSet::new();
+            for entity in &entities {
+                if !ids.insert(entity.id()) {
+                    return Err(DomainError::already_exists(&entity.id()));
+                }
+            }
+            let ids: Vec<E::Id> = ids.into_iter().collect();
+            if let Some(existing) = self.storage_component().read_many(&ids)?.first() {
+                return Err(DomainError::already_exists(&existing.id()));
+            }
+            let values: Vec<(E::Id, E)> = entities.into_iter().map(|entity| (entity.id(), entity)).collect();
+            Ok(self.storage_component().save_all(&values)?)
+        }
+
+        /// 大量のEntityを、無ければ追加しあれば置き換えて、`parallelism` 個までの塊に分けて並行に保存する。
+        /// insert_manyと違って全てか無しかにはならないので、失敗した時にどれが保存されたかは読んで確かめる。
+        /// 同じIDが2回入っていれば後の方が残る。
+        fn save_all_concurrent(&self, entities: Vec<E>, parallelism: usize) -> Result<(), DomainError> {
+            let _span = self.tracing_component().start_span(
+                "repository.save_all_concurrent",
+                &[
+                    ("entity", entity_name::<E>()),
+                    ("count", &entities.len().to_string()),
+                    ("parallelism", &parallelism.to_string()),
+                ],
+            );
+            let values: Vec<(E::Id, E)> = entities.into_iter().map(|entity| (entity.id(), entity)).collect();
+            Ok(self.storage_component().save_all_concurrent(&values, parallelism)?)
+        }
+    }
+
+    pub mod users {
+        //! Userの読み書きは、状態を変えるコマンド(UserCommands)と読むだけのクエリ(UserQueries)に分けている。
+        //! クエリは `&self` だけで答えられるので、envはレプリカや射影(projection)を返してもよい。
+        //! Cacheしたい場合はenvが `component::cache::CachingStorage` でストレージを包んで返す。
+        //! 実際のプロダクトではこの辺のレイヤはもっと泥臭い感じになると思う
+
+        use chrono::Duration;
+        use component::id::{HaveIdGeneratorComponent, IdGeneratorComponent};
+        use component::event_bus::{EventBusComponent, HaveEventBusComponent};
+        use component::lock::{HaveLockComponent, LockComponent};
+        use component::log::{HaveLoggingComponent, LoggingComponent};
+        use component::metrics::{HaveMetricsComponent, MetricsComponent};
+        use component::storage::{HaveUserStorageComponent, StorageComponent, UserStorageComponent};
+        use component::time::{TimeComponent, HaveTimeComponent};
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use component::validation::{HaveValidationComponent, ValidationComponent};
+        use entity::user::{Email, Name, Role, StatusError, User, UserEvent, UserId, UserStatus};
+        use super::{DomainError, Repository};
+
+        /// 名前・メールアドレスの重複チェックから保存までの間に取るロック
+        const CREATE_LOCK: &str = "users.create";
+
+        /// `Repository<User, UserId> + HaveTimeComponent + ...` は、+の左右のtraitを実装(impl)している型だけが、
+        /// UserCommandsを実装できる事を意味している。
+        /// get/update/delete/listは汎用のRepositoryのものをそのまま使い、User固有の処理だけをここに書く。
+        pub trait UserCommands:
+            Repository<User, UserId>
+            + HaveUserStorageComponent
+            + HaveTimeComponent
+            + HaveIdGeneratorComponent
+            + HaveLoggingComponent
+            + HaveMetricsComponent
+            + HaveLockComponent
+            + HaveEventBusComponent
+            + HaveValidationComponent
+            + HaveTracingComponent
+        {
+            /// 新しいUserIdを払い出し、現在時刻を作成日時・更新日時にしたUserを作って保存する。
+            /// 同じ名前のユーザーを他のインスタンスが同時に作らないように、保存が終わるまでロックを取る。
+            /// 名前・メールアドレスは環境に登録された検証のルールも守っている必要がある。
+            fn create(&self, name: Name, email: Email) -> Result<User, DomainError> {
+                let _span = self.tracing_component().start_span("users.create", &[("name", name.as_str())]);
+                let user = User::builder()
+                    .id(UserId::new(self.id_generator_component().generate()))
+                    .name(name)
+                    .email(email)
+                    .build(|| self.time_component().now())?;
+                self.validation_component().validate(&user)?;
+                let lock = self.lock_component().acquire(CREATE_LOCK, Duration::seconds(5))?;
+                let inserted = self.insert(user.clone());
+                self.lock_component().release(lock)?;
+                inserted?;
+                self.publish(&user, UserEvent::Created);
+                Ok(user)
+            }
+
+            /// 名前を変更して、更新日時を現在時刻にする
+            fn rename(&self, id: UserId, name: Name) -> Result<User, DomainError> {
+                let mut user = self.get(id)?;
+                user.name = name;
+                self.validation_component().validate(&user)?;
+                user.update_time = self.time_component().now();
+                self.update(user.clone())?;
+                self.publish(&user, UserEvent::Renamed);
+                Ok(user)
+            }
+
+            /// メールアドレスを変更して、更新日時を現在時刻にする
+            fn change_email(&self, id: UserId, email: Email) -> Result<User, DomainError> {
+                let mut user = self.get(id)?;
+                user.email = email;
+                self.validation_component().validate(&user)?;
+                user.update_time = self.time_component().now();
+                self.update(user.clone())?;
+                self.publish(&user, UserEvent::EmailChanged);
+                Ok(user)
+            }
+
+            /// 役割を変更して、更新日時を現在時刻にする
+            fn change_role(&self, id: UserId, role: Role) -> Result<User, DomainError> {
+                let mut user = self.get(id)?;
+                user.role = role;
+                user.update_time = self.time_component().now();
+                self.update(user.clone())?;
+                self.publish(&user, UserEvent::RoleChanged(role));
+                Ok(user)
+            }
+
+            /// Activeなユーザーを一時停止する
+            fn suspend(&self, id: UserId) -> Result<User, DomainError> {
+                let mut user = self.get(id)?;
+                if user.status != UserStatus::Active {
+                    let (status, id) = (user.status, user.id);
+                    return Err(StatusError::NotActive { action: "suspend", status, id }.into());
+                }
+                user.status = UserStatus::Suspended;
+                user.update_time = self.time_component().now();
+                self.update(user.clone())?;
+                self.publish(&user, UserEvent::Suspended);
+                Ok(user)
+            }
+
+            /// 使われなくなったユーザーを無効化する
+            fn deactivate(&self, id: UserId) -> Result<User, DomainError> {
+                let mut user = self.get(id)?;
+                if user.status == UserStatus::Deactivated {
+                    return Err(StatusError::Already { status: user.status, id: user.id }.into());
+                }
+                user.status = UserStatus::Deactivated;
+                user.update_time = self.time_component().now();
+                self.update(user.clone())?;
+                self.publish(&user, UserEvent::Deactivated);
+                Ok(user)
+            }
+
+            /// 停止・無効化されているユーザーをActiveに戻す
+            fn reactivate(&self, id: UserId) -> Result<User, DomainError> {
+                let mut user = self.get(id)?;
+                if user.status == UserStatus::Active {
+                    return Err(StatusError::Already { status: user.status, id: user.id }.into());
+                }
+                user.status = UserStatus::Active;
+                user.update_time = self.time_component().now();
+                self.update(user.clone())?;
+                self.publish(&user, UserEvent::Reactivated);
+                Ok(user)
+            }
+
+            /// 変更を保存した後に呼ぶ。ログとメトリクスに残して、EventBusComponentの購読者に配る。
+            /// 変更自体は保存済みなので、購読者が失敗してもエラーにはせずログに残すだけにする。
+            fn publish(&self, user: &User, event: UserEvent) {
+                self.logging_component().info(&format!("{}: {:?}", event, user.id));
+                self.metrics_component().increment(&format!("users.{}", event.kind()), 1);
+                for (subscriber, e) in self.event_bus_component().dispatch(self, user, event) {
+                    self.logging_component().warn(&format!("{} failed on {:?}: {}", subscriber, user.id, e));
+                }
+            }
+        }
+
+        /// 環境型は複数のEntityについて汎用のRepositoryを実装(impl)するので、環境型のまま `get` 等を呼ぶと
+        /// どのEntityのRepositoryなのか決まらない。なのでGetterの戻り値は `impl UserCommands` にして、
+        /// 呼び出し側からはUserCommandsとしてだけ見えるようにしている。
+        /// ストレージは `&self` のまま書き込めるので、コマンドも `&self` から取り出せる。共有した環境型からでも書き込める。
+        pub trait HaveUserCommands {
+            fn user_commands(&self) -> &impl UserCommands;
+        }
+
+        /// traitの実装(impl)は具象型だけでなくジェネリクスのパラメータのみで実装する事も出来る。
+        /// これにより特定の条件を満たしている型全ての実装(impl)を用意する事が簡単に行える。
+        impl<T> UserCommands for T
+        where
+            T: HaveUserStorageComponent
+                + HaveTimeComponent
+                + HaveIdGeneratorComponent
+                + HaveLoggingComponent
+                + HaveMetricsComponent
+                + HaveLockComponent
+                + HaveEventBusComponent
+                + HaveValidationComponent
+                + HaveTracingComponent,
+        {
+        }
+
+        /// 一覧を絞り込む条件。指定しなかった条件では絞り込まない
+        #[derive(Debug, Clone, Default, PartialEq, Eq)]
+        pub struct UserFilter {
+            /// メールアドレスの `@` より後ろ。大文字と小文字は区別しない
+            pub email_domain: Option<String>,
+        }
+
+        impl UserFilter {
+            pub fn matches(&self, user: &User) -> bool {
+                match self.email_domain {
+                    Some(ref domain) => user
+                        .email
+                        .as_str()
+                        .rsplit('@')
+                        .next()
+                        .map(|own| own.eq_ignore_ascii_case(domain))
+                        .unwrap_or(false),
+                    None => true,
+                }
+            }
+        }
+
+        /// 読むだけのUserの問い合わせ
+        pub trait UserQueries {
+            fn get(&self, id: UserId) -> Result<User, DomainError>;
+            fn get_by_name(&self, name: &Name) -> Result<User, DomainError>;
+            fn get_by_email(&self, email: &Email) -> Result<User, DomainError>;
+            /// 見つからないIDは飛ばす
+            fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, DomainError>;
+            fn list(&self) -> Result<Vec<User>, DomainError>;
+            /// 全ユーザーを1件ずつ読む。件数の多いエクスポートなどで、全件を一度にメモリへ載せないために使う
+            fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = Result<User, DomainError>> + 'a>;
+            /// `filter` に合うユーザーだけを返す
+            fn find(&self, filter: &UserFilter) -> Result<Vec<User>, DomainError>;
+        }
+
+        /// UserStorageComponentを持っている型ならクエリに答えられる。get/listは汎用のRepositoryを通す。
+        impl<T: HaveUserStorageComponent + HaveTracingComponent> UserQueries for T {
+            fn get(&self, id: UserId) -> Result<User, DomainError> {
+                Repository::get(self, id)
+            }
+
+            fn get_by_name(&self, name: &Name) -> Result<User, DomainError> {
+                Ok(self.user_storage_component().read_by_name(name)?)
+            }
+
+            fn get_by_email(&self, email: &Email) -> Result<User, DomainError> {
+                Ok(self.user_storage_component().read_by_email(email)?)
+            }
+
+            fn get_many(&self, ids: &[UserId]) -> Result<Vec<User>, DomainError> {
+                Ok(self.user_storage_component().read_many(ids)?)
+            }
+
+            fn list(&self) -> Result<Vec<User>, DomainError> {
+                Repository::list(self)
+            }
+
+            fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = Result<User, DomainError>> + 'a> {
+                Box::new(self.user_storage_component().iter_all().map(|user| Ok(user?)))
+            }
+
+            fn find(&self, filter: &UserFilter) -> Result<Vec<User>, DomainError> {
+                let _span = self.tracing_component().start_span("repository.find", &[("entity", "User")]);
+                self.iter()
+                    .filter(|user| user.as_ref().map(|user| filter.matches(user)).unwrap_or(true))
+                    .collect()
+            }
+        }
+
+        /// これを実装(impl)している型はUserQueriesを返せる。抽象化されたGetter.
+        /// 書き込み先とは別のレプリカや射影から読ませたい場合は、それを包んだ型を返せばよい。
+        pub trait HaveUserQueries {
+            fn user_queries(&self) -> &impl UserQueries;
+        }
+    }
+
+    pub mod credentials {
+        //! 認証のユースケースから使う、パスワードの設定と照合。
+        //! パスワードそのものは保存せず、PasswordHasherComponentでハッシュ化した値だけを保存する。
+
+        use component::password::{HavePasswordHasherComponent, PasswordHasherComponent};
+        use component::storage::HaveCredentialStorageComponent;
+        use component::trace::HaveTracingComponent;
+        use entity::credentials::Credentials;
+        use entity::user::UserId;
+        use failure::Error;
+        use super::Repository;
+
+        pub trait CredentialRepository: Repository<Credentials, UserId> + HavePasswordHasherComponent {
+            /// パスワードを設定する。既に設定されている場合は置き換える。
+            fn set_password(&self, user_id: UserId, password: &str) -> Result<(), Error> {
+                let credentials = Credentials {
+                    user_id: user_id.clone(),
+                    password_hash: self.password_hasher_component().hash(password)?,
+                };
+                if self.get(user_id).is_ok() {
+                    Ok(self.update(credentials)?)
+                } else {
+                    Ok(self.insert(credentials)?)
+                }
+            }
+
+            /// パスワードが合っているかを返す。認証情報が無い場合はエラー。
+            fn verify_password(&self, user_id: UserId, password: &str) -> Result<bool, Error> {
+                let credentials: Credentials = self.get(user_id)?;
+                self.password_hasher_component()
+                    .verify(password, &credentials.password_hash)
+            }
+        }
+
+        pub trait HaveCredentialRepository {
+            fn credential_repository(&self) -> &impl CredentialRepository;
+        }
+
+        impl<T> CredentialRepository for T
+        where
+            T: HaveCredentialStorageComponent + HavePasswordHasherComponent + HaveTracingComponent,
+        {
+        }
+    }
+
+    pub mod groups {
+        //! 2つ目の集約の例。ストレージとRepositoryはUserと同じ形で差し込み、
+        //! ユーザーの存在確認はHaveUserQueries経由で行う。
+
+        use component::storage::HaveGroupStorageComponent;
+        use component::trace::HaveTracingComponent;
+        use component::time::{HaveTimeComponent, TimeComponent};
+        use entity::group::{Group, GroupName};
+        use entity::user::UserId;
+        use failure::Error;
+        use repository::users::{HaveUserQueries, UserQueries};
+        use super::Repository;
+        use super::unit_of_work::UnitOfWork;
+
+        pub trait GroupRepository: Repository<Group, GroupName> + HaveTimeComponent + HaveUserQueries {
+            /// メンバーのいないグループを作って保存する
+            fn create_group(&self, name: GroupName) -> Result<Group, Error> {
+                let group = Group::new(name, self.time_component().now());
+                self.insert(group.clone())?;
+                Ok(group)
+            }
+
+            /// ユーザーをグループに加える。既にメンバーの場合は何もしない。
+            fn add_member(&self, name: GroupName, user_id: UserId) -> Result<Group, Error> {
+                self.user_queries().get(user_id.clone())?;
+                let mut group = self.get(name)?;
+                if group.members.insert(user_id) {
+                    self.update(group.clone())?;
+                }
+                Ok(group)
+            }
+
+            /// ユーザーをグループから外す。メンバーでない場合はエラー。
+            fn remove_member(&self, name: GroupName, user_id: UserId) -> Result<Group, Error> {
+                let mut group = self.get(name)?;
+                if !group.members.remove(&user_id) {
+                    bail!("not a member of {:?}: {:?}", group.name, user_id);
+                }
+                self.update(group.clone())?;
+                Ok(group)
+            }
+
+            /// ユーザーを全てのグループから外して、外したグループの数を返す。
+            /// 途中で失敗したら、それまでに外したグループにも戻す。
+            fn leave_all_groups(&self, user_id: &UserId) -> Result<usize, Error> {
+                let mut uow = UnitOfWork::new();
+                let mut count = 0;
+                for mut group in self.list()? {
+                    if group.members.remove(user_id) {
+                        uow.register_dirty(group);
+                        count += 1;
+                    }
+                }
+                uow.commit(self)?;
+                Ok(count)
+            }
+        }
+
+        pub trait HaveGroupRepository {
+            fn group_repository(&self) -> &impl GroupRepository;
+        }
+
+        impl<T> GroupRepository for T where
+            T: HaveGroupStorageComponent + HaveTimeComponent + HaveUserQueries + HaveTracingComponent
+        {
+        }
+    }
+
+    pub mod profiles {
+        //! 表示用のデータをアカウントとは別の集約として扱う例。
+        //! ProfileはUserをIDでだけ参照するので、Userの更新とProfileの更新は互いに影響しない。
+
+        use component::storage::HaveProfileStorageComponent;
+        use component::trace::HaveTracingComponent;
+        use component::time::{HaveTimeComponent, TimeComponent};
+        use entity::profile::Profile;
+        use entity::user::UserId;
+        use failure::Error;
+        use repository::users::{HaveUserQueries, UserQueries};
+        use super::Repository;
+
+        pub trait ProfileRepository: Repository<Profile, UserId> + HaveTimeComponent + HaveUserQueries {
+            /// 存在するユーザーに空のプロフィールを作って保存する
+            fn create_profile(&self, user_id: UserId) -> Result<Profile, Error> {
+                self.user_queries().get(user_id.clone())?;
+                let profile = Profile::new(user_id, self.time_component().now());
+                self.insert(profile.clone())?;
+                Ok(profile)
+            }
+
+            /// プロフィールを書き換えて保存する。update_timeはここで現在時刻にする。
+            fn edit_profile<F: FnOnce(&mut Profile)>(&self, user_id: UserId, edit: F) -> Result<Profile, Error> {
+                let mut profile = self.get(user_id)?;
+                edit(&mut profile);
+                profile.update_time = self.time_component().now();
+                self.update(profile.clone())?;
+                Ok(profile)
+            }
+        }
+
+        pub trait HaveProfileRepository {
+            fn profile_repository(&self) -> &impl ProfileRepository;
+        }
+
+        impl<T> ProfileRepository for T where
+            T: HaveProfileStorageComponent + HaveTimeComponent + HaveUserQueries + HaveTracingComponent
+        {
+        }
+    }
+
+    pub mod sessions {
+        //! ログインセッションの発行・検証・失効。
+        //! 有効期限の判定にはTimeComponentの現在時刻を使うので、テストではMockTimeで時刻を固定できる。
+
+        use chrono::Duration;
+        use component::id::{HaveIdGeneratorComponent, IdGeneratorComponent};
+        use component::storage::HaveSessionStorageComponent;
+        use component::trace::HaveTracingComponent;
+        use component::time::{HaveTimeComponent, TimeComponent};
+        use entity::session::{Session, SessionId};
+        use entity::user::UserId;
+        use failure::Error;
+        use super::Repository;
+        use super::unit_of_work::UnitOfWork;
+
+        pub trait SessionRepository:
+            Repository<Session, SessionId> + HaveTimeComponent + HaveIdGeneratorComponent
+        {
+            /// 現在時刻から `ttl` の間有効なセッションを作って保存する
+            fn create_session(&self, user_id: UserId, ttl: Duration) -> Result<Session, Error> {
+                let now = self.time_component().now();
+                let session = Session {
+                    id: SessionId::new(self.id_generator_component().generate()),
+                    user_id,
+                    create_time: now,
+                    expires_at: now + ttl,
+                };
+                self.insert(session.clone())?;
+                Ok(session)
+            }
+
+            /// セッションが存在して、かつ有効期限内ならそのセッションを返す
+            fn validate_session(&self, id: SessionId) -> Result<Session, Error> {
+                let session = self.get(id)?;
+                if session.is_expired(self.time_component().now()) {
+                    bail!("session expired: {:?}", session.id);
+                }
+                Ok(session)
+            }
+
+            fn revoke_session(&self, id: SessionId) -> Result<(), Error> {
+                Ok(self.delete(id)?)
+            }
+
+            /// `user_id` のセッションを全て失効させて、失効させた数を返す
+            fn revoke_user_sessions(&self, user_id: &UserId) -> Result<usize, Error> {
+                self.revoke_sessions_where(|session| session.user_id == *user_id)
+            }
+
+            /// 期限切れのセッションを消して、消した数を返す
+            fn purge_expired(&self) -> Result<usize, Error> {
+                let now = self.time_component().now();
+                self.revoke_sessions_where(|session| session.is_expired(now))
+            }
+
+            /// `matches` がtrueを返したセッションを失効させて、失効させた数を返す。
+            /// 途中で失敗したら、それまでに失効させたセッションも戻す。
+            fn revoke_sessions_where<F: Fn(&Session) -> bool>(&self, matches: F) -> Result<usize, Error> {
+                let mut uow = UnitOfWork::new();
+                let mut count = 0;
+                for session in self.list()?.into_iter().filter(|session| matches(session)) {
+                    uow.register_deleted(session.id);
+                    count += 1;
+                }
+                uow.commit(self)?;
+                Ok(count)
+            }
+        }
+
+        pub trait HaveSessionRepository {
+            fn session_repository(&self) -> &impl SessionRepository;
+        }
+
+        impl<T> SessionRepository for T where
+            T: HaveSessionStorageComponent + HaveTimeComponent + HaveIdGeneratorComponent + HaveTracingComponent
+        {
+        }
+    }
+
+    pub mod api_tokens {
+        //! 機械的なクライアント向けのAPIトークン。
+        //! 利用者に渡すトークンは `<トークンID>.<秘密の文字列>` の形で、保存するのは秘密の文字列のハッシュだけ。
+        //! トークンIDで保存先を引いてから、秘密の文字列をPasswordHasherComponentで照合する。
+
+        use chrono::Duration;
+        use component::id::{HaveIdGeneratorComponent, IdGeneratorComponent};
+        use component::password::{HavePasswordHasherComponent, PasswordHasherComponent};
+        use component::random::{HaveRandomComponent, RandomComponent};
+        use component::rate_limit::{HaveRateLimiterComponent, RateLimit, RateLimiterComponent};
+        use component::storage::HaveApiTokenStorageComponent;
+        use component::trace::HaveTracingComponent;
+        use component::time::{HaveTimeComponent, TimeComponent};
+        use entity::api_token::{ApiToken, ApiTokenId, Scope};
+        use entity::user::UserId;
+        use failure::Error;
+        use uuid::Uuid;
+        use super::Repository;
+
+        /// 秘密の文字列の長さ。英数字32文字で190bit程度になる。
+        const SECRET_LEN: usize = 32;
+
+        pub trait ApiTokenRepository:
+            Repository<ApiToken, ApiTokenId>
+            + HaveTimeComponent
+            + HaveIdGeneratorComponent
+            + HaveRandomComponent
+            + HavePasswordHasherComponent
+            + HaveRateLimiterComponent
+        {
+            /// トークンを発行する。平文のトークンはここで返す1回しか手に入らない。
+            /// `ttl` がNoneなら有効期限なし。
+            fn issue_token(
+                &self,
+                owner: UserId,
+                scopes: &[Scope],
+                ttl: Option<Duration>,
+            ) -> Result<(ApiToken, String), Error> {
+                let id = ApiTokenId::new(self.id_generator_component().generate());
+                let secret = self.random_component().token(SECRET_LEN);
+                let now = self.time_component().now();
+                let token = ApiToken {
+                    id: id.clone(),
+                    token_hash: self.password_hasher_component().hash(&secret)?,
+                    owner,
+                    scopes: scopes.iter().cloned().collect(),
+                    create_time: now,
+                    expires_at: ttl.map(|ttl| now + ttl),
+                };
+                self.insert(token.clone())?;
+                Ok((token, format!("{}.{}", id.as_uuid().simple(), secret)))
+            }
+
+            /// `owner` が持っているトークンを発行順に返す
+            fn list_tokens(&self, owner: &UserId) -> Result<Vec<ApiToken>, Error> {
+                let mut tokens: Vec<ApiToken> = self.list()?.into_iter().filter(|t| t.owner == *owner).collect();
+                tokens.sort_by_key(|t| t.create_time);
+                Ok(tokens)
+            }
+
+            fn revoke_token(&self, id: ApiTokenId) -> Result<(), Error> {
+                Ok(self.delete(id)?)
+            }
+
+            /// 平文のトークンを照合し、有効期限内ならトークンを返す。
+            /// 秘密の文字列の総当たりを防ぐため、照合の回数はトークンID毎に制限する。
+            fn authenticate_token(&self, token: &str) -> Result<ApiToken, Error> {
+                let mut parts = token.splitn(2, '.');
+                let (id, secret) = match (parts.next(), parts.next()) {
+                    (Some(id), Some(secret)) => (id, secret),
+                    _ => bail!("malformed api token"),
+                };
+                let id = ApiTokenId::new(Uuid::parse_str(id)?);
+                let key = format!("api_token:{}", id.as_uuid().simple());
+                if let RateLimit::Limited { retry_after } = self.rate_limiter_component().check_and_consume(&key) {
+                    bail!("too many attempts for api token {:?}, retry after {}s", id, retry_after.num_seconds());
+                }
+                let stored = self.get(id)?;
+                if !self.password_hasher_component().verify(secret, &stored.token_hash)? {
+                    bail!("invalid api token");
+                }
+                if stored.is_expired(self.time_component().now()) {
+                    bail!("api token expired: {:?}", stored.id);
+                }
+                Ok(stored)
+            }
+        }
+
+        pub trait HaveApiTokenRepository {
+            fn api_token_repository(&self) -> &impl ApiTokenRepository;
+        }
+
+        impl<T> ApiTokenRepository for T
+        where
+            T: HaveApiTokenStorageComponent
+                + HaveTimeComponent
+                + HaveIdGeneratorComponent
+                + HaveRandomComponent
+                + HavePasswordHasherComponent
+                + HaveRateLimiterComponent
+                + HaveTracingComponent,
+        {
+        }
+    }
+
+    pub mod password_resets {
+        //! パスワード再設定用のトークン。形式と照合の仕方はAPIトークンと同じで、`<トークンID>.<秘密の文字列>` を渡す。
+        //! 使えるのは1回だけで、照合に成功したトークンはその場で消す。
+
+        use chrono::Duration;
+        use component::id::{HaveIdGeneratorComponent, IdGeneratorComponent};
+        use component::password::{HavePasswordHasherComponent, PasswordHasherComponent};
+        use component::random::{HaveRandomComponent, RandomComponent};
+        use component::storage::HavePasswordResetStorageComponent;
+        use component::trace::HaveTracingComponent;
+        use component::time::{HaveTimeComponent, TimeComponent};
+        use entity::password_reset::{PasswordResetId, PasswordResetToken};
+        use entity::user::UserId;
+        use failure::Error;
+        use uuid::Uuid;
+        use super::Repository;
+        use super::unit_of_work::UnitOfWork;
+
+        /// 秘密の文字列の長さ
+        const SECRET_LEN: usize = 32;
+
+        pub trait PasswordResetRepository:
+            Repository<PasswordResetToken, PasswordResetId>
+            + HaveTimeComponent
+            + HaveIdGeneratorComponent
+            + HaveRandomComponent
+            + HavePasswordHasherComponent
+        {
+            /// トークンを発行し、平文のトークンを返す。
+            /// 有効なトークンはユーザー毎に1つだけにするため、同じユーザーの発行済みのトークンは消す。
+            fn issue_reset(&self, user_id: UserId, ttl: Duration) -> Result<(PasswordResetToken, String), Error> {
+                let mut uow = UnitOfWork::new();
+                for old in self.list()?.into_iter().filter(|t| t.user_id == user_id) {
+                    uow.register_deleted(old.id);
+                }
+                let id = PasswordResetId::new(self.id_generator_component().generate());
+                let secret = self.random_component().token(SECRET_LEN);
+                let now = self.time_component().now();
+                let token = PasswordResetToken {
+                    id: id.clone(),
+                    user_id,
+                    token_hash: self.password_hasher_component().hash(&secret)?,
+                    expires_at: now + ttl,
+                };
+                uow.register_new(token.clone());
+                uow.commit(self)?;
+                Ok((token, format!("{}.{}", id.as_uuid().simple(), secret)))
+            }
+
+            /// 平文のトークンを照合して消し、トークンを発行したユーザーを返す。
+            /// 期限切れのトークンも消してからエラーにする。
+            fn redeem_reset(&self, token: &str) -> Result<UserId, Error> {
+                let mut parts = token.splitn(2, '.');
+                let (id, secret) = match (parts.next(), parts.next()) {
+                    (Some(id), Some(secret)) => (id, secret),
+                    _ => bail!("malformed password reset token"),
+                };
+                let id = PasswordResetId::new(Uuid::parse_str(id)?);
+                let stored = self.get(id)?;
+                if !self.password_hasher_component().verify(secret, &stored.token_hash)? {
+                    bail!("invalid password reset token");
+                }
+                self.delete(stored.id.clone())?;
+                if stored.is_expired(self.time_component().now()) {
+                    bail!("password reset token expired: {:?}", stored.id);
+                }
+                Ok(stored.user_id)
+            }
+        }
+
+        pub trait HavePasswordResetRepository {
+            fn password_reset_repository(&self) -> &impl PasswordResetRepository;
+        }
+
+        impl<T> PasswordResetRepository for T
+        where
+            T: HavePasswordResetStorageComponent
+                + HaveTimeComponent
+                + HaveIdGeneratorComponent
+                + HaveRandomComponent
+                + HavePasswordHasherComponent
+                + HaveTracingComponent,
+        {
+        }
+    }
+
+    pub mod invitations {
+        //! 招待。招待用のトークンの形式と照合の仕方はAPIトークンと同じで、`<招待ID>.<秘密の文字列>` を渡す。
+
+        use chrono::Duration;
+        use component::id::{HaveIdGeneratorComponent, IdGeneratorComponent};
+        use component::password::{HavePasswordHasherComponent, PasswordHasherComponent};
+        use component::random::{HaveRandomComponent, RandomComponent};
+        use component::storage::HaveInvitationStorageComponent;
+        use component::trace::HaveTracingComponent;
+        use component::time::{HaveTimeComponent, TimeComponent};
+        use entity::invitation::{Invitation, InvitationId};
+        use entity::user::{Email, Role, UserId};
+        use failure::Error;
+        use uuid::Uuid;
+        use super::Repository;
+        use super::unit_of_work::UnitOfWork;
+
+        /// 秘密の文字列の長さ
+        const SECRET_LEN: usize = 32;
+
+        pub trait InvitationRepository:
+            Repository<Invitation, InvitationId>
+            + HaveTimeComponent
+            + HaveIdGeneratorComponent
+            + HaveRandomComponent
+            + HavePasswordHasherComponent
+        {
+            /// 招待を作り、平文のトークンを返す。同じメールアドレス宛の招待が残っていれば置き換える。
+            fn invite(
+                &self,
+                email: Email,
+                role: Role,
+                invited_by: UserId,
+                ttl: Duration,
+            ) -> Result<(Invitation, String), Error> {
+                let mut uow = UnitOfWork::new();
+                for old in self.list()?.into_iter().filter(|i| i.email == email) {
+                    uow.register_deleted(old.id);
+                }
+                let id = InvitationId::new(self.id_generator_component().generate());
+                let secret = self.random_component().token(SECRET_LEN);
+                let now = self.time_component().now();
+                let invitation = Invitation {
+                    id: id.clone(),
+                    email,
+                    role,
+                    invited_by,
+                    token_hash: self.password_hasher_component().hash(&secret)?,
+                    create_time: now,
+                    expires_at: now + ttl,
+                };
+                uow.register_new(invitation.clone());
+                uow.commit(self)?;
+                Ok((invitation, format!("{}.{}", id.as_uuid().simple(), secret)))
+            }
+
+            /// 平文のトークンを照合し、有効期限内なら招待を返す。招待は消さない。
+            fn verify_invitation(&self, token: &str) -> Result<Invitation, Error> {
+                let mut parts = token.splitn(2, '.');
+                let (id, secret) = match (parts.next(), parts.next()) {
+                    (Some(id), Some(secret)) => (id, secret),
+                    _ => bail!("malformed invitation token"),
+                };
+                let stored = self.get(InvitationId::new(Uuid::parse_str(id)?))?;
+                if !self.password_hasher_component().verify(secret, &stored.token_hash)? {
+                    bail!("invalid invitation token");
+                }
+                if stored.is_expired(self.time_component().now()) {
+                    bail!("invitation expired: {:?}", stored.id);
+                }
+                Ok(stored)
+            }
+        }
+
+        pub trait HaveInvitationRepository {
+            fn invitation_repository(&self) -> &impl InvitationRepository;
+        }
+
+        impl<T> InvitationRepository for T
+        where
+            T: HaveInvitationStorageComponent
+                + HaveTimeComponent
+                + HaveIdGeneratorComponent
+                + HaveRandomComponent
+                + HavePasswordHasherComponent
+                + HaveTracingComponent,
+        {
+        }
+    }
+
+    pub mod unit_of_work {
+        //! 複数回のRepository呼び出しをまとめて適用する。
+        //! ストレージにトランザクションが無くても、途中で失敗したら適用済みの変更を逆順に戻す。
+
+        use entity::Entity;
+        use super::{DomainError, Repository};
+
+        /// UnitOfWorkに積まれる変更
+        enum Change<E: Entity> {
+            Insert(E),
+            Update(E),
+            Delete(E::Id),
+        }
+
+        /// 適用済みの変更を戻す為の情報。変更前の値を持っておく。
+        enum Undo<E: Entity> {
+            Delete(E::Id),
+            Restore(E),
+            Reinsert(E),
+        }
+
+        /// Entity `E` に対する変更を溜めておき、`commit()` でまとめて適用する。
+        pub struct UnitOfWork<E: Entity> {
+            changes: Vec<Change<E>>,
+        }
+
+        impl<E: Entity> UnitOfWork<E> {
+            pub fn new() -> UnitOfWork<E> {
+                UnitOfWork {
+                    changes: Vec::new(),
+                }
+            }
+
+            pub fn register_new(&mut self, entity: E) {
+                self.changes.push(Change::Insert(entity));
+            }
+
+            pub fn register_dirty(&mut self, entity: E) {
+                self.changes.push(Change::Update(entity));
+            }
+
+            pub fn register_deleted(&mut self, id: E::Id) {
+                self.changes.push(Change::Delete(id));
+            }
+
+            /// 溜めた変更を順に適用する。
+            /// 途中で失敗した場合は適用済みの変更を戻してから、最初のエラーを返す。
+            pub fn commit<R: Repository<E, E::Id> + ?Sized>(self, repository: &R) -> Result<(), DomainError> {
+                let mut undo_log = Vec::new();
+                for change in self.changes {
+                    let result = match change {
+                        Change::Insert(entity) => {
+                            let id = entity.id();
+                            repository.insert(entity).map(|_| Undo::Delete(id))
+                        }
+                        Change::Update(entity) => repository
+                            .get(entity.id())
+                            .and_then(|before| repository.update(entity).map(|_| Undo::Restore(before))),
+                        Change::Delete(id) => repository
+                            .get(id.clone())
+                            .and_then(|before| repository.delete(id).map(|_| Undo::Reinsert(before))),
+                    };
+                    match result {
+                        Ok(undo) => undo_log.push(undo),
+                        Err(e) => {
+                            rollback(repository, undo_log);
+                            return Err(e);
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+
+        impl<E: Entity> Default for UnitOfWork<E> {
+            fn default() -> UnitOfWork<E> {
+                UnitOfWork::new()
+            }
+        }
+
+        /// 戻す処理自体の失敗は、元のエラーを優先して返す為に無視する
+        fn rollback<E: Entity, R: Repository<E, E::Id> + ?Sized>(repository: &R, undo_log: Vec<Undo<E>>) {
+            for undo in undo_log.into_iter().rev() {
+                let _ = match undo {
+                    Undo::Delete(id) => repository.delete(id),
+                    Undo::Restore(mut entity) => {
+                        // 変更前の値のバージョンは古いので、今保存されているバージョンに合わせてから戻す
+                        if let Some(version) = repository.get(entity.id()).ok().and_then(|e| e.version()) {
+                            entity.set_version(version);
+                        }
+                        repository.update(entity)
+                    }
+                    Undo::Reinsert(entity) => repository.insert(entity),
+                };
+            }
+        }
+    }
+}
+
+mod service {
+    //! 1つのEntityには収まらず、複数のユースケースが同じように守る業務ルール(ドメインサービス)を書くレイヤ。
+    //! ルールはtraitにして、どうやって確かめるかはenvが選べるようにする。
+
+    pub mod unique_email {
+        use entity::user::{Email, UserId};
+        use repository::DomainError;
+        use repository::users::{HaveUserQueries, UserQueries};
+        use std::error;
+        use std::fmt;
+
+        /// 他のユーザーが既に使っているメールアドレス
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct EmailTaken {
+            pub email: Email,
+        }
+
+        impl fmt::Display for EmailTaken {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "email already taken: {:?}", self.email)
+            }
+        }
+
+        impl error::Error for EmailTaken {}
+
+        impl From<EmailTaken> for DomainError {
+            fn from(e: EmailTaken) -> DomainError {
+                DomainError::AlreadyExists {
+                    field: "email",
+                    value: format!("{:?}", e.email),
+                }
+            }
+        }
+
+        /// メールアドレスは1人のユーザーしか使えない、というルール。
+        /// 同時に登録・変更された場合はストレージの一意制約で弾かれるので、ここでの確認は先回りの確認になる。
+        pub trait UniqueEmailService {
+            /// `except` のユーザー自身が使っているアドレスは、使われていないものとして扱う
+            fn is_email_taken(&self, email: &Email, except: Option<&UserId>) -> Result<bool, DomainError>;
+
+            fn ensure_email_available(&self, email: &Email, except: Option<&UserId>) -> Result<(), DomainError> {
+                if self.is_email_taken(email, except)? {
+                    return Err(EmailTaken { email: email.clone() }.into());
+                }
+                Ok(())
+            }
+        }
+
+        /// ユーザーをメールアドレスで引いて確かめる実装。
+        /// 索引を持つ別の検索先で確かめたい場合は、HaveUniqueEmailServiceでその実装を返す。
+        impl<T: HaveUserQueries> UniqueEmailService for T {
+            fn is_email_taken(&self, email: &Email, except: Option<&UserId>) -> Result<bool, DomainError> {
+                Ok(match self.user_queries().get_by_email(email) {
+                    Ok(user) => except != Some(&user.id),
+                    Err(_) => false,
+                })
+            }
+        }
+
+        /// これを実装(impl)している型はUniqueEmailServiceを返せる。抽象化されたGetter.
+        pub trait HaveUniqueEmailService {
+            fn unique_email_service(&self) -> &impl UniqueEmailService;
+        }
+    }
+}
+
+mod usecase {
+    //! アプリケーション固有の業務ルールを書くレイヤ。
+    //! Repositoryと同じように、必要なHave traitだけを制約にしたtraitのデフォルト実装として書く。
+    //! 外から呼ぶユースケースには、環境型を借りてUseCaseとして実行するInteractorも用意する。
+
+    use component::log::{HaveLoggingComponent, LoggingComponent};
+    use component::metrics::{HaveMetricsComponent, MetricsComponent};
+    use component::time::{HaveMonotonicTimeComponent, MonotonicTimeComponent};
+    use component::transaction::TransactionComponent;
+    use entity::user::{Permission, UserId, UserStatus};
+    use failure::Error;
+    use repository::DomainError;
+    use repository::users::{HaveUserQueries, UserQueries};
+    use std::error;
+    use std::fmt;
+    use std::panic::{self, AssertUnwindSafe};
+
+    /// 入力を受け取って出力を返す、1つのユースケース。
+    /// 形がそろっているので、ログ等のミドルウェアや呼び出しの振り分けをユースケース毎に書かずに済む。
+    pub trait UseCase {
+        type Input;
+        type Output;
+        type Error;
+        fn execute(&mut self, input: Self::Input) -> Result<Self::Output, Self::Error>;
+    }
+
+    impl<U: UseCase + ?Sized> UseCase for &mut U {
+        type Input = U::Input;
+        type Output = U::Output;
+        type Error = U::Error;
+        fn execute(&mut self, input: U::Input) -> Result<U::Output, U::Error> {
+            (**self).execute(input)
+        }
+    }
+
+    /// `Box<dyn UseCase<...>>` にまとめて、入出力の型が同じユースケースを実行時に選べるようにする
+    impl<U: UseCase + ?Sized> UseCase for Box<U> {
+        type Input = U::Input;
+        type Output = U::Output;
+        type Error = U::Error;
+        fn execute(&mut self, input: U::Input) -> Result<U::Output, U::Error> {
+            (**self).execute(input)
+        }
+    }
+
+    /// 環境型を借りて動くUseCase。デコレータは環境型のComponentをここから使う。
+    pub trait Interactor: UseCase {
+        type World;
+        /// ログやメトリクスに使う名前
+        const NAME: &'static str;
+        fn world(&self) -> &Self::World;
+    }
+
+    /// Interactorにデコレータを重ねる。後から重ねたものほど外側で動く。
+    /// 横断的な振る舞いは各Interactorには書かず、envでユースケースを組み立てる時にこれで重ねる。
+    pub trait Decorate: Interactor + Sized {
+        fn logged(self) -> Logged<Self> {
+            Logged { inner: self }
+        }
+
+        fn metered(self) -> Metered<Self> {
+            Metered { inner: self }
+        }
+
+        /// `actor` が `permission` を持つActiveなユーザーの時だけ実行する
+        fn authorized(self, actor: UserId, permission: Permission) -> Authorized<Self> {
+            Authorized {
+                inner: self,
+                actor,
+                permission,
+            }
+        }
+
+        fn transactional(self) -> Transactional<Self> {
+            Transactional { inner: self }
+        }
+    }
+
+    impl<U: Interactor> Decorate for U {}
+
+    /// 実行の結果を環境型のLoggingComponentに残すデコレータ
+    pub struct Logged<U> {
+        inner: U,
+    }
+
+    impl<U> UseCase for Logged<U>
+    where
+        U: Interactor,
+        U::World: HaveLoggingComponent,
+        U::Error: fmt::Display,
+    {
+        type Input = U::Input;
+        type Output = U::Output;
+        type Error = U::Error;
+        fn execute(&mut self, input: U::Input) -> Result<U::Output, U::Error> {
+            let result = self.inner.execute(input);
+            let logger = self.inner.world().logging_component();
+            match result {
+                Ok(_) => logger.info(&format!("{} succeeded", U::NAME)),
+                Err(ref e) => logger.warn(&format!("{} failed: {}", U::NAME, e)),
+            }
+            result
+        }
+    }
+
+    impl<U> Interactor for Logged<U>
+    where
+        U: Interactor,
+        U::World: HaveLoggingComponent,
+        U::Error: fmt::Display,
+    {
+        type World = U::World;
+        const NAME: &'static str = U::NAME;
+        fn world(&self) -> &U::World {
+            self.inner.world()
+        }
+    }
+
+    /// 実行の回数を `usecase.<名前>.ok` / `usecase.<名前>.error` に、
+    /// かかった秒数を `usecase.<名前>.seconds` に記録するデコレータ
+    pub struct Metered<U> {
+        inner: U,
+    }
+
+    impl<U> UseCase for Metered<U>
+    where
+        U: Interactor,
+        U::World: HaveMetricsComponent + HaveMonotonicTimeComponent,
+    {
+        type Input = U::Input;
+        type Output = U::Output;
+        type Error = U::Error;
+        fn execute(&mut self, input: U::Input) -> Result<U::Output, U::Error> {
+            let started = self.inner.world().monotonic_time_component().instant();
+            let result = self.inner.execute(input);
+            let world = self.inner.world();
+            let elapsed = world.monotonic_time_component().elapsed(started);
+            let outcome = if result.is_ok() { "ok" } else { "error" };
+            world.metrics_component().increment(&format!("usecase.{}.{}", U::NAME, outcome), 1);
+            let seconds = elapsed.num_microseconds().unwrap_or(i64::MAX) as f64 / 1_000_000.0;
+            world.metrics_component().observe(&format!("usecase.{}.seconds", U::NAME), seconds);
+            result
+        }
+    }
+
+    impl<U> Interactor for Metered<U>
+    where
+        U: Interactor,
+        U::World: HaveMetricsComponent + HaveMonotonicTimeComponent,
+    {
+        type World = U::World;
+        const NAME: &'static str = U::NAME;
+        fn world(&self) -> &U::World {
+            self.inner.world()
+        }
+    }
+
+    /// 権限が無いユーザーが実行しようとした
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct PermissionDenied {
+        pub actor: UserId,
+        pub permission: Permission,
+        pub use_case: &'static str,
+    }
+
+    impl fmt::Display for PermissionDenied {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "permission denied: {:?} needs {:?} to {}", self.actor, self.permission, self.use_case)
+        }
+    }
+
+    impl error::Error for PermissionDenied {}
+
+    impl From<PermissionDenied> for DomainError {
+        fn from(e: PermissionDenied) -> DomainError {
+            DomainError::rejected(e)
+        }
+    }
+
+    /// 実行する前に、実行するユーザーの権限を確かめるデコレータ
+    pub struct Authorized<U> {
+        inner: U,
+        actor: UserId,
+        permission: Permission,
+    }
+
+    impl<U> UseCase for Authorized<U>
+    where
+        U: Interactor,
+        U::World: HaveUserQueries,
+        U::Error: From<DomainError>,
+    {
+        type Input = U::Input;
+        type Output = U::Output;
+        type Error = U::Error;
+        fn execute(&mut self, input: U::Input) -> Result<U::Output, U::Error> {
+            let actor = self
+                .inner
+                .world()
+                .user_queries()
+                .get(self.actor.clone())
+                .map_err(U::Error::from)?;
+            if actor.status != UserStatus::Active || !actor.can(self.permission) {
+                let denied = PermissionDenied {
+                    actor: actor.id,
+                    permission: self.permission,
+                    use_case: U::NAME,
+                };
+                return Err(DomainError::from(denied).into());
+            }
+            self.inner.execute(input)
+        }
+    }
+
+    impl<U> Interactor for Authorized<U>
+    where
+        U: Interactor,
+        U::World: HaveUserQueries,
+        U::Error: From<DomainError>,
+    {
+        type World = U::World;
+        const NAME: &'static str = U::NAME;
+        fn world(&self) -> &U::World {
+            self.inner.world()
+        }
+    }
+
+    /// 実行の前にトランザクションを始め、成功したら確定し、失敗したら変更を戻すデコレータ。
+    /// ユースケースの中で呼んだ `transaction` は、入れ子にはならずにこのトランザクションに加わる。
+    pub struct Transactional<U> {
+        inner: U,
+    }
+
+    impl<U> UseCase for Transactional<U>
+    where
+        U: Interactor,
+        U::World: TransactionComponent,
+        U::Error: From<Error>,
+    {
+        type Input = U::Input;
+        type Output = U::Output;
+        type Error = U::Error;
+        fn execute(&mut self, input: U::Input) -> Result<U::Output, U::Error> {
+            if self.inner.world().in_transaction() {
+                return self.inner.execute(input);
+            }
+            self.inner.world().begin().map_err(U::Error::from)?;
+            // 途中でpanicしても、変更を戻して他のスレッドのトランザクションを待たせたままにしない
+            match panic::catch_unwind(AssertUnwindSafe(|| self.inner.execute(input))) {
+                Ok(Ok(output)) => {
+                    self.inner.world().commit();
+                    Ok(output)
+                }
+                Ok(Err(e)) => {
+                    // 戻す処理自体の失敗より、元のエラーを優先して返す
+                    let _ = self.inner.world().rollback();
+                    Err(e)
+                }
+                Err(panicked) => {
+                    let _ = self.inner.world().rollback();
+                    panic::resume_unwind(panicked)
+                }
+            }
+        }
+    }
+
+    impl<U> Interactor for Transactional<U>
+    where
+        U: Interactor,
+        U::World: TransactionComponent,
+        U::Error: From<Error>,
+    {
+        type World = U::World;
+        const NAME: &'static str = U::NAME;
+        fn world(&self) -> &U::World {
+            self.inner.world()
+        }
+    }
+
+    pub mod dto {
+        //! ユースケースが外に返す値(DTO)。
+        //! Entityをそのまま返すとEntityのフィールドを変えた時にpresenterやAPIのレスポンスまで壊れるので、
+        //! UseCaseのOutputにはEntityではなくここの型を使い、Entityからの詰め替えもここにまとめる。
+        //! IDや値オブジェクトは文字列にし、トークンのハッシュ等の外に出さない値は持たない。
+
+        use chrono::prelude::*;
+        use entity::address::Address;
+        use entity::api_token::{ApiToken, Scope};
+        use entity::group::Group;
+        use entity::invitation::Invitation;
+        use entity::profile::Profile;
+        use entity::session::Session;
+        use entity::user::{Role, User, UserEvent, UserStatus};
+        use utoipa::ToSchema;
+
+        fn role_name(role: Role) -> String {
+            format!("{:?}", role).to_lowercase()
+        }
+
+        fn status_name(status: UserStatus) -> String {
+            format!("{:?}", status).to_lowercase()
+        }
+
+        /// HTTPで受け取る名前と同じ。`read_users` のようなスネークケース
+        pub fn scope_name(scope: Scope) -> &'static str {
+            match scope {
+                Scope::ReadUsers => "read_users",
+                Scope::WriteUsers => "write_users",
+                Scope::Admin => "admin",
+            }
+        }
+
+        /// 1人分の詳しい情報
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+        pub struct UserDto {
+            pub id: String,
+            pub name: String,
+            pub email: String,
+            pub role: String,
+            pub status: String,
+            pub address: Option<AddressDto>,
+            pub phone_number: Option<String>,
+            pub create_time: DateTime<Utc>,
+            pub update_time: DateTime<Utc>,
+            /// 更新する時に楽観的排他制御に使う
+            pub version: u64,
+        }
+
+        impl From<&User> for UserDto {
+            fn from(user: &User) -> UserDto {
+                UserDto {
+                    id: user.id.as_uuid().to_string(),
+                    name: user.name.to_string(),
+                    email: user.email.to_string(),
+                    role: role_name(user.role),
+                    status: status_name(user.status),
+                    address: user.address.as_ref().map(AddressDto::from),
+                    phone_number: user.phone_number.as_ref().map(ToString::to_string),
+                    create_time: user.create_time,
+                    update_time: user.update_time,
+                    version: user.version,
+                }
+            }
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+        pub struct AddressDto {
+            pub country: String,
+            pub region: String,
+            pub postal_code: String,
+        }
+
+        impl From<&Address> for AddressDto {
+            fn from(address: &Address) -> AddressDto {
+                AddressDto {
+                    country: address.country().as_str().to_string(),
+                    region: address.region().to_string(),
+                    postal_code: address.postal_code().as_str().to_string(),
+                }
+            }
+        }
+
+        /// 一覧や検索結果に出す1人分の情報
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+        pub struct UserSummaryDto {
+            pub id: String,
+            pub name: String,
+            pub email: String,
+            pub role: String,
+            pub status: String,
+            pub create_time: DateTime<Utc>,
+        }
+
+        impl From<&User> for UserSummaryDto {
+            fn from(user: &User) -> UserSummaryDto {
+                UserSummaryDto {
+                    id: user.id.as_uuid().to_string(),
+                    name: user.name.to_string(),
+                    email: user.email.to_string(),
+                    role: role_name(user.role),
+                    status: status_name(user.status),
+                    create_time: user.create_time,
+                }
+            }
+        }
+
+        /// ログインして作られたセッション。`id` をCookie等に入れて使う。
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+        pub struct SessionDto {
+            pub id: String,
+            pub user_id: String,
+            pub create_time: DateTime<Utc>,
+            pub expires_at: DateTime<Utc>,
+        }
+
+        impl From<&Session> for SessionDto {
+            fn from(session: &Session) -> SessionDto {
+                SessionDto {
+                    id: session.id.as_uuid().to_string(),
+                    user_id: session.user_id.as_uuid().to_string(),
+                    create_time: session.create_time,
+                    expires_at: session.expires_at,
+                }
+            }
+        }
+
+        /// 発行したAPIトークン。`token` は保存されていないので、ここでしか見られない。
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+        pub struct IssuedApiTokenDto {
+            pub id: String,
+            /// `Authorization: Bearer` に入れる平文のトークン
+            pub token: String,
+            pub scopes: Vec<String>,
+            pub expires_at: Option<DateTime<Utc>>,
+        }
+
+        impl IssuedApiTokenDto {
+            pub fn new(token: &ApiToken, plain: String) -> IssuedApiTokenDto {
+                IssuedApiTokenDto {
+                    id: token.id.as_uuid().to_string(),
+                    token: plain,
+                    scopes: token.scopes.iter().map(|&scope| scope_name(scope).to_string()).collect(),
+                    expires_at: token.expires_at,
+                }
+            }
+        }
+
+        /// 送った招待。トークンはメールでしか渡さないので持たない。
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+        pub struct InvitationDto {
+            pub id: String,
+            pub email: String,
+            pub role: String,
+            pub invited_by: String,
+            pub create_time: DateTime<Utc>,
+            pub expires_at: DateTime<Utc>,
+        }
+
+        impl From<&Invitation> for InvitationDto {
+            fn from(invitation: &Invitation) -> InvitationDto {
+                InvitationDto {
+                    id: invitation.id.as_uuid().to_string(),
+                    email: invitation.email.to_string(),
+                    role: role_name(invitation.role),
+                    invited_by: invitation.invited_by.as_uuid().to_string(),
+                    create_time: invitation.create_time,
+                    expires_at: invitation.expires_at,
+                }
+            }
+        }
+
+        /// グループと、メンバーのユーザーのID
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+        pub struct GroupDto {
+            pub name: String,
+            pub members: Vec<String>,
+            pub create_time: DateTime<Utc>,
+        }
+
+        impl From<&Group> for GroupDto {
+            fn from(group: &Group) -> GroupDto {
+                GroupDto {
+                    name: group.name.to_string(),
+                    members: group.members.iter().map(|id| id.as_uuid().to_string()).collect(),
+                    create_time: group.create_time,
+                }
+            }
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+        pub struct ProfileDto {
+            pub user_id: String,
+            pub bio: String,
+            pub avatar_url: Option<String>,
+            pub locale: String,
+            pub signup_country: Option<String>,
+            pub signup_city: Option<String>,
+            pub update_time: DateTime<Utc>,
+        }
+
+        impl From<&Profile> for ProfileDto {
+            fn from(profile: &Profile) -> ProfileDto {
+                ProfileDto {
+                    user_id: profile.user_id.as_uuid().to_string(),
+                    bio: profile.bio.clone(),
+                    avatar_url: profile.avatar_url.clone(),
+                    locale: profile.locale.clone(),
+                    signup_country: profile.signup_country.clone(),
+                    signup_city: profile.signup_city.clone(),
+                    update_time: profile.update_time,
+                }
+            }
+        }
+
+        /// ユーザーに起きた変更の知らせ。キューやWebSocket等、外へイベントを流す時に使う。
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+        pub struct UserEventDto {
+            pub user_id: String,
+            /// `UserEvent::kind` の短い名前
+            pub event: String,
+            pub message: String,
+        }
+
+        impl UserEventDto {
+            pub fn new(user: &User, event: UserEvent) -> UserEventDto {
+                UserEventDto {
+                    user_id: user.id.as_uuid().to_string(),
+                    event: event.kind().to_string(),
+                    message: event.to_string(),
+                }
+            }
+        }
+    }
+
+    pub mod maintenance {
+        //! 定期実行するジョブ。いつ実行するかはSchedulerComponentが決める。
+
+        use chrono::Duration;
+        use component::scheduler::{HaveSchedulerComponent, Schedule, SchedulerComponent};
+        use component::time::{HaveTimeComponent, TimeComponent};
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use component::webhook::{HaveWebhookComponent, WebhookComponent};
+        use entity::user::UserStatus;
+        use repository::DomainError;
+        use repository::sessions::{HaveSessionRepository, SessionRepository};
+        use repository::users::{HaveUserCommands, HaveUserQueries, UserCommands, UserQueries};
+
+        /// 期限切れのセッションを消す
+        pub const PURGE_EXPIRED_SESSIONS: &str = "purge_expired_sessions";
+        /// 長い間更新されていないユーザーを無効化する
+        pub const ARCHIVE_INACTIVE_USERS: &str = "archive_inactive_users";
+        /// 送れなかったWebhookをもう一度送る
+        pub const REDELIVER_WEBHOOKS: &str = "redeliver_webhooks";
+
+        /// これだけの間更新されていないユーザーを無効化する
+        pub fn inactive_period() -> Duration {
+            Duration::days(365)
+        }
+
+        pub trait Maintenance:
+            HaveSessionRepository
+            + HaveUserCommands
+            + HaveUserQueries
+            + HaveTimeComponent
+            + HaveSchedulerComponent
+            + HaveTracingComponent
+            + HaveWebhookComponent
+        {
+            /// ジョブを登録する。起動時に1回呼ぶ。
+            fn schedule_maintenance(&self) -> Result<(), DomainError> {
+                let scheduler = self.scheduler_component();
+                scheduler.register(PURGE_EXPIRED_SESSIONS, "*/10 * * * *".parse::<Schedule>()?);
+                scheduler.register(ARCHIVE_INACTIVE_USERS, "0 3 * * *".parse::<Schedule>()?);
+                scheduler.register(REDELIVER_WEBHOOKS, "*/15 * * * *".parse::<Schedule>()?);
+                Ok(())
+            }
+
+            /// 実行時刻が来たジョブを実行して、実行したジョブの名前を返す。
+            /// 1つが失敗しても残りは実行し、最初のエラーを返す。
+            fn run_due_jobs(&self) -> Result<Vec<String>, DomainError> {
+                let jobs = self.scheduler_component().due_jobs();
+                let mut first_error = None;
+                for job in &jobs {
+                    let _span = self.tracing_component().start_span("usecase.run_job", &[("job", job)]);
+                    let result = match job.as_str() {
+                        PURGE_EXPIRED_SESSIONS => {
+                            self.session_repository().purge_expired().map(|_| ()).map_err(From::from)
+                        }
+                        ARCHIVE_INACTIVE_USERS => self.archive_inactive_users(inactive_period()).map(|_| ()),
+                        REDELIVER_WEBHOOKS => {
+                            self.webhook_component().redeliver();
+                            // 送れなかった分はデッドレターに残るので、次の回にまた送る
+                            match self.webhook_component().dead_letters().len() {
+                                0 => Ok(()),
+                                left => Err(DomainError::from(format_err!("{} webhooks are still undelivered", left))),
+                            }
+                        }
+                        _ => Err(DomainError::rejected(format!("unknown job: {}", job))),
+                    };
+                    if let Err(e) = result {
+                        first_error.get_or_insert(e);
+                    }
+                }
+                match first_error {
+                    Some(e) => Err(e),
+                    None => Ok(jobs),
+                }
+            }
+
+            /// `inactive_for` 以上更新されていないActiveなユーザーを無効化して、無効化した数を返す
+            fn archive_inactive_users(&self, inactive_for: Duration) -> Result<usize, DomainError> {
+                let threshold = self.time_component().now() - inactive_for;
+                let inactive: Vec<_> = self
+                    .user_queries()
+                    .list()?
+                    .into_iter()
+                    .filter(|user| user.status == UserStatus::Active && user.update_time <= threshold)
+                    .map(|user| user.id)
+                    .collect();
+                for id in &inactive {
+                    self.user_commands().deactivate(id.clone())?;
+                }
+                Ok(inactive.len())
+            }
+        }
+
+        impl<T> Maintenance for T where
+            T: HaveSessionRepository
+                + HaveUserCommands
+                + HaveUserQueries
+                + HaveTimeComponent
+                + HaveSchedulerComponent
+                + HaveTracingComponent
+                + HaveWebhookComponent
+        {
+        }
+    }
+
+    pub mod account_mail {
+        use component::mail::{EmailSenderComponent, HaveEmailSenderComponent, Mail};
+        use component::template::{self, HaveTemplateComponent};
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use entity::user::{Email, User};
+        use repository::DomainError;
+
+        /// アカウントに関するメールを、テンプレートから作って本人に送る
+        pub trait AccountMail: HaveTemplateComponent + HaveEmailSenderComponent + HaveTracingComponent {
+            fn send_welcome(&self, user: &User) -> Result<(), DomainError> {
+                let _span = self.tracing_component().start_span("usecase.send_welcome", &[]);
+                let context = json!({ "name": user.name.as_str() });
+                let mail = Mail::render(self.template_component(), template::WELCOME, user.email.clone(), &context)?;
+                Ok(self.email_sender_component().send(&mail)?)
+            }
+
+            /// `reset_url` はパスワードを再設定する画面のURL
+            fn send_password_reset(&self, user: &User, reset_url: &str) -> Result<(), DomainError> {
+                let _span = self.tracing_component().start_span("usecase.send_password_reset", &[]);
+                let context = json!({ "name": user.name.as_str(), "reset_url": reset_url });
+                let mail = Mail::render(
+                    self.template_component(),
+                    template::PASSWORD_RESET,
+                    user.email.clone(),
+                    &context,
+                )?;
+                Ok(self.email_sender_component().send(&mail)?)
+            }
+
+            /// まだユーザーがいないので、宛先は招待したメールアドレスにする
+            fn send_invitation(&self, to: &Email, inviter: &User, accept_url: &str) -> Result<(), DomainError> {
+                let _span = self.tracing_component().start_span("usecase.send_invitation", &[]);
+                let context = json!({ "inviter": inviter.name.as_str(), "accept_url": accept_url });
+                let mail = Mail::render(self.template_component(), template::INVITATION, to.clone(), &context)?;
+                Ok(self.email_sender_component().send(&mail)?)
+            }
+        }
+
+        impl<T: HaveTemplateComponent + HaveEmailSenderComponent + HaveTracingComponent> AccountMail for T {}
+    }
+
+    pub mod jobs {
+        //! リクエストの外で後から行うジョブの積み方と実行の仕方。
+        //! 失敗したジョブは間を空けてやり直し、MAX_ATTEMPTS回失敗したらもう実行しない。
+
+        use chrono::Duration;
+        use component::id::{HaveIdGeneratorComponent, IdGeneratorComponent};
+        use component::jobs::{HaveJobQueueComponent, JobQueueComponent};
+        use component::log::{HaveLoggingComponent, LoggingComponent};
+        use component::time::{HaveTimeComponent, TimeComponent};
+        use component::trace::TracingComponent;
+        use entity::job::{Job, JobId, JobKind};
+        use repository::DomainError;
+        use repository::users::{HaveUserQueries, UserQueries};
+        use usecase::account_mail::AccountMail;
+        use usecase::search_users::SearchUsers;
+
+        /// この回数失敗したジョブはもう実行しない
+        pub const MAX_ATTEMPTS: u32 = 5;
+
+        /// `attempts` 回失敗したジョブをやり直すまでの間。1回目は30秒後で、失敗する度に倍にする。
+        pub fn backoff(attempts: u32) -> Duration {
+            Duration::seconds(30 << attempts.saturating_sub(1).min(10))
+        }
+
+        /// 1回の `work_jobs` で実行したジョブの数
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+        pub struct JobReport {
+            pub completed: usize,
+            pub retried: usize,
+            pub poisoned: usize,
+        }
+
+        /// ジョブを積む。積むだけなので、実行に要るものは求めない。
+        pub trait EnqueueJob: HaveJobQueueComponent + HaveIdGeneratorComponent + HaveTimeComponent {
+            fn enqueue_job(&self, kind: JobKind) -> Result<JobId, DomainError> {
+                let id = JobId::new(self.id_generator_component().generate());
+                let job = Job::new(id.clone(), kind, self.time_component().now());
+                self.job_queue_component().enqueue(job)?;
+                Ok(id)
+            }
+        }
+
+        impl<T: HaveJobQueueComponent + HaveIdGeneratorComponent + HaveTimeComponent> EnqueueJob for T {}
+
+        /// 積まれたジョブを実行する。ワーカーが定期的に呼ぶ。
+        pub trait WorkJobs: EnqueueJob + HaveUserQueries + AccountMail + SearchUsers + HaveLoggingComponent {
+            /// 今実行できるジョブを無くなるまで実行する。
+            /// やり直すジョブは実行する時刻が先になるので、1回の呼び出しで同じジョブを2度実行する事は無い。
+            fn work_jobs(&self) -> Result<JobReport, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.work_jobs", &[]);
+                let now = self.time_component().now();
+                let queue = self.job_queue_component();
+                let mut report = JobReport::default();
+                while let Some(job) = queue.claim(now)? {
+                    match self.run_job(&job.kind) {
+                        Ok(()) => {
+                            queue.complete(&job.id)?;
+                            report.completed += 1;
+                        }
+                        Err(e) if job.attempts + 1 >= MAX_ATTEMPTS => {
+                            self.logging_component()
+                                .warn(&format!("job {:?} failed {} times, giving up: {}", job.id, MAX_ATTEMPTS, e));
+                            queue.poison(&job.id, &e.to_string())?;
+                            report.poisoned += 1;
+                        }
+                        Err(e) => {
+                            queue.retry(&job.id, &e.to_string(), now + backoff(job.attempts + 1))?;
+                            report.retried += 1;
+                        }
+                    }
+                }
+                Ok(report)
+            }
+
+            fn run_job(&self, kind: &JobKind) -> Result<(), DomainError> {
+                match *kind {
+                    JobKind::SendWelcomeMail { ref user_id } => match self.user_queries().get(user_id.clone()) {
+                        Ok(user) => self.send_welcome(&user),
+                        // 送る前に退会したユーザーには送らない
+                        Err(DomainError::NotFound { .. }) => Ok(()),
+                        Err(e) => Err(e),
+                    },
+                    JobKind::RebuildSearchIndex => self.reindex_users(),
+                }
+            }
+        }
+
+        impl<T> WorkJobs for T where
+            T: EnqueueJob + HaveUserQueries + AccountMail + SearchUsers + HaveLoggingComponent
+        {
+        }
+    }
+
+    pub mod register_user {
+        use component::log::{HaveLoggingComponent, LoggingComponent};
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use entity::job::JobKind;
+        use entity::user::{Email, Name, User};
+        use repository::DomainError;
+        use repository::users::{HaveUserCommands, HaveUserQueries, UserCommands, UserQueries};
+        use service::unique_email::{HaveUniqueEmailService, UniqueEmailService};
+        use std::error;
+        use std::fmt;
+        use usecase::dto::UserDto;
+        use usecase::jobs::EnqueueJob;
+        use usecase::{Interactor, UseCase};
+        use utoipa::ToSchema;
+
+        /// 他のユーザーが既に使っている名前
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct NameTaken {
+            pub name: Name,
+        }
+
+        impl fmt::Display for NameTaken {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "name already taken: {:?}", self.name)
+            }
+        }
+
+        impl error::Error for NameTaken {}
+
+        impl From<NameTaken> for DomainError {
+            fn from(e: NameTaken) -> DomainError {
+                DomainError::AlreadyExists {
+                    field: "name",
+                    value: format!("{:?}", e.name),
+                }
+            }
+        }
+
+        /// 画面等から受け取った名前・メールアドレスでユーザーを登録し、歓迎のメールを送るジョブを積む。
+        /// メールはワーカーが後から送る。ジョブを積めなくても登録は取り消さず、ログに残すだけにする。
+        pub trait RegisterUser:
+            HaveUserCommands
+            + HaveUserQueries
+            + HaveUniqueEmailService
+            + EnqueueJob
+            + HaveLoggingComponent
+            + HaveTracingComponent
+        {
+            fn register_user(&self, name: &str, email: &str) -> Result<User, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.register_user", &[("name", name)]);
+                let name = Name::new(name)?;
+                let email = Email::parse(email)?;
+                // 同時に登録された場合はストレージの一意制約で弾かれる
+                if self.user_queries().get_by_name(&name).is_ok() {
+                    return Err(NameTaken { name }.into());
+                }
+                self.unique_email_service().ensure_email_available(&email, None)?;
+                let user = self.user_commands().create(name, email)?;
+                let job = JobKind::SendWelcomeMail { user_id: user.id.clone() };
+                if let Err(e) = self.enqueue_job(job) {
+                    self.logging_component().warn(&format!("welcome mail to {:?} not queued: {}", user.id, e));
+                }
+                Ok(user)
+            }
+        }
+
+        impl<T> RegisterUser for T where
+            T: HaveUserCommands
+                + HaveUserQueries
+                + HaveUniqueEmailService
+                + EnqueueJob
+                + HaveLoggingComponent
+                + HaveTracingComponent
+        {
+        }
+
+        /// 登録するユーザー。画面等から受け取ったままの文字列を入れる。
+        #[derive(Debug, Clone, PartialEq, Eq, Deserialize, ToSchema)]
+        pub struct NewUser {
+            pub name: String,
+            pub email: String,
+        }
+
+        /// RegisterUserをUseCaseとして実行する
+        pub struct RegisterUserInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: RegisterUser> RegisterUserInteractor<'a, W> {
+            pub fn new(world: &'a W) -> RegisterUserInteractor<'a, W> {
+                RegisterUserInteractor { world }
+            }
+        }
+
+        impl<'a, W: RegisterUser> UseCase for RegisterUserInteractor<'a, W> {
+            type Input = NewUser;
+            type Output = UserDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: NewUser) -> Result<UserDto, DomainError> {
+                self.world.register_user(&input.name, &input.email).map(|user| UserDto::from(&user))
+            }
+        }
+
+        impl<'a, W: RegisterUser> Interactor for RegisterUserInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "register_user";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod authenticate_user {
+        use chrono::Duration;
+        use component::rate_limit::{HaveRateLimiterComponent, RateLimit, RateLimiterComponent};
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use entity::credentials::PlainPassword;
+        use entity::session::Session;
+        use entity::user::{Name, UserStatus};
+        use repository::credentials::{CredentialRepository, HaveCredentialRepository};
+        use repository::DomainError;
+        use repository::sessions::{HaveSessionRepository, SessionRepository};
+        use repository::users::{HaveUserQueries, UserQueries};
+        use std::error;
+        use std::fmt;
+        use usecase::dto::SessionDto;
+        use usecase::{Interactor, UseCase};
+
+        /// ログインしてから再度ログインが必要になるまでの時間(時間)
+        pub const SESSION_TTL_HOURS: i64 = 24;
+
+        /// ログインできなかった理由
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum AuthenticationError {
+            /// 名前かパスワードが違う。どちらが違うかは教えない。
+            InvalidCredentials,
+            /// パスワードは合っているが、ユーザーがActiveでない
+            Inactive(UserStatus),
+            /// 続けて試行しすぎた
+            RateLimited { retry_after: Duration },
+        }
+
+        impl fmt::Display for AuthenticationError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match *self {
+                    AuthenticationError::InvalidCredentials => write!(f, "invalid name or password"),
+                    AuthenticationError::Inactive(status) => write!(f, "user is {:?}", status),
+                    AuthenticationError::RateLimited { retry_after } => {
+                        write!(f, "too many attempts, retry after {}s", retry_after.num_seconds())
+                    }
+                }
+            }
+        }
+
+        impl error::Error for AuthenticationError {}
+
+        impl From<AuthenticationError> for DomainError {
+            fn from(e: AuthenticationError) -> DomainError {
+                DomainError::rejected(e)
+            }
+        }
+
+        /// 名前とパスワードでログインし、新しいセッションを返す。
+        /// 総当たりを防ぐため、試行の回数は名前毎に制限する。
+        pub trait AuthenticateUser:
+            HaveUserQueries
+            + HaveCredentialRepository
+            + HaveSessionRepository
+            + HaveRateLimiterComponent
+            + HaveTracingComponent
+        {
+            fn authenticate_user(&self, name: &str, password: &str) -> Result<Session, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.authenticate_user", &[("name", name)]);
+                let key = format!("login:{}", name.trim().to_lowercase());
+                if let RateLimit::Limited { retry_after } = self.rate_limiter_component().check_and_consume(&key) {
+                    return Err(AuthenticationError::RateLimited { retry_after }.into());
+                }
+                let user = match Name::new(name).map(|name| self.user_queries().get_by_name(&name)) {
+                    Ok(Ok(user)) => user,
+                    _ => return Err(AuthenticationError::InvalidCredentials.into()),
+                };
+                // パスワードが設定されていないユーザーも、パスワード違いと区別しない
+                if !self.credential_repository().verify_password(user.id.clone(), password).unwrap_or(false) {
+                    return Err(AuthenticationError::InvalidCredentials.into());
+                }
+                if !user.is_active() {
+                    return Err(AuthenticationError::Inactive(user.status).into());
+                }
+                Ok(self.session_repository().create_session(user.id, Duration::hours(SESSION_TTL_HOURS))?)
+            }
+        }
+
+        impl<T> AuthenticateUser for T where
+            T: HaveUserQueries
+                + HaveCredentialRepository
+                + HaveSessionRepository
+                + HaveRateLimiterComponent
+                + HaveTracingComponent
+        {
+        }
+
+        /// ログイン画面から受け取った名前とパスワード
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct LoginRequest {
+            pub name: String,
+            pub password: PlainPassword,
+        }
+
+        /// AuthenticateUserをUseCaseとして実行する。失敗の理由はエラーからAuthenticationErrorを取り出して見る。
+        pub struct AuthenticateUserInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: AuthenticateUser> AuthenticateUserInteractor<'a, W> {
+            pub fn new(world: &'a W) -> AuthenticateUserInteractor<'a, W> {
+                AuthenticateUserInteractor { world }
+            }
+        }
+
+        impl<'a, W: AuthenticateUser> UseCase for AuthenticateUserInteractor<'a, W> {
+            type Input = LoginRequest;
+            type Output = SessionDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: LoginRequest) -> Result<SessionDto, DomainError> {
+                let session = self.world.authenticate_user(&input.name, input.password.expose())?;
+                Ok(SessionDto::from(&session))
+            }
+        }
+
+        impl<'a, W: AuthenticateUser> Interactor for AuthenticateUserInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "authenticate_user";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod change_password {
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use component::transaction::TransactionComponent;
+        use component::validation::{HavePasswordPolicyComponent, ValidationComponent};
+        use entity::credentials::PlainPassword;
+        use entity::session::SessionId;
+        use repository::credentials::{CredentialRepository, HaveCredentialRepository};
+        use repository::DomainError;
+        use repository::sessions::{HaveSessionRepository, SessionRepository};
+        use usecase::authenticate_user::AuthenticationError;
+        use usecase::{Interactor, UseCase};
+
+        /// ログイン中のユーザーのパスワードを変更する。
+        /// 今のパスワードを確かめてから変更し、このセッション以外のセッションは全て失効させる。
+        pub trait ChangePassword:
+            HaveCredentialRepository
+            + HaveSessionRepository
+            + HavePasswordPolicyComponent
+            + HaveTracingComponent
+            + TransactionComponent
+        {
+            /// 失効させたセッションの数を返す
+            fn change_password(&self, session_id: SessionId, old: &str, new: &str) -> Result<usize, DomainError>
+            where
+                Self: Sized,
+            {
+                let _span = self.tracing_component().start_span("usecase.change_password", &[]);
+                let session = self.session_repository().validate_session(session_id)?;
+                if !self.credential_repository().verify_password(session.user_id.clone(), old)? {
+                    return Err(AuthenticationError::InvalidCredentials.into());
+                }
+                let new = PlainPassword::new(new);
+                self.password_policy_component().validate(&new)?;
+                let revoked = self.transaction(|world| {
+                    world.credential_repository().set_password(session.user_id.clone(), new.expose())?;
+                    world
+                        .session_repository()
+                        .revoke_sessions_where(|other| other.user_id == session.user_id && other.id != session.id)
+                })?;
+                Ok(revoked)
+            }
+        }
+
+        impl<T> ChangePassword for T where
+            T: HaveCredentialRepository
+                + HaveSessionRepository
+                + HavePasswordPolicyComponent
+                + HaveTracingComponent
+                + TransactionComponent
+        {
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct PasswordChange {
+            pub session_id: SessionId,
+            pub old: PlainPassword,
+            pub new: PlainPassword,
+        }
+
+        /// ChangePasswordをUseCaseとして実行する。出力は失効させたセッションの数。
+        pub struct ChangePasswordInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: ChangePassword> ChangePasswordInteractor<'a, W> {
+            pub fn new(world: &'a W) -> ChangePasswordInteractor<'a, W> {
+                ChangePasswordInteractor { world }
+            }
+        }
+
+        impl<'a, W: ChangePassword> UseCase for ChangePasswordInteractor<'a, W> {
+            type Input = PasswordChange;
+            type Output = usize;
+            type Error = DomainError;
+            fn execute(&mut self, input: PasswordChange) -> Result<usize, DomainError> {
+                self.world.change_password(input.session_id, input.old.expose(), input.new.expose())
+            }
+        }
+
+        impl<'a, W: ChangePassword> Interactor for ChangePasswordInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "change_password";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod issue_api_token {
+        use chrono::Duration;
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use entity::api_token::Scope;
+        use entity::user::UserId;
+        use repository::DomainError;
+        use repository::api_tokens::{ApiTokenRepository, HaveApiTokenRepository};
+        use repository::users::{HaveUserQueries, UserQueries};
+        use usecase::authenticate_user::AuthenticationError;
+        use usecase::dto::IssuedApiTokenDto;
+        use usecase::{Interactor, UseCase};
+
+        /// 有効なユーザーに、そのユーザーとして呼べるAPIトークンを発行する。
+        /// 平文のトークンは発行した時の出力でしか手に入らない。
+        pub trait IssueApiToken: HaveApiTokenRepository + HaveUserQueries + HaveTracingComponent {
+            fn issue_api_token(&self, request: ApiTokenRequest) -> Result<IssuedApiTokenDto, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.issue_api_token", &[]);
+                if request.scopes.is_empty() {
+                    return Err(DomainError::rejected("at least one scope is required"));
+                }
+                let owner = self.user_queries().get(request.owner)?;
+                if !owner.is_active() {
+                    return Err(AuthenticationError::Inactive(owner.status).into());
+                }
+                let (token, plain) = self.api_token_repository().issue_token(owner.id, &request.scopes, request.ttl)?;
+                Ok(IssuedApiTokenDto::new(&token, plain))
+            }
+        }
+
+        impl<T: HaveApiTokenRepository + HaveUserQueries + HaveTracingComponent> IssueApiToken for T {}
+
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct ApiTokenRequest {
+            pub owner: UserId,
+            pub scopes: Vec<Scope>,
+            /// Noneなら有効期限なし
+            pub ttl: Option<Duration>,
+        }
+
+        pub struct IssueApiTokenInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: IssueApiToken> IssueApiTokenInteractor<'a, W> {
+            pub fn new(world: &'a W) -> IssueApiTokenInteractor<'a, W> {
+                IssueApiTokenInteractor { world }
+            }
+        }
+
+        impl<'a, W: IssueApiToken> UseCase for IssueApiTokenInteractor<'a, W> {
+            type Input = ApiTokenRequest;
+            type Output = IssuedApiTokenDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: ApiTokenRequest) -> Result<IssuedApiTokenDto, DomainError> {
+                self.world.issue_api_token(input)
+            }
+        }
+
+        impl<'a, W: IssueApiToken> Interactor for IssueApiTokenInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "issue_api_token";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod invite_user {
+        //! 管理者がメールアドレス宛に招待を送り、招待された人は招待用のURLから名前とパスワードを決めて登録する。
+
+        use chrono::Duration;
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use component::transaction::TransactionComponent;
+        use component::validation::{HavePasswordPolicyComponent, ValidationComponent};
+        use entity::credentials::PlainPassword;
+        use entity::invitation::Invitation;
+        use entity::user::{Email, Name, Permission, Role, User, UserId};
+        use repository::DomainError;
+        use repository::Repository;
+        use repository::credentials::{CredentialRepository, HaveCredentialRepository};
+        use repository::invitations::{HaveInvitationRepository, InvitationRepository};
+        use repository::users::{HaveUserCommands, HaveUserQueries, UserCommands, UserQueries};
+        use service::unique_email::{HaveUniqueEmailService, UniqueEmailService};
+        use usecase::account_mail::AccountMail;
+        use usecase::dto::{InvitationDto, UserDto};
+        use usecase::{Interactor, PermissionDenied, UseCase};
+
+        /// 招待の有効期間(日)
+        pub const INVITATION_TTL_DAYS: i64 = 7;
+
+        /// 招待を作り、招待用のURLをメールで送る。招待できるのはユーザーを管理できる人だけ。
+        pub trait InviteUser: HaveUserQueries + HaveUniqueEmailService + HaveInvitationRepository + AccountMail {
+            /// `accept_url` は招待を受け入れる画面のURLで、`?token=...` を付けて送る
+            fn invite_user(
+                &self,
+                inviter: UserId,
+                email: &str,
+                role: Role,
+                accept_url: &str,
+            ) -> Result<Invitation, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.invite_user", &[]);
+                let inviter = self.user_queries().get(inviter)?;
+                if !inviter.can(Permission::ManageUsers) {
+                    let denied = PermissionDenied {
+                        actor: inviter.id,
+                        permission: Permission::ManageUsers,
+                        use_case: "invite_user",
+                    };
+                    return Err(denied.into());
+                }
+                let email = Email::parse(email)?;
+                self.unique_email_service().ensure_email_available(&email, None)?;
+                let ttl = Duration::days(INVITATION_TTL_DAYS);
+                let (invitation, token) =
+                    self.invitation_repository().invite(email.clone(), role, inviter.id.clone(), ttl)?;
+                self.send_invitation(&email, &inviter, &format!("{}?token={}", accept_url, token))?;
+                Ok(invitation)
+            }
+        }
+
+        impl<T> InviteUser for T where
+            T: HaveUserQueries + HaveUniqueEmailService + HaveInvitationRepository + AccountMail
+        {
+        }
+
+        /// 招待を受け入れてユーザーを作る。
+        /// ユーザーの作成・役割の設定・パスワードの設定はまとめて行い、全て終わってから招待を消す。
+        pub trait AcceptInvitation:
+            HaveInvitationRepository
+            + HaveUserCommands
+            + HaveCredentialRepository
+            + HavePasswordPolicyComponent
+            + HaveTracingComponent
+            + TransactionComponent
+        {
+            fn accept_invitation(&self, token: &str, name: &str, password: &str) -> Result<User, DomainError>
+            where
+                Self: Sized,
+            {
+                let _span = self.tracing_component().start_span("usecase.accept_invitation", &[("name", name)]);
+                // 誤ったトークンも期限切れのトークンも、区別せずに断る
+                let invitation = self
+                    .invitation_repository()
+                    .verify_invitation(token)
+                    .map_err(|_| DomainError::rejected("invalid or expired invitation"))?;
+                let name = Name::new(name)?;
+                let password = PlainPassword::new(password);
+                self.password_policy_component().validate(&password)?;
+                let user = self.transaction(|world| -> Result<User, DomainError> {
+                    let mut user = world.user_commands().create(name, invitation.email.clone())?;
+                    if user.role != invitation.role {
+                        user = world.user_commands().change_role(user.id, invitation.role)?;
+                    }
+                    world.credential_repository().set_password(user.id.clone(), password.expose())?;
+                    Ok(user)
+                })?;
+                self.invitation_repository().delete(invitation.id)?;
+                Ok(user)
+            }
+        }
+
+        impl<T> AcceptInvitation for T where
+            T: HaveInvitationRepository
+                + HaveUserCommands
+                + HaveCredentialRepository
+                + HavePasswordPolicyComponent
+                + HaveTracingComponent
+                + TransactionComponent
+        {
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct NewInvitation {
+            pub inviter: UserId,
+            pub email: String,
+            pub role: Role,
+            pub accept_url: String,
+        }
+
+        /// 招待を受け入れる人が決めた名前とパスワード
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct InvitationAcceptance {
+            pub token: String,
+            pub name: String,
+            pub password: PlainPassword,
+        }
+
+        /// InviteUserをUseCaseとして実行する
+        pub struct InviteUserInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: InviteUser> InviteUserInteractor<'a, W> {
+            pub fn new(world: &'a W) -> InviteUserInteractor<'a, W> {
+                InviteUserInteractor { world }
+            }
+        }
+
+        impl<'a, W: InviteUser> UseCase for InviteUserInteractor<'a, W> {
+            type Input = NewInvitation;
+            type Output = InvitationDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: NewInvitation) -> Result<InvitationDto, DomainError> {
+                let invitation = self.world.invite_user(input.inviter, &input.email, input.role, &input.accept_url)?;
+                Ok(InvitationDto::from(&invitation))
+            }
+        }
+
+        impl<'a, W: InviteUser> Interactor for InviteUserInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "invite_user";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+
+        /// AcceptInvitationをUseCaseとして実行する
+        pub struct AcceptInvitationInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: AcceptInvitation> AcceptInvitationInteractor<'a, W> {
+            pub fn new(world: &'a W) -> AcceptInvitationInteractor<'a, W> {
+                AcceptInvitationInteractor { world }
+            }
+        }
+
+        impl<'a, W: AcceptInvitation> UseCase for AcceptInvitationInteractor<'a, W> {
+            type Input = InvitationAcceptance;
+            type Output = UserDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: InvitationAcceptance) -> Result<UserDto, DomainError> {
+                let user = self.world.accept_invitation(&input.token, &input.name, input.password.expose())?;
+                Ok(UserDto::from(&user))
+            }
+        }
+
+        impl<'a, W: AcceptInvitation> Interactor for AcceptInvitationInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "accept_invitation";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod password_reset {
+        //! パスワードを忘れたときの再設定。メールアドレス宛に再設定用のURLを送り、
+        //! URLに含めたトークンと新しいパスワードを受け取って設定し直す。
+
+        use chrono::Duration;
+        use component::log::{HaveLoggingComponent, Level, LoggingComponent};
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use component::transaction::TransactionComponent;
+        use component::validation::{HavePasswordPolicyComponent, ValidationComponent};
+        use entity::credentials::PlainPassword;
+        use entity::user::Email;
+        use repository::credentials::{CredentialRepository, HaveCredentialRepository};
+        use repository::DomainError;
+        use repository::password_resets::{HavePasswordResetRepository, PasswordResetRepository};
+        use repository::sessions::{HaveSessionRepository, SessionRepository};
+        use repository::users::{HaveUserQueries, UserQueries};
+        use usecase::account_mail::AccountMail;
+        use usecase::{Interactor, UseCase};
+
+        /// 再設定用のトークンの有効期間(分)
+        pub const PASSWORD_RESET_TTL_MINUTES: i64 = 60;
+
+        /// 再設定用のURLをメールで送る。
+        /// 登録されているメールアドレスかどうかを漏らさないため、見つからなくてもエラーにはしない。
+        pub trait RequestPasswordReset:
+            HaveUserQueries + HavePasswordResetRepository + AccountMail + HaveLoggingComponent
+        {
+            /// `reset_url` は再設定する画面のURLで、`?token=...` を付けて送る
+            fn request_password_reset(&self, email: &str, reset_url: &str) -> Result<(), DomainError> {
+                let _span = self.tracing_component().start_span("usecase.request_password_reset", &[]);
+                let email = Email::parse(email)?;
+                let user = match self.user_queries().get_by_email(&email) {
+                    Ok(user) => user,
+                    Err(_) => {
+                        self.logging_component().log(Level::Info, "password reset requested for unknown email");
+                        return Ok(());
+                    }
+                };
+                let ttl = Duration::minutes(PASSWORD_RESET_TTL_MINUTES);
+                let (_, token) = self.password_reset_repository().issue_reset(user.id.clone(), ttl)?;
+                self.send_password_reset(&user, &format!("{}?token={}", reset_url, token))
+            }
+        }
+
+        impl<T> RequestPasswordReset for T where
+            T: HaveUserQueries + HavePasswordResetRepository + AccountMail + HaveLoggingComponent
+        {
+        }
+
+        /// トークンを確かめて新しいパスワードを設定し、そのユーザーのセッションは全て失効させる
+        pub trait ConfirmPasswordReset:
+            HavePasswordResetRepository
+            + HaveCredentialRepository
+            + HaveSessionRepository
+            + HavePasswordPolicyComponent
+            + HaveTracingComponent
+            + TransactionComponent
+        {
+            /// 失効させたセッションの数を返す
+            fn confirm_password_reset(&self, token: &str, new: &str) -> Result<usize, DomainError>
+            where
+                Self: Sized,
+            {
+                let _span = self.tracing_component().start_span("usecase.confirm_password_reset", &[]);
+                // ポリシー違反でトークンを使い切らないよう、先に新しいパスワードを確かめる
+                let new = PlainPassword::new(new);
+                self.password_policy_component().validate(&new)?;
+                // 誤ったトークンも期限切れのトークンも、区別せずに断る
+                let user_id = self
+                    .password_reset_repository()
+                    .redeem_reset(token)
+                    .map_err(|_| DomainError::rejected("invalid or expired password reset token"))?;
+                let revoked = self.transaction(|world| {
+                    world.credential_repository().set_password(user_id.clone(), new.expose())?;
+                    world.session_repository().revoke_user_sessions(&user_id)
+                })?;
+                Ok(revoked)
+            }
+        }
+
+        impl<T> ConfirmPasswordReset for T where
+            T: HavePasswordResetRepository
+                + HaveCredentialRepository
+                + HaveSessionRepository
+                + HavePasswordPolicyComponent
+                + HaveTracingComponent
+                + TransactionComponent
+        {
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct PasswordResetRequest {
+            pub email: String,
+            pub reset_url: String,
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct PasswordResetConfirmation {
+            pub token: String,
+            pub new_password: PlainPassword,
+        }
+
+        /// RequestPasswordResetをUseCaseとして実行する
+        pub struct RequestPasswordResetInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: RequestPasswordReset> RequestPasswordResetInteractor<'a, W> {
+            pub fn new(world: &'a W) -> RequestPasswordResetInteractor<'a, W> {
+                RequestPasswordResetInteractor { world }
+            }
+        }
+
+        impl<'a, W: RequestPasswordReset> UseCase for RequestPasswordResetInteractor<'a, W> {
+            type Input = PasswordResetRequest;
+            type Output = ();
+            type Error = DomainError;
+            fn execute(&mut self, input: PasswordResetRequest) -> Result<(), DomainError> {
+                self.world.request_password_reset(&input.email, &input.reset_url)
+            }
+        }
+
+        impl<'a, W: RequestPasswordReset> Interactor for RequestPasswordResetInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "request_password_reset";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+
+        /// ConfirmPasswordResetをUseCaseとして実行する。出力は失効させたセッションの数。
+        pub struct ConfirmPasswordResetInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: ConfirmPasswordReset> ConfirmPasswordResetInteractor<'a, W> {
+            pub fn new(world: &'a W) -> ConfirmPasswordResetInteractor<'a, W> {
+                ConfirmPasswordResetInteractor { world }
+            }
+        }
+
+        impl<'a, W: ConfirmPasswordReset> UseCase for ConfirmPasswordResetInteractor<'a, W> {
+            type Input = PasswordResetConfirmation;
+            type Output = usize;
+            type Error = DomainError;
+            fn execute(&mut self, input: PasswordResetConfirmation) -> Result<usize, DomainError> {
+                self.world.confirm_password_reset(&input.token, input.new_password.expose())
+            }
+        }
+
+        impl<'a, W: ConfirmPasswordReset> Interactor for ConfirmPasswordResetInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "confirm_password_reset";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod delete_account {
+        //! 退会は2段階で行う。確認用のトークンを発行して本人に渡し、そのトークンで確認されてから無効化する。
+        //! トークンはユーザーID・有効期限・乱数に署名したもので、サーバー側には何も保存しない。
+
+        use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+        use base64::Engine;
+        use chrono::{Duration, TimeZone, Utc};
+        use component::crypto::{CryptoComponent, HaveCryptoComponent};
+        use component::random::{HaveRandomComponent, RandomComponent};
+        use component::time::{HaveTimeComponent, TimeComponent};
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use component::transaction::TransactionComponent;
+        use entity::user::{StatusError, User, UserId, UserStatus};
+        use repository::DomainError;
+        use repository::sessions::{HaveSessionRepository, SessionRepository};
+        use repository::users::{HaveUserCommands, HaveUserQueries, UserCommands, UserQueries};
+        use uuid::Uuid;
+        use usecase::dto::UserDto;
+        use usecase::{Interactor, UseCase};
+
+        /// 確認用のトークンの有効期間(分)
+        pub const CONFIRMATION_TTL_MINUTES: i64 = 30;
+
+        /// 同じ時刻に発行したトークンを区別するための乱数の長さ
+        const NONCE_LEN: usize = 16;
+
+        pub trait DeleteAccount:
+            HaveUserCommands
+            + HaveUserQueries
+            + HaveSessionRepository
+            + HaveRandomComponent
+            + HaveTimeComponent
+            + HaveCryptoComponent
+            + HaveTracingComponent
+            + TransactionComponent
+        {
+            /// 退会の確認用のトークンを発行する。トークンはメール等で本人にだけ渡す。
+            fn request_account_deletion(&self, id: UserId) -> Result<String, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.request_account_deletion", &[]);
+                let user = self.user_queries().get(id)?;
+                if user.status == UserStatus::Deactivated {
+                    return Err(StatusError::Already { status: user.status, id: user.id }.into());
+                }
+                let expires_at = self.time_component().now() + Duration::minutes(CONFIRMATION_TTL_MINUTES);
+                let payload = format!(
+                    "{}.{}.{}",
+                    user.id.as_uuid().simple(),
+                    expires_at.timestamp(),
+                    self.random_component().token(NONCE_LEN)
+                );
+                let signature = BASE64.encode(self.crypto_component().sign(payload.as_bytes()));
+                Ok(format!("{}.{}", payload, signature))
+            }
+
+            /// トークンを確かめてからユーザーを無効化し、そのユーザーのセッションを全て失効させる。
+            /// 途中で失敗した場合は、無効化も失効も取り消す。
+            fn confirm_account_deletion(&self, token: &str) -> Result<User, DomainError>
+            where
+                Self: Sized,
+            {
+                let _span = self.tracing_component().start_span("usecase.confirm_account_deletion", &[]);
+                let malformed = || DomainError::rejected("malformed confirmation token");
+                let (payload, signature) = match token.rsplit_once('.') {
+                    Some((payload, signature)) => (payload, BASE64.decode(signature).map_err(|_| malformed())?),
+                    None => return Err(malformed()),
+                };
+                if !self.crypto_component().verify(payload.as_bytes(), &signature) {
+                    return Err(DomainError::rejected("invalid confirmation token"));
+                }
+                let mut parts = payload.splitn(3, '.');
+                let (id, expires_at) = match (parts.next(), parts.next().and_then(|t| t.parse().ok())) {
+                    (Some(id), Some(expires_at)) => (Uuid::parse_str(id).map_err(|_| malformed())?, expires_at),
+                    _ => return Err(malformed()),
+                };
+                if Utc.timestamp_opt(expires_at, 0).single() < Some(self.time_component().now()) {
+                    return Err(DomainError::rejected("confirmation token expired"));
+                }
+                self.transaction(|world| {
+                    let user = world.user_commands().deactivate(UserId::new(id))?;
+                    world.session_repository().revoke_user_sessions(&user.id)?;
+                    Ok(user)
+                })
+            }
+        }
+
+        impl<T> DeleteAccount for T where
+            T: HaveUserCommands
+                + HaveUserQueries
+                + HaveSessionRepository
+                + HaveRandomComponent
+                + HaveTimeComponent
+                + HaveCryptoComponent
+                + HaveTracingComponent
+                + TransactionComponent
+        {
+        }
+
+        /// 退会の1段階目をUseCaseとして実行する。出力は確認用のトークン。
+        pub struct RequestAccountDeletionInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: DeleteAccount> RequestAccountDeletionInteractor<'a, W> {
+            pub fn new(world: &'a W) -> RequestAccountDeletionInteractor<'a, W> {
+                RequestAccountDeletionInteractor { world }
+            }
+        }
+
+        impl<'a, W: DeleteAccount> UseCase for RequestAccountDeletionInteractor<'a, W> {
+            type Input = UserId;
+            type Output = String;
+            type Error = DomainError;
+            fn execute(&mut self, input: UserId) -> Result<String, DomainError> {
+                self.world.request_account_deletion(input)
+            }
+        }
+
+        impl<'a, W: DeleteAccount> Interactor for RequestAccountDeletionInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "request_account_deletion";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+
+        /// 退会の2段階目をUseCaseとして実行する。入力は確認用のトークン。
+        pub struct ConfirmAccountDeletionInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: DeleteAccount> ConfirmAccountDeletionInteractor<'a, W> {
+            pub fn new(world: &'a W) -> ConfirmAccountDeletionInteractor<'a, W> {
+                ConfirmAccountDeletionInteractor { world }
+            }
+        }
+
+        impl<'a, W: DeleteAccount> UseCase for ConfirmAccountDeletionInteractor<'a, W> {
+            type Input = String;
+            type Output = UserDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: String) -> Result<UserDto, DomainError> {
+                self.world.confirm_account_deletion(&input).map(|user| UserDto::from(&user))
+            }
+        }
+
+        impl<'a, W: DeleteAccount> Interactor for ConfirmAccountDeletionInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "confirm_account_deletion";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod admin {
+        //! ユーザーを管理する人だけが行う、アカウントの停止・復元・完全な削除。
+        //! 権限はここでは確かめず、envでユースケースを組み立てる時に `authorized` を重ねる。
+
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use component::transaction::TransactionComponent;
+        use entity::user::{StatusError, User, UserId, UserStatus};
+        use repository::DomainError;
+        use repository::Repository;
+        use repository::api_tokens::{ApiTokenRepository, HaveApiTokenRepository};
+        use repository::credentials::HaveCredentialRepository;
+        use repository::groups::{GroupRepository, HaveGroupRepository};
+        use repository::password_resets::HavePasswordResetRepository;
+        use repository::profiles::HaveProfileRepository;
+        use repository::sessions::{HaveSessionRepository, SessionRepository};
+        use repository::users::{HaveUserCommands, HaveUserQueries, UserCommands, UserQueries};
+        use usecase::dto::UserDto;
+        use usecase::{Interactor, UseCase};
+
+        pub trait AdministerUsers:
+            HaveUserCommands
+            + HaveUserQueries
+            + HaveCredentialRepository
+            + HaveSessionRepository
+            + HaveApiTokenRepository
+            + HavePasswordResetRepository
+            + HaveProfileRepository
+            + HaveGroupRepository
+            + HaveTracingComponent
+            + TransactionComponent
+        {
+            /// ユーザーを停止し、そのユーザーのセッションを全て失効させる
+            fn suspend_user(&self, id: UserId) -> Result<User, DomainError>
+            where
+                Self: Sized,
+            {
+                let _span = self.tracing_component().start_span("usecase.suspend_user", &[]);
+                self.transaction(|world| {
+                    let user = world.user_commands().suspend(id)?;
+                    world.session_repository().revoke_user_sessions(&user.id)?;
+                    Ok(user)
+                })
+            }
+
+            /// 停止したユーザーや退会したユーザーをActiveに戻す
+            fn restore_user(&self, id: UserId) -> Result<User, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.restore_user", &[]);
+                self.user_commands().reactivate(id)
+            }
+
+            /// 退会したユーザーと、そのユーザーに紐づくデータを全て消す。消したユーザーを返す。
+            /// 誤って消さないように、退会していないユーザーは消せない。
+            /// APIトークン・パスワード再設定のトークン・プロフィール・グループはトランザクションに参加していないので、
+            /// 途中で失敗するとそれまでに消した分は戻らない。ユーザー自身は最後に消すので、やり直せば全て消せる。
+            fn purge_user(&self, id: UserId) -> Result<User, DomainError>
+            where
+                Self: Sized,
+            {
+                let _span = self.tracing_component().start_span("usecase.purge_user", &[]);
+                let user = self.user_queries().get(id)?;
+                if user.status != UserStatus::Deactivated {
+                    let (status, id) = (user.status, user.id);
+                    return Err(StatusError::NotDeactivated { action: "purge", status, id }.into());
+                }
+                self.transaction(|world| {
+                    for token in world.api_token_repository().list_tokens(&user.id)? {
+                        world.api_token_repository().revoke_token(token.id)?;
+                    }
+                    let resets = world.password_reset_repository().list()?;
+                    for reset in resets.into_iter().filter(|reset| reset.user_id == user.id) {
+                        world.password_reset_repository().delete(reset.id)?;
+                    }
+                    if world.profile_repository().get(user.id.clone()).is_ok() {
+                        world.profile_repository().delete(user.id.clone())?;
+                    }
+                    world.group_repository().leave_all_groups(&user.id)?;
+                    world.session_repository().revoke_user_sessions(&user.id)?;
+                    if world.credential_repository().get(user.id.clone()).is_ok() {
+                        world.credential_repository().delete(user.id.clone())?;
+                    }
+                    world.user_commands().delete(user.id.clone())?;
+                    Ok(user)
+                })
+            }
+        }
+
+        impl<T> AdministerUsers for T where
+            T: HaveUserCommands
+                + HaveUserQueries
+                + HaveCredentialRepository
+                + HaveSessionRepository
+                + HaveApiTokenRepository
+                + HavePasswordResetRepository
+                + HaveProfileRepository
+                + HaveGroupRepository
+                + HaveTracingComponent
+                + TransactionComponent
+        {
+        }
+
+        /// ユーザーの停止をUseCaseとして実行する
+        pub struct SuspendUserInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: AdministerUsers> SuspendUserInteractor<'a, W> {
+            pub fn new(world: &'a W) -> SuspendUserInteractor<'a, W> {
+                SuspendUserInteractor { world }
+            }
+        }
+
+        impl<'a, W: AdministerUsers> UseCase for SuspendUserInteractor<'a, W> {
+            type Input = UserId;
+            type Output = UserDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: UserId) -> Result<UserDto, DomainError> {
+                self.world.suspend_user(input).map(|user| UserDto::from(&user))
+            }
+        }
+
+        impl<'a, W: AdministerUsers> Interactor for SuspendUserInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "suspend_user";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+
+        /// ユーザーの復元をUseCaseとして実行する
+        pub struct RestoreUserInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: AdministerUsers> RestoreUserInteractor<'a, W> {
+            pub fn new(world: &'a W) -> RestoreUserInteractor<'a, W> {
+                RestoreUserInteractor { world }
+            }
+        }
+
+        impl<'a, W: AdministerUsers> UseCase for RestoreUserInteractor<'a, W> {
+            type Input = UserId;
+            type Output = UserDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: UserId) -> Result<UserDto, DomainError> {
+                self.world.restore_user(input).map(|user| UserDto::from(&user))
+            }
+        }
+
+        impl<'a, W: AdministerUsers> Interactor for RestoreUserInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "restore_user";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+
+        /// ユーザーの完全な削除をUseCaseとして実行する。出力は消したユーザー
+        pub struct PurgeUserInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: AdministerUsers> PurgeUserInteractor<'a, W> {
+            pub fn new(world: &'a W) -> PurgeUserInteractor<'a, W> {
+                PurgeUserInteractor { world }
+            }
+        }
+
+        impl<'a, W: AdministerUsers> UseCase for PurgeUserInteractor<'a, W> {
+            type Input = UserId;
+            type Output = UserDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: UserId) -> Result<UserDto, DomainError> {
+                self.world.purge_user(input).map(|user| UserDto::from(&user))
+            }
+        }
+
+        impl<'a, W: AdministerUsers> Interactor for PurgeUserInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "purge_user";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod manage_groups {
+        //! グループを作り、メンバーを出し入れする。
+        //! 権限はここでは確かめず、envでユースケースを組み立てる時に `authorized` を重ねる。
+
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use entity::group::{Group, GroupName};
+        use entity::user::UserId;
+        use repository::DomainError;
+        use repository::Repository;
+        use repository::groups::{GroupRepository, HaveGroupRepository};
+        use usecase::dto::GroupDto;
+        use usecase::{Interactor, UseCase};
+
+        pub trait ManageGroups: HaveGroupRepository + HaveTracingComponent {
+            /// メンバーのいないグループを作る。同じ名前のグループがあればエラー
+            fn create_group(&self, name: GroupName) -> Result<Group, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.create_group", &[("group", name.as_str())]);
+                Ok(self.group_repository().create_group(name)?)
+            }
+
+            /// 存在するユーザーをグループに加える。既にメンバーなら何もしない
+            fn add_group_member(&self, membership: Membership) -> Result<Group, DomainError> {
+                let _span = self
+                    .tracing_component()
+                    .start_span("usecase.add_group_member", &[("group", membership.group.as_str())]);
+                Ok(self.group_repository().add_member(membership.group, membership.user_id)?)
+            }
+
+            /// ユーザーをグループから外す。メンバーでなければ、そのメンバーは見つからない事にする
+            fn remove_group_member(&self, membership: Membership) -> Result<Group, DomainError> {
+                let _span = self
+                    .tracing_component()
+                    .start_span("usecase.remove_group_member", &[("group", membership.group.as_str())]);
+                let group = self.group_repository().get(membership.group)?;
+                if !group.has_member(&membership.user_id) {
+                    let key = format!("{:?} in {}", membership.user_id, group.name);
+                    return Err(DomainError::NotFound { key });
+                }
+                Ok(self.group_repository().remove_member(group.name, membership.user_id)?)
+            }
+        }
+
+        impl<T> ManageGroups for T where T: HaveGroupRepository + HaveTracingComponent {}
+
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct Membership {
+            pub group: GroupName,
+            pub user_id: UserId,
+        }
+
+        /// グループの作成をUseCaseとして実行する
+        pub struct CreateGroupInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: ManageGroups> CreateGroupInteractor<'a, W> {
+            pub fn new(world: &'a W) -> CreateGroupInteractor<'a, W> {
+                CreateGroupInteractor { world }
+            }
+        }
+
+        impl<'a, W: ManageGroups> UseCase for CreateGroupInteractor<'a, W> {
+            type Input = GroupName;
+            type Output = GroupDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: GroupName) -> Result<GroupDto, DomainError> {
+                self.world.create_group(input).map(|group| GroupDto::from(&group))
+            }
+        }
+
+        impl<'a, W: ManageGroups> Interactor for CreateGroupInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "create_group";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+
+        /// メンバーの追加をUseCaseとして実行する
+        pub struct AddGroupMemberInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: ManageGroups> AddGroupMemberInteractor<'a, W> {
+            pub fn new(world: &'a W) -> AddGroupMemberInteractor<'a, W> {
+                AddGroupMemberInteractor { world }
+            }
+        }
+
+        impl<'a, W: ManageGroups> UseCase for AddGroupMemberInteractor<'a, W> {
+            type Input = Membership;
+            type Output = GroupDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: Membership) -> Result<GroupDto, DomainError> {
+                self.world.add_group_member(input).map(|group| GroupDto::from(&group))
+            }
+        }
+
+        impl<'a, W: ManageGroups> Interactor for AddGroupMemberInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "add_group_member";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+
+        /// メンバーを外すのをUseCaseとして実行する
+        pub struct RemoveGroupMemberInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: ManageGroups> RemoveGroupMemberInteractor<'a, W> {
+            pub fn new(world: &'a W) -> RemoveGroupMemberInteractor<'a, W> {
+                RemoveGroupMemberInteractor { world }
+            }
+        }
+
+        impl<'a, W: ManageGroups> UseCase for RemoveGroupMemberInteractor<'a, W> {
+            type Input = Membership;
+            type Output = GroupDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: Membership) -> Result<GroupDto, DomainError> {
+                self.world.remove_group_member(input).map(|group| GroupDto::from(&group))
+            }
+        }
+
+        impl<'a, W: ManageGroups> Interactor for RemoveGroupMemberInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "remove_group_member";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod export_users {
+        use component::filesystem::{FileSystemComponent, HaveFileSystemComponent};
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use failure::Error;
+        use repository::DomainError;
+        use repository::users::{HaveUserQueries, UserQueries};
+        use serde_json;
+        use std::path::Path;
+        use std::path::PathBuf;
+        use std::str::FromStr;
+        use usecase::dto::UserDto;
+        use usecase::{Interactor, UseCase};
+
+        /// この件数ごとにファイルへ追記する。全件を一度にメモリへ載せないため
+        pub const EXPORT_BATCH_SIZE: usize = 1000;
+
+        /// CSVに書き出す列
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Column {
+            Id,
+            Name,
+            Email,
+            Role,
+            Status,
+            CreateTime,
+            UpdateTime,
+        }
+
+        impl Column {
+            /// 列を指定しなかった時はこの順に全部書き出す
+            pub const ALL: [Column; 7] = [
+                Column::Id,
+                Column::Name,
+                Column::Email,
+                Column::Role,
+                Column::Status,
+                Column::CreateTime,
+                Column::UpdateTime,
+            ];
+
+            /// ヘッダ行と `--columns` で使う名前
+            pub fn name(self) -> &'static str {
+                match self {
+                    Column::Id => "id",
+                    Column::Name => "name",
+                    Column::Email => "email",
+                    Column::Role => "role",
+                    Column::Status => "status",
+                    Column::CreateTime => "create_time",
+                    Column::UpdateTime => "update_time",
+                }
+            }
+
+            /// 日時はRFC 3339
+            fn value(self, user: &UserDto) -> String {
+                match self {
+                    Column::Id => user.id.clone(),
+                    Column::Name => user.name.clone(),
+                    Column::Email => user.email.clone(),
+                    Column::Role => user.role.clone(),
+                    Column::Status => user.status.clone(),
+                    Column::CreateTime => user.create_time.to_rfc3339(),
+                    Column::UpdateTime => user.update_time.to_rfc3339(),
+                }
+            }
+        }
+
+        impl FromStr for Column {
+            type Err = Error;
+            fn from_str(s: &str) -> Result<Column, Error> {
+                match Column::ALL.iter().find(|column| column.name() == s) {
+                    Some(column) => Ok(*column),
+                    None => bail!("unknown column: {}", s),
+                }
+            }
+        }
+
+        /// 書き出す形式
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum ExportFormat {
+            /// 1行1件のJSON。import_usersでそのまま読み込める
+            Json,
+            /// ヘッダ付きのCSV。指定した列だけを書き出す
+            Csv(Vec<Column>),
+        }
+
+        /// 書き出し先と形式
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct Export {
+            pub path: PathBuf,
+            pub format: ExportFormat,
+        }
+
+        /// カンマ・ダブルクォート・改行を含む値はダブルクォートで囲む(RFC 4180)
+        fn csv_field(value: &str) -> String {
+            if value.contains(&[',', '"', '\n', '\r'][..]) {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                value.to_string()
+            }
+        }
+
+        fn csv_line<I: IntoIterator<Item = String>>(fields: I) -> String {
+            let fields: Vec<String> = fields.into_iter().map(|field| csv_field(&field)).collect();
+            format!("{}\n", fields.join(","))
+        }
+
+        /// 全ユーザーを書き出す。書き出した件数を返す。
+        /// ストレージから1件ずつ読み、EXPORT_BATCH_SIZE件ごとに追記するので、
+        /// 途中で失敗した場合はそこまでの分が書かれたファイルが残る。
+        pub trait ExportUsers: HaveUserQueries + HaveFileSystemComponent + HaveTracingComponent {
+            fn export_users_as(&self, path: &Path, format: &ExportFormat) -> Result<usize, DomainError> {
+                let _span = self
+                    .tracing_component()
+                    .start_span("usecase.export_users", &[("path", &path.display().to_string())]);
+                let files = self.file_system_component();
+                let header = match *format {
+                    ExportFormat::Json => String::new(),
+                    ExportFormat::Csv(ref columns) => csv_line(columns.iter().map(|column| column.name().to_string())),
+                };
+                files.write(path, &header)?;
+                let mut count = 0;
+                let mut batch = String::new();
+                for user in self.user_queries().iter() {
+                    let user = user?;
+                    match *format {
+                        ExportFormat::Json => {
+                            batch.push_str(&serde_json::to_string(&user).map_err(Error::from)?);
+                            batch.push('\n');
+                        }
+                        ExportFormat::Csv(ref columns) => {
+                            let user = UserDto::from(&user);
+                            batch.push_str(&csv_line(columns.iter().map(|column| column.value(&user))));
+                        }
+                    }
+                    count += 1;
+                    if count % EXPORT_BATCH_SIZE == 0 {
+                        files.append(path, &batch)?;
+                        batch.clear();
+                    }
+                }
+                if !batch.is_empty() {
+                    files.append(path, &batch)?;
+                }
+                Ok(count)
+            }
+        }
+
+        impl<T: HaveUserQueries + HaveFileSystemComponent + HaveTracingComponent> ExportUsers for T {}
+
+        /// ExportUsersをUseCaseとして実行する。出力は書き出した人数。
+        pub struct ExportUsersInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: ExportUsers> ExportUsersInteractor<'a, W> {
+            pub fn new(world: &'a W) -> ExportUsersInteractor<'a, W> {
+                ExportUsersInteractor { world }
+            }
+        }
+
+        impl<'a, W: ExportUsers> UseCase for ExportUsersInteractor<'a, W> {
+            type Input = Export;
+            type Output = usize;
+            type Error = DomainError;
+            fn execute(&mut self, input: Export) -> Result<usize, DomainError> {
+                self.world.export_users_as(&input.path, &input.format)
+            }
+        }
+
+        impl<'a, W: ExportUsers> Interactor for ExportUsersInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "export_users";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod import_users {
+        use chrono::prelude::*;
+        use component::filesystem::{FileSystemComponent, HaveFileSystemComponent};
+        use component::id::{HaveIdGeneratorComponent, IdGeneratorComponent};
+        use component::log::{HaveLoggingComponent, LoggingComponent};
+        use component::time::{HaveTimeComponent, TimeComponent};
+        use component::storage::StorageError;
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use component::transaction::TransactionComponent;
+        use component::validation::{HaveValidationComponent, ValidationComponent};
+        use entity::job::JobKind;
+        use entity::user::{Email, Name, Role, User, UserId, UserStatus};
+        use repository::DomainError;
+        use repository::Repository;
+        use repository::users::{HaveUserCommands, HaveUserQueries, UserQueries};
+        use serde_json;
+        use std::collections::BTreeMap;
+        use std::error;
+        use std::fmt;
+        use std::mem;
+        use std::path::{Path, PathBuf};
+        use usecase::export_users::Column;
+        use usecase::jobs::EnqueueJob;
+        use usecase::{Interactor, UseCase};
+        use uuid::Uuid;
+
+        /// 読み込むファイルの形式
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum ImportFormat {
+            /// export_usersで書き出した、1行に1人のJSON
+            Json,
+            /// 1行目がヘッダのCSV。列名は `user export --format csv` と同じで、nameとemailは必須。
+            /// 無い列は、IDは新しく振り、roleはmember、statusはactive、日時は読み込んだ時刻にする。
+            Csv,
+        }
+
+        impl ImportFormat {
+            /// 拡張子が `.csv` ならCSV、それ以外はJSON
+            pub fn from_path(path: &Path) -> ImportFormat {
+                match path.extension().and_then(|ext| ext.to_str()) {
+                    Some(ext) if ext.eq_ignore_ascii_case("csv") => ImportFormat::Csv,
+                    _ => ImportFormat::Json,
+                }
+            }
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct Import {
+            pub path: PathBuf,
+            pub format: ImportFormat,
+            /// 指定すると、この数までの塊に分けて並行して保存する
+            pub parallelism: Option<usize>,
+        }
+
+        /// 1行分の誤り。lineはファイルの行番号(1始まり)
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct RowError {
+            pub line: usize,
+            pub message: String,
+        }
+
+        /// 誤りのある行があったので、1件も読み込まなかった
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct ImportError {
+            pub rows: Vec<RowError>,
+        }
+
+        impl fmt::Display for ImportError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{} invalid rows, nothing was imported", self.rows.len())?;
+                for row in &self.rows {
+                    write!(f, "\nline {}: {}", row.line, row.message)?;
+                }
+                Ok(())
+            }
+        }
+
+        impl error::Error for ImportError {}
+
+        impl From<ImportError> for DomainError {
+            fn from(e: ImportError) -> DomainError {
+                DomainError::rejected(e)
+            }
+        }
+
+        /// ファイルからユーザーを読み込む。読み込んだ件数を返す。
+        /// 先に全ての行を検証し、1行でも誤りがあれば行ごとの誤りをImportErrorで返して1件も読み込まない。
+        /// 検証が通ったら、1つのトランザクションの中で `insert_many` でまとめて保存する。
+        /// `parallelism` を指定した時は `save_all_concurrent` で塊ごとに並行して保存するので、途中で失敗すると保存済みの塊は残る。
+        /// 読み込んだユーザーは1件ずつのイベントを出さず索引に入らないので、後から索引を作り直すジョブを積む。
+        pub trait ImportUsers:
+            HaveUserCommands
+            + HaveUserQueries
+            + HaveValidationComponent
+            + HaveIdGeneratorComponent
+            + HaveTimeComponent
+            + HaveFileSystemComponent
+            + HaveTracingComponent
+            + HaveLoggingComponent
+            + EnqueueJob
+            + TransactionComponent
+        {
+            fn import_users(
+                &self,
+                path: &Path,
+                format: ImportFormat,
+                parallelism: Option<usize>,
+            ) -> Result<usize, DomainError>
+            where
+                Self: Sized,
+            {
+                let _span = self
+                    .tracing_component()
+                    .start_span("usecase.import_users", &[("path", &path.display().to_string())]);
+                let contents = match self.file_system_component().read(path)? {
+                    Some(contents) => contents,
+                    None => return Err(StorageError::not_found(&path).into()),
+                };
+                let rows = match format {
+                    ImportFormat::Json => json_rows(&contents),
+                    ImportFormat::Csv => csv_rows(self, &contents)?,
+                };
+
+                let mut users = Vec::with_capacity(rows.len());
+                let mut errors = Vec::new();
+                let mut seen = Seen::default();
+                for (line, row) in rows {
+                    match row.and_then(|user| check(self, &mut seen, line, &user).map(|_| user)) {
+                        Ok(user) => users.push(user),
+                        Err(message) => errors.push(RowError { line, message }),
+                    }
+                }
+                if !errors.is_empty() {
+                    return Err(ImportError { rows: errors }.into());
+                }
+
+                let count = users.len();
+                match parallelism {
+                    Some(parallelism) => self.user_commands().save_all_concurrent(users, parallelism)?,
+                    None => self.transaction(|world| world.user_commands().insert_many(users))?,
+                }
+                if let Err(e) = self.enqueue_job(JobKind::RebuildSearchIndex) {
+                    self.logging_component().warn(&format!("search index rebuild not queued: {}", e));
+                }
+                Ok(count)
+            }
+        }
+
+        impl<T> ImportUsers for T where
+            T: HaveUserCommands
+                + HaveUserQueries
+                + HaveValidationComponent
+                + HaveIdGeneratorComponent
+                + HaveTimeComponent
+                + HaveFileSystemComponent
+                + HaveTracingComponent
+                + HaveLoggingComponent
+                + EnqueueJob
+                + TransactionComponent
+        {
+        }
+
+        type Row = (usize, Result<User, String>);
+
+        /// ファイルの中で既に出てきたID・名前・メールアドレスと、その行番号
+        #[derive(Default)]
+        struct Seen {
+            ids: BTreeMap<UserId, usize>,
+            names: BTreeMap<Name, usize>,
+            emails: BTreeMap<Email, usize>,
+        }
+
+        /// ルールに合っているか、ファイルの中や既にいるユーザーとぶつからないかを確かめる
+        fn check<W: HaveUserQueries + HaveValidationComponent>(
+            world: &W,
+            seen: &mut Seen,
+            line: usize,
+            user: &User,
+        ) -> Result<(), String> {
+            world.validation_component().validate(user).map_err(|e| e.to_string())?;
+            if let Some(first) = seen.ids.insert(user.id.clone(), line) {
+                return Err(format!("duplicate id {:?} (first seen on line {})", user.id, first));
+            }
+            if let Some(first) = seen.names.insert(user.name.clone(), line) {
+                return Err(format!("duplicate name {:?} (first seen on line {})", user.name, first));
+            }
+            if let Some(first) = seen.emails.insert(user.email.clone(), line) {
+                return Err(format!("duplicate email {:?} (first seen on line {})", user.email, first));
+            }
+            let queries = world.user_queries();
+            if queries.get(user.id.clone()).is_ok() {
+                return Err(format!("already exists: {:?}", user.id));
+            }
+            if queries.get_by_name(&user.name).is_ok() {
+                return Err(format!("name already taken: {:?}", user.name));
+            }
+            if queries.get_by_email(&user.email).is_ok() {
+                return Err(format!("email already taken: {:?}", user.email));
+            }
+            Ok(())
+        }
+
+        fn json_rows(contents: &str) -> Vec<Row> {
+            contents
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(i, line)| (i + 1, serde_json::from_str::<User>(line).map_err(|e| e.to_string())))
+                .collect()
+        }
+
+        /// ヘッダが読めない時はどの行も読めないので、行ごとの誤りではなくエラーにする
+        fn csv_rows<W: HaveIdGeneratorComponent + HaveTimeComponent>(
+            world: &W,
+            contents: &str,
+        ) -> Result<Vec<Row>, DomainError> {
+            let mut records = csv_records(contents).into_iter();
+            let header = match records.next() {
+                Some((_, Ok(header))) => header,
+                Some((line, Err(message))) => return Err(DomainError::rejected(format!("line {}: {}", line, message))),
+                None => return Err(DomainError::rejected("missing header row")),
+            };
+            let columns = header
+                .iter()
+                .map(|name| name.trim().parse::<Column>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| DomainError::rejected(e.to_string()))?;
+            for required in &[Column::Name, Column::Email] {
+                if !columns.contains(required) {
+                    return Err(DomainError::rejected(format!("missing column: {}", required.name())));
+                }
+            }
+            let now = world.time_component().now();
+            Ok(records
+                .map(|(line, fields)| {
+                    let user = fields.and_then(|fields| {
+                        if fields.len() != columns.len() {
+                            return Err(format!("expected {} fields, found {}", columns.len(), fields.len()));
+                        }
+                        csv_user(world, now, columns.iter().cloned().zip(fields))
+                    });
+                    (line, user)
+                })
+                .collect())
+        }
+
+        fn csv_user<W: HaveIdGeneratorComponent>(
+            world: &W,
+            now: DateTime<Utc>,
+            fields: impl Iterator<Item = (Column, String)>,
+        ) -> Result<User, String> {
+            let (mut id, mut name, mut email, mut create_time, mut update_time) = (None, None, None, None, None);
+            let (mut role, mut status) = (Role::default(), UserStatus::default());
+            for (column, value) in fields {
+                let value = value.trim();
+                match column {
+                    Column::Id => id = Some(UserId::new(Uuid::parse_str(value).map_err(|e| format!("id: {}", e))?)),
+                    Column::Name => name = Some(Name::new(value).map_err(|e| e.to_string())?),
+                    Column::Email => email = Some(Email::parse(value).map_err(|e| e.to_string())?),
+                    Column::Role => role = parse_variant("role", value, &[Role::Admin, Role::Member, Role::Guest])?,
+                    Column::Status => {
+                        let statuses = [UserStatus::Active, UserStatus::Suspended, UserStatus::Deactivated];
+                        status = parse_variant("status", value, &statuses)?
+                    }
+                    Column::CreateTime => create_time = Some(parse_time("create_time", value)?),
+                    Column::UpdateTime => update_time = Some(parse_time("update_time", value)?),
+                }
+            }
+            let create_time = create_time.unwrap_or(now);
+            Ok(User {
+                id: id.unwrap_or_else(|| UserId::new(world.id_generator_component().generate())),
+                name: name.ok_or("missing name")?,
+                email: email.ok_or("missing email")?,
+                role,
+                status,
+                address: None,
+                phone_number: None,
+                create_time,
+                update_time: update_time.unwrap_or(create_time),
+                version: 1,
+            })
+        }
+
+        /// 書き出す時と同じく、Debug表記を小文字にしたもので選ぶ
+        fn parse_variant<T: fmt::Debug + Copy>(column: &str, value: &str, variants: &[T]) -> Result<T, String> {
+            variants
+                .iter()
+                .find(|variant| format!("{:?}", variant).eq_ignore_ascii_case(value))
+                .cloned()
+                .ok_or_else(|| format!("{}: unknown value {:?}", column, value))
+        }
+
+        fn parse_time(column: &str, value: &str) -> Result<DateTime<Utc>, String> {
+            DateTime::parse_from_rfc3339(value)
+                .map(|time| time.with_timezone(&Utc))
+                .map_err(|e| format!("{}: {}", column, e))
+        }
+
+        /// CSVをレコードに分ける。`"` で囲んだフィールドの中のカンマ・改行・`""` も扱う。
+        /// 各レコードには始まった行の番号を付け、閉じていない `"` はそのレコードの誤りにする。
+        fn csv_records(contents: &str) -> Vec<(usize, Result<Vec<String>, String>)> {
+            let mut records = Vec::new();
+            let (mut fields, mut field) = (Vec::new(), String::new());
+            let (mut line, mut start, mut quoted) = (1, 1, false);
+            let mut chars = contents.chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if quoted && chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' if quoted => quoted = false,
+                    '"' if field.is_empty() => quoted = true,
+                    ',' if !quoted => fields.push(mem::take(&mut field)),
+                    '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+                    '\n' if !quoted => {
+                        fields.push(mem::take(&mut field));
+                        if fields.len() > 1 || !fields[0].trim().is_empty() {
+                            records.push((start, Ok(mem::take(&mut fields))));
+                        }
+                        fields.clear();
+                        line += 1;
+                        start = line;
+                    }
+                    c => {
+                        if c == '\n' {
+                            line += 1;
+                        }
+                        field.push(c);
+                    }
+                }
+            }
+            if quoted {
+                records.push((start, Err("unterminated quoted field".to_string())));
+            } else if !fields.is_empty() || !field.trim().is_empty() {
+                fields.push(field);
+                records.push((start, Ok(fields)));
+            }
+            records
+        }
+
+        /// ImportUsersをUseCaseとして実行する。出力は読み込んだ人数。
+        pub struct ImportUsersInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: ImportUsers> ImportUsersInteractor<'a, W> {
+            pub fn new(world: &'a W) -> ImportUsersInteractor<'a, W> {
+                ImportUsersInteractor { world }
+            }
+        }
+
+        impl<'a, W: ImportUsers> UseCase for ImportUsersInteractor<'a, W> {
+            type Input = Import;
+            type Output = usize;
+            type Error = DomainError;
+            fn execute(&mut self, input: Import) -> Result<usize, DomainError> {
+                self.world.import_users(&input.path, input.format, input.parallelism)
+            }
+        }
+
+        impl<'a, W: ImportUsers> Interactor for ImportUsersInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "import_users";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod search_users {
+        use component::search::{HaveSearchComponent, SearchComponent};
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use entity::user::{User, UserId};
+        use failure::Error;
+        use repository::DomainError;
+        use repository::users::{HaveUserQueries, UserQueries};
+        use usecase::user_events::search_text;
+        use uuid::Uuid;
+        use usecase::dto::UserSummaryDto;
+        use usecase::{Interactor, UseCase};
+
+        pub trait SearchUsers: HaveUserQueries + HaveSearchComponent + HaveTracingComponent {
+            /// 名前やメールアドレスで探して、よく合う順に最大 `limit` 人を返す。綴りが少し違っていても見つかる。
+            fn search_users(&self, query: &str, limit: usize) -> Result<Vec<User>, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.search_users", &[("query", query)]);
+                let mut users = Vec::new();
+                for id in self.search_component().query(query, limit)? {
+                    // 索引の更新が遅れて、既に消えたユーザーが見つかる事もあるので読み飛ばす
+                    if let Ok(user) = self.user_queries().get(UserId::new(Uuid::parse_str(&id).map_err(Error::from)?)) {
+                        users.push(user);
+                    }
+                }
+                Ok(users)
+            }
+
+            /// 全ユーザーを索引に入れ直す。索引を作り直した時(起動時等)に呼ぶ。
+            fn reindex_users(&self) -> Result<(), DomainError> {
+                let _span = self.tracing_component().start_span("usecase.reindex_users", &[]);
+                for user in self.user_queries().list()? {
+                    self.search_component().index(&user.id.as_uuid().to_string(), &search_text(&user))?;
+                }
+                Ok(())
+            }
+        }
+
+        impl<T: HaveUserQueries + HaveSearchComponent + HaveTracingComponent> SearchUsers for T {}
+
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct UserSearch {
+            pub query: String,
+            pub limit: usize,
+        }
+
+        /// SearchUsersをUseCaseとして実行する
+        pub struct SearchUsersInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: SearchUsers> SearchUsersInteractor<'a, W> {
+            pub fn new(world: &'a W) -> SearchUsersInteractor<'a, W> {
+                SearchUsersInteractor { world }
+            }
+        }
+
+        impl<'a, W: SearchUsers> UseCase for SearchUsersInteractor<'a, W> {
+            type Input = UserSearch;
+            type Output = Vec<UserSummaryDto>;
+            type Error = DomainError;
+            fn execute(&mut self, input: UserSearch) -> Result<Vec<UserSummaryDto>, DomainError> {
+                let users = self.world.search_users(&input.query, input.limit)?;
+                Ok(users.iter().map(UserSummaryDto::from).collect())
+            }
+        }
+
+        impl<'a, W: SearchUsers> Interactor for SearchUsersInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "search_users";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod get_user {
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use entity::user::{Name, User, UserId};
+        use repository::DomainError;
+        use repository::users::{HaveUserQueries, UserQueries};
+        use usecase::dto::UserDto;
+        use usecase::{Interactor, UseCase};
+
+        /// 読むだけなので、失敗はRepositoryのDomainErrorのまま返す。
+        /// InteractorもDomainErrorを返し、呼び出し側が種類で振り分けられるようにする
+        pub trait GetUser: HaveUserQueries + HaveTracingComponent {
+            fn get_user(&self, id: UserId) -> Result<User, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.get_user", &[]);
+                self.user_queries().get(id)
+            }
+
+            fn get_user_by_name(&self, name: &Name) -> Result<User, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.get_user_by_name", &[("name", name.as_str())]);
+                self.user_queries().get_by_name(name)
+            }
+
+            /// 複数のユーザーをまとめて引く。見つからないIDは飛ばす。
+            fn get_users(&self, ids: &[UserId]) -> Result<Vec<User>, DomainError> {
+                let count = ids.len().to_string();
+                let _span = self.tracing_component().start_span("usecase.get_users", &[("count", &count)]);
+                self.user_queries().get_many(ids)
+            }
+        }
+
+        impl<T: HaveUserQueries + HaveTracingComponent> GetUser for T {}
+
+        /// GetUserをUseCaseとして実行する
+        pub struct GetUserInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: GetUser> GetUserInteractor<'a, W> {
+            pub fn new(world: &'a W) -> GetUserInteractor<'a, W> {
+                GetUserInteractor { world }
+            }
+        }
+
+        impl<'a, W: GetUser> UseCase for GetUserInteractor<'a, W> {
+            type Input = UserId;
+            type Output = UserDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: UserId) -> Result<UserDto, DomainError> {
+                Ok(UserDto::from(&self.world.get_user(input)?))
+            }
+        }
+
+        impl<'a, W: GetUser> Interactor for GetUserInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "get_user";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+
+        /// 名前でGetUserをUseCaseとして実行する
+        pub struct GetUserByNameInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: GetUser> GetUserByNameInteractor<'a, W> {
+            pub fn new(world: &'a W) -> GetUserByNameInteractor<'a, W> {
+                GetUserByNameInteractor { world }
+            }
+        }
+
+        impl<'a, W: GetUser> UseCase for GetUserByNameInteractor<'a, W> {
+            type Input = Name;
+            type Output = UserDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: Name) -> Result<UserDto, DomainError> {
+                Ok(UserDto::from(&self.world.get_user_by_name(&input)?))
+            }
+        }
+
+        impl<'a, W: GetUser> Interactor for GetUserByNameInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "get_user_by_name";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+
+        /// 複数のユーザーのGetUserをまとめてUseCaseとして実行する
+        pub struct GetUsersInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: GetUser> GetUsersInteractor<'a, W> {
+            pub fn new(world: &'a W) -> GetUsersInteractor<'a, W> {
+                GetUsersInteractor { world }
+            }
+        }
+
+        impl<'a, W: GetUser> UseCase for GetUsersInteractor<'a, W> {
+            type Input = Vec<UserId>;
+            type Output = Vec<UserDto>;
+            type Error = DomainError;
+            fn execute(&mut self, input: Vec<UserId>) -> Result<Vec<UserDto>, DomainError> {
+                let users = self.world.get_users(&input)?;
+                Ok(users.iter().map(UserDto::from).collect())
+            }
+        }
+
+        impl<'a, W: GetUser> Interactor for GetUsersInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "get_users";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod list_users {
+        //! 一覧画面等向けのユーザーの一覧。
+        //! 表示する側がEntityに依存しないように、Userではなく表示用のUserSummaryDtoを返す。
+
+        use component::config::{ConfigComponent, HaveConfigComponent};
+        use component::storage::StorageError;
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use entity::user::UserId;
+        use failure::Error;
+        use repository::DomainError;
+        use repository::users::{HaveUserQueries, UserFilter, UserQueries};
+        use std::str::FromStr;
+        use usecase::dto::UserSummaryDto;
+        use usecase::{Interactor, UseCase};
+        use utoipa::ToSchema;
+
+        /// 並べ替えに使う項目
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+        pub enum UserSort {
+            Name,
+            Email,
+            #[default]
+            CreateTime,
+        }
+
+        /// `name`, `email`, `create_time`
+        impl FromStr for UserSort {
+            type Err = Error;
+            fn from_str(s: &str) -> Result<UserSort, Error> {
+                match s {
+                    "name" => Ok(UserSort::Name),
+                    "email" => Ok(UserSort::Email),
+                    "create_time" => Ok(UserSort::CreateTime),
+                    _ => bail!("unknown sort field: {}", s),
+                }
+            }
+        }
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+        pub enum SortOrder {
+            #[default]
+            Ascending,
+            Descending,
+        }
+
+        /// `asc`, `desc`
+        impl FromStr for SortOrder {
+            type Err = Error;
+            fn from_str(s: &str) -> Result<SortOrder, Error> {
+                match s {
+                    "asc" => Ok(SortOrder::Ascending),
+                    "desc" => Ok(SortOrder::Descending),
+                    _ => bail!("unknown sort order: {}", s),
+                }
+            }
+        }
+
+        /// 一覧の条件。ページは1始まりで、1ページの件数を指定しなければ設定の `page_size` を使う。
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct ListUsersQuery {
+            pub page: usize,
+            pub per_page: Option<usize>,
+            pub sort: UserSort,
+            pub order: SortOrder,
+            /// カーソルでページを送る時の、前のページの最後のユーザー。指定するとpageは使わず、その次から数える。
+            pub after: Option<UserId>,
+            /// 絞り込んでから並べ、ページに分ける。totalも絞り込んだ後の件数になる
+            pub filter: UserFilter,
+        }
+
+        impl Default for ListUsersQuery {
+            fn default() -> Self {
+                ListUsersQuery {
+                    page: 1,
+                    per_page: None,
+                    sort: UserSort::default(),
+                    order: SortOrder::default(),
+                    after: None,
+                    filter: UserFilter::default(),
+                }
+            }
+        }
+
+        /// 1ページ分の結果
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+        pub struct Page<T> {
+            pub items: Vec<T>,
+            pub page: usize,
+            pub per_page: usize,
+            /// 全ページ合わせた件数
+            pub total: usize,
+        }
+
+        impl<T> Page<T> {
+            pub fn total_pages(&self) -> usize {
+                self.total.div_ceil(self.per_page)
+            }
+        }
+
+        pub trait ListUsers: HaveUserQueries + HaveConfigComponent + HaveTracingComponent {
+            fn list_users(&self, query: ListUsersQuery) -> Result<Page<UserSummaryDto>, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.list_users", &[]);
+                let per_page = query.per_page.unwrap_or_else(|| self.config_component().page_size());
+                if query.page == 0 || per_page == 0 {
+                    return Err(DomainError::rejected("page and per_page must be greater than 0"));
+                }
+                let mut users = self.user_queries().find(&query.filter)?;
+                // 同じ値のユーザーはIDで並べ、ページを跨いでも順番が変わらないようにする
+                users.sort_by(|a, b| {
+                    let ordering = match query.sort {
+                        UserSort::Name => a.name.cmp(&b.name),
+                        UserSort::Email => a.email.cmp(&b.email),
+                        UserSort::CreateTime => a.create_time.cmp(&b.create_time),
+                    };
+                    ordering.then_with(|| a.id.cmp(&b.id))
+                });
+                if query.order == SortOrder::Descending {
+                    users.reverse();
+                }
+                let skip = match query.after {
+                    Some(ref after) => match users.iter().position(|user| user.id == *after) {
+                        Some(index) => index + 1,
+                        None => return Err(StorageError::not_found(after).into()),
+                    },
+                    None => (query.page - 1) * per_page,
+                };
+                Ok(Page {
+                    items: users
+                        .iter()
+                        .skip(skip)
+                        .take(per_page)
+                        .map(UserSummaryDto::from)
+                        .collect(),
+                    page: query.page,
+                    per_page,
+                    total: users.len(),
+                })
+            }
+        }
+
+        impl<T: HaveUserQueries + HaveConfigComponent + HaveTracingComponent> ListUsers for T {}
+
+        /// ListUsersをUseCaseとして実行する
+        pub struct ListUsersInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: ListUsers> ListUsersInteractor<'a, W> {
+            pub fn new(world: &'a W) -> ListUsersInteractor<'a, W> {
+                ListUsersInteractor { world }
+            }
+        }
+
+        impl<'a, W: ListUsers> UseCase for ListUsersInteractor<'a, W> {
+            type Input = ListUsersQuery;
+            type Output = Page<UserSummaryDto>;
+            type Error = DomainError;
+            fn execute(&mut self, input: ListUsersQuery) -> Result<Page<UserSummaryDto>, DomainError> {
+                self.world.list_users(input)
+            }
+        }
+
+        impl<'a, W: ListUsers> Interactor for ListUsersInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "list_users";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod rename_user {
+        use component::feature_flag::{FeatureFlagComponent, HaveFeatureFlagComponent};
+        use component::notification::{HaveNotificationComponent, NotificationComponent};
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use entity::user::{Name, StatusError, User, UserId};
+        use repository::DomainError;
+        use repository::users::{HaveUserCommands, HaveUserQueries, UserCommands, UserQueries};
+        use usecase::dto::UserDto;
+        use usecase::{Interactor, UseCase};
+
+        /// 有効になっているユーザーには、名前が変わった事を通知する
+        pub const NOTIFY_ON_RENAME: &str = "notify_on_rename";
+
+        /// ユーザー名を変更する。Activeでないユーザー(停止中等)は変更できない。
+        pub trait RenameUser:
+            HaveUserCommands
+            + HaveUserQueries
+            + HaveFeatureFlagComponent
+            + HaveNotificationComponent
+            + HaveTracingComponent
+        {
+            fn rename_user(&self, id: UserId, name: Name) -> Result<User, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.rename_user", &[("name", name.as_str())]);
+                let user = self.user_queries().get(id)?;
+                if !user.is_active() {
+                    let (status, id) = (user.status, user.id);
+                    return Err(StatusError::NotActive { action: "rename", status, id }.into());
+                }
+                let old_name = user.name;
+                let user = self.user_commands().rename(user.id, name)?;
+                if self.feature_flag_component().is_enabled(NOTIFY_ON_RENAME, &user) {
+                    let message = format!("name changed from {} to {}", old_name, user.name);
+                    self.notification_component().notify(&user, &message)?;
+                }
+                Ok(user)
+            }
+        }
+
+        impl<T> RenameUser for T where
+            T: HaveUserCommands
+                + HaveUserQueries
+                + HaveFeatureFlagComponent
+                + HaveNotificationComponent
+                + HaveTracingComponent
+        {
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct UserRename {
+            pub id: UserId,
+            pub name: Name,
+        }
+
+        /// RenameUserをUseCaseとして実行する
+        pub struct RenameUserInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: RenameUser> RenameUserInteractor<'a, W> {
+            pub fn new(world: &'a W) -> RenameUserInteractor<'a, W> {
+                RenameUserInteractor { world }
+            }
+        }
+
+        impl<'a, W: RenameUser> UseCase for RenameUserInteractor<'a, W> {
+            type Input = UserRename;
+            type Output = UserDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: UserRename) -> Result<UserDto, DomainError> {
+                self.world.rename_user(input.id, input.name).map(|user| UserDto::from(&user))
+            }
+        }
+
+        impl<'a, W: RenameUser> Interactor for RenameUserInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "rename_user";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod error_message {
+        //! エラーを利用者に見せる文言にする。HTTPのレイヤはエラーの本文をそのまま返さずにここを通す。
+
+        use component::locale::{HaveLocaleComponent, LocaleComponent};
+        use entity::user::UserId;
+        use repository::Repository;
+        use repository::profiles::HaveProfileRepository;
+        use usecase::presentation_error::PresentationError;
+
+        pub trait ErrorMessage: HaveLocaleComponent + HaveProfileRepository {
+            /// `user_id` のプロフィールのロケール。プロフィールが無ければNone
+            fn profile_locale(&self, user_id: UserId) -> Option<String> {
+                self.profile_repository().get(user_id).ok().map(|profile| profile.locale)
+            }
+
+            /// `error` の文言とフィールド毎の誤りを `locale` の文言にする。翻訳できない文言は元のまま残す。
+            fn localize_error(&self, locale: &str, mut error: PresentationError) -> PresentationError {
+                if let Some(ref key) = error.message_key {
+                    error.message = self.locale_component().localize(locale, key);
+                }
+                for (field, key) in &error.field_keys {
+                    error.fields.insert(field.clone(), self.locale_component().localize(locale, key));
+                }
+                error
+            }
+        }
+
+        impl<T: HaveLocaleComponent + HaveProfileRepository> ErrorMessage for T {}
+    }
+
+    pub mod presentation_error {
+        //! ユースケースのエラーを、フロントエンド(CLI, HTTP)が共通で扱える種類に分ける。
+        //! どのエラーがどの種類になるかはここだけで決め、各フロントエンドは種類を自分の表現に置き換えるだけにする。
+
+        use component::locale::{Localize, MessageKey};
+        use component::storage::StorageError;
+        use entity::ValidationError;
+        use entity::user::StatusError;
+        use failure::Error;
+        use repository::DomainError;
+        use service::unique_email::EmailTaken;
+        use std::collections::BTreeMap;
+        use std::error;
+        use std::fmt;
+        use usecase::PermissionDenied;
+        use usecase::authenticate_user::AuthenticationError;
+        use usecase::import_users::ImportError;
+        use usecase::register_user::NameTaken;
+
+        /// エラーの種類
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum ErrorKind {
+            /// ログインできなかった
+            Unauthenticated,
+            /// 権限が無い
+            Forbidden,
+            NotFound,
+            /// 既にある値や今の状態とぶつかった
+            Conflict,
+            /// 入力の値が検証で弾かれた
+            Validation,
+            /// 試行しすぎた
+            RateLimited,
+            /// 利用者には原因を見せないエラー
+            Internal,
+        }
+
+        impl ErrorKind {
+            /// HTTPで返すステータスコード
+            pub fn status_code(self) -> u16 {
+                match self {
+                    ErrorKind::Unauthenticated => 401,
+                    ErrorKind::Forbidden => 403,
+                    ErrorKind::NotFound => 404,
+                    ErrorKind::Conflict => 409,
+                    ErrorKind::Validation => 422,
+                    ErrorKind::RateLimited => 429,
+                    ErrorKind::Internal => 500,
+                }
+            }
+
+            /// CLIの終了コード(sysexits.h)
+            pub fn exit_code(self) -> i32 {
+                match self {
+                    ErrorKind::Unauthenticated | ErrorKind::Forbidden => 77,
+                    ErrorKind::NotFound => 66,
+                    ErrorKind::Conflict | ErrorKind::Validation => 65,
+                    ErrorKind::RateLimited => 75,
+                    ErrorKind::Internal => 70,
+                }
+            }
+        }
+
+        /// フロントエンドに渡すエラー。Internalの時は元のエラーの内容を `message` に含めない。
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct PresentationError {
+            pub kind: ErrorKind,
+            pub message: String,
+            /// 入力のフィールド毎の誤り。フィールド名から理由を引く
+            pub fields: BTreeMap<String, String>,
+            /// `message` を利用者のロケールの文言に出来る時の、カタログのキー
+            pub message_key: Option<MessageKey>,
+            /// `fields` の中で、利用者のロケールの文言に出来る誤りのキー
+            pub field_keys: BTreeMap<String, MessageKey>,
+        }
+
+        impl PresentationError {
+            pub fn new<M: ToString>(kind: ErrorKind, message: M) -> PresentationError {
+                PresentationError {
+                    kind,
+                    message: message.to_string(),
+                    fields: BTreeMap::new(),
+                    message_key: None,
+                    field_keys: BTreeMap::new(),
+                }
+            }
+
+            /// 翻訳できるエラーの文言を `message` にする
+            pub fn localizable<L: Localize + fmt::Display>(kind: ErrorKind, source: &L) -> PresentationError {
+                PresentationError {
+                    message_key: Some(MessageKey::of(source)),
+                    ..PresentationError::new(kind, source)
+                }
+            }
+
+            pub fn with_field<F: ToString, M: ToString>(mut self, field: F, message: M) -> PresentationError {
+                self.fields.insert(field.to_string(), message.to_string());
+                self
+            }
+
+            /// 翻訳できるエラーをフィールドの誤りとして足す
+            pub fn with_localizable_field<F: ToString, L: Localize + fmt::Display>(
+                mut self,
+                field: F,
+                source: &L,
+            ) -> PresentationError {
+                self.field_keys.insert(field.to_string(), MessageKey::of(source));
+                self.with_field(field, source)
+            }
+
+            pub fn status_code(&self) -> u16 {
+                self.kind.status_code()
+            }
+
+            pub fn exit_code(&self) -> i32 {
+                self.kind.exit_code()
+            }
+        }
+
+        impl fmt::Display for PresentationError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", self.message)
+            }
+        }
+
+        impl error::Error for PresentationError {}
+
+        impl From<ValidationError> for PresentationError {
+            fn from(e: ValidationError) -> PresentationError {
+                let error = PresentationError::localizable(ErrorKind::Validation, &e);
+                match e.field() {
+                    Some(field) => error.with_localizable_field(field, &e),
+                    None => error,
+                }
+            }
+        }
+
+        impl From<StorageError> for PresentationError {
+            fn from(e: StorageError) -> PresentationError {
+                match e {
+                    StorageError::Conflict { .. } => PresentationError::new(ErrorKind::Conflict, e),
+                    StorageError::NotFound { .. } => PresentationError::new(ErrorKind::NotFound, e),
+                    StorageError::Taken { field, .. } => {
+                        PresentationError::new(ErrorKind::Conflict, &e).with_field(field, e)
+                    }
+                }
+            }
+        }
+
+        impl From<DomainError> for PresentationError {
+            fn from(e: DomainError) -> PresentationError {
+                match e {
+                    DomainError::NotFound { .. } => PresentationError::new(ErrorKind::NotFound, e),
+                    DomainError::AlreadyExists { field: "id", .. } => PresentationError::new(ErrorKind::Conflict, e),
+                    DomainError::AlreadyExists { field, .. } => {
+                        PresentationError::new(ErrorKind::Conflict, &e).with_field(field, e)
+                    }
+                    DomainError::Conflict { .. } => PresentationError::new(ErrorKind::Conflict, e),
+                    DomainError::Validation(e) => e.into(),
+                    DomainError::Status(e) => e.into(),
+                    DomainError::Rejected(reason) => {
+                        let reason = match reason.downcast::<AuthenticationError>() {
+                            Ok(e) => return (*e).into(),
+                            Err(reason) => reason,
+                        };
+                        let reason = match reason.downcast::<PermissionDenied>() {
+                            Ok(e) => return (*e).into(),
+                            Err(reason) => reason,
+                        };
+                        match reason.downcast::<ImportError>() {
+                            Ok(e) => (*e).into(),
+                            // 理由が文言だけの時は、入力が誤っていたものとして扱う
+                            Err(reason) => PresentationError::new(ErrorKind::Validation, reason),
+                        }
+                    }
+                    DomainError::Storage(_) => PresentationError::new(ErrorKind::Internal, "internal error"),
+                }
+            }
+        }
+
+        impl From<StatusError> for PresentationError {
+            fn from(e: StatusError) -> PresentationError {
+                PresentationError::localizable(ErrorKind::Conflict, &e)
+            }
+        }
+
+        impl From<EmailTaken> for PresentationError {
+            fn from(e: EmailTaken) -> PresentationError {
+                PresentationError::new(ErrorKind::Conflict, &e).with_field("email", e)
+            }
+        }
+
+        impl From<NameTaken> for PresentationError {
+            fn from(e: NameTaken) -> PresentationError {
+                PresentationError::new(ErrorKind::Conflict, &e).with_field("name", e)
+            }
+        }
+
+        /// 行ごとの誤りはそのまま見せる
+        impl From<ImportError> for PresentationError {
+            fn from(e: ImportError) -> PresentationError {
+                PresentationError::new(ErrorKind::Validation, e)
+            }
+        }
+
+        impl From<PermissionDenied> for PresentationError {
+            fn from(e: PermissionDenied) -> PresentationError {
+                PresentationError::new(ErrorKind::Forbidden, e)
+            }
+        }
+
+        impl From<AuthenticationError> for PresentationError {
+            fn from(e: AuthenticationError) -> PresentationError {
+                match e {
+                    AuthenticationError::RateLimited { .. } => PresentationError::new(ErrorKind::RateLimited, e),
+                    _ => PresentationError::new(ErrorKind::Unauthenticated, e),
+                }
+            }
+        }
+
+        /// ユースケースが返したエラーの型を見て種類を決める。知らない型のエラーはInternalにする。
+        impl From<Error> for PresentationError {
+            fn from(e: Error) -> PresentationError {
+                let e = match e.downcast::<PresentationError>() {
+                    Ok(e) => return e,
+                    Err(e) => e,
+                };
+                let e = match e.downcast::<DomainError>() {
+                    Ok(e) => return e.into(),
+                    Err(e) => e,
+                };
+                if let Some(e) = e.downcast_ref::<ValidationError>() {
+                    return e.clone().into();
+                }
+                if let Some(e) = e.downcast_ref::<StorageError>() {
+                    return e.clone().into();
+                }
+                if let Some(e) = e.downcast_ref::<StatusError>() {
+                    return e.clone().into();
+                }
+                if let Some(e) = e.downcast_ref::<EmailTaken>() {
+                    return e.clone().into();
+                }
+                if let Some(e) = e.downcast_ref::<NameTaken>() {
+                    return e.clone().into();
+                }
+                if let Some(e) = e.downcast_ref::<PermissionDenied>() {
+                    return e.clone().into();
+                }
+                if let Some(e) = e.downcast_ref::<ImportError>() {
+                    return e.clone().into();
+                }
+                if let Some(e) = e.downcast_ref::<AuthenticationError>() {
+                    return (*e).into();
+                }
+                PresentationError::new(ErrorKind::Internal, "internal error")
+            }
+        }
+    }
+
+    pub mod signup_region {
+        use component::geoip::{GeoIpComponent, HaveGeoIpComponent};
+        use entity::profile::Profile;
+        use entity::user::UserId;
+        use repository::DomainError;
+        use repository::Repository;
+        use repository::profiles::{HaveProfileRepository, ProfileRepository};
+        use std::net::IpAddr;
+        use usecase::dto::ProfileDto;
+        use usecase::{Interactor, UseCase};
+
+        /// 登録した時のIPアドレスから引いた国・都市をプロフィールに記録する。
+        /// プロフィールがまだ無ければ作る。場所が引けなかった時は空のまま記録する。
+        pub trait RecordSignupRegion: HaveGeoIpComponent + HaveProfileRepository {
+            fn record_signup_region(&self, user_id: UserId, ip: IpAddr) -> Result<Profile, DomainError> {
+                let location = self.geo_ip_component().lookup(ip)?;
+                if self.profile_repository().get(user_id.clone()).is_err() {
+                    self.profile_repository().create_profile(user_id.clone())?;
+                }
+                let profile = self.profile_repository().edit_profile(user_id, |profile| {
+                    profile.signup_country = location.as_ref().and_then(|l| l.country.clone());
+                    profile.signup_city = location.and_then(|l| l.city);
+                })?;
+                Ok(profile)
+            }
+        }
+
+        impl<T: HaveGeoIpComponent + HaveProfileRepository> RecordSignupRegion for T {}
+
+        /// 登録したユーザーと、登録の要求を送ってきたIPアドレス
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct SignupOrigin {
+            pub user_id: UserId,
+            pub ip: IpAddr,
+        }
+
+        /// RecordSignupRegionをUseCaseとして実行する
+        pub struct RecordSignupRegionInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: RecordSignupRegion> RecordSignupRegionInteractor<'a, W> {
+            pub fn new(world: &'a W) -> RecordSignupRegionInteractor<'a, W> {
+                RecordSignupRegionInteractor { world }
+            }
+        }
+
+        impl<'a, W: RecordSignupRegion> UseCase for RecordSignupRegionInteractor<'a, W> {
+            type Input = SignupOrigin;
+            type Output = ProfileDto;
+            type Error = DomainError;
+            fn execute(&mut self, input: SignupOrigin) -> Result<ProfileDto, DomainError> {
+                self.world.record_signup_region(input.user_id, input.ip).map(|profile| ProfileDto::from(&profile))
+            }
+        }
+
+        impl<'a, W: RecordSignupRegion> Interactor for RecordSignupRegionInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "record_signup_region";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod update_email {
+        use chrono::prelude::*;
+        use component::trace::{HaveTracingComponent, TracingComponent};
+        use entity::user::{Email, UserId};
+        use repository::DomainError;
+        use repository::users::{HaveUserCommands, HaveUserQueries, UserCommands, UserQueries};
+        use service::unique_email::{HaveUniqueEmailService, UniqueEmailService};
+        use usecase::{Interactor, UseCase};
+
+        /// メールアドレスを変更した結果。画面等にはUserではなくこれを返す。
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+        pub struct EmailChange {
+            pub user_id: UserId,
+            pub old_email: Email,
+            pub new_email: Email,
+            pub update_time: DateTime<Utc>,
+        }
+
+        /// メールアドレスを変更する。他のユーザーが使っているアドレスには変更できない。
+        pub trait UpdateEmail: HaveUserCommands + HaveUserQueries + HaveUniqueEmailService + HaveTracingComponent {
+            fn update_email(&self, id: UserId, email: &str) -> Result<EmailChange, DomainError> {
+                let _span = self.tracing_component().start_span("usecase.update_email", &[]);
+                let email = Email::parse(email)?;
+                let user = self.user_queries().get(id)?;
+                // 今と同じアドレスへの変更は許す
+                self.unique_email_service().ensure_email_available(&email, Some(&user.id))?;
+                let updated = self.user_commands().change_email(user.id, email)?;
+                Ok(EmailChange {
+                    user_id: updated.id,
+                    old_email: user.email,
+                    new_email: updated.email,
+                    update_time: updated.update_time,
+                })
+            }
+        }
+
+        impl<T> UpdateEmail for T where
+            T: HaveUserCommands + HaveUserQueries + HaveUniqueEmailService + HaveTracingComponent
+        {
+        }
+
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct EmailUpdate {
+            pub id: UserId,
+            pub email: String,
+        }
+
+        /// UpdateEmailをUseCaseとして実行する
+        pub struct UpdateEmailInteractor<'a, W: 'a> {
+            world: &'a W,
+        }
+
+        impl<'a, W: UpdateEmail> UpdateEmailInteractor<'a, W> {
+            pub fn new(world: &'a W) -> UpdateEmailInteractor<'a, W> {
+                UpdateEmailInteractor { world }
+            }
+        }
+
+        impl<'a, W: UpdateEmail> UseCase for UpdateEmailInteractor<'a, W> {
+            type Input = EmailUpdate;
+            type Output = EmailChange;
+            type Error = DomainError;
+            fn execute(&mut self, input: EmailUpdate) -> Result<EmailChange, DomainError> {
+                self.world.update_email(input.id, &input.email)
+            }
+        }
+
+        impl<'a, W: UpdateEmail> Interactor for UpdateEmailInteractor<'a, W> {
+            type World = W;
+            const NAME: &'static str = "update_email";
+            fn world(&self) -> &W {
+                self.world
+            }
+        }
+    }
+
+    pub mod user_events {
+        //! Userのドメインイベントの購読者。
+        //! Repositoryは変更を保存してイベントを発行するだけで、検索の索引の更新・通知・他のシステムへの送信はここで行う。
+
+        use component::event_bus::{EventBusComponent, HaveEventBusComponent};
+        use component::notification::{HaveNotificationComponent, NotificationComponent};
+        use component::queue::{HaveMessageQueueComponent, MessageQueueComponent};
+        use component::search::{HaveSearchComponent, SearchComponent};
+        use component::webhook::{HaveWebhookComponent, WebhookComponent};
+        use entity::user::{User, UserEvent};
+        use failure::Error;
+        use serde_json;
+        use usecase::dto::UserEventDto;
+
+        /// Userのドメインイベントを流すトピック
+        pub const USER_EVENTS_TOPIC: &str = "user-events";
+
+        /// 検索の索引に入れる本文。名前とメールアドレスで探せるようにする。
+        pub fn search_text(user: &User) -> String {
+            format!("{} {}", user.name, user.email)
+        }
+
+        /// 無効化されたユーザーは検索に出さない
+        pub fn update_search_index<W: HaveSearchComponent>(
+            world: &W,
+            user: &User,
+            event: UserEvent,
+        ) -> Result<(), Error> {
+            let id = user.id.as_uuid().to_string();
+            match event {
+                UserEvent::Deactivated => world.search_component().remove(&id),
+                _ => world.search_component().index(&id, &search_text(user)),
+            }
+        }
+
+        pub fn notify_user<W: HaveNotificationComponent>(
+            world: &W,
+            user: &User,
+            event: UserEvent,
+        ) -> Result<(), Error> {
+            world.notification_component().notify(user, &event.to_string())
+        }
+
+        pub fn publish_to_queue<W: HaveMessageQueueComponent>(
+            world: &W,
+            user: &User,
+            event: UserEvent,
+        ) -> Result<(), Error> {
+            let payload = serde_json::to_vec(&UserEventDto::new(user, event))?;
+            world.message_queue_component().publish(USER_EVENTS_TOPIC, &payload)
+        }
+
+        /// キューに流すのと同じ形の本文を送る
+        pub fn deliver_webhook<W: HaveWebhookComponent>(world: &W, user: &User, event: UserEvent) -> Result<(), Error> {
+            let payload = serde_json::to_value(UserEventDto::new(user, event))?;
+            world.webhook_component().deliver(&payload)
+        }
+
+        pub trait SubscribeUserEvents:
+            HaveEventBusComponent
+            + HaveSearchComponent
+            + HaveNotificationComponent
+            + HaveMessageQueueComponent
+            + HaveWebhookComponent
+            + 'static
+        {
+            /// 購読者を登録する。起動時に1回呼ぶ。
+            fn subscribe_user_events(&self) {
+                let bus = self.event_bus_component();
+                bus.subscribe("search_index", Box::new(update_search_index::<Self>));
+                bus.subscribe("notification", Box::new(notify_user::<Self>));
+                bus.subscribe("queue", Box::new(publish_to_queue::<Self>));
+                bus.subscribe("webhook", Box::new(deliver_webhook::<Self>));
+            }
+        }
+
+        impl<T> SubscribeUserEvents for T where
+            T: HaveEventBusComponent
+                + HaveSearchComponent
+                + HaveNotificationComponent
+                + HaveMessageQueueComponent
+                + HaveWebhookComponent
+                + 'static
+        {
+        }
+    }
+}
+
+mod entity {
+    //! 一意性を持つデータを抽象化するレイヤ。
+    //! 一意性を持たない場合は値として扱い、entityにはしない（数値の1とか文字列とかと同じ扱いにする）
+
+    use std::error;
+    use std::fmt::{self, Debug};
+
+    /// 一意性を持つデータが実装(impl)するtrait。`id()` がその一意性を表す。
+    pub trait Entity {
+        type Id: Ord + Clone + Debug;
+        fn id(&self) -> Self::Id;
+
+        /// 楽観的排他制御に使うバージョン。バージョンを持たないEntityはNoneのまま。
+        fn version(&self) -> Option<u64> {
+            None
+        }
+
+        fn set_version(&mut self, _version: u64) {}
+    }
+
+    /// 値オブジェクトの生成時に検証で弾かれた時のエラー。
+    /// std::error::Errorを実装(impl)しておけば `?` でfailure::Errorに変換できる。
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum ValidationError {
+        /// 空白だけの値。持っているのは入力のフィールド名
+        Empty(&'static str),
+        InvalidEmail(String),
+        MissingField(&'static str),
+        InvalidAddress(&'static str),
+        InvalidPhoneNumber(String),
+        TooLong { field: &'static str, max: usize },
+        /// 環境毎に登録された検証のルールを破った
+        RuleViolated(String),
+    }
+
+    impl fmt::Display for ValidationError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                ValidationError::Empty(field) => write!(f, "{} must not be empty", field),
+                ValidationError::InvalidEmail(ref email) => write!(f, "invalid email address: {}", email),
+                ValidationError::MissingField(field) => write!(f, "missing required field: {}", field),
+                ValidationError::InvalidAddress(field) => write!(f, "invalid address: {}", field),
+                ValidationError::InvalidPhoneNumber(ref number) => write!(f, "invalid phone number: {}", number),
+                ValidationError::TooLong { field, max } => write!(f, "{} must be at most {} characters", field, max),
+                ValidationError::RuleViolated(ref rule) => write!(f, "violates validation rule: {}", rule),
+            }
+        }
+    }
+
+    impl error::Error for ValidationError {}
+
+    impl ValidationError {
+        /// 入力のどのフィールドの誤りか。環境毎のルールはEntity全体に対するものなのでNone
+        pub fn field(&self) -> Option<&'static str> {
+            match *self {
+                ValidationError::Empty(field) => Some(field),
+                ValidationError::InvalidEmail(_) => Some("email"),
+                ValidationError::MissingField(field) => Some(field),
+                ValidationError::InvalidAddress(_) => Some("address"),
+                ValidationError::InvalidPhoneNumber(_) => Some("phone_number"),
+                ValidationError::TooLong { field, .. } => Some(field),
+                ValidationError::RuleViolated(_) => None,
+            }
+        }
+    }
+
+    /// 文字列1つを包む値オブジェクトを定義する。
+    ///
+    /// * `value_object!(Name: String, max_len = 64, field = "name")`: 空白だけの値と `max_len` 文字を超える値を弾く
+    ///   `new` を持つ。どちらのエラーにも `field` を入力のフィールド名として入れる
+    /// * `value_object!(Email: String, parse = normalize_email)`: 検証して正規化した文字列を返す関数を使う `parse` を持つ
+    ///
+    /// どちらもフィールドは非公開で、`as_str`, Display, FromStr, serde と比較・順序・Hashのtraitを実装(impl)する。
+    /// FromStrはコンストラクタと同じ検証を通るので、`"user_a".parse::<Name>()` でも不正な値は作れない。
+    macro_rules! value_object {
+        (@common $name:ident, $ctor:ident) => {
+            impl $name {
+                pub fn as_str(&self) -> &str {
+                    &self.value
+                }
+            }
+
+            impl ::std::fmt::Display for $name {
+                fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                    f.write_str(&self.value)
+                }
+            }
+
+            impl ::std::str::FromStr for $name {
+                type Err = $crate::entity::ValidationError;
+                fn from_str(s: &str) -> Result<$name, $crate::entity::ValidationError> {
+                    $name::$ctor(s)
+                }
+            }
+
+            impl ::serde::Serialize for $name {
+                fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serializer.serialize_str(&self.value)
+                }
+            }
+
+            /// 読み込んだ値もコンストラクタと同じ検証を通す
+            impl<'de> ::serde::Deserialize<'de> for $name {
+                fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<$name, D::Error> {
+                    let value = <String as ::serde::Deserialize>::deserialize(deserializer)?;
+                    $name::$ctor(&value).map_err(::serde::de::Error::custom)
+                }
+            }
+        };
+        ($(#[$attr:meta])* $name:ident: String, max_len = $max:expr, field = $field:expr) => {
+            $(#[$attr])*
+            #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+            pub struct $name {
+                value: String,
+            }
+
+            impl $name {
+                pub fn new(value: &str) -> Result<$name, $crate::entity::ValidationError> {
+                    if value.trim().is_empty() {
+                        return Err($crate::entity::ValidationError::Empty($field));
+                    }
+                    if value.chars().count() > $max {
+                        return Err($crate::entity::ValidationError::TooLong {
+                            field: $field,
+                            max: $max,
+                        });
+                    }
+                    Ok($name {
+                        value: value.to_string(),
+                    })
+                }
+            }
+
+            value_object!(@common $name, new);
+        };
+        ($(#[$attr:meta])* $name:ident: String, parse = $parse:path) => {
+            $(#[$attr])*
+            #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+            pub struct $name {
+                value: String,
+            }
+
+            impl $name {
+                pub fn parse(value: &str) -> Result<$name, $crate::entity::ValidationError> {
+                    Ok($name { value: $parse(value)? })
+                }
+            }
+
+            value_object!(@common $name, parse);
+        };
+    }
+
+    pub mod address {
+        //! 値オブジェクトの中に値オブジェクトを持つ例。
+        //! Addressは一意性を持たないのでEntityにはせず、Userが値として持つ。
+
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+        use serde::de::Error;
+        use super::ValidationError;
+
+        /// 住所。国・地域・郵便番号だけを扱う。
+        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct Address {
+            country: CountryCode,
+            region: String,
+            postal_code: PostalCode,
+        }
+
+        impl Address {
+            pub fn new(country: &str, region: &str, postal_code: &str) -> Result<Address, ValidationError> {
+                let region = region.trim();
+                if region.is_empty() {
+                    return Err(ValidationError::InvalidAddress("region"));
+                }
+                Ok(Address {
+                    country: CountryCode::new(country)?,
+                    region: region.to_string(),
+                    postal_code: PostalCode::new(postal_code)?,
+                })
+            }
+
+            pub fn country(&self) -> &CountryCode {
+                &self.country
+            }
+
+            pub fn region(&self) -> &str {
+                &self.region
+            }
+
+            pub fn postal_code(&self) -> &PostalCode {
+                &self.postal_code
+            }
+        }
+
+        /// 保存する時の形。読み込む時は `Address::new` で検証し直す。
+        #[derive(Serialize, Deserialize)]
+        struct AddressRecord {
+            country: String,
+            region: String,
+            postal_code: String,
+        }
+
+        impl Serialize for Address {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                AddressRecord {
+                    country: self.country.as_str().to_string(),
+                    region: self.region.clone(),
+                    postal_code: self.postal_code.as_str().to_string(),
+                }
+                .serialize(serializer)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for Address {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Address, D::Error> {
+                let record = AddressRecord::deserialize(deserializer)?;
+                Address::new(&record.country, &record.region, &record.postal_code).map_err(D::Error::custom)
+            }
+        }
+
+        /// ISO 3166-1 alpha-2 の国コード。大文字に揃えて持つ。
+        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct CountryCode {
+            code: String,
+        }
+
+        impl CountryCode {
+            pub fn new(code: &str) -> Result<CountryCode, ValidationError> {
+                let code = code.trim();
+                if code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+                    return Err(ValidationError::InvalidAddress("country"));
+                }
+                Ok(CountryCode {
+                    code: code.to_ascii_uppercase(),
+                })
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.code
+            }
+        }
+
+        /// 郵便番号。書式は国によって違うので、英数字・空白・ハイフンだけで出来ている事だけを確認する。
+        #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct PostalCode {
+            code: String,
+        }
+
+        impl PostalCode {
+            pub fn new(code: &str) -> Result<PostalCode, ValidationError> {
+                let code = code.trim();
+                let valid_chars = code.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-');
+                if code.is_empty() || code.len() > 10 || !valid_chars {
+                    return Err(ValidationError::InvalidAddress("postal_code"));
+                }
+                Ok(PostalCode {
+                    code: code.to_string(),
+                })
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.code
+            }
+        }
+    }
+
+    pub mod phone_number {
+        use super::ValidationError;
+
+        value_object! {
+            /// E.164形式(`+` と国番号から始まる最大15桁の数字)に正規化した電話番号。
+            /// 国番号が分からないと正規化できないので、国内向けの書き方(`03-...` 等)は受け付けない。
+            PhoneNumber: String, parse = normalize
+        }
+
+        /// 空白・ハイフン・ピリオド・括弧は読み飛ばし、先頭の `00` は `+` と同じに扱う
+        fn normalize(input: &str) -> Result<String, ValidationError> {
+            let invalid = || ValidationError::InvalidPhoneNumber(input.to_string());
+            let trimmed = input.trim();
+            let rest = match trimmed.strip_prefix('+').or_else(|| trimmed.strip_prefix("00")) {
+                Some(rest) => rest,
+                None => return Err(invalid()),
+            };
+            let mut digits = String::new();
+            for c in rest.chars() {
+                match c {
+                    '0'..='9' => digits.push(c),
+                    ' ' | '-' | '.' | '(' | ')' => {}
+                    _ => return Err(invalid()),
+                }
+            }
+            if digits.len() < 8 || digits.len() > 15 || digits.starts_with('0') {
+                return Err(invalid());
+            }
+            Ok(format!("+{}", digits))
+        }
+    }
+
+    pub mod api_token {
+        use chrono::prelude::*;
+        use entity::credentials::PasswordHash;
+        use entity::user::UserId;
+        use std::collections::BTreeSet;
+        use super::Entity;
+        use uuid::Uuid;
+
+        /// APIトークンで許可する操作の範囲
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub enum Scope {
+            ReadUsers,
+            WriteUsers,
+            Admin,
+        }
+
+        /// 機械的なクライアントが使うAPIトークン。トークンそのものではなく、そのハッシュを持つ。
+        #[derive(Debug, Clone)]
+        pub struct ApiToken {
+            pub id: ApiTokenId,
+            pub token_hash: PasswordHash,
+            pub owner: UserId,
+            pub scopes: BTreeSet<Scope>,
+            pub create_time: DateTime<Utc>,
+            pub expires_at: Option<DateTime<Utc>>,
+        }
+
+        impl ApiToken {
+            pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+                self.expires_at.map(|expires_at| expires_at <= now).unwrap_or(false)
+            }
+        }
+
+        impl Entity for ApiToken {
+            type Id = ApiTokenId;
+            fn id(&self) -> ApiTokenId {
+                self.id.clone()
+            }
+        }
+
+        #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+        pub struct ApiTokenId {
+            id: Uuid,
+        }
+
+        impl ApiTokenId {
+            pub fn new(id: Uuid) -> ApiTokenId {
+                ApiTokenId { id }
+            }
+
+            pub fn as_uuid(&self) -> &Uuid {
+                &self.id
+            }
+        }
+    }
+
+    pub mod invitation {
+        use chrono::prelude::*;
+        use entity::credentials::PasswordHash;
+        use entity::user::{Email, Role, UserId};
+        use super::Entity;
+        use uuid::Uuid;
+
+        /// まだ受け入れられていない招待。受け入れられるとUserになり、招待は消える。
+        /// 招待用のトークンそのものではなく、そのハッシュを持つ。
+        #[derive(Debug, Clone)]
+        pub struct Invitation {
+            pub id: InvitationId,
+            pub email: Email,
+            pub role: Role,
+            pub invited_by: UserId,
+            pub token_hash: PasswordHash,
+            pub create_time: DateTime<Utc>,
+            pub expires_at: DateTime<Utc>,
+        }
+
+        impl Invitation {
+            pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+                self.expires_at <= now
+            }
+        }
+
+        impl Entity for Invitation {
+            type Id = InvitationId;
+            fn id(&self) -> InvitationId {
+                self.id.clone()
+            }
+        }
+
+        #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+        pub struct InvitationId {
+            id: Uuid,
+        }
+
+        impl InvitationId {
+            pub fn new(id: Uuid) -> InvitationId {
+                InvitationId { id }
+            }
+
+            pub fn as_uuid(&self) -> &Uuid {
+                &self.id
+            }
+        }
+    }
+
+    pub mod job {
+        use chrono::prelude::*;
+        use entity::user::UserId;
+        use super::Entity;
+        use uuid::Uuid;
+
+        /// リクエストの外で後から行う仕事。何をするかだけを持ち、やり方はusecaseが決める。
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+        #[serde(tag = "kind", rename_all = "snake_case")]
+        pub enum JobKind {
+            /// 登録したユーザーに歓迎のメールを送る
+            SendWelcomeMail { user_id: UserId },
+            /// 全ユーザーを検索の索引に入れ直す
+            RebuildSearchIndex,
+        }
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        pub enum JobStatus {
+            /// `run_at` を過ぎたら取り出せる
+            Pending,
+            /// ワーカーが取り出して実行している
+            Running,
+            /// 何度やり直しても失敗したので、もう実行しない
+            Poisoned,
+        }
+
+        /// キューに積まれたジョブ。終わったジョブはキューから消える。
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+        pub struct Job {
+            pub id: JobId,
+            pub kind: JobKind,
+            pub status: JobStatus,
+            /// 失敗した回数
+            pub attempts: u32,
+            /// これより前には実行しない
+            pub run_at: DateTime<Utc>,
+            pub last_error: Option<String>,
+            pub create_time: DateTime<Utc>,
+        }
+
+        impl Job {
+            pub fn new(id: JobId, kind: JobKind, now: DateTime<Utc>) -> Job {
+                Job {
+                    id,
+                    kind,
+                    status: JobStatus::Pending,
+                    attempts: 0,
+                    run_at: now,
+                    last_error: None,
+                    create_time: now,
+                }
+            }
+        }
+
+        impl Entity for Job {
+            type Id = JobId;
+            fn id(&self) -> JobId {
+                self.id.clone()
+            }
+        }
+
+        #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+        #[serde(transparent)]
+        pub struct JobId {
+            id: Uuid,
+        }
+
+        impl JobId {
+            pub fn new(id: Uuid) -> JobId {
+                JobId { id }
+            }
+        }
+    }
+
+    pub mod password_reset {
+        use chrono::prelude::*;
+        use entity::credentials::PasswordHash;
+        use entity::user::UserId;
+        use super::Entity;
+        use uuid::Uuid;
+
+        /// パスワード再設定用のトークン。APIトークンと同じく、トークンそのものではなくハッシュを持つ。
+        #[derive(Debug, Clone)]
+        pub struct PasswordResetToken {
+            pub id: PasswordResetId,
+            pub user_id: UserId,
+            pub token_hash: PasswordHash,
+            pub expires_at: DateTime<Utc>,
+        }
+
+        impl PasswordResetToken {
+            pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+                self.expires_at <= now
+            }
+        }
+
+        impl Entity for PasswordResetToken {
+            type Id = PasswordResetId;
+            fn id(&self) -> PasswordResetId {
+                self.id.clone()
+            }
+        }
+
+        #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+        pub struct PasswordResetId {
+            id: Uuid,
+        }
+
+        impl PasswordResetId {
+            pub fn new(id: Uuid) -> PasswordResetId {
+                PasswordResetId { id }
+            }
+
+            pub fn as_uuid(&self) -> &Uuid {
+                &self.id
+            }
+        }
+    }
+
+    pub mod credentials {
+        use entity::user::UserId;
+        use std::fmt;
+        use super::Entity;
+
+        /// ハッシュ化済みのパスワード。
+        /// 形式はPasswordHasherComponentの実装が決めるので、ここでは中身を解釈しない。
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct PasswordHash {
+            hash: String,
+        }
+
+        impl PasswordHash {
+            pub fn new(hash: String) -> PasswordHash {
+                PasswordHash { hash }
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.hash
+            }
+        }
+
+        /// 利用者が入力した平文のパスワード。ログ等に出ないように、Debugでは中身を伏せる。
+        #[derive(Clone, PartialEq, Eq)]
+        pub struct PlainPassword {
+            value: String,
+        }
+
+        impl PlainPassword {
+            pub fn new(value: &str) -> PlainPassword {
+                PlainPassword {
+                    value: value.to_string(),
+                }
+            }
+
+            pub fn expose(&self) -> &str {
+                &self.value
+            }
+        }
+
+        impl fmt::Debug for PlainPassword {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("PlainPassword(***)")
+            }
+        }
+
+        /// ユーザー1人分の認証情報。ユーザー1人に対して1つなので、UserIdで識別する。
+        #[derive(Debug, Clone)]
+        pub struct Credentials {
+            pub user_id: UserId,
+            pub password_hash: PasswordHash,
+        }
+
+        impl Entity for Credentials {
+            type Id = UserId;
+            fn id(&self) -> UserId {
+                self.user_id.clone()
+            }
+        }
+    }
+
+    pub mod group {
+        use chrono::prelude::*;
+        use entity::user::UserId;
+        use std::collections::BTreeSet;
+        use super::Entity;
+
+        /// ユーザーの集まり
+        #[derive(Debug, Clone)]
+        pub struct Group {
+            pub name: GroupName,
+            pub members: BTreeSet<UserId>,
+            pub create_time: DateTime<Utc>,
+        }
+
+        impl Group {
+            pub fn new(name: GroupName, create_time: DateTime<Utc>) -> Group {
+                Group {
+                    name,
+                    members: BTreeSet::new(),
+                    create_time,
+                }
+            }
+
+            pub fn has_member(&self, user_id: &UserId) -> bool {
+                self.members.contains(user_id)
+            }
+        }
+
+        impl Entity for Group {
+            type Id = GroupName;
+            fn id(&self) -> GroupName {
+                self.name.clone()
+            }
+        }
+
+        value_object!(GroupName: String, max_len = 64, field = "group_name");
+    }
+
+    pub mod profile {
+        use chrono::prelude::*;
+        use entity::user::UserId;
+        use super::Entity;
+
+        /// ユーザーの表示用の情報。
+        /// ログインや権限に関わるデータはUserに残し、こちらには画面に出すだけのデータを置く。
+        #[derive(Debug, Clone)]
+        pub struct Profile {
+            pub user_id: UserId,
+            pub bio: String,
+            pub avatar_url: Option<String>,
+            pub locale: String,
+            /// 登録した時のIPアドレスから引いた国コード
+            pub signup_country: Option<String>,
+            /// 登録した時のIPアドレスから引いた都市名
+            pub signup_city: Option<String>,
+            pub update_time: DateTime<Utc>,
+        }
+
+        impl Profile {
+            pub fn new(user_id: UserId, now: DateTime<Utc>) -> Profile {
+                Profile {
+                    user_id,
+                    bio: String::new(),
+                    avatar_url: None,
+                    locale: "ja-JP".to_string(),
+                    signup_country: None,
+                    signup_city: None,
+                    update_time: now,
+                }
+            }
+        }
+
+        /// Userと1対1なので、IDにはUserIdをそのまま使う
+        impl Entity for Profile {
+            type Id = UserId;
+            fn id(&self) -> UserId {
+                self.user_id.clone()
+            }
+        }
+    }
+
+    pub mod session {
+        use chrono::prelude::*;
+        use entity::user::UserId;
+        use super::Entity;
+        use uuid::Uuid;
+
+        /// ログインしているユーザー1人分のセッション
+        #[derive(Debug, Clone)]
+        pub struct Session {
+            pub id: SessionId,
+            pub user_id: UserId,
+            pub create_time: DateTime<Utc>,
+            pub expires_at: DateTime<Utc>,
+        }
+
+        impl Session {
+            /// 有効期限ちょうどの時刻から期限切れとして扱う
+            pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+                self.expires_at <= now
+            }
+        }
+
+        impl Entity for Session {
+            type Id = SessionId;
+            fn id(&self) -> SessionId {
+                self.id.clone()
+            }
+        }
+
+        #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+        pub struct SessionId {
+            id: Uuid,
+        }
+
+        impl SessionId {
+            pub fn new(id: Uuid) -> SessionId {
+                SessionId { id }
+            }
+
+            pub fn as_uuid(&self) -> &Uuid {
+                &self.id
+            }
+        }
+    }
+
+    pub mod user {
+        use chrono::prelude::*;
+        use entity::address::Address;
+        use entity::phone_number::PhoneNumber;
+        use std::error;
+        use std::fmt;
+        use super::{Entity, ValidationError};
+        use uuid::Uuid;
+
+        /// アカウント1つを表す型
+        #[derive(Debug, Clone, Serialize, Deserialize)]
+        pub struct User {
+            pub id: UserId,
+            pub name: Name,
+            pub email: Email,
+            pub role: Role,
+            pub status: UserStatus,
+            pub address: Option<Address>,
+            pub phone_number: Option<PhoneNumber>,
+            pub create_time: DateTime<Utc>,
+            pub update_time: DateTime<Utc>,
+            /// 作成時は1で、Repositoryで更新する度に1つ上がる
+            pub version: u64,
+        }
+
+        impl User {
+            pub fn builder() -> UserBuilder {
+                UserBuilder::default()
+            }
+
+            pub fn permissions(&self) -> &'static [Permission] {
+                self.role.permissions()
+            }
+
+            pub fn can(&self, permission: Permission) -> bool {
+                self.permissions().contains(&permission)
+            }
+
+            pub fn is_active(&self) -> bool {
+                self.status == UserStatus::Active
+            }
+
+            /// IDに加えて全てのフィールドが同じかどうか。
+            /// `==` はIDだけを比べるので、保存した値が書き換わっていないかを見たい時はこちらを使う。
+            pub fn same_state_as(&self, other: &User) -> bool {
+                self.id == other.id
+                    && self.name == other.name
+                    && self.email == other.email
+                    && self.role == other.role
+                    && self.status == other.status
+                    && self.address == other.address
+                    && self.phone_number == other.phone_number
+                    && self.create_time == other.create_time
+                    && self.update_time == other.update_time
+                    && self.version == other.version
+            }
+        }
+
+        /// Entityは値ではなく一意性で区別するので、IDが同じなら中身が違っても同じUserとして扱う
+        impl PartialEq for User {
+            fn eq(&self, other: &User) -> bool {
+                self.id == other.id
+            }
+        }
+
+        impl Eq for User {}
+
+        /// アカウントに起きた変更。Repositoryが変更を保存した後に発行する。
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum UserEvent {
+            Created,
+            RoleChanged(Role),
+            Suspended,
+            Reactivated,
+            Deactivated,
+            Renamed,
+            EmailChanged,
+        }
+
+        impl UserEvent {
+            /// メトリクス等で使う、種類ごとの短い名前
+            pub fn kind(&self) -> &'static str {
+                match *self {
+                    UserEvent::Created => "created",
+                    UserEvent::RoleChanged(_) => "role_changed",
+                    UserEvent::Suspended => "suspended",
+                    UserEvent::Reactivated => "reactivated",
+                    UserEvent::Deactivated => "deactivated",
+                    UserEvent::Renamed => "renamed",
+                    UserEvent::EmailChanged => "email_changed",
+                }
+            }
+        }
+
+        impl fmt::Display for UserEvent {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match *self {
+                    UserEvent::Created => write!(f, "user created"),
+                    UserEvent::RoleChanged(role) => write!(f, "role changed to {:?}", role),
+                    UserEvent::Suspended => write!(f, "user suspended"),
+                    UserEvent::Reactivated => write!(f, "user reactivated"),
+                    UserEvent::Deactivated => write!(f, "user deactivated"),
+                    UserEvent::Renamed => write!(f, "user renamed"),
+                    UserEvent::EmailChanged => write!(f, "email changed"),
+                }
+            }
+        }
+
+        /// アカウントの状態。作成直後はActive。
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+        pub enum UserStatus {
+            #[default]
+            Active,
+            /// 管理者によって一時的に止められている
+            Suspended,
+            /// 本人の退会等で使われなくなった
+            Deactivated,
+        }
+
+        /// 今の状態ではできない操作をしようとした時のエラー
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum StatusError {
+            /// `action` はActiveなユーザーにしかできない
+            NotActive {
+                action: &'static str,
+                status: UserStatus,
+                id: UserId,
+            },
+            /// 既にその状態になっている
+            Already { status: UserStatus, id: UserId },
+            /// `action` は退会したユーザーにしかできない
+            NotDeactivated {
+                action: &'static str,
+                status: UserStatus,
+                id: UserId,
+            },
+        }
+
+        impl fmt::Display for StatusError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match *self {
+                    StatusError::NotActive { action, status, ref id } => {
+                        write!(f, "cannot {} {:?} user: {:?}", action, status, id)
+                    }
+                    StatusError::Already { status, ref id } => {
+                        write!(f, "user is already {}: {:?}", format!("{:?}", status).to_lowercase(), id)
+                    }
+                    StatusError::NotDeactivated { action, status, ref id } => {
+                        write!(f, "cannot {} {:?} user, deactivate it first: {:?}", action, status, id)
+                    }
+                }
+            }
+        }
+
+        impl error::Error for StatusError {}
+
+        /// ユーザーの役割。何が出来るかは役割ごとに決まる。
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+        pub enum Role {
+            Admin,
+            #[default]
+            Member,
+            Guest,
+        }
+
+        impl Role {
+            pub fn permissions(self) -> &'static [Permission] {
+                match self {
+                    Role::Admin => &[
+                        Permission::ReadProfile,
+                        Permission::UpdateOwnProfile,
+                        Permission::ListUsers,
+                        Permission::ManageUsers,
+                        Permission::ManageRoles,
+                    ],
+                    Role::Member => &[Permission::ReadProfile, Permission::UpdateOwnProfile, Permission::ListUsers],
+                    Role::Guest => &[Permission::ReadProfile],
+                }
+            }
+        }
+
+        /// 役割によって許可される操作
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub enum Permission {
+            ReadProfile,
+            UpdateOwnProfile,
+            ListUsers,
+            ManageUsers,
+            ManageRoles,
+        }
+
+        /// Userを組み立てる。作成日時・更新日時は `build()` に渡した時計から埋める。
+        /// entityは外側のレイヤに依存できないので、時計はTimeComponentではなくクロージャで受け取る。
+        /// roleを指定しなかった場合はMemberになる。
+        #[derive(Debug, Default)]
+        pub struct UserBuilder {
+            id: Option<UserId>,
+            name: Option<Name>,
+            email: Option<Email>,
+            role: Role,
+            address: Option<Address>,
+            phone_number: Option<PhoneNumber>,
+        }
+
+        impl UserBuilder {
+            pub fn id(mut self, id: UserId) -> UserBuilder {
+                self.id = Some(id);
+                self
+            }
+
+            pub fn name(mut self, name: Name) -> UserBuilder {
+                self.name = Some(name);
+                self
+            }
+
+            pub fn email(mut self, email: Email) -> UserBuilder {
+                self.email = Some(email);
+                self
+            }
+
+            pub fn role(mut self, role: Role) -> UserBuilder {
+                self.role = role;
+                self
+            }
+
+            pub fn address(mut self, address: Address) -> UserBuilder {
+                self.address = Some(address);
+                self
+            }
+
+            pub fn phone_number(mut self, phone_number: PhoneNumber) -> UserBuilder {
+                self.phone_number = Some(phone_number);
+                self
+            }
+
+            pub fn build<F: FnOnce() -> DateTime<Utc>>(self, clock: F) -> Result<User, ValidationError> {
+                let id = self.id.ok_or(ValidationError::MissingField("id"))?;
+                let name = self.name.ok_or(ValidationError::MissingField("name"))?;
+                let email = self.email.ok_or(ValidationError::MissingField("email"))?;
+                let now = clock();
+                Ok(User {
+                    id,
+                    name,
+                    email,
+                    role: self.role,
+                    status: UserStatus::Active,
+                    address: self.address,
+                    phone_number: self.phone_number,
+                    create_time: now,
+                    update_time: now,
+                    version: 1,
+                })
+            }
+        }
+
+        /// 名前は変わりうるので、ユーザーの一意性はUserIdで表す
+        impl Entity for User {
+            type Id = UserId;
+            fn id(&self) -> UserId {
+                self.id.clone()
+            }
+
+            fn version(&self) -> Option<u64> {
+                Some(self.version)
+            }
+
+            fn set_version(&mut self, version: u64) {
+                self.version = version;
+            }
+        }
+
+        #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+        #[serde(transparent)]
+        pub struct UserId {
+            id: Uuid,
+        }
+
+        impl UserId {
+            pub fn new(id: Uuid) -> UserId {
+                UserId { id }
+            }
+
+            pub fn as_uuid(&self) -> &Uuid {
+                &self.id
+            }
+        }
+
+        value_object! {
+            /// ユーザー名。空文字列と長すぎる名前は作れないように、フィールドは非公開にして `Name::new` 経由でだけ作る。
+            Name: String, max_len = 64, field = "name"
+        }
+
+        value_object! {
+            /// メールアドレス。`Email::parse` で `local@domain.tld` っぽい形をしている事を検証してから作る。
+            /// 前後の空白を除いて小文字に揃えるので、`User@Example.com` と `user@example.com` は同じEmailになる。
+            Email: String, parse = normalize_email
+        }
+
+        impl Email {
+            /// `@` より後ろの部分
+            pub fn domain(&self) -> &str {
+                self.as_str().rsplit('@').next().unwrap_or_default()
+            }
+        }
+
+        fn normalize_email(email: &str) -> Result<String, ValidationError> {
+            let invalid = || ValidationError::InvalidEmail(email.to_string());
+            let normalized = email.trim().to_lowercase();
+            if normalized.chars().any(char::is_whitespace) {
+                return Err(invalid());
+            }
+            let mut parts = normalized.split('@');
+            let (local, domain) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(local), Some(domain), None) => (local, domain),
+                _ => return Err(invalid()),
+            };
+            let labels: Vec<&str> = domain.split('.').collect();
+            if local.is_empty() || labels.len() < 2 || labels.iter().any(|l| l.is_empty()) {
+                return Err(invalid());
+            }
+            Ok(normalized)
+        }
+    }
+}
+
+mod env {
+    use chrono::Duration;
+    use component::cache::{CacheComponent, CachePolicy, CachingStorage, MemoryCache, RedisCache};
+    use component::config::{Config, ConfigComponent, HaveConfigComponent, NotifierKind};
+    use component::environment::{EnvironmentComponent, ProcessEnvironment};
+    use component::event_bus::{HaveEventBusComponent, SyncEventBus};
+    use component::feature_flag::{HaveFeatureFlagComponent, PercentageRollout};
+    use component::crypto::{AesGcmCrypto, HaveCryptoComponent};
+    use component::file::{EncryptedFields, FileStorage, JsonCodec, RecordCodec, UserRecordCodec};
+    use component::filesystem::{HaveFileSystemComponent, StdFileSystem};
+    use component::health::{self, HealthCheckComponent, HealthReport};
+    use component::http::ReqwestClient;
+    use component::id::{HaveIdGeneratorComponent, UuidGen};
+    use component::jobs::{HaveJobQueueComponent, StoredJobQueue};
+    use component::lock::{HaveLockComponent, InProcessLocks, LockComponent, LockToken, RedisLocks};
+    use component::log::{ConsoleLogger, HaveLoggingComponent, Level, LoggingComponent};
+    use component::mail::{HaveEmailSenderComponent, SmtpSender};
+    use component::metrics::{HaveMetricsComponent, NoopMetrics};
+    use component::nonblocking::BlockOn;
+    use component::notification::{
+        ConsoleNotifier, EmailNotifier, HaveNotificationComponent, NotificationComponent, WebhookNotifier,
+    };
+    use component::password::{Argon2Hasher, HavePasswordHasherComponent};
+    use component::pool::ConnectionPool;
+    use component::postgres::PostgresUserStorage;
+    #[cfg(feature = "kafka")]
+    use component::queue::KafkaQueue;
+    use component::queue::{HaveMessageQueueComponent, InMemoryQueue, MessageQueueComponent};
+    use component::random::{HaveRandomComponent, OsRandom};
+    use component::rate_limit::{HaveRateLimiterComponent, TokenBucket};
+    use component::rest::RestStorage;
+    use component::scheduler::{HaveSchedulerComponent, ThreadScheduler};
+    use component::search::{HaveSearchComponent, TantivySearch};
+    use component::secrets::{EnvSecrets, FileVault, Secret, SecretsComponent};
+    use component::template::{HandlebarsTemplates, HaveTemplateComponent};
+    use component::timeout::{Timeout, WithTimeout};
+    use component::time::{Chrono, HaveMonotonicTimeComponent, HaveTimeComponent, StdClock};
+    use component::trace::{HaveTracingComponent, TracingSpans};
+    use component::transaction::{Journaled, Participant, TransactionComponent};
+    use component::validation::{self, HavePasswordPolicyComponent, HaveValidationComponent, Rules};
+    use component::webhook::{HaveWebhookComponent, WebhookDispatcher};
+    use component::locale::{Catalogs, HaveLocaleComponent};
+    use component::geoip::{GeoIpComponent, HaveGeoIpComponent, Location, MaxMindGeoIp, NoGeoIp};
+    use component::storage::{
+        ConcurrentMemoryStorage, HaveApiTokenStorageComponent, HaveCredentialStorageComponent,
+        HaveGroupStorageComponent, HaveInvitationStorageComponent, HavePasswordResetStorageComponent,
+        HaveProfileStorageComponent, HaveSessionStorageComponent, HaveUserStorageComponent, MemoryStorage,
+        IndexedUserStorage, StorageComponent,
+    };
+    use entity::api_token::{ApiToken, ApiTokenId};
+    use entity::credentials::{Credentials, PlainPassword};
+    use entity::group::{Group, GroupName};
+    use entity::invitation::{Invitation, InvitationId};
+    use entity::job::{Job, JobId};
+    use entity::password_reset::{PasswordResetId, PasswordResetToken};
+    use entity::profile::Profile;
+    use entity::session::{Session, SessionId};
+    use entity::user::{Name, Permission, User, UserId};
+    use failure::Error;
+    use futures::channel::oneshot;
+    use futures::future::{self, BoxFuture, Shared};
+    use futures::{Future, FutureExt};
+    use repository::DomainError;
+    use repository::api_tokens::{ApiTokenRepository, HaveApiTokenRepository};
+    use repository::credentials::{CredentialRepository, HaveCredentialRepository};
+    use repository::groups::{GroupRepository, HaveGroupRepository};
+    use repository::invitations::{HaveInvitationRepository, InvitationRepository};
+    use repository::password_resets::{HavePasswordResetRepository, PasswordResetRepository};
+    use repository::profiles::{HaveProfileRepository, ProfileRepository};
+    use repository::sessions::{HaveSessionRepository, SessionRepository};
+    use repository::users::{HaveUserCommands, HaveUserQueries, UserCommands, UserQueries};
+    use service::unique_email::{HaveUniqueEmailService, UniqueEmailService};
+    use std::mem;
+    use std::net::IpAddr;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex, PoisonError};
+    use tokio::task::JoinHandle;
+    use usecase::{Decorate, UseCase};
+    use usecase::admin::{PurgeUserInteractor, RestoreUserInteractor, SuspendUserInteractor};
+    use usecase::manage_groups::{
+        AddGroupMemberInteractor, CreateGroupInteractor, Membership, RemoveGroupMemberInteractor,
+    };
+    use usecase::authenticate_user::{AuthenticateUserInteractor, LoginRequest};
+    use usecase::change_password::{ChangePasswordInteractor, PasswordChange};
+    use usecase::issue_api_token::{ApiTokenRequest, IssueApiTokenInteractor};
+    use usecase::delete_account::{ConfirmAccountDeletionInteractor, RequestAccountDeletionInteractor};
+    use usecase::dto::{GroupDto, InvitationDto, IssuedApiTokenDto, SessionDto, UserDto, UserSummaryDto};
+    use usecase::get_user::{GetUserByNameInteractor, GetUserInteractor, GetUsersInteractor};
+    use usecase::invite_user::{
+        AcceptInvitationInteractor, InvitationAcceptance, InviteUserInteractor, NewInvitation,
+    };
+    use usecase::password_reset::{
+        ConfirmPasswordResetInteractor, PasswordResetConfirmation, PasswordResetRequest,
+        RequestPasswordResetInteractor,
+    };
+    use usecase::jobs::WorkJobs;
+    use usecase::list_users::{ListUsersInteractor, ListUsersQuery, Page};
+    use usecase::register_user::{NewUser, RegisterUserInteractor};
+    use usecase::rename_user::{RenameUserInteractor, UserRename};
+    use usecase::search_users::{SearchUsers, SearchUsersInteractor, UserSearch};
+    use usecase::update_email::{EmailChange, EmailUpdate, UpdateEmailInteractor};
+    use usecase::user_events::SubscribeUserEvents;
+
+    /// 新しく設定するパスワードの最低の長さ
+    const MIN_PASSWORD_LENGTH: usize = 10;
+
+    /// 同じ相手が続けて試行できる回数
+    const RATE_LIMIT_CAPACITY: u32 = 5;
+
+    /// RealWorldで使うセッション用ストレージ。検証の度に読まれるのでキャッシュを挟む。
+    pub type SessionStorage = Journaled<
+        CachingStorage<MemoryStorage<SessionId, Session>, MemoryCache<SessionId, Session>, SessionId, Session>,
+        Session,
+    >;
+
+    /// RealWorldで使うユーザー用ストレージ。保存先が答えなくなっても、設定した時間で諦める。
+    pub type UserStorage = Journaled<
+        IndexedUserStorage<CachingStorage<Timeout<UserBackend>, UserCache, UserId, User>>,
+        User,
+    >;
+
+    /// RealWorldで使う認証情報用ストレージ
+    pub type CredentialStorage = Journaled<MemoryStorage<UserId, Credentials>, Credentials>;
+
+    /// 暗号化の鍵の秘密の値の名前
+    const ENCRYPTION_KEY: &str = "encryption_key";
+
+    /// ファイルに保存する時に暗号化する、ユーザーの個人情報のフィールド
+    const ENCRYPTED_USER_FIELDS: &[&str] = &["/user/email", "/user/address", "/user/phone_number"];
+
+    /// ユーザーの保存先。どれを使うかは設定で決める。
+    /// 秘密の値 `encryption_key` があれば、ファイルに書く個人情報を暗号化する。
+    pub enum UserBackend {
+        Memory(MemoryStorage<UserId, User>),
+        /// 多くのスレッドから同時に読み書きする時に、ロックを待ち合わないようにシャードに分けて持つ
+        Sharded(ConcurrentMemoryStorage<UserId, User>),
+        File(FileStorage<UserId, User, UserRecordCodec>),
+        EncryptedFile(FileStorage<UserId, User, EncryptedFields<UserRecordCodec, AesGcmCrypto>>),
+        /// 個人情報は向こうのサービスが守るので、ここでは暗号化しない
+        Remote(RestStorage<ReqwestClient, UserId, User>),
+        /// 個人情報はDBの暗号化に任せる。呼ぶのはTimeoutのワーカーなので、答えを待ってもランタイムを塞がない
+        Postgres(BlockOn<PostgresUserStorage>),
+    }
+
+    impl UserBackend {
+        fn open(config: &Config, crypto: Option<AesGcmCrypto>) -> Result<UserBackend, Error> {
+            if let Some(url) = config.database_url() {
+                let postgres = BlockOn::new(|| PostgresUserStorage::connect_lazy(url, config.remote_timeout()))?;
+                postgres.block_on(postgres.storage().migrate())?;
+                return Ok(UserBackend::Postgres(postgres));
+            }
+            if let Some(url) = config.remote_storage_url() {
+                return Ok(UserBackend::Remote(RestStorage::new(ReqwestClient::new(config.remote_timeout())?, url)));
+            }
+            Ok(match (config.storage_path(), crypto) {
+                (Some(path), Some(crypto)) => {
+                    let codec = EncryptedFields::new(UserRecordCodec, crypto, ENCRYPTED_USER_FIELDS);
+                    UserBackend::EncryptedFile(FileStorage::open(path, codec)?)
+                }
+                (Some(path), None) => UserBackend::File(FileStorage::open(path, UserRecordCodec)?),
+                (None, crypto) => match (config.snapshot_path(), config.memory_shards()) {
+                    (Some(path), _) => {
+                        UserBackend::Memory(MemoryStorage::restore(path, &*user_codec(crypto), &StdFileSystem)?)
+                    }
+                    (None, Some(shards)) => UserBackend::Sharded(ConcurrentMemoryStorage::with_shards(shards)),
+                    (None, None) => UserBackend::Memory(MemoryStorage::new()),
+                },
+            })
+        }
+    }
+
+    /// スナップショットもファイルと同じく、鍵があれば個人情報を暗号化して書く
+    fn user_codec(crypto: Option<AesGcmCrypto>) -> Box<dyn RecordCodec<User>> {
+        match crypto {
+            Some(crypto) => Box::new(EncryptedFields::new(UserRecordCodec, crypto, ENCRYPTED_USER_FIELDS)),
+            None => Box::new(UserRecordCodec),
+        }
+    }
+
+    impl StorageComponent<UserId, User> for UserBackend {
+        fn read(&self, key: UserId) -> Result<User, Error> {
+            match *self {
+                UserBackend::Memory(ref storage) => storage.read(key),
+                UserBackend::Sharded(ref storage) => storage.read(key),
+                UserBackend::File(ref storage) => storage.read(key),
+                UserBackend::EncryptedFile(ref storage) => storage.read(key),
+                UserBackend::Remote(ref storage) => storage.read(key),
+                UserBackend::Postgres(ref storage) => storage.read(key),
+            }
+        }
+
+        fn save(&self, key: UserId, value: User) -> Result<(), Error> {
+            match *self {
+                UserBackend::Memory(ref storage) => storage.save(key, value),
+                UserBackend::Sharded(ref storage) => storage.save(key, value),
+                UserBackend::File(ref storage) => storage.save(key, value),
+                UserBackend::EncryptedFile(ref storage) => storage.save(key, value),
+                UserBackend::Remote(ref storage) => storage.save(key, value),
+                UserBackend::Postgres(ref storage) => storage.save(key, value),
+            }
+        }
+
+        fn delete(&self, key: UserId) -> Result<(), Error> {
+            match *self {
+                UserBackend::Memory(ref storage) => storage.delete(key),
+                UserBackend::Sharded(ref storage) => storage.delete(key),
+                UserBackend::File(ref storage) => storage.delete(key),
+                UserBackend::EncryptedFile(ref storage) => storage.delete(key),
+                UserBackend::Remote(ref storage) => storage.delete(key),
+                UserBackend::Postgres(ref storage) => storage.delete(key),
+            }
+        }
+
+        fn read_all(&self) -> Result<Vec<User>, Error> {
+            match *self {
+                UserBackend::Memory(ref storage) => storage.read_all(),
+                UserBackend::Sharded(ref storage) => storage.read_all(),
+                UserBackend::File(ref storage) => storage.read_all(),
+                UserBackend::EncryptedFile(ref storage) => storage.read_all(),
+                UserBackend::Remote(ref storage) => storage.read_all(),
+                UserBackend::Postgres(ref storage) => storage.read_all(),
+            }
+        }
+
+        fn iter_all<'a>(&'a self) -> Box<dyn Iterator<Item = Result<User, Error>> + 'a>
+        where
+            User: 'a,
+        {
+            match *self {
+                UserBackend::Memory(ref storage) => storage.iter_all(),
+                UserBackend::Sharded(ref storage) => storage.iter_all(),
+                UserBackend::File(ref storage) => storage.iter_all(),
+                UserBackend::EncryptedFile(ref storage) => storage.iter_all(),
+                UserBackend::Remote(ref storage) => storage.iter_all(),
+                UserBackend::Postgres(ref storage) => storage.iter_all(),
+            }
+        }
+
+        fn read_many(&self, keys: &[UserId]) -> Result<Vec<User>, Error> {
+            match *self {
+                UserBackend::Memory(ref storage) => storage.read_many(keys),
+                UserBackend::Sharded(ref storage) => storage.read_many(keys),
+                UserBackend::File(ref storage) => storage.read_many(keys),
+                UserBackend::EncryptedFile(ref storage) => storage.read_many(keys),
+                UserBackend::Remote(ref storage) => storage.read_many(keys),
+                UserBackend::Postgres(ref storage) => storage.read_many(keys),
+            }
+        }
+
+        fn save_all(&self, values: &[(UserId, User)]) -> Result<(), Error> {
+            match *self {
+                UserBackend::Memory(ref storage) => storage.save_all(values),
+                UserBackend::Sharded(ref storage) => storage.save_all(values),
+                UserBackend::File(ref storage) => storage.save_all(values),
+                UserBackend::EncryptedFile(ref storage) => storage.save_all(values),
+                UserBackend::Remote(ref storage) => storage.save_all(values),
+                UserBackend::Postgres(ref storage) => storage.save_all(values),
+            }
+        }
+
+        fn flush(&self) -> Result<(), Error> {
+            match *self {
+                UserBackend::Memory(ref storage) => storage.flush(),
+                UserBackend::Sharded(ref storage) => storage.flush(),
+                UserBackend::File(ref storage) => storage.flush(),
+                UserBackend::EncryptedFile(ref storage) => storage.flush(),
+                UserBackend::Remote(ref storage) => storage.flush(),
+                UserBackend::Postgres(ref storage) => storage.flush(),
+            }
+        }
+
+        fn save_all_concurrent(&self, values: &[(UserId, User)], parallelism: usize) -> Result<(), Error> {
+            match *self {
+                UserBackend::Memory(ref storage) => storage.save_all_concurrent(values, parallelism),
+                UserBackend::Sharded(ref storage) => storage.save_all_concurrent(values, parallelism),
+                UserBackend::File(ref storage) => storage.save_all_concurrent(values, parallelism),
+                UserBackend::EncryptedFile(ref storage) => storage.save_all_concurrent(values, parallelism),
+                UserBackend::Remote(ref storage) => storage.save_all_concurrent(values, parallelism),
+                UserBackend::Postgres(ref storage) => storage.save_all_concurrent(values, parallelism),
+            }
+        }
+    }
+
+    /// ジョブの保存先。`jobs_path` が設定されていればファイルに保存し、再起動しても残す。
+    /// 諦めたジョブは `jobs_path` の拡張子を `dead.jsonl` にしたファイルに残す。
+    pub enum JobBackend {
+        Memory(MemoryStorage<JobId, Job>),
+        File(FileStorage<JobId, Job, JsonCodec>),
+    }
+
+    impl JobBackend {
+        fn open(path: Option<&Path>) -> Result<JobBackend, Error> {
+            Ok(match path {
+                Some(path) => JobBackend::File(FileStorage::open(path, JsonCodec)?),
+                None => JobBackend::Memory(MemoryStorage::new()),
+            })
+        }
+
+        fn queue(config: &Config) -> Result<StoredJobQueue<JobBackend>, Error> {
+            let dead_letters = config.jobs_path().map(|path| path.with_extension("dead.jsonl"));
+            StoredJobQueue::new(
+                JobBackend::open(config.jobs_path())?,
+                JobBackend::open(dead_letters.as_deref())?,
+            )
+        }
+    }
+
+    impl StorageComponent<JobId, Job> for JobBackend {
+        fn read(&self, key: JobId) -> Result<Job, Error> {
+            match *self {
+                JobBackend::Memory(ref storage) => storage.read(key),
+                JobBackend::File(ref storage) => storage.read(key),
+            }
+        }
+
+        fn save(&self, key: JobId, value: Job) -> Result<(), Error> {
+            match *self {
+                JobBackend::Memory(ref storage) => storage.save(key, value),
+                JobBackend::File(ref storage) => storage.save(key, value),
+            }
+        }
+
+        fn delete(&self, key: JobId) -> Result<(), Error> {
+            match *self {
+                JobBackend::Memory(ref storage) => storage.delete(key),
+                JobBackend::File(ref storage) => storage.delete(key),
+            }
+        }
+
+        fn read_all(&self) -> Result<Vec<Job>, Error> {
+            match *self {
+                JobBackend::Memory(ref storage) => storage.read_all(),
+                JobBackend::File(ref storage) => storage.read_all(),
+            }
+        }
+
+        fn save_all(&self, values: &[(JobId, Job)]) -> Result<(), Error> {
+            match *self {
+                JobBackend::Memory(ref storage) => storage.save_all(values),
+                JobBackend::File(ref storage) => storage.save_all(values),
+            }
+        }
+    }
+
+    /// アカウントの変更の通知先。どれを使うかは設定で決める。
+    pub enum Notifier {
+        Console(ConsoleNotifier),
+        Email(EmailNotifier<SmtpSender>),
+        Webhook(WebhookNotifier<ReqwestClient>),
+    }
+
+    impl Notifier {
+        fn from_config<S: SecretsComponent>(config: &Config, secrets: &S) -> Result<Notifier, Error> {
+            Ok(match config.notifier() {
+                NotifierKind::Console => Notifier::Console(ConsoleNotifier),
+                NotifierKind::Email => Notifier::Email(EmailNotifier::new(smtp_sender(config, secrets)?)),
+                NotifierKind::Webhook => match config.webhook_url() {
+                    Some(url) => Notifier::Webhook(WebhookNotifier::new(url, ReqwestClient::default())),
+                    None => bail!("webhook_url is required for the webhook notifier"),
+                },
+            })
+        }
+    }
+
+    impl NotificationComponent for Notifier {
+        fn notify(&self, user: &User, message: &str) -> Result<(), Error> {
+            match *self {
+                Notifier::Console(ref notifier) => notifier.notify(user, message),
+                Notifier::Email(ref notifier) => notifier.notify(user, message),
+                Notifier::Webhook(ref notifier) => notifier.notify(user, message),
+            }
+        }
+    }
+
+    /// `smtp_user` が設定されていれば、秘密の値 `smtp_password` で認証する
+    fn smtp_sender<S: SecretsComponent>(config: &Config, secrets: &S) -> Result<SmtpSender, Error> {
+        match config.smtp_user() {
+            Some(user) => SmtpSender::with_credentials(
+                config.smtp_host(),
+                config.smtp_port(),
+                config.mail_from(),
+                user,
+                &secrets.require("smtp_password")?,
+            ),
+            None => SmtpSender::new(config.smtp_host(), config.smtp_port(), config.mail_from()),
+        }
+    }
+
+    /// 送り先のURLがあれば、秘密の値 `webhook_signing_key` で署名する
+    /// 答えないURLで他の配信やリクエストの処理が止まらないように、他のサービスと同じ時間で諦める
+    fn webhook_dispatcher<S: SecretsComponent>(
+        config: &Config,
+        secrets: &S,
+        timeout: Duration,
+    ) -> Result<WebhookDispatcher<Timeout<ReqwestClient>>, Error> {
+        let urls = config.event_webhook_urls().to_vec();
+        let key = if urls.is_empty() {
+            Secret::new("")
+        } else {
+            secrets.require("webhook_signing_key")?
+        };
+        let client = ReqwestClient::default().with_timeout(timeout);
+        Ok(WebhookDispatcher::new(urls, config.event_webhook_max_attempts(), key, client))
+    }
+
+    /// 設定されたユーザーの検証ルール
+    fn validation_rules(config: &Config) -> Rules<User> {
+        let mut rules = Rules::new();
+        if !config.allowed_email_domains().is_empty() {
+            let domains = config.allowed_email_domains().to_vec();
+            rules = rules.with("allowed_email_domains", validation::email_domain_in(domains));
+        }
+        rules
+    }
+
+    /// 秘密の値の読み込み元。ファイルが設定されていればそこから、無ければ環境変数から読む。
+    pub enum Secrets {
+        Env(EnvSecrets<ProcessEnvironment>),
+        File(FileVault),
+    }
+
+    impl Secrets {
+        fn from_config(config: &Config, environment: ProcessEnvironment) -> Result<Secrets, Error> {
+            Ok(match config.secrets_path() {
+                Some(path) => Secrets::File(FileVault::open(path)?),
+                None => Secrets::Env(EnvSecrets::with_environment(environment)),
+            })
+        }
+    }
+
+    impl SecretsComponent for Secrets {
+        fn secret(&self, name: &str) -> Option<Secret> {
+            match *self {
+                Secrets::Env(ref secrets) => secrets.secret(name),
+                Secrets::File(ref secrets) => secrets.secret(name),
+            }
+        }
+    }
+
+    /// IPアドレスの場所の引き方。データベースが設定されていなければ何も引かない。
+    pub enum GeoIp {
+        Disabled(NoGeoIp),
+        MaxMind(MaxMindGeoIp),
+    }
+
+    impl GeoIp {
+        fn from_config(config: &Config) -> Result<GeoIp, Error> {
+            Ok(match config.geoip_database() {
+                Some(path) => GeoIp::MaxMind(MaxMindGeoIp::open(path)?),
+                None => GeoIp::Disabled(NoGeoIp),
+            })
+        }
+    }
+
+    impl GeoIpComponent for GeoIp {
+        fn lookup(&self, ip: IpAddr) -> Result<Option<Location>, Error> {
+            match *self {
+                GeoIp::Disabled(ref geo_ip) => geo_ip.lookup(ip),
+                GeoIp::MaxMind(ref geo_ip) => geo_ip.lookup(ip),
+            }
+        }
+    }
+
+    /// `url` のRedisへの接続プール。空きを待つのは、別のサービスの呼び出しを待つのと同じ時間まで
+    fn redis_pool(config: &Config, url: &str) -> Result<ConnectionPool<redis::Client>, Error> {
+        let pool = ConnectionPool::new(redis::Client::open(url)?, config.redis_pool_size());
+        Ok(pool.with_timeout(Duration::from_std(config.remote_timeout())?))
+    }
+
+    /// ユーザーのキャッシュの置き場所。RedisのURLが設定されていれば、他のインスタンスとキャッシュを共有する。
+    pub enum UserCache {
+        Memory(MemoryCache<UserId, User>),
+        /// 接続プールを持つので大きく、箱に入れておく
+        Redis(Box<RedisCache>),
+    }
+
+    impl UserCache {
+        fn from_config(config: &Config) -> Result<UserCache, Error> {
+            Ok(match config.cache_url() {
+                Some(url) => UserCache::Redis(Box::new(RedisCache::new(redis_pool(config, url)?, "users"))),
+                None => UserCache::Memory(MemoryCache::new()),
+            })
+        }
+    }
+
+    impl CacheComponent<UserId, User> for UserCache {
+        fn get(&self, key: &UserId) -> Option<User> {
+            match *self {
+                UserCache::Memory(ref cache) => cache.get(key),
+                UserCache::Redis(ref cache) => cache.get(key),
+            }
+        }
+
+        fn set(&self, key: UserId, value: User) {
+            match *self {
+                UserCache::Memory(ref cache) => cache.set(key, value),
+                UserCache::Redis(ref cache) => cache.set(key, value),
+            }
+        }
+
+        fn set_with_ttl(&self, key: UserId, value: User, ttl: Duration) {
+            match *self {
+                UserCache::Memory(ref cache) => cache.set_with_ttl(key, value, ttl),
+                UserCache::Redis(ref cache) => cache.set_with_ttl(key, value, ttl),
+            }
+        }
+
+        fn invalidate(&self, key: &UserId) {
+            match *self {
+                UserCache::Memory(ref cache) => cache.invalidate(key),
+                UserCache::Redis(ref cache) => CacheComponent::<UserId, User>::invalidate(&**cache, key),
+            }
+        }
+    }
+
+    /// ロックの置き場所。RedisのURLが設定されていれば、他のインスタンスとロックを共有する。
+    pub enum Locks {
+        InProcess(InProcessLocks),
+        /// 接続プールを持つので大きく、箱に入れておく
+        Redis(Box<RedisLocks>),
+    }
+
+    impl Locks {
+        fn from_config<E: EnvironmentComponent>(config: &Config, environment: &E) -> Result<Locks, Error> {
+            Ok(match config.lock_url() {
+                // 取ったまま落ちても、30秒経てば他のインスタンスが取れる
+                Some(url) => Locks::Redis(Box::new(RedisLocks::new(
+                    redis_pool(config, url)?,
+                    Duration::seconds(30),
+                    &environment.instance_name(),
+                ))),
+                None => Locks::InProcess(InProcessLocks::new()),
+            })
+        }
+    }
+
+    impl LockComponent for Locks {
+        fn acquire(&self, name: &str, timeout: Duration) -> Result<LockToken, Error> {
+            match *self {
+                Locks::InProcess(ref locks) => locks.acquire(name, timeout),
+                Locks::Redis(ref locks) => locks.acquire(name, timeout),
+            }
+        }
+
+        fn release(&self, token: LockToken) -> Result<(), Error> {
+            match *self {
+                Locks::InProcess(ref locks) => locks.release(token),
+                Locks::Redis(ref locks) => locks.release(token),
+            }
+        }
+    }
+
+    /// ドメインイベントを流すキュー。ブローカーが設定されていればKafkaを使う。
+    pub enum EventQueue {
+        Memory(InMemoryQueue),
+        #[cfg(feature = "kafka")]
+        Kafka(KafkaQueue),
+    }
+
+    impl EventQueue {
+        #[cfg(feature = "kafka")]
+        fn from_config(config: &Config) -> Result<EventQueue, Error> {
+            if config.queue_brokers().is_empty() {
+                return Ok(EventQueue::Memory(InMemoryQueue::new()));
+            }
+            Ok(EventQueue::Kafka(KafkaQueue::new(config.queue_brokers().to_vec(), "layered")?))
+        }
+
+        #[cfg(not(feature = "kafka"))]
+        fn from_config(config: &Config) -> Result<EventQueue, Error> {
+            if !config.queue_brokers().is_empty() {
+                bail!("queue_brokers is set but this build does not have the kafka feature");
+            }
+            Ok(EventQueue::Memory(InMemoryQueue::new()))
+        }
+    }
+
+    impl MessageQueueComponent for EventQueue {
+        fn publish(&self, topic: &str, payload: &[u8]) -> Result<(), Error> {
+            match *self {
+                EventQueue::Memory(ref queue) => queue.publish(topic, payload),
+                #[cfg(feature = "kafka")]
+                EventQueue::Kafka(ref queue) => queue.publish(topic, payload),
+            }
+        }
+
+        fn consume(&self, topic: &str) -> Result<Vec<Vec<u8>>, Error> {
+            match *self {
+                EventQueue::Memory(ref queue) => queue.consume(topic),
+                #[cfg(feature = "kafka")]
+                EventQueue::Kafka(ref queue) => queue.consume(topic),
+            }
+        }
+
+        fn ping(&self) -> Result<(), Error> {
+            match *self {
+                EventQueue::Memory(ref queue) => queue.ping(),
+                #[cfg(feature = "kafka")]
+                EventQueue::Kafka(ref queue) => queue.ping(),
+            }
+        }
+    }
+
+    /// RealWorldと一緒に止めるバックグラウンドのタスク。
+    /// 止める合図は全てのタスクで共有し、`stop` で送ってから全て終わるのを待つ。
+    pub struct BackgroundTasks {
+        trigger: Mutex<Option<oneshot::Sender<()>>>,
+        stopping: Shared<BoxFuture<'static, ()>>,
+        tasks: Mutex<Vec<JoinHandle<()>>>,
+    }
+
+    impl BackgroundTasks {
+        fn new() -> BackgroundTasks {
+            let (trigger, stopping) = oneshot::channel();
+            BackgroundTasks {
+                trigger: Mutex::new(Some(trigger)),
+                // 合図を送らずに捨てられた時も止める
+                stopping: stopping.map(|_| ()).boxed().shared(),
+                tasks: Mutex::new(Vec::new()),
+            }
+        }
+
+        /// 止める合図を送り、覚えているタスクが全て終わると完了するFutureを返す。
+        /// panicしたタスクも終わったものとして扱う。
+        fn stop(&self) -> impl Future<Output = ()> + Send {
+            if let Some(trigger) = self.trigger.lock().unwrap_or_else(PoisonError::into_inner).take() {
+                let _ = trigger.send(());
+            }
+            let tasks = mem::take(&mut *self.tasks.lock().unwrap_or_else(PoisonError::into_inner));
+            future::join_all(tasks).map(|_| ())
+        }
+    }
+
+    /// Cake Pattern での環境型
+    /// この構造体に各レイヤーを担当するオブジェクトを格納する。
+    /// `&self` で状態を変えるComponentは中でロックを取るので、RealWorldはSend + Syncになっている。
+    pub struct RealWorld {
+        config_component: Config,
+        time_component: Chrono,
+        monotonic_time_component: StdClock,
+        id_generator_component: UuidGen,
+        random_component: OsRandom,
+        secrets_component: Secrets,
+        rate_limiter_component: TokenBucket,
+        lock_component: Locks,
+        scheduler_component: ThreadScheduler,
+        file_system_component: StdFileSystem,
+        template_component: HandlebarsTemplates,
+        search_component: TantivySearch,
+        event_bus_component: SyncEventBus<RealWorld>,
+        validation_component: Rules<User>,
+        password_policy_component: Rules<PlainPassword>,
+        locale_component: Catalogs,
+        geo_ip_component: GeoIp,
+        crypto_component: AesGcmCrypto,
+        tracing_component: TracingSpans,
+        logging_component: ConsoleLogger,
+        password_hasher_component: Argon2Hasher,
+        email_sender_component: Timeout<SmtpSender>,
+        notification_component: Notifier,
+        metrics_component: NoopMetrics,
+        feature_flag_component: PercentageRollout,
+        webhook_component: WebhookDispatcher<Timeout<ReqwestClient>>,
+        message_queue_component: EventQueue,
+        job_queue_component: StoredJobQueue<JobBackend>,
+        storage_component: UserStorage,
+        credential_storage_component: CredentialStorage,
+        group_storage_component: MemoryStorage<GroupName, Group>,
+        profile_storage_component: MemoryStorage<UserId, Profile>,
+        session_storage_component: SessionStorage,
+        api_token_storage_component: MemoryStorage<ApiTokenId, ApiToken>,
+        password_reset_storage_component: MemoryStorage<PasswordResetId, PasswordResetToken>,
+        invitation_storage_component: MemoryStorage<InvitationId, Invitation>,
+        background_tasks: BackgroundTasks,
+    }
+
+    impl RealWorld {
+        /// 設定を使わず、メモリ上のストレージで作る
+        #[cfg(test)]
+        pub fn with_cache_policy(policy: CachePolicy) -> RealWorld {
+            // メモリ上の空のストレージを開くだけなので失敗しない
+            RealWorld::with_config(Config::default(), policy).unwrap()
+        }
+
+        pub fn with_config(config: Config, policy: CachePolicy) -> Result<RealWorld, Error> {
+            let environment = ProcessEnvironment;
+            let secrets = Secrets::from_config(&config, environment)?;
+            let crypto = secrets.secret(ENCRYPTION_KEY).map(|key| AesGcmCrypto::new(&key));
+            let remote_timeout = Duration::from_std(config.remote_timeout())?;
+            let backend = UserBackend::open(&config, crypto.clone())?.with_timeout(remote_timeout);
+            let storage = CachingStorage::new(backend, UserCache::from_config(&config)?, policy);
+            let world = RealWorld {
+                time_component: Chrono,
+                monotonic_time_component: StdClock::new(),
+                id_generator_component: UuidGen,
+                random_component: OsRandom,
+                logging_component: ConsoleLogger,
+                password_hasher_component: Argon2Hasher::default(),
+                email_sender_component: smtp_sender(&config, &secrets)?.with_timeout(remote_timeout),
+                notification_component: Notifier::from_config(&config, &secrets)?,
+                webhook_component: webhook_dispatcher(&config, &secrets, remote_timeout)?,
+                secrets_component: secrets,
+                // 5回続けて失敗したら、以降は1分に1回だけ試行できる
+                rate_limiter_component: TokenBucket::new(RATE_LIMIT_CAPACITY, Duration::minutes(1)),
+                lock_component: Locks::from_config(&config, &environment)?,
+                scheduler_component: ThreadScheduler::start(),
+                file_system_component: StdFileSystem,
+                template_component: HandlebarsTemplates::new()?,
+                search_component: TantivySearch::in_memory()?,
+                event_bus_component: SyncEventBus::new(),
+                validation_component: validation_rules(&config),
+                password_policy_component: validation::password_policy(MIN_PASSWORD_LENGTH),
+                locale_component: Catalogs::builtin(),
+                geo_ip_component: GeoIp::from_config(&config)?,
+                // 鍵が無ければ、プロセスの中でだけ使える鍵で署名等を行う
+                crypto_component: crypto.unwrap_or_else(AesGcmCrypto::ephemeral),
+                tracing_component: TracingSpans,
+                metrics_component: NoopMetrics,
+                // `features` に書いた機能は全員に、`rollouts` に書いた機能は一部のユーザーに有効にする
+                feature_flag_component: PercentageRollout::new(
+                    config
+                        .features
+                        .iter()
+                        .map(|feature| (feature.clone(), 100))
+                        .chain(config.rollouts().clone()),
+                ),
+                message_queue_component: EventQueue::from_config(&config)?,
+                // 前のプロセスが実行し終えなかったジョブはここで積み直す
+                job_queue_component: JobBackend::queue(&config)?,
+                // ファイルから読み込んだユーザーの名前・メールアドレスが重複していたらここでエラーになる
+                storage_component: Journaled::new(IndexedUserStorage::new(storage)?),
+                credential_storage_component: Journaled::new(MemoryStorage::new()),
+                group_storage_component: MemoryStorage::new(),
+                profile_storage_component: MemoryStorage::new(),
+                // 失効はストレージから消すだけなので、他のプロセスで失効したセッションも数分で見えなくなるようにする
+                session_storage_component: Journaled::new(
+                    CachingStorage::new(MemoryStorage::new(), MemoryCache::new(), CachePolicy::WriteThrough)
+                        .with_ttl(Duration::minutes(5)),
+                ),
+                api_token_storage_component: MemoryStorage::new(),
+                password_reset_storage_component: MemoryStorage::new(),
+                invitation_storage_component: MemoryStorage::new(),
+                background_tasks: BackgroundTasks::new(),
+                config_component: config,
+            };
+            world.subscribe_user_events();
+            // 検索の索引はメモリ上にしか無いので、保存されているユーザーから作り直す
+            world.reindex_users()?;
+            Ok(world)
+        }
+
+        /// バックグラウンドのタスクが待つ、止める合図
+        pub fn stopping(&self) -> Shared<BoxFuture<'static, ()>> {
+            self.background_tasks.stopping.clone()
+        }
+
+        /// `shutdown` で終わるのを待つタスクとして覚えておく
+        pub fn track(&self, task: JoinHandle<()>) {
+            self.background_tasks.tasks.lock().unwrap_or_else(PoisonError::into_inner).push(task);
+        }
+
+        /// バックグラウンドのタスクを止めて、全て終わってから `persist` する
+        pub fn shutdown(world: &Arc<RealWorld>) -> impl Future<Output = Result<(), Error>> + Send {
+            let world = world.clone();
+            world.background_tasks.stop().map(move |_| world.persist())
+        }
+
+        /// プロセスを終了する前に1回呼ぶ。今実行できるジョブを済ませ、溜まっているユーザーの変更をストレージへ書き出し、
+        /// 保存先がメモリで `snapshot_path` が設定されていれば、全ユーザーをそのファイルへ書き出す。
+        /// 認証情報やセッション等はスナップショットに含めないので、起動し直すとログインし直しになる。
+        pub fn persist(&self) -> Result<(), Error> {
+            // ジョブがメモリ上にしか無い時は、ここで実行しないと消えてしまう。
+            // 失敗してもユーザーの書き出しは止めずに続ける
+            if let Err(e) = self.work_jobs() {
+                self.logging_component.log(Level::Error, &format!("failed to run pending jobs: {}", e));
+            }
+            self.storage_component.flush()?;
+            let backend = self.storage_component.storage().storage().storage().inner();
+            if let (UserBackend::Memory(storage), Some(path)) = (backend, self.config_component.snapshot_path()) {
+                let crypto = self.secrets_component.secret(ENCRYPTION_KEY).map(|key| AesGcmCrypto::new(&key));
+                let saved = storage.snapshot(path, &*user_codec(crypto), &self.file_system_component)?;
+                self.logging_component.info(&format!("saved {} users to {}", saved, path.display()));
+            }
+            Ok(())
+        }
+    }
+
+    /// 外から呼ぶユースケースの組み立て。ログ・メトリクス・権限の確認・トランザクションは各Interactorには書かず、ここで重ねる。
+    impl RealWorld {
+        pub fn register_user_use_case<'a>(
+            &'a self,
+        ) -> impl UseCase<Input = NewUser, Output = UserDto, Error = DomainError> + 'a {
+            RegisterUserInteractor::new(self).transactional().metered().logged()
+        }
+
+        /// 試行の回数はユースケースの中で名前毎に制限する
+        pub fn authenticate_user_use_case<'a>(
+            &'a self,
+        ) -> impl UseCase<Input = LoginRequest, Output = SessionDto, Error = DomainError> + 'a {
+            AuthenticateUserInteractor::new(self).metered().logged()
+        }
+
+        /// 出力は失効させたセッションの数。変更と失効はユースケースの中でまとめて行う
+        pub fn change_password_use_case<'a>(
+            &'a self,
+        ) -> impl UseCase<Input = PasswordChange, Output = usize, Error = DomainError> + 'a {
+            ChangePasswordInteractor::new(self).metered().logged()
+        }
+
+        /// `owner` は認証したユーザー。他人のトークンは発行できない
+        pub fn issue_api_token_use_case<'a>(
+            &'a self,
+        ) -> impl UseCase<Input = ApiTokenRequest, Output = IssuedApiTokenDto, Error = DomainError> + 'a {
+            IssueApiTokenInteractor::new(self).metered().logged()
+        }
+
+        /// 登録されていないメールアドレスでも成功にする
+        pub fn request_password_reset_use_case<'a>(
+            &'a self,
+        ) -> impl UseCase<Input = PasswordResetRequest, Output = (), Error = DomainError> + 'a {
+            RequestPasswordResetInteractor::new(self).metered().logged()
+        }
+
+        /// 出力は失効させたセッションの数
+        pub fn confirm_password_reset_use_case<'a>(
+            &'a self,
+        ) -> impl UseCase<Input = PasswordResetConfirmation, Output = usize, Error = DomainError> + 'a {
+            ConfirmPasswordResetInteractor::new(self).metered().logged()
+        }
+
+        /// 招待できるのはユーザーを管理できる人だけ
+        pub fn invite_user_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = NewInvitation, Output = InvitationDto, Error = DomainError> + 'a {
+            InviteUserInteractor::new(self)
+                .authorized(actor, Permission::ManageUsers)
+                .metered()
+                .logged()
+        }
+
+        /// 招待された人はまだユーザーではないので、権限は確かめずにトークンで確かめる
+        pub fn accept_invitation_use_case<'a>(
+            &'a self,
+        ) -> impl UseCase<Input = InvitationAcceptance, Output = UserDto, Error = DomainError> + 'a {
+            AcceptInvitationInteractor::new(self).metered().logged()
+        }
+
+        pub fn list_users_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = ListUsersQuery, Output = Page<UserSummaryDto>, Error = DomainError> + 'a {
+            ListUsersInteractor::new(self)
+                .authorized(actor, Permission::ListUsers)
+                .metered()
+                .logged()
+        }
+
+        /// 一覧を見られる人だけが探せる
+        pub fn search_users_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = UserSearch, Output = Vec<UserSummaryDto>, Error = DomainError> + 'a {
+            SearchUsersInteractor::new(self)
+                .authorized(actor, Permission::ListUsers)
+                .metered()
+                .logged()
+        }
+
+        pub fn get_user_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = UserId, Output = UserDto, Error = DomainError> + 'a {
+            GetUserInteractor::new(self)
+                .authorized(actor, Permission::ReadProfile)
+                .metered()
+                .logged()
+        }
+
+        pub fn get_user_by_name_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = Name, Output = UserDto, Error = DomainError> + 'a {
+            GetUserByNameInteractor::new(self)
+                .authorized(actor, Permission::ReadProfile)
+                .metered()
+                .logged()
+        }
+
+        /// 何人分を1度に引くかは呼ぶ側が決める(GraphQLではDataLoaderがまとめる)
+        pub fn get_users_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = Vec<UserId>, Output = Vec<UserDto>, Error = DomainError> + 'a {
+            GetUsersInteractor::new(self)
+                .authorized(actor, Permission::ReadProfile)
+                .metered()
+                .logged()
+        }
+
+        /// 他のユーザーの名前を変えられるのはユーザーを管理できる人だけ
+        pub fn rename_user_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = UserRename, Output = UserDto, Error = DomainError> + 'a {
+            RenameUserInteractor::new(self)
+                .authorized(actor, Permission::ManageUsers)
+                .metered()
+                .logged()
+        }
+
+        /// 変更できるのは自分のメールアドレスだけなので、呼ぶ側で本人かどうかを確かめる
+        pub fn update_email_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = EmailUpdate, Output = EmailChange, Error = DomainError> + 'a {
+            UpdateEmailInteractor::new(self)
+                .authorized(actor, Permission::UpdateOwnProfile)
+                .transactional()
+                .metered()
+                .logged()
+        }
+
+        /// 退会の確認用のトークンは本人にだけ渡すので、呼ぶ側で本人かどうかを確かめる
+        pub fn request_account_deletion_use_case<'a>(
+            &'a self,
+        ) -> impl UseCase<Input = UserId, Output = String, Error = DomainError> + 'a {
+            RequestAccountDeletionInteractor::new(self).metered().logged()
+        }
+
+        pub fn confirm_account_deletion_use_case<'a>(
+            &'a self,
+        ) -> impl UseCase<Input = String, Output = UserDto, Error = DomainError> + 'a {
+            ConfirmAccountDeletionInteractor::new(self).transactional().metered().logged()
+        }
+
+        /// 停止・復元・完全な削除ができるのはユーザーを管理できる人だけ
+        pub fn suspend_user_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = UserId, Output = UserDto, Error = DomainError> + 'a {
+            SuspendUserInteractor::new(self)
+                .authorized(actor, Permission::ManageUsers)
+                .transactional()
+                .metered()
+                .logged()
+        }
+
+        pub fn restore_user_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = UserId, Output = UserDto, Error = DomainError> + 'a {
+            RestoreUserInteractor::new(self)
+                .authorized(actor, Permission::ManageUsers)
+                .transactional()
+                .metered()
+                .logged()
+        }
+
+        pub fn purge_user_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = UserId, Output = UserDto, Error = DomainError> + 'a {
+            PurgeUserInteractor::new(self)
+                .authorized(actor, Permission::ManageUsers)
+                .transactional()
+                .metered()
+                .logged()
+        }
+
+        pub fn create_group_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = GroupName, Output = GroupDto, Error = DomainError> + 'a {
+            CreateGroupInteractor::new(self)
+                .authorized(actor, Permission::ManageUsers)
+                .metered()
+                .logged()
+        }
+
+        pub fn add_group_member_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = Membership, Output = GroupDto, Error = DomainError> + 'a {
+            AddGroupMemberInteractor::new(self)
+                .authorized(actor, Permission::ManageUsers)
+                .metered()
+                .logged()
+        }
+
+        pub fn remove_group_member_use_case<'a>(
+            &'a self,
+            actor: UserId,
+        ) -> impl UseCase<Input = Membership, Output = GroupDto, Error = DomainError> + 'a {
+            RemoveGroupMemberInteractor::new(self)
+                .authorized(actor, Permission::ManageUsers)
+                .metered()
+                .logged()
+        }
+    }
+
+    impl HaveTracingComponent for RealWorld {
+        type TracingComponent = TracingSpans;
+        fn tracing_component(&self) -> &TracingSpans {
+            &self.tracing_component
+        }
+    }
+
+    impl HealthCheckComponent for RealWorld {
+        fn health(&self) -> HealthReport {
+            HealthReport::new()
+                .check("user_storage", health::ping_storage(&self.storage_component))
+                .check("session_storage", health::ping_storage(&self.session_storage_component))
+                .check("clock", health::check_clock(&self.time_component))
+                .check("queue", health::ping_queue(&self.message_queue_component))
+        }
+    }
+
+    /// ユーザー・認証情報・セッションを1つのトランザクションで変更できる
+    impl TransactionComponent for RealWorld {
+        fn participants(&self) -> Vec<&dyn Participant> {
+            vec![
+                &self.storage_component,
+                &self.credential_storage_component,
+                &self.session_storage_component,
+            ]
+        }
+    }
+
+    impl HaveValidationComponent for RealWorld {
+        type ValidationComponent = Rules<User>;
+        fn validation_component(&self) -> &Rules<User> {
+            &self.validation_component
+        }
+    }
+
+    impl HavePasswordPolicyComponent for RealWorld {
+        type PasswordPolicyComponent = Rules<PlainPassword>;
+        fn password_policy_component(&self) -> &Rules<PlainPassword> {
+            &self.password_policy_component
+        }
+    }
+
+    impl HaveLocaleComponent for RealWorld {
+        type LocaleComponent = Catalogs;
+        fn locale_component(&self) -> &Catalogs {
+            &self.locale_component
+        }
+    }
+
+    impl HaveCryptoComponent for RealWorld {
+        type CryptoComponent = AesGcmCrypto;
+        fn crypto_component(&self) -> &AesGcmCrypto {
+            &self.crypto_component
+        }
+    }
+
+    impl HaveGeoIpComponent for RealWorld {
+        type GeoIpComponent = GeoIp;
+        fn geo_ip_component(&self) -> &GeoIp {
+            &self.geo_ip_component
+        }
+    }
+
+    impl HaveEventBusComponent for RealWorld {
+        type EventBusComponent = SyncEventBus<RealWorld>;
+        fn event_bus_component(&self) -> &SyncEventBus<RealWorld> {
+            &self.event_bus_component
+        }
+    }
+
+    impl HaveSearchComponent for RealWorld {
+        type SearchComponent = TantivySearch;
+        fn search_component(&self) -> &TantivySearch {
+            &self.search_component
+        }
+    }
+
+    impl HaveTemplateComponent for RealWorld {
+        type TemplateComponent = HandlebarsTemplates;
+        fn template_component(&self) -> &HandlebarsTemplates {
+            &self.template_component
+        }
+    }
+
+    impl HaveFileSystemComponent for RealWorld {
+        type FileSystemComponent = StdFileSystem;
+        fn file_system_component(&self) -> &StdFileSystem {
+            &self.file_system_component
+        }
+    }
+
+    impl HaveSchedulerComponent for RealWorld {
+        type SchedulerComponent = ThreadScheduler;
+        fn scheduler_component(&self) -> &ThreadScheduler {
+            &self.scheduler_component
+        }
+    }
+
+    impl HaveLockComponent for RealWorld {
+        type LockComponent = Locks;
+        fn lock_component(&self) -> &Locks {
+            &self.lock_component
+        }
+    }
+
+    impl HaveRateLimiterComponent for RealWorld {
+        type RateLimiterComponent = TokenBucket;
+        fn rate_limiter_component(&self) -> &TokenBucket {
+            &self.rate_limiter_component
+        }
+    }
+
+    impl HaveFeatureFlagComponent for RealWorld {
+        type FeatureFlagComponent = PercentageRollout;
+        fn feature_flag_component(&self) -> &PercentageRollout {
+            &self.feature_flag_component
+        }
+    }
+
+    impl HaveConfigComponent for RealWorld {
+        type ConfigComponent = Config;
+        fn config_component(&self) -> &Config {
+            &self.config_component
+        }
+    }
+
+    impl HaveTimeComponent for RealWorld {
+        type TimeComponent = Chrono;
+        fn time_component(&self) -> &Chrono {
+            &self.time_component
+        }
+    }
+
+    impl HaveMonotonicTimeComponent for RealWorld {
+        type MonotonicTimeComponent = StdClock;
+        fn monotonic_time_component(&self) -> &StdClock {
+            &self.monotonic_time_component
+        }
+    }
+
+    impl HaveIdGeneratorComponent for RealWorld {
+        type IdGeneratorComponent = UuidGen;
+        fn id_generator_component(&self) -> &UuidGen {
+            &self.id_generator_component
+        }
+    }
+
+    impl HaveRandomComponent for RealWorld {
+        type RandomComponent = OsRandom;
+        fn random_component(&self) -> &OsRandom {
+            &self.random_component
+        }
+    }
+
+    impl HaveLoggingComponent for RealWorld {
+        type LoggingComponent = ConsoleLogger;
+        fn logging_component(&self) -> &ConsoleLogger {
+            &self.logging_component
+        }
+    }
+
+    impl HaveUserStorageComponent for RealWorld {
+        type UserStorageComponent = UserStorage;
+        fn user_storage_component(&self) -> &UserStorage {
+            &self.storage_component
+        }
+    }
+
+    impl HavePasswordHasherComponent for RealWorld {
+        type PasswordHasherComponent = Argon2Hasher;
+        fn password_hasher_component(&self) -> &Argon2Hasher {
+            &self.password_hasher_component
+        }
+    }
+
+    impl HaveMessageQueueComponent for RealWorld {
+        type MessageQueueComponent = EventQueue;
+        fn message_queue_component(&self) -> &EventQueue {
+            &self.message_queue_component
+        }
+    }
+
+    impl HaveJobQueueComponent for RealWorld {
+        type JobQueueComponent = StoredJobQueue<JobBackend>;
+        fn job_queue_component(&self) -> &StoredJobQueue<JobBackend> {
+            &self.job_queue_component
+        }
+    }
+
+    impl HaveWebhookComponent for RealWorld {
+        type WebhookComponent = WebhookDispatcher<Timeout<ReqwestClient>>;
+        fn webhook_component(&self) -> &WebhookDispatcher<Timeout<ReqwestClient>> {
+            &self.webhook_component
+        }
+    }
+
+    impl HaveMetricsComponent for RealWorld {
+        type MetricsComponent = NoopMetrics;
+        fn metrics_component(&self) -> &NoopMetrics {
+            &self.metrics_component
+        }
+    }
+
+    impl HaveNotificationComponent for RealWorld {
+        type NotificationComponent = Notifier;
+        fn notification_component(&self) -> &Notifier {
+            &self.notification_component
+        }
+    }
+
+    impl HaveEmailSenderComponent for RealWorld {
+        type EmailSenderComponent = Timeout<SmtpSender>;
+        fn email_sender_component(&self) -> &Timeout<SmtpSender> {
+            &self.email_sender_component
+        }
+    }
+
+    impl HaveCredentialStorageComponent for RealWorld {
+        type CredentialStorageComponent = CredentialStorage;
+        fn credential_storage_component(&self) -> &CredentialStorage {
+            &self.credential_storage_component
+        }
+    }
+
+    impl HaveCredentialRepository for RealWorld {
+        fn credential_repository(&self) -> &impl CredentialRepository {
+            self
+        }
+    }
+
+    impl HaveApiTokenStorageComponent for RealWorld {
+        type ApiTokenStorageComponent = MemoryStorage<ApiTokenId, ApiToken>;
+        fn api_token_storage_component(&self) -> &MemoryStorage<ApiTokenId, ApiToken> {
+            &self.api_token_storage_component
+        }
+    }
+
+    impl HaveApiTokenRepository for RealWorld {
+        fn api_token_repository(&self) -> &impl ApiTokenRepository {
+            self
+        }
+    }
+
+    impl HavePasswordResetStorageComponent for RealWorld {
+        type PasswordResetStorageComponent = MemoryStorage<PasswordResetId, PasswordResetToken>;
+        fn password_reset_storage_component(&self) -> &MemoryStorage<PasswordResetId, PasswordResetToken> {
+            &self.password_reset_storage_component
+        }
+    }
+
+    impl HavePasswordResetRepository for RealWorld {
+        fn password_reset_repository(&self) -> &impl PasswordResetRepository {
+            self
+        }
+    }
+
+    impl HaveInvitationStorageComponent for RealWorld {
+        type InvitationStorageComponent = MemoryStorage<InvitationId, Invitation>;
+        fn invitation_storage_component(&self) -> &MemoryStorage<InvitationId, Invitation> {
+            &self.invitation_storage_component
+        }
+    }
+
+    impl HaveInvitationRepository for RealWorld {
+        fn invitation_repository(&self) -> &impl InvitationRepository {
+            self
+        }
+    }
+
+    impl HaveGroupStorageComponent for RealWorld {
+        type GroupStorageComponent = MemoryStorage<GroupName, Group>;
+        fn group_storage_component(&self) -> &MemoryStorage<GroupName, Group> {
+            &self.group_storage_component
+        }
+    }
+
+    impl HaveGroupRepository for RealWorld {
+        fn group_repository(&self) -> &impl GroupRepository {
+            self
+        }
+    }
+
+    impl HaveProfileStorageComponent for RealWorld {
+        type ProfileStorageComponent = MemoryStorage<UserId, Profile>;
+        fn profile_storage_component(&self) -> &MemoryStorage<UserId, Profile> {
+            &self.profile_storage_component
+        }
+    }
+
+    impl HaveProfileRepository for RealWorld {
+        fn profile_repository(&self) -> &impl ProfileRepository {
+            self
+        }
+    }
+
+    impl HaveSessionStorageComponent for RealWorld {
+        type SessionStorageComponent = SessionStorage;
+        fn session_storage_component(&self) -> &SessionStorage {
+            &self.session_storage_component
+        }
+    }
+
+    impl HaveSessionRepository for RealWorld {
+        fn session_repository(&self) -> &impl SessionRepository {
+            self
+        }
+    }
+
+    impl HaveUserCommands for RealWorld {
+        fn user_commands(&self) -> &impl UserCommands {
+            self
+        }
+    }
+
+    impl HaveUserQueries for RealWorld {
+        fn user_queries(&self) -> &impl UserQueries {
+            self
+        }
+    }
+
+    impl HaveUniqueEmailService for RealWorld {
+        fn unique_email_service(&self) -> &impl UniqueEmailService {
+            self
+        }
+    }
+}
+
+#[cfg(test)]
+mod actor {
+    //! RealWorldとテストで比べるための、部品ごとに専用のスレッドで動かす環境型。
+    //! 部品はMailboxの向こうのスレッドに移し、呼び出しはチャネルで送って結果を待つ。
+    //! Have*のGetterはMailboxを返すので、ユースケースやRepositoryはRealWorldの時と同じコードのまま動く。
+    //!
+    //! 状態やIOを持つ部品(ストレージ・キュー・時刻・ID・ログ)だけを動かし、
+    //! 呼んだスレッドに結び付くspanや、状態を持たない検証・メトリクスはその場で呼ぶ。
+    //! ロックは取るまで待つので、スレッドに移すと解放の呼び出しが待たされてしまう。なのでこれもその場で取る。
+
+    use chrono::prelude::*;
+    use component::event_bus::{HaveEventBusComponent, SyncEventBus};
+    use component::id::{HaveIdGeneratorComponent, IdGeneratorComponent, UuidGen};
+    use component::jobs::{HaveJobQueueComponent, JobQueueComponent, StoredJobQueue};
+    use component::lock::{HaveLockComponent, InProcessLocks};
+    use component::log::{ConsoleLogger, HaveLoggingComponent, Level, LoggingComponent};
+    use component::metrics::{HaveMetricsComponent, NoopMetrics};
+    use component::storage::{
+        HaveUserStorageComponent, IndexedUserStorage, MemoryStorage, StorageComponent, UserStorageComponent,
+    };
+    use component::time::{Chrono, HaveTimeComponent, TimeComponent};
+    use component::trace::{HaveTracingComponent, TracingSpans};
+    use component::validation::{HaveValidationComponent, Rules};
+    use entity::job::{Job, JobId};
+    use entity::user::{Email, Name, User, UserId};
+    use failure::Error;
+    use repository::users::{HaveUserCommands, HaveUserQueries, UserCommands, UserQueries};
+    use service::unique_email::{HaveUniqueEmailService, UniqueEmailService};
+    use std::sync::mpsc::{self, Sender};
+    use std::thread::{self, JoinHandle};
+    use uuid::Uuid;
+
+    /// 部品のスレッドで行う処理
+    type Message<C> = Box<dyn FnOnce(&C) + Send>;
+
+    /// 部品を持つスレッドへの送り口。届いた順に1つずつ処理するので、部品を同時に触るのはそのスレッドだけになる。
+    /// 捨てると、それまでに送った処理を終えてからスレッドが止まるのを待つ。
+    pub struct Mailbox<C> {
+        sender: Option<Sender<Message<C>>>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl<C: Send + 'static> Mailbox<C> {
+        /// `component` を `actor-{name}` という名前のスレッドへ移す
+        pub fn spawn(name: &str, component: C) -> Result<Mailbox<C>, Error> {
+            let (sender, receiver) = mpsc::channel::<Message<C>>();
+            let thread = thread::Builder::new().name(format!("actor-{}", name)).spawn(move || {
+                for message in receiver {
+                    message(&component);
+                }
+            })?;
+            Ok(Mailbox {
+                sender: Some(sender),
+                thread: Some(thread),
+            })
+        }
+
+        /// `f` を部品のスレッドで実行し、結果を待つ。部品のスレッドがパニックで止まっていたらエラー
+        pub fn call<T, F>(&self, f: F) -> Result<T, Error>
+        where
+            T: Send + 'static,
+            F: FnOnce(&C) -> T + Send + 'static,
+        {
+            let (reply, result) = mpsc::sync_channel(1);
+            self.cast(move |component| {
+                // 待っている側が先に諦めていれば、結果は捨てる
+                let _ = reply.send(f(component));
+            });
+            result.recv().map_err(|_| format_err!("actor stopped"))
+        }
+
+        /// 結果を待たずに `f` を部品のスレッドへ送る
+        pub fn cast<F: FnOnce(&C) + Send + 'static>(&self, f: F) {
+            if let Some(ref sender) = self.sender {
+                // 送れないのはスレッドが止まっている時で、その時はcallが結果を受け取れずにエラーになる
+                let _ = sender.send(Box::new(f));
+            }
+        }
+    }
+
+    impl<C> Drop for Mailbox<C> {
+        fn drop(&mut self) {
+            self.sender.take();
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    /// 時刻やIDは無ければ何も出来ないので、部品のスレッドが止まっていたらパニックにする
+    const STOPPED: &str = "actor stopped";
+
+    impl<C: TimeComponent + Send + 'static> TimeComponent for Mailbox<C> {
+        fn now(&self) -> DateTime<Utc> {
+            self.call(|time| time.now()).expect(STOPPED)
+        }
+    }
+
+    impl<C: IdGeneratorComponent + Send + 'static> IdGeneratorComponent for Mailbox<C> {
+        fn generate(&self) -> Uuid {
+            self.call(|id_generator| id_generator.generate()).expect(STOPPED)
+        }
+    }
+
+    /// ログは書き終わるのを待たない
+    impl<C: LoggingComponent + Send + 'static> LoggingComponent for Mailbox<C> {
+        fn log(&self, level: Level, message: &str) {
+            let message = message.to_string();
+            self.cast(move |logger| logger.log(level, &message));
+        }
+    }
+
+    impl<K, V, C> StorageComponent<K, V> for Mailbox<C>
+    where
+        K: Clone + Send + 'static,
+        V: Clone + Send + 'static,
+        C: StorageComponent<K, V> + Send + 'static,
+    {
+        fn read(&self, key: K) -> Result<V, Error> {
+            self.call(|storage| storage.read(key))?
+        }
+
+        fn save(&self, key: K, value: V) -> Result<(), Error> {
+            self.call(|storage| storage.save(key, value))?
+        }
+
+        fn delete(&self, key: K) -> Result<(), Error> {
+            self.call(|storage| storage.delete(key))?
+        }
+
+        fn read_all(&self) -> Result<Vec<V>, Error> {
+            self.call(|storage| storage.read_all())?
+        }
+
+        fn save_all(&self, values: &[(K, V)]) -> Result<(), Error> {
+            let values = values.to_vec();
+            self.call(move |storage| storage.save_all(&values))?
+        }
+
+        fn read_many(&self, keys: &[K]) -> Result<Vec<V>, Error> {
+            let keys = keys.to_vec();
+            self.call(move |storage| storage.read_many(&keys))?
+        }
+
+        fn flush(&self) -> Result<(), Error> {
+            self.call(|storage| storage.flush())?
+        }
+
+        fn save_all_concurrent(&self, values: &[(K, V)], parallelism: usize) -> Result<(), Error> {
+            let values = values.to_vec();
+            self.call(move |storage| storage.save_all_concurrent(&values, parallelism))?
+        }
+    }
+
+    impl<C: UserStorageComponent + Send + 'static> UserStorageComponent for Mailbox<C> {
+        fn read_by_name(&self, name: &Name) -> Result<User, Error> {
+            let name = name.clone();
+            self.call(move |storage| storage.read_by_name(&name))?
+        }
+
+        fn read_by_email(&self, email: &Email) -> Result<User, Error> {
+            let email = email.clone();
+            self.call(move |storage| storage.read_by_email(&email))?
+        }
+    }
+
+    impl<C: JobQueueComponent + Send + 'static> JobQueueComponent for Mailbox<C> {
+        fn enqueue(&self, job: Job) -> Result<(), Error> {
+            self.call(|queue| queue.enqueue(job))?
+        }
+
+        fn claim(&self, now: DateTime<Utc>) -> Result<Option<Job>, Error> {
+            self.call(move |queue| queue.claim(now))?
+        }
+
+        fn complete(&self, id: &JobId) -> Result<(), Error> {
+            let id = id.clone();
+            self.call(move |queue| queue.complete(&id))?
+        }
+
+        fn retry(&self, id: &JobId, error: &str, run_at: DateTime<Utc>) -> Result<(), Error> {
+            let (id, error) = (id.clone(), error.to_string());
+            self.call(move |queue| queue.retry(&id, &error, run_at))?
+        }
+
+        fn poison(&self, id: &JobId, error: &str) -> Result<(), Error> {
+            let (id, error) = (id.clone(), error.to_string());
+            self.call(move |queue| queue.poison(&id, &error))?
+        }
+
+        fn jobs(&self) -> Result<Vec<Job>, Error> {
+            self.call(|queue| queue.jobs())?
+        }
+
+        fn dead_letters(&self) -> Result<Vec<Job>, Error> {
+            self.call(|queue| queue.dead_letters())?
+        }
+    }
+
+    /// ActorWorldで使うユーザー用ストレージ
+    pub type ActorUserStorage = IndexedUserStorage<MemoryStorage<UserId, User>>;
+
+    /// 部品ごとのスレッドにMailboxで話しかける環境型。保存先はメモリ上だけで、ユーザーの登録・読み書きに要る部品だけを持つ。
+    pub struct ActorWorld {
+        time_component: Mailbox<Chrono>,
+        id_generator_component: Mailbox<UuidGen>,
+        logging_component: Mailbox<ConsoleLogger>,
+        storage_component: Mailbox<ActorUserStorage>,
+        job_queue_component: Mailbox<StoredJobQueue<MemoryStorage<JobId, Job>>>,
+        lock_component: InProcessLocks,
+        tracing_component: TracingSpans,
+        metrics_component: NoopMetrics,
+        validation_component: Rules<User>,
+        event_bus_component: SyncEventBus<ActorWorld>,
+    }
+
+    impl ActorWorld {
+        pub fn new() -> Result<ActorWorld, Error> {
+            Ok(ActorWorld {
+                time_component: Mailbox::spawn("time", Chrono)?,
+                id_generator_component: Mailbox::spawn("id", UuidGen)?,
+                logging_component: Mailbox::spawn("log", ConsoleLogger)?,
+                storage_component: Mailbox::spawn("user-storage", IndexedUserStorage::new(MemoryStorage::new())?)?,
+                job_queue_component: Mailbox::spawn(
+                    "job-queue",
+                    StoredJobQueue::new(MemoryStorage::new(), MemoryStorage::new())?,
+                )?,
+                lock_component: InProcessLocks::default(),
+                tracing_component: TracingSpans,
+                metrics_component: NoopMetrics,
+                validation_component: Rules::new(),
+                event_bus_component: SyncEventBus::new(),
+            })
+        }
+    }
+
+    impl HaveTimeComponent for ActorWorld {
+        type TimeComponent = Mailbox<Chrono>;
+        fn time_component(&self) -> &Mailbox<Chrono> {
+            &self.time_component
+        }
+    }
+
+    impl HaveIdGeneratorComponent for ActorWorld {
+        type IdGeneratorComponent = Mailbox<UuidGen>;
+        fn id_generator_component(&self) -> &Mailbox<UuidGen> {
+            &self.id_generator_component
+        }
+    }
+
+    impl HaveLoggingComponent for ActorWorld {
+        type LoggingComponent = Mailbox<ConsoleLogger>;
+        fn logging_component(&self) -> &Mailbox<ConsoleLogger> {
+            &self.logging_component
+        }
+    }
+
+    impl HaveUserStorageComponent for ActorWorld {
+        type UserStorageComponent = Mailbox<ActorUserStorage>;
+        fn user_storage_component(&self) -> &Mailbox<ActorUserStorage> {
+            &self.storage_component
+        }
+    }
+
+    impl HaveJobQueueComponent for ActorWorld {
+        type JobQueueComponent = Mailbox<StoredJobQueue<MemoryStorage<JobId, Job>>>;
+        fn job_queue_component(&self) -> &Mailbox<StoredJobQueue<MemoryStorage<JobId, Job>>> {
+            &self.job_queue_component
+        }
+    }
+
+    impl HaveLockComponent for ActorWorld {
+        type LockComponent = InProcessLocks;
+        fn lock_component(&self) -> &InProcessLocks {
+            &self.lock_component
+        }
+    }
+
+    impl HaveTracingComponent for ActorWorld {
+        type TracingComponent = TracingSpans;
+        fn tracing_component(&self) -> &TracingSpans {
+            &self.tracing_component
+        }
+    }
+
+    impl HaveMetricsComponent for ActorWorld {
+        type MetricsComponent = NoopMetrics;
+        fn metrics_component(&self) -> &NoopMetrics {
+            &self.metrics_component
+        }
+    }
+
+    impl HaveValidationComponent for ActorWorld {
+        type ValidationComponent = Rules<User>;
+        fn validation_component(&self) -> &Rules<User> {
+            &self.validation_component
+        }
+    }
+
+    impl HaveEventBusComponent for ActorWorld {
+        type EventBusComponent = SyncEventBus<ActorWorld>;
+        fn event_bus_component(&self) -> &SyncEventBus<ActorWorld> {
+            &self.event_bus_component
+        }
+    }
+
+    impl HaveUserCommands for ActorWorld {
+        fn user_commands(&self) -> &impl UserCommands {
+            self
+        }
+    }
+
+    impl HaveUserQueries for ActorWorld {
+        fn user_queries(&self) -> &impl UserQueries {
+            self
+        }
+    }
+
+    impl HaveUniqueEmailService for ActorWorld {
+        fn unique_email_service(&self) -> &impl UniqueEmailService {
+            self
+        }
+    }
+}
+
+mod adapter {
+    //! 外からの入力をユースケースに渡し、結果を外に返すレイヤ(Clean ArchitectureのInterface Adapters)。
+    //! HTTP等の受け口の違いはここで吸収し、内側のレイヤはどこから呼ばれたかを知らない。
+    //!
+    //! サーバーとして動く受け口は、どれも `principal` でAPIトークンかセッションを確かめて認証する。
+    //! 前段(ゲートウェイ等)で認証を済ませている設定の時だけ、ログインしているユーザーのIDを
+    //! `x-user-id` (HTTPのヘッダ、gRPCのメタデータ)でも受け取る。
+    //! RealWorldは全体をロックせずにリクエストの間で共有し、ユースケースはそれぞれのリクエストの中で同期的に実行する。
+    //!
+    //! サーバーとして動く受け口はSIGTERMかSIGINTを受け取ると新しいリクエストの受け付けを止め、
+    //! 処理中のリクエストが終わるのを待ってから `RealWorld::shutdown` で変更を書き出して終了する。
+
+    use component::config::{ConfigComponent, HaveConfigComponent};
+    use component::log::{HaveLoggingComponent, LoggingComponent};
+    use entity::api_token::Scope;
+    use entity::session::SessionId;
+    use entity::user::UserId;
+    use env::RealWorld;
+    use failure::Error;
+    use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+    use futures::future::{self, BoxFuture, Either, Shared};
+    use futures::{stream, Future, FutureExt, StreamExt};
+    use repository::api_tokens::{ApiTokenRepository, HaveApiTokenRepository};
+    use repository::sessions::{HaveSessionRepository, SessionRepository};
+    use std::collections::BTreeSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+    use tokio::signal;
+    use usecase::jobs::WorkJobs;
+    use usecase::maintenance::Maintenance;
+    use usecase::presentation_error::{ErrorKind, PresentationError};
+    use uuid::Uuid;
+
+    /// リクエストを処理するスレッドの間で共有するRealWorld。
+    /// Componentは `&self` のまま中でロックを取るので、RealWorld全体をロックせずに並行に使う。
+    pub type SharedWorld = Arc<RealWorld>;
+
+    /// 止める合図を受け取ってから、処理中のリクエストが終わるのを待つ時間の上限。
+    /// SSE等の終わらない応答があっても、これを過ぎたら切って終了する
+    pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// 止める合図。受け付けを止める側と、待つ時間を数える側の両方で待てるように共有する
+    pub type Stop = Shared<BoxFuture<'static, ()>>;
+
+    /// SIGTERMかSIGINT(Ctrl-C)を受け取ると完了する。シグナルの登録は初めてpollした時に行う
+    pub fn shutdown_signal() -> Stop {
+        let interrupt = signal::ctrl_c().map(|_| ());
+        future::select(Box::pin(interrupt), Box::pin(terminate())).map(|_| ()).boxed().shared()
+    }
+
+    #[cfg(unix)]
+    fn terminate() -> impl Future<Output = ()> + Send {
+        use tokio::signal::unix::{signal, SignalKind};
+        future::lazy(|_| signal(SignalKind::terminate())).then(|registered| match registered {
+            Ok(mut terminate) => Either::Left(future::poll_fn(move |cx| terminate.poll_recv(cx)).map(|_| ())),
+            // 登録できなければSIGINTだけを待つ
+            Err(_) => Either::Right(future::pending()),
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn terminate() -> impl Future<Output = ()> + Send {
+        future::pending()
+    }
+
+    /// `stop` が完了すると受け付けを止めるサーバーを `runtime` で動かす。
+    /// `stop` が完了してから `DRAIN_TIMEOUT` が過ぎても処理中のリクエストが残っていれば、待たずに返る。
+    pub fn drain<S, E>(runtime: &Runtime, server: S, stop: Stop) -> Result<(), Error>
+    where
+        S: Future<Output = Result<(), E>>,
+        Error: From<E>,
+    {
+        let deadline = stop.then(|_| tokio::time::sleep(DRAIN_TIMEOUT));
+        match runtime.block_on(future::select(Box::pin(server), Box::pin(deadline))) {
+            Either::Left((result, _)) => result.map_err(Error::from),
+            // 残っている接続はランタイムを捨てる時に切れる
+            Either::Right(_) => Ok(()),
+        }
+    }
+
+    /// 受け付けを止め、処理中のリクエストが終わった後に呼ぶ。
+    /// バックグラウンドのタスクも終わるのを待ってから変更を書き出す。
+    pub fn finish(runtime: &Runtime, world: &SharedWorld) -> Result<(), Error> {
+        runtime.block_on(RealWorld::shutdown(world))
+    }
+
+    /// 実行時刻が来たジョブを確かめる間隔
+    const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// 積まれたジョブがあるかを確かめる間隔
+    const JOB_WORKER_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// 止める合図を受けるまで `period` 毎に `tick` を実行するタスクを `runtime` で立てる。
+    /// `tick` はストレージやメールを待つので、ランタイムのワーカーを塞がないようにブロッキング用のスレッドで実行する。
+    /// 前の `tick` が終わるまで次は実行しない。
+    fn spawn_periodic<F>(runtime: &Runtime, world: &SharedWorld, period: Duration, tick: F) -> Result<(), Error>
+    where
+        F: Fn(&RealWorld) + Send + Sync + 'static,
+    {
+        let mut interval = {
+            let _guard = runtime.enter();
+            tokio::time::interval(period)
+        };
+        let ticks = stream::poll_fn(move |cx| interval.poll_tick(cx).map(Some));
+        let shared = world.clone();
+        let tick = Arc::new(tick);
+        let task = ticks.take_until(world.stopping()).for_each(move |_| {
+            let (world, tick) = (shared.clone(), tick.clone());
+            tokio::task::spawn_blocking(move || tick(&world)).map(|_| ())
+        });
+        world.track(runtime.spawn(task));
+        Ok(())
+    }
+
+    /// 定期実行するジョブを登録し、止める合図を受けるまで毎分実行するタスクを `runtime` で立てる。
+    /// 失敗したジョブはログに残して、次の実行時刻を待つ。
+    pub fn spawn_maintenance(runtime: &Runtime, world: &SharedWorld) -> Result<(), Error> {
+        world.schedule_maintenance()?;
+        spawn_periodic(runtime, world, MAINTENANCE_INTERVAL, |world| {
+            if let Err(e) = world.run_due_jobs() {
+                world.logging_component().warn(&format!("maintenance failed: {}", e));
+            }
+        })
+    }
+
+    /// 積まれたジョブを、止める合図を受けるまで毎秒実行するワーカーを `runtime` で立てる。
+    /// 失敗したジョブのやり直しはキューに任せるので、ここではキュー自体が使えなかった時だけログに残す。
+    pub fn spawn_job_worker(runtime: &Runtime, world: &SharedWorld) -> Result<(), Error> {
+        spawn_periodic(runtime, world, JOB_WORKER_INTERVAL, |world| {
+            if let Err(e) = world.work_jobs() {
+                world.logging_component().warn(&format!("job worker failed: {}", e));
+            }
+        })
+    }
+
+    /// イベントを流し続ける受け口(WebSocket, SSE)で、接続中のクライアントへ値を配る。
+    /// 切断された接続は次に配る時に取り除く。
+    pub struct Subscribers<T> {
+        senders: Arc<Mutex<Vec<UnboundedSender<T>>>>,
+    }
+
+    impl<T: Clone> Subscribers<T> {
+        fn add(&self) -> UnboundedReceiver<T> {
+            let (sender, receiver) = mpsc::unbounded();
+            if let Ok(mut senders) = self.senders.lock() {
+                senders.push(sender);
+            }
+            receiver
+        }
+
+        fn publish(&self, value: &T) {
+            if let Ok(mut senders) = self.senders.lock() {
+                senders.retain(|sender| sender.unbounded_send(value.clone()).is_ok());
+            }
+        }
+    }
+
+    impl<T> Clone for Subscribers<T> {
+        fn clone(&self) -> Self {
+            Subscribers {
+                senders: self.senders.clone(),
+            }
+        }
+    }
+
+    impl<T> Default for Subscribers<T> {
+        fn default() -> Self {
+            Subscribers {
+                senders: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+    }
+
+    /// ログインしているユーザーのIDを入れるヘッダ
+    pub const ACTOR_HEADER: &str = "x-user-id";
+
+    /// 中で `?` を使って、受け口で返すエラーを1つの値にまとめる
+    fn attempt<T, F: FnOnce() -> Result<T, PresentationError>>(f: F) -> Result<T, PresentationError> {
+        f()
+    }
+
+    /// `x-user-id` の値
+    fn actor(value: Option<&str>) -> Result<UserId, PresentationError> {
+        value
+            .and_then(|value| Uuid::parse_str(value).ok())
+            .map(UserId::new)
+            .ok_or_else(|| PresentationError::new(ErrorKind::Unauthenticated, "missing or invalid x-user-id"))
+    }
+
+    /// UUIDとして読めないIDのユーザーは居ない
+    fn user_id(id: &str) -> Result<UserId, PresentationError> {
+        Uuid::parse_str(id)
+            .map(UserId::new)
+            .map_err(|_| PresentationError::new(ErrorKind::NotFound, format!("not found: {}", id)))
+    }
+
+    /// 本人のアカウントのIDだけを通す
+    fn own_account(actor: UserId, id: &str) -> Result<UserId, PresentationError> {
+        let id = user_id(id)?;
+        if actor != id {
+            return Err(PresentationError::new(ErrorKind::Forbidden, "only the user can do this"));
+        }
+        Ok(id)
+    }
+
+    /// 認証したユーザー
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Principal {
+        pub user_id: UserId,
+        /// APIトークンで認証した時だけ、そのトークンに許されている操作が入る
+        pub scopes: Option<BTreeSet<Scope>>,
+    }
+
+    impl Principal {
+        /// セッション等で認証した時は何でも許す。Adminのスコープは他の全てのスコープを含む
+        pub fn allows(&self, scope: Scope) -> bool {
+            match self.scopes {
+                Some(ref scopes) => scopes.contains(&scope) || scopes.contains(&Scope::Admin),
+                None => true,
+            }
+        }
+
+        /// `scope` が許されていなければForbidden
+        pub fn require(&self, scope: Scope) -> Result<&UserId, PresentationError> {
+            if !self.allows(scope) {
+                let message = format!("api token does not allow {:?}", scope);
+                return Err(PresentationError::new(ErrorKind::Forbidden, message));
+            }
+            Ok(&self.user_id)
+        }
+    }
+
+    /// 受け口が受け取った認証情報。どれも受け取ったままの文字列で渡す
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct Credentials<'a> {
+        /// `Authorization` の値。`Bearer <APIトークン>` の形でなければ誤った認証情報
+        pub authorization: Option<&'a str>,
+        /// セッションのID
+        pub session: Option<&'a str>,
+        /// `x-user-id` の値。設定で信じる事にしている時だけ見る
+        pub actor: Option<&'a str>,
+    }
+
+    /// APIトークン、セッション、(設定で信じる事にしていれば) `x-user-id` の順に見て、
+    /// 最初に見つかった認証情報で認証する。認証情報が無ければNone。
+    /// 誤っている理由は攻撃の手がかりになるので、どれも同じUnauthenticatedにする
+    pub fn principal(world: &RealWorld, credentials: Credentials) -> Result<Option<Principal>, PresentationError> {
+        let invalid = |what: &str| PresentationError::new(ErrorKind::Unauthenticated, format!("invalid {}", what));
+        if let Some(value) = credentials.authorization {
+            let token = value.strip_prefix("Bearer ").ok_or_else(|| invalid("authorization header"))?;
+            let token = world
+                .api_token_repository()
+                .authenticate_token(token.trim())
+                .map_err(|_| invalid("api token"))?;
+            return Ok(Some(Principal {
+                user_id: token.owner,
+                scopes: Some(token.scopes),
+            }));
+        }
+        if let Some(id) = credentials.session {
+            let id = Uuid::parse_str(id).map_err(|_| invalid("session"))?;
+            let session = world
+                .session_repository()
+                .validate_session(SessionId::new(id))
+                .map_err(|_| invalid("session"))?;
+            return Ok(Some(Principal {
+                user_id: session.user_id,
+                scopes: None,
+            }));
+        }
+        match credentials.actor {
+            Some(value) if world.config_component().trust_actor_header() => Ok(Some(Principal {
+                user_id: actor(Some(value))?,
+                scopes: None,
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    /// 認証したユーザーに `scope` が許されていれば、そのユーザーのID
+    fn authorized(principal: Option<Principal>, scope: Scope) -> Result<UserId, PresentationError> {
+        match principal {
+            Some(principal) => principal.require(scope).cloned(),
+            None => Err(PresentationError::new(ErrorKind::Unauthenticated, "authentication required")),
+        }
+    }
+
+    pub mod controller {
+        //! 受け口から呼ぶ入り口。受け取った値をユースケースの入力に直し、誰として呼ぶかでユースケースの重ね方を選ぶ。
+        //! HTTPのハンドラやCLIのコマンドは `HaveUserController` だけに頼り、RealWorldでの組み立てを知らない。
+
+        use adapter::user_id;
+        use entity::user::{Name, UserId};
+        use env::RealWorld;
+        use failure::Error;
+        use usecase::admin::{PurgeUserInteractor, RestoreUserInteractor, SuspendUserInteractor};
+        use usecase::delete_account::{ConfirmAccountDeletionInteractor, RequestAccountDeletionInteractor};
+        use std::net::IpAddr;
+        use usecase::dto::{ProfileDto, UserDto, UserSummaryDto};
+        use usecase::export_users::{Export, ExportUsersInteractor};
+        use usecase::get_user::GetUserInteractor;
+        use usecase::import_users::{Import, ImportUsersInteractor};
+        use usecase::list_users::{ListUsersInteractor, ListUsersQuery, Page};
+        use usecase::presentation_error::{ErrorKind, PresentationError};
+        use usecase::register_user::NewUser;
+        use usecase::rename_user::{RenameUserInteractor, UserRename};
+        use usecase::search_users::{SearchUsersInteractor, UserSearch};
+        use usecase::signup_region::{RecordSignupRegionInteractor, SignupOrigin};
+        use usecase::{Decorate, UseCase};
+
+        /// 誰として呼ぶか
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum Caller {
+            /// サーバー上で運用する人(CLI等)。権限は確かめない
+            Operator,
+            /// ログインしているユーザー。ユースケースで権限を確かめる
+            User(UserId),
+        }
+
+        impl Caller {
+            /// 本人のアカウントだけを通す。運用する人は誰のアカウントでも扱える
+            fn ensure_own_account(&self, id: &UserId) -> Result<(), PresentationError> {
+                match *self {
+                    Caller::User(ref actor) if actor != id => {
+                        Err(PresentationError::new(ErrorKind::Forbidden, "only the user can do this"))
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        /// ユーザーを扱う操作。IDは受け口から受け取ったままの文字列で渡す
+        pub trait UserController {
+            fn register(&self, new_user: NewUser) -> Result<UserDto, Error>;
+            /// 登録の要求を送ってきたIPアドレスから、登録した地域をプロフィールに記録する
+            fn record_signup_region(&self, id: &str, ip: IpAddr) -> Result<ProfileDto, Error>;
+            fn get(&self, caller: &Caller, id: &str) -> Result<UserDto, Error>;
+            fn list(&self, caller: &Caller, query: ListUsersQuery) -> Result<Page<UserSummaryDto>, Error>;
+            fn search(&self, caller: &Caller, search: UserSearch) -> Result<Vec<UserSummaryDto>, Error>;
+            fn rename(&self, caller: &Caller, id: &str, name: &str) -> Result<UserDto, Error>;
+            /// 退会の確認用のトークンを発行する。ユーザーは自分のアカウントしか退会できない
+            fn request_deletion(&self, caller: &Caller, id: &str) -> Result<String, Error>;
+            fn confirm_deletion(&self, caller: &Caller, id: &str, token: String) -> Result<UserDto, Error>;
+            /// 停止・復元・完全な削除は、ユーザーとして呼ぶならユーザーを管理できる人だけ
+            fn suspend(&self, caller: &Caller, id: &str) -> Result<UserDto, Error>;
+            fn restore(&self, caller: &Caller, id: &str) -> Result<UserDto, Error>;
+            /// 退会したユーザーだけを、紐づくデータごと消す
+            fn purge(&self, caller: &Caller, id: &str) -> Result<UserDto, Error>;
+            /// ファイルからまとめて読み込めるのは運用する人だけ
+            fn import(&self, import: Import) -> Result<usize, Error>;
+            /// ファイルへまとめて書き出せるのも運用する人だけ
+            fn export(&self, export: Export) -> Result<usize, Error>;
+        }
+
+        pub trait HaveUserController {
+            fn user_controller(&self) -> &impl UserController;
+        }
+
+        /// ユーザーとして呼ぶ時は、`impl RealWorld` で権限の確認まで重ねたユースケースを使う
+        impl UserController for RealWorld {
+            fn register(&self, new_user: NewUser) -> Result<UserDto, Error> {
+                Ok(self.register_user_use_case().execute(new_user)?)
+            }
+
+            fn record_signup_region(&self, id: &str, ip: IpAddr) -> Result<ProfileDto, Error> {
+                let origin = SignupOrigin { user_id: user_id(id)?, ip };
+                Ok(RecordSignupRegionInteractor::new(self).logged().execute(origin)?)
+            }
+
+            fn get(&self, caller: &Caller, id: &str) -> Result<UserDto, Error> {
+                let id = user_id(id)?;
+                match *caller {
+                    Caller::Operator => GetUserInteractor::new(self).logged().execute(id),
+                    Caller::User(ref actor) => self.get_user_use_case(actor.clone()).execute(id),
+                }
+                .map_err(Error::from)
+            }
+
+            fn list(&self, caller: &Caller, query: ListUsersQuery) -> Result<Page<UserSummaryDto>, Error> {
+                match *caller {
+                    Caller::Operator => ListUsersInteractor::new(self).logged().execute(query),
+                    Caller::User(ref actor) => self.list_users_use_case(actor.clone()).execute(query),
+                }
+                .map_err(Error::from)
+            }
+
+            fn search(&self, caller: &Caller, search: UserSearch) -> Result<Vec<UserSummaryDto>, Error> {
+                match *caller {
+                    Caller::Operator => SearchUsersInteractor::new(self).logged().execute(search),
+                    Caller::User(ref actor) => self.search_users_use_case(actor.clone()).execute(search),
+                }
+                .map_err(Error::from)
+            }
+
+            fn rename(&self, caller: &Caller, id: &str, name: &str) -> Result<UserDto, Error> {
+                let input = UserRename {
+                    id: user_id(id)?,
+                    name: Name::new(name)?,
+                };
+                match *caller {
+                    Caller::Operator => RenameUserInteractor::new(self).transactional().logged().execute(input),
+                    Caller::User(ref actor) => self.rename_user_use_case(actor.clone()).execute(input),
+                }
+                .map_err(Error::from)
+            }
+
+            fn request_deletion(&self, caller: &Caller, id: &str) -> Result<String, Error> {
+                let id = user_id(id)?;
+                caller.ensure_own_account(&id)?;
+                match *caller {
+                    Caller::Operator => RequestAccountDeletionInteractor::new(self).execute(id),
+                    Caller::User(_) => self.request_account_deletion_use_case().execute(id),
+                }
+                .map_err(Error::from)
+            }
+
+            fn confirm_deletion(&self, caller: &Caller, id: &str, token: String) -> Result<UserDto, Error> {
+                caller.ensure_own_account(&user_id(id)?)?;
+                match *caller {
+                    Caller::Operator => {
+                        ConfirmAccountDeletionInteractor::new(self).transactional().logged().execute(token)
+                    }
+                    Caller::User(_) => self.confirm_account_deletion_use_case().execute(token),
+                }
+                .map_err(Error::from)
+            }
+
+            fn suspend(&self, caller: &Caller, id: &str) -> Result<UserDto, Error> {
+                let id = user_id(id)?;
+                match *caller {
+                    Caller::Operator => SuspendUserInteractor::new(self).transactional().logged().execute(id),
+                    Caller::User(ref actor) => self.suspend_user_use_case(actor.clone()).execute(id),
+                }
+                .map_err(Error::from)
+            }
+
+            fn restore(&self, caller: &Caller, id: &str) -> Result<UserDto, Error> {
+                let id = user_id(id)?;
+                match *caller {
+                    Caller::Operator => RestoreUserInteractor::new(self).transactional().logged().execute(id),
+                    Caller::User(ref actor) => self.restore_user_use_case(actor.clone()).execute(id),
+                }
+                .map_err(Error::from)
+            }
+
+            fn purge(&self, caller: &Caller, id: &str) -> Result<UserDto, Error> {
+                let id = user_id(id)?;
+                match *caller {
+                    Caller::Operator => PurgeUserInteractor::new(self).transactional().logged().execute(id),
+                    Caller::User(ref actor) => self.purge_user_use_case(actor.clone()).execute(id),
+                }
+                .map_err(Error::from)
+            }
+
+            fn import(&self, import: Import) -> Result<usize, Error> {
+                Ok(ImportUsersInteractor::new(self).logged().execute(import)?)
+            }
+
+            fn export(&self, export: Export) -> Result<usize, Error> {
+                Ok(ExportUsersInteractor::new(self).logged().execute(export)?)
+            }
+        }
+
+        impl HaveUserController for RealWorld {
+            fn user_controller(&self) -> &impl UserController {
+                self
+            }
+        }
+    }
+
+    pub mod presenter {
+        //! ユースケースの結果(DTO)を、受け口ごとの見せ方(ViewModel)に変える。
+        //! 表の列や日時の書式、ページ送りの情報等の見せ方の決め事はここに置き、ユースケースには持ち込まない。
+
+        use chrono::prelude::*;
+        use component::time::{to_timezone, TimeComponent};
+        use std::fmt;
+        use std::iter;
+        use usecase::dto::{UserDto, UserSummaryDto};
+        use usecase::list_users::Page;
+        use utoipa::ToSchema;
+
+        /// 1つの受け口での見せ方
+        pub trait Presenter<T> {
+            type ViewModel;
+            fn present(&self, output: T) -> Self::ViewModel;
+        }
+
+        /// CLIで表示する表
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct Table {
+            pub header: Vec<String>,
+            pub rows: Vec<Vec<String>>,
+            /// 表の下に出す1行(ページの位置等)
+            pub footer: Option<String>,
+        }
+
+        impl Table {
+            /// 列の幅を揃えた文字列にする。行末に空白は付けない
+            pub fn render(&self) -> String {
+                let mut widths = vec![0; self.header.len()];
+                for row in iter::once(&self.header).chain(&self.rows) {
+                    for (width, cell) in widths.iter_mut().zip(row) {
+                        *width = (*width).max(cell.chars().count());
+                    }
+                }
+                let mut lines: Vec<String> = iter::once(&self.header)
+                    .chain(&self.rows)
+                    .map(|row| {
+                        let cells: Vec<String> = row
+                            .iter()
+                            .zip(&widths)
+                            .map(|(cell, &width)| format!("{:<width$}", cell, width = width))
+                            .collect();
+                        cells.join("  ").trim_end().to_string()
+                    })
+                    .collect();
+                lines.extend(self.footer.clone());
+                lines.join("\n")
+            }
+        }
+
+        /// CLIの表にする。日時は見る人のタイムゾーン `Tz` で見せる
+        pub struct TablePresenter<Tz: TimeZone> {
+            /// 表を作った時刻。一覧にはいつの時点の結果かを添える
+            now: DateTime<Tz>,
+        }
+
+        impl<Tz: TimeZone> TablePresenter<Tz>
+        where
+            Tz::Offset: fmt::Display,
+        {
+            pub fn new<T: TimeComponent>(time: &T, tz: &Tz) -> TablePresenter<Tz> {
+                TablePresenter { now: time.now_in(tz) }
+            }
+
+            /// 秒までは要らないので分までにする
+            fn time(&self, time: &DateTime<Utc>) -> String {
+                to_timezone(time, &self.now.timezone()).format("%Y-%m-%d %H:%M").to_string()
+            }
+        }
+
+        const USER_COLUMNS: [&str; 6] = ["ID", "NAME", "EMAIL", "ROLE", "STATUS", "CREATED"];
+
+        fn user_header() -> Vec<String> {
+            USER_COLUMNS.iter().map(|column| column.to_string()).collect()
+        }
+
+        impl<Tz: TimeZone> Presenter<UserDto> for TablePresenter<Tz>
+        where
+            Tz::Offset: fmt::Display,
+        {
+            type ViewModel = Table;
+            fn present(&self, user: UserDto) -> Table {
+                let created = self.time(&user.create_time);
+                let row = vec![user.id, user.name, user.email, user.role, user.status, created];
+                Table {
+                    header: user_header(),
+                    rows: vec![row],
+                    footer: None,
+                }
+            }
+        }
+
+        impl<Tz: TimeZone> Presenter<Page<UserSummaryDto>> for TablePresenter<Tz>
+        where
+            Tz::Offset: fmt::Display,
+        {
+            type ViewModel = Table;
+            fn present(&self, page: Page<UserSummaryDto>) -> Table {
+                let footer = format!(
+                    "page {}/{} ({} users) as of {}",
+                    page.page,
+                    page.total_pages().max(1),
+                    page.total,
+                    self.now.format("%Y-%m-%d %H:%M %:z")
+                );
+                let rows = page
+                    .items
+                    .into_iter()
+                    .map(|user| {
+                        let created = self.time(&user.create_time);
+                        vec![user.id, user.name, user.email, user.role, user.status, created]
+                    })
+                    .collect();
+                Table {
+                    header: user_header(),
+                    rows,
+                    footer: Some(footer),
+                }
+            }
+        }
+
+        /// HTTP等でJSONとして返す形にする
+        pub struct JsonPresenter;
+
+        /// 1ページ分の結果に、ページを送るための情報を足したもの
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+        pub struct PageView<T> {
+            pub items: Vec<T>,
+            pub page: usize,
+            pub per_page: usize,
+            /// 全ページ合わせた件数
+            pub total: usize,
+            pub total_pages: usize,
+            /// 最後のページではNone
+            pub next_page: Option<usize>,
+        }
+
+        impl<T> Presenter<Page<T>> for JsonPresenter {
+            type ViewModel = PageView<T>;
+            fn present(&self, page: Page<T>) -> PageView<T> {
+                let total_pages = page.total_pages();
+                PageView {
+                    next_page: if page.page < total_pages { Some(page.page + 1) } else { None },
+                    items: page.items,
+                    page: page.page,
+                    per_page: page.per_page,
+                    total: page.total,
+                    total_pages,
+                }
+            }
+        }
+
+        /// 1人分の詳しい情報は、DTOの形のまま返す
+        impl Presenter<UserDto> for JsonPresenter {
+            type ViewModel = UserDto;
+            fn present(&self, user: UserDto) -> UserDto {
+                user
+            }
+        }
+    }
+
+    pub mod http {
+        //! `/users` のREST API。仕様は `/openapi.json` で返す。
+        //! `/users/events` ではユーザーの変更をServer-Sent Eventsで流し続ける。
+        //! 認証は `authenticate` ミドルウェアが全てのルートの前で行い、ハンドラは認証済みの `Principal` だけを見る。
+        //! `/admin` 以下はユーザーを管理する人向けのルートで、APIトークンならAdminのスコープが要る。
+
+        use adapter::graphql::{self, GraphQL};
+        use adapter::controller::{Caller, HaveUserController, UserController};
+        use adapter::presenter::{JsonPresenter, PageView, Presenter};
+        use adapter::{self, attempt, Credentials, Principal, SharedWorld, Stop, Subscribers, ACTOR_HEADER};
+        use async_graphql::futures_util::FutureExt;
+        use axum::extract::rejection::JsonRejection;
+        use axum::extract::{ConnectInfo, Path, Query, Request, State};
+        use axum::http::header::{ACCEPT_LANGUAGE, AUTHORIZATION, COOKIE, LINK, SET_COOKIE};
+        use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, Uri};
+        use axum::middleware::{self, Next};
+        use axum::response::sse::{Event, KeepAlive, Sse};
+        use axum::response::{IntoResponse, Response};
+        use axum::routing::{delete, get, post, put};
+        use axum::{Extension, Json, Router};
+        use chrono::Duration;
+        use component::config::{ConfigComponent, HaveConfigComponent};
+        use component::event_bus::{EventBusComponent, HaveEventBusComponent};
+        use component::health::HealthCheckComponent;
+        use component::log::{HaveLoggingComponent, LoggingComponent};
+        use entity::api_token::Scope;
+        use entity::credentials::PlainPassword;
+        use entity::group::GroupName;
+        use entity::session::SessionId;
+        use entity::user::{Email, Name, Permission, Role, User, UserEvent, UserId, UserStatus};
+        use entity::ValidationError;
+        use env::RealWorld;
+        use failure::Error;
+        use futures::future::Either;
+        use futures::StreamExt;
+        use repository::sessions::{HaveSessionRepository, SessionRepository};
+        use repository::users::{HaveUserQueries, UserQueries};
+        use serde::Serialize;
+        use std::collections::BTreeMap;
+        use std::future::{self, Future, IntoFuture, Ready};
+        use std::net::{SocketAddr, TcpListener};
+        use std::sync::Arc;
+        use tokio::runtime::{Handle, Runtime};
+        use tokio::task;
+        use usecase::{PermissionDenied, UseCase};
+        use usecase::authenticate_user::{LoginRequest, SESSION_TTL_HOURS};
+        use usecase::change_password::PasswordChange;
+        use usecase::password_reset::{PasswordResetConfirmation, PasswordResetRequest};
+        use usecase::dto::{scope_name, GroupDto, InvitationDto, IssuedApiTokenDto, SessionDto, UserDto, UserSummaryDto};
+        use usecase::error_message::ErrorMessage;
+        use usecase::invite_user::{InvitationAcceptance, NewInvitation};
+        use usecase::issue_api_token::ApiTokenRequest;
+        use usecase::list_users::ListUsersQuery;
+        use usecase::manage_groups::Membership;
+        use usecase::presentation_error::{ErrorKind, PresentationError};
+        use usecase::register_user::NewUser;
+        use usecase::search_users::UserSearch;
+        use utoipa::openapi::OpenApi as Document;
+        use utoipa::openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme};
+        use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+        use uuid::Uuid;
+
+        /// 仕様の中での、`x-user-id` ヘッダーの認証方式の名前。各ハンドラの `security` にも同じ名前を書く
+        pub const ACTOR_SCHEME: &str = "actor";
+        /// 仕様の中での、`Authorization: Bearer` のAPIトークンの認証方式の名前
+        pub const BEARER_SCHEME: &str = "bearer";
+        /// 仕様の中での、セッションのCookieの認証方式の名前
+        pub const SESSION_SCHEME: &str = "session";
+
+        /// ログインセッションのIDを入れるCookie
+        pub const SESSION_COOKIE: &str = "layered_session";
+
+        /// 認証しなくても呼べる変更系のルート。登録・ログイン・パスワードの再設定・招待の受け入れと、
+        /// 操作ごとに認証を確かめるGraphQL
+        const PUBLIC_MUTATIONS: [(&str, &str); 6] = [
+            ("POST", "/users"),
+            ("POST", "/sessions"),
+            ("POST", "/password-resets"),
+            ("POST", "/password-resets/confirm"),
+            ("POST", "/invitations/accept"),
+            ("POST", "/graphql"),
+        ];
+
+        /// パスワードを再設定する画面のパス。`public_url` の後ろに付けてメールに載せる
+        pub const PASSWORD_RESET_PAGE: &str = "/password-reset";
+
+        /// 招待を受け入れる画面のパス
+        pub const INVITATION_PAGE: &str = "/invitation";
+
+        /// ユーザーを管理する人向けのルートのパスの先頭
+        const ADMIN_PREFIX: &str = "/admin/";
+
+        /// パスは各ハンドラの `#[utoipa::path]` から、型はDTOの定義から作る
+        #[derive(OpenApi)]
+        #[openapi(
+            info(title = "layered", description = "Cake Pattern + Clean Architecture のサンプルのREST API"),
+            paths(
+                create_user,
+                list_users,
+                search_users,
+                get_user,
+                rename_user,
+                delete_user,
+                watch_users,
+                suspend_user,
+                restore_user,
+                purge_user,
+                create_group,
+                add_group_member,
+                remove_group_member,
+                create_session,
+                delete_session,
+                change_password,
+                issue_api_token,
+                request_password_reset,
+                confirm_password_reset,
+                invite_user,
+                accept_invitation
+            ),
+            modifiers(&SecuritySchemes)
+        )]
+        pub struct ApiDoc;
+
+        /// 誰として呼ぶかは `ACTOR_HEADER` か、APIトークンか、セッションのCookieで渡す
+        struct SecuritySchemes;
+
+        impl Modify for SecuritySchemes {
+            fn modify(&self, openapi: &mut Document) {
+                let components = openapi.components.get_or_insert_with(Default::default);
+                let actor = SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(ACTOR_HEADER)));
+                components.add_security_scheme(ACTOR_SCHEME, actor);
+                components.add_security_scheme(BEARER_SCHEME, SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+                let session = SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new(SESSION_COOKIE)));
+                components.add_security_scheme(SESSION_SCHEME, session);
+            }
+        }
+
+        /// `world` のイベントバスに、`/users/events` の接続へ変更を配る購読者を登録してからルーターを作る
+        pub fn router(world: SharedWorld) -> Result<Router, Error> {
+            let graphql = GraphQL::new(world.clone());
+            let sdl = graphql.sdl();
+            let changes: Subscribers<UserChange> = Subscribers::default();
+            let publisher = changes.clone();
+            world.event_bus_component().subscribe(
+                "server_sent_events",
+                Box::new(move |_: &RealWorld, user: &User, event: UserEvent| {
+                    publisher.publish(&UserChange::new(user, event));
+                    Ok(())
+                }),
+            );
+            Ok(Router::new()
+                .route("/openapi.json", get(|| future::ready(Json(ApiDoc::openapi()))))
+                .route("/health", get(health))
+                .route("/users", get(list_users).post(create_user))
+                .route("/users/search", get(search_users))
+                .route(
+                    "/users/events",
+                    get(
+                        move |State(world): State<SharedWorld>,
+                              principal: Option<Extension<Principal>>,
+                              Query(params): Query<WatchParams>| {
+                            watch_users(&world, &changes, principal, params)
+                        },
+                    ),
+                )
+                .route("/users/:id", get(get_user).patch(rename_user).delete(delete_user))
+                .route("/admin/users/:id", delete(purge_user))
+                .route("/admin/users/:id/suspend", post(suspend_user))
+                .route("/admin/users/:id/restore", post(restore_user))
+                .route("/admin/groups", post(create_group))
+                .route("/admin/groups/:name/members/:id", put(add_group_member).delete(remove_group_member))
+                .route("/sessions", post(create_session))
+                .route("/sessions/current", delete(delete_session))
+                .route("/sessions/current/password", put(change_password))
+                .route("/api-tokens", post(issue_api_token))
+                .route("/password-resets", post(request_password_reset))
+                .route("/password-resets/confirm", post(confirm_password_reset))
+                .route("/admin/invitations", post(invite_user))
+                .route("/invitations/accept", post(accept_invitation))
+                .route(
+                    "/graphql",
+                    // `GET` ではクライアントのコード生成等に使えるように、スキーマをSDLで返す
+                    get(move || future::ready(sdl.clone())).post(
+                        move |principal: Option<Extension<Principal>>, Json(request): Json<graphql::Request>| {
+                            execute_graphql(graphql.clone(), principal, request)
+                        },
+                    ),
+                )
+                .layer(middleware::from_fn_with_state(world.clone(), localize_errors))
+                .layer(middleware::from_fn_with_state(world.clone(), authenticate))
+                .with_state(world))
+        }
+
+        /// `addr` で待ち受けて、SIGTERMかSIGINTを受け取るまでリクエストを処理する
+        pub fn serve(runtime: &Runtime, world: RealWorld, addr: &str) -> Result<(), Error> {
+            let world = Arc::new(world);
+            adapter::spawn_maintenance(runtime, &world)?;
+            adapter::spawn_job_worker(runtime, &world)?;
+            serve_router(runtime, router(world.clone())?, addr)?;
+            adapter::finish(runtime, &world)
+        }
+
+        /// 他の受け口のルーターも同じように動かせるようにしておく
+        pub fn serve_router(runtime: &Runtime, app: Router, addr: &str) -> Result<(), Error> {
+            serve_router_until(runtime, app, TcpListener::bind(addr)?, adapter::shutdown_signal())
+        }
+
+        /// `stop` が完了したら新しい接続を受け付けるのをやめ、処理中のリクエストが終わってから返る
+        pub fn serve_router_until(
+            runtime: &Runtime,
+            app: Router,
+            listener: TcpListener,
+            stop: Stop,
+        ) -> Result<(), Error> {
+            listener.set_nonblocking(true)?;
+            // 止める合図を待つタスクも、この中で立てる
+            let _guard = runtime.enter();
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            // 登録した地域を引けるように、接続元のアドレスをハンドラへ渡す
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            let server = axum::serve(listener, app).with_graceful_shutdown(stop.clone()).into_future();
+            adapter::drain(runtime, server, stop)
+        }
+
+        /// エラーの時のレスポンス
+        #[derive(Debug, Serialize, ToSchema)]
+        pub struct ErrorBody {
+            pub error: String,
+            /// 入力のフィールド毎の誤り。フィールド名から理由を引く。どのフィールドの誤りでもなければ省く
+            #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+            #[schema(required = false)]
+            pub fields: BTreeMap<String, String>,
+        }
+
+        /// `localize_errors` で文言を直せるように、元のエラーもレスポンスに付けておく
+        impl IntoResponse for PresentationError {
+            fn into_response(self) -> Response {
+                let status = StatusCode::from_u16(self.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                let body = ErrorBody {
+                    error: self.message.clone(),
+                    fields: self.fields.clone(),
+                };
+                let mut response = (status, Json(body)).into_response();
+                response.extensions_mut().insert(self);
+                response
+            }
+        }
+
+        /// 本文を読めなければ422にする。型が合わない時は、serdeの誤りからどのフィールドの誤りかを読み取る。
+        /// 誤りは `b[0]: missing field `y` at line 1 column 23` のように、フィールドの場所・理由・位置の順に並ぶ。
+        fn json_body<T>(body: Result<Json<T>, JsonRejection>) -> Result<T, PresentationError> {
+            let rejection = match body {
+                Ok(Json(body)) => return Ok(body),
+                Err(rejection) => rejection,
+            };
+            let error = PresentationError::new(ErrorKind::Validation, rejection.body_text());
+            if let JsonRejection::JsonDataError(_) = rejection {
+                let detail = error.message.split_once("target type: ").map(|(_, detail)| detail).unwrap_or_default();
+                let (path, reason) = match detail.split_once(": ") {
+                    Some((path, reason)) if !path.contains(' ') => (Some(path), reason),
+                    _ => (None, detail),
+                };
+                let reason = reason.split(" at line ").next().unwrap_or_default();
+                let missing = reason.strip_prefix("missing field `").and_then(|rest| rest.split('`').next());
+                let field = match (path, missing) {
+                    (Some(path), Some(missing)) => format!("{}.{}", path, missing),
+                    (None, Some(missing)) => missing.to_string(),
+                    (Some(path), None) => path.to_string(),
+                    (None, None) => return Err(error),
+                };
+                let reason = match missing {
+                    Some(_) => "missing required field".to_string(),
+                    None => reason.to_string(),
+                };
+                return Err(error.with_field(field, reason));
+            }
+            Err(error)
+        }
+
+        /// ユースケースを呼ぶ前に、値オブジェクトを作れるかを全てのフィールドについて確かめる。
+        /// 本文のフィールド名と、そのフィールドから値オブジェクトを作った時の誤りを渡す。
+        fn validate_fields(fields: &[(&str, Option<ValidationError>)]) -> Result<(), PresentationError> {
+            let mut errors = fields
+                .iter()
+                .filter_map(|(field, error)| error.as_ref().map(|error| (field, error)));
+            let (field, first) = match errors.next() {
+                Some(error) => error,
+                None => return Ok(()),
+            };
+            let error = PresentationError::localizable(ErrorKind::Validation, first);
+            let error = error.with_localizable_field(field, first);
+            Err(errors.fold(error, |error, (field, other)| error.with_localizable_field(field, other)))
+        }
+
+        #[derive(Debug, Deserialize, IntoParams)]
+        #[into_params(parameter_in = Query)]
+        pub struct ListParams {
+            /// 1始まり
+            pub page: Option<usize>,
+            /// 無ければ設定の件数
+            pub per_page: Option<usize>,
+            /// `項目:向き` の形(例: `create_time:desc`)。項目はname, email, create_time、向きはasc, descで、向きを省くとasc
+            pub sort: Option<String>,
+            /// このドメインのメールアドレスのユーザーだけにする
+            pub email_domain: Option<String>,
+        }
+
+        impl ListParams {
+            fn query(&self) -> Result<ListUsersQuery, PresentationError> {
+                let mut query = ListUsersQuery {
+                    page: self.page.unwrap_or(1),
+                    per_page: self.per_page,
+                    ..ListUsersQuery::default()
+                };
+                if let Some(ref sort) = self.sort {
+                    let (field, order) = sort.split_once(':').unwrap_or((sort, "asc"));
+                    let invalid = |e: Error| PresentationError::new(ErrorKind::Validation, &e).with_field("sort", e);
+                    query.sort = field.parse().map_err(invalid)?;
+                    query.order = order.parse().map_err(invalid)?;
+                }
+                if let Some(ref domain) = self.email_domain {
+                    // Linkヘッダーにそのまま書けるように、ドメインに使える文字だけを受け付ける
+                    if domain.is_empty() || !domain.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-') {
+                        let message = format!("invalid email domain: {}", domain);
+                        let error = PresentationError::new(ErrorKind::Validation, &message);
+                        return Err(error.with_field("email_domain", message));
+                    }
+                    query.filter.email_domain = Some(domain.clone());
+                }
+                Ok(query)
+            }
+
+            /// 他の条件はそのままで、ページだけを変えた一覧のURL
+            fn link(&self, path: &str, page: usize, per_page: usize, rel: &str) -> String {
+                let mut uri = format!("{}?page={}&per_page={}", path, page, per_page);
+                if let Some(ref sort) = self.sort {
+                    uri.push_str(&format!("&sort={}", sort));
+                }
+                if let Some(ref domain) = self.email_domain {
+                    uri.push_str(&format!("&email_domain={}", domain));
+                }
+                format!("<{}>; rel=\"{}\"", uri, rel)
+            }
+        }
+
+        #[derive(Debug, Deserialize, ToSchema)]
+        pub struct RenameBody {
+            pub name: String,
+        }
+
+        #[derive(Debug, Deserialize, ToSchema)]
+        pub struct LoginBody {
+            pub name: String,
+            pub password: String,
+        }
+
+        #[derive(Debug, Deserialize, ToSchema)]
+        pub struct PasswordChangeBody {
+            pub old_password: String,
+            pub new_password: String,
+        }
+
+        #[derive(Debug, Deserialize, ToSchema)]
+        pub struct ApiTokenBody {
+            /// `read_users`, `write_users`, `admin` のどれか
+            pub scopes: Vec<String>,
+            /// 省略すると有効期限なし
+            pub ttl_days: Option<i64>,
+        }
+
+        #[derive(Debug, Deserialize, ToSchema)]
+        pub struct PasswordResetBody {
+            pub email: String,
+        }
+
+        #[derive(Debug, Deserialize, ToSchema)]
+        pub struct PasswordResetConfirmationBody {
+            /// メールで送ったURLに付けたトークン
+            pub token: String,
+            pub new_password: String,
+        }
+
+        #[derive(Debug, Deserialize, ToSchema)]
+        pub struct GroupBody {
+            pub name: String,
+        }
+
+        #[derive(Debug, Deserialize, ToSchema)]
+        pub struct InvitationBody {
+            pub email: String,
+            /// admin, member, guestのどれか。無ければmember
+            pub role: Option<String>,
+        }
+
+        #[derive(Debug, Deserialize, ToSchema)]
+        pub struct InvitationAcceptanceBody {
+            /// メールで送ったURLに付けたトークン
+            pub token: String,
+            pub name: String,
+            pub password: String,
+        }
+
+        /// パスワードを設定し直した時のレスポンス
+        #[derive(Debug, Serialize, ToSchema)]
+        pub struct RevokedSessions {
+            /// 失効させたセッションの数
+            pub revoked_sessions: usize,
+        }
+
+        #[derive(Debug, Deserialize, IntoParams)]
+        #[into_params(parameter_in = Query)]
+        pub struct DeleteParams {
+            /// 無ければ確認用のトークンを発行し、あればそのトークンで退会を確定する
+            pub confirmation_token: Option<String>,
+        }
+
+        /// 退会の確認用のトークンを発行した時のレスポンス
+        #[derive(Debug, Serialize, ToSchema)]
+        pub struct ConfirmationToken {
+            pub confirmation_token: String,
+        }
+
+        #[derive(Debug, Deserialize, IntoParams)]
+        #[into_params(parameter_in = Query)]
+        pub struct WatchParams {
+            /// この文字列で始まる名前のユーザーの変更だけを受け取る。無ければ全員
+            pub prefix: Option<String>,
+        }
+
+        /// `/users/events` で送る1件の変更。SSEのイベント名は `kind`、データは変更後のユーザー
+        #[derive(Debug, Clone)]
+        pub struct UserChange {
+            pub kind: &'static str,
+            pub user: UserSummaryDto,
+        }
+
+        impl UserChange {
+            /// 退会(無効化)は `deleted`、登録は `created`、それ以外の変更は `updated` にまとめる
+            pub fn new(user: &User, event: UserEvent) -> UserChange {
+                let kind = match event {
+                    UserEvent::Created => "created",
+                    UserEvent::Deactivated => "deleted",
+                    _ => "updated",
+                };
+                UserChange {
+                    kind,
+                    user: UserSummaryDto::from(user),
+                }
+            }
+        }
+
+        fn respond<T: Serialize>(status: StatusCode, result: Result<T, PresentationError>) -> Ready<Response> {
+            future::ready(match result {
+                Ok(body) => (status, Json(body)).into_response(),
+                Err(e) => e.into_response(),
+            })
+        }
+
+        /// APIトークン、セッションのCookie、(設定で信じる事にしていれば) `x-user-id` の順に見て、
+        /// 最初に見つかった認証情報で認証したユーザーをリクエストに付ける。
+        /// 認証情報が誤っていれば401、APIトークンのスコープが足りなければ403にする。
+        /// `/admin` 以下は読むだけのルートでも、APIトークンにAdminのスコープが要る。
+        /// 認証情報が無ければ、`PUBLIC_MUTATIONS` 以外の変更系のルートは401にする。
+        fn authenticate(
+            State(world): State<SharedWorld>,
+            mut request: Request,
+            next: Next,
+        ) -> impl Future<Output = Response> {
+            let read_only = [Method::GET, Method::HEAD, Method::OPTIONS].contains(request.method());
+            let public = PUBLIC_MUTATIONS
+                .iter()
+                .any(|&(method, path)| request.method() == method && request.uri().path() == path);
+            let admin = request.uri().path().starts_with(ADMIN_PREFIX);
+            let principal = adapter::principal(&world, credentials(request.headers()));
+            let result = principal.and_then(|principal| match principal {
+                Some(principal) => {
+                    let scope = match (admin, read_only) {
+                        (true, _) => Scope::Admin,
+                        (false, true) => Scope::ReadUsers,
+                        (false, false) => Scope::WriteUsers,
+                    };
+                    principal.require(scope)?;
+                    Ok(Some(principal))
+                }
+                None if !read_only && !public => {
+                    Err(PresentationError::new(ErrorKind::Unauthenticated, "authentication required"))
+                }
+                None => Ok(None),
+            });
+            match result {
+                Ok(principal) => {
+                    if let Some(principal) = principal {
+                        request.extensions_mut().insert(principal);
+                    }
+                    Either::Left(next.run(request))
+                }
+                Err(e) => Either::Right(future::ready(e.into_response())),
+            }
+        }
+
+        /// エラーの本文を `Accept-Language` の最初の言語の文言に直す。無ければ認証したユーザーのプロフィールのロケールにし、
+        /// どちらも分からなければ元の文言のまま返す。
+        fn localize_errors(
+            State(world): State<SharedWorld>,
+            request: Request,
+            next: Next,
+        ) -> impl Future<Output = Response> {
+            let locale = accept_language(request.headers()).or_else(|| {
+                let principal = request.extensions().get::<Principal>()?;
+                world.profile_locale(principal.user_id.clone())
+            });
+            next.run(request).map(move |mut response| {
+                match (locale, response.extensions_mut().remove::<PresentationError>()) {
+                    (Some(locale), Some(error)) => world.localize_error(&locale, error).into_response(),
+                    _ => response,
+                }
+            })
+        }
+
+        /// `Accept-Language` の最初の言語。q値による順位は見ない
+        fn accept_language(headers: &HeaderMap) -> Option<String> {
+            let value = headers.get(ACCEPT_LANGUAGE)?.to_str().ok()?;
+            let language = value.split(',').next()?.split(';').next()?.trim();
+            Some(language.to_string()).filter(|language| !language.is_empty() && language != "*")
+        }
+
+        /// ヘッダーの中の認証情報。ヘッダーの値が文字列として読めなければ空の値にして、誤った認証情報として断る
+        pub fn credentials(headers: &HeaderMap) -> Credentials<'_> {
+            Credentials {
+                authorization: headers.get(AUTHORIZATION).map(|value| value.to_str().unwrap_or_default()),
+                session: session_cookie(headers),
+                actor: headers.get(ACTOR_HEADER).and_then(|value| value.to_str().ok()),
+            }
+        }
+
+        /// `Cookie` ヘッダーの中の `SESSION_COOKIE` の値
+        fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+            headers
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|pair| {
+                    let mut parts = pair.trim().splitn(2, '=');
+                    match (parts.next(), parts.next()) {
+                        (Some(SESSION_COOKIE), Some(value)) => Some(value),
+                        _ => None,
+                    }
+                })
+                .next()
+        }
+
+        /// 認証したユーザーのID
+        fn authenticated(principal: Option<Extension<Principal>>) -> Result<UserId, PresentationError> {
+            match principal {
+                Some(Extension(principal)) => Ok(principal.user_id),
+                None => Err(PresentationError::new(ErrorKind::Unauthenticated, "authentication required")),
+            }
+        }
+
+        fn caller(principal: Option<Extension<Principal>>) -> Result<Caller, PresentationError> {
+            authenticated(principal).map(Caller::User)
+        }
+
+        /// スキーマの実行はスキーマを借りたままのFutureになるので、ブロックして良いスレッドへ渡して最後まで実行する
+        fn execute_graphql(
+            graphql: GraphQL,
+            principal: Option<Extension<Principal>>,
+            request: graphql::Request,
+        ) -> impl Future<Output = Response> {
+            let handle = Handle::current();
+            let actor = principal.map(|Extension(principal)| principal.user_id.as_uuid().to_string());
+            task::spawn_blocking(move || handle.block_on(graphql.execute(actor.as_deref(), request))).map(|result| {
+                match result {
+                    Ok(response) => Json(response).into_response(),
+                    Err(_) => PresentationError::new(ErrorKind::Internal, "internal error").into_response(),
+                }
+            })
+        }
+
+        #[utoipa::path(
+            post,
+            path = "/users",
+            request_body = NewUser,
+            responses(
+                (status = 201, description = "登録したユーザー", body = UserDto),
+                (status = 409, description = "名前かメールアドレスが使われている", body = ErrorBody),
+                (status = 422, description = "入力の誤り", body = ErrorBody)
+            )
+        )]
+        fn create_user(
+            State(world): State<SharedWorld>,
+            client: Option<ConnectInfo<SocketAddr>>,
+            new_user: Result<Json<NewUser>, JsonRejection>,
+        ) -> Ready<Response> {
+            let result = json_body(new_user).and_then(|new_user| {
+                validate_fields(&[
+                    ("name", Name::new(&new_user.name).err()),
+                    ("email", Email::parse(&new_user.email).err()),
+                ])?;
+                let user = world.user_controller().register(new_user)?;
+                // 地域は統計に使うだけなので、記録できなくても登録は成功させる
+                if let Some(ConnectInfo(addr)) = client {
+                    if let Err(e) = world.user_controller().record_signup_region(&user.id, addr.ip()) {
+                        world.logging_component().warn(&format!("failed to record signup region: {}", e));
+                    }
+                }
+                Ok(user)
+            });
+            respond(StatusCode::CREATED, result)
+        }
+
+        #[utoipa::path(
+            get,
+            path = "/users",
+            params(ListParams),
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 200, description = "1ページ分のユーザー。前後のページがあればLinkヘッダーに `prev` `next` で載せる",
+                 body = PageView<UserSummaryDto>),
+                (status = 401, description = "誰として呼んだのかが分からない", body = ErrorBody),
+                (status = 403, description = "一覧を見る権限が無い", body = ErrorBody),
+                (status = 422, description = "並べ替えや絞り込みの条件の誤り", body = ErrorBody)
+            )
+        )]
+        fn list_users(
+            State(world): State<SharedWorld>,
+            principal: Option<Extension<Principal>>,
+            uri: Uri,
+            Query(params): Query<ListParams>,
+        ) -> Ready<Response> {
+            let result = params.query().and_then(|query| {
+                let page = world.user_controller().list(&caller(principal)?, query)?;
+                Ok(JsonPresenter.present(page))
+            });
+            let page = match result {
+                Ok(page) => page,
+                Err(e) => return future::ready(e.into_response()),
+            };
+            let mut links = Vec::new();
+            // 最後のページより後ろを開いた時は、最後のページを前のページにする
+            if page.page > 1 {
+                let prev = (page.page - 1).min(page.total_pages.max(1));
+                links.push(params.link(uri.path(), prev, page.per_page, "prev"));
+            }
+            if let Some(next) = page.next_page {
+                links.push(params.link(uri.path(), next, page.per_page, "next"));
+            }
+            let mut response = (StatusCode::OK, Json(page)).into_response();
+            if let Ok(links) = HeaderValue::from_str(&links.join(", ")) {
+                if !links.is_empty() {
+                    response.headers_mut().insert(LINK, links);
+                }
+            }
+            future::ready(response)
+        }
+
+        /// 検索で1度に返すユーザーの数の上限
+        const MAX_SEARCH_LIMIT: usize = 100;
+
+        #[derive(Debug, Deserialize, IntoParams)]
+        #[into_params(parameter_in = Query)]
+        pub struct SearchParams {
+            /// 名前やメールアドレス。綴りが少し違っていても見つかる
+            pub q: String,
+            /// 無ければ20。100を超える数は100として扱う
+            pub limit: Option<usize>,
+        }
+
+        #[utoipa::path(
+            get,
+            path = "/users/search",
+            params(SearchParams),
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 200, description = "よく合う順のユーザー", body = Vec<UserSummaryDto>),
+                (status = 401, description = "誰として呼んだのかが分からない", body = ErrorBody),
+                (status = 403, description = "一覧を見る権限が無い", body = ErrorBody),
+                (status = 422, description = "検索語が空", body = ErrorBody)
+            )
+        )]
+        fn search_users(
+            State(world): State<SharedWorld>,
+            principal: Option<Extension<Principal>>,
+            Query(params): Query<SearchParams>,
+        ) -> Ready<Response> {
+            let result = attempt(|| {
+                if params.q.trim().is_empty() {
+                    let error = PresentationError::new(ErrorKind::Validation, "empty search query");
+                    return Err(error.with_field("q", "empty search query"));
+                }
+                let search = UserSearch {
+                    query: params.q.clone(),
+                    limit: params.limit.unwrap_or(20).min(MAX_SEARCH_LIMIT),
+                };
+                Ok(world.user_controller().search(&caller(principal)?, search)?)
+            });
+            respond(StatusCode::OK, result)
+        }
+
+        #[utoipa::path(
+            get,
+            path = "/users/{id}",
+            params(("id" = String, Path, description = "ユーザーのID(UUID)")),
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 200, description = "ユーザー", body = UserDto),
+                (status = 401, description = "誰として呼んだのかが分からない", body = ErrorBody),
+                (status = 404, description = "ユーザーがいない", body = ErrorBody)
+            )
+        )]
+        fn get_user(
+            State(world): State<SharedWorld>,
+            principal: Option<Extension<Principal>>,
+            Path(id): Path<String>,
+        ) -> Ready<Response> {
+            let result = attempt(|| {
+                let user = world.user_controller().get(&caller(principal)?, &id)?;
+                Ok(user)
+            });
+            respond(StatusCode::OK, result)
+        }
+
+        #[utoipa::path(
+            patch,
+            path = "/users/{id}",
+            params(("id" = String, Path, description = "ユーザーのID(UUID)")),
+            request_body = RenameBody,
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 200, description = "名前を変えたユーザー", body = UserDto),
+                (status = 403, description = "他のユーザーの名前を変える権限が無い", body = ErrorBody),
+                (status = 404, description = "ユーザーがいない", body = ErrorBody),
+                (status = 409, description = "名前が使われている", body = ErrorBody)
+            )
+        )]
+        fn rename_user(
+            State(world): State<SharedWorld>,
+            principal: Option<Extension<Principal>>,
+            Path(id): Path<String>,
+            body: Result<Json<RenameBody>, JsonRejection>,
+        ) -> Ready<Response> {
+            let result = json_body(body).and_then(|body| {
+                validate_fields(&[("name", Name::new(&body.name).err())])?;
+                let user = world.user_controller().rename(&caller(principal)?, &id, &body.name)?;
+                Ok(user)
+            });
+            respond(StatusCode::OK, result)
+        }
+
+        /// 退会は本人しかできない
+        #[utoipa::path(
+            delete,
+            path = "/users/{id}",
+            params(("id" = String, Path, description = "ユーザーのID(UUID)"), DeleteParams),
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 202, description = "確認用のトークンを発行した", body = ConfirmationToken),
+                (status = 200, description = "退会したユーザー", body = UserDto),
+                (status = 403, description = "本人ではない", body = ErrorBody)
+            )
+        )]
+        fn delete_user(
+            State(world): State<SharedWorld>,
+            principal: Option<Extension<Principal>>,
+            Path(id): Path<String>,
+            Query(params): Query<DeleteParams>,
+        ) -> Ready<Response> {
+            let caller = match caller(principal) {
+                Ok(caller) => caller,
+                Err(e) => return future::ready(e.into_response()),
+            };
+            match params.confirmation_token {
+                None => {
+                    let result = attempt(|| {
+                        let confirmation_token = world.user_controller().request_deletion(&caller, &id)?;
+                        Ok(ConfirmationToken { confirmation_token })
+                    });
+                    respond(StatusCode::ACCEPTED, result)
+                }
+                Some(token) => {
+                    let result = attempt(|| {
+                        let user = world.user_controller().confirm_deletion(&caller, &id, token)?;
+                        Ok(user)
+                    });
+                    respond(StatusCode::OK, result)
+                }
+            }
+        }
+
+        #[utoipa::path(
+            post,
+            path = "/admin/users/{id}/suspend",
+            params(("id" = String, Path, description = "ユーザーのID(UUID)")),
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 200, description = "停止したユーザー。セッションは全て失効する", body = UserDto),
+                (status = 403, description = "ユーザーを管理する権限が無い", body = ErrorBody),
+                (status = 404, description = "ユーザーがいない", body = ErrorBody),
+                (status = 409, description = "Activeなユーザーではない", body = ErrorBody)
+            )
+        )]
+        fn suspend_user(
+            State(world): State<SharedWorld>,
+            principal: Option<Extension<Principal>>,
+            Path(id): Path<String>,
+        ) -> Ready<Response> {
+            let result = attempt(|| {
+                let user = world.user_controller().suspend(&caller(principal)?, &id)?;
+                Ok(user)
+            });
+            respond(StatusCode::OK, result)
+        }
+
+        #[utoipa::path(
+            post,
+            path = "/admin/users/{id}/restore",
+            params(("id" = String, Path, description = "ユーザーのID(UUID)")),
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 200, description = "Activeに戻したユーザー", body = UserDto),
+                (status = 403, description = "ユーザーを管理する権限が無い", body = ErrorBody),
+                (status = 404, description = "ユーザーがいない", body = ErrorBody),
+                (status = 409, description = "既にActiveなユーザー", body = ErrorBody)
+            )
+        )]
+        fn restore_user(
+            State(world): State<SharedWorld>,
+            principal: Option<Extension<Principal>>,
+            Path(id): Path<String>,
+        ) -> Ready<Response> {
+            let result = attempt(|| {
+                let user = world.user_controller().restore(&caller(principal)?, &id)?;
+                Ok(user)
+            });
+            respond(StatusCode::OK, result)
+        }
+
+        /// 退会したユーザーだけを、紐づくデータごと消す
+        #[utoipa::path(
+            delete,
+            path = "/admin/users/{id}",
+            params(("id" = String, Path, description = "ユーザーのID(UUID)")),
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 200, description = "消したユーザー", body = UserDto),
+                (status = 403, description = "ユーザーを管理する権限が無い", body = ErrorBody),
+                (status = 404, description = "ユーザーがいない", body = ErrorBody),
+                (status = 409, description = "退会していないユーザー", body = ErrorBody)
+            )
+        )]
+        fn purge_user(
+            State(world): State<SharedWorld>,
+            principal: Option<Extension<Principal>>,
+            Path(id): Path<String>,
+        ) -> Ready<Response> {
+            let result = attempt(|| {
+                let user = world.user_controller().purge(&caller(principal)?, &id)?;
+                Ok(user)
+            });
+            respond(StatusCode::OK, result)
+        }
+
+        #[utoipa::path(
+            post,
+            path = "/admin/groups",
+            request_body = GroupBody,
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 201, description = "作ったグループ。メンバーはいない", body = GroupDto),
+                (status = 403, description = "ユーザーを管理する権限が無い", body = ErrorBody),
+                (status = 409, description = "同じ名前のグループがある", body = ErrorBody),
+                (status = 422, description = "入力の誤り", body = ErrorBody)
+            )
+        )]
+        fn create_group(
+            State(world): State<SharedWorld>,
+            principal: Option<Extension<Principal>>,
+            body: Result<Json<GroupBody>, JsonRejection>,
+        ) -> Ready<Response> {
+            let result = json_body(body).and_then(|body| {
+                let actor = authenticated(principal)?;
+                let name = GroupName::new(&body.name)?;
+                Ok(world.create_group_use_case(actor).execute(name)?)
+            });
+            respond(StatusCode::CREATED, result)
+        }
+
+        #[utoipa::path(
+            put,
+            path = "/admin/groups/{name}/members/{id}",
+            params(
+                ("name" = String, Path, description = "グループの名前"),
+                ("id" = String, Path, description = "加えるユーザーのID(UUID)")
+            ),
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 200, description = "加えた後のグループ。既にメンバーなら何もしない", body = GroupDto),
+                (status = 403, description = "ユーザーを管理する権限が無い", body = ErrorBody),
+                (status = 404, description = "グループかユーザーがいない", body = ErrorBody)
+            )
+        )]
+        fn add_group_member(
+            State(world): State<SharedWorld>,
+            principal: Option<Extension<Principal>>,
+            Path((name, id)): Path<(String, String)>,
+        ) -> Ready<Response> {
+            let result = attempt(|| {
+                let actor = authenticated(principal)?;
+                let membership = membership(&name, &id)?;
+                Ok(world.add_group_member_use_case(actor).execute(membership)?)
+            });
+            respond(StatusCode::OK, result)
+        }
+
+        #[utoipa::path(
+            delete,
+            path = "/admin/groups/{name}/members/{id}",
+            params(
+                ("name" = String, Path, description = "グループの名前"),
+                ("id" = String, Path, description = "外すユーザーのID(UUID)")
+            ),
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 200, description = "外した後のグループ", body = GroupDto),
+                (status = 403, description = "ユーザーを管理する権限が無い", body = ErrorBody),
+                (status = 404, description = "グループがいないか、ユーザーがメンバーではない", body = ErrorBody)
+            )
+        )]
+        fn remove_group_member(
+            State(world): State<SharedWorld>,
+            principal: Option<Extension<Principal>>,
+            Path((name, id)): Path<(String, String)>,
+        ) -> Ready<Response> {
+            let result = attempt(|| {
+                let actor = authenticated(principal)?;
+                let membership = membership(&name, &id)?;
+                Ok(world.remove_group_member_use_case(actor).execute(membership)?)
+            });
+            respond(StatusCode::OK, result)
+        }
+
+        /// 名前として読めないグループや、UUIDとして読めないIDのユーザーは居ない
+        fn membership(name: &str, id: &str) -> Result<Membership, PresentationError> {
+            let group = GroupName::new(name)
+                .map_err(|_| PresentationError::new(ErrorKind::NotFound, format!("not found: {}", name)))?;
+            Ok(Membership {
+                group,
+                user_id: adapter::user_id(id)?,
+            })
+        }
+
+        /// 名前とパスワードでログインする。名前とパスワードのどちらが違うかは教えない
+        #[utoipa::path(
+            post,
+            path = "/sessions",
+            request_body = LoginBody,
+            responses(
+                (status = 201, description = "作ったセッション", body = SessionDto,
+                 headers(("set-cookie" = String, description = "セッションのIDを入れたCookie"))),
+                (status = 401, description = "名前かパスワードが違うか、Activeなユーザーではない", body = ErrorBody),
+                (status = 429, description = "続けて試行しすぎた", body = ErrorBody)
+            )
+        )]
+        fn create_session(
+            State(world): State<SharedWorld>,
+            body: Result<Json<LoginBody>, JsonRejection>,
+        ) -> Ready<Response> {
+            let result = json_body(body).and_then(|body| {
+                let login = LoginRequest {
+                    name: body.name,
+                    password: PlainPassword::new(&body.password),
+                };
+                let session = world.authenticate_user_use_case().execute(login)?;
+                Ok(session)
+            });
+            future::ready(match result {
+                Ok(session) => {
+                    let cookie = set_session_cookie(&world, &session.id, SESSION_TTL_HOURS * 3600);
+                    (StatusCode::CREATED, [(SET_COOKIE, cookie)], Json(session)).into_response()
+                }
+                Err(e) => e.into_response(),
+            })
+        }
+
+        /// ログアウトする。Cookieのセッションを失効させ、ブラウザにもCookieを消させる
+        #[utoipa::path(
+            delete,
+            path = "/sessions/current",
+            security(("session" = [])),
+            responses(
+                (status = 204, description = "失効させた", headers(("set-cookie" = String, description = "空のCookie"))),
+                (status = 401, description = "セッションが無いか、既に失効している", body = ErrorBody)
+            )
+        )]
+        fn delete_session(State(world): State<SharedWorld>, headers: HeaderMap) -> Ready<Response> {
+            let result = current_session(&headers).and_then(|id| {
+                world.session_repository().revoke_session(id)?;
+                Ok(())
+            });
+            future::ready(match result {
+                Ok(()) => {
+                    let cookie = set_session_cookie(&world, "", 0);
+                    (StatusCode::NO_CONTENT, [(SET_COOKIE, cookie)]).into_response()
+                }
+                Err(e) => e.into_response(),
+            })
+        }
+
+        /// `Set-Cookie` で送るセッションのCookie。`max_age` 秒で消える。`public_url` がHTTPSならHTTPSの時だけ送らせる
+        fn set_session_cookie(world: &SharedWorld, value: &str, max_age: i64) -> String {
+            let secure = if world.config_component().public_url().starts_with("https://") { "; Secure" } else { "" };
+            format!("{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}", SESSION_COOKIE, value, max_age, secure)
+        }
+
+        /// Cookieで渡されたセッションのID。APIトークン等で認証した時は無い
+        fn current_session(headers: &HeaderMap) -> Result<SessionId, PresentationError> {
+            credentials(headers)
+                .session
+                .and_then(|id| Uuid::parse_str(id).ok())
+                .map(SessionId::new)
+                .ok_or_else(|| PresentationError::new(ErrorKind::Unauthenticated, "session required"))
+        }
+
+        /// ログイン中のセッションのパスワードを変える。どのセッションかはCookieで決めるので、APIトークンでは呼べない
+        #[utoipa::path(
+            put,
+            path = "/sessions/current/password",
+            request_body = PasswordChangeBody,
+            security(("session" = [])),
+            responses(
+                (status = 200, description = "変更した。このセッション以外のセッションは全て失効する", body = RevokedSessions),
+                (status = 401, description = "セッションが無いか、今のパスワードが違う", body = ErrorBody),
+                (status = 422, description = "新しいパスワードが弱すぎる", body = ErrorBody)
+            )
+        )]
+        fn change_password(
+            State(world): State<SharedWorld>,
+            headers: HeaderMap,
+            body: Result<Json<PasswordChangeBody>, JsonRejection>,
+        ) -> Ready<Response> {
+            let result = json_body(body).and_then(|body| {
+                let change = PasswordChange {
+                    session_id: current_session(&headers)?,
+                    old: PlainPassword::new(&body.old_password),
+                    new: PlainPassword::new(&body.new_password),
+                };
+                let revoked_sessions = world.change_password_use_case().execute(change)?;
+                Ok(RevokedSessions { revoked_sessions })
+            });
+            respond(StatusCode::OK, result)
+        }
+
+        /// ロードバランサ等から稼働状態を確かめる。`/openapi.json` と同じく運用のためのルートなので、仕様には載せない
+        fn health(State(world): State<SharedWorld>) -> Ready<Response> {
+            let report = world.health();
+            let status = if report.healthy {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            future::ready((status, Json(report)).into_response())
+        }
+
+        /// DTOと同じスネークケースのスコープの名前を読む
+        fn scope(name: &str) -> Result<Scope, PresentationError> {
+            [Scope::ReadUsers, Scope::WriteUsers, Scope::Admin]
+                .iter()
+                .copied()
+                .find(|&scope| scope_name(scope) == name)
+                .ok_or_else(|| {
+                    let message = format!("unknown scope: {}", name);
+                    PresentationError::new(ErrorKind::Validation, &message).with_field("scopes", message)
+                })
+        }
+
+        /// 自分として呼べるAPIトークンを発行する。
+        /// APIトークンで呼んだ時は、そのトークンに許されているスコープしか付けられない
+        #[utoipa::path(
+            post,
+            path = "/api-tokens",
+            request_body = ApiTokenBody,
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 201, description = "発行したトークン。平文のトークンはこの応答でしか見られない", body = IssuedApiTokenDto),
+                (status = 401, description = "認証されていないか、ユーザーが有効でない", body = ErrorBody),
+                (status = 403, description = "呼んだトークンに許されていないスコープがある", body = ErrorBody),
+                (status = 422, description = "スコープが無いか、知らないスコープか、有効期限が正でない", body = ErrorBody)
+            )
+        )]
+        fn issue_api_token(
+            State(world): State<SharedWorld>,
+            principal: Option<Extension<Principal>>,
+            body: Result<Json<ApiTokenBody>, JsonRejection>,
+        ) -> Ready<Response> {
+            let result = json_body(body).and_then(|body| {
+                let owner = authenticated(principal.clone())?;
+                let scopes = body.scopes.iter().map(|name| scope(name)).collect::<Result<Vec<_>, _>>()?;
+                if let Some(Extension(principal)) = principal {
+                    for &scope in &scopes {
+                        principal.require(scope)?;
+                    }
+                }
+                let ttl = match body.ttl_days {
+                    Some(days) if days <= 0 => {
+                        let message = "ttl_days must be greater than 0";
+                        let error = PresentationError::new(ErrorKind::Validation, message);
+                        return Err(error.with_field("ttl_days", message));
+                    }
+                    ttl_days => ttl_days.map(Duration::days),
+                };
+                Ok(world.issue_api_token_use_case().execute(ApiTokenRequest { owner, scopes, ttl })?)
+            });
+            respond(StatusCode::CREATED, result)
+        }
+
+        /// 再設定用のURLをメールで送る。登録されているメールアドレスかどうかは教えない
+        #[utoipa::path(
+            post,
+            path = "/password-resets",
+            request_body = PasswordResetBody,
+            responses(
+                (status = 202, description = "登録されているメールアドレスなら、再設定用のURLを送った"),
+                (status = 422, description = "メールアドレスの形式の誤り", body = ErrorBody)
+            )
+        )]
+        fn request_password_reset(
+            State(world): State<SharedWorld>,
+            body: Result<Json<PasswordResetBody>, JsonRejection>,
+        ) -> Ready<Response> {
+            let result = json_body(body).and_then(|body| {
+                let request = PasswordResetRequest {
+                    email: body.email,
+                    reset_url: format!("{}{}", world.config_component().public_url(), PASSWORD_RESET_PAGE),
+                };
+                Ok(world.request_password_reset_use_case().execute(request)?)
+            });
+            future::ready(match result {
+                Ok(()) => StatusCode::ACCEPTED.into_response(),
+                Err(e) => e.into_response(),
+            })
+        }
+
+        #[utoipa::path(
+            post,
+            path = "/password-resets/confirm",
+            request_body = PasswordResetConfirmationBody,
+            responses(
+                (status = 200, description = "設定し直した。そのユーザーのセッションは全て失効する", body = RevokedSessions),
+                (status = 422, description = "新しいパスワードが弱すぎるか、トークンが誤っているか期限が切れている",
+                 body = ErrorBody)
+            )
+        )]
+        fn confirm_password_reset(
+            State(world): State<SharedWorld>,
+            body: Result<Json<PasswordResetConfirmationBody>, JsonRejection>,
+        ) -> Ready<Response> {
+            let result = json_body(body).and_then(|body| {
+                let confirmation = PasswordResetConfirmation {
+                    token: body.token,
+                    new_password: PlainPassword::new(&body.new_password),
+                };
+                let revoked_sessions = world.confirm_password_reset_use_case().execute(confirmation)?;
+                Ok(RevokedSessions { revoked_sessions })
+            });
+            respond(StatusCode::OK, result)
+        }
+
+        /// DTOと同じ小文字の役割の名前を読む
+        fn role(name: &str) -> Result<Role, PresentationError> {
+            [Role::Admin, Role::Member, Role::Guest]
+                .iter()
+                .copied()
+                .find(|role| format!("{:?}", role).eq_ignore_ascii_case(name))
+                .ok_or_else(|| {
+                    let message = format!("unknown role: {}", name);
+                    PresentationError::new(ErrorKind::Validation, &message).with_field("role", message)
+                })
+        }
+
+        /// 招待用のURLをメールで送る
+        #[utoipa::path(
+            post,
+            path = "/admin/invitations",
+            request_body = InvitationBody,
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 201, description = "送った招待", body = InvitationDto),
+                (status = 403, description = "ユーザーを管理する権限が無い", body = ErrorBody),
+                (status = 409, description = "メールアドレスが使われている", body = ErrorBody),
+                (status = 422, description = "入力の誤り", body = ErrorBody)
+            )
+        )]
+        fn invite_user(
+            State(world): State<SharedWorld>,
+            principal: Option<Extension<Principal>>,
+            body: Result<Json<InvitationBody>, JsonRejection>,
+        ) -> Ready<Response> {
+            let result = json_body(body).and_then(|body| {
+                let actor = authenticated(principal)?;
+                validate_fields(&[("email", Email::parse(&body.email).err())])?;
+                let invitation = NewInvitation {
+                    inviter: actor.clone(),
+                    email: body.email,
+                    role: body.role.as_deref().map(role).transpose()?.unwrap_or_default(),
+                    accept_url: format!("{}{}", world.config_component().public_url(), INVITATION_PAGE),
+                };
+                Ok(world.invite_user_use_case(actor).execute(invitation)?)
+            });
+            respond(StatusCode::CREATED, result)
+        }
+
+        /// 招待を受け入れて、名前とパスワードを決めてユーザーになる
+        #[utoipa::path(
+            post,
+            path = "/invitations/accept",
+            request_body = InvitationAcceptanceBody,
+            responses(
+                (status = 201, description = "作ったユーザー。役割は招待の時に決めたもの", body = UserDto),
+                (status = 409, description = "名前が使われている", body = ErrorBody),
+                (status = 422, description = "入力の誤りか、トークンが誤っているか期限が切れている", body = ErrorBody)
+            )
+        )]
+        fn accept_invitation(
+            State(world): State<SharedWorld>,
+            body: Result<Json<InvitationAcceptanceBody>, JsonRejection>,
+        ) -> Ready<Response> {
+            let result = json_body(body).and_then(|body| {
+                validate_fields(&[("name", Name::new(&body.name).err())])?;
+                let acceptance = InvitationAcceptance {
+                    token: body.token,
+                    name: body.name,
+                    password: PlainPassword::new(&body.password),
+                };
+                Ok(world.accept_invitation_use_case().execute(acceptance)?)
+            });
+            respond(StatusCode::CREATED, result)
+        }
+
+        /// 一覧を見られるユーザーだけが購読できる
+        fn ensure_can_watch(
+            world: &SharedWorld,
+            principal: Option<Extension<Principal>>,
+        ) -> Result<(), PresentationError> {
+            let actor = world.user_queries().get(authenticated(principal)?)?;
+            if actor.status != UserStatus::Active || !actor.can(Permission::ListUsers) {
+                let denied = PermissionDenied {
+                    actor: actor.id,
+                    permission: Permission::ListUsers,
+                    use_case: "watch_users",
+                };
+                return Err(denied.into());
+            }
+            Ok(())
+        }
+
+        #[utoipa::path(
+            get,
+            path = "/users/events",
+            params(WatchParams),
+            security(("actor" = []), ("bearer" = []), ("session" = [])),
+            responses(
+                (status = 200, description = "`created` `updated` `deleted` のイベントの流れ。データは変更後のユーザー",
+                 content_type = "text/event-stream", body = UserSummaryDto),
+                (status = 401, description = "誰として呼んだのかが分からない", body = ErrorBody),
+                (status = 403, description = "一覧を見る権限が無い", body = ErrorBody)
+            )
+        )]
+        fn watch_users(
+            world: &SharedWorld,
+            changes: &Subscribers<UserChange>,
+            principal: Option<Extension<Principal>>,
+            params: WatchParams,
+        ) -> Ready<Response> {
+            if let Err(e) = ensure_can_watch(world, principal) {
+                return future::ready(e.into_response());
+            }
+            let prefix = params.prefix.unwrap_or_default();
+            let events = changes
+                .add()
+                .filter(move |change| future::ready(change.user.name.starts_with(&prefix)))
+                .map(|change| Event::default().event(change.kind).json_data(&change.user));
+            future::ready(Sse::new(events).keep_alive(KeepAlive::default()).into_response())
+        }
+    }
+
+    pub mod grpc {
+        //! `proto/user.proto` の `UserService`。
+        //! メッセージの型とサーバーの骨組みはlayered-protoにあり、ここではユースケースを呼ぶだけ。
+
+        use adapter::{self, attempt, user_id, Credentials, SharedWorld, ACTOR_HEADER};
+        use entity::api_token::Scope;
+        use entity::user::UserId;
+        use env::RealWorld;
+        use failure::Error;
+        use layered_proto::user_service_server::{UserService, UserServiceServer};
+        use layered_proto::{
+            CreateUserRequest, DeleteUserRequest, DeleteUserResponse, GetUserRequest, ListUsersRequest,
+            ListUsersResponse, User,
+        };
+        use std::net::SocketAddr;
+        use std::sync::Arc;
+        use tokio::runtime::Runtime;
+        use tonic::transport::Server;
+        use tonic::{Code, Request, Response, Status};
+        use usecase::UseCase;
+        use usecase::dto::{UserDto, UserSummaryDto};
+        use usecase::list_users::ListUsersQuery;
+        use usecase::presentation_error::{ErrorKind, PresentationError};
+        use usecase::register_user::NewUser;
+
+        /// UserServiceをユースケースで実装(impl)する型
+        pub struct GrpcAdapter {
+            world: SharedWorld,
+        }
+
+        impl GrpcAdapter {
+            pub fn new(world: SharedWorld) -> GrpcAdapter {
+                GrpcAdapter { world }
+            }
+        }
+
+        pub fn service(world: SharedWorld) -> UserServiceServer<GrpcAdapter> {
+            UserServiceServer::new(GrpcAdapter::new(world))
+        }
+
+        /// `addr` で待ち受けて、SIGTERMかSIGINTを受け取るまでリクエストを処理する
+        pub fn serve(runtime: &Runtime, world: RealWorld, addr: &str) -> Result<(), Error> {
+            let addr: SocketAddr = addr.parse()?;
+            let world = Arc::new(world);
+            adapter::spawn_maintenance(runtime, &world)?;
+            adapter::spawn_job_worker(runtime, &world)?;
+            let stop = adapter::shutdown_signal();
+            let server = Server::builder()
+                .add_service(service(world.clone()))
+                .serve_with_shutdown(addr, stop.clone());
+            adapter::drain(runtime, server, stop)?;
+            adapter::finish(runtime, &world)
+        }
+
+        impl From<PresentationError> for Status {
+            fn from(e: PresentationError) -> Status {
+                let code = match e.kind {
+                    ErrorKind::Unauthenticated => Code::Unauthenticated,
+                    ErrorKind::Forbidden => Code::PermissionDenied,
+                    ErrorKind::NotFound => Code::NotFound,
+                    ErrorKind::Conflict => Code::AlreadyExists,
+                    ErrorKind::Validation => Code::InvalidArgument,
+                    ErrorKind::RateLimited => Code::ResourceExhausted,
+                    ErrorKind::Internal => Code::Internal,
+                };
+                Status::new(code, e.message)
+            }
+        }
+
+        impl From<UserDto> for User {
+            fn from(user: UserDto) -> User {
+                User {
+                    id: user.id,
+                    name: user.name,
+                    email: user.email,
+                    role: user.role,
+                    status: user.status,
+                    create_time: user.create_time.to_rfc3339(),
+                }
+            }
+        }
+
+        impl From<UserSummaryDto> for User {
+            fn from(user: UserSummaryDto) -> User {
+                User {
+                    id: user.id,
+                    name: user.name,
+                    email: user.email,
+                    role: user.role,
+                    status: user.status,
+                    create_time: user.create_time.to_rfc3339(),
+                }
+            }
+        }
+
+        /// HTTPと同じく、`authorization` のAPIトークンか(設定で信じる事にしていれば) `x-user-id` のメタデータで認証し、
+        /// `scope` が許されているユーザーのIDを返す
+        fn authenticate<T>(world: &RealWorld, request: &Request<T>, scope: Scope) -> Result<UserId, PresentationError> {
+            let value = |key: &str| request.metadata().get(key).map(|value| value.to_str().unwrap_or_default());
+            let credentials = Credentials {
+                authorization: value("authorization"),
+                session: None,
+                actor: value(ACTOR_HEADER),
+            };
+            adapter::authorized(adapter::principal(world, credentials)?, scope)
+        }
+
+        /// Statusが大きいのはtonicの都合なので、UserServiceと同じ形で返す
+        #[allow(clippy::result_large_err)]
+        fn respond<T, U: From<T>>(result: Result<T, PresentationError>) -> Result<Response<U>, Status> {
+            result.map(|value| Response::new(U::from(value))).map_err(Status::from)
+        }
+
+        impl UserService for GrpcAdapter {
+            fn create_user(&self, request: Request<CreateUserRequest>) -> Result<Response<User>, Status> {
+                let request = request.into_inner();
+                let world = &self.world;
+                let result = attempt(|| {
+                    let new_user = NewUser {
+                        name: request.name,
+                        email: request.email,
+                    };
+                    Ok(world.register_user_use_case().execute(new_user)?)
+                });
+                respond(result)
+            }
+
+            fn get_user(&self, request: Request<GetUserRequest>) -> Result<Response<User>, Status> {
+                let world = &self.world;
+                let result = attempt(|| {
+                    let actor = authenticate(world, &request, Scope::ReadUsers)?;
+                    let id = user_id(&request.get_ref().id)?;
+                    Ok(world.get_user_use_case(actor).execute(id)?)
+                });
+                respond(result)
+            }
+
+            fn list_users(&self, request: Request<ListUsersRequest>) -> Result<Response<ListUsersResponse>, Status> {
+                let world = &self.world;
+                let actor = authenticate(world, &request, Scope::ReadUsers);
+                let request = request.into_inner();
+                let result = attempt(|| {
+                    let query = ListUsersQuery {
+                        page: request.page.max(1) as usize,
+                        per_page: Some(request.per_page as usize).filter(|&per_page| per_page > 0),
+                        ..ListUsersQuery::default()
+                    };
+                    let page = world.list_users_use_case(actor?).execute(query)?;
+                    Ok(ListUsersResponse {
+                        users: page.items.into_iter().map(User::from).collect(),
+                        page: page.page as u32,
+                        per_page: page.per_page as u32,
+                        total: page.total as u32,
+                    })
+                });
+                respond(result)
+            }
+
+            /// 退会は本人しかできない。トークンが空なら確認用のトークンを発行し、あればそのトークンで確定する。
+            fn delete_user(&self, request: Request<DeleteUserRequest>) -> Result<Response<DeleteUserResponse>, Status> {
+                let world = &self.world;
+                let actor = authenticate(world, &request, Scope::WriteUsers)?;
+                let id = adapter::own_account(actor, &request.get_ref().id)?;
+                let token = request.into_inner().confirmation_token;
+                let result = attempt(|| {
+                    if token.is_empty() {
+                        let token = world.request_account_deletion_use_case().execute(id)?;
+                        return Ok(DeleteUserResponse {
+                            confirmation_token: token,
+                            user: None,
+                        });
+                    }
+                    let user = world.confirm_account_deletion_use_case().execute(token)?;
+                    Ok(DeleteUserResponse {
+                        confirmation_token: String::new(),
+                        user: Some(User::from(user)),
+                    })
+                });
+                respond(result)
+            }
+        }
+    }
+
+    pub mod graphql {
+        //! `POST /graphql` のGraphQL API。
+        //! async-graphqlの動的スキーマで組み立てるので、リゾルバはユースケースを同期的に呼ぶだけで良い。
+        //! 一覧に出てくるユーザーはDataLoaderに集め、1回の `get_users` (`read_many`)でまとめて読む。
+
+        use adapter::{self, SharedWorld};
+        use async_graphql::dataloader::{DataLoader, Loader};
+        use async_graphql::dynamic::{
+            Field, FieldFuture, FieldValue, InputValue, Object, ResolverContext, Schema, TypeRef,
+        };
+        use async_graphql::futures_util::FutureExt;
+        use async_graphql::{self, ErrorExtensions, Value};
+        use entity::user::Name;
+        use serde::Serialize;
+        use std::collections::HashMap;
+        use std::future::{self, Future};
+        use tokio;
+        use usecase::UseCase;
+        use usecase::dto::UserDto;
+        use usecase::list_users::ListUsersQuery;
+        use usecase::presentation_error::{ErrorKind, PresentationError};
+        use usecase::register_user::NewUser;
+        use usecase::update_email::EmailUpdate;
+
+        pub use async_graphql::{Request, Response};
+
+        /// `users` で `first` を指定しなかった時の件数
+        pub const DEFAULT_FIRST: usize = 20;
+
+        type GraphQLError = async_graphql::Error;
+
+        /// スキーマと、リゾルバから使うRealWorld
+        #[derive(Clone)]
+        pub struct GraphQL {
+            schema: Schema,
+            world: SharedWorld,
+        }
+
+        impl GraphQL {
+            pub fn new(world: SharedWorld) -> GraphQL {
+                GraphQL {
+                    schema: schema(world.clone()),
+                    world,
+                }
+            }
+
+            /// スキーマをGraphQLのスキーマ定義言語(SDL)で返す
+            pub fn sdl(&self) -> String {
+                self.schema.sdl()
+            }
+
+            /// `actor` は `x-user-id` の値。DataLoaderはリクエストごとに作るので、別のリクエストの結果は混ざらない。
+            pub fn execute<'a>(&'a self, actor: Option<&str>, request: Request) -> impl Future<Output = Response> + 'a {
+                let actor = actor.map(String::from);
+                let loader = UserLoader {
+                    world: self.world.clone(),
+                    actor: actor.clone(),
+                };
+                self.schema.execute(request.data(Actor(actor)).data(DataLoader::new(loader, tokio::spawn)))
+            }
+        }
+
+        /// リクエストの `x-user-id` の値
+        struct Actor(Option<String>);
+
+        /// IDで引くユーザーを集めて、まとめて読む
+        pub struct UserLoader {
+            world: SharedWorld,
+            actor: Option<String>,
+        }
+
+        impl UserLoader {
+            fn get_users(&self, keys: &[String]) -> Result<HashMap<String, UserDto>, PresentationError> {
+                let actor = adapter::actor(self.actor.as_deref())?;
+                // UUIDとして読めないIDのユーザーは居ないので、問い合わせずに飛ばす
+                let ids = keys.iter().filter_map(|key| adapter::user_id(key).ok()).collect();
+                let users = self.world.get_users_use_case(actor).execute(ids)?;
+                Ok(users.into_iter().map(|user| (user.id.clone(), user)).collect())
+            }
+        }
+
+        impl Loader<String> for UserLoader {
+            type Value = UserDto;
+            type Error = GraphQLError;
+            fn load(
+                &self,
+                keys: &[String],
+            ) -> impl Future<Output = Result<HashMap<String, UserDto>, GraphQLError>> + Send {
+                future::ready(self.get_users(keys).map_err(error))
+            }
+        }
+
+        /// PresentationErrorを `extensions.code` 付きのGraphQLのエラーにする
+        fn error(e: PresentationError) -> GraphQLError {
+            let code = match e.kind {
+                ErrorKind::Unauthenticated => "UNAUTHENTICATED",
+                ErrorKind::Forbidden => "FORBIDDEN",
+                ErrorKind::NotFound => "NOT_FOUND",
+                ErrorKind::Conflict => "CONFLICT",
+                ErrorKind::Validation => "BAD_USER_INPUT",
+                ErrorKind::RateLimited => "RATE_LIMITED",
+                ErrorKind::Internal => "INTERNAL_SERVER_ERROR",
+            };
+            GraphQLError::new(e.message).extend_with(|_, extensions| extensions.set("code", code))
+        }
+
+        /// DTO等をGraphQLの値にする
+        fn value<T: Serialize>(value: &T) -> Value {
+            async_graphql::to_value(value).unwrap_or(Value::Null)
+        }
+
+        fn resolved<'a>(result: Result<Value, GraphQLError>) -> FieldFuture<'a> {
+            match result {
+                Ok(value) => FieldFuture::from_value(Some(value)),
+                Err(e) => FieldFuture::new(future::ready(Err::<Option<Value>, _>(e))),
+            }
+        }
+
+        /// 親の値(DTOから作ったオブジェクト)の `key` をそのまま返すフィールド
+        fn property(name: &str, key: &'static str, ty: TypeRef) -> Field {
+            Field::new(name, ty, move |ctx| FieldFuture::from_value(parent_property(ctx.parent_value, key)))
+        }
+
+        fn parent_property(parent: &FieldValue, key: &str) -> Option<Value> {
+            match parent.as_value() {
+                Some(Value::Object(object)) => object.get(key).cloned(),
+                _ => None,
+            }
+        }
+
+        fn world<'a>(ctx: &ResolverContext<'a>) -> Result<&'a SharedWorld, GraphQLError> {
+            ctx.data::<SharedWorld>()
+        }
+
+        fn actor_value<'a>(ctx: &ResolverContext<'a>) -> Result<Option<&'a str>, GraphQLError> {
+            Ok(ctx.data::<Actor>()?.0.as_deref())
+        }
+
+        fn schema(world: SharedWorld) -> Schema {
+            let user = Object::new("User")
+                .field(property("id", "id", TypeRef::named_nn(TypeRef::ID)))
+                .field(property("name", "name", TypeRef::named_nn(TypeRef::STRING)))
+                .field(property("email", "email", TypeRef::named_nn(TypeRef::STRING)))
+                .field(property("role", "role", TypeRef::named_nn(TypeRef::STRING)))
+                .field(property("status", "status", TypeRef::named_nn(TypeRef::STRING)))
+                .field(property("phoneNumber", "phone_number", TypeRef::named(TypeRef::STRING)))
+                .field(property("createTime", "create_time", TypeRef::named_nn(TypeRef::STRING)))
+                .field(property("updateTime", "update_time", TypeRef::named_nn(TypeRef::STRING)))
+                .field(property("version", "version", TypeRef::named_nn(TypeRef::INT)));
+            let edge = Object::new("UserEdge")
+                .field(property("cursor", "cursor", TypeRef::named_nn(TypeRef::STRING)))
+                .field(Field::new("node", TypeRef::named("User"), node));
+            let page_info = Object::new("PageInfo")
+                .field(property("hasNextPage", "hasNextPage", TypeRef::named_nn(TypeRef::BOOLEAN)))
+                .field(property("endCursor", "endCursor", TypeRef::named(TypeRef::STRING)));
+            let connection = Object::new("UserConnection")
+                .field(property("edges", "edges", TypeRef::named_nn_list_nn("UserEdge")))
+                .field(property("pageInfo", "pageInfo", TypeRef::named_nn("PageInfo")))
+                .field(property("totalCount", "totalCount", TypeRef::named_nn(TypeRef::INT)));
+            let email_change = Object::new("EmailChange")
+                .field(property("userId", "user_id", TypeRef::named_nn(TypeRef::ID)))
+                .field(property("oldEmail", "old_email", TypeRef::named_nn(TypeRef::STRING)))
+                .field(property("newEmail", "new_email", TypeRef::named_nn(TypeRef::STRING)))
+                .field(property("updateTime", "update_time", TypeRef::named_nn(TypeRef::STRING)));
+            let first = InputValue::new("first", TypeRef::named_nn(TypeRef::INT)).default_value(DEFAULT_FIRST);
+            let query = Object::new("Query")
+                .field(
+                    Field::new("user", TypeRef::named("User"), |ctx| resolved(find_user(&ctx)))
+                        .argument(InputValue::new("name", TypeRef::named_nn(TypeRef::STRING))),
+                )
+                .field(
+                    Field::new("users", TypeRef::named_nn("UserConnection"), |ctx| resolved(list_users(&ctx)))
+                        .argument(first)
+                        .argument(InputValue::new("after", TypeRef::named(TypeRef::STRING))),
+                );
+            let mutation = Object::new("Mutation")
+                .field(
+                    Field::new("registerUser", TypeRef::named_nn("User"), |ctx| resolved(register_user(&ctx)))
+                        .argument(InputValue::new("name", TypeRef::named_nn(TypeRef::STRING)))
+                        .argument(InputValue::new("email", TypeRef::named_nn(TypeRef::STRING))),
+                )
+                .field(
+                    Field::new("updateEmail", TypeRef::named_nn("EmailChange"), |ctx| resolved(update_email(&ctx)))
+                        .argument(InputValue::new("id", TypeRef::named_nn(TypeRef::ID)))
+                        .argument(InputValue::new("email", TypeRef::named_nn(TypeRef::STRING))),
+                );
+            Schema::build("Query", Some("Mutation"), None)
+                .register(user)
+                .register(edge)
+                .register(page_info)
+                .register(connection)
+                .register(email_change)
+                .register(query)
+                .register(mutation)
+                .data(world)
+                .finish()
+                // 型はここで全て定義しているので、組み立てに失敗するのはこの関数の書き間違いだけ
+                .expect("invalid GraphQL schema")
+        }
+
+        /// 居なければnull
+        fn find_user(ctx: &ResolverContext) -> Result<Value, GraphQLError> {
+            let actor = adapter::actor(actor_value(ctx)?).map_err(error)?;
+            let name = Name::new(ctx.args.try_get("name")?.string()?).map_err(|e| error(e.into()))?;
+            let world = world(ctx)?;
+            let result = world.get_user_by_name_use_case(actor).execute(name);
+            match result {
+                Ok(user) => Ok(value(&user)),
+                Err(e) => match PresentationError::from(e) {
+                    ref e if e.kind == ErrorKind::NotFound => Ok(Value::Null),
+                    e => Err(error(e)),
+                },
+            }
+        }
+
+        /// Relayのコネクションの形で返す。カーソルはユーザーのIDで、各ユーザーは `node` で読む。
+        fn list_users(ctx: &ResolverContext) -> Result<Value, GraphQLError> {
+            let actor = adapter::actor(actor_value(ctx)?).map_err(error)?;
+            let first = ctx.args.try_get("first")?.u64()? as usize;
+            let after = match ctx.args.get("after") {
+                Some(after) if !after.is_null() => Some(adapter::user_id(after.string()?).map_err(error)?),
+                _ => None,
+            };
+            // 次のページがあるかを知るために1件多く読む
+            let query = ListUsersQuery {
+                per_page: Some(first + 1),
+                after,
+                ..ListUsersQuery::default()
+            };
+            let world = world(ctx)?;
+            let page = world.list_users_use_case(actor).execute(query).map_err(|e| error(e.into()))?;
+            let edges: Vec<_> = page.items.iter().take(first).map(|user| json!({ "cursor": user.id })).collect();
+            let end_cursor = edges.last().map(|edge| edge["cursor"].clone());
+            Ok(value(&json!({
+                "edges": edges,
+                "pageInfo": { "hasNextPage": page.items.len() > first, "endCursor": end_cursor },
+                "totalCount": page.total,
+            })))
+        }
+
+        fn node<'a>(ctx: ResolverContext<'a>) -> FieldFuture<'a> {
+            let id = match parent_property(ctx.parent_value, "cursor") {
+                Some(Value::String(id)) => id,
+                _ => return FieldFuture::from_value(None),
+            };
+            match ctx.data::<DataLoader<UserLoader>>() {
+                Ok(loader) => FieldFuture::new(loader.load_one(id).map(|user| Ok(user?.map(|user| value(&user))))),
+                Err(e) => resolved(Err(e)),
+            }
+        }
+
+        fn register_user(ctx: &ResolverContext) -> Result<Value, GraphQLError> {
+            let new_user = NewUser {
+                name: ctx.args.try_get("name")?.string()?.to_string(),
+                email: ctx.args.try_get("email")?.string()?.to_string(),
+            };
+            let world = world(ctx)?;
+            let user = world.register_user_use_case().execute(new_user).map_err(|e| error(e.into()))?;
+            Ok(value(&user))
+        }
+
+        /// 変更できるのは自分のメールアドレスだけ
+        fn update_email(ctx: &ResolverContext) -> Result<Value, GraphQLError> {
+            let actor = adapter::actor(actor_value(ctx)?).map_err(error)?;
+            let id = adapter::own_account(actor, ctx.args.try_get("id")?.string()?).map_err(error)?;
+            let input = EmailUpdate {
+                id: id.clone(),
+                email: ctx.args.try_get("email")?.string()?.to_string(),
+            };
+            let world = world(ctx)?;
+            let change = world.update_email_use_case(id).execute(input).map_err(|e| error(e.into()))?;
+            Ok(value(&change))
+        }
+    }
+
+    pub mod json_rpc {
+        //! 1行に1つのJSON-RPC 2.0のリクエストを読み、1行に1つのレスポンスを書く。
+        //! 標準入出力はスクリプトやエディタから使う前提なので、CLIと同じく権限の確認はせずに実行する。
+        //! TCPでは接続ごとに `auth.authenticate` でAPIトークンかセッションを渡して認証するまで、他のメソッドは呼べない。
+        //! 認証した後は、HTTPと同じくAPIトークンのスコープとユースケースで権限を確かめる。
+        //! 標準入出力でもTCPでも同じ `serve` を使う。
+
+        use adapter::controller::{Caller, HaveUserController, UserController};
+        use adapter::{self, Credentials, Principal, SharedWorld};
+        use entity::api_token::Scope;
+        use env::RealWorld;
+        use failure::Error;
+        use futures::FutureExt;
+        use serde::Serialize;
+        use serde::de::DeserializeOwned;
+        use serde_json::{self, Value};
+        use std::io::{self, BufRead, BufReader, Write};
+        use std::net::TcpListener;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::Duration;
+        use tokio::runtime::Runtime;
+        use usecase::list_users::ListUsersQuery;
+        use usecase::presentation_error::{ErrorKind, PresentationError};
+        use usecase::register_user::NewUser;
+
+        pub const PARSE_ERROR: i64 = -32700;
+        pub const INVALID_REQUEST: i64 = -32600;
+        pub const METHOD_NOT_FOUND: i64 = -32601;
+        pub const INVALID_PARAMS: i64 = -32602;
+        pub const INTERNAL_ERROR: i64 = -32603;
+
+        /// 止める合図を確かめる間隔
+        const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+        /// JSON-RPCのエラー。`data.kind` にPresentationErrorの種類を入れる。
+        #[derive(Debug, Clone, PartialEq, Serialize)]
+        pub struct RpcError {
+            pub code: i64,
+            pub message: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub data: Option<Value>,
+        }
+
+        impl RpcError {
+            pub fn new<S: Into<String>>(code: i64, message: S) -> RpcError {
+                RpcError {
+                    code,
+                    message: message.into(),
+                    data: None,
+                }
+            }
+        }
+
+        /// 入力の誤りは仕様のInvalid paramsにする。
+        /// それ以外はサーバーが決めてよい範囲(-32000〜-32099)で、HTTPのステータスコードの下2桁を使う(404なら-32004)。
+        impl From<PresentationError> for RpcError {
+            fn from(e: PresentationError) -> RpcError {
+                let code = match e.kind {
+                    ErrorKind::Validation => INVALID_PARAMS,
+                    ErrorKind::Internal => INTERNAL_ERROR,
+                    kind => -32000 - (kind.status_code() as i64 - 400),
+                };
+                RpcError {
+                    code,
+                    message: e.message,
+                    data: Some(json!({ "kind": format!("{:?}", e.kind) })),
+                }
+            }
+        }
+
+        impl From<Error> for RpcError {
+            fn from(e: Error) -> RpcError {
+                RpcError::from(PresentationError::from(e))
+            }
+        }
+
+        /// 接続してきた相手
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum Peer {
+            /// 標準入出力で繋いだ、サーバー上で運用する人。権限は確かめない
+            Operator,
+            /// まだ認証していない接続
+            Anonymous,
+            Authenticated(Principal),
+        }
+
+        impl Peer {
+            /// `scope` の操作を誰として呼ぶか。認証していなければUnauthenticated
+            pub fn caller(&self, scope: Scope) -> Result<Caller, PresentationError> {
+                match *self {
+                    Peer::Operator => Ok(Caller::Operator),
+                    Peer::Anonymous => {
+                        Err(PresentationError::new(ErrorKind::Unauthenticated, "authentication required"))
+                    }
+                    Peer::Authenticated(ref principal) => Ok(Caller::User(principal.require(scope)?.clone())),
+                }
+            }
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Request {
+            jsonrpc: String,
+            method: String,
+            #[serde(default)]
+            params: Value,
+            /// 無ければ通知なので、レスポンスを返さない
+            id: Option<Value>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct IdParams {
+            id: String,
+        }
+
+        /// `token` (APIトークン)か `session` (セッションのID)のどちらか
+        #[derive(Debug, Deserialize)]
+        struct AuthParams {
+            token: Option<String>,
+            session: Option<String>,
+        }
+
+        #[derive(Debug, Default, Deserialize)]
+        struct ListParams {
+            page: Option<usize>,
+            per_page: Option<usize>,
+        }
+
+        fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+            match result {
+                Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+                Err(error) => json!({ "jsonrpc": "2.0", "error": error, "id": id }),
+            }
+        }
+
+        /// 1行分を処理する。返すものが無い時(通知だけの時)はNone
+        pub fn handle(world: &RealWorld, peer: &mut Peer, line: &str) -> Option<String> {
+            let response = match serde_json::from_str::<Value>(line) {
+                Err(e) => Some(response(Value::Null, Err(RpcError::new(PARSE_ERROR, e.to_string())))),
+                Ok(Value::Array(ref batch)) if batch.is_empty() => {
+                    Some(response(Value::Null, Err(RpcError::new(INVALID_REQUEST, "empty batch"))))
+                }
+                Ok(Value::Array(batch)) => {
+                    let responses: Vec<_> = batch
+                        .into_iter()
+                        .filter_map(|request| call(world, peer, request))
+                        .collect();
+                    if responses.is_empty() {
+                        None
+                    } else {
+                        Some(Value::Array(responses))
+                    }
+                }
+                Ok(request) => call(world, peer, request),
+            };
+            response.map(|response| response.to_string())
+        }
+
+        fn call(world: &RealWorld, peer: &mut Peer, request: Value) -> Option<Value> {
+            let request: Request = match serde_json::from_value(request) {
+                Ok(request) => request,
+                Err(e) => return Some(response(Value::Null, Err(RpcError::new(INVALID_REQUEST, e.to_string())))),
+            };
+            if request.jsonrpc != "2.0" {
+                let error = RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\"");
+                return Some(response(request.id.unwrap_or(Value::Null), Err(error)));
+            }
+            let result = dispatch(world, peer, &request.method, request.params);
+            request.id.map(|id| response(id, result))
+        }
+
+        fn dispatch(world: &RealWorld, peer: &mut Peer, method: &str, params: Value) -> Result<Value, RpcError> {
+            let controller = world.user_controller();
+            match method {
+                // 認証できたら、この接続の相手をそのユーザーにする
+                "auth.authenticate" => {
+                    let params = params_of::<AuthParams>(params)?;
+                    let authorization = params.token.map(|token| format!("Bearer {}", token));
+                    let credentials = Credentials {
+                        authorization: authorization.as_deref(),
+                        session: params.session.as_deref(),
+                        actor: None,
+                    };
+                    let missing = || PresentationError::new(ErrorKind::Unauthenticated, "token or session required");
+                    let principal = adapter::principal(world, credentials)?.ok_or_else(missing)?;
+                    let user_id = principal.user_id.as_uuid().to_string();
+                    *peer = Peer::Authenticated(principal);
+                    result(&json!({ "user_id": user_id }))
+                }
+                "user.insert" => {
+                    peer.caller(Scope::WriteUsers)?;
+                    result(&controller.register(params_of::<NewUser>(params)?)?)
+                }
+                "user.get" => {
+                    let caller = peer.caller(Scope::ReadUsers)?;
+                    result(&controller.get(&caller, &params_of::<IdParams>(params)?.id)?)
+                }
+                "user.list" => {
+                    let caller = peer.caller(Scope::ReadUsers)?;
+                    let params = if params.is_null() {
+                        ListParams::default()
+                    } else {
+                        params_of::<ListParams>(params)?
+                    };
+                    let query = ListUsersQuery {
+                        page: params.page.unwrap_or(1),
+                        per_page: params.per_page,
+                        ..ListUsersQuery::default()
+                    };
+                    result(&controller.list(&caller, query)?)
+                }
+                // CLIと同じく、確認用のトークンを発行してそのまま確定する。ユーザーは自分のアカウントしか退会できない
+                "user.delete" => {
+                    let caller = peer.caller(Scope::WriteUsers)?;
+                    let id = params_of::<IdParams>(params)?.id;
+                    let token = controller.request_deletion(&caller, &id)?;
+                    result(&controller.confirm_deletion(&caller, &id, token)?)
+                }
+                _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("method not found: {}", method))),
+            }
+        }
+
+        /// 名前付きのパラメータ(オブジェクト)だけを受け付ける
+        fn params_of<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+            serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+        }
+
+        fn result<T: Serialize>(value: &T) -> Result<Value, RpcError> {
+            serde_json::to_value(value).map_err(|e| RpcError::from(Error::from(e)))
+        }
+
+        /// `input` が閉じられるまで、1行ずつ `peer` からのリクエストとして処理して `output` に書く
+        pub fn serve<R, W>(world: &SharedWorld, mut peer: Peer, input: R, mut output: W) -> Result<(), Error>
+        where
+            R: BufRead,
+            W: Write,
+        {
+            for line in input.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let response = handle(world, &mut peer, &line);
+                if let Some(response) = response {
+                    writeln!(output, "{}", response)?;
+                    output.flush()?;
+                }
+            }
+            Ok(())
+        }
+
+        /// 標準入力が閉じられたら、変更を書き出して終了する
+        pub fn serve_stdio(world: RealWorld) -> Result<(), Error> {
+            let world = Arc::new(world);
+            let (stdin, stdout) = (io::stdin(), io::stdout());
+            serve(&world, Peer::Operator, stdin.lock(), stdout.lock())?;
+            world.persist()?;
+            Ok(())
+        }
+
+        /// 接続ごとにスレッドを立てて、SIGTERMかSIGINTを受け取るまで待ち受ける。どの接続も認証するまで何もできない
+        pub fn serve_tcp(runtime: &Runtime, world: RealWorld, addr: &str) -> Result<(), Error> {
+            let listener = TcpListener::bind(addr)?;
+            let stopped = Arc::new(AtomicBool::new(false));
+            let flag = stopped.clone();
+            runtime.spawn(adapter::shutdown_signal().map(move |_| flag.store(true, Ordering::SeqCst)));
+            serve_tcp_until(world, listener, &stopped)
+        }
+
+        /// `stopped` がtrueになるまで接続を受け付ける。受け付けるのをやめた後は、
+        /// 処理中のリクエストが終わってから変更を書き出す。
+        pub fn serve_tcp_until(world: RealWorld, listener: TcpListener, stopped: &AtomicBool) -> Result<(), Error> {
+            let world = Arc::new(world);
+            // 止める合図を見逃さないように、接続を待ち続けずに一定の間隔で見に行く
+            listener.set_nonblocking(true)?;
+            while !stopped.load(Ordering::SeqCst) {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(ACCEPT_INTERVAL);
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                stream.set_nonblocking(false)?;
+                let world = world.clone();
+                thread::spawn(move || -> Result<(), Error> {
+                    let input = BufReader::new(stream.try_clone()?);
+                    serve(&world, Peer::Anonymous, input, stream)
+                });
+            }
+            world.persist()?;
+            Ok(())
+        }
+    }
+
+    pub mod command_bus {
+        //! 直列化したコマンドをmpscのチャンネルで受け取り、1つのスレッドが持つenvで順に実行する。
+        //! envに書くのはこのスレッドだけなので、フロントエンドがいくつあっても書き込みは1つずつ行われる。
+        //! CLIと同じく、運用する人として権限の確認はせずに実行する。
+
+        use adapter::controller::{Caller, HaveUserController, UserController};
+        use env::RealWorld;
+        use failure::Error;
+        use serde::Serialize;
+        use serde_json::{self, Value};
+        use std::io::{self, BufRead, Write};
+        use std::sync::mpsc::{self, Receiver, Sender};
+        use std::thread::{self, JoinHandle};
+        use usecase::list_users::ListUsersQuery;
+        use usecase::presentation_error::{ErrorKind, PresentationError};
+        use usecase::register_user::NewUser;
+
+        /// バスで送れるコマンド。`{"command": "register_user", "name": ..., "email": ...}` の形で直列化する。
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+        #[serde(tag = "command", rename_all = "snake_case")]
+        pub enum Command {
+            RegisterUser { name: String, email: String },
+            GetUser { id: String },
+            ListUsers { page: Option<usize>, per_page: Option<usize> },
+            /// CLIと同じく、確認用のトークンを発行してそのまま確定する
+            DeleteUser { id: String },
+        }
+
+        pub type Reply = Result<Value, PresentationError>;
+
+        enum Envelope {
+            Command { command: String, reply: Sender<Reply> },
+            Stop,
+        }
+
+        /// コマンドを実行するスレッド。止めるとenvを返すので、呼び出し側で書き出す。
+        pub struct CommandBus<W> {
+            sender: Sender<Envelope>,
+            core: JoinHandle<W>,
+        }
+
+        /// コマンドを送る口。フロントエンドごとに複製して使う
+        #[derive(Clone)]
+        pub struct CommandClient {
+            sender: Sender<Envelope>,
+        }
+
+        impl<W: HaveUserController + Send + 'static> CommandBus<W> {
+            pub fn start(world: W) -> Result<CommandBus<W>, Error> {
+                let (sender, receiver) = mpsc::channel();
+                let core = thread::Builder::new()
+                    .name("command-bus".to_string())
+                    .spawn(move || run(world, &receiver))?;
+                Ok(CommandBus { sender, core })
+            }
+
+            pub fn client(&self) -> CommandClient {
+                CommandClient {
+                    sender: self.sender.clone(),
+                }
+            }
+
+            /// 先に届いていたコマンドを実行し終えてから止める
+            pub fn stop(self) -> Result<W, Error> {
+                // スレッドが先に終わっていても、joinで理由が分かる
+                let _ = self.sender.send(Envelope::Stop);
+                self.core.join().map_err(|_| format_err!("command bus panicked"))
+            }
+        }
+
+        impl CommandClient {
+            /// 直列化済みのコマンドを送り、実行し終わるまで待つ
+            pub fn send_json<S: Into<String>>(&self, command: S) -> Reply {
+                let stopped = || PresentationError::new(ErrorKind::Internal, "command bus stopped");
+                let (reply, receiver) = mpsc::channel();
+                self.sender
+                    .send(Envelope::Command {
+                        command: command.into(),
+                        reply,
+                    })
+                    .map_err(|_| stopped())?;
+                receiver.recv().map_err(|_| stopped())?
+            }
+        }
+
+        fn run<W: HaveUserController>(world: W, receiver: &Receiver<Envelope>) -> W {
+            for envelope in receiver {
+                match envelope {
+                    Envelope::Command { command, reply } => {
+                        // 待つのをやめたクライアントには返さなくてよい
+                        let _ = reply.send(handle(&world, &command));
+                    }
+                    Envelope::Stop => break,
+                }
+            }
+            world
+        }
+
+        fn handle<W: HaveUserController>(world: &W, command: &str) -> Reply {
+            let command = serde_json::from_str(command)
+                .map_err(|e| PresentationError::new(ErrorKind::Validation, format!("invalid command: {}", e)))?;
+            let controller = world.user_controller();
+            match command {
+                Command::RegisterUser { name, email } => result(&controller.register(NewUser { name, email })?),
+                Command::GetUser { id } => result(&controller.get(&Caller::Operator, &id)?),
+                Command::ListUsers { page, per_page } => {
+                    let query = ListUsersQuery {
+                        page: page.unwrap_or(1),
+                        per_page,
+                        ..ListUsersQuery::default()
+                    };
+                    result(&controller.list(&Caller::Operator, query)?)
+                }
+                Command::DeleteUser { id } => {
+                    let token = controller.request_deletion(&Caller::Operator, &id)?;
+                    result(&controller.confirm_deletion(&Caller::Operator, &id, token)?)
+                }
+            }
+        }
+
+        fn result<T: Serialize>(value: &T) -> Reply {
+            Ok(serde_json::to_value(value).map_err(Error::from)?)
+        }
+
+        /// 1行に1つのコマンドを読んでバスへ送り、1行に1つの結果を書く。
+        /// 成功は `{"ok": 結果}`、失敗は `{"error": {"status": HTTPのステータスコード, "message": 理由}}` にする。
+        pub fn serve<R: BufRead, O: Write>(client: &CommandClient, input: R, mut output: O) -> Result<(), Error> {
+            for line in input.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let response = match client.send_json(line) {
+                    Ok(value) => json!({ "ok": value }),
+                    Err(e) => json!({ "error": { "status": e.status_code(), "message": e.message } }),
+                };
+                writeln!(output, "{}", response)?;
+                output.flush()?;
+            }
+            Ok(())
+        }
+
+        /// 標準入力からのコマンドをバスで実行する。標準入力が閉じられたら、変更を書き出して終了する
+        pub fn serve_stdio(world: RealWorld) -> Result<(), Error> {
+            let bus = CommandBus::start(world)?;
+            let (stdin, stdout) = (io::stdin(), io::stdout());
+            let served = serve(&bus.client(), stdin.lock(), stdout.lock());
+            let world = bus.stop()?;
+            served.and_then(|_| world.persist())
+        }
+    }
+
+    pub mod websocket {
+        //! `/ws` のWebSocketでJSON-RPC 2.0のリクエストを受け付け、レスポンスに加えてUserのイベントを通知として送る。
+        //! 接続ごとに `events.subscribe` したかどうかを覚えておき、購読している接続にだけイベントを流す。
+        //! 繋ぐ時のHTTPのリクエストをHTTPの受け口と同じく認証し、認証できなければ繋がない。
+        //! 繋いだ後はJSON-RPCの受け口と同じく、コマンドごとにAPIトークンのスコープとユースケースで権限を確かめる。
+
+        use adapter::json_rpc::{self, Peer, RpcError};
+        use adapter::{self, http, Principal, SharedWorld, Subscribers};
+        use axum::Router;
+        use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+        use axum::http::HeaderMap;
+        use axum::response::IntoResponse;
+        use axum::routing::get;
+        use component::event_bus::{EventBusComponent, HaveEventBusComponent};
+        use entity::api_token::Scope;
+        use entity::user::{User, UserEvent};
+        use env::RealWorld;
+        use failure::Error;
+        use futures::{future, stream, Future, FutureExt, StreamExt};
+        use serde_json::{self, Value};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use tokio::runtime::Runtime;
+        use usecase::dto::UserEventDto;
+        use usecase::presentation_error::{ErrorKind, PresentationError};
+
+        /// イベントを送る通知のメソッド名
+        pub const USER_EVENT_METHOD: &str = "user.event";
+
+        /// `world` のイベントバスに、接続中のクライアントへ配る購読者を登録してからルーターを作る
+        pub fn router(world: SharedWorld) -> Result<Router, Error> {
+            let subscribers: Subscribers<String> = Subscribers::default();
+            let publisher = subscribers.clone();
+            world.event_bus_component().subscribe(
+                "websocket",
+                Box::new(move |_: &RealWorld, user: &User, event: UserEvent| {
+                    let params = UserEventDto::new(user, event);
+                    let notification = json!({ "jsonrpc": "2.0", "method": USER_EVENT_METHOD, "params": params });
+                    publisher.publish(&notification.to_string());
+                    Ok(())
+                }),
+            );
+            Ok(Router::new().route(
+                "/ws",
+                get(move |headers: HeaderMap, ws: WebSocketUpgrade| {
+                    let (world, subscribers) = (world.clone(), subscribers.clone());
+                    future::ready(match authenticate(&world, &headers) {
+                        Ok(principal) => {
+                            let peer = Peer::Authenticated(principal);
+                            ws.on_upgrade(move |socket| session(world, subscribers, peer, socket))
+                        }
+                        Err(e) => e.into_response(),
+                    })
+                }),
+            ))
+        }
+
+        /// `addr` で待ち受けて、SIGTERMかSIGINTを受け取るまで接続を受け付ける
+        pub fn serve(runtime: &Runtime, world: RealWorld, addr: &str) -> Result<(), Error> {
+            let world = Arc::new(world);
+            adapter::spawn_maintenance(runtime, &world)?;
+            adapter::spawn_job_worker(runtime, &world)?;
+            http::serve_router(runtime, router(world.clone())?, addr)?;
+            adapter::finish(runtime, &world)
+        }
+
+        /// HTTPの受け口と同じ認証情報で認証する。認証情報が無ければ繋がない
+        fn authenticate(world: &RealWorld, headers: &HeaderMap) -> Result<Principal, PresentationError> {
+            match adapter::principal(world, http::credentials(headers))? {
+                Some(principal) => Ok(principal),
+                None => Err(PresentationError::new(ErrorKind::Unauthenticated, "authentication required")),
+            }
+        }
+
+        /// 1つの接続。クライアントが閉じるまで、レスポンスと購読中のイベントを同じ接続に流す。
+        fn session(
+            world: SharedWorld,
+            subscribers: Subscribers<String>,
+            mut peer: Peer,
+            socket: WebSocket,
+        ) -> impl Future<Output = ()> {
+            let subscribed = Arc::new(AtomicBool::new(false));
+            let (sink, incoming) = socket.split();
+            let responses = {
+                let subscribed = subscribed.clone();
+                incoming
+                    .take_while(|message| future::ready(!matches!(*message, Ok(Message::Close(_)) | Err(_))))
+                    .filter_map(move |message| {
+                        future::ready(match message {
+                            Ok(Message::Text(text)) => respond(&world, &mut peer, &subscribed, &text),
+                            _ => None,
+                        })
+                    })
+                    .map(Some)
+                    // クライアントが閉じたら、次のイベントを待たずに終わる
+                    .chain(stream::once(future::ready(None)))
+            };
+            let events = subscribers
+                .add()
+                .filter(move |_| future::ready(subscribed.load(Ordering::SeqCst)))
+                .map(Some);
+            stream::select(responses, events)
+                .take_while(|message| future::ready(message.is_some()))
+                .filter_map(|message| future::ready(message.map(|text| Ok(Message::Text(text)))))
+                .forward(sink)
+                .map(|_| ())
+        }
+
+        /// 購読の切り替えはここで受け、それ以外はJSON-RPCの受け口に任せる。購読にはユーザーを読むスコープが要る
+        fn respond(world: &SharedWorld, peer: &mut Peer, subscribed: &AtomicBool, text: &str) -> Option<String> {
+            let request: Value = serde_json::from_str(text).unwrap_or(Value::Null);
+            let subscribe = match request["method"].as_str() {
+                Some("events.subscribe") => true,
+                Some("events.unsubscribe") => false,
+                _ => return json_rpc::handle(world, peer, text),
+            };
+            if let Err(e) = peer.caller(Scope::ReadUsers) {
+                let error = RpcError::from(e);
+                return request.get("id").map(|id| json!({ "jsonrpc": "2.0", "error": error, "id": id }).to_string());
+            }
+            subscribed.store(subscribe, Ordering::SeqCst);
+            request.get("id").map(|id| json!({ "jsonrpc": "2.0", "result": subscribe, "id": id }).to_string())
+        }
+    }
+
+    pub mod repl {
+        //! 1行ずつコマンドを読んで、起動したままの `RealWorld` でユースケースを実行する。
+        //! CLIと同じく権限の確認はせず、結果もCLIと同じJSONで表示する。Tabでコマンドとユーザー名を補完する。
+
+        use adapter::{user_id, SharedWorld};
+        use entity::user::Name;
+        use env::RealWorld;
+        use failure::Error;
+        use repository::users::{HaveUserQueries, UserQueries};
+        use rustyline::completion::Completer;
+        use rustyline::error::ReadlineError;
+        use rustyline::highlight::Highlighter;
+        use rustyline::hint::Hinter;
+        use rustyline::history::DefaultHistory;
+        use rustyline::validate::Validator;
+        use rustyline::{Context, Editor, Helper};
+        use serde_json;
+        use std::io::Write;
+        use std::sync::Arc;
+        use usecase::delete_account::{ConfirmAccountDeletionInteractor, RequestAccountDeletionInteractor};
+        use usecase::get_user::GetUserByNameInteractor;
+        use usecase::list_users::{ListUsersInteractor, ListUsersQuery};
+        use usecase::register_user::NewUser;
+        use usecase::{Decorate, UseCase};
+
+        /// 使えるコマンドと、その書き方
+        pub const COMMANDS: &[(&str, &str)] = &[
+            ("add", "add <name> <email>"),
+            ("get", "get <name>"),
+            ("list", "list [page]"),
+            ("delete", "delete <name>"),
+            ("help", "help"),
+            ("quit", "quit"),
+        ];
+
+        /// 1行分のコマンドを実行して、表示するものを返す。`quit` の時はNone
+        pub fn eval(world: &RealWorld, line: &str) -> Result<Option<String>, Error> {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let output = match words.as_slice() {
+                [] => String::new(),
+                ["add", name, email] => {
+                    let new_user = NewUser {
+                        name: name.to_string(),
+                        email: email.to_string(),
+                    };
+                    serde_json::to_string_pretty(&world.register_user_use_case().execute(new_user)?)?
+                }
+                ["get", name] => {
+                    let user = GetUserByNameInteractor::new(world).logged().execute(Name::new(name)?)?;
+                    serde_json::to_string_pretty(&user)?
+                }
+                ["list"] | ["list", _] => {
+                    let page = match words.get(1) {
+                        Some(page) => page.parse().map_err(|_| format_err!("page must be a number: {}", page))?,
+                        None => 1,
+                    };
+                    let query = ListUsersQuery {
+                        page,
+                        ..ListUsersQuery::default()
+                    };
+                    serde_json::to_string_pretty(&ListUsersInteractor::new(world).logged().execute(query)?)?
+                }
+                // CLIと同じく、確認用のトークンを発行してそのまま確定する
+                ["delete", name] => {
+                    let user = GetUserByNameInteractor::new(world).execute(Name::new(name)?)?;
+                    let token = RequestAccountDeletionInteractor::new(world).execute(user_id(&user.id)?)?;
+                    let user = ConfirmAccountDeletionInteractor::new(world).transactional().logged().execute(token)?;
+                    serde_json::to_string_pretty(&user)?
+                }
+                ["help"] => COMMANDS.iter().map(|&(_, usage)| usage).collect::<Vec<_>>().join("\n"),
+                ["quit"] | ["exit"] => return Ok(None),
+                [command, ..] => match COMMANDS.iter().find(|&&(name, _)| name == *command) {
+                    Some(&(_, usage)) => bail!("usage: {}", usage),
+                    None => bail!("unknown command: {} (try `help`)", command),
+                },
+            };
+            Ok(Some(output))
+        }
+
+        /// カーソルの前の単語の候補と、その単語の始まりを返す。1語目はコマンド、`get` と `delete` の2語目はユーザー名
+        pub fn complete(world: &RealWorld, line: &str, pos: usize) -> (usize, Vec<String>) {
+            let line = &line[..pos];
+            let word = line.rsplit(char::is_whitespace).next().unwrap_or("");
+            let start = pos - word.len();
+            let candidates = match line[..start].split_whitespace().collect::<Vec<_>>().as_slice() {
+                [] => COMMANDS.iter().map(|&(command, _)| command.to_string()).collect(),
+                ["get"] | ["delete"] => {
+                    let users = world.user_queries().list().unwrap_or_default();
+                    users.into_iter().map(|user| user.name.as_str().to_string()).collect()
+                }
+                _ => Vec::new(),
+            };
+            let mut candidates: Vec<String> = candidates.into_iter().filter(|c| c.starts_with(word)).collect();
+            candidates.sort();
+            (start, candidates)
+        }
+
+        /// rustylineから補完を呼ぶ。入力を待っている間も同じ `RealWorld` を見る
+        struct UserNames {
+            world: SharedWorld,
+        }
+
+        impl Completer for UserNames {
+            type Candidate = String;
+            fn complete(&self, line: &str, pos: usize, _: &Context) -> rustyline::Result<(usize, Vec<String>)> {
+                Ok(complete(&self.world, line, pos))
+            }
+        }
+
+        impl Hinter for UserNames {
+            type Hint = String;
+        }
+
+        impl Highlighter for UserNames {}
+
+        impl Validator for UserNames {}
+
+        impl Helper for UserNames {}
+
+        /// `quit` かCtrl-C・Ctrl-Dで終わるまで読み続ける。エラーは表示して次の行に進む。
+        /// 終わる時には変更を書き出す。
+        pub fn run<W: Write>(world: RealWorld, out: &mut W) -> Result<(), Error> {
+            let world = Arc::new(world);
+            let mut editor: Editor<UserNames, DefaultHistory> = Editor::new()?;
+            editor.set_helper(Some(UserNames { world: world.clone() }));
+            loop {
+                let line = match editor.readline("layered> ") {
+                    Ok(line) => line,
+                    Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+                    Err(e) => return Err(e.into()),
+                };
+                editor.add_history_entry(line.as_str())?;
+                let result = eval(&world, &line);
+                match result {
+                    Ok(Some(output)) => {
+                        if !output.is_empty() {
+                            writeln!(out, "{}", output)?;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => writeln!(out, "error: {}", e)?,
+                }
+            }
+            world.persist()?;
+            Ok(())
+        }
+    }
+
+    pub mod tui {
+        //! ratatuiで動く管理画面。一覧から選んだユーザーの登録・編集・退会を、CLIと同じユースケースで行う。
+        //! CLIと同じく、サーバー上で運用する人が使う前提なので権限の確認はしない。
+        //! キー操作と描画は端末から切り離してあるので、`App` だけを動かして確かめられる。
+
+        use adapter::user_id;
+        use entity::user::Name;
+        use env::RealWorld;
+        use failure::Error;
+        use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+        use ratatui::layout::{Constraint, Layout, Rect};
+        use ratatui::style::{Modifier, Style};
+        use ratatui::text::Line;
+        use ratatui::widgets::{Block, Clear, Paragraph, Row, Table, TableState};
+        use ratatui::{DefaultTerminal, Frame};
+        use std::mem;
+        use usecase::delete_account::{ConfirmAccountDeletionInteractor, RequestAccountDeletionInteractor};
+        use usecase::dto::UserSummaryDto;
+        use usecase::list_users::{ListUsersInteractor, ListUsersQuery};
+        use usecase::presentation_error::PresentationError;
+        use usecase::register_user::NewUser;
+        use usecase::rename_user::{RenameUserInteractor, UserRename};
+        use usecase::update_email::{EmailUpdate, UpdateEmailInteractor};
+        use usecase::{Decorate, UseCase};
+
+        /// 入力欄のうち、今打ち込んでいる方
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+        pub enum Field {
+            #[default]
+            Name,
+            Email,
+        }
+
+        /// 登録と編集で使う入力欄
+        #[derive(Debug, Clone, Default, PartialEq, Eq)]
+        pub struct Form {
+            pub name: String,
+            pub email: String,
+            pub focus: Field,
+        }
+
+        impl Form {
+            fn input(&mut self) -> &mut String {
+                match self.focus {
+                    Field::Name => &mut self.name,
+                    Field::Email => &mut self.email,
+                }
+            }
+        }
+
+        /// 一覧の上に開いている枠
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum Pane {
+            List,
+            Create(Form),
+            /// 編集前のユーザーと入力欄
+            Edit(UserSummaryDto, Form),
+            /// 退会させてよいかを確かめている
+            Delete(UserSummaryDto),
+        }
+
+        /// 画面の状態
+        pub struct App {
+            pub users: Vec<UserSummaryDto>,
+            /// 1始まり
+            pub page: usize,
+            pub pages: usize,
+            pub pane: Pane,
+            /// 最後の操作の結果。エラーもここに出す
+            pub status: String,
+            pub quit: bool,
+            table: TableState,
+        }
+
+        impl App {
+            pub fn new(world: &RealWorld) -> App {
+                let mut app = App {
+                    users: Vec::new(),
+                    page: 1,
+                    pages: 1,
+                    pane: Pane::List,
+                    status: String::new(),
+                    quit: false,
+                    table: TableState::default(),
+                };
+                app.reload(world);
+                app
+            }
+
+            /// 今のページを読み直す。選んでいた位置はなるべく保つ
+            pub fn reload(&mut self, world: &RealWorld) {
+                let query = ListUsersQuery {
+                    page: self.page,
+                    ..ListUsersQuery::default()
+                };
+                match ListUsersInteractor::new(world).logged().execute(query) {
+                    Ok(page) => {
+                        self.pages = page.total.div_ceil(page.per_page.max(1)).max(1);
+                        self.users = page.items;
+                        // 最後のページの最後の1人を退会させた時は、1つ前のページに戻る
+                        if self.users.is_empty() && self.page > self.pages {
+                            self.page = self.pages;
+                            return self.reload(world);
+                        }
+                        let selected = self.table.selected().unwrap_or(0);
+                        self.select(selected);
+                    }
+                    Err(e) => self.fail(e.into()),
+                }
+            }
+
+            pub fn selected(&self) -> Option<&UserSummaryDto> {
+                self.table.selected().and_then(|i| self.users.get(i))
+            }
+
+            fn select(&mut self, index: usize) {
+                let last = self.users.len().checked_sub(1);
+                self.table.select(last.map(|last| index.min(last)));
+            }
+
+            fn fail(&mut self, e: Error) {
+                self.status = format!("error: {}", PresentationError::from(e));
+            }
+
+            pub fn handle_key(&mut self, world: &RealWorld, key: KeyEvent) {
+                if key.kind != KeyEventKind::Press {
+                    return;
+                }
+                match mem::replace(&mut self.pane, Pane::List) {
+                    Pane::List => self.on_list(world, key.code),
+                    Pane::Create(form) => self.on_form(world, None, form, key.code),
+                    Pane::Edit(user, form) => self.on_form(world, Some(user), form, key.code),
+                    // `y` 以外は取り消し
+                    Pane::Delete(user) => {
+                        if key.code == KeyCode::Char('y') {
+                            let result = delete(world, &user);
+                            self.done(world, result);
+                        }
+                    }
+                }
+            }
+
+            fn on_list(&mut self, world: &RealWorld, code: KeyCode) {
+                match code {
+                    KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+                    KeyCode::Down | KeyCode::Char('j') => {
+                        let next = self.table.selected().map_or(0, |i| i + 1);
+                        self.select(next);
+                    }
+                    KeyCode::Up | KeyCode::Char('k') => {
+                        let previous = self.table.selected().map_or(0, |i| i.saturating_sub(1));
+                        self.select(previous);
+                    }
+                    KeyCode::Right | KeyCode::Char('l') if self.page < self.pages => {
+                        self.page += 1;
+                        self.table.select(None);
+                        self.reload(world);
+                    }
+                    KeyCode::Left | KeyCode::Char('h') if self.page > 1 => {
+                        self.page -= 1;
+                        self.table.select(None);
+                        self.reload(world);
+                    }
+                    KeyCode::Char('r') => self.reload(world),
+                    KeyCode::Char('n') => self.pane = Pane::Create(Form::default()),
+                    KeyCode::Char('e') | KeyCode::Enter => {
+                        if let Some(user) = self.selected().cloned() {
+                            let form = Form {
+                                name: user.name.clone(),
+                                email: user.email.clone(),
+                                focus: Field::Name,
+                            };
+                            self.pane = Pane::Edit(user, form);
+                        }
+                    }
+                    KeyCode::Char('d') => {
+                        if let Some(user) = self.selected().cloned() {
+                            self.pane = Pane::Delete(user);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            fn on_form(
+                &mut self,
+                world: &RealWorld,
+                editing: Option<UserSummaryDto>,
+                mut form: Form,
+                code: KeyCode,
+            ) {
+                match code {
+                    KeyCode::Esc => return,
+                    KeyCode::Tab | KeyCode::BackTab | KeyCode::Up | KeyCode::Down => {
+                        form.focus = match form.focus {
+                            Field::Name => Field::Email,
+                            Field::Email => Field::Name,
+                        }
+                    }
+                    KeyCode::Backspace => {
+                        form.input().pop();
+                    }
+                    KeyCode::Char(c) => form.input().push(c),
+                    KeyCode::Enter => {
+                        let result = match editing {
+                            None => create(world, &form),
+                            Some(ref user) => edit(world, user, &form),
+                        };
+                        match result {
+                            Ok(status) => return self.done(world, Ok(status)),
+                            // 入力し直せるように枠は開いたままにする
+                            Err(e) => self.fail(e),
+                        }
+                    }
+                    _ => {}
+                }
+                self.pane = match editing {
+                    None => Pane::Create(form),
+                    Some(user) => Pane::Edit(user, form),
+                };
+            }
+
+            fn done(&mut self, world: &RealWorld, result: Result<String, Error>) {
+                match result {
+                    Ok(status) => {
+                        self.status = status;
+                        self.reload(world);
+                    }
+                    Err(e) => self.fail(e),
+                }
+            }
+
+            pub fn draw(&mut self, frame: &mut Frame) {
+                let [list, help] = Layout::vertical([Constraint::Min(3), Constraint::Length(2)]).areas(frame.area());
+                let rows = self.users.iter().map(|user| {
+                    Row::new(vec![user.name.clone(), user.email.clone(), user.role.clone(), user.status.clone()])
+                });
+                let widths = [
+                    Constraint::Percentage(25),
+                    Constraint::Percentage(45),
+                    Constraint::Percentage(15),
+                    Constraint::Percentage(15),
+                ];
+                let bold = Style::new().add_modifier(Modifier::BOLD);
+                let header = Row::new(vec!["name", "email", "role", "status"]).style(bold);
+                let table = Table::new(rows, widths)
+                    .header(header)
+                    .block(Block::bordered().title(format!(" users (page {}/{}) ", self.page, self.pages)))
+                    .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+                frame.render_stateful_widget(table, list, &mut self.table);
+
+                let keys = match self.pane {
+                    Pane::List => "n: new  e: edit  d: delete  ←/→: page  r: reload  q: quit",
+                    Pane::Create(_) | Pane::Edit(..) => "tab: next field  enter: save  esc: cancel",
+                    Pane::Delete(_) => "y: delete  any other key: cancel",
+                };
+                frame.render_widget(Paragraph::new(vec![Line::from(self.status.as_str()), Line::from(keys)]), help);
+
+                match self.pane {
+                    Pane::List => {}
+                    Pane::Create(ref form) => draw_form(frame, " new user ".to_string(), form),
+                    Pane::Edit(ref user, ref form) => draw_form(frame, format!(" edit {} ", user.name), form),
+                    Pane::Delete(ref user) => {
+                        let area = popup(frame.area(), 1);
+                        let question = format!("delete {} <{}>? (y/n)", user.name, user.email);
+                        frame.render_widget(Clear, area);
+                        frame.render_widget(Paragraph::new(question).block(Block::bordered().title(" delete ")), area);
+                    }
+                }
+            }
+        }
+
+        fn draw_form(frame: &mut Frame, title: String, form: &Form) {
+            let area = popup(frame.area(), 2);
+            let marker = |field| if form.focus == field { "> " } else { "  " };
+            let lines = vec![
+                Line::from(format!("{}name:  {}", marker(Field::Name), form.name)),
+                Line::from(format!("{}email: {}", marker(Field::Email), form.email)),
+            ];
+            frame.render_widget(Clear, area);
+            frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(title)), area);
+        }
+
+        /// 画面の中央に、中身が `height` 行の枠を置く
+        fn popup(area: Rect, height: u16) -> Rect {
+            let vertical = [Constraint::Fill(1), Constraint::Length(height + 2), Constraint::Fill(1)];
+            let [_, area, _] = Layout::vertical(vertical).areas(area);
+            let horizontal = [Constraint::Percentage(15), Constraint::Percentage(70), Constraint::Percentage(15)];
+            let [_, area, _] = Layout::horizontal(horizontal).areas(area);
+            area
+        }
+
+        fn create(world: &RealWorld, form: &Form) -> Result<String, Error> {
+            let new_user = NewUser {
+                name: form.name.clone(),
+                email: form.email.clone(),
+            };
+            let user = world.register_user_use_case().execute(new_user)?;
+            Ok(format!("created {}", user.name))
+        }
+
+        /// 変わった項目だけを、それぞれのユースケースで変更する
+        fn edit(world: &RealWorld, user: &UserSummaryDto, form: &Form) -> Result<String, Error> {
+            let id = user_id(&user.id)?;
+            if form.name != user.name {
+                let rename = UserRename {
+                    id: id.clone(),
+                    name: Name::new(&form.name)?,
+                };
+                RenameUserInteractor::new(world).transactional().logged().execute(rename)?;
+            }
+            if form.email != user.email {
+                let update = EmailUpdate {
+                    id,
+                    email: form.email.clone(),
+                };
+                UpdateEmailInteractor::new(world).transactional().logged().execute(update)?;
+            }
+            Ok(format!("updated {}", form.name))
+        }
+
+        /// 確認はこの画面で済ませているので、CLIと同じく確認用のトークンを発行してそのまま確定する
+        fn delete(world: &RealWorld, user: &UserSummaryDto) -> Result<String, Error> {
+            let token = RequestAccountDeletionInteractor::new(world).execute(user_id(&user.id)?)?;
+            let user = ConfirmAccountDeletionInteractor::new(world).transactional().logged().execute(token)?;
+            Ok(format!("deleted {}", user.name))
+        }
+
+        /// 端末を全画面にして、`q` で終わるまで動かす。終わったら変更を書き出す
+        pub fn run(world: RealWorld) -> Result<(), Error> {
+            let mut terminal = ratatui::init();
+            let result = event_loop(&mut terminal, &world);
+            // エラーで抜けた時も端末は元に戻す
+            ratatui::restore();
+            result.and_then(|_| world.persist())
+        }
+
+        fn event_loop(terminal: &mut DefaultTerminal, world: &RealWorld) -> Result<(), Error> {
+            let mut app = App::new(world);
+            while !app.quit {
+                terminal.draw(|frame| app.draw(frame))?;
+                if let Event::Key(key) = event::read()? {
+                    app.handle_key(world, key);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    pub mod cli {
+        //! コマンドラインからユースケースを呼ぶ。
+        //! サーバー上で運用する人が使う前提なので、権限の確認はせずに実行する。
+
+        use adapter::controller::{Caller, HaveUserController, UserController};
+        use adapter::presenter::{JsonPresenter, Presenter, Table, TablePresenter};
+        use adapter::{command_bus, grpc, http, json_rpc, repl, tui, websocket};
+        use chrono::{FixedOffset, Local};
+        use clap::{Arg, ArgMatches, Command};
+        use component::config::{Config, ConfigComponent};
+        use component::environment::ProcessEnvironment;
+        use component::filesystem::StdFileSystem;
+        use component::jobs::{HaveJobQueueComponent, JobQueueComponent};
+        use component::queue::{HaveMessageQueueComponent, MessageQueueComponent};
+        use component::time::HaveTimeComponent;
+        use env::RealWorld;
+        use failure::Error;
+        use serde::Serialize;
+        use serde_json;
+        use std::io::Write;
+        use std::path::PathBuf;
+        use std::str::FromStr;
+        use usecase::export_users::{Column, Export, ExportFormat};
+        use usecase::import_users::{Import, ImportFormat};
+        use tokio::runtime::Runtime;
+        use usecase::list_users::ListUsersQuery;
+        use usecase::register_user::NewUser;
+        use usecase::user_events::USER_EVENTS_TOPIC;
+
+        /// ユーザーの保存先。`memory` か `file:<パス>` で指定する。
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum Storage {
+            Memory,
+            File(PathBuf),
+        }
+
+        impl FromStr for Storage {
+            type Err = Error;
+            fn from_str(s: &str) -> Result<Storage, Error> {
+                match s.split_once(':') {
+                    _ if s == "memory" => Ok(Storage::Memory),
+                    Some(("file", path)) if !path.is_empty() => Ok(Storage::File(PathBuf::from(path))),
+                    _ => bail!("unknown storage: {} (expected `memory` or `file:<path>`)", s),
+                }
+            }
+        }
+
+        /// 結果の表示の仕方
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Output {
+            Json,
+            Table,
+        }
+
+        impl FromStr for Output {
+            type Err = Error;
+            fn from_str(s: &str) -> Result<Output, Error> {
+                match s {
+                    "json" => Ok(Output::Json),
+                    "table" => Ok(Output::Table),
+                    _ => bail!("unknown output: {} (expected `json` or `table`)", s),
+                }
+            }
+        }
+
+        pub fn command() -> Command {
+            let id = || Arg::new("id").required(true).help("ユーザーのID(UUID)");
+            Command::new("layered")
+                .about("Cake Pattern + Clean Architecture のサンプル")
+                .subcommand_required(true)
+                .arg(
+                    Arg::new("storage")
+                        .long("storage")
+                        .global(true)
+                        .value_name("BACKEND")
+                        .value_parser(Storage::from_str)
+                        .help("ユーザーの保存先(`memory` か `file:<パス>`)。無ければ設定の通りにする"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .global(true)
+                        .value_name("FORMAT")
+                        .value_parser(Output::from_str)
+                        .help("結果の表示の仕方(`json` か `table`)。無ければ `json`"),
+                )
+                .arg(
+                    Arg::new("tz")
+                        .long("tz")
+                        .global(true)
+                        .value_name("OFFSET")
+                        .value_parser(FixedOffset::from_str)
+                        .help("表に出す日時のタイムゾーン(`+09:00` 等)。無ければ実行環境のタイムゾーン"),
+                )
+                .subcommand(
+                    Command::new("user")
+                        .about("ユーザーを操作する")
+                        .subcommand_required(true)
+                        .subcommand(
+                            Command::new("add")
+                                .about("ユーザーを登録する")
+                                .arg(Arg::new("name").required(true))
+                                .arg(Arg::new("email").required(true)),
+                        )
+                        .subcommand(Command::new("get").about("ユーザーを1人表示する").arg(id()))
+                        .subcommand(
+                            Command::new("list")
+                                .about("ユーザーの一覧を表示する")
+                                .arg(Arg::new("page").long("page").value_parser(clap::value_parser!(usize)))
+                                .arg(Arg::new("per_page").long("per-page").value_parser(clap::value_parser!(usize))),
+                        )
+                        .subcommand(Command::new("delete").about("ユーザーを退会させる").arg(id()))
+                        .subcommand(Command::new("suspend").about("ユーザーを停止する").arg(id()))
+                        .subcommand(Command::new("restore").about("停止・退会したユーザーを元に戻す").arg(id()))
+                        .subcommand(
+                            Command::new("purge")
+                                .about("退会したユーザーを、紐づくデータごと完全に消す")
+                                .arg(id()),
+                        )
+                        .subcommand(
+                            Command::new("import")
+                                .about("JSONかCSVのファイルからユーザーを読み込む。誤りのある行があれば1件も読み込まない")
+                                .arg(Arg::new("path").required(true).value_parser(clap::value_parser!(PathBuf)))
+                                .arg(
+                                    Arg::new("format")
+                                        .long("format")
+                                        .value_parser(["json", "csv"])
+                                        .help("省略した時は拡張子が.csvならcsv、それ以外はjson"),
+                                )
+                                .arg(
+                                    Arg::new("parallelism")
+                                        .long("parallelism")
+                                        .value_parser(clap::value_parser!(usize))
+                                        .help("指定すると、この数までの塊に分けて並行して保存する。途中で失敗すると保存済みの塊は残る"),
+                                ),
+                        )
+                        .subcommand(
+                            Command::new("export")
+                                .about("全ユーザーをファイルに書き出す")
+                                .arg(
+                                    Arg::new("out")
+                                        .long("out")
+                                        .required(true)
+                                        .value_name("FILE")
+                                        .value_parser(clap::value_parser!(PathBuf)),
+                                )
+                                .arg(
+                                    Arg::new("format")
+                                        .long("format")
+                                        .value_parser(["json", "csv"])
+                                        .default_value("json")
+                                        .help("`json` ならuser importで読み込める"),
+                                )
+                                .arg(
+                                    Arg::new("columns")
+                                        .long("columns")
+                                        .value_name("COLUMNS")
+                                        .value_delimiter(',')
+                                        .value_parser(Column::from_str)
+                                        .help("CSVに書き出す列(カンマ区切り)。無ければ全部"),
+                                ),
+                        ),
+                )
+                .subcommand(
+                    Command::new("serve").about("REST APIのサーバーを起動する").arg(
+                        Arg::new("addr")
+                            .long("addr")
+                            .default_value("127.0.0.1:8080")
+                            .help("待ち受けるアドレス"),
+                    ),
+                )
+                .subcommand(
+                    Command::new("serve-grpc").about("gRPCのサーバーを起動する").arg(
+                        Arg::new("addr")
+                            .long("addr")
+                            .default_value("127.0.0.1:50051")
+                            .help("待ち受けるアドレス"),
+                    ),
+                )
+                .subcommand(
+                    Command::new("serve-ws").about("WebSocketのサーバーを起動する").arg(
+                        Arg::new("addr")
+                            .long("addr")
+                            .default_value("127.0.0.1:8081")
+                            .help("待ち受けるアドレス"),
+                    ),
+                )
+                .subcommand(Command::new("tui").about("端末で管理画面を開く"))
+                .subcommand(Command::new("repl").about("コマンドを1行ずつ読んで実行する"))
+                .subcommand(
+                    Command::new("rpc").about("JSON-RPC 2.0のリクエストを1行ずつ処理する").arg(
+                        Arg::new("tcp")
+                            .long("tcp")
+                            .value_name("ADDR")
+                            .help("このアドレスで待ち受ける。無ければ標準入出力を使う"),
+                    ),
+                )
+                .subcommand(Command::new("commands").about("標準入力のJSONのコマンドを1行ずつコマンドバスで実行する"))
+                .subcommand(Command::new("events").about("キューに溜まっているユーザーのイベントを取り出して、1行に1つずつ表示する"))
+                .subcommand(Command::new("jobs").about("積まれているジョブと、諦めたジョブを表示する"))
+        }
+
+        /// 設定を読み、`--storage` が指定されていれば保存先を差し替えてから実行する。
+        /// サーバーは `runtime` の上で動かす
+        pub fn run<W: Write>(runtime: &Runtime, matches: &ArgMatches, out: &mut W) -> Result<(), Error> {
+            let mut config = Config::load(&ProcessEnvironment, &StdFileSystem)?;
+            match matches.get_one::<Storage>("storage") {
+                Some(&Storage::Memory) => config.storage_path = None,
+                Some(Storage::File(path)) => config.storage_path = Some(path.clone()),
+                None => {}
+            }
+            let policy = config.cache_policy();
+            let world = RealWorld::with_config(config, policy)?;
+            match matches.subcommand() {
+                Some(("serve", serve)) => http::serve(runtime, world, addr(serve)),
+                Some(("serve-grpc", serve)) => grpc::serve(runtime, world, addr(serve)),
+                Some(("serve-ws", serve)) => websocket::serve(runtime, world, addr(serve)),
+                Some(("tui", _)) => tui::run(world),
+                Some(("repl", _)) => repl::run(world, out),
+                Some(("rpc", rpc)) => match rpc.get_one::<String>("tcp") {
+                    Some(addr) => json_rpc::serve_tcp(runtime, world, addr),
+                    None => json_rpc::serve_stdio(world),
+                },
+                Some(("commands", _)) => command_bus::serve_stdio(world),
+                Some(("events", _)) => events(&world, out),
+                Some(("jobs", _)) => jobs(&world, out),
+                _ => {
+                    dispatch(&world, matches, out)?;
+                    world.persist()
+                }
+            }
+        }
+
+        /// サブコマンドに対応するユースケースを実行し、結果をJSONで `out` に書く
+        pub fn dispatch<C, W>(world: &C, matches: &ArgMatches, out: &mut W) -> Result<(), Error>
+        where
+            C: HaveUserController + HaveTimeComponent,
+            W: Write,
+        {
+            let user = match matches.subcommand() {
+                Some(("user", user)) => user,
+                _ => bail!("unknown command"),
+            };
+            match user.subcommand() {
+                Some(("add", args)) => {
+                    let new_user = NewUser {
+                        name: args.get_one::<String>("name").cloned().unwrap_or_default(),
+                        email: args.get_one::<String>("email").cloned().unwrap_or_default(),
+                    };
+                    let user = world.user_controller().register(new_user)?;
+                    show(world, out, args, user)
+                }
+                Some(("get", args)) => {
+                    let user = world.user_controller().get(&Caller::Operator, id(args))?;
+                    show(world, out, args, user)
+                }
+                Some(("list", args)) => {
+                    let query = ListUsersQuery {
+                        page: args.get_one::<usize>("page").cloned().unwrap_or(1),
+                        per_page: args.get_one::<usize>("per_page").cloned(),
+                        ..ListUsersQuery::default()
+                    };
+                    let page = world.user_controller().list(&Caller::Operator, query)?;
+                    show(world, out, args, page)
+                }
+                // 本人への確認は要らないので、確認用のトークンを発行してそのまま確定する
+                Some(("delete", args)) => {
+                    let token = world.user_controller().request_deletion(&Caller::Operator, id(args))?;
+                    let user = world.user_controller().confirm_deletion(&Caller::Operator, id(args), token)?;
+                    show(world, out, args, user)
+                }
+                Some(("suspend", args)) => {
+                    let user = world.user_controller().suspend(&Caller::Operator, id(args))?;
+                    show(world, out, args, user)
+                }
+                Some(("restore", args)) => {
+                    let user = world.user_controller().restore(&Caller::Operator, id(args))?;
+                    show(world, out, args, user)
+                }
+                Some(("purge", args)) => {
+                    let user = world.user_controller().purge(&Caller::Operator, id(args))?;
+                    show(world, out, args, user)
+                }
+                Some(("import", args)) => {
+                    let path = args.get_one::<PathBuf>("path").cloned().unwrap_or_default();
+                    let format = match args.get_one::<String>("format").map(String::as_str) {
+                        Some("csv") => ImportFormat::Csv,
+                        Some(_) => ImportFormat::Json,
+                        None => ImportFormat::from_path(&path),
+                    };
+                    let parallelism = args.get_one::<usize>("parallelism").copied();
+                    let imported = world.user_controller().import(Import { path, format, parallelism })?;
+                    print(out, &json!({ "imported": imported }))
+                }
+                Some(("export", args)) => {
+                    let columns: Option<Vec<Column>> = args.get_many::<Column>("columns").map(|c| c.cloned().collect());
+                    let format = match (args.get_one::<String>("format").map(String::as_str), columns) {
+                        (Some("csv"), columns) => ExportFormat::Csv(columns.unwrap_or_else(|| Column::ALL.to_vec())),
+                        (_, Some(_)) => bail!("--columns can only be used with --format csv"),
+                        (_, None) => ExportFormat::Json,
+                    };
+                    let export = Export {
+                        path: args.get_one::<PathBuf>("out").cloned().unwrap_or_default(),
+                        format,
+                    };
+                    let exported = world.user_controller().export(export)?;
+                    print(out, &json!({ "exported": exported }))
+                }
+                _ => bail!("unknown command"),
+            }
+        }
+
+        /// キューに流れたのと同じJSONを、流れた順に1行ずつ書く。取り出したイベントはキューから消える
+        pub fn events<Q: HaveMessageQueueComponent, W: Write>(world: &Q, out: &mut W) -> Result<(), Error> {
+            for payload in world.message_queue_component().consume(USER_EVENTS_TOPIC)? {
+                out.write_all(&payload)?;
+                writeln!(out)?;
+            }
+            Ok(())
+        }
+
+        /// ジョブをファイルに保存していれば、動いているサーバーが積んだジョブも見られる
+        pub fn jobs<J: HaveJobQueueComponent, W: Write>(world: &J, out: &mut W) -> Result<(), Error> {
+            let queue = world.job_queue_component();
+            print(out, &json!({ "jobs": queue.jobs()?, "dead_letters": queue.dead_letters()? }))
+        }
+
+        fn addr(args: &ArgMatches) -> &str {
+            args.get_one::<String>("addr").map_or("", |addr| addr.as_str())
+        }
+
+        fn id(args: &ArgMatches) -> &str {
+            args.get_one::<String>("id").map_or("", |id| id.as_str())
+        }
+
+        fn print<W: Write, T: Serialize>(out: &mut W, value: &T) -> Result<(), Error> {
+            writeln!(out, "{}", serde_json::to_string_pretty(value)?)?;
+            Ok(())
+        }
+
+        /// `--output` の通りに、表かJSONで表示する。表の日時は `--tz` のタイムゾーンで見せる
+        fn show<C, W, T>(world: &C, out: &mut W, args: &ArgMatches, value: T) -> Result<(), Error>
+        where
+            C: HaveTimeComponent,
+            W: Write,
+            TablePresenter<FixedOffset>: Presenter<T, ViewModel = Table>,
+            TablePresenter<Local>: Presenter<T, ViewModel = Table>,
+            JsonPresenter: Presenter<T>,
+            <JsonPresenter as Presenter<T>>::ViewModel: Serialize,
+        {
+            match args.get_one::<Output>("output") {
+                Some(&Output::Table) => {
+                    let time = world.time_component();
+                    let table = match args.get_one::<FixedOffset>("tz") {
+                        Some(tz) => TablePresenter::new(time, tz).present(value),
+                        None => TablePresenter::new(time, &Local).present(value),
+                    };
+                    writeln!(out, "{}", table.render())?;
+                    Ok(())
+                }
+                _ => print(out, &JsonPresenter.present(value)),
+            }
+        }
+    }
+}
+
+fn main() {
+    use failure::Error;
+    use std::io;
+    use std::process;
+    use tokio::runtime::Runtime;
+    use usecase::presentation_error::PresentationError;
+
+    let matches = adapter::cli::command().get_matches();
+    // サーバーもバックグラウンドのタスクも、ここで作った1つのランタイムで動かす
+    let result = Runtime::new()
+        .map_err(Error::from)
+        .and_then(|runtime| adapter::cli::run(&runtime, &matches, &mut io::stdout()));
+    if let Err(error) = result {
+        // 運用する人が見るので、原因はそのまま表示する
+        eprintln!("error: {}", error);
+        process::exit(PresentationError::from(error).exit_code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod mock {
+        pub mod time {
+            use chrono::prelude::*;
+            use chrono::Duration;
+            use component::time::{Instant, MonotonicTimeComponent, TimeComponent};
+            use std::cell::Cell;
+            use std::rc::Rc;
+            use std::str::FromStr;
+
+            /// テスト用のTimeComponent実装。
+            /// now()は特定の日時(2018-08-20T01:00:00Z)から、`advance` で進めた分だけ進んだ時刻を返す。
+            /// cloneした値とは時計を共有するので、他のComponentに渡した時計もまとめて進められる。
+            #[derive(Clone)]
+            pub struct MockTime {
+                elapsed: Rc<Cell<Duration>>,
+            }
+
+            impl MockTime {
+                pub fn new() -> MockTime {
+                    MockTime {
+                        elapsed: Rc::new(Cell::new(Duration::zero())),
+                    }
+                }
+
+                pub fn advance(&self, duration: Duration) {
+                    self.elapsed.set(self.elapsed.get() + duration);
+                }
+            }
+
+            impl TimeComponent for MockTime {
+                fn now(&self) -> DateTime<Utc> {
+                    DateTime::<Utc>::from_str("2018-08-20T10:00:00 +0900").unwrap() + self.elapsed.get()
+                }
+            }
+
+            /// 待機は実際には待たずに時計を進める
+            impl MonotonicTimeComponent for MockTime {
+                fn instant(&self) -> Instant {
+                    Instant::from_origin(self.elapsed.get())
+                }
+
+                fn sleep(&self, duration: Duration) {
+                    if duration > Duration::zero() {
+                        self.advance(duration);
+                    }
+                }
+            }
+        }
+
+        pub mod id {
+            use component::id::IdGeneratorComponent;
+            use std::cell::Cell;
+            use uuid::Uuid;
+
+            /// テスト用のIdGeneratorComponent実装。
+            /// 1, 2, 3, ... を順にUUIDにして返す。
+            pub struct SequentialIdGen {
+                next: Cell<u128>,
+            }
+
+            impl SequentialIdGen {
+                pub fn new() -> SequentialIdGen {
+                    SequentialIdGen { next: Cell::new(1) }
+                }
+            }
+
+            impl IdGeneratorComponent for SequentialIdGen {
+                fn generate(&self) -> Uuid {
+                    let id = self.next.get();
+                    self.next.set(id + 1);
+                    Uuid::from_u128(id)
+                }
+            }
+        }
+
+        pub mod mail {
+            use component::mail::{EmailSenderComponent, Mail};
+            use failure::Error;
+            use std::cell::{Cell, RefCell};
+
+            /// テスト用のEmailSenderComponent実装。送らずに覚えておくだけ。
+            pub struct RecordingMailer {
+                sent: RefCell<Vec<Mail>>,
+                refusing: Cell<bool>,
+            }
+
+            impl RecordingMailer {
+                pub fn new() -> RecordingMailer {
+                    RecordingMailer {
+                        sent: RefCell::new(Vec::new()),
+                        refusing: Cell::new(false),
+                    }
+                }
+
+                pub fn sent(&self) -> Vec<Mail> {
+                    self.sent.borrow().clone()
+                }
+
+                /// trueの間はSMTPサーバーに繋がらない時のように失敗する
+                pub fn refuse(&self, refusing: bool) {
+                    self.refusing.set(refusing);
+                }
+            }
+
+            impl EmailSenderComponent for RecordingMailer {
+                fn send(&self, mail: &Mail) -> Result<(), Error> {
+                    if self.refusing.get() {
+                        bail!("connection refused");
+                    }
+                    self.sent.borrow_mut().push(mail.clone());
+                    Ok(())
+                }
+            }
+        }
+
+        pub mod http {
+            use component::http::HttpClientComponent;
+            use failure::Error;
+            use serde_json::Value;
+            use std::collections::BTreeMap;
+            use std::sync::Mutex;
+
+            /// テスト用のHttpClientComponent実装。
+            /// URLごとに登録しておいた応答を返し、送られたリクエストを覚えておく。
+            /// 別々のスレッドから送られても覚えられるようにMutexで持つ。
+            pub struct StubHttpClient {
+                responses: BTreeMap<String, Value>,
+                requests: Mutex<Vec<(String, Option<Value>)>>,
+                headers: Mutex<Vec<(String, String)>>,
+            }
+
+            impl StubHttpClient {
+                pub fn new() -> StubHttpClient {
+                    StubHttpClient {
+                        responses: BTreeMap::new(),
+                        requests: Mutex::new(Vec::new()),
+                        headers: Mutex::new(Vec::new()),
+                    }
+                }
+
+                pub fn respond(mut self, url: &str, response: Value) -> StubHttpClient {
+                    self.responses.insert(url.to_string(), response);
+                    self
+                }
+
+                /// (URL, POSTした本文) を送った順に返す。GETの本文はNone。
+                pub fn requests(&self) -> Vec<(String, Option<Value>)> {
+                    self.requests.lock().unwrap().clone()
+                }
+
+                /// 全てのリクエストに付けられた (名前, 値) を送った順に返す
+                pub fn headers(&self) -> Vec<(String, String)> {
+                    self.headers.lock().unwrap().clone()
+                }
+
+                fn respond_to(&self, url: &str, body: Option<&Value>) -> Result<Value, Error> {
+                    self.requests.lock().unwrap().push((url.to_string(), body.cloned()));
+                    match self.responses.get(url) {
+                        Some(response) => Ok(response.clone()),
+                        None => bail!("no stub response for {}", url),
+                    }
+                }
+            }
+
+            impl HttpClientComponent for StubHttpClient {
+                fn get_json(&self, url: &str) -> Result<Value, Error> {
+                    self.respond_to(url, None)
+                }
+
+                /// 応答が登録されていないURLは404として扱う
+                fn find_json(&self, url: &str) -> Result<Option<Value>, Error> {
+                    self.requests.lock().unwrap().push((url.to_string(), None));
+                    Ok(self.responses.get(url).cloned())
+                }
+
+                fn post_json(&self, url: &str, body: &Value) -> Result<Value, Error> {
+                    self.respond_to(url, Some(body))
+                }
+
+                fn post_json_with_headers(
+                    &self,
+                    url: &str,
+                    body: &Value,
+                    headers: &[(&str, &str)],
+                ) -> Result<Value, Error> {
+                    let headers = headers.iter().map(|&(name, value)| (name.to_string(), value.to_string()));
+                    self.headers.lock().unwrap().extend(headers);
+                    self.respond_to(url, Some(body))
+                }
+
+                /// 応答が登録されていなければnullを返す
+                fn put_json(&self, url: &str, body: &Value) -> Result<Value, Error> {
+                    self.requests.lock().unwrap().push((url.to_string(), Some(body.clone())));
+                    Ok(self.responses.get(url).cloned().unwrap_or(Value::Null))
+                }
+
+                fn delete(&self, url: &str) -> Result<bool, Error> {
+                    self.find_json(url).map(|found| found.is_some())
+                }
+            }
+        }
+
+        pub mod notification {
+            use component::notification::NotificationComponent;
+            use entity::user::{User, UserId};
+            use failure::Error;
+            use std::cell::RefCell;
+
+            /// テスト用のNotificationComponent実装。送らずに覚えておくだけ。
+            pub struct RecordingNotifier {
+                sent: RefCell<Vec<(UserId, String)>>,
+            }
+
+            impl RecordingNotifier {
+                pub fn new() -> RecordingNotifier {
+                    RecordingNotifier {
+                        sent: RefCell::new(Vec::new()),
+                    }
+                }
+
+                pub fn sent(&self) -> Vec<(UserId, String)> {
+                    self.sent.borrow().clone()
+                }
+            }
+
+            impl NotificationComponent for RecordingNotifier {
+                fn notify(&self, user: &User, message: &str) -> Result<(), Error> {
+                    self.sent.borrow_mut().push((user.id.clone(), message.to_string()));
+                    Ok(())
+                }
+            }
+        }
+
+        pub mod random {
+            use component::random::RandomComponent;
+            use rand::rngs::StdRng;
+            use rand::{RngCore, SeedableRng};
+            use std::cell::RefCell;
+
+            /// テスト用のRandomComponent実装。
+            /// 同じseedからは毎回同じ値の並びが出てくる。
+            pub struct MockRandom {
+                rng: RefCell<StdRng>,
+            }
+
+            impl MockRandom {
+                pub fn new(seed: u64) -> MockRandom {
+                    MockRandom {
+                        rng: RefCell::new(StdRng::seed_from_u64(seed)),
+                    }
+                }
+            }
+
+            impl RandomComponent for MockRandom {
+                fn fill_bytes(&self, buf: &mut [u8]) {
+                    self.rng.borrow_mut().fill_bytes(buf);
+                }
+            }
+        }
+
+        pub mod secrets {
+            use component::secrets::{Secret, SecretsComponent};
+            use std::collections::BTreeMap;
+
+            /// テスト用のSecretsComponent実装。登録しておいた固定の値を返す。
+            #[derive(Default)]
+            pub struct FixedSecrets {
+                secrets: BTreeMap<String, String>,
+            }
+
+            impl FixedSecrets {
+                pub fn new() -> FixedSecrets {
+                    FixedSecrets::default()
+                }
+
+                pub fn with(mut self, name: &str, value: &str) -> FixedSecrets {
+                    self.secrets.insert(name.to_string(), value.to_string());
+                    self
+                }
+            }
+
+            impl SecretsComponent for FixedSecrets {
+                fn secret(&self, name: &str) -> Option<Secret> {
+                    self.secrets.get(name).map(|value| Secret::new(value.as_str()))
+                }
+            }
+        }
+
+        pub mod search {
+            use component::search::SearchComponent;
+            use failure::Error;
+            use std::cell::RefCell;
+            use std::collections::BTreeMap;
+
+            /// テスト用のSearchComponent実装。
+            /// 検索語のどれかを(大文字小文字を区別せず)本文に含む文書を、idの順に返す。
+            #[derive(Default)]
+            pub struct SubstringSearch {
+                documents: RefCell<BTreeMap<String, String>>,
+            }
+
+            impl SubstringSearch {
+                pub fn new() -> SubstringSearch {
+                    SubstringSearch::default()
+                }
+            }
+
+            impl SearchComponent for SubstringSearch {
+                fn index(&self, id: &str, text: &str) -> Result<(), Error> {
+                    self.documents.borrow_mut().insert(id.to_string(), text.to_lowercase());
+                    Ok(())
+                }
+
+                fn remove(&self, id: &str) -> Result<(), Error> {
+                    self.documents.borrow_mut().remove(id);
+                    Ok(())
+                }
+
+                fn query(&self, query: &str, limit: usize) -> Result<Vec<String>, Error> {
+                    let words: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+                    Ok(self
+                        .documents
+                        .borrow()
+                        .iter()
+                        .filter(|(_, text)| words.iter().any(|word| text.contains(word.as_str())))
+                        .map(|(id, _)| id.clone())
+                        .take(limit)
+                        .collect())
+                }
+            }
+        }
+
+        pub mod trace {
+            use component::trace::TracingComponent;
+            use std::sync::{Arc, Mutex};
+
+            /// テスト用のTracingComponent実装。spanの開始と終了を起きた順に記録する。
+            #[derive(Default)]
+            pub struct RecordingTracer {
+                events: Arc<Mutex<Vec<String>>>,
+            }
+
+            impl RecordingTracer {
+                pub fn new() -> RecordingTracer {
+                    RecordingTracer::default()
+                }
+
+                /// `start <name> <key>=<value> ...` と `end <name>` の列
+                pub fn events(&self) -> Vec<String> {
+                    self.events.lock().unwrap().clone()
+                }
+            }
+
+            pub struct RecordedSpan {
+                name: &'static str,
+                events: Arc<Mutex<Vec<String>>>,
+            }
+
+            impl Drop for RecordedSpan {
+                fn drop(&mut self) {
+                    self.events.lock().unwrap().push(format!("end {}", self.name));
+                }
+            }
+
+            impl TracingComponent for RecordingTracer {
+                type Span = RecordedSpan;
+
+                fn start_span(&self, name: &'static str, attributes: &[(&str, &str)]) -> RecordedSpan {
+                    let mut event = format!("start {}", name);
+                    for (key, value) in attributes {
+                        event.push_str(&format!(" {}={}", key, value));
+                    }
+                    self.events.lock().unwrap().push(event);
+                    RecordedSpan {
+                        name,
+                        events: self.events.clone(),
+                    }
+                }
+            }
+        }
+
+        pub mod geoip {
+            use component::geoip::{GeoIpComponent, Location};
+            use failure::Error;
+            use std::collections::BTreeMap;
+            use std::net::IpAddr;
+
+            /// 登録したアドレスだけ場所を返すGeoIpComponent実装
+            #[derive(Default)]
+            pub struct StaticGeoIp {
+                locations: BTreeMap<IpAddr, Location>,
+            }
+
+            impl StaticGeoIp {
+                pub fn with(mut self, ip: &str, country: &str, city: &str) -> StaticGeoIp {
+                    let location = Location {
+                        country: Some(country.to_string()),
+                        city: Some(city.to_string()),
+                    };
+                    self.locations.insert(ip.parse().unwrap(), location);
+                    self
+                }
+            }
+
+            impl GeoIpComponent for StaticGeoIp {
+                fn lookup(&self, ip: IpAddr) -> Result<Option<Location>, Error> {
+                    Ok(self.locations.get(&ip).cloned())
+                }
+            }
+        }
+
+        pub mod template {
+            use component::template::TemplateComponent;
+            use failure::Error;
+            use serde_json::Value;
+
+            /// テスト用のTemplateComponent実装。
+            /// テンプレートを使わず、`<テンプレート名> <埋め込む値のJSON>` をそのまま返す。
+            pub struct PassthroughTemplates;
+
+            impl TemplateComponent for PassthroughTemplates {
+                fn render(&self, template: &str, context: &Value) -> Result<String, Error> {
+                    Ok(format!("{} {}", template, context))
+                }
+            }
+        }
+
+        pub mod crypto {
+            use component::crypto::CryptoComponent;
+            use failure::Error;
+
+            /// テスト用のCryptoComponent実装。暗号化せずにそのまま返し、署名はメッセージそのものにする。
+            #[derive(Debug, Clone, Copy, Default)]
+            pub struct NoopCrypto;
+
+            impl CryptoComponent for NoopCrypto {
+                fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+                    Ok(plaintext.to_vec())
+                }
+
+                fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+                    Ok(ciphertext.to_vec())
+                }
+
+                fn sign(&self, message: &[u8]) -> Vec<u8> {
+                    message.to_vec()
+                }
+
+                fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+                    message == signature
+                }
+            }
+        }
+
+        pub mod environment {
+            use component::environment::EnvironmentComponent;
+            use std::collections::BTreeMap;
+
+            /// テスト用のEnvironmentComponent実装。登録しておいた環境変数だけがある。
+            /// ホスト名は `test-host`、プロセスIDは1。
+            #[derive(Default)]
+            pub struct FakeEnvironment {
+                vars: BTreeMap<String, String>,
+            }
+
+            impl FakeEnvironment {
+                pub fn new() -> FakeEnvironment {
+                    FakeEnvironment::default()
+                }
+
+                pub fn with(mut self, key: &str, value: &str) -> FakeEnvironment {
+                    self.vars.insert(key.to_string(), value.to_string());
+                    self
+                }
+            }
+
+            impl EnvironmentComponent for FakeEnvironment {
+                fn var(&self, key: &str) -> Option<String> {
+                    self.vars.get(key).cloned()
+                }
+
+                fn hostname(&self) -> String {
+                    "test-host".to_string()
+                }
+
+                fn pid(&self) -> u32 {
+                    1
+                }
+            }
+        }
+
+        pub mod filesystem {
+            use component::filesystem::FileSystemComponent;
+            use failure::Error;
+            use std::cell::{Cell, RefCell};
+            use std::collections::BTreeMap;
+            use std::path::{Path, PathBuf};
+
+            /// テスト用のFileSystemComponent実装。ファイルをメモリ上に持つ。
+            /// `fail_writes(true)` の間は、ディスクが一杯になった時のように全体の書き直しが失敗する。
+            #[derive(Default)]
+            pub struct MemoryFileSystem {
+                files: RefCell<BTreeMap<PathBuf, String>>,
+                failing: Cell<bool>,
+            }
+
+            impl MemoryFileSystem {
+                pub fn new() -> MemoryFileSystem {
+                    MemoryFileSystem::default()
+                }
+
+                pub fn fail_writes(&self, failing: bool) {
+                    self.failing.set(failing);
+                }
+            }
+
+            impl FileSystemComponent for MemoryFileSystem {
+                fn read(&self, path: &Path) -> Result<Option<String>, Error> {
+                    Ok(self.files.borrow().get(path).cloned())
+                }
+
+                fn write(&self, path: &Path, contents: &str) -> Result<(), Error> {
+                    if self.failing.get() {
+                        bail!("no space left: {}", path.display());
+                    }
+                    self.files.borrow_mut().insert(path.to_path_buf(), contents.to_string());
+                    Ok(())
+                }
+
+                fn append(&self, path: &Path, contents: &str) -> Result<(), Error> {
+                    self.files
+                        .borrow_mut()
+                        .entry(path.to_path_buf())
+                        .or_default()
+                        .push_str(contents);
+                    Ok(())
+                }
+            }
+        }
+
+        pub mod pool {
+            use component::pool::Connector;
+            use failure::Error;
+            use std::collections::BTreeSet;
+            use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+            use std::sync::Mutex;
+
+            /// テスト用のConnector実装。接続は作った順の番号で、`break_connection` で壊れた事にできる。
+            #[derive(Default)]
+            pub struct MemoryConnector {
+                opened: AtomicUsize,
+                broken: Mutex<BTreeSet<usize>>,
+                refusing: AtomicBool,
+            }
+
+            impl MemoryConnector {
+                pub fn new() -> MemoryConnector {
+                    MemoryConnector::default()
+                }
+
+                /// これまでに作った接続の数
+                pub fn opened(&self) -> usize {
+                    self.opened.load(Ordering::SeqCst)
+                }
+
+                pub fn break_connection(&self, connection: usize) {
+                    self.broken.lock().unwrap().insert(connection);
+                }
+
+                /// trueにしている間は接続を作れない
+                pub fn refuse(&self, refusing: bool) {
+                    self.refusing.store(refusing, Ordering::SeqCst);
+                }
+            }
+
+            /// プールに渡した後も、テストから接続を壊したり数えたりできるように参照で渡す
+            impl Connector for &MemoryConnector {
+                type Connection = usize;
+                fn connect(&self) -> Result<usize, Error> {
+                    if self.refusing.load(Ordering::SeqCst) {
+                        bail!("connection refused");
+                    }
+                    Ok(self.opened.fetch_add(1, Ordering::SeqCst))
+                }
+
+                fn is_healthy(&self, connection: &mut usize) -> bool {
+                    !self.broken.lock().unwrap().contains(connection)
+                }
+            }
+        }
+
+        pub mod storage {
+            use component::storage::{save_in_chunks, MemoryStorage, StorageComponent};
+            use entity::Entity;
+            use failure::Error;
+            use std::fmt::Debug;
+            use std::sync::Mutex;
+            use std::thread::{self, ThreadId};
+
+            /// テスト用の、塊ごとに別々に書けるStorageComponent実装。値はMemoryStorageに入れる。
+            /// 塊を書いたスレッドを覚えておき、`fail_on` で指定したキーを含む塊は1件も書かずに失敗する。
+            pub struct ChunkedStorage<K, V> {
+                storage: MemoryStorage<K, V>,
+                threads: Mutex<Vec<ThreadId>>,
+                failing: Mutex<Option<K>>,
+            }
+
+            impl<K: Ord + Clone, V> ChunkedStorage<K, V> {
+                pub fn new() -> ChunkedStorage<K, V> {
+                    ChunkedStorage {
+                        storage: MemoryStorage::new(),
+                        threads: Mutex::new(Vec::new()),
+                        failing: Mutex::new(None),
+                    }
+                }
+
+                /// 塊を書いたスレッドの数
+                pub fn threads(&self) -> usize {
+                    self.threads.lock().unwrap().len()
+                }
+
+                pub fn fail_on(&self, key: Option<K>) {
+                    *self.failing.lock().unwrap() = key;
+                }
+            }
+
+            impl<K, V> StorageComponent<K, V> for ChunkedStorage<K, V>
+            where
+                K: Ord + Clone + Debug + Send + Sync,
+                V: Entity + Clone + Send + Sync,
+            {
+                fn read(&self, key: K) -> Result<V, Error> {
+                    self.storage.read(key)
+                }
+
+                fn save(&self, key: K, value: V) -> Result<(), Error> {
+                    self.storage.save(key, value)
+                }
+
+                fn delete(&self, key: K) -> Result<(), Error> {
+                    self.storage.delete(key)
+                }
+
+                fn read_all(&self) -> Result<Vec<V>, Error> {
+                    self.storage.read_all()
+                }
+
+                fn save_all(&self, values: &[(K, V)]) -> Result<(), Error> {
+                    let failing = self.failing.lock().unwrap().clone();
+                    if values.iter().any(|(key, _)| Some(key) == failing.as_ref()) {
+                        bail!("failed to save {:?}", failing);
+                    }
+                    self.storage.save_all(values)
+                }
+
+                fn save_all_concurrent(&self, values: &[(K, V)], parallelism: usize) -> Result<(), Error> {
+                    save_in_chunks(values, parallelism, |chunk| {
+                        let mut threads = self.threads.lock().unwrap();
+                        if !threads.contains(&thread::current().id()) {
+                            threads.push(thread::current().id());
+                        }
+                        drop(threads);
+                        self.save_all(chunk)
+                    })
+                }
+            }
+        }
+
+        pub mod scheduler {
+            use chrono::prelude::*;
+            use component::scheduler::{Schedule, SchedulerComponent};
+            use std::cell::RefCell;
+
+            /// テスト用のSchedulerComponent実装。
+            /// 時間では進まず、`tick` で渡した時刻に実行時刻が来たジョブだけが溜まる。
+            #[derive(Default)]
+            pub struct ManualScheduler {
+                jobs: RefCell<Vec<(String, Schedule)>>,
+                due: RefCell<Vec<String>>,
+            }
+
+            impl ManualScheduler {
+                pub fn new() -> ManualScheduler {
+                    ManualScheduler::default()
+                }
+
+                pub fn tick(&self, at: DateTime<Utc>) {
+                    for (job, schedule) in self.jobs.borrow().iter() {
+                        if schedule.matches(&at) {
+                            self.due.borrow_mut().push(job.clone());
+                        }
+                    }
+                }
+            }
+
+            impl SchedulerComponent for ManualScheduler {
+                fn register(&self, job: &str, schedule: Schedule) {
+                    let mut jobs = self.jobs.borrow_mut();
+                    jobs.retain(|(name, _)| name != job);
+                    jobs.push((job.to_string(), schedule));
+                }
+
+                fn due_jobs(&self) -> Vec<String> {
+                    self.due.borrow_mut().drain(..).collect()
+                }
+            }
+        }
+
+        pub mod password {
+            use component::password::PasswordHasherComponent;
+            use entity::credentials::PasswordHash;
+            use failure::Error;
+
+            /// テスト用のPasswordHasherComponent実装。
+            /// ハッシュ化せずに目印を付けるだけなので速い。
+            pub struct PlainHasher;
+
+            impl PasswordHasherComponent for PlainHasher {
+                fn hash(&self, password: &str) -> Result<PasswordHash, Error> {
+                    Ok(PasswordHash::new(format!("plain:{}", password)))
+                }
+
+                fn verify(&self, password: &str, hash: &PasswordHash) -> Result<bool, Error> {
+                    Ok(hash.as_str() == format!("plain:{}", password))
+                }
+            }
+        }
+
+        pub mod log {
+            use component::log::{Level, LoggingComponent};
+            use std::cell::RefCell;
+
+            /// テスト用のLoggingComponent実装。書かれたログを覚えておいて後から確認できる。
+            pub struct RecordingLogger {
+                records: RefCell<Vec<(Level, String)>>,
+            }
+
+            impl RecordingLogger {
+                pub fn new() -> RecordingLogger {
+                    RecordingLogger {
+                        records: RefCell::new(Vec::new()),
+                    }
+                }
+
+                pub fn records(&self) -> Vec<(Level, String)> {
+                    self.records.borrow().clone()
+                }
+            }
+
+            impl LoggingComponent for RecordingLogger {
+                fn log(&self, level: Level, message: &str) {
+                    self.records.borrow_mut().push((level, message.to_string()));
+                }
+            }
+        }
+
+        pub mod feature_flag {
+            use component::feature_flag::FeatureFlagComponent;
+            use entity::user::User;
+            use std::collections::BTreeSet;
+
+            /// テスト用のFeatureFlagComponent実装。指定した機能を全員に対して有効にする
+            #[derive(Debug, Clone, Default)]
+            pub struct StaticFlags {
+                enabled: BTreeSet<String>,
+            }
+
+            impl StaticFlags {
+                pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(flags: I) -> StaticFlags {
+                    StaticFlags {
+                        enabled: flags.into_iter().map(Into::into).collect(),
+                    }
+                }
+            }
+
+            impl FeatureFlagComponent for StaticFlags {
+                fn is_enabled(&self, flag: &str, _user: &User) -> bool {
+                    self.enabled.contains(flag)
+                }
+            }
+        }
+
+        pub mod metrics {
+            use component::metrics::MetricsComponent;
+            use std::collections::BTreeMap;
+            use std::sync::{Mutex, PoisonError};
+
+            /// テスト用のMetricsComponent実装。メモリ上に記録して、後から値を取り出せる。
+            #[derive(Default)]
+            pub struct InMemoryMetrics {
+                counters: Mutex<BTreeMap<String, u64>>,
+                histograms: Mutex<BTreeMap<String, Vec<f64>>>,
+            }
+
+            impl InMemoryMetrics {
+                pub fn new() -> InMemoryMetrics {
+                    InMemoryMetrics::default()
+                }
+
+                /// 一度も増やされていないカウンタは0
+                pub fn counter(&self, name: &str) -> u64 {
+                    self.counters.lock().unwrap_or_else(PoisonError::into_inner).get(name).cloned().unwrap_or(0)
+                }
+
+                /// 記録した順に返す
+                pub fn observations(&self, name: &str) -> Vec<f64> {
+                    let histograms = self.histograms.lock().unwrap_or_else(PoisonError::into_inner);
+                    histograms.get(name).cloned().unwrap_or_default()
+                }
+            }
+
+            impl MetricsComponent for InMemoryMetrics {
+                fn increment(&self, name: &str, value: u64) {
+                    let mut counters = self.counters.lock().unwrap_or_else(PoisonError::into_inner);
+                    *counters.entry(name.to_string()).or_insert(0) += value;
+                }
+
+                fn observe(&self, name: &str, value: f64) {
+                    self.histograms
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .entry(name.to_string())
+                        .or_default()
+                        .push(value);
+                }
+            }
+        }
+
+        pub mod env {
+            use super::crypto::NoopCrypto;
+            use super::feature_flag::StaticFlags;
+            use super::filesystem::MemoryFileSystem;
+            use super::geoip::StaticGeoIp;
+            use super::http::StubHttpClient;
+            use super::id::SequentialIdGen;
+            use super::log::RecordingLogger;
+            use super::mail::RecordingMailer;
+            use super::metrics::InMemoryMetrics;
+            use super::notification::RecordingNotifier;
+            use super::password::PlainHasher;
+            use super::random::MockRandom;
+            use super::scheduler::ManualScheduler;
+            use super::search::SubstringSearch;
+            use super::template::PassthroughTemplates;
+            use super::time::MockTime;
+            use super::trace::RecordingTracer;
+            use component::event_bus::{HaveEventBusComponent, SyncEventBus};
+            use component::feature_flag::HaveFeatureFlagComponent;
+            use component::filesystem::HaveFileSystemComponent;
+            use component::health::{self, HealthCheckComponent, HealthReport};
+            use component::id::HaveIdGeneratorComponent;
+            use component::jobs::{HaveJobQueueComponent, StoredJobQueue};
+            use component::lock::{HaveLockComponent, InProcessLocks};
+            use component::log::HaveLoggingComponent;
+            use component::mail::HaveEmailSenderComponent;
+            use component::metrics::HaveMetricsComponent;
+            use component::queue::{HaveMessageQueueComponent, InMemoryQueue};
+            use component::notification::HaveNotificationComponent;
+            use component::password::HavePasswordHasherComponent;
+            use chrono::Duration;
+            use component::random::HaveRandomComponent;
+            use component::rate_limit::{HaveRateLimiterComponent, TokenBucket};
+            use component::scheduler::HaveSchedulerComponent;
+            use component::search::HaveSearchComponent;
+            use component::secrets::Secret;
+            use component::template::HaveTemplateComponent;
+            use component::time::{HaveMonotonicTimeComponent, HaveTimeComponent};
+            use component::trace::HaveTracingComponent;
+            use component::transaction::{Journaled, Participant, TransactionComponent};
+            use component::validation::{self, HavePasswordPolicyComponent, HaveValidationComponent, Rules};
+            use component::webhook::{HaveWebhookComponent, WebhookDispatcher};
+            use component::locale::{Catalogs, HaveLocaleComponent};
+            use component::config::{Config, HaveConfigComponent};
+            use component::crypto::HaveCryptoComponent;
+            use component::geoip::HaveGeoIpComponent;
+            use component::storage::{
+                HaveApiTokenStorageComponent, HaveCredentialStorageComponent, HaveGroupStorageComponent,
+                HaveInvitationStorageComponent, HavePasswordResetStorageComponent, HaveProfileStorageComponent,
+                HaveSessionStorageComponent, HaveUserStorageComponent, MemoryStorage, IndexedUserStorage,
+            };
+            use entity::api_token::{ApiToken, ApiTokenId};
+            use entity::credentials::{Credentials, PlainPassword};
+            use entity::group::{Group, GroupName};
+            use entity::invitation::{Invitation, InvitationId};
+            use entity::job::{Job, JobId};
+            use entity::password_reset::{PasswordResetId, PasswordResetToken};
+            use entity::profile::Profile;
+            use entity::session::{Session, SessionId};
+            use entity::user::{User, UserId};
+            use repository::api_tokens::{ApiTokenRepository, HaveApiTokenRepository};
+            use repository::credentials::{CredentialRepository, HaveCredentialRepository};
+            use repository::groups::{GroupRepository, HaveGroupRepository};
+            use repository::invitations::{HaveInvitationRepository, InvitationRepository};
+            use repository::password_resets::{HavePasswordResetRepository, PasswordResetRepository};
+            use repository::profiles::{HaveProfileRepository, ProfileRepository};
+            use repository::sessions::{HaveSessionRepository, SessionRepository};
+            use repository::users::{HaveUserCommands, HaveUserQueries, UserCommands, UserQueries};
+            use service::unique_email::{HaveUniqueEmailService, UniqueEmailService};
+            use usecase::user_events::SubscribeUserEvents;
+
+            pub type TestUserStorage = Journaled<IndexedUserStorage<MemoryStorage<UserId, User>>, User>;
+            pub type TestCredentialStorage = Journaled<MemoryStorage<UserId, Credentials>, Credentials>;
+            pub type TestSessionStorage = Journaled<MemoryStorage<SessionId, Session>, Session>;
+
+            /// テスト用の Cake Pattern での環境型
+            /// この構造体に各レイヤーを担当するオブジェクトを格納する。
+            pub struct TestWorld {
+                config_component: Config,
+                time_component: MockTime,
+                id_generator_component: SequentialIdGen,
+                random_component: MockRandom,
+                rate_limiter_component: TokenBucket<MockTime>,
+                lock_component: InProcessLocks,
+                scheduler_component: ManualScheduler,
+                file_system_component: MemoryFileSystem,
+                template_component: PassthroughTemplates,
+                search_component: SubstringSearch,
+                event_bus_component: SyncEventBus<TestWorld>,
+                validation_component: Rules<User>,
+                password_policy_component: Rules<PlainPassword>,
+                locale_component: Catalogs,
+                geo_ip_component: StaticGeoIp,
+                crypto_component: NoopCrypto,
+                tracing_component: RecordingTracer,
+                logging_component: RecordingLogger,
+                password_hasher_component: PlainHasher,
+                email_sender_component: RecordingMailer,
+                notification_component: RecordingNotifier,
+                metrics_component: InMemoryMetrics,
+                feature_flag_component: StaticFlags,
+                message_queue_component: InMemoryQueue,
+                job_queue_component: StoredJobQueue<MemoryStorage<JobId, Job>>,
+                webhook_component: WebhookDispatcher<StubHttpClient>,
+                storage_component: TestUserStorage,
+                credential_storage_component: TestCredentialStorage,
+                group_storage_component: MemoryStorage<GroupName, Group>,
+                profile_storage_component: MemoryStorage<UserId, Profile>,
+                session_storage_component: TestSessionStorage,
+                api_token_storage_component: MemoryStorage<ApiTokenId, ApiToken>,
+                password_reset_storage_component: MemoryStorage<PasswordResetId, PasswordResetToken>,
+                invitation_storage_component: MemoryStorage<InvitationId, Invitation>,
+            }
+
+            impl TestWorld {
+                pub fn new() -> TestWorld {
+                    // 時計は共有しておき、time_component()を進めればレート制限等の時刻も進むようにする
+                    let time = MockTime::new();
+                    let world = TestWorld {
+                        config_component: Config::default(),
+                        time_component: time.clone(),
+                        id_generator_component: SequentialIdGen::new(),
+                        random_component: MockRandom::new(0),
+                        rate_limiter_component: TokenBucket::with_clock(5, Duration::minutes(1), time),
+                        lock_component: InProcessLocks::new(),
+                        scheduler_component: ManualScheduler::new(),
+                        file_system_component: MemoryFileSystem::new(),
+                        template_component: PassthroughTemplates,
+                        search_component: SubstringSearch::new(),
+                        event_bus_component: SyncEventBus::new(),
+                        validation_component: Rules::new(),
+                        password_policy_component: validation::password_policy(8),
+                        locale_component: Catalogs::builtin(),
+                        geo_ip_component: StaticGeoIp::default(),
+                        crypto_component: NoopCrypto,
+                        tracing_component: RecordingTracer::new(),
+                        logging_component: RecordingLogger::new(),
+                        password_hasher_component: PlainHasher,
+                        email_sender_component: RecordingMailer::new(),
+                        notification_component: RecordingNotifier::new(),
+                        metrics_component: InMemoryMetrics::new(),
+                        feature_flag_component: StaticFlags::default(),
+                        message_queue_component: InMemoryQueue::new(),
+                        job_queue_component: StoredJobQueue::new(MemoryStorage::new(), MemoryStorage::new()).unwrap(),
+                        // 送り先が無いので何も送らない
+                        webhook_component: WebhookDispatcher::new(
+                            Vec::new(),
+                            1,
+                            Secret::new(""),
+                            StubHttpClient::new(),
+                        ),
+                        storage_component: Journaled::new(IndexedUserStorage::new(MemoryStorage::new()).unwrap()),
+                        credential_storage_component: Journaled::new(MemoryStorage::new()),
+                        group_storage_component: MemoryStorage::new(),
+                        profile_storage_component: MemoryStorage::new(),
+                        session_storage_component: Journaled::new(MemoryStorage::new()),
+                        api_token_storage_component: MemoryStorage::new(),
+                        password_reset_storage_component: MemoryStorage::new(),
+                        invitation_storage_component: MemoryStorage::new(),
+                    };
+                    world.subscribe_user_events();
+                    world
+                }
+
+                /// 指定した機能だけを有効にする
+                pub fn with_flags(mut self, flags: StaticFlags) -> TestWorld {
+                    self.feature_flag_component = flags;
+                    self
+                }
+
+                pub fn with_config(mut self, config: Config) -> TestWorld {
+                    self.config_component = config;
+                    self
+                }
+
+                pub fn with_webhooks(mut self, webhooks: WebhookDispatcher<StubHttpClient>) -> TestWorld {
+                    self.webhook_component = webhooks;
+                    self
+                }
+
+                /// IPアドレスの場所を登録する
+                pub fn with_geo_ip(mut self, geo_ip: StaticGeoIp) -> TestWorld {
+                    self.geo_ip_component = geo_ip;
+                    self
+                }
+
+                /// ユーザーの検証ルールを登録する
+                pub fn with_validation(mut self, rules: Rules<User>) -> TestWorld {
+                    self.validation_component = rules;
+                    self
+                }
+            }
+
+            impl HaveTracingComponent for TestWorld {
+                type TracingComponent = RecordingTracer;
+                fn tracing_component(&self) -> &RecordingTracer {
+                    &self.tracing_component
+                }
+            }
+
+            impl HealthCheckComponent for TestWorld {
+                fn health(&self) -> HealthReport {
+                    HealthReport::new()
+                        .check("user_storage", health::ping_storage(&self.storage_component))
+                        .check("clock", health::check_clock(&self.time_component))
+                        .check("queue", health::ping_queue(&self.message_queue_component))
+                }
+            }
+
+            impl TransactionComponent for TestWorld {
+                fn participants(&self) -> Vec<&dyn Participant> {
+                    vec![
+                        &self.storage_component,
+                        &self.credential_storage_component,
+                        &self.session_storage_component,
+                    ]
+                }
+            }
+
+            impl HaveValidationComponent for TestWorld {
+                type ValidationComponent = Rules<User>;
+                fn validation_component(&self) -> &Rules<User> {
+                    &self.validation_component
+                }
+            }
+
+            impl HavePasswordPolicyComponent for TestWorld {
+                type PasswordPolicyComponent = Rules<PlainPassword>;
+                fn password_policy_component(&self) -> &Rules<PlainPassword> {
+                    &self.password_policy_component
+                }
+            }
+
+            impl HaveLocaleComponent for TestWorld {
+                type LocaleComponent = Catalogs;
+                fn locale_component(&self) -> &Catalogs {
+                    &self.locale_component
+                }
+            }
+
+            impl HaveConfigComponent for TestWorld {
+                type ConfigComponent = Config;
+                fn config_component(&self) -> &Config {
+                    &self.config_component
+                }
+            }
+
+            impl HaveCryptoComponent for TestWorld {
+                type CryptoComponent = NoopCrypto;
+                fn crypto_component(&self) -> &NoopCrypto {
+                    &self.crypto_component
+                }
+            }
+
+            impl HaveGeoIpComponent for TestWorld {
+                type GeoIpComponent = StaticGeoIp;
+                fn geo_ip_component(&self) -> &StaticGeoIp {
+                    &self.geo_ip_component
+                }
+            }
+
+            impl HaveEventBusComponent for TestWorld {
+                type EventBusComponent = SyncEventBus<TestWorld>;
+                fn event_bus_component(&self) -> &SyncEventBus<TestWorld> {
+                    &self.event_bus_component
+                }
+            }
+
+            impl HaveSearchComponent for TestWorld {
+                type SearchComponent = SubstringSearch;
+                fn search_component(&self) -> &SubstringSearch {
+                    &self.search_component
+                }
+            }
+
+            impl HaveTemplateComponent for TestWorld {
+                type TemplateComponent = PassthroughTemplates;
+                fn template_component(&self) -> &PassthroughTemplates {
+                    &self.template_component
+                }
+            }
+
+            impl HaveFileSystemComponent for TestWorld {
+                type FileSystemComponent = MemoryFileSystem;
+                fn file_system_component(&self) -> &MemoryFileSystem {
+                    &self.file_system_component
+                }
+            }
+
+            impl HaveSchedulerComponent for TestWorld {
+                type SchedulerComponent = ManualScheduler;
+                fn scheduler_component(&self) -> &ManualScheduler {
+                    &self.scheduler_component
+                }
+            }
+
+            impl HaveLockComponent for TestWorld {
+                type LockComponent = InProcessLocks;
+                fn lock_component(&self) -> &InProcessLocks {
+                    &self.lock_component
+                }
+            }
+
+            impl HaveRateLimiterComponent for TestWorld {
+                type RateLimiterComponent = TokenBucket<MockTime>;
+                fn rate_limiter_component(&self) -> &TokenBucket<MockTime> {
+                    &self.rate_limiter_component
+                }
+            }
+
+            impl HaveFeatureFlagComponent for TestWorld {
+                type FeatureFlagComponent = StaticFlags;
+                fn feature_flag_component(&self) -> &StaticFlags {
+                    &self.feature_flag_component
+                }
+            }
+
+            impl HavePasswordHasherComponent for TestWorld {
+                type PasswordHasherComponent = PlainHasher;
+                fn password_hasher_component(&self) -> &PlainHasher {
+                    &self.password_hasher_component
+                }
+            }
+
+            impl HaveMessageQueueComponent for TestWorld {
+                type MessageQueueComponent = InMemoryQueue;
+                fn message_queue_component(&self) -> &InMemoryQueue {
+                    &self.message_queue_component
+                }
+            }
+
+            impl HaveJobQueueComponent for TestWorld {
+                type JobQueueComponent = StoredJobQueue<MemoryStorage<JobId, Job>>;
+                fn job_queue_component(&self) -> &StoredJobQueue<MemoryStorage<JobId, Job>> {
+                    &self.job_queue_component
+                }
+            }
+
+            impl HaveWebhookComponent for TestWorld {
+                type WebhookComponent = WebhookDispatcher<StubHttpClient>;
+                fn webhook_component(&self) -> &WebhookDispatcher<StubHttpClient> {
+                    &self.webhook_component
+                }
+            }
+
+            impl HaveMetricsComponent for TestWorld {
+                type MetricsComponent = InMemoryMetrics;
+                fn metrics_component(&self) -> &InMemoryMetrics {
+                    &self.metrics_component
+                }
+            }
+
+            impl HaveNotificationComponent for TestWorld {
+                type NotificationComponent = RecordingNotifier;
+                fn notification_component(&self) -> &RecordingNotifier {
+                    &self.notification_component
+                }
+            }
+
+            impl HaveEmailSenderComponent for TestWorld {
+                type EmailSenderComponent = RecordingMailer;
+                fn email_sender_component(&self) -> &RecordingMailer {
+                    &self.email_sender_component
+                }
+            }
+
+            impl HaveCredentialStorageComponent for TestWorld {
+                type CredentialStorageComponent = TestCredentialStorage;
+                fn credential_storage_component(&self) -> &TestCredentialStorage {
+                    &self.credential_storage_component
+                }
+            }
+
+            impl HaveGroupStorageComponent for TestWorld {
+                type GroupStorageComponent = MemoryStorage<GroupName, Group>;
+                fn group_storage_component(&self) -> &MemoryStorage<GroupName, Group> {
+                    &self.group_storage_component
+                }
+            }
+
+            impl HaveGroupRepository for TestWorld {
+                fn group_repository(&self) -> &impl GroupRepository {
+                    self
+                }
+            }
+
+            impl HaveProfileStorageComponent for TestWorld {
+            type ProfileStorageComponent = MemoryStorage<UserId, Profile>;
+            fn profile_storage_component(&self) -> &MemoryStorage<UserId, Profile> {
+                &self.profile_storage_component
+            }
+        }
+
+        impl HaveProfileRepository for TestWorld {
+            fn profile_repository(&self) -> &impl ProfileRepository {
+                self
+            }
+        }
+
+        impl HaveSessionStorageComponent for TestWorld {
+                type SessionStorageComponent = TestSessionStorage;
+                fn session_storage_component(&self) -> &TestSessionStorage {
+                    &self.session_storage_component
+                }
+            }
+
+            impl HaveSessionRepository for TestWorld {
+                fn session_repository(&self) -> &impl SessionRepository {
+                    self
+                }
+            }
+
+            impl HaveApiTokenStorageComponent for TestWorld {
+                type ApiTokenStorageComponent = MemoryStorage<ApiTokenId, ApiToken>;
+                fn api_token_storage_component(&self) -> &MemoryStorage<ApiTokenId, ApiToken> {
+                    &self.api_token_storage_component
+                }
+            }
+
+            impl HaveApiTokenRepository for TestWorld {
+                fn api_token_repository(&self) -> &impl ApiTokenRepository {
+                    self
+                }
+            }
+
+            impl HavePasswordResetStorageComponent for TestWorld {
+                type PasswordResetStorageComponent = MemoryStorage<PasswordResetId, PasswordResetToken>;
+                fn password_reset_storage_component(&self) -> &MemoryStorage<PasswordResetId, PasswordResetToken> {
+                    &self.password_reset_storage_component
+                }
+            }
+
+            impl HavePasswordResetRepository for TestWorld {
+                fn password_reset_repository(&self) -> &impl PasswordResetRepository {
+                    self
+                }
+            }
+
+            impl HaveInvitationStorageComponent for TestWorld {
+                type InvitationStorageComponent = MemoryStorage<InvitationId, Invitation>;
+                fn invitation_storage_component(&self) -> &MemoryStorage<InvitationId, Invitation> {
+                    &self.invitation_storage_component
+                }
+            }
+
+            impl HaveInvitationRepository for TestWorld {
+                fn invitation_repository(&self) -> &impl InvitationRepository {
+                    self
+                }
+            }
+
+            impl HaveCredentialRepository for TestWorld {
+                fn credential_repository(&self) -> &impl CredentialRepository {
+                    self
+                }
+            }
+
+            impl HaveTimeComponent for TestWorld {
+                type TimeComponent = MockTime;
+                fn time_component(&self) -> &MockTime {
+                    &self.time_component
+                }
+            }
+
+            impl HaveMonotonicTimeComponent for TestWorld {
+                type MonotonicTimeComponent = MockTime;
+                fn monotonic_time_component(&self) -> &MockTime {
+                    &self.time_component
+                }
+            }
+
+            impl HaveIdGeneratorComponent for TestWorld {
+                type IdGeneratorComponent = SequentialIdGen;
+                fn id_generator_component(&self) -> &SequentialIdGen {
+                    &self.id_generator_component
+                }
+            }
+
+            impl HaveRandomComponent for TestWorld {
+                type RandomComponent = MockRandom;
+                fn random_component(&self) -> &MockRandom {
+                    &self.random_component
+                }
+            }
+
+            impl HaveLoggingComponent for TestWorld {
+                type LoggingComponent = RecordingLogger;
+                fn logging_component(&self) -> &RecordingLogger {
+                    &self.logging_component
+                }
+            }
+
+            impl HaveUserStorageComponent for TestWorld {
+                type UserStorageComponent = TestUserStorage;
+                fn user_storage_component(&self) -> &TestUserStorage {
+                    &self.storage_component
+                }
+            }
+
+            impl HaveUserCommands for TestWorld {
+                fn user_commands(&self) -> &impl UserCommands {
+                    self
+                }
+            }
+
+            impl HaveUserQueries for TestWorld {
+                fn user_queries(&self) -> &impl UserQueries {
+                    self
+                }
+            }
+
+            impl HaveUniqueEmailService for TestWorld {
+                fn unique_email_service(&self) -> &impl UniqueEmailService {
+                    self
+                }
+            }
+        }
+    }
+
+    use self::mock::env::TestWorld;
+    use self::mock::environment::FakeEnvironment;
+    use self::mock::feature_flag::StaticFlags;
+    use self::mock::filesystem::MemoryFileSystem;
+    use self::mock::geoip::StaticGeoIp;
+    use self::mock::http::StubHttpClient;
+    use self::mock::mail::RecordingMailer;
+    use self::mock::metrics::InMemoryMetrics;
+    use self::mock::pool::MemoryConnector;
+    use self::mock::random::MockRandom;
+    use self::mock::secrets::FixedSecrets;
+    use self::mock::storage::ChunkedStorage;
+    use self::mock::time::MockTime;
+    use actor::{ActorWorld, Mailbox};
+    use adapter::cli::{self, Storage};
+    use adapter::controller::{Caller, HaveUserController, UserController};
+    use adapter::graphql::GraphQL;
+    use adapter::json_rpc::{self, Peer};
+    use adapter::presenter::{JsonPresenter, Presenter, TablePresenter};
+    use adapter::command_bus::{self, Command, CommandBus};
+    use adapter::{repl, tui, websocket};
+    use adapter::{self, grpc, http, ACTOR_HEADER};
+    use axum::body::{self, Body};
+    use axum::extract::ConnectInfo;
+    use axum::http::{Request, StatusCode};
+    use chrono::Duration;
+    use chrono::prelude::*;
+    use component::cache::{CacheComponent, CachePolicy, CachingStorage, MemoryCache};
+    use component::config::{Config, ConfigComponent, HaveConfigComponent, NotifierKind};
+    use component::environment::EnvironmentComponent;
+    use component::event_bus::{EventBusComponent, HaveEventBusComponent};
+    use component::feature_flag::{FeatureFlagComponent, PercentageRollout};
+    use component::crypto::{AesGcmCrypto, CryptoComponent};
+    use component::file::{EncryptedFields, FileStorage, JsonCodec, RecordCodec, UserRecordCodec};
+    use component::filesystem::{FileSystemComponent, HaveFileSystemComponent};
+    use component::health::{HealthCheckComponent, HealthReport};
+    use component::http::HttpClientComponent;
+    use component::jobs::{HaveJobQueueComponent, JobQueueComponent, StoredJobQueue};
+    use component::geoip::MaxMindGeoIp;
+    use component::locale::{Catalogs, LocaleComponent};
+    use component::lock::{HaveLockComponent, InProcessLocks, LockComponent};
+    use component::log::{HaveLoggingComponent, Level};
+    use component::mail::{EmailSenderComponent, HaveEmailSenderComponent, Mail, SmtpSender};
+    use component::metrics::{HaveMetricsComponent, MetricsComponent};
+    use component::queue::{HaveMessageQueueComponent, MessageQueueComponent};
+    use component::notification::{EmailNotifier, HaveNotificationComponent, NotificationComponent, WebhookNotifier};
+    use component::password::{Argon2Hasher, PasswordHasherComponent};
+    use component::pool::{ConnectionPool, ConnectionPoolComponent};
+    use component::random::RandomComponent;
+    use component::rate_limit::{RateLimit, RateLimiterComponent, TokenBucket};
+    use component::scheduler::{HaveSchedulerComponent, Schedule};
+    use component::search::{SearchComponent, TantivySearch};
+    use component::secrets::{EnvSecrets, FileVault, Secret, SecretsComponent};
+    use component::template::{HandlebarsTemplates, TemplateComponent, INVITATION, PASSWORD_RESET, WELCOME};
+    use component::storage::{ConcurrentMemoryStorage, IndexedUserStorage, MemoryStorage, StorageComponent};
+    use component::storage::{StorageError, UserStorageComponent};
+    use component::time::{
+        to_timezone, Chrono, HaveMonotonicTimeComponent, HaveTimeComponent, MonotonicTimeComponent, StdClock,
+        TimeComponent,
+    };
+    use component::trace::HaveTracingComponent;
+    use component::transaction::TransactionComponent;
+    use component::validation::{self, Rules};
+    use component::webhook::{self, HaveWebhookComponent, WebhookComponent, WebhookDispatcher, SIGNATURE_HEADER};
+    use env::RealWorld;
+    use entity::ValidationError;
+    use entity::address::Address;
+    use entity::api_token::Scope;
+    use entity::credentials::{PasswordHash, PlainPassword};
+    use entity::phone_number::PhoneNumber;
+    use entity::group::GroupName;
+    use entity::job::{Job, JobId, JobKind, JobStatus};
+    use entity::session::{Session, SessionId};
+    use entity::user::{Email, Name, Permission, Role, User, UserEvent, UserId, UserStatus};
+    use failure::Error;
+    use futures::{Sink, SinkExt, Stream, StreamExt};
+    use layered_proto::user_service_client::UserServiceClient;
+    use layered_proto::{CreateUserRequest, DeleteUserRequest, GetUserRequest, ListUsersRequest};
+    use ratatui::crossterm::event::{KeyCode, KeyEvent};
+    use repository::{DomainError, Repository};
+    use repository::api_tokens::{ApiTokenRepository, HaveApiTokenRepository};
+    use repository::credentials::{CredentialRepository, HaveCredentialRepository};
+    use repository::groups::{GroupRepository, HaveGroupRepository};
+    use repository::invitations::{HaveInvitationRepository, InvitationRepository};
+    use repository::password_resets::{HavePasswordResetRepository, PasswordResetRepository};
+    use repository::profiles::{HaveProfileRepository, ProfileRepository};
+    use repository::sessions::{HaveSessionRepository, SessionRepository};
+    use repository::unit_of_work::UnitOfWork;
+    use repository::users::{HaveUserCommands, HaveUserQueries, UserCommands, UserQueries};
+    use service::unique_email::{HaveUniqueEmailService, UniqueEmailService};
+    use serde_json::{self, Value};
+    use std::fmt;
+    use std::future::IntoFuture;
+    use std::net::{IpAddr, SocketAddr};
+    use std::path::Path;
+    use std::str::FromStr;
+    use std::cell::RefCell;
+    use std::sync::{Arc, Mutex};
+    use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+    use tonic::codegen::tokio_stream::wrappers::TcpListenerStream;
+    use tower::ServiceExt;
+    use usecase::dto::{InvitationDto, ProfileDto, UserDto, UserSummaryDto};
+    use usecase::presentation_error::{ErrorKind, PresentationError};
+    use usecase::{Decorate, Interactor, PermissionDenied, UseCase};
+    use usecase::account_mail::AccountMail;
+    use usecase::authenticate_user::{AuthenticateUser, AuthenticationError};
+    use usecase::change_password::{ChangePassword, ChangePasswordInteractor, PasswordChange};
+    use usecase::delete_account::{DeleteAccount, CONFIRMATION_TTL_MINUTES};
+    use usecase::error_message::ErrorMessage;
+    use usecase::export_users::{Column, Export, ExportFormat, ExportUsers};
+    use usecase::import_users::{Import, ImportError, ImportFormat, ImportUsers};
+    use usecase::list_users::{ListUsers, ListUsersInteractor, ListUsersQuery, Page, SortOrder, UserSort};
+    use usecase::invite_user::{AcceptInvitation, InviteUser, InviteUserInteractor, NewInvitation, INVITATION_TTL_DAYS};
+    use usecase::jobs::{backoff, JobReport, WorkJobs, MAX_ATTEMPTS};
+    use usecase::maintenance::{Maintenance, PURGE_EXPIRED_SESSIONS};
+    use usecase::password_reset::{ConfirmPasswordReset, RequestPasswordReset, PASSWORD_RESET_TTL_MINUTES};
+    use usecase::register_user::{NewUser, RegisterUser, RegisterUserInteractor};
+    use usecase::rename_user::{RenameUser, NOTIFY_ON_RENAME};
+    use usecase::search_users::{SearchUsers, UserSearch};
+    use usecase::signup_region::RecordSignupRegion;
+    use usecase::update_email::{EmailChange, EmailUpdate, UpdateEmail, UpdateEmailInteractor};
+    use usecase::user_events::USER_EVENTS_TOPIC;
+    use uuid::Uuid;
+
+    #[test]
+    fn add_user() {
+        let app = TestWorld::new();
+
+        let name = Name::new("user1").unwrap();
+        let email = Email::parse("user1@example.com").unwrap();
+
+        let created = app.user_commands().create(name.clone(), email.clone()).unwrap();
+        assert_eq!(created.id, UserId::new(Uuid::from_u128(1)));
+
+        let user = app.user_queries().get_by_name(&name).unwrap();
+        assert_eq!(user.id, created.id);
+        assert_eq!(user.name, name);
+        assert_eq!(user.email, email);
+        assert_eq!(
+            user.create_time,
+            DateTime::<Utc>::from_str("2018-08-20T10:00:00 +0900").unwrap()
+        );
+        assert_eq!(
+            user.update_time,
+            DateTime::<Utc>::from_str("2018-08-20T10:00:00 +0900").unwrap()
+        );
+    }
+
+    #[test]
+    fn update_and_delete_user() {
+        let app = TestWorld::new();
+
+        let name = Name::new("user1").unwrap();
+        let email = Email::parse("user1@example.com").unwrap();
+
+        let mut user = app.user_commands().create(name.clone(), email.clone()).unwrap();
+        assert!(app.user_commands().create(name.clone(), email.clone()).is_err());
+
+        user.email = Email::parse("user1@example.net").unwrap();
+        app.user_commands().update(user.clone()).unwrap();
+        assert_eq!(
+            app.user_queries().get(user.id.clone()).unwrap().email.as_str(),
+            "user1@example.net"
+        );
+        assert_eq!(app.user_queries().list().unwrap().len(), 1);
+
+        app.user_commands().delete(user.id.clone()).unwrap();
+        assert!(app.user_queries().get(user.id.clone()).is_err());
+        assert!(app.user_queries().get_by_name(&name).is_err());
+        assert!(app.user_queries().list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rename_user_updates_name_index() {
+        let app = TestWorld::new();
+
+        let mut user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        app.user_commands()
+            .create(Name::new("user2").unwrap(), Email::parse("user2@example.com").unwrap())
+            .unwrap();
+
+        user.name = Name::new("user2").unwrap();
+        assert!(app.user_commands().update(user.clone()).is_err());
+
+        user.name = Name::new("user3").unwrap();
+        app.user_commands().update(user.clone()).unwrap();
+        assert!(app.user_queries().get_by_name(&Name::new("user1").unwrap()).is_err());
+        assert_eq!(
+            app.user_queries().get_by_name(&Name::new("user3").unwrap()).unwrap().id,
+            user.id
+        );
+    }
+
+    /// 前段のゲートウェイで認証を済ませている設定で、`x-user-id` を信じるRealWorld
+    fn gateway_world() -> RealWorld {
+        let config = Config {
+            trust_actor_header: true,
+            ..Config::default()
+        };
+        RealWorld::with_config(config, CachePolicy::WriteThrough).unwrap()
+    }
+
+    fn cached_storage(
+        policy: CachePolicy,
+    ) -> CachingStorage<MemoryStorage<UserId, User>, MemoryCache<UserId, User>, UserId, User> {
+        CachingStorage::new(MemoryStorage::new(), MemoryCache::new(), policy)
+    }
+
+    fn test_user(name: &str) -> User {
+        User::builder()
+            .id(UserId::new(Uuid::new_v4()))
+            .name(Name::new(name).unwrap())
+            .email(Email::parse(&format!("{}@example.com", name)).unwrap())
+            .build(|| MockTime::new().now())
+            .unwrap()
+    }
+
+    #[test]
+    fn user_builder_requires_fields() {
+        let name = Name::new("user1").unwrap();
+        let email = Email::parse("user1@example.com").unwrap();
+
+        let missing_id = User::builder().name(name.clone()).email(email.clone()).build(|| MockTime::new().now());
+        assert_eq!(missing_id.unwrap_err(), ValidationError::MissingField("id"));
+
+        let missing_email = User::builder()
+            .id(UserId::new(Uuid::from_u128(1)))
+            .name(name.clone())
+            .build(|| MockTime::new().now());
+        assert_eq!(missing_email.unwrap_err(), ValidationError::MissingField("email"));
+
+        let user = User::builder()
+            .id(UserId::new(Uuid::from_u128(1)))
+            .name(name)
+            .email(email)
+            .build(|| MockTime::new().now())
+            .unwrap();
+        assert_eq!(user.create_time, MockTime::new().now());
+        assert_eq!(user.update_time, MockTime::new().now());
+    }
+
+    #[test]
+    fn cache_invalidated_on_delete() {
+        for &policy in &[CachePolicy::ReadThrough, CachePolicy::WriteThrough, CachePolicy::WriteBack] {
+            let storage = cached_storage(policy);
+            let user = test_user("user1");
+
+            storage.save(user.id.clone(), user.clone()).unwrap();
+            storage.read(user.id.clone()).unwrap();
+            assert!(storage.cache().get(&user.id).is_some(), "{:?}", policy);
+
+            storage.delete(user.id.clone()).unwrap();
+            assert!(storage.cache().get(&user.id).is_none(), "{:?}", policy);
+            assert!(storage.read(user.id.clone()).is_err(), "{:?}", policy);
+            assert!(storage.storage().read(user.id.clone()).is_err(), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn cache_write_policies() {
+        let user = test_user("user1");
+
+        let read_through = cached_storage(CachePolicy::ReadThrough);
+        read_through.save(user.id.clone(), user.clone()).unwrap();
+        assert!(read_through.cache().is_empty());
+        assert!(read_through.storage().read(user.id.clone()).is_ok());
+
+        let write_through = cached_storage(CachePolicy::WriteThrough);
+        write_through.save(user.id.clone(), user.clone()).unwrap();
+        assert_eq!(write_through.cache().len(), 1);
+        assert!(write_through.storage().read(user.id.clone()).is_ok());
+
+        let write_back = cached_storage(CachePolicy::WriteBack);
+        write_back.save(user.id.clone(), user.clone()).unwrap();
+        assert!(write_back.storage().read(user.id.clone()).is_err());
+        assert_eq!(write_back.read_all().unwrap().len(), 1);
+        write_back.flush().unwrap();
+        assert!(write_back.storage().read(user.id.clone()).is_ok());
+    }
+
+    #[test]
+    fn write_back_save_all_keeps_nothing_when_one_value_conflicts() {
+        let storage = cached_storage(CachePolicy::WriteBack);
+        let users: Vec<User> = (0..5).map(|i| test_user(&format!("user{}", i))).collect();
+        storage.save(users[3].id.clone(), users[3].clone()).unwrap();
+
+        // 4件目が溜めている値と同じバージョンなので、前の3件も溜めない
+        let values: Vec<(UserId, User)> = users.iter().map(|user| (user.id.clone(), user.clone())).collect();
+        let err = storage.save_all(&values).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&StorageError::Conflict { stored: 1, given: 1 }));
+        assert_eq!(storage.read_all().unwrap(), vec![users[3].clone()]);
+        assert!(storage.cache().get(&users[0].id).is_none());
+
+        // 同じキーが2回ある時は、後の値が前の値より新しければ全て溜まる
+        let mut values: Vec<(UserId, User)> = values.into_iter().filter(|(id, _)| *id != users[3].id).collect();
+        let mut renamed = users[0].clone();
+        renamed.name = Name::new("renamed").unwrap();
+        renamed.version += 1;
+        values.push((renamed.id.clone(), renamed.clone()));
+        storage.save_all(&values).unwrap();
+        assert_eq!(storage.read_all().unwrap().len(), 5);
+        assert_eq!(storage.read(renamed.id.clone()).unwrap(), renamed);
+        storage.flush().unwrap();
+        assert_eq!(storage.storage().read(renamed.id.clone()).unwrap(), renamed);
+    }
+
+    #[test]
+    fn cache_never_keeps_an_older_value_than_the_storage() {
+        for &policy in &[CachePolicy::ReadThrough, CachePolicy::WriteThrough] {
+            assert_cache_follows_concurrent_saves(policy);
+        }
+    }
+
+    /// 書き込みと読み込みが入り混じっても、最後にキャッシュにある値はストレージと同じ
+    fn assert_cache_follows_concurrent_saves(policy: CachePolicy) {
+        use std::thread;
+
+        let storage = cached_storage(policy);
+        let user = test_user("user1");
+        storage.save(user.id.clone(), user.clone()).unwrap();
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        let mut current = storage.storage().read(user.id.clone()).unwrap();
+                        current.version += 1;
+                        let _ = storage.save(user.id.clone(), current);
+                    }
+                });
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        storage.cache().invalidate(&user.id);
+                        storage.read(user.id.clone()).unwrap();
+                    }
+                });
+            }
+        });
+        let stored = storage.storage().read(user.id.clone()).unwrap();
+        let cached = storage.cache().get(&user.id).map_or(stored.version, |cached| cached.version);
+        assert_eq!(cached, stored.version, "{:?}", policy);
+        assert_eq!(storage.read(user.id.clone()).unwrap(), stored, "{:?}", policy);
+    }
+
+    #[test]
+    fn remote_storages_speak_rest_and_give_up_on_slow_databases() {
+        use component::postgres::PostgresUserStorage;
+        use component::rest::RestStorage;
+        use std::net::TcpListener;
+        use std::time::{Duration, Instant};
+
+        // `{base}/{key}` を読み書きし、404は「無い」として扱う
+        let (alice, bob) = (test_user("alice"), test_user("bob"));
+        let url = |user: &User| format!("https://users.example.com/users/{}", user.id.as_uuid());
+        let client = StubHttpClient::new()
+            .respond(&url(&alice), serde_json::to_value(&alice).unwrap())
+            .respond("https://users.example.com/users", json!([alice]));
+        let storage = RestStorage::new(&client, "https://users.example.com/users/");
+        assert_eq!(storage.read(alice.id.clone()).unwrap(), alice);
+        assert_eq!(storage.read_all().unwrap(), vec![alice.clone()]);
+        storage.save(bob.id.clone(), bob.clone()).unwrap();
+        assert_eq!(client.requests().pop(), Some((url(&bob), Some(serde_json::to_value(&bob).unwrap()))));
+        let not_found = StorageError::not_found(&bob.id);
+        assert_eq!(storage.read(bob.id.clone()).unwrap_err().downcast_ref(), Some(&not_found));
+        assert_eq!(storage.delete(bob.id.clone()).unwrap_err().downcast_ref(), Some(&not_found));
+        storage.delete(alice.id.clone()).unwrap();
+
+        // 大量の保存は塊ごとに1回ずつPOSTし、全ての値がどれか1つの塊で送られる
+        let users: Vec<User> = (0..10).map(|i| test_user(&format!("user{}", i))).collect();
+        let values: Vec<(UserId, User)> = users.iter().map(|user| (user.id.clone(), user.clone())).collect();
+        let sent = client.requests().len();
+        storage.save_all_concurrent(&values, 3).unwrap();
+        let posts: Vec<Vec<User>> = client.requests()[sent..]
+            .iter()
+            .map(|(url, body)| {
+                assert_eq!(url, "https://users.example.com/users");
+                serde_json::from_value(body.clone().unwrap()).unwrap()
+            })
+            .collect();
+        assert_eq!(posts.len(), 3);
+        assert!(posts.iter().all(|chunk| chunk.len() >= 3));
+        let mut posted: Vec<User> = posts.into_iter().flatten().collect();
+        posted.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut sorted = users.clone();
+        sorted.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(posted, sorted);
+
+        // 接続は受け付けても答えないDBは、設定した時間で諦める
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = Config::default()
+            .override_with(|key| match key {
+                "LAYERED_REMOTE_TIMEOUT_MS" => Some("200".to_string()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(config.remote_timeout(), Duration::from_millis(200));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _entered = runtime.enter();
+        let url = format!("postgres://layered@{}/layered", listener.local_addr().unwrap());
+        let postgres = PostgresUserStorage::connect_lazy(&url, config.remote_timeout()).unwrap();
+        let started = Instant::now();
+        let read = ::component::nonblocking::AsyncStorageComponent::read(&postgres, alice.id.clone());
+        assert!(runtime.block_on(read).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn with_timeout_gives_up_by_the_injected_clock() {
+        use component::timeout::{Timeout, TimeoutError, BACKLOG, WORKERS};
+        use std::sync::mpsc::{self, Receiver};
+
+        /// 合図が来るまで答えないメールサーバー・ストレージ・HTTPサーバー
+        struct Stalled(Mutex<Receiver<()>>);
+
+        impl Stalled {
+            fn wait(&self) -> Result<(), Error> {
+                Ok(self.0.lock().unwrap().recv()?)
+            }
+        }
+
+        impl EmailSenderComponent for Stalled {
+            fn send(&self, _: &Mail) -> Result<(), Error> {
+                self.wait()
+            }
+        }
+
+        impl StorageComponent<UserId, User> for Stalled {
+            fn read(&self, key: UserId) -> Result<User, Error> {
+                self.wait()?;
+                Err(StorageError::not_found(&key).into())
+            }
+
+            fn save(&self, _: UserId, _: User) -> Result<(), Error> {
+                self.wait()
+            }
+
+            fn delete(&self, _: UserId) -> Result<(), Error> {
+                self.wait()
+            }
+
+            fn read_all(&self) -> Result<Vec<User>, Error> {
+                self.wait().map(|_| Vec::new())
+            }
+
+            fn save_all(&self, _: &[(UserId, User)]) -> Result<(), Error> {
+                self.wait()
+            }
+        }
+
+        impl HttpClientComponent for Stalled {
+            fn get_json(&self, _: &str) -> Result<Value, Error> {
+                self.wait().map(|_| Value::Null)
+            }
+
+            fn find_json(&self, _: &str) -> Result<Option<Value>, Error> {
+                self.wait().map(|_| None)
+            }
+
+            fn post_json(&self, _: &str, _: &Value) -> Result<Value, Error> {
+                self.wait().map(|_| Value::Null)
+            }
+
+            fn post_json_with_headers(&self, _: &str, _: &Value, _: &[(&str, &str)]) -> Result<Value, Error> {
+                self.wait().map(|_| Value::Null)
+            }
+
+            fn put_json(&self, _: &str, _: &Value) -> Result<Value, Error> {
+                self.wait().map(|_| Value::Null)
+            }
+
+            fn delete(&self, _: &str) -> Result<bool, Error> {
+                self.wait().map(|_| true)
+            }
+        }
+
+        let stall = || {
+            let (release, stalled) = mpsc::channel();
+            (release, Stalled(Mutex::new(stalled)))
+        };
+        let timeout = |operation: &str, limit: i64| TimeoutError {
+            operation: operation.to_string(),
+            limit: Duration::milliseconds(limit),
+        };
+
+        // 待っている間はテスト用の時計を進めるので、期限ちょうどで諦める
+        let clock = MockTime::new();
+        let (release, stalled) = stall();
+        let sender = Timeout::with_clock(stalled, Duration::milliseconds(50), clock.clone());
+        let mail = Mail {
+            to: Email::parse("user1@example.com").unwrap(),
+            subject: "hello".to_string(),
+            body: String::new(),
+        };
+        let started = clock.instant();
+        let err = sender.send(&mail).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&timeout("mail.send", 50)));
+        assert_eq!(clock.elapsed(started), Duration::milliseconds(50));
+
+        // 諦めた呼び出しは裏で終わり、期限の内に終わった呼び出しは結果をそのまま返す
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        sender.send(&mail).unwrap();
+        let storage = Timeout::with_clock(MemoryStorage::new(), Duration::seconds(1), clock.clone());
+        let user = test_user("user1");
+        storage.save(user.id.clone(), user.clone()).unwrap();
+        assert_eq!(storage.read(user.id.clone()).unwrap(), user);
+        storage.delete(user.id.clone()).unwrap();
+        let not_found = StorageError::not_found(&user.id);
+        assert_eq!(storage.read(user.id.clone()).unwrap_err().downcast_ref(), Some(&not_found));
+
+        // ストレージやHTTPクライアントが答えない時も同じく諦める
+        let (release, stalled) = stall();
+        let storage = Timeout::with_clock(stalled, Duration::milliseconds(30), clock.clone());
+        let err = StorageComponent::<UserId, User>::read(&storage, user.id.clone()).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&timeout("storage.read", 30)));
+        let err = StorageComponent::<UserId, User>::save(&storage, user.id.clone(), user.clone()).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&timeout("storage.save", 30)));
+        drop(release);
+        let (release, stalled) = stall();
+        let client = Timeout::with_clock(stalled, Duration::milliseconds(30), clock.clone());
+        let err = client.get_json("https://example.com/").unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&timeout("http.get", 30)));
+        let err = client.post_json("https://example.com/", &json!({})).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&timeout("http.post", 30)));
+
+        // 諦めた呼び出しが残っていてもスレッドは増やさず、待ちきれない呼び出しはすぐにエラーにする
+        for _ in 2..WORKERS + BACKLOG {
+            assert!(client.get_json("https://example.com/").unwrap_err().downcast_ref::<TimeoutError>().is_some());
+        }
+        let started = clock.instant();
+        let busy = client.get_json("https://example.com/").unwrap_err();
+        assert!(busy.to_string().starts_with("http.get is busy"), "{}", busy);
+        assert_eq!(clock.elapsed(started), Duration::zero());
+        for _ in 0..=WORKERS + BACKLOG {
+            release.send(()).unwrap();
+        }
+        // 残っていた呼び出しが終わればまた呼べる
+        let answered = (0..100).any(|_| {
+            ::std::thread::sleep(::std::time::Duration::from_millis(10));
+            client.get_json("https://example.com/").is_ok()
+        });
+        assert!(answered);
+    }
+
+    #[test]
+    fn real_world_gives_up_on_slow_http_servers() {
+        use component::storage::HaveUserStorageComponent;
+        use std::net::TcpListener;
+        use std::time::Instant;
+
+        // 接続は受け付けても答えないユーザーのサービスは、設定した時間で諦める
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let slow = |key: &str| match key {
+            "LAYERED_REMOTE_TIMEOUT_MS" => Some("200".to_string()),
+            "LAYERED_REMOTE_STORAGE_URL" => Some(url.clone()),
+            _ => None,
+        };
+        let config = Config::default().override_with(slow).unwrap();
+        let started = Instant::now();
+        assert!(RealWorld::with_config(config, CachePolicy::WriteThrough).is_err());
+        assert!(started.elapsed() < ::std::time::Duration::from_secs(5));
+
+        // ユーザーのストレージも同じ時間で諦めるように包んである
+        let config = Config::default()
+            .override_with(|key| match key {
+                "LAYERED_REMOTE_TIMEOUT_MS" => Some("200".to_string()),
+                _ => None,
+            })
+            .unwrap();
+        let world = RealWorld::with_config(config, CachePolicy::WriteThrough).unwrap();
+        let user = test_user("user1");
+        world.user_storage_component().save(user.id.clone(), user.clone()).unwrap();
+        assert_eq!(world.user_storage_component().read(user.id.clone()).unwrap(), user);
+    }
+
+    #[test]
+    fn real_world_reads_the_storage_when_the_shared_cache_is_down() {
+        // 繋がらないRedisのキャッシュは、キャッシュに無いものとして扱う
+        let url = "redis://127.0.0.1:1/";
+        let config = Config::default()
+            .override_with(|key| Some(url.to_string()).filter(|_| key == "LAYERED_CACHE_URL"))
+            .unwrap();
+        assert_eq!(config.cache_url(), Some(url));
+        let world = RealWorld::with_config(config, CachePolicy::WriteThrough).unwrap();
+        let user = world
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        assert_eq!(world.user_queries().get(user.id.clone()).unwrap().name, user.name);
+    }
+
+    #[test]
+    fn unit_of_work_rolls_back_on_failure() {
+        let app = TestWorld::new();
+        let existing = test_user("user1");
+        app.user_commands().insert(existing.clone()).unwrap();
+
+        let mut updated = existing.clone();
+        updated.email = Email::parse("user1@example.net").unwrap();
+
+        let mut uow = UnitOfWork::new();
+        uow.register_new(test_user("user2"));
+        uow.register_dirty(updated);
+        uow.register_new(existing.clone());
+        assert!(uow.commit(app.user_commands()).is_err());
+
+        let users = app.user_queries().list().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].email, existing.email);
+
+        let mut uow = UnitOfWork::new();
+        uow.register_new(test_user("user2"));
+        uow.register_deleted(existing.id.clone());
+        uow.commit(app.user_commands()).unwrap();
+
+        let users = app.user_queries().list().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].name.as_str(), "user2");
+    }
+
+    #[test]
+    fn value_object_macro_names_the_field_in_every_error() {
+        // max_len を持つ値オブジェクトは、空の時も長すぎる時も同じフィールド名を返す
+        let cases = [
+            (Name::new(" ").unwrap_err(), "name"),
+            (Name::new(&"a".repeat(65)).unwrap_err(), "name"),
+            (GroupName::new("").unwrap_err(), "group_name"),
+            (GroupName::new(&"a".repeat(65)).unwrap_err(), "group_name"),
+        ];
+        for (error, field) in cases.iter() {
+            assert_eq!(error.field(), Some(*field));
+            assert!(error.to_string().starts_with(field), "{}", error);
+        }
+        let error = PresentationError::from(GroupName::new(&"a".repeat(65)).unwrap_err());
+        assert_eq!(error.fields["group_name"], "group_name must be at most 64 characters");
+
+        // FromStrとDeserializeもコンストラクタと同じ検証を通る
+        assert_eq!("admins".parse::<GroupName>().unwrap().to_string(), "admins");
+        assert_eq!(
+            "a".repeat(65).parse::<GroupName>(),
+            Err(ValidationError::TooLong { field: "group_name", max: 64 })
+        );
+        assert!(serde_json::from_str::<Name>(&format!("\"{}\"", "a".repeat(65))).is_err());
+        let email: Email = serde_json::from_str("\" User@Example.com\"").unwrap();
+        assert_eq!(email.as_str(), "user@example.com");
+        assert_eq!(serde_json::to_string(&email).unwrap(), "\"user@example.com\"");
+        assert_eq!(
+            "03-1234-5678".parse::<PhoneNumber>().unwrap_err().field(),
+            Some("phone_number")
+        );
+    }
+
+    #[test]
+    fn validate_name_and_email() {
+        assert_eq!(Name::new(""), Err(ValidationError::Empty("name")));
+        assert_eq!(Name::new("  "), Err(ValidationError::Empty("name")));
+        assert_eq!(Name::new("user1").unwrap().as_str(), "user1");
+        assert_eq!(
+            Name::new(&"a".repeat(65)),
+            Err(ValidationError::TooLong { field: "name", max: 64 })
+        );
+        assert!(Name::new(&"あ".repeat(64)).is_ok());
+
+        // 同じマクロで作った他の値オブジェクトは、自分のフィールド名でエラーを返す
+        let empty = GroupName::new(" ").unwrap_err();
+        assert_eq!(empty, ValidationError::Empty("group_name"));
+        assert_eq!((empty.field(), empty.to_string().as_str()), (Some("group_name"), "group_name must not be empty"));
+        assert_eq!(
+            GroupName::new(&"a".repeat(65)),
+            Err(ValidationError::TooLong { field: "group_name", max: 64 })
+        );
+
+        let invalids = [
+            "",
+            "user1",
+            "@example.com",
+            "user1@",
+            "user1@example",
+            "a@b@example.com",
+            "user 1@example.com",
+            "user1@example..com",
+        ];
+        for invalid in &invalids {
+            assert_eq!(
+                Email::parse(invalid),
+                Err(ValidationError::InvalidEmail(invalid.to_string()))
+            );
+        }
+        assert_eq!(Email::parse("user1@example.com").unwrap().as_str(), "user1@example.com");
+    }
+
+    #[test]
+    fn set_and_verify_password() {
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+
+        assert!(app.credential_repository().verify_password(user.id.clone(), "secret").is_err());
+
+        app.credential_repository().set_password(user.id.clone(), "secret").unwrap();
+        assert!(app.credential_repository().verify_password(user.id.clone(), "secret").unwrap());
+        assert!(!app.credential_repository().verify_password(user.id.clone(), "wrong").unwrap());
+
+        app.credential_repository().set_password(user.id.clone(), "changed").unwrap();
+        assert!(!app.credential_repository().verify_password(user.id.clone(), "secret").unwrap());
+        assert!(app.credential_repository().verify_password(user.id.clone(), "changed").unwrap());
+
+        let credentials = app.credential_repository().get(user.id.clone()).unwrap();
+        assert_ne!(credentials.password_hash.as_str(), "changed");
+    }
+
+    #[test]
+    fn change_role_bumps_update_time() {
+        let app = TestWorld::new();
+        let past = DateTime::<Utc>::from_str("2018-01-01T00:00:00 +0900").unwrap();
+        let user = User::builder()
+            .id(UserId::new(Uuid::from_u128(1)))
+            .name(Name::new("user1").unwrap())
+            .email(Email::parse("user1@example.com").unwrap())
+            .build(|| past)
+            .unwrap();
+        assert_eq!(user.role, Role::Member);
+        assert!(!user.can(Permission::ManageUsers));
+        app.user_commands().insert(user.clone()).unwrap();
+
+        let changed = app.user_commands().change_role(user.id.clone(), Role::Admin).unwrap();
+        assert_eq!(changed.role, Role::Admin);
+        assert!(changed.can(Permission::ManageUsers));
+        assert_eq!(changed.create_time, past);
+        assert_eq!(changed.update_time, MockTime::new().now());
+
+        let stored = app.user_queries().get(user.id.clone()).unwrap();
+        assert_eq!(stored.role, Role::Admin);
+        assert_eq!(stored.update_time, MockTime::new().now());
+    }
+
+    #[test]
+    fn add_and_remove_group_members() {
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        let name = GroupName::new("group1").unwrap();
+
+        let group = app.group_repository().create_group(name.clone()).unwrap();
+        assert!(group.members.is_empty());
+        assert!(app.group_repository().create_group(name.clone()).is_err());
+
+        let missing = UserId::new(Uuid::from_u128(999));
+        assert!(app.group_repository().add_member(name.clone(), missing.clone()).is_err());
+
+        app.group_repository().add_member(name.clone(), user.id.clone()).unwrap();
+        let group = app.group_repository().add_member(name.clone(), user.id.clone()).unwrap();
+        assert_eq!(group.members.len(), 1);
+        assert!(app.group_repository().get(name.clone()).unwrap().has_member(&user.id));
+
+        assert!(app.group_repository().remove_member(name.clone(), missing).is_err());
+        app.group_repository().remove_member(name.clone(), user.id.clone()).unwrap();
+        assert!(app.group_repository().get(name.clone()).unwrap().members.is_empty());
+    }
+
+    #[test]
+    fn profile_is_separate_from_user() {
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+
+        assert!(app.profile_repository().create_profile(UserId::new(Uuid::from_u128(999))).is_err());
+        let profile = app.profile_repository().create_profile(user.id.clone()).unwrap();
+        assert_eq!(profile.bio, "");
+        assert!(app.profile_repository().create_profile(user.id.clone()).is_err());
+
+        app.profile_repository()
+            .edit_profile(user.id.clone(), |profile| {
+                profile.bio = "hello".to_string();
+                profile.avatar_url = Some("https://example.com/avatar.png".to_string());
+            })
+            .unwrap();
+
+        let profile = app.profile_repository().get(user.id.clone()).unwrap();
+        assert_eq!(profile.bio, "hello");
+        assert_eq!(profile.avatar_url.as_deref(), Some("https://example.com/avatar.png"));
+        // プロフィールを書き換えてもアカウント側は変わらない
+        assert_eq!(app.user_queries().get(user.id.clone()).unwrap().version, 1);
+    }
+
+    #[test]
+    fn create_validate_and_revoke_session() {
+        let app = TestWorld::new();
+        let user_id = UserId::new(Uuid::from_u128(1));
+
+        let session = app
+            .session_repository()
+            .create_session(user_id.clone(), Duration::hours(1))
+            .unwrap();
+        assert_eq!(session.expires_at, MockTime::new().now() + Duration::hours(1));
+        assert_eq!(
+            app.session_repository().validate_session(session.id.clone()).unwrap().user_id,
+            user_id
+        );
+
+        let expired = Session {
+            id: SessionId::new(Uuid::from_u128(100)),
+            user_id: user_id.clone(),
+            create_time: MockTime::new().now() - Duration::hours(2),
+            expires_at: MockTime::new().now() - Duration::hours(1),
+        };
+        app.session_repository().insert(expired.clone()).unwrap();
+        assert!(app.session_repository().validate_session(expired.id.clone()).is_err());
+
+        app.session_repository().revoke_session(session.id.clone()).unwrap();
+        assert!(app.session_repository().validate_session(session.id.clone()).is_err());
+    }
+
+    #[test]
+    fn issue_list_and_revoke_api_tokens() {
+        let app = TestWorld::new();
+        let owner = UserId::new(Uuid::from_u128(1));
+
+        let (issued, plain) = app
+            .api_token_repository()
+            .issue_token(owner.clone(), &[Scope::ReadUsers], Some(Duration::days(30)))
+            .unwrap();
+        let (expired, expired_plain) = app
+            .api_token_repository()
+            .issue_token(owner.clone(), &[Scope::ReadUsers, Scope::WriteUsers], Some(Duration::zero()))
+            .unwrap();
+        app.api_token_repository()
+            .issue_token(UserId::new(Uuid::from_u128(2)), &[Scope::Admin], None)
+            .unwrap();
+
+        assert_eq!(app.api_token_repository().list_tokens(&owner).unwrap().len(), 2);
+
+        let authenticated = app.api_token_repository().authenticate_token(&plain).unwrap();
+        assert_eq!(authenticated.owner, owner);
+        assert!(authenticated.scopes.contains(&Scope::ReadUsers));
+        assert!(!authenticated.scopes.contains(&Scope::WriteUsers));
+        assert!(app.api_token_repository().authenticate_token(&expired_plain).is_err());
+        assert!(app.api_token_repository().authenticate_token("garbage").is_err());
+        let forged = format!("{}.wrong", issued.id.as_uuid().simple());
+        assert!(app.api_token_repository().authenticate_token(&forged).is_err());
+
+        app.api_token_repository().revoke_token(issued.id.clone()).unwrap();
+        app.api_token_repository().revoke_token(expired.id.clone()).unwrap();
+        assert!(app.api_token_repository().authenticate_token(&plain).is_err());
+        assert!(app.api_token_repository().list_tokens(&owner).unwrap().is_empty());
+    }
+
+    #[test]
+    fn suspended_user_cannot_be_renamed() {
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        assert_eq!(user.status, UserStatus::Active);
+
+        let suspended = app.user_commands().suspend(user.id.clone()).unwrap();
+        assert_eq!(suspended.status, UserStatus::Suspended);
+        assert!(app.user_commands().suspend(user.id.clone()).is_err());
+        assert!(app.rename_user(user.id.clone(), Name::new("user2").unwrap()).is_err());
+
+        app.user_commands().reactivate(user.id.clone()).unwrap();
+        assert!(app.user_commands().reactivate(user.id.clone()).is_err());
+        let renamed = app.rename_user(user.id.clone(), Name::new("user2").unwrap()).unwrap();
+        assert_eq!(renamed.name.as_str(), "user2");
+        assert!(app.user_queries().get_by_name(&Name::new("user2").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn validate_address() {
+        let address = Address::new("jp", " Tokyo ", "100-0001").unwrap();
+        assert_eq!(address.country().as_str(), "JP");
+        assert_eq!(address.region(), "Tokyo");
+        assert_eq!(address.postal_code().as_str(), "100-0001");
+
+        assert_eq!(Address::new("JPN", "Tokyo", "100-0001"), Err(ValidationError::InvalidAddress("country")));
+        assert_eq!(Address::new("JP", " ", "100-0001"), Err(ValidationError::InvalidAddress("region")));
+        assert_eq!(Address::new("JP", "Tokyo", "100_0001"), Err(ValidationError::InvalidAddress("postal_code")));
+        assert_eq!(Address::new("JP", "Tokyo", ""), Err(ValidationError::InvalidAddress("postal_code")));
+
+        let app = TestWorld::new();
+        let mut user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        assert_eq!(user.address, None);
+        user.address = Some(address.clone());
+        app.user_commands().update(user.clone()).unwrap();
+        assert_eq!(app.user_queries().get(user.id).unwrap().address, Some(address));
+    }
+
+    #[test]
+    fn parse_phone_number() {
+        assert_eq!(PhoneNumber::parse("+81 3-1234-5678").unwrap().as_str(), "+81312345678");
+        assert_eq!(PhoneNumber::parse("001 (415) 555.2671").unwrap().as_str(), "+14155552671");
+        for invalid in &["03-1234-5678", "+0 123 456 789", "+81 3 1234", "+1234567890123456", "+81-3-abcd-5678"] {
+            assert_eq!(
+                PhoneNumber::parse(invalid),
+                Err(ValidationError::InvalidPhoneNumber(invalid.to_string()))
+            );
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn phone_number_ignores_separators(
+            digits in "[1-9][0-9]{7,14}",
+            separators in proptest::collection::vec("[ .()-]?", 15),
+        ) {
+            let formatted: String = digits
+                .chars()
+                .zip(separators.iter())
+                .map(|(d, sep)| format!("{}{}", d, sep))
+                .collect();
+            let parsed = PhoneNumber::parse(&format!("+{}", formatted)).unwrap();
+            prop_assert_eq!(parsed.as_str(), format!("+{}", digits));
+        }
+
+        #[test]
+        fn phone_number_parse_is_idempotent(input in "\\PC{0,20}") {
+            if let Ok(parsed) = PhoneNumber::parse(&input) {
+                prop_assert_eq!(PhoneNumber::parse(parsed.as_str()), Ok(parsed.clone()));
+            }
+        }
+
+        #[test]
+        fn phone_number_rejects_letters(prefix in "\\+[0-9]{4,8}", letter in "[a-zA-Z]", suffix in "[0-9]{4,8}") {
+            let input = format!("{}{}{}", prefix, letter, suffix);
+            prop_assert!(PhoneNumber::parse(&input).is_err());
+        }
+    }
+
+    #[test]
+    fn display_and_parse_name_and_email() {
+        let name: Name = "user1".parse().unwrap();
+        assert_eq!(name.to_string(), "user1");
+        assert_eq!("".parse::<Name>(), Err(ValidationError::Empty("name")));
+
+        let email: Email = "user1@example.com".parse().unwrap();
+        assert_eq!(format!("<{}>", email), "<user1@example.com>");
+        assert_eq!(
+            "user1".parse::<Email>(),
+            Err(ValidationError::InvalidEmail("user1".to_string()))
+        );
+    }
+
+    #[test]
+    fn email_is_case_insensitive() {
+        let email = Email::parse("  User1@Example.COM ").unwrap();
+        assert_eq!(email.as_str(), "user1@example.com");
+        assert_eq!(email, Email::parse("user1@example.com").unwrap());
+
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("User1@Example.com").unwrap())
+            .unwrap();
+        assert!(app
+            .user_commands()
+            .create(Name::new("user2").unwrap(), Email::parse("user1@example.com").unwrap())
+            .is_err());
+        assert_eq!(
+            app.user_queries().get_by_email(&Email::parse("USER1@EXAMPLE.COM").unwrap()).unwrap().id,
+            user.id
+        );
+    }
+
+    #[test]
+    fn times_are_stored_in_utc() {
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        assert_eq!(user.create_time.to_rfc3339(), "2018-08-20T01:00:00+00:00");
+
+        let jst = FixedOffset::east_opt(9 * 3600).unwrap();
+        assert_eq!(to_timezone(&user.create_time, &jst).to_rfc3339(), "2018-08-20T10:00:00+09:00");
+        assert_eq!(app.time_component().now_in(&jst).to_rfc3339(), "2018-08-20T10:00:00+09:00");
+
+        // 表では見る人のタイムゾーンで見せる
+        let row = |tz: &FixedOffset| {
+            let table = TablePresenter::new(app.time_component(), tz).present(UserDto::from(&user));
+            table.rows[0][5].clone()
+        };
+        assert_eq!(row(&jst), "2018-08-20 10:00");
+        assert_eq!(row(&FixedOffset::west_opt(5 * 3600).unwrap()), "2018-08-19 20:00");
+    }
+
+    #[test]
+    fn concurrent_update_conflicts() {
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        assert_eq!(user.version, 1);
+
+        let mut writer_a = app.user_queries().get(user.id.clone()).unwrap();
+        let mut writer_b = app.user_queries().get(user.id.clone()).unwrap();
+
+        writer_a.email = Email::parse("a@example.com").unwrap();
+        app.user_commands().update(writer_a).unwrap();
+        assert_eq!(app.user_queries().get(user.id.clone()).unwrap().version, 2);
+
+        writer_b.email = Email::parse("b@example.com").unwrap();
+        let conflict = app.user_commands().update(writer_b).unwrap_err();
+        assert_eq!(conflict.to_string(), "version conflict: stored 2, given 2");
+        match conflict {
+            DomainError::Conflict { stored: 2, given: 2 } => {}
+            e => panic!("{:?}", e),
+        }
+        assert_eq!(app.user_queries().get(user.id.clone()).unwrap().email.as_str(), "a@example.com");
+    }
+
+    #[test]
+    fn repository_errors_can_be_matched_by_kind() {
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+
+        let missing = UserId::new(Uuid::from_u128(99));
+        match app.user_queries().get(missing.clone()).unwrap_err() {
+            DomainError::NotFound { key } => assert_eq!(key, format!("{:?}", missing)),
+            e => panic!("{:?}", e),
+        }
+        match app.user_commands().insert(user.clone()).unwrap_err() {
+            DomainError::AlreadyExists { field: "id", .. } => {}
+            e => panic!("{:?}", e),
+        }
+        let taken = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user2@example.com").unwrap())
+            .unwrap_err();
+        match taken {
+            DomainError::AlreadyExists { field: "name", value } => assert_eq!(value, format!("{:?}", user.name)),
+            e => panic!("{:?}", e),
+        }
+        match DomainError::from(Error::from(ValidationError::Empty("name"))) {
+            DomainError::Validation(ValidationError::Empty("name")) => {}
+            e => panic!("{:?}", e),
+        }
+        let e = PresentationError::from(DomainError::from(StorageError::taken("email", &user.email)));
+        assert_eq!((e.kind, e.fields.get("email").cloned()), (ErrorKind::Conflict, Some(e.message.clone())));
+        let e = PresentationError::from(DomainError::Conflict { stored: 2, given: 1 });
+        assert_eq!((e.kind, e.status_code()), (ErrorKind::Conflict, 409));
+        // 中身の文言は1回だけ出す
+        let e = DomainError::from(format_err!("disk on fire"));
+        assert_eq!(e.to_string(), "disk on fire");
+        let e = PresentationError::from(e);
+        assert_eq!(e.kind, ErrorKind::Internal);
+    }
+
+    #[test]
+    fn user_records_are_versioned() {
+        let codec = UserRecordCodec;
+        let legacy = r#"{"id":"00000000-0000-0000-0000-000000000001","name":"user1","email":"user1@example.com","role":"Admin","create_time":"2018-08-20T01:00:00Z","update_time":"2018-08-20T01:00:00Z"}"#;
+        let user = codec.decode(legacy).unwrap();
+        assert_eq!(user.id, UserId::new(Uuid::from_u128(1)));
+        assert_eq!(user.role, Role::Admin);
+        assert_eq!(user.status, UserStatus::Active);
+        assert_eq!(user.version, 1);
+
+        let record = codec.encode(&user).unwrap();
+        assert!(record.starts_with(r#"{"v":2,"user":{"#));
+        assert_eq!(codec.decode(&record).unwrap().email, user.email);
+
+        assert!(codec.decode(r#"{"v":99,"user":{}}"#).is_err());
+        assert!(codec.decode(&legacy.replace("user1@example.com", "not an email")).is_err());
+    }
+
+    #[test]
+    fn file_storage_keeps_users_across_reopen() {
+        let path = ::std::env::temp_dir().join(format!("layered-{}.jsonl", Uuid::new_v4()));
+        let user = test_user("user1");
+        {
+            let storage = FileStorage::open(&path, UserRecordCodec).unwrap();
+            storage.save(user.id.clone(), user.clone()).unwrap();
+        }
+        let storage: FileStorage<UserId, User, _> = FileStorage::open(&path, UserRecordCodec).unwrap();
+        assert_eq!(storage.read(user.id.clone()).unwrap().name, user.name);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn users_are_equal_by_identity() {
+        let user = test_user("user1");
+        let mut renamed = user.clone();
+        renamed.name = Name::new("user2").unwrap();
+
+        assert_eq!(user, renamed);
+        assert!(user.same_state_as(&user.clone()));
+        assert!(!user.same_state_as(&renamed));
+        assert_ne!(user, test_user("user1"));
+    }
+
+    #[test]
+    fn user_mutations_are_logged() {
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        app.user_commands().suspend(user.id.clone()).unwrap();
+        assert!(app.user_commands().suspend(user.id.clone()).is_err());
+
+        let records = app.logging_component().records();
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|&(level, _)| level == Level::Info));
+        assert_eq!(records[0].1, format!("user created: {:?}", user.id));
+        assert_eq!(records[1].1, format!("user suspended: {:?}", user.id));
+    }
+
+    #[test]
+    fn config_from_toml_and_env() {
+        let source = "page_size = 50\nfeatures = [\"search\"]\ncache_policy = \"write-back\"";
+        let config = Config::from_toml(source).unwrap();
+        assert_eq!(config.page_size(), 50);
+        assert_eq!(config.cache_policy(), CachePolicy::WriteBack);
+        assert!(config.features.contains("search"));
+        assert!(config.storage_path().is_none());
+        assert!(Config::from_toml("unknown = 1").is_err());
+
+        let config = config
+            .override_with(|key| match key {
+                "LAYERED_STORAGE_PATH" => Some("users.jsonl".to_string()),
+                "LAYERED_FEATURES" => Some("invite, export".to_string()),
+                "LAYERED_CACHE_POLICY" => Some("read-through".to_string()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(config.page_size(), 50);
+        assert_eq!(config.cache_policy(), CachePolicy::ReadThrough);
+        assert_eq!(config.storage_path(), Some(Path::new("users.jsonl")));
+        assert!(!config.features.contains("search"));
+        assert!(config.features.contains("invite") && config.features.contains("export"));
+
+        assert!(Config::default().override_with(|_| Some("0".to_string())).is_err());
+    }
+
+    #[test]
+    fn use_case_spans_enclose_repository_spans() {
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        let id = format!("{:?}", user.id);
+        assert_eq!(
+            app.tracing_component().events(),
+            vec![
+                "start users.create name=user1".to_string(),
+                format!("start repository.insert entity=User id={}", id),
+                "end repository.insert".to_string(),
+                "end users.create".to_string(),
+            ]
+        );
+
+        assert!(app.rename_user(UserId::new(Uuid::from_u128(99)), Name::new("user2").unwrap()).is_err());
+        let events = app.tracing_component().events();
+        assert_eq!(
+            events[4..],
+            [
+                "start usecase.rename_user name=user2".to_string(),
+                format!("start repository.get entity=User id={:?}", UserId::new(Uuid::from_u128(99))),
+                "end repository.get".to_string(),
+                "end usecase.rename_user".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn health_report_lists_every_check() {
+        let report = TestWorld::new().health();
+        assert!(report.healthy);
+        let names: Vec<&str> = report.checks.iter().map(|check| check.name).collect();
+        assert_eq!(names, vec!["user_storage", "clock", "queue"]);
+        assert!(RealWorld::with_cache_policy(CachePolicy::WriteThrough).health().healthy);
+
+        // HTTPでは認証しなくても確かめられる
+        let app = http::router(Arc::new(RealWorld::with_cache_policy(CachePolicy::WriteThrough))).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let request = Request::builder().uri("/health").body(Body::empty()).unwrap();
+        let response = runtime.block_on(app.oneshot(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["healthy"], json!(true));
+        assert_eq!(body["checks"].as_array().unwrap().len(), 4);
+
+        let report = HealthReport::new()
+            .check("clock", Ok(()))
+            .check("queue", Err(format_err!("connection refused")));
+        assert!(!report.healthy);
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            json!({
+                "healthy": false,
+                "checks": [
+                    { "name": "clock", "healthy": true, "error": null },
+                    { "name": "queue", "healthy": false, "error": "connection refused" },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn registered_validation_rules_are_enforced() {
+        let rules = Rules::new()
+            .with("corporate_email", validation::email_domain_in(vec!["Example.com".to_string()]))
+            .with("no_admin_name", Box::new(|user: &User| !user.name.as_str().contains("admin")));
+        let app = TestWorld::new().with_validation(rules);
+
+        let error = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@gmail.com").unwrap())
+            .unwrap_err();
+        match error {
+            DomainError::Validation(e) => assert_eq!(e, ValidationError::RuleViolated("corporate_email".to_string())),
+            e => panic!("unexpected error: {}", e),
+        }
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        let error = app
+            .user_commands()
+            .rename(user.id.clone(), Name::new("admin").unwrap())
+            .unwrap_err();
+        assert_eq!(error.to_string(), "violates validation rule: no_admin_name");
+        assert_eq!(app.user_queries().get(user.id).unwrap().name, user.name);
+
+        let config = Config::default()
+            .override_with(|key| match key {
+                "LAYERED_ALLOWED_EMAIL_DOMAINS" => Some("example.com, example.org".to_string()),
+                _ => None,
+            })
+            .unwrap();
+        let app = RealWorld::with_config(config, CachePolicy::WriteThrough).unwrap();
+        assert!(app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.org").unwrap())
+            .is_ok());
+        assert!(app
+            .user_commands()
+            .create(Name::new("user2").unwrap(), Email::parse("user2@example.net").unwrap())
+            .is_err());
+    }
+
+    /// `DATABASE_URL` のPostgreSQLが要るので、`cargo test -- --ignored` で動かす
+    #[test]
+    #[ignore]
+    fn real_world_saves_users_to_postgres() {
+        use component::storage::HaveUserStorageComponent;
+
+        let url = ::std::env::var("DATABASE_URL").expect("DATABASE_URL is required");
+        let config = Config {
+            database_url: Some(url),
+            ..Config::default()
+        };
+        let app = RealWorld::with_config(config.clone(), CachePolicy::WriteThrough).unwrap();
+        let name = Name::new(&format!("user-{}", Uuid::new_v4().simple())).unwrap();
+        let email = Email::parse(&format!("{}@example.com", name.as_str())).unwrap();
+        let user = app.user_commands().create(name, email).unwrap();
+
+        // 別のプロセスからも同じユーザーが読める
+        let other = RealWorld::with_config(config, CachePolicy::WriteThrough).unwrap();
+        assert_eq!(other.user_queries().get(user.id.clone()).unwrap(), user);
+
+        // 先に更新された後で古いバージョンを書こうとするとConflictになる
+        let mut writer_a = app.user_queries().get(user.id.clone()).unwrap();
+        let mut writer_b = other.user_queries().get(user.id.clone()).unwrap();
+        writer_a.email = Email::parse(&format!("a-{}", user.email.as_str())).unwrap();
+        app.user_commands().update(writer_a.clone()).unwrap();
+        writer_b.email = Email::parse(&format!("b-{}", user.email.as_str())).unwrap();
+        match other.user_commands().update(writer_b).unwrap_err() {
+            DomainError::Conflict { stored: 2, given: 2 } => {}
+            e => panic!("{:?}", e),
+        }
+
+        // まとめて保存する時は、1件でも古ければ1件も書かない
+        let fresh = test_user(&format!("user-{}", Uuid::new_v4().simple()));
+        let batch = [(fresh.id.clone(), fresh.clone()), (user.id.clone(), user.clone())];
+        let conflict = app.user_storage_component().save_all(&batch).unwrap_err();
+        assert_eq!(conflict.downcast_ref(), Some(&StorageError::Conflict { stored: 2, given: 1 }));
+        assert!(other.user_queries().get(fresh.id).is_err());
+        app.user_commands().delete(user.id.clone()).unwrap();
+    }
+
+    #[test]
+    fn real_world_uses_configured_storage() {
+        let path = ::std::env::temp_dir().join(format!("layered-{}.jsonl", Uuid::new_v4()));
+        let config = Config {
+            storage_path: Some(path.clone()),
+            ..Config::default()
+        };
+        let name = Name::new("user1").unwrap();
+        {
+            let app = RealWorld::with_config(config.clone(), CachePolicy::WriteThrough).unwrap();
+            app.user_commands()
+                .create(name.clone(), Email::parse("user1@example.com").unwrap())
+                .unwrap();
+        }
+        let app = RealWorld::with_config(config, CachePolicy::WriteThrough).unwrap();
+        assert!(app.user_queries().get_by_name(&name).is_ok());
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn seeded_random_is_deterministic() {
+        let (a, b) = (MockRandom::new(42), MockRandom::new(42));
+        let (mut first, mut second) = ([0u8; 16], [0u8; 16]);
+        a.fill_bytes(&mut first);
+        b.fill_bytes(&mut second);
+        assert_eq!(first, second);
+
+        let token = a.token(32);
+        assert_eq!(token, b.token(32));
+        assert_eq!(token.len(), 32);
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(token, a.token(32));
+
+        let app = TestWorld::new();
+        let (_, plain) = app
+            .api_token_repository()
+            .issue_token(UserId::new(Uuid::from_u128(1)), &[Scope::ReadUsers], None)
+            .unwrap();
+        let expected = format!("{}.{}", Uuid::from_u128(1).simple(), MockRandom::new(0).token(32));
+        assert_eq!(plain, expected);
+    }
+
+    #[test]
+    fn argon2_hashes_and_verifies_passwords() {
+        let hasher = Argon2Hasher::default();
+        let hash = hasher.hash("correct horse").unwrap();
+        assert!(hash.as_str().starts_with("$argon2id$"));
+        assert_ne!(hash, hasher.hash("correct horse").unwrap());
+
+        assert!(hasher.verify("correct horse", &hash).unwrap());
+        assert!(!hasher.verify("wrong", &hash).unwrap());
+        assert!(hasher.verify("correct horse", &PasswordHash::new("plain:x".to_string())).is_err());
+    }
+
+    #[test]
+    fn mail_is_sent_through_the_injected_sender() {
+        let app = TestWorld::new();
+        let mail = Mail {
+            to: Email::parse("user1@example.com").unwrap(),
+            subject: "welcome".to_string(),
+            body: "hello".to_string(),
+        };
+        app.email_sender_component().send(&mail).unwrap();
+        assert_eq!(app.email_sender_component().sent(), vec![mail]);
+
+        assert!(SmtpSender::new("localhost", 25, "noreply@localhost").is_ok());
+        assert!(SmtpSender::new("localhost", 25, "not an address").is_err());
+    }
+
+    #[test]
+    fn account_changes_are_notified() {
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        app.user_commands().change_role(user.id.clone(), Role::Admin).unwrap();
+        app.user_commands().suspend(user.id.clone()).unwrap();
+
+        let messages: Vec<String> = app.notification_component().sent().into_iter().map(|(_, m)| m).collect();
+        assert_eq!(messages, vec!["user created", "role changed to Admin", "user suspended"]);
+
+        let mailer = RecordingMailer::new();
+        EmailNotifier::new(&mailer).notify(&user, "user suspended").unwrap();
+        assert_eq!(mailer.sent()[0].to, user.email);
+        assert_eq!(mailer.sent()[0].body, "user suspended");
+    }
+
+    #[test]
+    fn metrics_are_recorded_in_memory() {
+        let app = TestWorld::new();
+        for name in &["user1", "user2"] {
+            app.user_commands()
+                .create(Name::new(name).unwrap(), Email::parse(&format!("{}@example.com", name)).unwrap())
+                .unwrap();
+        }
+        assert_eq!(app.metrics_component().counter("users.created"), 2);
+        assert_eq!(app.metrics_component().counter("users.suspended"), 0);
+
+        let metrics = InMemoryMetrics::new();
+        metrics.observe("latency", 1.5);
+        metrics.observe("latency", 0.5);
+        assert_eq!(metrics.observations("latency"), vec![1.5, 0.5]);
+        assert!(metrics.observations("unknown").is_empty());
+    }
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Verification {
+        deliverable: bool,
+    }
+
+    #[test]
+    fn http_client_returns_typed_stub_responses() {
+        let client = StubHttpClient::new()
+            .respond("https://verify.example.com/check", json!({ "deliverable": true }))
+            .respond("https://hooks.example.com/users", Value::Null);
+
+        let verification: Verification = client.get("https://verify.example.com/check").unwrap();
+        assert_eq!(verification, Verification { deliverable: true });
+        assert!(client.get::<Verification>("https://unknown.example.com").is_err());
+
+        let user = test_user("user1");
+        WebhookNotifier::new("https://hooks.example.com/users", &client)
+            .notify(&user, "user suspended")
+            .unwrap();
+        let (url, body) = client.requests().pop().unwrap();
+        assert_eq!(url, "https://hooks.example.com/users");
+        assert_eq!(body.unwrap()["message"], json!("user suspended"));
+    }
+    #[test]
+    fn user_events_are_published_to_the_queue() {
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        app.user_commands().suspend(user.id.clone()).unwrap();
+
+        let messages: Vec<Value> = app
+            .message_queue_component()
+            .consume(USER_EVENTS_TOPIC)
+            .unwrap()
+            .iter()
+            .map(|payload| serde_json::from_slice(payload).unwrap())
+            .collect();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1]["event"], json!("suspended"));
+        assert_eq!(messages[1]["user_id"], json!(user.id.as_uuid().to_string()));
+        assert!(app.message_queue_component().consume(USER_EVENTS_TOPIC).unwrap().is_empty());
+        assert!(app.message_queue_component().consume("unknown").unwrap().is_empty());
+
+        // CLIでは取り出したイベントを1行に1つずつ書く
+        app.user_commands().deactivate(user.id.clone()).unwrap();
+        let mut out = Vec::new();
+        cli::events(&app, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["event"], json!("deactivated"));
+        assert!(app.message_queue_component().consume(USER_EVENTS_TOPIC).unwrap().is_empty());
+    }
+    #[test]
+    fn user_events_are_delivered_to_webhooks_with_a_signature() {
+        let client = StubHttpClient::new().respond("https://hooks.example.com/ok", Value::Null);
+        let key = Secret::new("webhook-key");
+        let urls = vec!["https://hooks.example.com/ok".to_string(), "https://hooks.example.com/down".to_string()];
+        let time = MockTime::new();
+        let dispatcher = WebhookDispatcher::with_clock(urls, 3, key.clone(), &client, time.clone(), MockRandom::new(1));
+        let payload = json!({ "event": "created" });
+        let started = time.instant();
+        assert!(dispatcher.deliver(&payload).is_err());
+        // 試す間は0.5〜1秒、1〜2秒と倍々に待つ
+        let waited = time.elapsed(started);
+        assert!(waited >= Duration::milliseconds(1500) && waited <= Duration::seconds(3), "{}", waited);
+
+        // 送れたURLへは1回、送れないURLへは3回試す。本文は受け取る側で署名を確かめられる
+        let urls: Vec<String> = client.requests().into_iter().map(|(url, _)| url).collect();
+        assert_eq!(urls.iter().filter(|url| url.ends_with("/ok")).count(), 1);
+        assert_eq!(urls.iter().filter(|url| url.ends_with("/down")).count(), 3);
+        let signature = webhook::signature(&key, &serde_json::to_string(&payload).unwrap());
+        assert!(client.headers().iter().all(|header| *header == (SIGNATURE_HEADER.to_string(), signature.clone())));
+        assert_ne!(signature, webhook::signature(&Secret::new("other-key"), &payload.to_string()));
+
+        let dead_letters = dispatcher.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!((dead_letters[0].url.as_str(), dead_letters[0].attempts), ("https://hooks.example.com/down", 3));
+        assert_eq!(dead_letters[0].payload, payload);
+        assert_eq!(dispatcher.redeliver(), 0);
+        assert_eq!(dispatcher.dead_letters()[0].attempts, 6);
+
+        // TestWorldではイベントバスの購読者として送る。送れなくてもユーザーの変更は保存される
+        let down = vec!["https://hooks.example.com/down".to_string()];
+        let app = TestWorld::new().with_webhooks(WebhookDispatcher::new(down, 1, key, StubHttpClient::new()));
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        let dead_letters = app.webhook_component().dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].payload["event"], json!("created"));
+        assert_eq!(dead_letters[0].payload["user_id"], json!(user.id.as_uuid().to_string()));
+        let records = app.logging_component().records();
+        assert!(records.iter().any(|(level, message)| *level == Level::Warn && message.starts_with("webhook failed")));
+        // 定期的に送り直すジョブは、送れないものが残っていれば失敗にする
+        app.schedule_maintenance().unwrap();
+        app.scheduler_component().tick(DateTime::from_str("2018-08-20T01:15:00Z").unwrap());
+        assert!(app.run_due_jobs().is_err());
+        assert_eq!(app.webhook_component().dead_letters()[0].attempts, 2);
+
+        let config = Config::default()
+            .override_with(|key| match key {
+                "LAYERED_EVENT_WEBHOOK_URLS" => Some("https://a.example.com, https://b.example.com".to_string()),
+                "LAYERED_EVENT_WEBHOOK_MAX_ATTEMPTS" => Some("5".to_string()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(config.event_webhook_urls(), ["https://a.example.com", "https://b.example.com"]);
+        assert_eq!(config.event_webhook_max_attempts(), 5);
+        let zero = Config::default().override_with(|key| match key {
+            "LAYERED_EVENT_WEBHOOK_MAX_ATTEMPTS" => Some("0".to_string()),
+            _ => None,
+        });
+        assert!(zero.is_err());
+    }
+    #[test]
+    fn failed_transaction_rolls_back_every_participant() {
+        let app = TestWorld::new();
+        let existing = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+
+        let result: Result<(), _> = app.transaction(|app| {
+            let user = app
+                .user_commands()
+                .create(Name::new("user2").unwrap(), Email::parse("user2@example.com").unwrap())?;
+            app.credential_repository().set_password(user.id.clone(), "secret")?;
+            app.session_repository().create_session(user.id, Duration::hours(1))?;
+            app.user_commands().rename(existing.id.clone(), Name::new("renamed").unwrap())?;
+            bail!("payment declined")
+        });
+        assert_eq!(result.unwrap_err().to_string(), "payment declined");
+
+        let users = app.user_queries().list().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].name, existing.name);
+        assert!(app.user_queries().get_by_name(&Name::new("user2").unwrap()).is_err());
+        assert!(app.credential_repository().list().unwrap().is_empty());
+        assert!(app.session_repository().list().unwrap().is_empty());
+        // 戻した後も続けて更新できる
+        app.user_commands().rename(existing.id.clone(), Name::new("renamed").unwrap()).unwrap();
+
+        let user = app
+            .transaction(|app| {
+                app.user_commands()
+                    .create(Name::new("user2").unwrap(), Email::parse("user2@example.com").unwrap())
+            })
+            .unwrap();
+        assert!(app.user_queries().get(user.id).is_ok());
+        app.begin().unwrap();
+        assert!(app.begin().is_err());
+    }
+
+    #[test]
+    fn event_bus_fans_out_to_every_subscriber() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let app = TestWorld::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = received.clone();
+        app.event_bus_component().subscribe(
+            "failing",
+            Box::new(|_: &TestWorld, _: &User, _: UserEvent| Err(format_err!("unavailable"))),
+        );
+        app.event_bus_component().subscribe(
+            "recording",
+            Box::new(move |_: &TestWorld, user: &User, event: UserEvent| {
+                sink.lock().unwrap().push((user.id.clone(), event));
+                Ok(())
+            }),
+        );
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        app.user_commands().suspend(user.id.clone()).unwrap();
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![(user.id.clone(), UserEvent::Created), (user.id.clone(), UserEvent::Suspended)]
+        );
+        // 失敗した購読者がいても、その後の購読者や組み込みの購読者には届く
+        assert_eq!(app.notification_component().sent().len(), 2);
+        let warnings: Vec<String> = app
+            .logging_component()
+            .records()
+            .into_iter()
+            .filter(|&(level, _)| level == Level::Warn)
+            .map(|(_, message)| message)
+            .collect();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0], format!("failing failed on {:?}: unavailable", user.id));
+
+        // 購読者の中で購読者を登録しても止まらず、登録された購読者には次のイベントから届く
+        let late = Arc::new(Mutex::new(Vec::new()));
+        let (sink, subscribed) = (late.clone(), Arc::new(AtomicBool::new(false)));
+        app.event_bus_component().subscribe(
+            "subscribing",
+            Box::new(move |world: &TestWorld, _: &User, _: UserEvent| {
+                if !subscribed.swap(true, Ordering::SeqCst) {
+                    let sink = sink.clone();
+                    world.event_bus_component().subscribe(
+                        "late",
+                        Box::new(move |_: &TestWorld, _: &User, event: UserEvent| {
+                            sink.lock().unwrap().push(event);
+                            Ok(())
+                        }),
+                    );
+                }
+                Ok(())
+            }),
+        );
+        let other = app
+            .user_commands()
+            .create(Name::new("user2").unwrap(), Email::parse("user2@example.com").unwrap())
+            .unwrap();
+        assert!(late.lock().unwrap().is_empty());
+        app.user_commands().suspend(other.id).unwrap();
+        assert_eq!(*late.lock().unwrap(), vec![UserEvent::Suspended]);
+    }
+    #[test]
+    fn cached_values_expire_after_ttl() {
+        let clock = MockTime::new();
+        let cache: MemoryCache<UserId, User, MockTime> = MemoryCache::with_clock(clock.clone());
+        let (fresh, stale) = (test_user("user1"), test_user("user2"));
+        cache.set_with_ttl(fresh.id.clone(), fresh.clone(), Duration::minutes(5));
+        cache.set_with_ttl(stale.id.clone(), stale.clone(), Duration::zero());
+        assert!(cache.get(&fresh.id).is_some());
+        assert!(cache.get(&stale.id).is_none());
+        assert_eq!(cache.len(), 1);
+        clock.advance(Duration::minutes(5));
+        assert!(cache.get(&fresh.id).is_none());
+
+        let storage = CachingStorage::new(MemoryStorage::new(), cache, CachePolicy::WriteThrough)
+            .with_ttl(Duration::zero());
+        storage.save(stale.id.clone(), stale.clone()).unwrap();
+        assert!(storage.cache().get(&stale.id).is_none());
+        assert!(storage.read(stale.id.clone()).is_ok());
+    }
+    #[test]
+    fn percentage_rollout_is_stable_per_user() {
+        let users: Vec<User> = (0..1000).map(|i| test_user(&format!("user{}", i))).collect();
+        let enabled = |rollout: &PercentageRollout| -> Vec<bool> {
+            users.iter().map(|user| rollout.is_enabled("search", user)).collect()
+        };
+        let none = PercentageRollout::new(vec![("search".to_string(), 0)]);
+        let half = PercentageRollout::new(vec![("search".to_string(), 50)]);
+        let all = PercentageRollout::new(vec![("search".to_string(), 100)]);
+
+        assert!(enabled(&none).iter().all(|&e| !e));
+        assert!(enabled(&all).iter().all(|&e| e));
+        let count = enabled(&half).iter().filter(|&&e| e).count();
+        assert!(400 < count && count < 600, "{}", count);
+        assert_eq!(enabled(&half), enabled(&half));
+        assert!(!half.is_enabled("unknown", &users[0]));
+
+        let wider = PercentageRollout::new(vec![("search".to_string(), 80)]);
+        assert!(enabled(&half).iter().zip(enabled(&wider)).all(|(&h, w)| !h || w));
+
+        let config = Config::default()
+            .override_with(|key| match key {
+                "LAYERED_ROLLOUTS" => Some("search=10, export=100".to_string()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(config.rollouts().get("search"), Some(&10));
+        assert!(Config::default().override_with(|_| Some("search=101".to_string())).is_err());
+    }
+
+    #[test]
+    fn rename_is_notified_only_when_flag_enabled() {
+        for &enabled in &[false, true] {
+            let flags = if enabled { StaticFlags::new(vec![NOTIFY_ON_RENAME]) } else { StaticFlags::default() };
+            let app = TestWorld::new().with_flags(flags);
+            let user = app
+                .user_commands()
+                .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+                .unwrap();
+            app.rename_user(user.id.clone(), Name::new("user2").unwrap()).unwrap();
+
+            let messages: Vec<String> = app.notification_component().sent().into_iter().map(|(_, m)| m).collect();
+            assert_eq!(messages.contains(&"name changed from user1 to user2".to_string()), enabled);
+        }
+    }
+    #[test]
+    fn secrets_are_resolved_by_name() {
+        let secrets = FixedSecrets::new().with("signing_key", "test-signing-key");
+        assert_eq!(secrets.require("signing_key").unwrap().expose(), "test-signing-key");
+        assert!(secrets.require("db_password").is_err());
+
+        let path = ::std::env::temp_dir().join(format!("layered-secrets-{}.toml", Uuid::new_v4()));
+        ::std::fs::write(&path, "db_password = \"hunter2\"\n").unwrap();
+        let vault = FileVault::open(&path).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+        let password = vault.secret("db_password").unwrap();
+        assert_eq!(password.expose(), "hunter2");
+        assert_eq!(format!("{:?}", password), "Secret(***)");
+
+        let env = FakeEnvironment::new().with("LAYERED_SECRET_DB_PASSWORD", "from-env");
+        assert_eq!(EnvSecrets::with_environment(env).secret("db_password").unwrap().expose(), "from-env");
+
+        let config = Config {
+            smtp_user: Some("mailer".to_string()),
+            ..Config::default()
+        };
+        assert!(RealWorld::with_config(config, CachePolicy::WriteThrough).is_err());
+    }
+    #[test]
+    fn token_bucket_limits_each_key() {
+        let clock = MockTime::new();
+        let limiter = TokenBucket::with_clock(2, Duration::minutes(1), clock.clone());
+        assert_eq!(limiter.check_and_consume("user1"), RateLimit::Allowed);
+        assert_eq!(limiter.check_and_consume("user1"), RateLimit::Allowed);
+        assert_eq!(
+            limiter.check_and_consume("user1"),
+            RateLimit::Limited {
+                retry_after: Duration::minutes(1)
+            }
+        );
+        assert_eq!(limiter.check_and_consume("user2"), RateLimit::Allowed);
+        clock.advance(Duration::seconds(40));
+        assert_eq!(
+            limiter.check_and_consume("user1"),
+            RateLimit::Limited {
+                retry_after: Duration::seconds(20)
+            }
+        );
+        clock.advance(Duration::seconds(20));
+        assert_eq!(limiter.check_and_consume("user1"), RateLimit::Allowed);
+
+        let app = TestWorld::new();
+        let owner = UserId::new(Uuid::new_v4());
+        let (token, _) = app.api_token_repository().issue_token(owner, &[Scope::ReadUsers], None).unwrap();
+        let wrong = format!("{}.wrong", token.id.as_uuid().simple());
+        for _ in 0..5 {
+            let err = app.api_token_repository().authenticate_token(&wrong).unwrap_err();
+            assert_eq!(err.to_string(), "invalid api token");
+        }
+        let err = app.api_token_repository().authenticate_token(&wrong).unwrap_err();
+        assert!(err.to_string().starts_with("too many attempts"), "{}", err);
+        // 環境型の時計を進めると、レート制限の時計も進む
+        app.monotonic_time_component().sleep(Duration::minutes(1));
+        let err = app.api_token_repository().authenticate_token(&wrong).unwrap_err();
+        assert_eq!(err.to_string(), "invalid api token");
+    }
+
+    #[test]
+    fn mock_time_advances_without_waiting() {
+        let clock = MockTime::new();
+        let (started, now) = (clock.instant(), clock.now());
+        clock.sleep(Duration::seconds(-1));
+        assert_eq!(clock.elapsed(started), Duration::zero());
+        clock.sleep(Duration::seconds(90));
+        assert_eq!(clock.elapsed(started), Duration::seconds(90));
+        assert_eq!(clock.now(), now + Duration::seconds(90));
+
+        let real = StdClock::new();
+        let started = real.instant();
+        real.sleep(Duration::milliseconds(5));
+        assert!(real.elapsed(started) >= Duration::milliseconds(5));
+    }
+    #[test]
+    fn named_locks_are_exclusive() {
+        let locks = ::std::sync::Arc::new(InProcessLocks::new());
+        let token = locks.acquire("users.create", Duration::seconds(1)).unwrap();
+        assert!(locks.acquire("users.create", Duration::milliseconds(10)).is_err());
+        let other = locks.acquire("groups.create", Duration::milliseconds(10)).unwrap();
+        locks.release(other).unwrap();
+
+        // 他のスレッドが解放するのを待って取れる
+        let waiting = {
+            let locks = locks.clone();
+            ::std::thread::spawn(move || {
+                let token = locks.acquire("users.create", Duration::seconds(5)).unwrap();
+                locks.release(token).unwrap();
+            })
+        };
+        ::std::thread::sleep(::std::time::Duration::from_millis(20));
+        locks.release(token).unwrap();
+        waiting.join().unwrap();
+
+        let app = TestWorld::new();
+        app.user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        let token = app.lock_component().acquire("users.create", Duration::zero()).unwrap();
+        app.lock_component().release(token).unwrap();
+    }
+    #[test]
+    fn connection_pool_reuses_checks_and_limits_connections() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let clock = MockTime::new();
+        let connector = MemoryConnector::new();
+        let pool = ConnectionPool::with_clock(&connector, 2, clock.clone()).with_timeout(Duration::milliseconds(20));
+        let first = pool.acquire().unwrap();
+        let second = pool.acquire().unwrap();
+        assert!(pool.acquire().is_err());
+
+        // 返された接続を使い回す
+        pool.release(first);
+        assert_eq!(pool.acquire().unwrap(), first);
+        assert_eq!(connector.opened(), 2);
+
+        // 返されてすぐの接続は確かめずに貸し、しばらく置かれていた接続は確かめて、壊れていれば作り直す
+        connector.break_connection(first);
+        pool.release(first);
+        assert_eq!(pool.acquire().unwrap(), first);
+        pool.release(first);
+        clock.advance(Duration::seconds(30));
+        let third = pool.acquire().unwrap();
+        assert_eq!((third, connector.opened()), (2, 3));
+
+        // 作れなかった時は枠を空けるので、上限まで借りたままにならない
+        connector.refuse(true);
+        pool.release(third);
+        connector.break_connection(third);
+        clock.advance(Duration::seconds(30));
+        assert!(pool.acquire().is_err());
+        connector.refuse(false);
+        let fourth = pool.acquire().unwrap();
+        pool.release(fourth);
+        pool.release(second);
+
+        // 上限まで貸している時は、他のスレッドが返すのを待つ
+        let connector = MemoryConnector::new();
+        let pool = ConnectionPool::new(&connector, 2);
+        let first = pool.acquire().unwrap();
+        let second = pool.acquire().unwrap();
+        ::std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| pool.with_connection(|connection| Ok(*connection)));
+            ::std::thread::sleep(::std::time::Duration::from_millis(20));
+            pool.release(second);
+            assert_eq!(waiting.join().unwrap().unwrap(), second);
+        });
+        pool.release(first);
+
+        // 貸している間にpanicしても枠は空け、やり取りの途中かもしれない接続は使い回さない
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.with_connection(|_| -> Result<(), Error> { panic!("lost the connection mid-reply") })
+        }));
+        assert!(panicked.is_err());
+        let (fresh, reused) = (pool.acquire().unwrap(), pool.acquire().unwrap());
+        assert_eq!((fresh.max(reused), connector.opened()), (2, 3));
+    }
+    #[test]
+    fn schedules_match_like_cron() {
+        let at = |s: &str| DateTime::<Utc>::from_str(s).unwrap();
+        let every_ten = Schedule::from_str("*/10 * * * *").unwrap();
+        assert!(every_ten.matches(&at("2018-08-20T01:20:00Z")));
+        assert!(!every_ten.matches(&at("2018-08-20T01:25:00Z")));
+
+        // 2018-08-20は月曜日
+        let weekday_mornings = Schedule::from_str("30 9 * 1-12 1-5").unwrap();
+        assert!(weekday_mornings.matches(&at("2018-08-20T09:30:00Z")));
+        assert!(!weekday_mornings.matches(&at("2018-08-19T09:30:00Z")));
+
+        for invalid in &["* * * *", "60 * * * *", "*/0 * * * *", "5-1 * * * *", "a * * * *"] {
+            assert!(Schedule::from_str(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn scheduled_jobs_run_when_due() {
+        let app = TestWorld::new();
+        app.schedule_maintenance().unwrap();
+        let user = UserId::new(Uuid::new_v4());
+        let expired = app.session_repository().create_session(user.clone(), Duration::minutes(-1)).unwrap();
+        let valid = app.session_repository().create_session(user, Duration::hours(1)).unwrap();
+
+        app.scheduler_component().tick(DateTime::from_str("2018-08-20T01:05:00Z").unwrap());
+        assert!(app.run_due_jobs().unwrap().is_empty());
+        assert!(app.session_repository().get(expired.id.clone()).is_ok());
+
+        app.scheduler_component().tick(DateTime::from_str("2018-08-20T01:10:00Z").unwrap());
+        assert_eq!(app.run_due_jobs().unwrap(), vec![PURGE_EXPIRED_SESSIONS.to_string()]);
+        assert!(app.session_repository().get(expired.id).is_err());
+        assert!(app.session_repository().get(valid.id).is_ok());
+
+        let created = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        assert_eq!(app.archive_inactive_users(Duration::days(1)).unwrap(), 0);
+        assert_eq!(app.archive_inactive_users(Duration::zero()).unwrap(), 1);
+        assert_eq!(app.user_queries().get(created.id).unwrap().status, UserStatus::Deactivated);
+    }
+    #[test]
+    fn file_storage_and_export_use_the_injected_file_system() {
+        let fs = MemoryFileSystem::new();
+        let path = Path::new("data/users.jsonl");
+        let user = test_user("user1");
+        {
+            let storage = FileStorage::open_with(path, UserRecordCodec, &fs).unwrap();
+            storage.save(user.id.clone(), user.clone()).unwrap();
+        }
+        let storage: FileStorage<UserId, User, _, _> = FileStorage::open_with(path, UserRecordCodec, &fs).unwrap();
+        assert!(storage.read(user.id.clone()).unwrap().same_state_as(&user));
+
+        let app = TestWorld::new();
+        for name in &["user1", "user2"] {
+            app.user_commands()
+                .create(Name::new(name).unwrap(), Email::parse(&format!("{}@example.com", name)).unwrap())
+                .unwrap();
+        }
+        assert_eq!(app.export_users_as(Path::new("export/users.jsonl"), &ExportFormat::Json).unwrap(), 2);
+        let exported = app.file_system_component().read(Path::new("export/users.jsonl")).unwrap().unwrap();
+        let names: Vec<String> = exported
+            .lines()
+            .map(|line| serde_json::from_str::<User>(line).unwrap().name.as_str().to_string())
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&"user1".to_string()) && names.contains(&"user2".to_string()));
+    }
+    #[test]
+    fn config_is_loaded_from_the_injected_environment() {
+        let fs = MemoryFileSystem::new();
+        fs.write(Path::new("/etc/layered.toml"), "page_size = 50\nnotifier = \"email\"").unwrap();
+        let env = FakeEnvironment::new()
+            .with("LAYERED_CONFIG", "/etc/layered.toml")
+            .with("LAYERED_PAGE_SIZE", "10");
+
+        let config = Config::load(&env, &fs).unwrap();
+        assert_eq!(config.page_size(), 10);
+        assert_eq!(config.notifier(), NotifierKind::Email);
+        assert_eq!(Config::load(&FakeEnvironment::new(), &fs).unwrap(), Config::default());
+
+        let missing = FakeEnvironment::new().with("LAYERED_CONFIG", "/etc/missing.toml");
+        assert!(Config::load(&missing, &fs).is_err());
+        assert_eq!(env.instance_name(), "test-host:1");
+    }
+    #[test]
+    fn account_mails_are_rendered_from_templates() {
+        let templates = HandlebarsTemplates::new().unwrap();
+        let context = json!({ "name": "<user1>", "reset_url": "https://example.com/reset?t=1&u=2" });
+        assert_eq!(templates.render("welcome.subject", &context).unwrap(), "Welcome, <user1>");
+        let body = templates.render("password_reset.body", &context).unwrap();
+        assert!(body.contains("https://example.com/reset?t=1&u=2"), "{}", body);
+        assert!(templates.render("password_reset.body", &json!({ "name": "user1" })).is_err());
+        assert!(templates.render("unknown", &context).is_err());
+
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        app.send_welcome(&user).unwrap();
+        app.send_password_reset(&user, "https://example.com/reset").unwrap();
+
+        let sent = app.email_sender_component().sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].to, user.email);
+        assert_eq!(sent[0].subject, format!("{}.subject {}", WELCOME, json!({ "name": "user1" })));
+        assert!(sent[1].body.starts_with(&format!("{}.body", PASSWORD_RESET)));
+        assert!(sent[1].body.contains("https://example.com/reset"));
+    }
+    #[test]
+    fn tantivy_search_finds_misspelled_words() {
+        let search = TantivySearch::in_memory().unwrap();
+        search.index("1", "alice alice@example.com").unwrap();
+        search.index("2", "bob bob@example.org").unwrap();
+        assert_eq!(search.query("alise", 10).unwrap(), vec!["1"]);
+        assert_eq!(search.query("BOB", 10).unwrap(), vec!["2"]);
+        assert_eq!(search.query("example", 10).unwrap().len(), 2);
+        assert!(search.query("carol", 10).unwrap().is_empty());
+        assert!(search.query("", 10).unwrap().is_empty());
+
+        search.index("1", "carol carol@example.com").unwrap();
+        assert!(search.query("alice", 10).unwrap().is_empty());
+        assert_eq!(search.query("carol", 10).unwrap(), vec!["1"]);
+        search.remove("1").unwrap();
+        assert!(search.query("carol", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn search_index_follows_user_events() {
+        let app = TestWorld::new();
+        let alice = app
+            .user_commands()
+            .create(Name::new("alice").unwrap(), Email::parse("alice@example.com").unwrap())
+            .unwrap();
+        app.user_commands()
+            .create(Name::new("bob").unwrap(), Email::parse("bob@example.org").unwrap())
+            .unwrap();
+        let names = |users: Vec<User>| -> Vec<String> { users.iter().map(|u| u.name.to_string()).collect() };
+        assert_eq!(names(app.search_users("alice", 10).unwrap()), vec!["alice"]);
+        assert_eq!(app.search_users("example", 10).unwrap().len(), 2);
+
+        // メールアドレスはそのままなので、前の名前でも見つかる
+        app.rename_user(alice.id.clone(), Name::new("carol").unwrap()).unwrap();
+        assert_eq!(names(app.search_users("carol", 10).unwrap()), vec!["carol"]);
+        assert_eq!(names(app.search_users("alice", 10).unwrap()), vec!["carol"]);
+
+        app.user_commands().deactivate(alice.id).unwrap();
+        assert!(app.search_users("carol", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn catalogs_fall_back_to_language_and_default_locale() {
+        let catalogs = Catalogs::new("en")
+            .with("en", "# greetings\ngreeting = Hello, { $name }")
+            .and_then(|catalogs| catalogs.with("ja", "farewell = さようなら"))
+            .unwrap();
+        let args = [("name", "alice".to_string())];
+        assert_eq!(catalogs.message("ja-JP", "farewell", &[]), "さようなら");
+        assert_eq!(catalogs.message("ja-JP", "greeting", &args), "Hello, alice");
+        assert_eq!(catalogs.message("en", "missing", &[]), "missing");
+        assert!(Catalogs::new("en").with("en", "no separator").is_err());
+    }
+
+    #[test]
+    fn error_messages_follow_profile_locale() {
+        let app = TestWorld::new();
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        let too_long = || PresentationError::from(ValidationError::TooLong { field: "name", max: 64 });
+        let error = app.localize_error("ja", too_long());
+        assert_eq!(error.message, "name は 64 文字以内で入力してください");
+        assert_eq!(error.fields["name"], "name は 64 文字以内で入力してください");
+
+        // プロフィールが無ければロケールは分からない
+        assert_eq!(app.profile_locale(user.id.clone()), None);
+        app.profile_repository().create_profile(user.id.clone()).unwrap();
+        app.profile_repository()
+            .edit_profile(user.id.clone(), |profile| profile.locale = "en-US".to_string())
+            .unwrap();
+        let locale = app.profile_locale(user.id.clone()).unwrap();
+        assert_eq!(app.localize_error(&locale, too_long()).message, "name must be at most 64 characters");
+
+        app.user_commands().suspend(user.id.clone()).unwrap();
+        let not_active = app.rename_user(user.id.clone(), Name::new("user2").unwrap()).unwrap_err();
+        let error = app.localize_error(&locale, not_active.into());
+        assert_eq!(error.message, "Cannot rename a user who is suspended");
+
+        // 翻訳できないエラーは元の文言のまま
+        let unavailable = PresentationError::new(ErrorKind::Conflict, "unavailable");
+        assert_eq!(app.localize_error(&locale, unavailable).message, "unavailable");
+    }
+
+    #[test]
+    fn signup_region_is_recorded_on_profile() {
+        let geo_ip = StaticGeoIp::default().with("203.0.113.7", "JP", "Tokyo");
+        let app = TestWorld::new().with_geo_ip(geo_ip);
+        let user = app
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+
+        // プロフィールが無ければ作ってから記録する
+        let profile = app.record_signup_region(user.id.clone(), "203.0.113.7".parse().unwrap()).unwrap();
+        assert_eq!(profile.signup_country.as_deref(), Some("JP"));
+        assert_eq!(profile.signup_city.as_deref(), Some("Tokyo"));
+        assert_eq!(app.profile_repository().get(user.id.clone()).unwrap().signup_country.as_deref(), Some("JP"));
+
+        let profile = app.record_signup_region(user.id.clone(), "192.168.0.1".parse().unwrap()).unwrap();
+        assert_eq!(profile.signup_country, None);
+        assert_eq!(profile.signup_city, None);
+
+        assert!(MaxMindGeoIp::open(Path::new("/nonexistent/GeoLite2-City.mmdb")).is_err());
+    }
+
+    #[test]
+    fn aes_gcm_crypto_detects_tampering_and_wrong_keys() {
+        let crypto = AesGcmCrypto::new(&Secret::new("test-encryption-key"));
+        let ciphertext = crypto.encrypt(b"user1@example.com").unwrap();
+        assert_ne!(crypto.encrypt(b"user1@example.com").unwrap(), ciphertext);
+        assert_eq!(crypto.decrypt(&ciphertext).unwrap(), b"user1@example.com");
+
+        let mut tampered = ciphertext.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(crypto.decrypt(&tampered).is_err());
+        assert!(crypto.decrypt(&ciphertext[..4]).is_err());
+        assert!(AesGcmCrypto::new(&Secret::new("other-key")).decrypt(&ciphertext).is_err());
+
+        let signature = crypto.sign(b"message");
+        assert!(crypto.verify(b"message", &signature));
+        assert!(!crypto.verify(b"massage", &signature));
+        assert!(!AesGcmCrypto::ephemeral().verify(b"message", &signature));
+    }
+
+    #[test]
+    fn aes_gcm_crypto_takes_nonces_from_the_random_component_and_keys_from_hkdf() {
+        use hkdf::Hkdf;
+        use hmac::{Hmac, Mac};
+        use sha2::Sha256;
+        use tests::mock::random::MockRandom;
+
+        // nonceは渡した乱数から取るので、同じseedなら同じ暗号文になる
+        let secret = Secret::new("test-encryption-key");
+        let crypto = AesGcmCrypto::with_random(&secret, MockRandom::new(7));
+        let ciphertext = crypto.encrypt(b"user1@example.com").unwrap();
+        let mut nonce = [0u8; 12];
+        MockRandom::new(7).fill_bytes(&mut nonce);
+        assert_eq!(ciphertext[..12], nonce);
+        let again = AesGcmCrypto::with_random(&secret, MockRandom::new(7));
+        assert_eq!(again.encrypt(b"user1@example.com").unwrap(), ciphertext);
+        assert_eq!(AesGcmCrypto::new(&secret).decrypt(&ciphertext).unwrap(), b"user1@example.com");
+
+        // 署名の鍵は秘密の値からHKDF-SHA256で `sign` 用に導出したもの
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(None, b"test-encryption-key").expand(b"sign", &mut key).unwrap();
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key).unwrap();
+        mac.update(b"message");
+        assert_eq!(crypto.sign(b"message"), mac.finalize().into_bytes().to_vec());
+    }
+
+    #[test]
+    fn file_storage_keeps_memory_unchanged_when_the_write_fails() {
+        let fs = MemoryFileSystem::new();
+        let path = Path::new("users.jsonl");
+        let storage = FileStorage::open_with(path, UserRecordCodec, &fs).unwrap();
+        let user = test_user("user1");
+        storage.save(user.id.clone(), user.clone()).unwrap();
+        let written = fs.read(path).unwrap();
+
+        fs.fail_writes(true);
+        let mut renamed = user.clone();
+        renamed.name = Name::new("renamed").unwrap();
+        renamed.version += 1;
+        assert!(storage.save(user.id.clone(), renamed.clone()).is_err());
+        let other = test_user("user2");
+        assert!(storage.save_all(&[(other.id.clone(), other.clone())]).is_err());
+        assert!(storage.delete(user.id.clone()).is_err());
+
+        // 書き出せなかった変更は、読み込みにもバージョンの確認にも現れない
+        assert!(storage.read(user.id.clone()).unwrap().same_state_as(&user));
+        assert!(storage.read(other.id.clone()).is_err());
+        assert_eq!(fs.read(path).unwrap(), written);
+        fs.fail_writes(false);
+        storage.save(user.id.clone(), renamed.clone()).unwrap();
+        let reopened: FileStorage<UserId, User, _, _> = FileStorage::open_with(path, UserRecordCodec, &fs).unwrap();
+        assert_eq!(reopened.read(user.id.clone()).unwrap().name, renamed.name);
+    }
+
+    #[test]
+    fn encrypted_fields_hide_personal_data_in_files() {
+        let fs = MemoryFileSystem::new();
+        let path = Path::new("users.jsonl");
+        let crypto = AesGcmCrypto::new(&Secret::new("test-encryption-key"));
+        let codec = || EncryptedFields::new(UserRecordCodec, crypto.clone(), &["/user/email", "/user/address"]);
+        let plain = test_user("user1");
+        // 暗号化する前に書かれたファイルもそのまま読める
+        FileStorage::open_with(path, UserRecordCodec, &fs)
+            .unwrap()
+            .save(plain.id.clone(), plain.clone())
+            .unwrap();
+
+        let storage = FileStorage::open_with(path, codec(), &fs).unwrap();
+        assert!(storage.read(plain.id.clone()).unwrap().same_state_as(&plain));
+        let user = test_user("user2");
+        storage.save(user.id.clone(), user.clone()).unwrap();
+        let contents = fs.read(path).unwrap().unwrap();
+        assert!(!contents.contains("@example.com"));
+        assert!(contents.contains("\"address\":null"));
+
+        let storage: FileStorage<UserId, User, _, _> = FileStorage::open_with(path, codec(), &fs).unwrap();
+        assert!(storage.read(user.id.clone()).unwrap().same_state_as(&user));
+        assert!(FileStorage::<UserId, User, _, _>::open_with(path, UserRecordCodec, &fs).is_err());
+    }
+
+    #[test]
+    fn register_user_sends_welcome_mail_once() {
+        let app = TestWorld::new();
+        let user = app.register_user("user1", " User1@Example.com ").unwrap();
+        assert_eq!(user.email.as_str(), "user1@example.com");
+        assert!(app.user_queries().get_by_name(&user.name).unwrap().same_state_as(&user));
+        // メールは積んだジョブをワーカーが実行した時に送る
+        assert!(app.email_sender_component().sent().is_empty());
+        assert_eq!(app.work_jobs().unwrap().completed, 1);
+        let sent = app.email_sender_component().sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, user.email);
+
+        let taken = app.register_user("user1", "other@example.com").unwrap_err();
+        assert!(matches!(taken, DomainError::AlreadyExists { field: "name", .. }), "{}", taken);
+        assert!(app.register_user("user2", "user1@example.com").is_err());
+        let invalid = app.register_user("user2", "not an email").unwrap_err();
+        assert!(matches!(invalid, DomainError::Validation(_)), "{}", invalid);
+        assert_eq!(app.user_queries().list().unwrap().len(), 1);
+        assert_eq!(app.work_jobs().unwrap(), JobReport::default());
+        assert_eq!(app.email_sender_component().sent().len(), 1);
+    }
+
+    #[test]
+    fn background_jobs_retry_poison_and_survive_restart() {
+        let app = TestWorld::new();
+        // 送れない間は間を空けてやり直し、MAX_ATTEMPTS回失敗したら諦める
+        app.email_sender_component().refuse(true);
+        let user = app.register_user("user1", "user1@example.com").unwrap();
+        let retried = JobReport {
+            retried: 1,
+            ..JobReport::default()
+        };
+        assert_eq!(app.work_jobs().unwrap(), retried);
+        assert_eq!(app.work_jobs().unwrap(), JobReport::default());
+        for attempts in 1..MAX_ATTEMPTS - 1 {
+            app.time_component().advance(backoff(attempts));
+            assert_eq!(app.work_jobs().unwrap(), retried);
+        }
+        app.time_component().advance(backoff(MAX_ATTEMPTS - 1));
+        assert_eq!(app.work_jobs().unwrap().poisoned, 1);
+        assert!(app.job_queue_component().jobs().unwrap().is_empty());
+        let jobs = app.job_queue_component().dead_letters().unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].kind, JobKind::SendWelcomeMail { user_id: user.id.clone() });
+        assert_eq!((jobs[0].status, jobs[0].attempts), (JobStatus::Poisoned, MAX_ATTEMPTS));
+        assert_eq!(jobs[0].last_error.as_deref(), Some("connection refused"));
+        assert!(app.logging_component().records().iter().any(|(level, _)| *level == Level::Warn));
+        app.time_component().advance(Duration::days(1));
+        assert_eq!(app.work_jobs().unwrap(), JobReport::default());
+
+        // 読み込んだユーザーは、積まれたジョブが索引を作り直すまで検索に出てこない
+        app.email_sender_component().refuse(false);
+        let path = Path::new("import/users.csv");
+        app.file_system_component().write(path, "name,email
+carol,carol@example.com").unwrap();
+        app.import_users(path, ImportFormat::Csv, None).unwrap();
+        assert!(app.search_users("carol", 10).unwrap().is_empty());
+        assert_eq!(app.work_jobs().unwrap().completed, 1);
+        assert_eq!(app.search_users("carol", 10).unwrap()[0].name.as_str(), "carol");
+
+        // 実行し終える前にプロセスが止まったジョブは、開き直した時に積み直される
+        let fs = MemoryFileSystem::new();
+        let storage = |path| FileStorage::open_with(Path::new(path), JsonCodec, &fs).unwrap();
+        let open = || StoredJobQueue::new(storage("jobs.jsonl"), storage("jobs.dead.jsonl")).unwrap();
+        let now = app.time_component().now();
+        let job = Job::new(JobId::new(Uuid::new_v4()), JobKind::RebuildSearchIndex, now);
+        open().enqueue(job.clone()).unwrap();
+        let queue = open();
+        assert_eq!(queue.claim(now).unwrap().unwrap().id, job.id);
+        assert!(queue.claim(now).unwrap().is_none());
+        let claimed = open().claim(now).unwrap().unwrap();
+        assert_eq!((claimed.id, claimed.status), (job.id.clone(), JobStatus::Running));
+        open().complete(&job.id).unwrap();
+        assert!(open().jobs().unwrap().is_empty());
+
+        // 実行時刻の早い順に取り出し、諦めたジョブは開き直しても取り出さない
+        let later = Job::new(JobId::new(Uuid::new_v4()), JobKind::RebuildSearchIndex, now + Duration::minutes(1));
+        let sooner = Job::new(JobId::new(Uuid::new_v4()), JobKind::RebuildSearchIndex, now);
+        let queue = open();
+        queue.enqueue(later.clone()).unwrap();
+        queue.enqueue(sooner.clone()).unwrap();
+        assert_eq!(queue.claim(now).unwrap().unwrap().id, sooner.id);
+        assert!(queue.claim(now).unwrap().is_none());
+        queue.poison(&sooner.id, "broken").unwrap();
+        let queue = open();
+        assert_eq!(queue.dead_letters().unwrap()[0].id, sooner.id);
+        assert!(queue.claim(now + Duration::days(1)).unwrap().is_some());
+        assert!(queue.claim(now + Duration::days(1)).unwrap().is_none());
+    }
+
+    #[test]
+    fn update_email_rejects_addresses_of_other_users() {
+        let app = TestWorld::new();
+        let user1 = app.register_user("user1", "user1@example.com").unwrap();
+        let user2 = app.register_user("user2", "user2@example.com").unwrap();
+        app.time_component().advance(Duration::minutes(1));
+
+        let change = app.update_email(user1.id.clone(), "User1@Example.org").unwrap();
+        assert_eq!(change.old_email, user1.email);
+        assert_eq!(change.new_email.as_str(), "user1@example.org");
+        assert_eq!(change.update_time, user1.update_time + Duration::minutes(1));
+        let stored = app.user_queries().get(user1.id.clone()).unwrap();
+        assert_eq!(stored.email, change.new_email);
+        assert_eq!(stored.version, 2);
+        assert_eq!(app.user_queries().get_by_email(&change.new_email).unwrap().id, user1.id);
+        assert!(app.user_queries().get_by_email(&user1.email).is_err());
+
+        let taken = app.update_email(user1.id.clone(), "user2@example.com").unwrap_err();
+        assert!(taken.to_string().starts_with("email already taken"), "{}", taken);
+        assert_eq!(app.user_queries().get(user2.id.clone()).unwrap().version, 1);
+        assert!(app.update_email(UserId::new(Uuid::from_u128(99)), "user3@example.com").is_err());
+        assert!(app.update_email(user1.id.clone(), "not an email").is_err());
+        assert_eq!(app.user_queries().get(user1.id).unwrap().email, change.new_email);
+    }
+
+    #[test]
+    fn unique_email_service_ignores_the_user_being_changed() {
+        let app = TestWorld::new();
+        let user1 = app.register_user("user1", "user1@example.com").unwrap();
+        let service = app.unique_email_service();
+        assert!(service.is_email_taken(&user1.email, None).unwrap());
+        assert!(!service.is_email_taken(&user1.email, Some(&user1.id)).unwrap());
+        assert!(!service.is_email_taken(&Email::parse("user2@example.com").unwrap(), None).unwrap());
+
+        // 同じアドレスへの変更は通り、他人のアドレスは型付きのエラーになる
+        assert!(app.update_email(user1.id.clone(), "user1@example.com").is_ok());
+        app.register_user("user2", "user2@example.com").unwrap();
+        let taken = Email::parse("user2@example.com").unwrap();
+        let err = app.update_email(user1.id.clone(), taken.as_str()).unwrap_err();
+        match err {
+            DomainError::AlreadyExists { field, value } => {
+                assert_eq!((field, value), ("email", format!("{:?}", taken)))
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        let taken = app.register_user("user3", "user1@example.com").unwrap_err();
+        assert!(matches!(taken, DomainError::AlreadyExists { field: "email", .. }), "{}", taken);
+    }
+
+    #[test]
+    fn use_case_errors_map_to_presentation_errors() {
+        let app = TestWorld::new();
+        let user1 = app.register_user("user1", "user1@example.com").unwrap();
+        let present = |e: DomainError| PresentationError::from(e);
+
+        let e = present(app.register_user("", "user2@example.com").unwrap_err());
+        assert_eq!((e.kind, e.status_code()), (ErrorKind::Validation, 422));
+        assert_eq!(e.message, "name must not be empty");
+        let e = present(app.register_user("user2", "user1@example.com").unwrap_err());
+        assert_eq!((e.kind, e.status_code()), (ErrorKind::Conflict, 409));
+        let e = present(app.user_queries().get(UserId::new(Uuid::from_u128(99))).unwrap_err());
+        assert_eq!((e.kind, e.status_code(), e.exit_code()), (ErrorKind::NotFound, 404, 66));
+        let missing = Email::parse("user2@example.com").unwrap();
+        let e = present(app.user_queries().get_by_email(&missing).unwrap_err());
+        assert_eq!(e.kind, ErrorKind::NotFound);
+        app.user_commands().suspend(user1.id.clone()).unwrap();
+        assert_eq!(present(app.user_commands().suspend(user1.id).unwrap_err()).kind, ErrorKind::Conflict);
+
+        // 型の分からないエラーは内容を見せない
+        let e = present(format_err!("connection refused: 10.0.0.1:5432").into());
+        assert_eq!((e.kind, e.status_code()), (ErrorKind::Internal, 500));
+        assert_eq!(e.to_string(), "internal error");
+        let e = PresentationError::from(Error::from(PresentationError::new(ErrorKind::Forbidden, "no")));
+        assert_eq!(e, PresentationError::new(ErrorKind::Forbidden, "no"));
+    }
+
+    #[test]
+    fn delete_account_requires_a_fresh_confirmation_token() {
+        let app = TestWorld::new();
+        let user = app.register_user("user1", "user1@example.com").unwrap();
+        let other = app.register_user("user2", "user2@example.com").unwrap();
+        for user_id in &[&user.id, &user.id, &other.id] {
+            app.session_repository().create_session((*user_id).clone(), Duration::hours(1)).unwrap();
+        }
+
+        let token = app.request_account_deletion(user.id.clone()).unwrap();
+        assert_ne!(app.request_account_deletion(user.id.clone()).unwrap(), token);
+        let (user_key, other_key) = (user.id.as_uuid().simple().to_string(), other.id.as_uuid().simple().to_string());
+        assert!(app.confirm_account_deletion(&token.replacen(&user_key, &other_key, 1)).is_err());
+        assert!(app.confirm_account_deletion("garbage").is_err());
+
+        let deleted = app.confirm_account_deletion(&token).unwrap();
+        assert_eq!(deleted.status, UserStatus::Deactivated);
+        let sessions = app.session_repository().list().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].user_id, other.id);
+        assert!(app.confirm_account_deletion(&token).is_err());
+        assert!(app.request_account_deletion(user.id.clone()).is_err());
+
+        let token = app.request_account_deletion(other.id.clone()).unwrap();
+        app.time_component().advance(Duration::minutes(CONFIRMATION_TTL_MINUTES + 1));
+        assert!(app.confirm_account_deletion(&token).is_err());
+        assert!(app.user_queries().get(other.id.clone()).unwrap().is_active());
+        assert_eq!(app.session_repository().list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn list_users_pages_sorted_summaries() {
+        let config = Config {
+            page_size: 2,
+            ..Config::default()
+        };
+        let app = TestWorld::new().with_config(config);
+        for name in &["carol", "alice", "bob"] {
+            app.register_user(name, &format!("{}@example.com", name)).unwrap();
+            app.time_component().advance(Duration::minutes(1));
+        }
+        let names =
+            |page: &Page<UserSummaryDto>| -> Vec<String> { page.items.iter().map(|u| u.name.clone()).collect() };
+
+        let first = app.list_users(ListUsersQuery::default()).unwrap();
+        assert_eq!(names(&first), vec!["carol", "alice"]);
+        assert_eq!((first.per_page, first.total, first.total_pages()), (2, 3, 2));
+        assert_eq!(first.items[0].email, "carol@example.com");
+        assert_eq!((first.items[0].role.as_str(), first.items[0].status.as_str()), ("member", "active"));
+
+        let query = ListUsersQuery {
+            page: 2,
+            sort: UserSort::Name,
+            order: SortOrder::Descending,
+            ..ListUsersQuery::default()
+        };
+        assert_eq!(names(&app.list_users(query).unwrap()), vec!["alice"]);
+        let query = ListUsersQuery {
+            per_page: Some(10),
+            sort: UserSort::Name,
+            ..ListUsersQuery::default()
+        };
+        assert_eq!(names(&app.list_users(query).unwrap()), vec!["alice", "bob", "carol"]);
+        assert!(app.list_users(ListUsersQuery { page: 3, ..ListUsersQuery::default() }).unwrap().items.is_empty());
+        assert!(app.list_users(ListUsersQuery { page: 0, ..ListUsersQuery::default() }).is_err());
+    }
+
+    #[test]
+    fn authenticate_user_distinguishes_failures() {
+        let app = TestWorld::new();
+        let user = app.register_user("user1", "user1@example.com").unwrap();
+        app.credential_repository().set_password(user.id.clone(), "secret").unwrap();
+        let error = |result: Result<Session, DomainError>| -> AuthenticationError {
+            *result.unwrap_err().reason().unwrap()
+        };
+
+        let session = app.authenticate_user("user1", "secret").unwrap();
+        assert_eq!(session.user_id, user.id);
+        assert!(app.session_repository().validate_session(session.id).is_ok());
+        assert_eq!(error(app.authenticate_user("user1", "wrong")), AuthenticationError::InvalidCredentials);
+        assert_eq!(error(app.authenticate_user("nobody", "secret")), AuthenticationError::InvalidCredentials);
+
+        app.user_commands().suspend(user.id.clone()).unwrap();
+        assert_eq!(error(app.authenticate_user("user1", "wrong")), AuthenticationError::InvalidCredentials);
+        let suspended = error(app.authenticate_user("user1", "secret"));
+        assert_eq!(suspended, AuthenticationError::Inactive(UserStatus::Suspended));
+
+        app.user_commands().reactivate(user.id.clone()).unwrap();
+        assert!(app.authenticate_user("user1", "secret").is_ok());
+        // 5回続けて試行したので、1分経つまでは正しいパスワードでも試行できない。大文字小文字は区別しない。
+        let limited = error(app.authenticate_user("User1", "secret"));
+        assert_eq!(limited, AuthenticationError::RateLimited { retry_after: Duration::minutes(1) });
+        app.time_component().advance(Duration::minutes(1));
+        assert!(app.authenticate_user("user1", "secret").is_ok());
+    }
+
+    #[test]
+    fn change_password_keeps_only_the_current_session() {
+        let app = TestWorld::new();
+        let user = app.register_user("user1", "user1@example.com").unwrap();
+        let other = app.register_user("user2", "user2@example.com").unwrap();
+        app.credential_repository().set_password(user.id.clone(), "old-secret1").unwrap();
+        let current = app.session_repository().create_session(user.id.clone(), Duration::hours(1)).unwrap();
+        let stale = app.session_repository().create_session(user.id.clone(), Duration::hours(1)).unwrap();
+        let others = app.session_repository().create_session(other.id.clone(), Duration::hours(1)).unwrap();
+
+        let wrong = app.change_password(current.id.clone(), "wrong", "new-secret1").unwrap_err();
+        assert_eq!(wrong.reason(), Some(&AuthenticationError::InvalidCredentials));
+        let weak = app.change_password(current.id.clone(), "old-secret1", "password").unwrap_err();
+        let rule = "password_letters_and_digits".to_string();
+        match weak {
+            DomainError::Validation(e) => assert_eq!(e, ValidationError::RuleViolated(rule)),
+            e => panic!("unexpected error: {}", e),
+        }
+        assert!(app.change_password(current.id.clone(), "old-secret1", "short1").is_err());
+        assert_eq!(app.session_repository().list().unwrap().len(), 3);
+
+        assert_eq!(app.change_password(current.id.clone(), "old-secret1", "new-secret1").unwrap(), 1);
+        assert!(app.credential_repository().verify_password(user.id.clone(), "new-secret1").unwrap());
+        assert!(!app.credential_repository().verify_password(user.id.clone(), "old-secret1").unwrap());
+        assert!(app.session_repository().validate_session(current.id.clone()).is_ok());
+        assert!(app.session_repository().validate_session(stale.id).is_err());
+        assert!(app.session_repository().validate_session(others.id).is_ok());
+        assert!(!format!("{:?}", PlainPassword::new("new-secret1")).contains("secret"));
+    }
+
+    #[test]
+    fn password_reset_token_is_single_use_and_expires() {
+        let app = TestWorld::new();
+        let user = app.register_user("user1", "user1@example.com").unwrap();
+        app.work_jobs().unwrap();
+        app.credential_repository().set_password(user.id.clone(), "old-secret1").unwrap();
+        let session = app.session_repository().create_session(user.id.clone(), Duration::hours(1)).unwrap();
+        let reset_token = |app: &TestWorld| -> String {
+            let body = app.email_sender_component().sent().last().unwrap().body.clone();
+            let start = body.find("token=").unwrap() + "token=".len();
+            body[start..].split('"').next().unwrap().to_string()
+        };
+
+        // 登録されていないメールアドレスでもエラーにはならず、メールも送らない
+        app.request_password_reset("nobody@example.com", "https://example.com/reset").unwrap();
+        assert_eq!(app.email_sender_component().sent().len(), 1);
+
+        app.request_password_reset("user1@example.com", "https://example.com/reset").unwrap();
+        let first = reset_token(&app);
+        app.request_password_reset("user1@example.com", "https://example.com/reset").unwrap();
+        let token = reset_token(&app);
+        assert_eq!(app.email_sender_component().sent().len(), 3);
+        assert!(app.confirm_password_reset(&first, "new-secret1").is_err());
+        assert!(app.confirm_password_reset(&token, "short1").is_err());
+
+        assert_eq!(app.confirm_password_reset(&token, "new-secret1").unwrap(), 1);
+        assert!(app.credential_repository().verify_password(user.id.clone(), "new-secret1").unwrap());
+        assert!(app.session_repository().validate_session(session.id).is_err());
+        assert!(app.confirm_password_reset(&token, "new-secret2").is_err());
+
+        app.request_password_reset("user1@example.com", "https://example.com/reset").unwrap();
+        let expired = reset_token(&app);
+        app.time_component().advance(Duration::minutes(PASSWORD_RESET_TTL_MINUTES));
+        assert!(app.confirm_password_reset(&expired, "new-secret2").is_err());
+        assert!(app.password_reset_repository().list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn accepted_invitation_becomes_a_user_with_the_invited_role() {
+        let app = TestWorld::new();
+        let member = app.register_user("member", "member@example.com").unwrap();
+        let admin = app.register_user("admin", "admin@example.com").unwrap();
+        app.user_commands().change_role(admin.id.clone(), Role::Admin).unwrap();
+        let accept_url = "https://example.com/invitation";
+        let invitation_token = |app: &TestWorld| -> String {
+            let body = app.email_sender_component().sent().last().unwrap().body.clone();
+            let start = body.find("token=").unwrap() + "token=".len();
+            body[start..].split('"').next().unwrap().to_string()
+        };
+
+        assert!(app.invite_user(member.id.clone(), "new@example.com", Role::Admin, accept_url).is_err());
+        assert!(app.invite_user(admin.id.clone(), "member@example.com", Role::Member, accept_url).is_err());
+        let invitation = app.invite_user(admin.id.clone(), "new@example.com", Role::Admin, accept_url).unwrap();
+        assert_eq!(invitation.invited_by, admin.id);
+        assert_eq!(InvitationDto::from(&invitation).create_time, app.time_component().now());
+        let mail = app.email_sender_component().sent().last().unwrap().clone();
+        assert_eq!(mail.to, Email::parse("new@example.com").unwrap());
+        assert!(mail.subject.starts_with(&format!("{}.subject", INVITATION)));
+        let token = invitation_token(&app);
+
+        // パスワードがポリシーに合わなければユーザーは作らず、招待も残す
+        assert!(app.accept_invitation(&token, "newcomer", "short1").is_err());
+        assert!(app.accept_invitation(&token, "member", "new-secret1").is_err());
+        assert!(app.user_queries().get_by_email(&invitation.email).is_err());
+
+        let user = app.accept_invitation(&token, "newcomer", "new-secret1").unwrap();
+        assert_eq!(user.email, invitation.email);
+        assert_eq!(user.role, Role::Admin);
+        assert!(app.credential_repository().verify_password(user.id.clone(), "new-secret1").unwrap());
+        assert!(app.accept_invitation(&token, "newcomer2", "new-secret1").is_err());
+        assert!(app.invitation_repository().list().unwrap().is_empty());
+
+        app.invite_user(admin.id.clone(), "late@example.com", Role::Guest, accept_url).unwrap();
+        let expired = invitation_token(&app);
+        app.time_component().advance(Duration::days(INVITATION_TTL_DAYS));
+        assert!(app.accept_invitation(&expired, "late", "new-secret1").is_err());
+    }
+
+    #[test]
+    fn interactors_run_behind_the_same_use_case_interface() {
+        let app = TestWorld::new();
+        let new_user = |name: &str| NewUser {
+            name: name.to_string(),
+            email: format!("{}@example.com", name),
+        };
+        {
+            let mut register_user = RegisterUserInteractor::new(&app).logged();
+            assert_eq!(register_user.execute(new_user("user1")).unwrap().name.as_str(), "user1");
+            assert!(register_user.execute(new_user("user1")).is_err());
+        }
+        let records: Vec<_> = app
+            .logging_component()
+            .records()
+            .into_iter()
+            .filter(|(_, message)| message.starts_with("register_user"))
+            .collect();
+        assert_eq!(records[0], (Level::Info, "register_user succeeded".to_string()));
+        assert_eq!(records[1].0, Level::Warn);
+        assert!(records[1].1.starts_with("register_user failed: name already taken"));
+
+        let user = app.user_queries().get_by_name(&Name::new("user1").unwrap()).unwrap();
+        let input = EmailUpdate {
+            id: user.id.clone(),
+            email: "renamed@example.com".to_string(),
+        };
+        let change = {
+            let mut update_email: Box<dyn UseCase<Input = EmailUpdate, Output = EmailChange, Error = DomainError>> =
+                Box::new(UpdateEmailInteractor::new(&app));
+            update_email.execute(input).unwrap()
+        };
+        assert_eq!(change.old_email, user.email);
+
+        let page = ListUsersInteractor::new(&app).execute(ListUsersQuery::default()).unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.items[0].email, "renamed@example.com");
+    }
+
+    #[test]
+    fn use_case_outputs_are_mapped_to_dtos() {
+        let app = TestWorld::new();
+        let admin = app.register_user("admin", "admin@example.com").unwrap();
+        app.user_commands().change_role(admin.id.clone(), Role::Admin).unwrap();
+        let admin = User {
+            address: Some(Address::new("jp", "Tokyo", "100-0001").unwrap()),
+            ..app.user_queries().get(admin.id).unwrap()
+        };
+        let dto = UserDto::from(&admin);
+        assert_eq!(dto.id, admin.id.as_uuid().to_string());
+        assert_eq!((dto.role.as_str(), dto.status.as_str(), dto.version), ("admin", "active", 2));
+        let address = dto.address.unwrap();
+        assert_eq!((address.country.as_str(), address.postal_code.as_str()), ("JP", "100-0001"));
+        assert_eq!(UserSummaryDto::from(&admin).email, "admin@example.com");
+
+        // トークンのハッシュはDTOに入らない
+        let invitation = InviteUserInteractor::new(&app)
+            .execute(NewInvitation {
+                inviter: admin.id.clone(),
+                email: "new@example.com".to_string(),
+                role: Role::Member,
+                accept_url: "https://example.com/invitation".to_string(),
+            })
+            .unwrap();
+        assert_eq!((invitation.email.as_str(), invitation.role.as_str()), ("new@example.com", "member"));
+        let json = serde_json::to_value(&invitation).unwrap();
+        assert!(json.get("token_hash").is_none());
+        assert_eq!(json["invited_by"], dto.id);
+    }
+
+    #[test]
+    fn decorators_check_permissions_and_record_metrics() {
+        let app = TestWorld::new();
+        let admin = app.register_user("admin", "admin@example.com").unwrap();
+        app.user_commands().change_role(admin.id.clone(), Role::Admin).unwrap();
+        let guest = app.register_user("guest", "guest@example.com").unwrap();
+        app.user_commands().change_role(guest.id.clone(), Role::Guest).unwrap();
+        let list_users = |app: &TestWorld, actor: &UserId| {
+            ListUsersInteractor::new(app)
+                .authorized(actor.clone(), Permission::ListUsers)
+                .metered()
+                .execute(ListUsersQuery::default())
+        };
+
+        let denied = list_users(&app, &guest.id).unwrap_err();
+        let expected = PermissionDenied {
+            actor: guest.id.clone(),
+            permission: Permission::ListUsers,
+            use_case: "list_users",
+        };
+        assert_eq!(denied.reason(), Some(&expected));
+        assert_eq!(list_users(&app, &admin.id).unwrap().total, 2);
+        // 権限があっても、停止中のユーザーは実行できない
+        app.user_commands().suspend(admin.id.clone()).unwrap();
+        assert!(list_users(&app, &admin.id).is_err());
+
+        assert_eq!(app.metrics_component().counter("usecase.list_users.ok"), 1);
+        assert_eq!(app.metrics_component().counter("usecase.list_users.error"), 2);
+        assert_eq!(app.metrics_component().observations("usecase.list_users.seconds").len(), 3);
+
+        let real = RealWorld::with_cache_policy(CachePolicy::WriteThrough);
+        let member = real
+            .register_user_use_case()
+            .execute(NewUser {
+                name: "member".to_string(),
+                email: "member@example.com".to_string(),
+            })
+            .unwrap();
+        let member_id = UserId::new(Uuid::parse_str(&member.id).unwrap());
+        assert_eq!(real.list_users_use_case(member_id).execute(ListUsersQuery::default()).unwrap().total, 1);
+    }
+
+    #[test]
+    fn transactional_use_case_rolls_back_every_change_on_failure() {
+        /// ユーザーを作った後で失敗するユースケース
+        struct CreateThenFail<'a>(&'a TestWorld);
+
+        impl<'a> UseCase for CreateThenFail<'a> {
+            type Input = &'static str;
+            type Output = ();
+            type Error = Error;
+            fn execute(&mut self, name: &'static str) -> Result<(), Error> {
+                let email = Email::parse(&format!("{}@example.com", name))?;
+                self.0.user_commands().create(Name::new(name)?, email)?;
+                bail!("failed after creating {}", name)
+            }
+        }
+
+        impl<'a> Interactor for CreateThenFail<'a> {
+            type World = TestWorld;
+            const NAME: &'static str = "create_then_fail";
+            fn world(&self) -> &TestWorld {
+                self.0
+            }
+        }
+
+        let app = TestWorld::new();
+        assert!(CreateThenFail(&app).transactional().execute("user1").is_err());
+        assert!(app.user_queries().get_by_name(&Name::new("user1").unwrap()).is_err());
+        assert!(CreateThenFail(&app).execute("user2").is_err());
+        assert!(app.user_queries().get_by_name(&Name::new("user2").unwrap()).is_ok());
+
+        // 中で `transaction` を使うユースケースも、外側のトランザクションに加わって実行できる
+        let user = app.register_user("user3", "user3@example.com").unwrap();
+        app.credential_repository().set_password(user.id.clone(), "old-secret1").unwrap();
+        let session = app.session_repository().create_session(user.id.clone(), Duration::hours(1)).unwrap();
+        let change = PasswordChange {
+            session_id: session.id,
+            old: PlainPassword::new("old-secret1"),
+            new: PlainPassword::new("new-secret1"),
+        };
+        assert_eq!(ChangePasswordInteractor::new(&app).transactional().execute(change).unwrap(), 0);
+        assert!(app.credential_repository().verify_password(user.id, "new-secret1").unwrap());
+        assert!(!app.in_transaction());
+    }
+
+    #[test]
+    fn http_api_serves_users_through_the_use_cases() {
+        let world = Arc::new(gateway_world());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let call = |method: &str, uri: &str, actor: Option<&str>, body: Option<Value>| -> (StatusCode, Value) {
+            let mut request = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+            if let Some(actor) = actor {
+                request = request.header(ACTOR_HEADER, actor);
+            }
+            let body = body.map(|body| Body::from(body.to_string())).unwrap_or_else(Body::empty);
+            let response = runtime.block_on(app.clone().oneshot(request.body(body).unwrap())).unwrap();
+            let status = response.status();
+            let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+            (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+        };
+        let new_user = |name: &str| Some(json!({ "name": name, "email": format!("{}@example.com", name) }));
+
+        let (status, admin) = call("POST", "/users", None, new_user("admin"));
+        assert_eq!(status, StatusCode::CREATED);
+        let admin = admin["id"].as_str().unwrap().to_string();
+        let admin_id = UserId::new(Uuid::parse_str(&admin).unwrap());
+        world.user_commands().change_role(admin_id, Role::Admin).unwrap();
+        let (_, member) = call("POST", "/users", None, new_user("member"));
+        let member = member["id"].as_str().unwrap().to_string();
+        let (status, body) = call("POST", "/users", None, new_user("member"));
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body["error"].as_str().unwrap().starts_with("name already taken"));
+        assert_eq!(call("POST", "/users", None, new_user(" ")).0, StatusCode::UNPROCESSABLE_ENTITY);
+
+        assert_eq!(call("GET", "/users", None, None).0, StatusCode::UNAUTHORIZED);
+        let (status, page) = call("GET", "/users?per_page=1", Some(&member), None);
+        assert_eq!((status, page["total"].as_u64()), (StatusCode::OK, Some(2)));
+        assert_eq!(page["items"].as_array().unwrap().len(), 1);
+        let (status, user) = call("GET", &format!("/users/{}", member), Some(&admin), None);
+        assert_eq!((status, user["name"].as_str()), (StatusCode::OK, Some("member")));
+        assert_eq!(call("GET", "/users/unknown", Some(&admin), None).0, StatusCode::NOT_FOUND);
+        let missing = format!("/users/{}", Uuid::from_u128(99));
+        assert_eq!(call("GET", &missing, Some(&admin), None).0, StatusCode::NOT_FOUND);
+
+        let uri = format!("/users/{}", member);
+        let rename = Some(json!({ "name": "renamed" }));
+        assert_eq!(call("PATCH", &uri, Some(&member), rename.clone()).0, StatusCode::FORBIDDEN);
+        let (status, user) = call("PATCH", &uri, Some(&admin), rename);
+        assert_eq!((status, user["name"].as_str()), (StatusCode::OK, Some("renamed")));
+
+        // 退会は本人が確認用のトークンを受け取ってから確定する
+        assert_eq!(call("DELETE", &uri, Some(&admin), None).0, StatusCode::FORBIDDEN);
+        let (status, body) = call("DELETE", &uri, Some(&member), None);
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let token = body["confirmation_token"].as_str().unwrap().to_string();
+        let (status, user) = call("DELETE", &format!("{}?confirmation_token={}", uri, token), Some(&member), None);
+        assert_eq!((status, user["status"].as_str()), (StatusCode::OK, Some("deactivated")));
+    }
+
+    #[test]
+    fn http_registration_records_the_signup_region_of_the_client() {
+        let world = Arc::new(gateway_world());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let register = |name: &str, client: Option<SocketAddr>| -> UserId {
+            let body = json!({ "name": name, "email": format!("{}@example.com", name) });
+            let request = Request::builder().method("POST").uri("/users");
+            let mut request = request.header("content-type", "application/json");
+            if let Some(client) = client {
+                request = request.extension(ConnectInfo(client));
+            }
+            let response = runtime.block_on(app.clone().oneshot(request.body(Body::from(body.to_string())).unwrap()));
+            let response = response.unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+            let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+            let user: Value = serde_json::from_slice(&bytes).unwrap();
+            UserId::new(Uuid::parse_str(user["id"].as_str().unwrap()).unwrap())
+        };
+
+        // データベースが設定されていないので場所は引けないが、接続元が分かればプロフィールに記録する
+        let alice = register("alice", Some("203.0.113.7:50000".parse().unwrap()));
+        let profile = world.profile_repository().get(alice).unwrap();
+        assert_eq!((profile.signup_country, profile.signup_city), (None, None));
+        let bob = register("bob", None);
+        assert!(world.profile_repository().get(bob).is_err());
+    }
+
+    #[test]
+    fn http_api_authenticates_sessions_and_api_tokens() {
+        // `x-user-id` は `LAYERED_TRUST_ACTOR_HEADER=1` で信じる事にした時だけ見る
+        let config = Config::default();
+        assert!(!config.trust_actor_header());
+        let trusting = |value: &str| {
+            let value = value.to_string();
+            Config::default().override_with(|key| Some(value.clone()).filter(|_| key == "LAYERED_TRUST_ACTOR_HEADER"))
+        };
+        assert!(trusting("1").unwrap().trust_actor_header());
+        assert!(!trusting("0").unwrap().trust_actor_header());
+        assert!(trusting("yes").is_err());
+        let world = Arc::new(RealWorld::with_config(config, CachePolicy::WriteThrough).unwrap());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let call = |method: &str, uri: &str, header: Option<(&str, &str)>, body: Option<Value>| -> StatusCode {
+            let mut request = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+            if let Some((name, value)) = header {
+                request = request.header(name, value);
+            }
+            let body = body.map(|body| Body::from(body.to_string())).unwrap_or_else(Body::empty);
+            runtime.block_on(app.clone().oneshot(request.body(body).unwrap())).unwrap().status()
+        };
+
+        // 登録は認証しなくても呼べる
+        let alice = json!({ "name": "alice", "email": "alice@example.com" });
+        assert_eq!(call("POST", "/users", None, Some(alice)), StatusCode::CREATED);
+        let alice = world.user_queries().get_by_name(&Name::new("alice").unwrap()).unwrap().id;
+        world.user_commands().change_role(alice.clone(), Role::Admin).unwrap();
+        let uri = format!("/users/{}", alice.as_uuid());
+        let rename = |name: &str| Some(json!({ "name": name }));
+
+        // 既定の設定では `x-user-id` だけでは認証できず、変更系のルートはハンドラの前で断る
+        let actor = alice.as_uuid().to_string();
+        assert_eq!(call("GET", &uri, Some((ACTOR_HEADER, &actor)), None), StatusCode::UNAUTHORIZED);
+        assert_eq!(call("PATCH", &uri, None, rename("alicia")), StatusCode::UNAUTHORIZED);
+
+        let session = world.session_repository().create_session(alice.clone(), Duration::hours(1));
+        let cookie = format!("theme=dark; {}={}", http::SESSION_COOKIE, session.unwrap().id.as_uuid());
+        assert_eq!(call("GET", &uri, Some(("cookie", &cookie)), None), StatusCode::OK);
+        assert_eq!(call("PATCH", &uri, Some(("cookie", &cookie)), rename("alicia")), StatusCode::OK);
+        let forged = format!("{}={}", http::SESSION_COOKIE, Uuid::new_v4());
+        assert_eq!(call("GET", &uri, Some(("cookie", &forged)), None), StatusCode::UNAUTHORIZED);
+
+        // APIトークンは許されている操作だけできる
+        let issue = |scopes: &[Scope]| {
+            let (_, plain) = world.api_token_repository().issue_token(alice.clone(), scopes, None).unwrap();
+            format!("Bearer {}", plain)
+        };
+        let read_only = issue(&[Scope::ReadUsers]);
+        assert_eq!(call("GET", &uri, Some(("authorization", &read_only)), None), StatusCode::OK);
+        assert_eq!(call("PATCH", &uri, Some(("authorization", &read_only)), rename("al")), StatusCode::FORBIDDEN);
+        let writer = issue(&[Scope::ReadUsers, Scope::WriteUsers]);
+        assert_eq!(call("PATCH", &uri, Some(("authorization", &writer)), rename("al")), StatusCode::OK);
+        assert_eq!(call("GET", &uri, Some(("authorization", "Bearer garbage")), None), StatusCode::UNAUTHORIZED);
+        assert_eq!(call("GET", &uri, Some(("authorization", "Basic YWxpY2U=")), None), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn http_issues_api_tokens_for_the_caller() {
+        let world = Arc::new(RealWorld::with_config(Config::default(), CachePolicy::WriteThrough).unwrap());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let call = |method: &str, uri: &str, header: (&str, &str), body: Option<Value>| -> (StatusCode, Value) {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("content-type", "application/json")
+                .header(header.0, header.1);
+            let body = body.map(|body| Body::from(body.to_string())).unwrap_or_else(Body::empty);
+            let response = runtime.block_on(app.clone().oneshot(request.body(body).unwrap())).unwrap();
+            let status = response.status();
+            let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+            (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+        };
+        let new_user = NewUser {
+            name: "alice".to_string(),
+            email: "alice@example.com".to_string(),
+        };
+        let alice = world.user_controller().register(new_user).unwrap().id;
+        let alice_id = UserId::new(Uuid::parse_str(&alice).unwrap());
+        let session = world.session_repository().create_session(alice_id, Duration::hours(1)).unwrap();
+        let cookie = format!("{}={}", http::SESSION_COOKIE, session.id.as_uuid());
+        let uri = format!("/users/{}", alice);
+
+        // 発行したトークンはすぐに使え、付けたスコープの操作だけできる
+        let body = json!({ "scopes": ["read_users"], "ttl_days": 30 });
+        let (status, issued) = call("POST", "/api-tokens", ("cookie", &cookie), Some(body));
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(issued["scopes"], json!(["read_users"]));
+        assert!(issued["expires_at"].is_string());
+        let bearer = format!("Bearer {}", issued["token"].as_str().unwrap());
+        assert_eq!(call("GET", &uri, ("authorization", &bearer), None).0, StatusCode::OK);
+        let rename = json!({ "name": "alicia" });
+        assert_eq!(call("PATCH", &uri, ("authorization", &bearer), Some(rename)).0, StatusCode::FORBIDDEN);
+
+        // 発行は変更なのでwrite_usersが要り、トークンからそのトークンより強いトークンは作れない
+        let body = json!({ "scopes": ["read_users"] });
+        assert_eq!(call("POST", "/api-tokens", ("authorization", &bearer), Some(body)).0, StatusCode::FORBIDDEN);
+        let body = json!({ "scopes": ["read_users", "write_users"] });
+        let (_, writer) = call("POST", "/api-tokens", ("cookie", &cookie), Some(body));
+        let writer = format!("Bearer {}", writer["token"].as_str().unwrap());
+        let body = json!({ "scopes": ["admin"] });
+        assert_eq!(call("POST", "/api-tokens", ("authorization", &writer), Some(body)).0, StatusCode::FORBIDDEN);
+        let body = json!({ "scopes": ["read_users"] });
+        let (status, issued) = call("POST", "/api-tokens", ("authorization", &writer), Some(body));
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(issued["expires_at"].is_null());
+
+        let invalid = [
+            json!({ "scopes": [] }),
+            json!({ "scopes": ["root"] }),
+            json!({ "scopes": ["admin"], "ttl_days": 0 }),
+        ];
+        for body in invalid {
+            let (status, _) = call("POST", "/api-tokens", ("cookie", &cookie), Some(body.clone()));
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+        }
+        let body = json!({ "scopes": ["read_users"] });
+        assert_eq!(call("POST", "/api-tokens", ("theme", "dark"), Some(body)).0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn http_login_creates_a_session_with_the_password() {
+        let world = Arc::new(RealWorld::with_config(Config::default(), CachePolicy::WriteThrough).unwrap());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let call = |method: &str, uri: &str, header: Option<(&str, &str)>, body: Option<Value>| -> (StatusCode, Value) {
+            let mut request = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+            if let Some((name, value)) = header {
+                request = request.header(name, value);
+            }
+            let body = body.map(|body| Body::from(body.to_string())).unwrap_or_else(Body::empty);
+            let response = runtime.block_on(app.clone().oneshot(request.body(body).unwrap())).unwrap();
+            let status = response.status();
+            let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+            (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+        };
+        let new_user = NewUser {
+            name: "alice".to_string(),
+            email: "alice@example.com".to_string(),
+        };
+        let alice = world.user_controller().register(new_user).unwrap().id;
+        let alice_id = UserId::new(Uuid::parse_str(&alice).unwrap());
+        world.credential_repository().set_password(alice_id.clone(), "correct horse 1").unwrap();
+        let login = |password: &str| Some(json!({ "name": "alice", "password": password }));
+
+        let (status, body) = call("POST", "/sessions", None, login("wrong password 1"));
+        assert_eq!((status, body["error"].as_str()), (StatusCode::UNAUTHORIZED, Some("invalid name or password")));
+        let (status, session) = call("POST", "/sessions", None, login("correct horse 1"));
+        assert_eq!((status, session["user_id"].as_str()), (StatusCode::CREATED, Some(alice.as_str())));
+
+        // 作ったセッションで認証できる
+        let cookie = format!("{}={}", http::SESSION_COOKIE, session["id"].as_str().unwrap());
+        let uri = format!("/users/{}", alice);
+        assert_eq!(call("GET", &uri, Some(("cookie", &cookie)), None).0, StatusCode::OK);
+
+        world.user_commands().suspend(alice_id).unwrap();
+        let (status, body) = call("POST", "/sessions", None, login("correct horse 1"));
+        assert_eq!((status, body["error"].as_str()), (StatusCode::UNAUTHORIZED, Some("user is Suspended")));
+    }
+
+    #[test]
+    fn http_logout_revokes_the_session_cookie() {
+        let world = Arc::new(RealWorld::with_config(Config::default(), CachePolicy::WriteThrough).unwrap());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let call = |method: &str, uri: &str, cookie: Option<&str>, body: Option<Value>| -> (StatusCode, String, Value) {
+            let mut request = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+            if let Some(cookie) = cookie {
+                request = request.header("cookie", cookie);
+            }
+            let body = body.map(|body| Body::from(body.to_string())).unwrap_or_else(Body::empty);
+            let response = runtime.block_on(app.clone().oneshot(request.body(body).unwrap())).unwrap();
+            let status = response.status();
+            let set_cookie = response
+                .headers()
+                .get("set-cookie")
+                .map(|value| value.to_str().unwrap().to_string())
+                .unwrap_or_default();
+            let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+            (status, set_cookie, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+        };
+        let new_user = NewUser {
+            name: "alice".to_string(),
+            email: "alice@example.com".to_string(),
+        };
+        let alice = world.user_controller().register(new_user).unwrap().id;
+        let alice_id = UserId::new(Uuid::parse_str(&alice).unwrap());
+        world.credential_repository().set_password(alice_id, "correct horse 1").unwrap();
+
+        // ログインするとセッションのIDがCookieで返る
+        let login = json!({ "name": "alice", "password": "correct horse 1" });
+        let (status, set_cookie, session) = call("POST", "/sessions", None, Some(login));
+        assert_eq!(status, StatusCode::CREATED);
+        let cookie = format!("{}={}", http::SESSION_COOKIE, session["id"].as_str().unwrap());
+        assert!(set_cookie.starts_with(&format!("{};", cookie)), "{}", set_cookie);
+        assert!(set_cookie.contains("HttpOnly"), "{}", set_cookie);
+        let uri = format!("/users/{}", alice);
+        assert_eq!(call("GET", &uri, Some(&cookie), None).0, StatusCode::OK);
+
+        // ログアウトするとCookieが消され、同じセッションでは認証できなくなる
+        let (status, set_cookie, _) = call("DELETE", "/sessions/current", Some(&cookie), None);
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(set_cookie.contains("Max-Age=0"), "{}", set_cookie);
+        assert_eq!(call("GET", &uri, Some(&cookie), None).0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call("DELETE", "/sessions/current", None, None).0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn http_password_change_revokes_the_other_sessions() {
+        let world = Arc::new(RealWorld::with_config(Config::default(), CachePolicy::WriteThrough).unwrap());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let call = |method: &str, uri: &str, cookie: Option<&str>, body: Value| -> (StatusCode, Value) {
+            let mut request = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+            if let Some(cookie) = cookie {
+                request = request.header("cookie", cookie);
+            }
+            let response = runtime.block_on(app.clone().oneshot(request.body(Body::from(body.to_string())).unwrap()));
+            let response = response.unwrap();
+            let status = response.status();
+            let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+            (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+        };
+        let new_user = NewUser {
+            name: "alice".to_string(),
+            email: "alice@example.com".to_string(),
+        };
+        let alice = UserId::new(Uuid::parse_str(&world.user_controller().register(new_user).unwrap().id).unwrap());
+        world.credential_repository().set_password(alice, "correct horse 1").unwrap();
+        let login = |password: &str| {
+            let (status, session) = call("POST", "/sessions", None, json!({ "name": "alice", "password": password }));
+            assert_eq!(status, StatusCode::CREATED);
+            format!("{}={}", http::SESSION_COOKIE, session["id"].as_str().unwrap())
+        };
+        let (current, other) = (login("correct horse 1"), login("correct horse 1"));
+        let change = |old: &str, new: &str| json!({ "old_password": old, "new_password": new });
+        let uri = "/sessions/current/password";
+
+        assert_eq!(call("PUT", uri, None, change("correct horse 1", "battery staple 2")).0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call("PUT", uri, Some(&current), change("wrong", "battery staple 2")).0, StatusCode::UNAUTHORIZED);
+        let (status, body) = call("PUT", uri, Some(&current), change("correct horse 1", "short1"));
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "violates validation rule: password_min_length");
+        let (status, body) = call("PUT", uri, Some(&current), change("correct horse 1", "battery staple 2"));
+        assert_eq!((status, body["revoked_sessions"].as_u64()), (StatusCode::OK, Some(1)));
+
+        // 変更したセッションはそのまま使え、他のセッションは失効する
+        let next = change("battery staple 2", "third try 3");
+        assert_eq!(call("PUT", uri, Some(&other), next.clone()).0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call("PUT", uri, Some(&current), next).0, StatusCode::OK);
+        login("third try 3");
+    }
+
+    #[test]
+    fn http_password_reset_sets_a_new_password_with_the_token() {
+        let world = Arc::new(RealWorld::with_config(Config::default(), CachePolicy::WriteThrough).unwrap());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let call = |uri: &str, body: Value| -> (StatusCode, Value) {
+            let request = Request::builder().method("POST").uri(uri).header("content-type", "application/json");
+            let response = runtime.block_on(app.clone().oneshot(request.body(Body::from(body.to_string())).unwrap()));
+            let response = response.unwrap();
+            let status = response.status();
+            let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+            (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+        };
+        let new_user = NewUser {
+            name: "alice".to_string(),
+            email: "alice@example.com".to_string(),
+        };
+        let alice = UserId::new(Uuid::parse_str(&world.user_controller().register(new_user).unwrap().id).unwrap());
+        world.credential_repository().set_password(alice.clone(), "forgotten pass 1").unwrap();
+        let login = |password: &str| call("/sessions", json!({ "name": "alice", "password": password })).0;
+        assert_eq!(login("forgotten pass 1"), StatusCode::CREATED);
+
+        // 登録されていないメールアドレスでも同じように受け付ける
+        let request = |email: &str| call("/password-resets", json!({ "email": email })).0;
+        assert_eq!(request("nobody@example.com"), StatusCode::ACCEPTED);
+        assert_eq!(request("not-an-email"), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(world.config_component().public_url(), "http://127.0.0.1:8080");
+
+        // メールで送るトークンは、ここでは直接発行する
+        let (_, token) = world.password_reset_repository().issue_reset(alice, Duration::hours(1)).unwrap();
+        let confirm = |token: &str, password: &str| {
+            call("/password-resets/confirm", json!({ "token": token, "new_password": password }))
+        };
+        assert_eq!(confirm(&token, "short1").0, StatusCode::UNPROCESSABLE_ENTITY);
+        let (status, body) = confirm(&token, "remembered pass 2");
+        assert_eq!((status, body["revoked_sessions"].as_u64()), (StatusCode::OK, Some(1)));
+        let (status, body) = confirm(&token, "remembered pass 3");
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["error"], "invalid or expired password reset token");
+        assert_eq!(login("forgotten pass 1"), StatusCode::UNAUTHORIZED);
+        assert_eq!(login("remembered pass 2"), StatusCode::CREATED);
+    }
+
+    #[test]
+    fn http_invitations_are_sent_by_admins_and_accepted_with_the_token() {
+        let world = Arc::new(gateway_world());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let call = |uri: &str, actor: Option<&str>, body: Value| -> (StatusCode, Value) {
+            let mut request = Request::builder().method("POST").uri(uri).header("content-type", "application/json");
+            if let Some(actor) = actor {
+                request = request.header(ACTOR_HEADER, actor);
+            }
+            let response = runtime.block_on(app.clone().oneshot(request.body(Body::from(body.to_string())).unwrap()));
+            let response = response.unwrap();
+            let status = response.status();
+            let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+            (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+        };
+        let register = |name: &str| {
+            let new_user = NewUser {
+                name: name.to_string(),
+                email: format!("{}@example.com", name),
+            };
+            UserId::new(Uuid::parse_str(&world.user_controller().register(new_user).unwrap().id).unwrap())
+        };
+        let (member, admin) = (register("member"), register("admin"));
+        world.user_commands().change_role(admin.clone(), Role::Admin).unwrap();
+        let (member, admin) = (member.as_uuid().to_string(), admin.as_uuid().to_string());
+
+        let invite = |role: &str| json!({ "email": "new@example.com", "role": role });
+        assert_eq!(call("/admin/invitations", None, invite("guest")).0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call("/admin/invitations", Some(&member), invite("guest")).0, StatusCode::FORBIDDEN);
+        let (status, body) = call("/admin/invitations", Some(&admin), invite("owner"));
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["fields"]["role"], "unknown role: owner");
+        let taken = json!({ "email": "member@example.com" });
+        assert_eq!(call("/admin/invitations", Some(&admin), taken).0, StatusCode::CONFLICT);
+
+        // メールで送るトークンは、ここでは直接発行する
+        let email = Email::parse("new@example.com").unwrap();
+        let inviter = UserId::new(Uuid::parse_str(&admin).unwrap());
+        let (_, token) = world.invitation_repository().invite(email, Role::Guest, inviter, Duration::days(1)).unwrap();
+        let accept = |token: &str, name: &str| {
+            call("/invitations/accept", None, json!({ "token": token, "name": name, "password": "first pass 1" }))
+        };
+        assert_eq!(accept("garbage", "newcomer").0, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(accept(&token, "member").0, StatusCode::CONFLICT);
+        let (status, user) = accept(&token, "newcomer");
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!((user["email"].as_str(), user["role"].as_str()), (Some("new@example.com"), Some("guest")));
+        assert_eq!(accept(&token, "newcomer2").0, StatusCode::UNPROCESSABLE_ENTITY);
+        let login = json!({ "name": "newcomer", "password": "first pass 1" });
+        assert_eq!(call("/sessions", None, login).0, StatusCode::CREATED);
+    }
+
+    #[test]
+    fn http_admin_routes_suspend_restore_and_purge_users() {
+        let world = Arc::new(gateway_world());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let call = |method: &str, uri: &str, header: (&str, &str)| -> (StatusCode, Value) {
+            let request = Request::builder().method(method).uri(uri).header(header.0, header.1);
+            let response = runtime.block_on(app.clone().oneshot(request.body(Body::empty()).unwrap())).unwrap();
+            let status = response.status();
+            let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+            (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+        };
+        let create = |name: &str| {
+            let email = Email::parse(&format!("{}@example.com", name)).unwrap();
+            world.user_commands().create(Name::new(name).unwrap(), email).unwrap().id
+        };
+        let (admin, member, bob) = (create("admin"), create("member"), create("bob"));
+        world.user_commands().change_role(admin.clone(), Role::Admin).unwrap();
+        let (admin_actor, member_actor) = (admin.as_uuid().to_string(), member.as_uuid().to_string());
+        let as_admin = (ACTOR_HEADER, admin_actor.as_str());
+        let uri = format!("/admin/users/{}", bob.as_uuid());
+        let suspend = format!("{}/suspend", uri);
+        let restore = format!("{}/restore", uri);
+
+        // ユーザーを管理できない人は呼べず、APIトークンならAdminのスコープが要る
+        assert_eq!(call("POST", &suspend, (ACTOR_HEADER, &member_actor)).0, StatusCode::FORBIDDEN);
+        let (_, writer) = {
+            world.api_token_repository().issue_token(admin.clone(), &[Scope::WriteUsers], None).unwrap()
+        };
+        let writer = format!("Bearer {}", writer);
+        assert_eq!(call("POST", &suspend, ("authorization", &writer)).0, StatusCode::FORBIDDEN);
+
+        // 停止するとセッションは失効する
+        let session = world.session_repository().create_session(bob.clone(), Duration::hours(1));
+        let (status, user) = call("POST", &suspend, as_admin);
+        assert_eq!((status, user["status"].as_str()), (StatusCode::OK, Some("suspended")));
+        assert!(world.session_repository().get(session.unwrap().id).is_err());
+        assert_eq!(call("POST", &suspend, as_admin).0, StatusCode::CONFLICT);
+        let (status, user) = call("POST", &restore, as_admin);
+        assert_eq!((status, user["status"].as_str()), (StatusCode::OK, Some("active")));
+        assert_eq!(call("POST", &restore, as_admin).0, StatusCode::CONFLICT);
+
+        // 完全に消せるのは退会したユーザーだけで、紐づくデータも一緒に消える
+        assert_eq!(call("DELETE", &uri, as_admin).0, StatusCode::CONFLICT);
+        let group = GroupName::new("staff").unwrap();
+        {
+            world.credential_repository().set_password(bob.clone(), "secret-pass1").unwrap();
+            world.api_token_repository().issue_token(bob.clone(), &[Scope::ReadUsers], None).unwrap();
+            world.group_repository().create_group(group.clone()).unwrap();
+            world.group_repository().add_member(group.clone(), bob.clone()).unwrap();
+            world.user_commands().deactivate(bob.clone()).unwrap();
+        }
+        let (status, user) = call("DELETE", &uri, as_admin);
+        assert_eq!((status, user["name"].as_str()), (StatusCode::OK, Some("bob")));
+        assert!(world.user_queries().get(bob.clone()).is_err());
+        assert!(world.credential_repository().get(bob.clone()).is_err());
+        assert!(world.api_token_repository().list_tokens(&bob).unwrap().is_empty());
+        assert!(world.group_repository().get(group).unwrap().members.is_empty());
+        drop(world);
+        assert_eq!(call("DELETE", &uri, as_admin).0, StatusCode::NOT_FOUND);
+
+        // CLIから運用する人が呼ぶ時は権限を確かめない
+        let world = RealWorld::with_cache_policy(CachePolicy::WriteThrough);
+        let carol = world.user_controller().register(NewUser {
+            name: "carol".to_string(),
+            email: "carol@example.com".to_string(),
+        });
+        let carol = carol.unwrap().id;
+        let run = |command: &str| -> Value {
+            let matches = cli::command().try_get_matches_from(["layered", "user", command, &carol]).unwrap();
+            let mut out = Vec::new();
+            cli::dispatch(&world, &matches, &mut out).unwrap();
+            serde_json::from_slice(&out).unwrap()
+        };
+        assert_eq!(run("suspend")["status"], "suspended");
+        assert_eq!(run("restore")["status"], "active");
+        assert_eq!(run("delete")["status"], "deactivated");
+        assert_eq!(run("purge")["name"], "carol");
+    }
+
+    #[test]
+    fn http_admin_routes_manage_group_members() {
+        let world = Arc::new(gateway_world());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let call = |method: &str, uri: &str, actor: &str, body: Option<Value>| -> (StatusCode, Value) {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(ACTOR_HEADER, actor)
+                .header("content-type", "application/json");
+            let body = body.map(|body| Body::from(body.to_string())).unwrap_or_else(Body::empty);
+            let response = runtime.block_on(app.clone().oneshot(request.body(body).unwrap())).unwrap();
+            let status = response.status();
+            let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+            (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+        };
+        let create = |name: &str| {
+            let email = Email::parse(&format!("{}@example.com", name)).unwrap();
+            world.user_commands().create(Name::new(name).unwrap(), email).unwrap().id
+        };
+        let (admin, bob) = (create("admin"), create("bob"));
+        world.user_commands().change_role(admin.clone(), Role::Admin).unwrap();
+        let (admin, bob) = (admin.as_uuid().to_string(), bob.as_uuid().to_string());
+        let staff = || Some(json!({ "name": "staff" }));
+
+        assert_eq!(call("POST", "/admin/groups", &bob, staff()).0, StatusCode::FORBIDDEN);
+        let (status, group) = call("POST", "/admin/groups", &admin, staff());
+        assert_eq!((status, group["members"].clone()), (StatusCode::CREATED, json!([])));
+        assert_eq!(call("POST", "/admin/groups", &admin, staff()).0, StatusCode::CONFLICT);
+        let (status, body) = call("POST", "/admin/groups", &admin, Some(json!({ "name": "" })));
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body["fields"]["group_name"].is_string(), "{}", body);
+
+        // 加えるのは何度でもよいが、メンバーでないユーザーは外せない
+        let member = format!("/admin/groups/staff/members/{}", bob);
+        assert_eq!(call("PUT", &member, &bob, None).0, StatusCode::FORBIDDEN);
+        let (status, group) = call("PUT", &member, &admin, None);
+        assert_eq!((status, group["members"].clone()), (StatusCode::OK, json!([bob])));
+        assert_eq!(call("PUT", &member, &admin, None).1["members"], json!([bob]));
+        let (status, group) = call("DELETE", &member, &admin, None);
+        assert_eq!((status, group["members"].clone()), (StatusCode::OK, json!([])));
+        assert_eq!(call("DELETE", &member, &admin, None).0, StatusCode::NOT_FOUND);
+        assert_eq!(call("PUT", "/admin/groups/missing/members/x", &admin, None).0, StatusCode::NOT_FOUND);
+        let missing = format!("/admin/groups/staff/members/{}", Uuid::from_u128(99));
+        assert_eq!(call("PUT", &missing, &admin, None).0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn real_world_is_shared_across_threads() {
+        use std::thread;
+
+        let world = Arc::new(RealWorld::with_cache_policy(CachePolicy::WriteThrough));
+        let create = |world: &RealWorld, name: &str| {
+            let email = Email::parse(&format!("{}@example.com", name)).unwrap();
+            world.user_commands().create(Name::new(name).unwrap(), email).is_ok()
+        };
+
+        // 書き込みは1つずつ行うので、同じ名前を同時に登録しても1人しか作られない
+        let workers: Vec<_> = (0..8)
+            .map(|i| {
+                let world = world.clone();
+                thread::spawn(move || {
+                    let own = (0..5).filter(|j| create(&world, &format!("user{}-{}", i, j))).count();
+                    own + create(&world, "shared") as usize
+                })
+            })
+            .collect();
+        let created: usize = workers.into_iter().map(|worker| worker.join().unwrap()).sum();
+        assert_eq!(created, 8 * 5 + 1);
+
+        // 同じRealWorldを複数のスレッドから読める
+        let world: &RealWorld = &world;
+        thread::scope(|scope| {
+            let readers: Vec<_> = (0..4)
+                .map(|i| {
+                    let name = Name::new(&format!("user{}-0", i)).unwrap();
+                    scope.spawn(move || world.user_queries().get_by_name(&name))
+                })
+                .collect();
+            for reader in readers {
+                assert!(reader.join().unwrap().is_ok());
+            }
+        });
+        assert_eq!(world.user_queries().list().unwrap().len(), 41);
+    }
+
+    #[test]
+    fn real_world_writes_through_shared_reference() {
+        use std::thread;
+
+        // Mutexで包まなくても、Arcで共有したRealWorldからそのまま書き込める
+        let world = Arc::new(RealWorld::with_cache_policy(CachePolicy::WriteBack));
+        let create = |world: &RealWorld, name: &str| {
+            let email = Email::parse(&format!("{}@example.com", name)).unwrap();
+            world.user_commands().create(Name::new(name).unwrap(), email).is_ok()
+        };
+        let workers: Vec<_> = (0..8)
+            .map(|i| {
+                let world = world.clone();
+                thread::spawn(move || {
+                    let own = (0..5).filter(|j| create(&world, &format!("user{}-{}", i, j))).count();
+                    let name = Name::new(&format!("user{}-0", i)).unwrap();
+                    assert!(world.user_queries().get_by_name(&name).is_ok());
+                    own + create(&world, "shared") as usize
+                })
+            })
+            .collect();
+        let created: usize = workers.into_iter().map(|worker| worker.join().unwrap()).sum();
+
+        // 同じ名前を同時に登録しても1人しか作られない
+        assert_eq!(created, 8 * 5 + 1);
+        assert_eq!(world.user_queries().list().unwrap().len(), 41);
+    }
+
+    #[test]
+    fn actor_world_sends_component_calls_to_their_own_threads() {
+        use std::thread;
+
+        // 送った処理は部品のスレッドで、送った順に1つずつ実行される
+        let mailbox = Mailbox::spawn("probe", Mutex::new(Vec::new())).unwrap();
+        for i in 0..10 {
+            mailbox.cast(move |seen: &Mutex<Vec<usize>>| seen.lock().unwrap().push(i));
+        }
+        let (name, seen) = mailbox
+            .call(|seen| (thread::current().name().map(str::to_string), seen.lock().unwrap().clone()))
+            .unwrap();
+        assert_eq!(name.as_deref(), Some("actor-probe"));
+        assert_eq!(seen, (0..10).collect::<Vec<_>>());
+
+        // 部品のスレッドがパニックで止まったら、以降の呼び出しはエラーになる
+        let broken = Mailbox::spawn("broken", ()).unwrap();
+        assert!(broken.call::<(), _>(|_| panic!("broken")).is_err());
+        assert!(broken.call(|_| 1).is_err());
+
+        // ユースケースはRealWorldと同じコードのまま、Mailboxを通して動く
+        let world = ActorWorld::new().unwrap();
+        let alice = world.register_user("alice", "alice@example.com").unwrap();
+        assert!(world.register_user("alice", "other@example.com").is_err());
+        assert_eq!(world.user_queries().get_by_name(&alice.name).unwrap().id, alice.id);
+        let jobs = world.job_queue_component().jobs().unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].kind, JobKind::SendWelcomeMail { user_id: alice.id.clone() });
+
+        // 共有した環境型から同時に書いても、ストレージを触るのはストレージのスレッドだけ
+        let world = Arc::new(world);
+        let workers: Vec<_> = (0..4)
+            .map(|i| {
+                let world = world.clone();
+                thread::spawn(move || {
+                    for j in 0..5 {
+                        let name = format!("user{}-{}", i, j);
+                        let email = Email::parse(&format!("{}@example.com", name)).unwrap();
+                        world.user_commands().create(Name::new(&name).unwrap(), email).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(world.user_queries().list().unwrap().len(), 21);
+    }
+
+    #[test]
+    fn servers_stop_on_signal_and_persist_after_background_tasks() {
+        use futures::FutureExt;
+        use std::io::{Read, Write};
+
+        let path = ::std::env::temp_dir().join(format!("layered-snapshot-{}.jsonl", Uuid::new_v4()));
+        let config = || {
+            let path = path.to_str().unwrap().to_string();
+            Config::default()
+                .override_with(|key| match key {
+                    "LAYERED_SNAPSHOT_PATH" => Some(path.clone()),
+                    "LAYERED_TRUST_ACTOR_HEADER" => Some("1".to_string()),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let world = Arc::new(RealWorld::with_config(config(), CachePolicy::WriteBack).unwrap());
+        let email = Email::parse("alice@example.com").unwrap();
+        let alice = world.user_commands().create(Name::new("alice").unwrap(), email).unwrap().id;
+
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = futures::channel::oneshot::channel::<()>();
+        let app = http::router(world.clone()).unwrap();
+        let server = {
+            let runtime = runtime.clone();
+            let stopped = stopped.map(|_| ()).boxed().shared();
+            ::std::thread::spawn(move || http::serve_router_until(&runtime, app, listener, stopped))
+        };
+        let mut stream = ::std::net::TcpStream::connect(addr).unwrap();
+        let id = alice.as_uuid();
+        let request = format!("GET /users/{} HTTP/1.1\r\nhost: localhost\r\n{}: {}\r\n", id, ACTOR_HEADER, id);
+        write!(stream, "{}connection: close\r\n\r\n", request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        // 合図を受けたら待ち受けをやめて返る
+        stop.send(()).unwrap();
+        server.join().unwrap().unwrap();
+        assert!(::std::net::TcpStream::connect(addr).is_err());
+
+        // バックグラウンドのタスクが終わるのを待ってから、WriteBackで溜めていた変更も書き出して、
+        // メモリ上のストレージを保存する
+        adapter::spawn_maintenance(&runtime, &world).unwrap();
+        let finished = Arc::new(::std::sync::atomic::AtomicBool::new(false));
+        let task = {
+            let (world, finished) = (world.clone(), finished.clone());
+            let stopping = world.stopping();
+            stopping.then(|_| tokio::time::sleep(::std::time::Duration::from_millis(50))).map(move |_| {
+                let bob = Email::parse("bob@example.com").unwrap();
+                world.user_commands().create(Name::new("bob").unwrap(), bob).unwrap();
+                finished.store(true, ::std::sync::atomic::Ordering::SeqCst);
+            })
+        };
+        world.track(runtime.spawn(task));
+        assert!(!path.exists());
+        adapter::finish(&runtime, &world).unwrap();
+        assert!(finished.load(::std::sync::atomic::Ordering::SeqCst));
+        let restored = RealWorld::with_config(config(), CachePolicy::WriteThrough).unwrap();
+        assert_eq!(restored.user_queries().get(alice).unwrap().name.as_str(), "alice");
+        assert!(restored.user_queries().get_by_name(&Name::new("bob").unwrap()).is_ok());
+
+        // 止めた後のJSON-RPCも、受け付けずに変更を書き出して返る
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stopped = ::std::sync::atomic::AtomicBool::new(true);
+        json_rpc::serve_tcp_until(restored, listener, &stopped).unwrap();
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn shutdown_waits_for_the_job_worker_and_keeps_queued_jobs() {
+        let dir = ::std::env::temp_dir().join(format!("layered-jobs-{}", Uuid::new_v4()));
+        ::std::fs::create_dir(&dir).unwrap();
+        let config = || {
+            let dir = dir.to_str().unwrap().to_string();
+            Config::default()
+                .override_with(|key| match key {
+                    "LAYERED_SNAPSHOT_PATH" => Some(format!("{}/users.jsonl", dir)),
+                    "LAYERED_JOBS_PATH" => Some(format!("{}/jobs.jsonl", dir)),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        let world = Arc::new(RealWorld::with_config(config(), CachePolicy::WriteThrough).unwrap());
+        let email = Email::parse("alice@example.com").unwrap();
+        let alice = world.user_commands().create(Name::new("alice").unwrap(), email).unwrap().id;
+        let now = world.time_component().now();
+        let due = Job::new(JobId::new(Uuid::new_v4()), JobKind::RebuildSearchIndex, now);
+        let later = Job::new(JobId::new(Uuid::new_v4()), JobKind::RebuildSearchIndex, now + Duration::days(1));
+        world.job_queue_component().enqueue(due).unwrap();
+        world.job_queue_component().enqueue(later.clone()).unwrap();
+
+        // ワーカーは最初の間隔を待たずに、実行時刻の来たジョブだけを実行する
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        adapter::spawn_job_worker(&runtime, &world).unwrap();
+        for _ in 0..100 {
+            if world.job_queue_component().jobs().unwrap().len() == 1 {
+                break;
+            }
+            ::std::thread::sleep(::std::time::Duration::from_millis(20));
+        }
+        assert_eq!(world.job_queue_component().jobs().unwrap().len(), 1);
+
+        // 止めるとワーカーが終わるのを待ってから書き出し、まだのジョブは開き直しても残っている
+        adapter::finish(&runtime, &world).unwrap();
+        let restored = RealWorld::with_config(config(), CachePolicy::WriteThrough).unwrap();
+        assert_eq!(restored.user_queries().get(alice).unwrap().name.as_str(), "alice");
+        let jobs = restored.job_queue_component().jobs().unwrap();
+        assert_eq!(jobs.iter().map(|job| job.id.clone()).collect::<Vec<_>>(), vec![later.id.clone()]);
+        let mut out = Vec::new();
+        cli::jobs(&restored, &mut out).unwrap();
+        let listed: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(listed["jobs"][0]["id"], json!(later.id));
+        assert_eq!(listed["dead_letters"], json!([]));
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn graphql_api_resolves_users_through_the_use_cases() {
+        let world = Arc::new(gateway_world());
+        let graphql = GraphQL::new(world.clone());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let run = |actor: Option<&str>, query: &str| -> Value {
+            let response = runtime.block_on(graphql.execute(actor, async_graphql::Request::new(query)));
+            serde_json::to_value(&response).unwrap()
+        };
+        let register = |name: &str| {
+            let query = format!(r#"mutation {{ registerUser(name: "{0}", email: "{0}@example.com") {{ id }} }}"#, name);
+            run(None, &query)["data"]["registerUser"]["id"].as_str().unwrap().to_string()
+        };
+
+        let (admin, bob, _) = (register("admin"), register("bob"), register("carol"));
+        let response = run(None, r#"mutation { registerUser(name: "bob", email: "x@example.com") { id } }"#);
+        assert_eq!(response["errors"][0]["extensions"]["code"], "CONFLICT");
+
+        // 一覧の各ユーザーはIDでまとめて読む。見つからないIDは飛ばす
+        let ids = [UserId::new(Uuid::parse_str(&bob).unwrap()), UserId::new(Uuid::from_u128(99))];
+        assert_eq!(world.user_queries().get_many(&ids).unwrap().len(), 1);
+        let users = "query($after: String) { users(first: 2, after: $after) { \
+                     totalCount edges { node { name email } } pageInfo { hasNextPage endCursor } } }";
+        let response = run(None, users);
+        assert_eq!(response["errors"][0]["extensions"]["code"], "UNAUTHENTICATED");
+        let first = run(Some(&admin), users)["data"]["users"].clone();
+        assert_eq!((first["totalCount"].as_u64(), first["pageInfo"]["hasNextPage"].as_bool()), (Some(3), Some(true)));
+        let request = async_graphql::Request::new(users)
+            .variables(async_graphql::Variables::from_json(json!({ "after": first["pageInfo"]["endCursor"] })));
+        let second = serde_json::to_value(runtime.block_on(graphql.execute(Some(&admin), request))).unwrap();
+        let second = second["data"]["users"].clone();
+        assert_eq!(second["pageInfo"]["hasNextPage"].as_bool(), Some(false));
+        let mut names: Vec<_> = first["edges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .chain(second["edges"].as_array().unwrap())
+            .map(|edge| edge["node"]["name"].as_str().unwrap().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["admin", "bob", "carol"]);
+
+        let user = run(Some(&admin), r#"{ user(name: "bob") { id email } }"#);
+        assert_eq!(user["data"]["user"], json!({ "id": bob, "email": "bob@example.com" }));
+        assert_eq!(run(Some(&admin), r#"{ user(name: "nobody") { id } }"#)["data"]["user"], Value::Null);
+
+        // メールアドレスは本人しか変えられない
+        let update = format!(
+            r#"mutation {{ updateEmail(id: "{}", email: "new@example.com") {{ oldEmail newEmail }} }}"#,
+            bob
+        );
+        assert_eq!(run(Some(&admin), &update)["errors"][0]["extensions"]["code"], "FORBIDDEN");
+        let change = run(Some(&bob), &update)["data"]["updateEmail"].clone();
+        assert_eq!(change, json!({ "oldEmail": "bob@example.com", "newEmail": "new@example.com" }));
+
+        // HTTPでは `POST /graphql` で受け付ける
+        let request = Request::builder()
+            .method("POST")
+            .uri("/graphql")
+            .header("content-type", "application/json")
+            .header(ACTOR_HEADER, bob.as_str())
+            .body(Body::from(json!({ "query": "{ user(name: \"bob\") { email } }" }).to_string()))
+            .unwrap();
+        let app = http::router(world).unwrap();
+        let response = runtime.block_on(app.clone().oneshot(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["data"]["user"]["email"], "new@example.com");
+
+        // `GET /graphql` はスキーマをSDLで返す
+        let request = Request::builder().uri("/graphql").body(Body::empty()).unwrap();
+        let response = runtime.block_on(app.oneshot(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+        assert_eq!(String::from_utf8(bytes.to_vec()).unwrap(), graphql.sdl());
+    }
+
+    #[test]
+    fn http_api_serves_its_openapi_document() {
+        let world = Arc::new(RealWorld::with_cache_policy(CachePolicy::WriteThrough));
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let request = Request::builder().uri("/openapi.json").body(Body::empty()).unwrap();
+        let response = runtime.block_on(http::router(world).unwrap().oneshot(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+        let spec: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(spec["info"]["title"], "layered");
+
+        // ルーターのパスとメソッドがそのまま載る
+        let paths = spec["paths"].as_object().unwrap();
+        let mut methods: Vec<String> = paths
+            .iter()
+            .flat_map(|(path, item)| item.as_object().unwrap().keys().map(move |method| format!("{} {}", method, path)))
+            .collect();
+        methods.sort();
+        assert_eq!(
+            methods,
+            [
+                "delete /admin/groups/{name}/members/{id}",
+                "delete /admin/users/{id}",
+                "delete /sessions/current",
+                "delete /users/{id}",
+                "get /users",
+                "get /users/events",
+                "get /users/search",
+                "get /users/{id}",
+                "patch /users/{id}",
+                "post /admin/groups",
+                "post /admin/invitations",
+                "post /admin/users/{id}/restore",
+                "post /admin/users/{id}/suspend",
+                "post /api-tokens",
+                "post /invitations/accept",
+                "post /password-resets",
+                "post /password-resets/confirm",
+                "post /sessions",
+                "post /users",
+                "put /admin/groups/{name}/members/{id}",
+                "put /sessions/current/password"
+            ]
+        );
+        let list = &paths["/users"]["get"];
+        let page = &list["responses"]["200"]["content"]["application/json"]["schema"]["$ref"];
+        assert_eq!(page, "#/components/schemas/PageView_UserSummaryDto");
+        assert_eq!(list["security"][0][http::ACTOR_SCHEME], json!([]));
+        assert!(paths["/users"]["post"].get("security").is_none());
+
+        // 型はDTOの定義から作られる
+        let schemas = &spec["components"]["schemas"];
+        assert_eq!(schemas["NewUser"]["required"], json!(["name", "email"]));
+        assert_eq!(schemas["UserDto"]["properties"]["create_time"]["format"], "date-time");
+        assert_eq!(schemas["ErrorBody"]["required"], json!(["error"]));
+        let scheme = &spec["components"]["securitySchemes"][http::ACTOR_SCHEME];
+        assert_eq!((scheme["in"].as_str(), scheme["name"].as_str()), (Some("header"), Some(ACTOR_HEADER)));
+    }
+
+    #[test]
+    fn http_api_pages_sorts_and_filters_users() {
+        let world = Arc::new(gateway_world());
+        let users = [("ann", "a.example"), ("bob", "b.example"), ("cat", "a.example"), ("dan", "A.example")];
+        for (name, domain) in &users {
+            let email = Email::parse(&format!("{}@{}", name, domain)).unwrap();
+            world.user_commands().create(Name::new(name).unwrap(), email).unwrap();
+        }
+        let actor = world.user_queries().get_by_name(&Name::new("ann").unwrap()).unwrap().id;
+        let actor = actor.as_uuid().to_string();
+        let app = http::router(world).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let get = |uri: &str| -> (StatusCode, Option<String>, Value) {
+            let request = Request::builder().uri(uri).header(ACTOR_HEADER, actor.as_str()).body(Body::empty());
+            let response = runtime.block_on(app.clone().oneshot(request.unwrap())).unwrap();
+            let link = response.headers().get("link").map(|value| value.to_str().unwrap().to_string());
+            let status = response.status();
+            let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+            (status, link, serde_json::from_slice(&bytes).unwrap())
+        };
+        let names = |page: &Value| -> Vec<String> {
+            page["items"].as_array().unwrap().iter().map(|user| user["name"].as_str().unwrap().to_string()).collect()
+        };
+
+        let (status, link, page) = get("/users?sort=name:desc&email_domain=a.example&per_page=1&page=2");
+        assert_eq!((status, page["total"].as_u64()), (StatusCode::OK, Some(3)));
+        assert_eq!(names(&page), ["cat"]);
+        assert_eq!(
+            link.unwrap(),
+            "</users?page=1&per_page=1&sort=name:desc&email_domain=a.example>; rel=\"prev\", \
+             </users?page=3&per_page=1&sort=name:desc&email_domain=a.example>; rel=\"next\""
+        );
+        let (_, link, page) = get("/users?sort=email&per_page=2");
+        assert_eq!(names(&page), ["ann", "bob"]);
+        assert_eq!(link.unwrap(), "</users?page=2&per_page=2&sort=email>; rel=\"next\"");
+        let (_, link, _) = get("/users?per_page=10");
+        assert!(link.is_none());
+
+        let (status, _, body) = get("/users?sort=age:desc");
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["fields"]["sort"], "unknown sort field: age");
+        assert_eq!(get("/users?sort=name:up").0, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(get("/users?email_domain=a%3Eexample").0, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn http_api_localizes_error_bodies() {
+        let world = Arc::new(gateway_world());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let call = |method: &str, uri: &str, headers: &[(&str, &str)], body: &str| -> Value {
+            let mut request = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+            for &(name, value) in headers {
+                request = request.header(name, value);
+            }
+            let response = runtime.block_on(app.clone().oneshot(request.body(Body::from(body.to_string())).unwrap()));
+            let bytes = runtime.block_on(body::to_bytes(response.unwrap().into_body(), usize::MAX)).unwrap();
+            serde_json::from_slice(&bytes).unwrap()
+        };
+
+        let japanese = [("accept-language", "ja-JP,en;q=0.8")];
+        let body = call("POST", "/users", &japanese, r#"{ "name": " ", "email": "not-an-email" }"#);
+        assert_eq!(body["error"], "name を入力してください(空白だけにはできません)");
+        assert_eq!(body["fields"]["email"], "メールアドレスの形式が正しくありません: not-an-email");
+        // 翻訳できない文言は元のまま
+        let body = call("POST", "/users", &japanese, r#"{ "name": "alice" }"#);
+        assert_eq!(body["fields"]["email"], "missing required field");
+
+        // Accept-Languageが無ければ、認証したユーザーのプロフィールのロケールにする
+        let alice = call("POST", "/users", &[], r#"{ "name": "alice", "email": "alice@example.com" }"#);
+        let alice = alice["id"].as_str().unwrap();
+        let alice_id = UserId::new(Uuid::parse_str(alice).unwrap());
+        let too_long = json!({ "name": "a".repeat(65) }).to_string();
+        let uri = format!("/users/{}", alice);
+        let body = call("PATCH", &uri, &[(ACTOR_HEADER, alice)], &too_long);
+        assert_eq!(body["error"], "name must be at most 64 characters");
+        world.profile_repository().create_profile(alice_id).unwrap();
+        let body = call("PATCH", &uri, &[(ACTOR_HEADER, alice)], &too_long);
+        assert_eq!(body["fields"]["name"], "name は 64 文字以内で入力してください");
+        let body = call("PATCH", &uri, &[(ACTOR_HEADER, alice), ("accept-language", "en")], &too_long);
+        assert_eq!(body["fields"]["name"], "name must be at most 64 characters");
+    }
+
+    #[test]
+    fn http_api_reports_invalid_fields_in_the_error_body() {
+        let world = Arc::new(gateway_world());
+        let app = http::router(world).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let call = |method: &str, uri: &str, actor: Option<&str>, body: &str| -> (StatusCode, Value) {
+            let mut request = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+            if let Some(actor) = actor {
+                request = request.header(ACTOR_HEADER, actor);
+            }
+            let response = runtime.block_on(app.clone().oneshot(request.body(Body::from(body.to_string())).unwrap()));
+            let response = response.unwrap();
+            let status = response.status();
+            let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+            (status, serde_json::from_slice(&bytes).unwrap())
+        };
+
+        // 最初の誤りで止めず、全てのフィールドの誤りを返す
+        let (status, body) = call("POST", "/users", None, r#"{ "name": " ", "email": "not-an-email" }"#);
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["fields"]["name"], "name must not be empty");
+        assert_eq!(body["fields"]["email"], "invalid email address: not-an-email");
+
+        // 本文の型が合わない時もJSONで返す
+        let (status, body) = call("POST", "/users", None, r#"{ "name": "alice" }"#);
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["fields"], json!({ "email": "missing required field" }));
+        let (status, body) = call("POST", "/users", None, r#"{ "name": 1, "email": "alice@example.com" }"#);
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body["fields"]["name"].as_str().unwrap().starts_with("invalid type: integer `1`"));
+        let (status, body) = call("POST", "/users", None, "{");
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body.get("fields").is_none());
+
+        // ユースケースでぶつかった値もフィールドを付けて返す
+        let (status, alice) = call("POST", "/users", None, r#"{ "name": "alice", "email": "alice@example.com" }"#);
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, body) = call("POST", "/users", None, r#"{ "name": "alice", "email": "other@example.com" }"#);
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body["fields"]["name"].as_str().unwrap().starts_with("name already taken"));
+        let alice = alice["id"].as_str().unwrap();
+        let too_long = json!({ "name": "a".repeat(65) }).to_string();
+        let (status, body) = call("PATCH", &format!("/users/{}", alice), Some(alice), &too_long);
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["fields"]["name"], "name must be at most 64 characters");
+    }
+
+    #[test]
+    fn http_api_streams_user_changes_as_server_sent_events() {
+        let world = Arc::new(gateway_world());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        let register = |name: &str| {
+            let new_user = NewUser {
+                name: name.to_string(),
+                email: format!("{}@example.com", name),
+            };
+            world.user_controller().register(new_user).unwrap().id
+        };
+        let watch = |actor: Option<&str>| {
+            let mut request = Request::builder().uri("/users/events?prefix=al");
+            if let Some(actor) = actor {
+                request = request.header(ACTOR_HEADER, actor);
+            }
+            runtime.block_on(app.clone().oneshot(request.body(Body::empty()).unwrap())).unwrap()
+        };
+        assert_eq!(watch(None).status(), StatusCode::UNAUTHORIZED);
+
+        let watcher = register("watcher");
+        let response = watch(Some(&watcher));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut events = response.into_body().into_data_stream();
+
+        // 名前が `al` で始まるユーザーの変更だけが届く
+        let alice = register("alice");
+        register("bob");
+        let caller = Caller::Operator;
+        world.user_controller().rename(&caller, &alice, "alicia").unwrap();
+        let token = world.user_controller().request_deletion(&caller, &alice).unwrap();
+        world.user_controller().confirm_deletion(&caller, &alice, token).unwrap();
+        let mut next = || {
+            let frame = runtime.block_on(events.next()).unwrap().unwrap();
+            let frame = String::from_utf8(frame.to_vec()).unwrap();
+            let event = frame.lines().find(|line| line.starts_with("event:")).unwrap().to_string();
+            let data = frame.lines().find(|line| line.starts_with("data:")).unwrap();
+            let user: Value = serde_json::from_str(data.trim_start_matches("data:")).unwrap();
+            (event, user["name"].as_str().unwrap().to_string())
+        };
+        assert_eq!(next(), ("event: created".to_string(), "alice".to_string()));
+        assert_eq!(next(), ("event: updated".to_string(), "alicia".to_string()));
+        assert_eq!(next(), ("event: deleted".to_string(), "alicia".to_string()));
+
+        // 無効化されたユーザーは購読できない
+        let albert = register("albert");
+        let token = world.user_controller().request_deletion(&caller, &albert).unwrap();
+        world.user_controller().confirm_deletion(&caller, &albert, token).unwrap();
+        assert_eq!(watch(Some(&albert)).status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn http_api_searches_users_by_name() {
+        let world = Arc::new(gateway_world());
+        let app = http::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let mut ids = Vec::new();
+        for name in &["alice", "bob"] {
+            let new_user = NewUser {
+                name: name.to_string(),
+                email: format!("{}@example.com", name),
+            };
+            ids.push(world.user_controller().register(new_user).unwrap().id);
+        }
+        let search = |uri: &str, actor: Option<&str>| -> (StatusCode, Value) {
+            let mut request = Request::builder().uri(uri);
+            if let Some(actor) = actor {
+                request = request.header(ACTOR_HEADER, actor);
+            }
+            let response = runtime.block_on(app.clone().oneshot(request.body(Body::empty()).unwrap())).unwrap();
+            let status = response.status();
+            let bytes = runtime.block_on(body::to_bytes(response.into_body(), usize::MAX)).unwrap();
+            (status, serde_json::from_slice(&bytes).unwrap())
+        };
+        assert_eq!(search("/users/search?q=alice", None).0, StatusCode::UNAUTHORIZED);
+
+        // 綴りが少し違っていても見つかる
+        let (status, users) = search("/users/search?q=alise", Some(&ids[1]));
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(users.as_array().unwrap().len(), 1);
+        assert_eq!(users[0]["name"], "alice");
+        let (_, users) = search("/users/search?q=example&limit=1", Some(&ids[1]));
+        assert_eq!(users.as_array().unwrap().len(), 1);
+        let (status, body) = search("/users/search?q=%20", Some(&ids[1]));
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["fields"]["q"], "empty search query");
+    }
+
+    #[test]
+    fn json_rpc_maps_methods_to_use_cases() {
+        let world = Arc::new(RealWorld::with_cache_policy(CachePolicy::WriteThrough));
+        let run = |lines: &[Value]| -> Vec<Value> {
+            let input: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+            let mut output = Vec::new();
+            json_rpc::serve(&world, Peer::Operator, input.as_bytes(), &mut output).unwrap();
+            String::from_utf8(output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+        };
+        let insert = |id: u64, name: &str| {
+            let params = json!({ "name": name, "email": format!("{}@example.com", name) });
+            json!({ "jsonrpc": "2.0", "method": "user.insert", "params": params, "id": id })
+        };
+
+        let responses = run(&[insert(1, "alice"), insert(2, "alice")]);
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[0]["result"]["name"], "alice");
+        assert_eq!((responses[1]["error"]["code"].as_i64(), responses[1]["id"].as_u64()), (Some(-32009), Some(2)));
+        let alice = responses[0]["result"]["id"].clone();
+
+        let get = json!({ "jsonrpc": "2.0", "method": "user.get", "params": { "id": alice }, "id": "get" });
+        let missing = json!({ "jsonrpc": "2.0", "method": "user.get", "params": { "id": "unknown" }, "id": 3 });
+        let responses = run(&[get, missing]);
+        assert_eq!(responses[0]["id"], "get");
+        assert_eq!(responses[0]["result"]["email"], "alice@example.com");
+        assert_eq!(responses[1]["error"]["code"], -32004);
+        assert_eq!(responses[1]["error"]["data"]["kind"], "NotFound");
+
+        // 通知(idが無いもの)にはレスポンスを返さず、バッチにはまとめて配列で返す
+        let mut notification = insert(0, "bob");
+        notification.as_object_mut().unwrap().remove("id");
+        let batch = json!([
+            { "jsonrpc": "2.0", "method": "user.list", "id": 4 },
+            { "jsonrpc": "2.0", "method": "user.rename", "id": 5 },
+            { "jsonrpc": "2.0", "method": "user.get", "params": [1], "id": 6 },
+            { "jsonrpc": "1.0", "method": "user.list", "id": 7 },
+        ]);
+        let responses = run(&[notification, batch]);
+        assert_eq!(responses.len(), 1);
+        let codes: Vec<_> = responses[0].as_array().unwrap().iter().map(|r| r["error"]["code"].as_i64()).collect();
+        assert_eq!(codes, vec![None, Some(-32601), Some(-32602), Some(-32600)]);
+        assert_eq!(responses[0][0]["result"]["total"], 2);
+
+        let delete = json!({ "jsonrpc": "2.0", "method": "user.delete", "params": { "id": alice }, "id": 8 });
+        let responses = run(&[delete]);
+        assert_eq!(responses[0]["result"]["status"], "deactivated");
+        let mut output = Vec::new();
+        json_rpc::serve(&world, Peer::Operator, "{ not json\n".as_bytes(), &mut output).unwrap();
+        let response: Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!((response["error"]["code"].as_i64(), &response["id"]), (Some(-32700), &Value::Null));
+    }
+
+    #[test]
+    fn json_rpc_over_tcp_requires_authentication() {
+        let world = Arc::new(RealWorld::with_cache_policy(CachePolicy::WriteThrough));
+        let email = Email::parse("alice@example.com").unwrap();
+        let alice = world.user_commands().create(Name::new("alice").unwrap(), email).unwrap().id;
+        let request = |method: &str, params: Value| {
+            json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 })
+        };
+        let run = |lines: &[Value]| -> Vec<Value> {
+            let input: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+            let mut output = Vec::new();
+            json_rpc::serve(&world, Peer::Anonymous, input.as_bytes(), &mut output).unwrap();
+            String::from_utf8(output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+        };
+        let code = |response: &Value| response["error"]["code"].as_i64();
+        let id = json!({ "id": alice.as_uuid().to_string() });
+        let bob = json!({ "name": "bob", "email": "bob@example.com" });
+
+        // 認証するまでは、読むのも変えるのも断る
+        let responses = run(&[request("user.delete", id.clone()), request("user.insert", bob.clone())]);
+        assert_eq!(responses.iter().map(code).collect::<Vec<_>>(), [Some(-32001), Some(-32001)]);
+        assert_eq!(code(&run(&[request("user.get", id.clone())])[0]), Some(-32001));
+        assert_eq!(code(&run(&[request("auth.authenticate", json!({ "token": "forged" }))])[0]), Some(-32001));
+        assert!(world.user_queries().get(alice.clone()).is_ok());
+
+        // 認証した後も、APIトークンのスコープに無い操作は断る
+        let (_, plain) = world.api_token_repository().issue_token(alice.clone(), &[Scope::ReadUsers], None).unwrap();
+        let responses = run(&[
+            request("auth.authenticate", json!({ "token": plain })),
+            request("user.get", id.clone()),
+            request("user.delete", id.clone()),
+        ]);
+        assert_eq!(responses[0]["result"]["user_id"], alice.as_uuid().to_string());
+        assert_eq!(responses[1]["result"]["name"], "alice");
+        assert_eq!(code(&responses[2]), Some(-32003));
+        assert!(world.user_queries().get(alice).is_ok());
+    }
+
+    #[test]
+    fn command_bus_runs_commands_from_many_senders_on_one_core() {
+        use std::io::Cursor;
+        use std::thread;
+
+        let bus = CommandBus::start(RealWorld::with_cache_policy(CachePolicy::WriteThrough)).unwrap();
+        // 2つのフロントエンドが、それぞれの送り口から同じコアへ同時に送る
+        let senders: Vec<_> = (0..2)
+            .map(|i| {
+                let client = bus.client();
+                thread::spawn(move || {
+                    let mut lines = String::new();
+                    for j in 0..3 {
+                        let name = format!("user{}{}", i, j);
+                        let command = Command::RegisterUser {
+                            email: format!("{}@example.com", name),
+                            name,
+                        };
+                        lines.push_str(&serde_json::to_string(&command).unwrap());
+                        lines.push('\n');
+                    }
+                    lines.push_str("{\"command\": \"drop\"}\n");
+                    let mut output = Vec::new();
+                    command_bus::serve(&client, Cursor::new(lines), &mut output).unwrap();
+                    String::from_utf8(output).unwrap()
+                })
+            })
+            .collect();
+        for sender in senders {
+            let output = sender.join().unwrap();
+            let replies: Vec<Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+            assert_eq!(replies.len(), 4);
+            assert!(replies[..3].iter().all(|reply| reply["ok"]["id"].is_string()), "{:?}", replies);
+            assert_eq!(replies[3]["error"]["status"], 422);
+        }
+
+        let client = bus.client();
+        let send = |command: Command| client.send_json(serde_json::to_string(&command).unwrap());
+        let page = send(Command::ListUsers { page: None, per_page: None }).unwrap();
+        assert_eq!(page["total"], 6);
+        let id = page["items"][0]["id"].as_str().unwrap().to_string();
+        // 直列化したJSONをそのまま送ってもよい
+        let command = json!({ "command": "get_user", "id": id }).to_string();
+        assert_eq!(client.send_json(command).unwrap()["id"], id.as_str());
+        let duplicate = Command::RegisterUser {
+            name: "user00".to_string(),
+            email: "user00@example.com".to_string(),
+        };
+        assert_eq!(send(duplicate).unwrap_err().kind, ErrorKind::Conflict);
+        let deleted = send(Command::DeleteUser { id: id.clone() }).unwrap();
+        assert_eq!(deleted["status"], "deactivated");
+
+        // 止めた後は送れず、envは呼び出し側に戻る
+        let world = bus.stop().unwrap();
+        let stopped = send(Command::GetUser { id });
+        assert_eq!(stopped.unwrap_err().kind, ErrorKind::Internal);
+        assert_eq!(world.user_queries().list().unwrap().len(), 6);
+        world.persist().unwrap();
+    }
+
+    #[test]
+    fn websocket_pushes_user_events_to_subscribed_clients() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let world = Arc::new(RealWorld::with_cache_policy(CachePolicy::WriteThrough));
+        let app = websocket::router(world.clone()).unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = runtime.block_on(tokio::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        runtime.spawn(axum::serve(listener, app).into_future());
+        let issue = |name: &str, scopes: &[Scope]| {
+            let email = Email::parse(&format!("{}@example.com", name)).unwrap();
+            let user = world.user_commands().create(Name::new(name).unwrap(), email).unwrap();
+            world.api_token_repository().issue_token(user.id, scopes, None).unwrap().1
+        };
+        let connect = |token: Option<&str>| {
+            let mut request = url.as_str().into_client_request().unwrap();
+            if let Some(token) = token {
+                request.headers_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+            }
+            match runtime.block_on(tokio_tungstenite::connect_async(request)) {
+                Ok((socket, _)) => Ok(socket),
+                Err(WsError::Http(response)) => Err(response.status().as_u16()),
+                Err(e) => panic!("failed to connect: {}", e),
+            }
+        };
+
+        // 繋ぐ時のリクエストで認証できなければ繋がない
+        assert_eq!(connect(None).err(), Some(401));
+        let read_only = issue("alice", &[Scope::ReadUsers]);
+        let read_write = issue("bob", &[Scope::ReadUsers, Scope::WriteUsers]);
+        let (mut alice, mut bob) = (connect(Some(&read_only)).unwrap(), connect(Some(&read_write)).unwrap());
+        fn call<S>(runtime: &tokio::runtime::Runtime, socket: &mut S, request: Value) -> Value
+        where
+            S: Sink<WsMessage> + Stream<Item = Result<WsMessage, WsError>> + Unpin,
+            S::Error: fmt::Debug,
+        {
+            runtime.block_on(socket.send(WsMessage::Text(request.to_string()))).unwrap();
+            receive(runtime, socket)
+        }
+        fn receive<S>(runtime: &tokio::runtime::Runtime, socket: &mut S) -> Value
+        where
+            S: Stream<Item = Result<WsMessage, WsError>> + Unpin,
+        {
+            match runtime.block_on(socket.next()) {
+                Some(Ok(WsMessage::Text(text))) => serde_json::from_str(&text).unwrap(),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+        let request = |method: &str, params: Value, id: u64| {
+            json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": id })
+        };
+        let carol = json!({ "name": "carol", "email": "carol@example.com" });
+        let dave = json!({ "name": "dave", "email": "dave@example.com" });
+
+        let response = call(&runtime, &mut alice, request("events.subscribe", Value::Null, 1));
+        assert_eq!((response["result"].as_bool(), response["id"].as_u64()), (Some(true), Some(1)));
+
+        // 購読している接続にだけ、他の接続での操作がイベントとして届く
+        let response = call(&runtime, &mut bob, request("user.insert", carol, 2));
+        assert_eq!(response["result"]["name"], "carol");
+        let notification = receive(&runtime, &mut alice);
+        assert_eq!(notification["method"], "user.event");
+        assert_eq!(notification["params"]["user_id"], response["result"]["id"]);
+        assert!(notification.get("id").is_none());
+        let response = call(&runtime, &mut bob, request("user.list", json!({}), 3));
+        assert_eq!((response["id"].as_u64(), response["result"]["total"].as_u64()), (Some(3), Some(3)));
+
+        // 購読をやめた後はレスポンスだけが届く
+        call(&runtime, &mut alice, request("events.unsubscribe", Value::Null, 4));
+        call(&runtime, &mut bob, request("user.insert", dave, 5));
+        let response = call(&runtime, &mut alice, request("user.list", json!({}), 6));
+        assert_eq!((response["id"].as_u64(), response["result"]["total"].as_u64()), (Some(6), Some(4)));
+
+        // APIトークンのスコープに無いコマンドは断る
+        let erin = json!({ "name": "erin", "email": "erin@example.com" });
+        let response = call(&runtime, &mut alice, request("user.insert", erin, 7));
+        assert_eq!((response["id"].as_u64(), response["error"]["code"].as_i64()), (Some(7), Some(-32003)));
+    }
+
+    #[test]
+    fn repl_evaluates_commands_and_completes_user_names() {
+        let world = RealWorld::with_cache_policy(CachePolicy::WriteThrough);
+        let eval = |line: &str| -> Value {
+            let output = repl::eval(&world, line).unwrap().unwrap();
+            serde_json::from_str(&output).unwrap()
+        };
+        assert_eq!(eval("add user_a user_a@example.com")["name"], "user_a");
+        eval("add user_b  user_b@example.com");
+        assert_eq!(eval("get user_a")["email"], "user_a@example.com");
+        assert_eq!(eval("list")["total"], 2);
+
+        assert_eq!(repl::eval(&world, "  ").unwrap(), Some(String::new()));
+        assert_eq!(repl::eval(&world, "quit").unwrap(), None);
+        let usage = repl::eval(&world, "add user_c").unwrap_err();
+        assert_eq!(usage.to_string(), "usage: add <name> <email>");
+        let missing = PresentationError::from(repl::eval(&world, "get nobody").unwrap_err());
+        assert_eq!(missing.kind, ErrorKind::NotFound);
+
+        assert_eq!(repl::complete(&world, "ge", 2), (0, vec!["get".to_string()]));
+        assert_eq!(repl::complete(&world, "get user_", 9), (4, vec!["user_a".to_string(), "user_b".to_string()]));
+        assert_eq!(repl::complete(&world, "delete user_b", 13), (7, vec!["user_b".to_string()]));
+        // カーソルより後ろは見ない
+        assert_eq!(repl::complete(&world, "get u list", 5).1.len(), 2);
+        assert!(repl::complete(&world, "add user_", 9).1.is_empty());
+    }
+
+    #[test]
+    fn tui_manages_users_through_the_use_cases() {
+        let world = RealWorld::with_cache_policy(CachePolicy::WriteThrough);
+        let mut app = tui::App::new(&world);
+        fn press(app: &mut tui::App, world: &RealWorld, code: KeyCode) {
+            app.handle_key(world, KeyEvent::from(code));
+        }
+        fn type_in(app: &mut tui::App, world: &RealWorld, text: &str) {
+            text.chars().for_each(|c| press(app, world, KeyCode::Char(c)));
+        }
+
+        type_in(&mut app, &world, "nalice");
+        press(&mut app, &world, KeyCode::Tab);
+        type_in(&mut app, &world, "alice@example.com");
+        press(&mut app, &world, KeyCode::Enter);
+        assert_eq!((app.pane.clone(), app.status.as_str()), (tui::Pane::List, "created alice"));
+        assert_eq!(app.selected().map(|user| user.email.as_str()), Some("alice@example.com"));
+
+        // 入力の誤りは枠を開いたまま知らせる
+        type_in(&mut app, &world, "nbob");
+        press(&mut app, &world, KeyCode::Tab);
+        type_in(&mut app, &world, "bob");
+        press(&mut app, &world, KeyCode::Enter);
+        assert!(matches!(app.pane, tui::Pane::Create(ref form) if form.email == "bob"));
+        assert!(app.status.starts_with("error:"));
+        press(&mut app, &world, KeyCode::Esc);
+
+        press(&mut app, &world, KeyCode::Char('e'));
+        (0..5).for_each(|_| press(&mut app, &world, KeyCode::Backspace));
+        type_in(&mut app, &world, "alicia");
+        press(&mut app, &world, KeyCode::Enter);
+        assert_eq!(app.status, "updated alicia");
+        let alicia = world.user_queries().get_by_name(&Name::new("alicia").unwrap()).unwrap();
+        assert_eq!(alicia.email.as_str(), "alice@example.com");
+
+        let mut terminal = ratatui::Terminal::new(ratatui::backend::TestBackend::new(60, 10)).unwrap();
+        press(&mut app, &world, KeyCode::Char('d'));
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let screen: String = terminal.backend().buffer().content().iter().map(|cell| cell.symbol()).collect();
+        assert!(screen.contains("users (page 1/1)"));
+        assert!(screen.contains("delete alicia <alice@example.com>? (y/n)"));
+
+        press(&mut app, &world, KeyCode::Char('n'));
+        assert_eq!((app.pane.clone(), app.users.len()), (tui::Pane::List, 1));
+        press(&mut app, &world, KeyCode::Char('d'));
+        press(&mut app, &world, KeyCode::Char('y'));
+        assert_eq!(app.status, "deleted alicia");
+        // 退会させても一覧には残り、状態だけが変わる
+        assert_eq!(app.selected().map(|user| user.status.as_str()), Some("deactivated"));
+        press(&mut app, &world, KeyCode::Char('q'));
+        assert!(app.quit);
+    }
+
+    #[test]
+    fn grpc_api_serves_users_through_the_use_cases() {
+        let world = Arc::new(gateway_world());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let serve = |world: Arc<RealWorld>| {
+            let listener = runtime.block_on(tokio::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tonic::transport::Server::builder()
+                .add_service(grpc::service(world))
+                .serve_with_incoming(TcpListenerStream::new(listener));
+            runtime.spawn(server);
+            runtime.block_on(UserServiceClient::connect(format!("http://{}", addr))).unwrap()
+        };
+        let mut client = serve(world.clone());
+        fn as_user<T>(actor: &str, message: T) -> tonic::Request<T> {
+            let mut request = tonic::Request::new(message);
+            request.metadata_mut().insert(ACTOR_HEADER, actor.parse().unwrap());
+            request
+        }
+
+        let create = |name: &str| CreateUserRequest {
+            name: name.to_string(),
+            email: format!("{}@example.com", name),
+        };
+        let admin = runtime.block_on(client.create_user(create("admin"))).unwrap().into_inner();
+        let admin_id = UserId::new(Uuid::parse_str(&admin.id).unwrap());
+        world.user_commands().change_role(admin_id, Role::Admin).unwrap();
+        let member = runtime.block_on(client.create_user(create("member"))).unwrap().into_inner();
+        let status = runtime.block_on(client.create_user(create("member"))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+
+        let list = ListUsersRequest { page: 0, per_page: 1 };
+        let status = runtime.block_on(client.list_users(list.clone())).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let page = runtime.block_on(client.list_users(as_user(&member.id, list.clone()))).unwrap().into_inner();
+        assert_eq!((page.users.len(), page.page, page.total), (1, 1, 2));
+        let get = GetUserRequest { id: member.id.clone() };
+        let user = runtime.block_on(client.get_user(as_user(&admin.id, get))).unwrap().into_inner();
+        assert_eq!(user, member);
+        let get = GetUserRequest { id: "unknown".to_string() };
+        let status = runtime.block_on(client.get_user(as_user(&admin.id, get))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        // 退会は本人が確認用のトークンを受け取ってから確定する
+        let delete = |token: &str| DeleteUserRequest {
+            id: member.id.clone(),
+            confirmation_token: token.to_string(),
+        };
+        let status = runtime.block_on(client.delete_user(as_user(&admin.id, delete("")))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let response = runtime.block_on(client.delete_user(as_user(&member.id, delete("")))).unwrap().into_inner();
+        assert!(response.user.is_none());
+        let token = response.confirmation_token;
+        let response = runtime.block_on(client.delete_user(as_user(&member.id, delete(&token)))).unwrap().into_inner();
+        assert_eq!(response.user.map(|user| user.status), Some("deactivated".to_string()));
+
+        // 既定の設定では `x-user-id` を信じず、HTTPと同じくAPIトークンのスコープまで確かめる
+        let world = Arc::new(RealWorld::with_cache_policy(CachePolicy::WriteThrough));
+        let email = Email::parse("alice@example.com").unwrap();
+        let alice = world.user_commands().create(Name::new("alice").unwrap(), email).unwrap().id;
+        let (_, plain) = world.api_token_repository().issue_token(alice.clone(), &[Scope::ReadUsers], None).unwrap();
+        let mut client = serve(world);
+        fn with_token<T>(token: &str, message: T) -> tonic::Request<T> {
+            let mut request = tonic::Request::new(message);
+            request.metadata_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+            request
+        }
+        let alice = alice.as_uuid().to_string();
+        let status = runtime.block_on(client.list_users(as_user(&alice, list.clone()))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let page = runtime.block_on(client.list_users(with_token(&plain, list))).unwrap().into_inner();
+        assert_eq!(page.total, 1);
+        let delete = DeleteUserRequest {
+            id: alice,
+            confirmation_token: String::new(),
+        };
+        let status = runtime.block_on(client.delete_user(with_token(&plain, delete))).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[test]
+    fn presenters_shape_use_case_outputs_per_frontend() {
+        let summary = |id: &str, name: &str| UserSummaryDto {
+            id: id.to_string(),
+            name: name.to_string(),
+            email: format!("{}@example.com", name),
+            role: "member".to_string(),
+            status: "active".to_string(),
+            create_time: Utc.with_ymd_and_hms(2018, 7, 1, 9, 30, 15).unwrap(),
+        };
+        let page = Page {
+            items: vec![summary("1", "alice"), summary("2", "bob")],
+            page: 1,
+            per_page: 2,
+            total: 3,
+        };
+
+        let jst = FixedOffset::east_opt(9 * 3600).unwrap();
+        let table = TablePresenter::new(&MockTime::new(), &jst).present(page.clone());
+        assert_eq!(
+            table.render(),
+            [
+                "ID  NAME   EMAIL              ROLE    STATUS  CREATED",
+                "1   alice  alice@example.com  member  active  2018-07-01 18:30",
+                "2   bob    bob@example.com    member  active  2018-07-01 18:30",
+                "page 1/2 (3 users) as of 2018-08-20 10:00 +09:00",
+            ]
+            .join("\n")
+        );
+
+        let view = JsonPresenter.present(page);
+        assert_eq!((view.total_pages, view.next_page), (2, Some(2)));
+        let last = JsonPresenter.present(Page {
+            items: vec![summary("3", "carol")],
+            page: 2,
+            per_page: 2,
+            total: 3,
+        });
+        assert_eq!((last.total_pages, last.next_page), (2, None));
+
+        // CLIでは `--output table` で表にする
+        let world = RealWorld::with_cache_policy(CachePolicy::WriteThrough);
+        let run = |args: &[&str]| -> String {
+            let matches = cli::command().try_get_matches_from(args).unwrap();
+            let mut out = Vec::new();
+            cli::dispatch(&world, &matches, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let added = run(&["layered", "user", "add", "carol", "carol@example.com", "--output", "table"]);
+        assert!(added.starts_with("ID "));
+        assert!(added.lines().nth(1).unwrap().contains("  carol  carol@example.com  member  active  "));
+        let listed = run(&["layered", "--output", "table", "user", "list", "--tz", "+09:00"]);
+        assert!(listed.lines().last().unwrap().starts_with("page 1/1 (1 users) as of "), "{}", listed);
+        assert!(listed.lines().last().unwrap().ends_with(" +09:00"), "{}", listed);
+        assert!(cli::command().try_get_matches_from(["layered", "user", "list", "--tz", "Tokyo"]).is_err());
+        let json: Value = serde_json::from_str(&run(&["layered", "user", "list"])).unwrap();
+        assert_eq!((json["total_pages"].as_u64(), json["next_page"].clone()), (Some(1), Value::Null));
+        assert!("yaml".parse::<cli::Output>().is_err());
+    }
+
+    #[test]
+    fn delivery_mechanisms_call_the_injected_user_controller() {
+        /// 呼ばれた操作を覚えておき、決まった結果を返す
+        #[derive(Default)]
+        struct RecordingController {
+            calls: RefCell<Vec<String>>,
+        }
+
+        fn user(id: &str) -> UserDto {
+            UserDto {
+                id: id.to_string(),
+                name: "stub".to_string(),
+                email: "stub@example.com".to_string(),
+                role: "member".to_string(),
+                status: "active".to_string(),
+                address: None,
+                phone_number: None,
+                create_time: Utc.with_ymd_and_hms(2018, 7, 1, 0, 0, 0).unwrap(),
+                update_time: Utc.with_ymd_and_hms(2018, 7, 1, 0, 0, 0).unwrap(),
+                version: 1,
+            }
+        }
+
+        impl UserController for RecordingController {
+            fn register(&self, new_user: NewUser) -> Result<UserDto, Error> {
+                self.calls.borrow_mut().push(format!("register {}", new_user.name));
+                Ok(user("new"))
+            }
+            fn record_signup_region(&self, id: &str, _: IpAddr) -> Result<ProfileDto, Error> {
+                bail!("no region for {}", id)
+            }
+            fn get(&self, _: &Caller, id: &str) -> Result<UserDto, Error> {
+                Ok(user(id))
+            }
+            fn list(&self, _: &Caller, query: ListUsersQuery) -> Result<Page<UserSummaryDto>, Error> {
+                let per_page = query.per_page.unwrap_or(20);
+                Ok(Page { items: Vec::new(), page: query.page, per_page, total: 0 })
+            }
+            fn search(&self, _: &Caller, _: UserSearch) -> Result<Vec<UserSummaryDto>, Error> {
+                Ok(Vec::new())
+            }
+            fn rename(&self, _: &Caller, id: &str, name: &str) -> Result<UserDto, Error> {
+                self.calls.borrow_mut().push(format!("rename {} {}", id, name));
+                Ok(user(id))
+            }
+            fn request_deletion(&self, caller: &Caller, id: &str) -> Result<String, Error> {
+                assert_eq!(*caller, Caller::Operator);
+                Ok(format!("token-for-{}", id))
+            }
+            fn confirm_deletion(&self, caller: &Caller, id: &str, token: String) -> Result<UserDto, Error> {
+                assert_eq!(*caller, Caller::Operator);
+                self.calls.borrow_mut().push(format!("delete {} {}", id, token));
+                Ok(user(id))
+            }
+            fn suspend(&self, _: &Caller, id: &str) -> Result<UserDto, Error> {
+                self.calls.borrow_mut().push(format!("suspend {}", id));
+                Ok(user(id))
+            }
+            fn restore(&self, _: &Caller, id: &str) -> Result<UserDto, Error> {
+                self.calls.borrow_mut().push(format!("restore {}", id));
+                Ok(user(id))
+            }
+            fn purge(&self, caller: &Caller, id: &str) -> Result<UserDto, Error> {
+                assert_eq!(*caller, Caller::Operator);
+                self.calls.borrow_mut().push(format!("purge {}", id));
+                Ok(user(id))
+            }
+            fn import(&self, import: Import) -> Result<usize, Error> {
+                let call = format!("import {} {:?} {:?}", import.path.display(), import.format, import.parallelism);
+                self.calls.borrow_mut().push(call);
+                Ok(3)
+            }
+            fn export(&self, _: Export) -> Result<usize, Error> {
+                Ok(0)
+            }
+        }
+
+        impl HaveUserController for RecordingController {
+            fn user_controller(&self) -> &impl UserController {
+                self
+            }
+        }
+
+        impl HaveTimeComponent for RecordingController {
+            type TimeComponent = Chrono;
+            fn time_component(&self) -> &Chrono {
+                &Chrono
+            }
+        }
+
+        let controller = RecordingController::default();
+        let run = |args: &[&str]| -> Value {
+            let matches = cli::command().try_get_matches_from(args).unwrap();
+            let mut out = Vec::new();
+            cli::dispatch(&controller, &matches, &mut out).unwrap();
+            serde_json::from_slice(&out).unwrap()
+        };
+        assert_eq!(run(&["layered", "user", "add", "alice", "alice@example.com"])["id"], "new");
+        assert_eq!(run(&["layered", "user", "get", "some-id"])["id"], "some-id");
+        assert_eq!(run(&["layered", "user", "list", "--page", "2"])["page"], 2);
+        assert_eq!(run(&["layered", "user", "delete", "some-id"])["id"], "some-id");
+        assert_eq!(run(&["layered", "user", "suspend", "some-id"])["id"], "some-id");
+        assert_eq!(run(&["layered", "user", "restore", "some-id"])["id"], "some-id");
+        assert_eq!(run(&["layered", "user", "purge", "some-id"])["id"], "some-id");
+        assert_eq!(run(&["layered", "user", "import", "users.jsonl"])["imported"], 3);
+        assert_eq!(run(&["layered", "user", "import", "users.csv", "--parallelism", "4"])["imported"], 3);
+        assert_eq!(
+            *controller.calls.borrow(),
+            [
+                "register alice",
+                "delete some-id token-for-some-id",
+                "suspend some-id",
+                "restore some-id",
+                "purge some-id",
+                "import users.jsonl Json None",
+                "import users.csv Csv Some(4)"
+            ]
+        );
+
+        // RealWorldでは、ユーザーとして呼ぶと本人のアカウントしか退会できない
+        let world = RealWorld::with_cache_policy(CachePolicy::WriteThrough);
+        let new_user = |name: &str| NewUser {
+            name: name.to_string(),
+            email: format!("{}@example.com", name),
+        };
+        let alice = world.user_controller().register(new_user("alice")).unwrap();
+        let bob = world.user_controller().register(new_user("bob")).unwrap();
+        let as_bob = Caller::User(UserId::new(Uuid::parse_str(&bob.id).unwrap()));
+        let denied = world.user_controller().request_deletion(&as_bob, &alice.id).unwrap_err();
+        assert_eq!(PresentationError::from(denied).kind, ErrorKind::Forbidden);
+        let token = world.user_controller().request_deletion(&as_bob, &bob.id).unwrap();
+        let deleted = world.user_controller().confirm_deletion(&as_bob, &bob.id, token).unwrap();
+        assert_eq!(deleted.status, "deactivated");
+        let token = world.user_controller().request_deletion(&Caller::Operator, &alice.id).unwrap();
+        assert!(world.user_controller().confirm_deletion(&Caller::Operator, &alice.id, token).is_ok());
+    }
+
+    #[test]
+    fn users_are_exported_as_csv_with_the_selected_columns() {
+        let app = TestWorld::new();
+        for name in &["Smith, John", "alice"] {
+            let email = Email::parse(&format!("{}@example.com", name.replace(", ", "."))).unwrap();
+            app.user_commands().create(Name::new(name).unwrap(), email).unwrap();
+        }
+        let path = Path::new("export/users.csv");
+        let format = ExportFormat::Csv(vec![Column::Name, Column::Email, Column::Status]);
+        assert_eq!(app.export_users_as(path, &format).unwrap(), 2);
+        let exported = app.file_system_component().read(path).unwrap().unwrap();
+        let mut lines: Vec<&str> = exported.lines().collect();
+        assert_eq!(lines.remove(0), "name,email,status");
+        lines.sort();
+        assert_eq!(lines, ["\"Smith, John\",smith.john@example.com,active", "alice,alice@example.com,active"]);
+        assert_eq!("create_time".parse::<Column>().unwrap(), Column::CreateTime);
+        assert!("password".parse::<Column>().is_err());
+
+        // CLIでは `--columns` を付けなければ全部の列を書き出す。JSONには列を選べない
+        fn run(world: &RealWorld, args: &[&str]) -> Result<Value, Error> {
+            let matches = cli::command().try_get_matches_from(args)?;
+            let mut out = Vec::new();
+            cli::dispatch(world, &matches, &mut out)?;
+            Ok(serde_json::from_slice(&out).unwrap())
+        }
+        let world = RealWorld::with_cache_policy(CachePolicy::WriteThrough);
+        run(&world, &["layered", "user", "add", "user1", "user1@example.com"]).unwrap();
+        let path = ::std::env::temp_dir().join(format!("layered-{}.csv", Uuid::new_v4()));
+        let out = path.to_str().unwrap();
+        let exported = run(&world, &["layered", "user", "export", "--format", "csv", "--out", out]).unwrap();
+        assert_eq!(exported["exported"], 1);
+        let contents = ::std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().next(), Some("id,name,email,role,status,create_time,update_time"));
+        let selected = ["layered", "user", "export", "--format", "csv", "--columns", "email,id", "--out", out];
+        run(&world, &selected).unwrap();
+        let contents = ::std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().next(), Some("email,id"));
+        assert!(contents.lines().nth(1).unwrap().starts_with("user1@example.com,"));
+        assert!(run(&world, &["layered", "user", "export", "--columns", "id", "--out", out]).is_err());
+        assert!(run(&world, &["layered", "user", "export", "--format", "xml", "--out", out]).is_err());
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn users_are_imported_from_csv_only_when_every_row_is_valid() {
+        let app = TestWorld::new();
+        let alice = Email::parse("alice@example.com").unwrap();
+        let alice = app.user_commands().create(Name::new("alice").unwrap(), alice).unwrap();
+        let path = Path::new("import/users.csv");
+        let rows = [
+            "name,email,role",
+            "bob,bob@example.com,admin",
+            "alice,alice2@example.com,member",
+            "carol,not-an-email,member",
+            "\"Dave, Jr.\",dave@example.com,guest",
+            "erin,bob@example.com,member",
+            "frank,frank@example.com,owner",
+        ];
+        app.file_system_component().write(path, &rows.join("\n")).unwrap();
+        let error = app.import_users(path, ImportFormat::Csv, None).unwrap_err();
+        let lines: Vec<usize> = error.reason::<ImportError>().unwrap().rows.iter().map(|r| r.line).collect();
+        assert_eq!(lines, [3, 4, 6, 7]);
+        assert!(error.to_string().contains("line 6: duplicate email"));
+        assert_eq!(PresentationError::from(error).kind, ErrorKind::Validation);
+        assert_eq!(app.user_queries().list().unwrap().len(), 1);
+
+        let rows = ["name,email,role", "bob,bob@example.com,admin", "\"Dave, Jr.\",dave@example.com,guest"];
+        app.file_system_component().write(path, &rows.join("\r\n")).unwrap();
+        assert_eq!(app.import_users(path, ImportFormat::from_path(path), None).unwrap(), 2);
+        let dave = app.user_queries().get_by_name(&Name::new("Dave, Jr.").unwrap()).unwrap();
+        assert_eq!((dave.role, dave.status), (Role::Guest, UserStatus::Active));
+
+        // 何万件あってもまとめて1回で保存する
+        let mut rows = vec!["name,email".to_string()];
+        rows.extend((0..20_000).map(|i| format!("user{},user{}@example.com", i, i)));
+        app.file_system_component().write(path, &rows.join("\n")).unwrap();
+        assert_eq!(app.import_users(path, ImportFormat::Csv, None).unwrap(), 20_000);
+        assert_eq!(app.user_queries().list().unwrap().len(), 20_003);
+
+        // 塊に分けて並行して保存しても、全員が保存される
+        let mut rows = vec!["name,email".to_string()];
+        rows.extend((0..1_000).map(|i| format!("chunk{},chunk{}@example.com", i, i)));
+        app.file_system_component().write(path, &rows.join("\n")).unwrap();
+        assert_eq!(app.import_users(path, ImportFormat::Csv, Some(4)).unwrap(), 1_000);
+        assert_eq!(app.user_queries().list().unwrap().len(), 21_003);
+
+        // insert_manyは既にあるIDやバッチの中の重複があれば1件も保存しない
+        let mut carol = alice.clone();
+        carol.id = UserId::new(Uuid::new_v4());
+        carol.name = Name::new("carol").unwrap();
+        carol.email = Email::parse("carol@example.com").unwrap();
+        assert!(app.user_commands().insert_many(vec![carol.clone(), alice]).is_err());
+        assert!(app.user_commands().insert_many(vec![carol.clone(), carol.clone()]).is_err());
+        assert!(app.user_queries().get(carol.id.clone()).is_err());
+    }
+
+    #[test]
+    fn save_all_concurrent_writes_chunks_in_parallel() {
+        let users: Vec<User> = (0..100).map(|i| test_user(&format!("user{}", i))).collect();
+        let values = |users: &[User]| -> Vec<(UserId, User)> {
+            users.iter().map(|user| (user.id.clone(), user.clone())).collect()
+        };
+
+        // 塊ごとに別のスレッドで書き、同じキーは同じ塊に入るので後の値が残る
+        let storage = ChunkedStorage::new();
+        let mut renamed = users[0].clone();
+        renamed.name = Name::new("renamed").unwrap();
+        let mut batch = values(&users);
+        batch.push((renamed.id.clone(), renamed.clone()));
+        storage.save_all_concurrent(&batch, 4).unwrap();
+        assert_eq!(storage.threads(), 4);
+        assert_eq!(storage.read_all().unwrap().len(), 100);
+        assert_eq!(storage.read(renamed.id.clone()).unwrap().name, renamed.name);
+
+        // 失敗した塊は1件も書かれないが、他の塊は書かれたまま残る
+        let storage = ChunkedStorage::new();
+        storage.fail_on(Some(users[1].id.clone()));
+        assert!(storage.save_all_concurrent(&values(&users), 4).is_err());
+        assert_eq!(storage.read_all().unwrap().len(), 75);
+        assert!(storage.read(users[1].id.clone()).is_err());
+
+        // 並行に書けないストレージはsave_allと同じく全てか無しか
+        let storage = MemoryStorage::new();
+        let mut stale = users[2].clone();
+        storage.save(stale.id.clone(), stale.clone()).unwrap();
+        stale.name = Name::new("stale").unwrap();
+        let mut batch = values(&users[3..]);
+        batch.push((stale.id.clone(), stale));
+        assert!(storage.save_all_concurrent(&batch, 4).is_err());
+        assert_eq!(storage.read_all().unwrap().len(), 1);
+
+        // 名前の重複は塊をまたいでも見つかり、一部だけ書けた時は索引をストレージから作り直す
+        let storage = IndexedUserStorage::new(ChunkedStorage::new()).unwrap();
+        let mut twin = test_user("user5");
+        twin.email = Email::parse("twin@example.com").unwrap();
+        let mut batch = values(&users);
+        batch.push((twin.id.clone(), twin));
+        assert!(storage.save_all_concurrent(&batch, 4).is_err());
+        assert!(storage.read_all().unwrap().is_empty());
+        storage.storage().fail_on(Some(users[1].id.clone()));
+        assert!(storage.save_all_concurrent(&values(&users), 4).is_err());
+        assert!(storage.read_by_name(&users[0].name).is_ok());
+        assert!(storage.read_by_name(&users[1].name).is_err());
+        storage.storage().fail_on(None);
+        let missing: Vec<User> = users.iter().filter(|u| storage.read(u.id.clone()).is_err()).cloned().collect();
+        assert_eq!(missing.len(), 25);
+        storage.save_all_concurrent(&values(&missing), 4).unwrap();
+        assert_eq!(storage.read_by_name(&users[1].name).unwrap().id, users[1].id);
+
+        let app = TestWorld::new();
+        app.user_commands().save_all_concurrent(users.clone(), 8).unwrap();
+        assert_eq!(app.user_queries().list().unwrap().len(), 100);
+    }
+
+    #[test]
+    fn concurrent_memory_storage_locks_only_the_shards_it_touches() {
+        use component::storage::HaveUserStorageComponent;
+        use env::UserBackend;
+        use std::thread;
+
+        let users: Vec<User> = (0..64).map(|i| test_user(&format!("user{}", i))).collect();
+        let storage = ConcurrentMemoryStorage::with_shards(8);
+        thread::scope(|scope| {
+            for chunk in users.chunks(8) {
+                let storage = &storage;
+                scope.spawn(move || chunk.iter().for_each(|user| storage.save(user.id.clone(), user.clone()).unwrap()));
+            }
+        });
+        // 並びはMemoryStorageと同じくキーの順
+        let mut sorted = users.clone();
+        sorted.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(storage.read_all().unwrap(), sorted);
+
+        // シャードをまたいだsave_allも、1件がConflictなら1件も書かない
+        let mut bumped: Vec<(UserId, User)> = users.iter().map(|user| (user.id.clone(), user.clone())).collect();
+        bumped.iter_mut().skip(1).for_each(|&mut (_, ref mut user)| user.version += 1);
+        let err = storage.save_all(&bumped).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&StorageError::Conflict { stored: 1, given: 1 }));
+        assert!(storage.read_all().unwrap().iter().all(|user| user.version == 1));
+        storage.save_all_concurrent(&bumped[1..], 4).unwrap();
+        assert_eq!(storage.read(users[1].id.clone()).unwrap().version, 2);
+        storage.delete(users[0].id.clone()).unwrap();
+        assert_eq!(storage.read_many(&[users[0].id.clone(), users[1].id.clone()]).unwrap().len(), 1);
+        assert!(storage.delete(users[0].id.clone()).is_err());
+
+        // シャードの数を設定すると、RealWorldもユーザーをシャードに分けて持つ
+        let config = |shards: &str, snapshot: Option<&str>| {
+            Config::default().override_with(|key| match key {
+                "LAYERED_MEMORY_SHARDS" => Some(shards.to_string()),
+                "LAYERED_SNAPSHOT_PATH" => snapshot.map(str::to_string),
+                _ => None,
+            })
+        };
+        assert!(config("0", None).is_err());
+        assert!(config("4", Some("users.jsonl")).is_err());
+        let world = RealWorld::with_config(config("4", None).unwrap(), CachePolicy::WriteThrough).unwrap();
+        let user = world
+            .user_commands()
+            .create(Name::new("user1").unwrap(), Email::parse("user1@example.com").unwrap())
+            .unwrap();
+        match *world.user_storage_component().storage().storage().storage().inner() {
+            UserBackend::Sharded(ref storage) => assert_eq!(storage.read(user.id.clone()).unwrap(), user),
+            _ => panic!("users are not stored in shards"),
+        }
+        assert_eq!(world.user_queries().get(user.id).unwrap().name.as_str(), "user1");
+    }
+
+    /// `cargo test --release -- --ignored --nocapture concurrent_storages` で、
+    /// 1つのRwLockのMemoryStorageとシャードに分けたConcurrentMemoryStorageを、読み8割・書き2割の負荷で比べる
+    #[test]
+    #[ignore]
+    fn concurrent_storages_under_mixed_load() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        fn run<S: StorageComponent<UserId, User> + Sync>(storage: &S, users: &[User], threads: usize) -> Duration {
+            for user in users {
+                storage.save(user.id.clone(), user.clone()).unwrap();
+            }
+            // キーごとに次に書くバージョンを配り、どのスレッドからでも前より新しいバージョンで書く
+            let versions: Vec<AtomicU64> = users.iter().map(|user| AtomicU64::new(user.version + 1)).collect();
+            let versions = &versions;
+            let started = Instant::now();
+            thread::scope(|scope| {
+                for t in 0..threads {
+                    scope.spawn(move || {
+                        for i in 0..100_000 {
+                            let index = (i * 7919 + t * 104_729) % users.len();
+                            let mut user = users[index].clone();
+                            if i % 5 == 0 {
+                                // 後のバージョンを配られたスレッドが先に書いた時だけConflictになる
+                                user.version = versions[index].fetch_add(1, Ordering::SeqCst);
+                                let _ = storage.save(user.id.clone(), user);
+                            } else {
+                                storage.read(user.id).unwrap();
+                            }
+                        }
+                    });
+                }
+            });
+            started.elapsed()
+        }
+
+        let users: Vec<User> = (0..1000).map(|i| test_user(&format!("user{}", i))).collect();
+        let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1).max(2);
+        let single = run(&MemoryStorage::new(), &users, threads);
+        let sharded = run(&ConcurrentMemoryStorage::new(), &users, threads);
+        println!("{} threads: MemoryStorage {:?}, ConcurrentMemoryStorage {:?}", threads, single, sharded);
+    }
+
+    #[test]
+    fn cli_dispatches_user_subcommands_to_use_cases() {
+        fn run(world: &RealWorld, args: &[&str]) -> Result<Value, Error> {
+            let matches = cli::command().try_get_matches_from(args).unwrap();
+            let mut out = Vec::new();
+            cli::dispatch(world, &matches, &mut out)?;
+            Ok(serde_json::from_slice(&out).unwrap())
+        }
+
+        assert_eq!("memory".parse::<Storage>().unwrap(), Storage::Memory);
+        assert_eq!("file:users.jsonl".parse::<Storage>().unwrap(), Storage::File("users.jsonl".into()));
+        assert!("file:".parse::<Storage>().is_err());
+        assert!(cli::command().try_get_matches_from(["layered", "--storage", "s3", "user", "list"]).is_err());
+
+        let world = RealWorld::with_cache_policy(CachePolicy::WriteThrough);
+        let user = run(&world, &["layered", "user", "add", "user1", "user1@example.com"]).unwrap();
+        let id = user["id"].as_str().unwrap().to_string();
+        assert_eq!(run(&world, &["layered", "user", "get", &id]).unwrap()["name"], "user1");
+        let page = run(&world, &["layered", "user", "list", "--per-page", "5"]).unwrap();
+        assert_eq!((page["total"].as_u64(), page["per_page"].as_u64()), (Some(1), Some(5)));
+        let missing = run(&world, &["layered", "user", "get", "not-a-uuid"]).unwrap_err();
+        assert_eq!(PresentationError::from(missing).exit_code(), 66);
+
+        // exportしたファイルを別の保存先に読み込む。同じユーザーがいれば1件も読み込まない。
+        let path = ::std::env::temp_dir().join(format!("layered-{}.jsonl", Uuid::new_v4()));
+        assert_eq!(world.export_users_as(&path, &ExportFormat::Json).unwrap(), 1);
+        let other = RealWorld::with_cache_policy(CachePolicy::WriteThrough);
+        let imported = run(&other, &["layered", "user", "import", path.to_str().unwrap()]).unwrap();
+        assert_eq!(imported["imported"], 1);
+        assert_eq!(run(&other, &["layered", "user", "get", &id]).unwrap()["email"], "user1@example.com");
+        assert!(run(&other, &["layered", "user", "import", path.to_str().unwrap()]).is_err());
+        assert_eq!(other.user_queries().list().unwrap().len(), 1);
+        ::std::fs::remove_file(&path).unwrap();
+
+        let deleted = run(&world, &["layered", "user", "delete", &id]).unwrap();
+        assert_eq!(deleted["status"], "deactivated");
     }
 }